@@ -0,0 +1,63 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use ittech::error::VerboseError;
+use ittech::*;
+
+/// Module with 200 patterns of 64 rows with commands on 16 channels
+fn module() -> Vec<u8> {
+    const NOTES: [&str; 7] = ["C-5", "D-5", "E-5", "F-5", "G-5", "A-5", "B-5"];
+    let mut text = String::from("ModPlug Tracker  IT\r\n");
+    for row in 0..64 {
+        for channel in 0..16 {
+            match (row + channel) % 4 {
+                0 => text.push_str("|..........."),
+                1 => text.push_str(&format!("|{}01v{:02}...", NOTES[(row + channel) % 7], row)),
+                _ => text.push_str(&format!("|{}02...H{:02X}", NOTES[row % 7], channel * 16 + 4)),
+            }
+        }
+        text.push_str("\r\n");
+    }
+    let pattern = Pattern::from_text(&text).unwrap();
+    let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/effect_alphabet.it")).unwrap();
+    module.patterns = vec![pattern; 200];
+    writer::module_file(&module).unwrap()
+}
+
+fn patterns(c: &mut Criterion) {
+    let data = module();
+    let mut group = c.benchmark_group("patterns");
+    group.throughput(Throughput::Bytes(u64::try_from(data.len()).unwrap()));
+    group.bench_function("module_file", |b| {
+        b.iter(|| parser::module_file::<VerboseError<&[u8]>>(black_box(&data)).unwrap())
+    });
+    group.bench_function("module_file/unit error", |b| {
+        b.iter(|| parser::module_file::<()>(black_box(&data)).unwrap())
+    });
+    group.bench_function("module_patterns", |b| {
+        b.iter(|| {
+            parser::module_patterns::<VerboseError<&[u8]>>(black_box(&data))
+                .unwrap()
+                .map(|cells| cells.unwrap().map(Result::unwrap).count())
+                .sum::<usize>()
+        })
+    });
+    group.finish();
+}
+
+/// Rejecting invalid files, where the verbose errors collect their trace
+fn errors(c: &mut Criterion) {
+    let mut data = module();
+    // Corrupt the first command of the first pattern with an out of range channel.
+    let count = |offset: usize| usize::from(u16::from_le_bytes([data[offset], data[offset + 1]]));
+    let table = 0xC0 + count(0x20) + 4 * (count(0x22) + count(0x24));
+    let pattern = usize::try_from(u32::from_le_bytes(data[table..table + 4].try_into().unwrap())).unwrap();
+    data[pattern + 8] = 0xC1;
+    let mut group = c.benchmark_group("errors");
+    group.bench_function("verbose", |b| {
+        b.iter(|| parser::module_file::<VerboseError<&[u8]>>(black_box(&data)).unwrap_err())
+    });
+    group.bench_function("unit", |b| b.iter(|| parser::module_file::<()>(black_box(&data)).unwrap_err()));
+    group.finish();
+}
+
+criterion_group!(benches, patterns, errors);
+criterion_main!(benches);
@@ -0,0 +1,223 @@
+use anyhow::{bail, Context, Result};
+use ittech::error::{convert_error, VerboseError};
+use ittech::parser;
+use ittech::player::{render_to_wav, Interpolation, Player, RenderOptions, WavFormat};
+use nom::Err;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+const USAGE: &str = "\
+usage: cargo run --example it2wav -- [options] <itmodule> <outputwav>
+
+options:
+    --rate <hz>             output sample rate, 44100 by default
+    --interpolation <kind>  nearest, linear, cubic or sinc (default)
+    --loops <count>         times a looping song is repeated, 0 by default
+    --fade <seconds>        fade-out ending the last repetition, 0 by default
+    --float                 write 32-bit floating point samples instead of 16-bit
+    --stems                 also write every channel to <outputwav>-NN.wav";
+
+/// Frames rendered at once
+const CHUNK_FRAMES: usize = 1024;
+
+struct Args {
+    input: PathBuf,
+    output: PathBuf,
+    options: RenderOptions,
+    stems: bool,
+}
+
+fn main() -> Result<()> {
+    let args = parse_args().with_context(|| USAGE)?;
+
+    let data = fs::read(&args.input)
+        .with_context(|| format!("failed to read file {}", args.input.display()))?;
+    let module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+            eprintln!("parser failed\n\n{}", convert_error(&data, e));
+            return Ok(());
+        }
+        _ => unreachable!(),
+    };
+
+    let frames = if args.stems {
+        render_with_stems(module, &args)?
+    } else {
+        let mut outfile = BufWriter::new(File::create(&args.output)
+            .with_context(|| format!("failed to write file {}", args.output.display()))?);
+        let frames = render_to_wav(&module, &mut outfile, &args.options)
+            .with_context(|| format!("failed to write file {}", args.output.display()))?;
+        outfile.flush()
+            .with_context(|| format!("failed to write file {}", args.output.display()))?;
+        u64::from(frames)
+    };
+
+    let seconds = frames / u64::from(args.options.player.sample_rate);
+    println!("rendered {}:{:02} to {}", seconds / 60, seconds % 60, args.output.display());
+    Ok(())
+}
+
+fn parse_args() -> Result<Args> {
+    let mut options = RenderOptions::default();
+    options.player.interpolation = Interpolation::Sinc;
+    let mut stems = false;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().with_context(|| format!("missing value of {arg}"));
+        match arg.as_str() {
+            "--rate" => options.player.sample_rate = value()?.parse().context("invalid sample rate")?,
+            "--interpolation" => {
+                options.player.interpolation = match value()?.as_str() {
+                    "nearest" => Interpolation::Nearest,
+                    "linear" => Interpolation::Linear,
+                    "cubic" => Interpolation::Cubic,
+                    "sinc" => Interpolation::Sinc,
+                    other => bail!("unknown interpolation {other}"),
+                }
+            }
+            "--loops" => options.player.repeat = Some(value()?.parse().context("invalid loop count")?),
+            "--fade" => options.player.fade_out = value()?.parse().context("invalid fade-out length")?,
+            "--float" => options.format = WavFormat::Float32,
+            "--stems" => stems = true,
+            _ if arg.starts_with("--") => bail!("unknown option {arg}"),
+            _ => paths.push(PathBuf::from(&arg)),
+        }
+    }
+    if options.player.sample_rate == 0 {
+        bail!("invalid sample rate");
+    }
+    if !(options.player.fade_out >= 0.0) {
+        bail!("invalid fade-out length");
+    }
+
+    let [input, output] = <[PathBuf; 2]>::try_from(paths)
+        .map_err(|_| anyhow::anyhow!("expected an input and an output file"))?;
+    Ok(Args { input, output, options, stems })
+}
+
+/// Renders the mix and a file for every channel used by the song, returns the number of frames.
+fn render_with_stems(module: ittech::Module, args: &Args) -> Result<u64> {
+    let channels = module.active_channels();
+    let mut player = Player::new(module, args.options.player.clone());
+
+    let mut mix = WavWriter::create(&args.output, args.options.format, args.options.player.sample_rate)?;
+    let stem_count = channels.iter().map(|chan| chan.as_usize() + 1).max().unwrap_or(0);
+    let mut stems = Vec::new();
+    for chan in channels.iter() {
+        let path = stem_path(&args.output, chan.as_usize() + 1);
+        stems.push((chan.as_usize(), WavWriter::create(&path, args.options.format, args.options.player.sample_rate)?));
+    }
+
+    let mut out = vec![0.0f32; CHUNK_FRAMES * 2];
+    let mut buffers = vec![vec![0.0f32; CHUNK_FRAMES * 2]; stem_count];
+    let mut frames = 0;
+    loop {
+        buffers.iter_mut().for_each(|buffer| buffer.fill(0.0));
+        let mut slices = buffers.iter_mut().map(|buffer| &mut buffer[..]).collect::<Vec<_>>();
+        let count = player.render_stems(&mut out, &mut slices);
+        mix.write(&out[..count * 2])?;
+        for (idx, stem) in &mut stems {
+            stem.write(&buffers[*idx][..count * 2])?;
+        }
+        frames += u64::try_from(count).unwrap();
+        if count < CHUNK_FRAMES {
+            break;
+        }
+    }
+
+    mix.finish()?;
+    for (_, stem) in stems {
+        stem.finish()?;
+    }
+    Ok(frames)
+}
+
+/// Returns `song-03.wav` for channel 3 of `song.wav`.
+fn stem_path(output: &Path, channel: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(format!("{stem}-{channel:02}.wav"))
+}
+
+/// Stereo WAV file written as it's rendered, the sizes are filled in by [`WavWriter::finish`]
+struct WavWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    format: WavFormat,
+    sample_rate: u32,
+    data_size: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, format: WavFormat, sample_rate: u32) -> Result<WavWriter> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to write file {}", path.display()))?;
+        let mut writer = WavWriter {
+            path: path.to_owned(),
+            file: BufWriter::new(file),
+            format,
+            sample_rate,
+            data_size: 0,
+        };
+        writer.header()?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 4);
+        for &sample in samples {
+            match self.format {
+                WavFormat::Pcm16 => {
+                    let sample = (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+                    bytes.extend_from_slice(&sample.to_le_bytes());
+                }
+                WavFormat::Float32 => bytes.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+        self.data_size = u32::try_from(bytes.len())
+            .ok()
+            .and_then(|size| self.data_size.checked_add(size))
+            .filter(|&size| size <= u32::MAX - 36)
+            .context("song too long for a WAV file")?;
+        self.file.write_all(&bytes)
+            .with_context(|| format!("failed to write file {}", self.path.display()))
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))
+            .with_context(|| format!("failed to write file {}", self.path.display()))?;
+        self.header()?;
+        self.file.flush()
+            .with_context(|| format!("failed to write file {}", self.path.display()))
+    }
+
+    /// Writes the canonical 44-byte header, the `fact` chunk is optional for float files.
+    fn header(&mut self) -> Result<()> {
+        let (tag, bits) = match self.format {
+            WavFormat::Pcm16 => (1u16, 16u16),
+            WavFormat::Float32 => (3, 32),
+        };
+        let block_align = 2 * bits / 8;
+
+        let mut header = Vec::with_capacity(44);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(36 + self.data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&tag.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(self.sample_rate * u32::from(block_align)).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_size.to_le_bytes());
+        self.file.write_all(&header)
+            .with_context(|| format!("failed to write file {}", self.path.display()))
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{bail, Result};
+use ittech::error::{convert_error, VerboseError};
+use ittech::{parser, Module, ModuleFlags, Order};
+use nom::Err;
+use std::fmt::Write;
+use std::{env, fs, process};
+
+const USAGE: &str = "\
+usage: cargo run --example itcheck -- [options] <itmodule>...
+
+Validates the modules and lists the values the parser had to repair. Exits with 1 if any file
+has problems.
+
+options:
+    --json           print the findings as a JSON array
+    --allow-repairs  don't fail on repaired values, only on errors";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Severity {
+    /// The parser repaired an out-of-range value, other trackers may load the file differently
+    Repair,
+
+    /// The file can't be parsed or references data which doesn't exist
+    Error,
+}
+
+struct Finding {
+    file: String,
+    severity: Severity,
+    kind: &'static str,
+    message: String,
+}
+
+fn main() -> Result<()> {
+    let mut json = false;
+    let mut allow_repairs = false;
+    let mut fnames = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--allow-repairs" => allow_repairs = true,
+            _ if arg.starts_with("--") => bail!("unknown option {arg}\n\n{USAGE}"),
+            _ => fnames.push(arg),
+        }
+    }
+    if fnames.is_empty() {
+        bail!(USAGE);
+    }
+
+    let mut findings = Vec::new();
+    for fname in &fnames {
+        check(fname, &mut findings);
+    }
+
+    if json {
+        println!("{}", to_json(&findings));
+    } else {
+        for finding in &findings {
+            let severity = match finding.severity {
+                Severity::Repair => "repair",
+                Severity::Error => "error",
+            };
+            println!("{}: {severity}: {}: {}", finding.file, finding.kind, finding.message);
+        }
+        let checked = fnames.len();
+        let failed = fnames.iter().filter(|fname| findings.iter().any(|finding| finding.file == **fname)).count();
+        eprintln!("{checked} files checked, {failed} with problems");
+    }
+
+    let failed = findings.iter().any(|finding| finding.severity == Severity::Error || !allow_repairs);
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn check(fname: &str, findings: &mut Vec<Finding>) {
+    let mut report = |severity, kind, message| {
+        findings.push(Finding { file: fname.to_owned(), severity, kind, message });
+    };
+
+    let data = match fs::read(fname) {
+        Ok(data) => data,
+        Err(error) => return report(Severity::Error, "io", error.to_string()),
+    };
+    let (module, repairs) = match parser::module_file_with_repairs::<VerboseError<_>>(&data) {
+        Ok(parsed) => parsed,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => return report(Severity::Error, "parse", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+    for repair in repairs {
+        report(Severity::Repair, "repair", repair.to_string());
+    }
+    for (kind, message) in validate(&module) {
+        report(Severity::Error, kind, message);
+    }
+}
+
+/// Checks the references between the parts of the module and the sample loops.
+fn validate(module: &Module) -> Vec<(&'static str, String)> {
+    let mut problems = Vec::new();
+
+    for (idx, order) in module.orders.iter().enumerate() {
+        if let Order::Index(pattern) = order {
+            if pattern.as_usize() >= module.patterns.len() {
+                problems.push(("order", format!("order {idx} plays pattern {pattern:?} which doesn't exist")));
+            }
+        }
+    }
+
+    let instruments = module.flags.contains(ModuleFlags::USE_INSTRUMENTS);
+    for (idx, instrument) in module.instruments.iter().enumerate() {
+        if let Err(error) = instrument.sample_map.validate(module.samples.len()) {
+            problems.push(("instrument", format!("instrument {:02}: {error}", idx + 1)));
+        }
+    }
+
+    for (idx, sample) in module.samples.iter().enumerate() {
+        for loop_ in [&sample.loop_, &sample.sustain_loop].into_iter().flatten() {
+            if let Err(error) = loop_.validate(sample.length()) {
+                problems.push(("sample", format!("sample {:02}: {error}", idx + 1)));
+            }
+        }
+    }
+
+    // Without instruments the instrument column holds sample numbers.
+    let (count, what) = if instruments {
+        (module.instruments.len(), "instrument")
+    } else {
+        (module.samples.len(), "sample")
+    };
+    for (idx, pattern) in module.patterns.iter().enumerate() {
+        let missing = pattern.rows
+            .iter()
+            .flat_map(|row| row.iter().filter_map(|(_, command)| command.instrument))
+            .filter(|instrument| instrument.as_usize() >= count)
+            .collect::<Vec<_>>();
+        if let Some(first) = missing.first() {
+            problems.push(("pattern", format!(
+                "pattern {idx} plays {what} {first} which doesn't exist in {} cells",
+                missing.len(),
+            )));
+        }
+    }
+
+    problems
+}
+
+fn to_json(findings: &[Finding]) -> String {
+    let mut json = String::from("[");
+    for (idx, finding) in findings.iter().enumerate() {
+        let severity = match finding.severity {
+            Severity::Repair => "repair",
+            Severity::Error => "error",
+        };
+        json.push_str(if idx == 0 { "\n  " } else { ",\n  " });
+        write!(
+            json,
+            r#"{{"file": {}, "severity": "{severity}", "kind": "{}", "message": {}}}"#,
+            string(&finding.file),
+            finding.kind,
+            string(&finding.message),
+        ).unwrap();
+    }
+    json.push_str(if findings.is_empty() { "]" } else { "\n]" });
+    json
+}
+
+/// Returns `text` as a JSON string literal.
+fn string(text: &str) -> String {
+    let mut escaped = String::from("\"");
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            ch if ch.is_control() => write!(escaped, "\\u{:04x}", u32::from(ch)).unwrap(),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
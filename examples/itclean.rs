@@ -0,0 +1,100 @@
+use anyhow::{bail, Context, Result};
+use ittech::error::{convert_error, VerboseError};
+use ittech::optimize::{optimize, OptimizeOptions};
+use ittech::parser;
+use ittech::writer::{self, Compression, WriteOptions};
+use nom::Err;
+use std::path::PathBuf;
+use std::{env, fs};
+
+const USAGE: &str = "\
+usage: cargo run --example itclean -- [options] <itmodule> [<output>]
+
+options:
+    --dry-run           report the savings without writing the output
+    --keep-unused       keep the unused patterns, instruments and samples
+    --keep-silence      keep the silence at the end of samples
+    --threshold <level> largest normalized sample value trimmed as silence, 0 by default
+    --no-compress       store the samples uncompressed instead of with IT 2.15 compression";
+
+fn main() -> Result<()> {
+    let mut options = OptimizeOptions::default();
+    let mut compression = Compression::It215;
+    let mut dry_run = false;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--keep-unused" => options.remove_unused = false,
+            "--keep-silence" => options.trim_silence = false,
+            "--threshold" => {
+                options.silence_threshold = args.next()
+                    .context(USAGE)?
+                    .parse()
+                    .context("invalid silence threshold")?;
+            }
+            "--no-compress" => compression = Compression::None,
+            _ if arg.starts_with("--") => bail!("unknown option {arg}\n\n{USAGE}"),
+            _ => paths.push(PathBuf::from(&arg)),
+        }
+    }
+    let (inpname, outname) = match (paths.as_slice(), dry_run) {
+        ([inpname], true) => (inpname, None),
+        ([inpname, outname], _) => (inpname, Some(outname)),
+        _ => bail!(USAGE),
+    };
+
+    let data = fs::read(inpname)
+        .with_context(|| format!("failed to read file {}", inpname.display()))?;
+    let mut module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("parser failed\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+
+    let report = optimize(&mut module, &options);
+    for pattern in &report.removed_patterns {
+        println!("removed unused pattern {pattern:?}");
+    }
+    for instrument in &report.removed_instruments {
+        println!("removed unused instrument {instrument}");
+    }
+    for sample in &report.removed_samples {
+        println!("removed unused sample {sample}");
+    }
+    for (sample, length) in &report.trimmed_samples {
+        println!("trimmed {length} samples of silence from sample {sample}");
+    }
+
+    let write_options = WriteOptions { compression, ..WriteOptions::default() };
+    let (output, write_report) = writer::module_file_with_options(&module, &write_options)
+        .context("failed to write the module")?;
+    println!(
+        "sample data {} -> {} bytes",
+        write_report.raw_sample_size(),
+        write_report.stored_sample_size(),
+    );
+
+    let (before, after) = (data.len(), output.len());
+    if after < before {
+        println!("{before} -> {after} bytes, saved {} bytes ({:.1}%)", before - after, percent(before - after, before));
+    } else {
+        println!("{before} -> {after} bytes, no savings");
+    }
+
+    match outname {
+        Some(outname) if !dry_run => {
+            fs::write(outname, &output)
+                .with_context(|| format!("failed to write file {}", outname.display()))?;
+        }
+        _ => println!("dry run, nothing written"),
+    }
+    Ok(())
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    let (part, total) = (u32::try_from(part).unwrap_or(u32::MAX), u32::try_from(total).unwrap_or(u32::MAX));
+    f64::from(part) * 100.0 / f64::from(total)
+}
@@ -0,0 +1,60 @@
+use anyhow::{bail, Context, Result};
+use ittech::diff::{diff, Difference};
+use ittech::error::{convert_error, VerboseError};
+use ittech::{parser, Module};
+use nom::Err;
+use std::{env, fs, process};
+
+const USAGE: &str = "usage: cargo run --example itdiff -- <old.it> <new.it>";
+
+fn main() -> Result<()> {
+    let oldname = env::args().nth(1).context(USAGE)?;
+    let newname = env::args().nth(2).context(USAGE)?;
+    let old = read(&oldname)?;
+    let new = read(&newname)?;
+
+    let differences = diff(&old, &new);
+    if differences.is_empty() {
+        println!("modules are equal");
+        return Ok(());
+    }
+
+    println!("--- {oldname}");
+    println!("+++ {newname}");
+    let mut section = "";
+    for difference in &differences {
+        let title = match difference {
+            Difference::Header { .. } | Difference::Channel { .. } => "header",
+            Difference::Order { .. } => "orders",
+            Difference::SampleAdded { .. } | Difference::SampleRemoved { .. } | Difference::SampleModified { .. } => "samples",
+            Difference::InstrumentAdded { .. }
+            | Difference::InstrumentRemoved { .. }
+            | Difference::InstrumentModified { .. } => "instruments",
+            Difference::PatternAdded { .. }
+            | Difference::PatternRemoved { .. }
+            | Difference::PatternRows { .. }
+            | Difference::Cell { .. } => "patterns",
+        };
+        if title != section {
+            println!("\n{title}:");
+            section = title;
+        }
+        println!("  {difference}");
+    }
+
+    let cells = differences.iter().filter(|difference| matches!(difference, Difference::Cell { .. })).count();
+    println!("\n{} differences, {cells} of them in pattern cells", differences.len());
+
+    // Like diff(1), differences exit with 1 so scripts can check that a pass changed nothing.
+    process::exit(1);
+}
+
+fn read(fname: &str) -> Result<Module> {
+    let data = fs::read(fname)
+        .with_context(|| format!("failed to read file {fname}"))?;
+    match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => Ok(module),
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("failed to parse {fname}\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    }
+}
@@ -0,0 +1,389 @@
+//! Annotated walk of the structures of a module file
+//!
+//! Reads the raw bytes instead of the parsed module, so it also walks files the parser rejects
+//! as far as their offsets allow. Every field is printed with its offset, raw bytes and decoded
+//! value.
+
+use anyhow::{Context, Result};
+use ittech::error::VerboseError;
+use ittech::parser;
+use nom::Err;
+use std::fmt::Display;
+use std::{env, fs};
+
+const USAGE: &str = "usage: cargo run --example itdump -- <itmodule>";
+
+const MODULE_FLAGS: [&str; 8] = [
+    "stereo",
+    "vol0 mix optimizations",
+    "instruments",
+    "linear slides",
+    "old effects",
+    "link G with E/F memory",
+    "midi pitch controller",
+    "embedded midi config",
+];
+
+const MODULE_SPECIAL: [&str; 4] = ["message", "edit history", "row highlight", "embedded midi config"];
+
+const SAMPLE_FLAGS: [&str; 8] = [
+    "data present",
+    "16-bit",
+    "stereo",
+    "compressed",
+    "loop",
+    "sustain loop",
+    "ping-pong loop",
+    "ping-pong sustain loop",
+];
+
+const ENVELOPE_FLAGS: [&str; 5] = ["enabled", "loop", "sustain loop", "carry", "filter"];
+
+const INSTRUMENT_SIZE: usize = 554;
+const SAMPLE_HEADER_SIZE: usize = 80;
+const PATTERN_HEADER_SIZE: usize = 8;
+
+fn main() -> Result<()> {
+    let fname = env::args().nth(1).context(USAGE)?;
+    let data = fs::read(&fname)
+        .with_context(|| format!("failed to read file {}", &fname))?;
+    Dump { data: &data }.module();
+    Ok(())
+}
+
+struct Dump<'a> {
+    data: &'a [u8],
+}
+
+impl Dump<'_> {
+    fn module(&self) {
+        section(format_args!("module header, {} bytes in file", self.data.len()));
+        self.field(0x00, 4, "magic", self.text(0x00, 4));
+        self.field(0x04, 26, "song name", self.text(0x04, 26));
+        self.u8(0x1E, "highlight minor");
+        self.u8(0x1F, "highlight major");
+        let ordnum = self.u16(0x20, "orders");
+        let insnum = self.u16(0x22, "instruments");
+        let smpnum = self.u16(0x24, "samples");
+        let patnum = self.u16(0x26, "patterns");
+        let cwtv = self.u16(0x28, "made with version");
+        self.u16(0x2A, "compatible with version");
+        self.flags16(0x2C, "flags", &MODULE_FLAGS);
+        let special = self.flags16(0x2E, "special", &MODULE_SPECIAL);
+        self.u8(0x30, "global volume");
+        self.u8(0x31, "mix volume");
+        self.u8(0x32, "initial speed");
+        self.u8(0x33, "initial tempo");
+        self.u8(0x34, "panning separation");
+        self.u8(0x35, "pitch wheel depth");
+        let msglength = self.u16(0x36, "message length");
+        let msgoffset = self.u32(0x38, "message offset");
+        self.field(0x3C, 4, "reserved", "");
+        self.field(0x40, 64, "channel panning", self.list(0x40, 64));
+        self.field(0x80, 64, "channel volume", self.list(0x80, 64));
+
+        let (Some(ordnum), Some(insnum), Some(smpnum), Some(patnum)) = (ordnum, insnum, smpnum, patnum) else {
+            return;
+        };
+        let (ordnum, insnum, smpnum, patnum) = (usize::from(ordnum), usize::from(insnum), usize::from(smpnum), usize::from(patnum));
+        let instruments = 0xC0 + ordnum;
+        let samples = instruments + 4 * insnum;
+        let patterns = samples + 4 * smpnum;
+        let header_end = patterns + 4 * patnum;
+        self.field(0xC0, ordnum, "orders", self.list(0xC0, ordnum));
+        let instruments = self.offsets(instruments, insnum, "instrument offset");
+        let samples = self.offsets(samples, smpnum, "sample offset");
+        let patterns = self.offsets(patterns, patnum, "pattern offset");
+
+        // Edit history and OpenMPT chunks follow the tables up to the first structure.
+        let first = [msgoffset.unwrap_or(0)]
+            .into_iter()
+            .chain(instruments.iter().copied())
+            .chain(samples.iter().copied())
+            .chain(patterns.iter().copied())
+            .map(to_usize)
+            .filter(|&offset| offset >= header_end)
+            .min()
+            .unwrap_or(self.data.len());
+        self.header_extra(header_end, first, special.unwrap_or(0) & 0x0002 != 0, cwtv.unwrap_or(0));
+
+        if let (Some(length), Some(offset)) = (msglength, msgoffset) {
+            if offset != 0 {
+                section(format_args!("message"));
+                let text = self.text(to_usize(offset), usize::from(length));
+                self.field(to_usize(offset), usize::from(length), "message", text.lines().next().unwrap_or(""));
+            }
+        }
+        for (idx, &offset) in instruments.iter().enumerate() {
+            self.instrument(idx + 1, to_usize(offset));
+        }
+        let mut data = Vec::new();
+        for (idx, &offset) in samples.iter().enumerate() {
+            data.extend(self.sample_header(idx + 1, to_usize(offset)));
+        }
+        for (idx, &offset) in patterns.iter().enumerate() {
+            self.pattern(idx, to_usize(offset));
+        }
+        for (idx, flags, offset, length) in data {
+            self.sample_data(idx, flags, offset, length);
+        }
+    }
+
+    fn header_extra(&self, start: usize, end: usize, history: bool, cwtv: u16) {
+        if start >= end || end > self.data.len() {
+            return;
+        }
+        section(format_args!("header extra data, {} bytes", end - start));
+        let mut offset = start;
+        if history {
+            if let Some(count) = self.u16(offset, "edit history entries") {
+                self.field(offset + 2, 8 * usize::from(count), "edit history", "");
+                offset += 2 + 8 * usize::from(count);
+            }
+        }
+        // OpenMPT writes its chunks as a 4-byte id followed by a 32-bit length.
+        while offset + 8 <= end {
+            let id = &self.data[offset..offset + 4];
+            let length = read_u32(self.data, offset + 4).map(to_usize).unwrap_or(0);
+            if !id.iter().all(u8::is_ascii_alphanumeric) || offset + 8 + length > end {
+                break;
+            }
+            self.field(offset, 8 + length, "chunk", format_args!("{} of {length} bytes", String::from_utf8_lossy(id)));
+            offset += 8 + length;
+        }
+        if offset < end {
+            let owner = if cwtv >> 12 == 0x5 { ", OpenMPT" } else { "" };
+            self.field(offset, end - offset, "unknown data", format_args!("{} bytes{owner}", end - offset));
+        }
+    }
+
+    fn instrument(&self, number: usize, offset: usize) {
+        section(format_args!("instrument {number:02}"));
+        if self.data.len() < offset + INSTRUMENT_SIZE {
+            self.field(offset, INSTRUMENT_SIZE, "instrument", "<past the end of the file>");
+            return;
+        }
+        self.field(offset, 4, "magic", self.text(offset, 4));
+        self.field(offset + 0x04, 12, "filename", self.text(offset + 0x04, 12));
+        self.u8(offset + 0x11, "new note action");
+        self.u8(offset + 0x12, "duplicate check type");
+        self.u8(offset + 0x13, "duplicate check action");
+        self.u16(offset + 0x14, "fadeout");
+        self.u8(offset + 0x16, "pitch-pan separation");
+        self.u8(offset + 0x17, "pitch-pan center");
+        self.u8(offset + 0x18, "global volume");
+        self.u8(offset + 0x19, "default panning");
+        self.u8(offset + 0x1A, "random volume");
+        self.u8(offset + 0x1B, "random panning");
+        self.u16(offset + 0x1C, "tracker version");
+        self.u8(offset + 0x1E, "number of samples");
+        self.field(offset + 0x20, 26, "name", self.text(offset + 0x20, 26));
+        self.u8(offset + 0x3A, "filter cutoff");
+        self.u8(offset + 0x3B, "filter resonance");
+        self.u8(offset + 0x3C, "midi channel");
+        self.u8(offset + 0x3D, "midi program");
+        self.u16(offset + 0x3E, "midi bank");
+        let mapped = self.data[offset + 0x40..offset + 0x130]
+            .chunks(2)
+            .filter(|entry| entry[1] != 0)
+            .count();
+        self.field(offset + 0x40, 240, "sample map", format_args!("{mapped} notes mapped"));
+        for (idx, name) in ["volume envelope", "panning envelope", "pitch envelope"].into_iter().enumerate() {
+            let envelope = offset + 0x130 + 82 * idx;
+            let nodes = self.data[envelope + 1];
+            let flags = self.flag_names(u16::from(self.data[envelope]), &ENVELOPE_FLAGS);
+            self.field(envelope, 82, name, format_args!("{nodes} nodes, {flags}"));
+        }
+    }
+
+    /// Returns the sample number, flags, offset and length of the sample data if present.
+    fn sample_header(&self, number: usize, offset: usize) -> Option<(usize, u8, usize, u32)> {
+        section(format_args!("sample {number:02}"));
+        if self.data.len() < offset + SAMPLE_HEADER_SIZE {
+            self.field(offset, SAMPLE_HEADER_SIZE, "sample header", "<past the end of the file>");
+            return None;
+        }
+        self.field(offset, 4, "magic", self.text(offset, 4));
+        self.field(offset + 0x04, 12, "filename", self.text(offset + 0x04, 12));
+        self.u8(offset + 0x10, "global volume");
+        let flags = self.flags8(offset + 0x11, "flags", &SAMPLE_FLAGS)?;
+        self.u8(offset + 0x12, "default volume");
+        self.field(offset + 0x14, 26, "name", self.text(offset + 0x14, 26));
+        self.flags8(offset + 0x2E, "convert", &["signed", "big endian", "delta", "byte delta", "12-bit", "prompt"]);
+        self.u8(offset + 0x2F, "default panning");
+        let length = self.u32(offset + 0x30, "length")?;
+        self.u32(offset + 0x34, "loop start");
+        self.u32(offset + 0x38, "loop end");
+        self.u32(offset + 0x3C, "C-5 sample rate");
+        self.u32(offset + 0x40, "sustain loop start");
+        self.u32(offset + 0x44, "sustain loop end");
+        let pointer = self.u32(offset + 0x48, "data offset")?;
+        self.u8(offset + 0x4C, "vibrato speed");
+        self.u8(offset + 0x4D, "vibrato depth");
+        self.u8(offset + 0x4E, "vibrato rate");
+        self.u8(offset + 0x4F, "vibrato type");
+        (flags & 0x01 != 0).then(|| (number, flags, to_usize(pointer), length))
+    }
+
+    fn pattern(&self, number: usize, offset: usize) {
+        section(format_args!("pattern {number}"));
+        if offset == 0 {
+            println!("  not stored, 64 empty rows");
+            return;
+        }
+        let Some(length) = self.u16(offset, "packed length") else {
+            return;
+        };
+        self.u16(offset + 2, "rows");
+        self.field(offset + 4, 4, "reserved", "");
+
+        let end = (offset + PATTERN_HEADER_SIZE + usize::from(length)).min(self.data.len());
+        let cells = match parser::pattern_cells::<VerboseError<&[u8]>>(&self.data[offset..end]) {
+            Ok(cells) => cells,
+            Err(Err::Error(e)) | Err(Err::Failure(e)) => return self.error(e),
+            Err(Err::Incomplete(_)) => unreachable!(),
+        };
+        let mut count = 0;
+        let mut channels = 0u64;
+        for cell in cells {
+            match cell {
+                Ok((_, channel, _)) => {
+                    count += 1;
+                    channels |= 1 << channel.as_usize();
+                }
+                Err(Err::Error(e)) | Err(Err::Failure(e)) => return self.error(e),
+                Err(Err::Incomplete(_)) => unreachable!(),
+            }
+        }
+        self.field(
+            offset + PATTERN_HEADER_SIZE,
+            usize::from(length),
+            "packed data",
+            format_args!("{count} commands in {} channels", channels.count_ones()),
+        );
+    }
+
+    fn sample_data(&self, number: usize, flags: u8, offset: usize, length: u32) {
+        section(format_args!("sample {number:02} data"));
+        let bits = if flags & 0x02 != 0 { 2 } else { 1 };
+        let bytes = if flags & 0x04 != 0 { 2 * bits } else { bits };
+        if flags & 0x08 != 0 {
+            // Compressed data is a sequence of blocks with a 16-bit length each.
+            let mut position = offset;
+            let mut blocks = 0;
+            while let Some(block) = read_u16(self.data, position) {
+                position += 2 + usize::from(block);
+                blocks += 1;
+                if blocks * 0x8000 / bytes >= to_usize(length) {
+                    break;
+                }
+            }
+            let size = position.min(self.data.len()).saturating_sub(offset);
+            self.field(offset, size, "compressed data", format_args!("{length} samples in {blocks} blocks"));
+        } else {
+            self.field(offset, to_usize(length) * bytes, "pcm data", format_args!("{length} samples"));
+        }
+    }
+
+    fn error(&self, error: VerboseError<&[u8]>) {
+        for (input, kind) in error.errors {
+            let offset = input.as_ptr() as usize - self.data.as_ptr() as usize;
+            println!("  {offset:08X}  error: {kind:?}");
+        }
+    }
+
+    /// Prints a field of `size` bytes at `offset`.
+    fn field(&self, offset: usize, size: usize, name: &str, value: impl Display) {
+        let raw = match self.data.get(offset..offset.saturating_add(size)) {
+            Some(bytes) if bytes.len() <= 8 => hex(bytes),
+            Some(bytes) => format!("{} ..", hex(&bytes[..8])),
+            None => String::from("<past the end of the file>"),
+        };
+        println!("  {offset:08X}  {raw:<26} {name:<24} {value}");
+    }
+
+    fn u8(&self, offset: usize, name: &str) -> Option<u8> {
+        let value = self.data.get(offset).copied();
+        self.field(offset, 1, name, display(value));
+        value
+    }
+
+    fn u16(&self, offset: usize, name: &str) -> Option<u16> {
+        let value = read_u16(self.data, offset);
+        self.field(offset, 2, name, display(value));
+        value
+    }
+
+    fn u32(&self, offset: usize, name: &str) -> Option<u32> {
+        let value = read_u32(self.data, offset);
+        self.field(offset, 4, name, display(value));
+        value
+    }
+
+    fn flags8(&self, offset: usize, name: &str, names: &[&str]) -> Option<u8> {
+        let value = self.data.get(offset).copied();
+        self.field(offset, 1, name, value.map(|value| self.flag_names(u16::from(value), names)).unwrap_or_default());
+        value
+    }
+
+    fn flags16(&self, offset: usize, name: &str, names: &[&str]) -> Option<u16> {
+        let value = read_u16(self.data, offset);
+        self.field(offset, 2, name, value.map(|value| self.flag_names(value, names)).unwrap_or_default());
+        value
+    }
+
+    fn offsets(&self, offset: usize, count: usize, name: &str) -> Vec<u32> {
+        (0..count)
+            .map_while(|idx| self.u32(offset + 4 * idx, name))
+            .collect()
+    }
+
+    /// Returns the names of the bits set in `value`, unknown bits in hex.
+    fn flag_names(&self, value: u16, names: &[&str]) -> String {
+        let mut set = (0..16)
+            .filter(|bit| value & (1 << bit) != 0)
+            .map(|bit| names.get(bit).map_or_else(|| format!("{:#06X}", 1 << bit), |name| name.to_string()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if set.is_empty() {
+            set.push_str("none");
+        }
+        set
+    }
+
+    /// Returns the bytes as text up to the first NUL, with the other control characters escaped.
+    fn text(&self, offset: usize, size: usize) -> String {
+        let bytes = self.data.get(offset..(offset + size).min(self.data.len())).unwrap_or_default();
+        let bytes = bytes.split(|&byte| byte == 0).next().unwrap_or_default();
+        format!("{:?}", String::from_utf8_lossy(bytes))
+    }
+
+    fn list(&self, offset: usize, size: usize) -> String {
+        let bytes = self.data.get(offset..(offset + size).min(self.data.len())).unwrap_or_default();
+        bytes.iter().map(u8::to_string).collect::<Vec<_>>().join(" ")
+    }
+}
+
+fn section(title: impl Display) {
+    println!("\n{title}");
+}
+
+fn display<T: Display>(value: Option<T>) -> String {
+    value.map_or_else(String::new, |value| value.to_string())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02X}")).collect::<Vec<_>>().join(" ")
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn to_usize(value: u32) -> usize {
+    usize::try_from(value).unwrap()
+}
@@ -0,0 +1,102 @@
+use anyhow::{bail, Context, Result};
+use ittech::convert::sfz;
+use ittech::error::{convert_error, VerboseError};
+use ittech::{cp437, parser, InstrumentId};
+use nom::Err;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+usage: cargo run --example itextract -- [options] <itmodule> <outputdir>
+
+Writes every sample with data to <outputdir>/NN_name.wav.
+
+options:
+    --iti   also write every instrument to <outputdir>/NN_name.iti
+    --sfz   also write every instrument to <outputdir>/NN_name/NN_name.sfz with its samples";
+
+fn main() -> Result<()> {
+    let mut iti = false;
+    let mut sfz = false;
+    let mut paths = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--iti" => iti = true,
+            "--sfz" => sfz = true,
+            _ if arg.starts_with("--") => bail!("unknown option {arg}\n\n{USAGE}"),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    let [inpname, outdir] = <[PathBuf; 2]>::try_from(paths).ok().context(USAGE)?;
+
+    let data = fs::read(&inpname)
+        .with_context(|| format!("failed to read file {}", inpname.display()))?;
+    let module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("parser failed\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+    fs::create_dir_all(&outdir)
+        .with_context(|| format!("failed to create directory {}", outdir.display()))?;
+
+    for (idx, sample) in module.samples.iter().enumerate() {
+        if sample.data.is_none() {
+            continue;
+        }
+        let name = file_name(idx + 1, &sample.name.decode(), &cp437::decode(sample.filename.as_bytes()), "sample");
+        let mut wav = Vec::new();
+        sample.write_wav(&mut wav)
+            .with_context(|| format!("failed to serialize sample {:02}", idx + 1))?;
+        write(&outdir.join(format!("{name}.wav")), &wav)?;
+    }
+
+    if !iti && !sfz {
+        return Ok(());
+    }
+    for (idx, instrument) in module.instruments.iter().enumerate() {
+        let name = file_name(idx + 1, &instrument.name.decode(), &cp437::decode(instrument.filename.as_bytes()), "instrument");
+        if iti {
+            let path = outdir.join(format!("{name}.iti"));
+            let bytes = instrument.write_iti(&module)
+                .with_context(|| format!("failed to serialize instrument {:02}", idx + 1))?;
+            write(&path, &bytes)?;
+        }
+        if sfz {
+            let id = InstrumentId::from_index(u8::try_from(idx)?)?;
+            let (sfz, report) = sfz::export(&module, id).unwrap();
+            let dir = outdir.join(&name);
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("failed to create directory {}", dir.display()))?;
+            write(&dir.join(format!("{name}.sfz")), sfz.text.as_bytes())?;
+            for (file_name, wav) in &sfz.samples {
+                write(&dir.join(file_name), wav)?;
+            }
+            for issue in &report.issues {
+                eprintln!("instrument {:02}: {issue}", idx + 1);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns `NN_name` with the characters which aren't safe in file names replaced, falls back to
+/// the DOS filename and then to `fallback` when the name is empty.
+fn file_name(number: usize, name: &str, filename: &str, fallback: &str) -> String {
+    let filename = filename.rsplit_once('.').map_or(filename, |(stem, _)| stem);
+    let mut sanitized = String::new();
+    for ch in [name, filename, fallback].into_iter().find(|name| !name.trim().is_empty()).unwrap().trim().chars() {
+        if ch.is_ascii_alphanumeric() || ch == '-' {
+            sanitized.push(ch);
+        } else if !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    format!("{number:02}_{}", sanitized.trim_matches('_'))
+}
+
+fn write(path: &Path, bytes: &[u8]) -> Result<()> {
+    fs::write(path, bytes).with_context(|| format!("failed to write file {}", path.display()))?;
+    println!("{}", path.display());
+    Ok(())
+}
@@ -0,0 +1,93 @@
+use anyhow::{bail, Context, Result};
+use ittech::error::{convert_error, VerboseError};
+use ittech::metadata::Metadata;
+use ittech::{parser, Module};
+use nom::Err;
+use std::{env, fs};
+
+const USAGE: &str = "usage: cargo run --example itinfo -- <itmodule>...";
+
+fn main() -> Result<()> {
+    let fnames = env::args().skip(1).collect::<Vec<_>>();
+    if fnames.is_empty() {
+        bail!(USAGE);
+    }
+
+    let mut failed = 0;
+    for fname in &fnames {
+        if let Err(error) = info(fname) {
+            eprintln!("{fname}: {error:#}\n");
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} files failed", fnames.len());
+    }
+    Ok(())
+}
+
+fn info(fname: &str) -> Result<()> {
+    let data = fs::read(fname)
+        .with_context(|| format!("failed to read file {fname}"))?;
+    let module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("parser failed\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+    let metadata = Metadata::new(&module);
+
+    println!("{fname} ({} bytes)", data.len());
+    println!("  title:      {}", metadata.title);
+    println!("  tracker:    {} (compatible with {})", tracker(module.made_with_version), tracker(module.compatible_with_version));
+    println!("  speed:      {}", metadata.speed);
+    println!("  tempo:      {}", metadata.tempo);
+    println!("  channels:   {}", metadata.channels);
+    println!("  orders:     {}", metadata.orders);
+    println!("  patterns:   {} ({} rows)", metadata.patterns, module.patterns.iter().map(|pattern| pattern.rows.len()).sum::<usize>());
+    println!("  duration:   {}", duration(metadata.duration));
+
+    println!("  instruments: {}", metadata.instruments.len());
+    for (idx, name) in metadata.instruments.iter().enumerate() {
+        println!("    {:02} {name}", idx + 1);
+    }
+
+    println!("  samples:    {} ({} bytes of sample data)", metadata.samples.len(), sample_bytes(&module));
+    for (idx, sample) in metadata.samples.iter().enumerate() {
+        let bytes = u64::from(sample.length) * u64::from(sample.bits / 8);
+        println!(
+            "    {:02} {:<26} {:>8} samples {:>2}-bit {:>9} bytes {:>6} Hz",
+            idx + 1,
+            sample.name,
+            sample.length,
+            sample.bits,
+            bytes,
+            sample.samplerate,
+        );
+    }
+    println!();
+    Ok(())
+}
+
+/// Guesses the tracker from a "Made With" or "Compatible With" version.
+fn tracker(version: u16) -> String {
+    let (major, minor) = ((version >> 8) & 0xF, version & 0xFF);
+    match version >> 12 {
+        0x0 => format!("Impulse Tracker {major:X}.{minor:02X}"),
+        0x1 => String::from("Schism Tracker"),
+        0x5 => format!("OpenMPT {major:X}.{minor:02X}"),
+        _ => format!("unknown tracker {version:04X}"),
+    }
+}
+
+fn duration(seconds: f64) -> String {
+    let seconds = seconds.round();
+    format!("{}:{:02}", (seconds / 60.0).floor(), seconds % 60.0)
+}
+
+fn sample_bytes(module: &Module) -> usize {
+    module.samples
+        .iter()
+        .filter_map(|sample| sample.data.as_ref())
+        .map(|data| data.len() * if data.is_16bit() { 2 } else { 1 })
+        .sum()
+}
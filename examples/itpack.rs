@@ -0,0 +1,119 @@
+use anyhow::{bail, Context, Result};
+use ittech::error::{convert_error, VerboseError};
+use ittech::optimize::{optimize, OptimizeOptions};
+use ittech::writer::{self, Compression, WriteOptions};
+use ittech::{parser, Module, SampleData};
+use nom::Err;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+const USAGE: &str = "\
+usage: cargo run --example itpack -- [options] (--out-dir <dir> | --in-place) <itmodule>...
+
+Rewrites the modules with IT 2.15 sample compression and without unused data.
+
+options:
+    --out-dir <dir>  write the packed modules to <dir>
+    --in-place       overwrite the modules
+    --8bit           convert 16-bit samples to 8-bit
+    --keep-unused    keep the unused patterns, instruments and samples";
+
+struct Stats {
+    before: usize,
+    after: usize,
+}
+
+fn main() -> Result<()> {
+    let mut out_dir = None;
+    let mut in_place = false;
+    let mut to_8bit = false;
+    let mut remove_unused = true;
+    let mut fnames = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out-dir" => out_dir = Some(PathBuf::from(args.next().context(USAGE)?)),
+            "--in-place" => in_place = true,
+            "--8bit" => to_8bit = true,
+            "--keep-unused" => remove_unused = false,
+            _ if arg.starts_with("--") => bail!("unknown option {arg}\n\n{USAGE}"),
+            _ => fnames.push(PathBuf::from(arg)),
+        }
+    }
+    if fnames.is_empty() || out_dir.is_some() == in_place {
+        bail!(USAGE);
+    }
+    if let Some(out_dir) = &out_dir {
+        fs::create_dir_all(out_dir)
+            .with_context(|| format!("failed to create directory {}", out_dir.display()))?;
+    }
+
+    let mut total = Stats { before: 0, after: 0 };
+    let mut failed = 0;
+    for fname in &fnames {
+        let output = match &out_dir {
+            Some(out_dir) => out_dir.join(fname.file_name().context("input is not a file")?),
+            None => fname.clone(),
+        };
+        match pack(fname, &output, to_8bit, remove_unused) {
+            Ok(stats) => {
+                println!("{}: {}", fname.display(), savings(&stats));
+                total.before += stats.before;
+                total.after += stats.after;
+            }
+            Err(error) => {
+                eprintln!("{}: {error:#}", fname.display());
+                failed += 1;
+            }
+        }
+    }
+    if fnames.len() > 1 {
+        println!("total: {}", savings(&total));
+    }
+    if failed > 0 {
+        bail!("{failed} of {} files failed", fnames.len());
+    }
+    Ok(())
+}
+
+fn pack(fname: &Path, output: &Path, to_8bit: bool, remove_unused: bool) -> Result<Stats> {
+    let data = fs::read(fname)
+        .with_context(|| format!("failed to read file {}", fname.display()))?;
+    let mut module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("parser failed\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+
+    let options = OptimizeOptions { remove_unused, ..OptimizeOptions::default() };
+    optimize(&mut module, &options);
+    if to_8bit {
+        convert_to_8bit(&mut module);
+    }
+
+    let write_options = WriteOptions { compression: Compression::It215, ..WriteOptions::default() };
+    let (packed, _) = writer::module_file_with_options(&module, &write_options)
+        .context("failed to write the module")?;
+    fs::write(output, &packed)
+        .with_context(|| format!("failed to write file {}", output.display()))?;
+    Ok(Stats { before: data.len(), after: packed.len() })
+}
+
+/// Converts the 16-bit samples to 8-bit, rounding to the nearest value.
+fn convert_to_8bit(module: &mut Module) {
+    for sample in &mut module.samples {
+        if let Some(SampleData::Pcm16(data)) = &sample.data {
+            let data = data.iter()
+                .map(|&value| i8::try_from(((i32::from(value) + 0x80) >> 8).min(127)).unwrap())
+                .collect::<Vec<_>>();
+            sample.data = Some(SampleData::from(data));
+        }
+    }
+}
+
+fn savings(stats: &Stats) -> String {
+    let saved = i64::try_from(stats.before).unwrap() - i64::try_from(stats.after).unwrap();
+    let percent = if stats.before == 0 { 0.0 } else { saved as f64 * 100.0 / stats.before as f64 };
+    format!("{} -> {} bytes, saved {saved} bytes ({percent:.1}%)", stats.before, stats.after)
+}
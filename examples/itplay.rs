@@ -0,0 +1,87 @@
+//! Plays a module on the default audio output with cpal
+//!
+//! The player is moved into the audio callback with [`ittech::player::realtime`], the main
+//! thread keeps its controller and sends it the commands read from the standard input.
+//!
+//! Commands: `m <channel>` mutes a channel, `u <channel>` unmutes it, `s <channel>` solos it,
+//! `t <scale>` sets the tempo scale and `q` stops.
+
+use anyhow::{bail, Context, Result};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use ittech::error::{convert_error, VerboseError};
+use ittech::player::{self, ControlMessage, Player, PlayerOptions};
+use ittech::{parser, Channel};
+use nom::Err;
+use std::io::{self, BufRead};
+use std::sync::mpsc;
+use std::{env, fs, thread};
+
+const USAGE: &str = "usage: cargo run --example itplay -- <itmodule>";
+
+fn main() -> Result<()> {
+    let fname = env::args().nth(1).context(USAGE)?;
+    let data = fs::read(&fname)
+        .with_context(|| format!("failed to read file {}", &fname))?;
+    let module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("parser failed\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+
+    let device = cpal::default_host().default_output_device().context("no audio output device")?;
+    // The player renders interleaved stereo `f32`, which most outputs accept at their default rate.
+    let sample_rate = device.default_output_config().context("failed to query the audio output")?.sample_rate();
+    let config = cpal::StreamConfig { channels: 2, sample_rate, buffer_size: cpal::BufferSize::Default };
+    let options = PlayerOptions { sample_rate: sample_rate.0, ..PlayerOptions::default() };
+    let (mut player, controller) = player::realtime(Player::new(module, options), 64);
+
+    let (finished, done) = mpsc::sync_channel(1);
+    let stream = device.build_output_stream(
+        &config,
+        move |out: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let frames = player.render_f32(out);
+            out[frames * 2..].fill(0.0);
+            if frames * 2 < out.len() {
+                // Only the first one is received, the others are dropped without blocking.
+                let _ = finished.try_send(());
+            }
+        },
+        |error| eprintln!("audio output failed: {error}"),
+        None,
+    ).context("failed to open the audio output")?;
+    stream.play().context("failed to start the audio output")?;
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(|line| line.ok()) {
+            match message(&line) {
+                Some(message) => {
+                    if !controller.send(message) {
+                        eprintln!("player is busy, try again");
+                    }
+                }
+                None => eprintln!("unknown command {line:?}"),
+            }
+        }
+    });
+
+    done.recv().context("audio output stopped")?;
+    Ok(())
+}
+
+/// Parses a command of the standard input.
+fn message(line: &str) -> Option<ControlMessage> {
+    let channel = |number: &str| {
+        number.parse().ok().filter(|number| (1..=64).contains(number)).map(Channel::new)
+    };
+
+    let mut words = line.split_whitespace();
+    let message = match (words.next()?, words.next()) {
+        ("m", Some(number)) => ControlMessage::SetChannelMuted(channel(number)?, true),
+        ("u", Some(number)) => ControlMessage::SetChannelMuted(channel(number)?, false),
+        ("s", Some(number)) => ControlMessage::SetChannelSolo(channel(number)?, true),
+        ("t", Some(scale)) => ControlMessage::SetTempoScale(scale.parse().ok()?),
+        ("q", None) => ControlMessage::Stop,
+        _ => return None,
+    };
+    Some(message)
+}
@@ -0,0 +1,236 @@
+//! Terminal pattern viewer
+//!
+//! Shows the patterns as a tracker grid. Playing runs the player in real time without audio
+//! output and the grid follows the row being played, channels flash when a note starts.
+//!
+//! Keys: space plays and pauses, up/down and page up/down scroll the rows, left/right move
+//! between the orders, `[`/`]` scroll the channels, `f` toggles following the playback and `q`
+//! quits.
+
+use anyhow::{bail, Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::style::{Attribute, Print, SetAttribute};
+use crossterm::{cursor, execute, queue, terminal};
+use ittech::analysis::Position;
+use ittech::error::{convert_error, VerboseError};
+use ittech::player::{EventKind, Player, PlayerOptions};
+use ittech::{parser, Channel, Command, Get, Module, Order, OrderIdx, Pattern};
+use nom::Err;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use std::{env, fs};
+
+const USAGE: &str = "usage: cargo run --example itview -- <itmodule>";
+
+/// Width of a cell, `C-5 01 v64 D04`
+const CELL_WIDTH: usize = 14;
+
+/// How long a channel header stays highlighted after a note
+const FLASH: Duration = Duration::from_millis(120);
+
+/// Pattern displayed for orders without one, e.g. separators
+const EMPTY: Pattern = Pattern { active_channels: ittech::ActiveChannels::empty(), rows: Vec::new() };
+
+fn main() -> Result<()> {
+    let fname = env::args().nth(1).context(USAGE)?;
+    let data = fs::read(&fname)
+        .with_context(|| format!("failed to read file {}", &fname))?;
+    let module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => bail!("parser failed\n\n{}", convert_error(&data, e)),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    };
+    if module.orders.is_empty() {
+        bail!("the module has no orders");
+    }
+
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let result = Viewer::new(module).run(&mut stdout);
+    execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+struct Viewer {
+    player: Player,
+    playing: bool,
+    follow: bool,
+
+    /// Time the player was last advanced to
+    clock: Instant,
+
+    /// Order and row at the centre of the grid
+    order: usize,
+    row: usize,
+
+    /// First displayed channel
+    first_channel: usize,
+
+    /// Time of the last note in each channel
+    notes: [Option<Instant>; 64],
+}
+
+impl Viewer {
+    fn new(module: Module) -> Viewer {
+        let options = PlayerOptions { events: true, ..PlayerOptions::default() };
+        Viewer {
+            player: Player::new(module, options),
+            playing: false,
+            follow: true,
+            clock: Instant::now(),
+            order: 0,
+            row: 0,
+            first_channel: 0,
+            notes: [None; 64],
+        }
+    }
+
+    fn run(&mut self, out: &mut impl Write) -> Result<()> {
+        loop {
+            self.draw(out)?;
+            if event::poll(Duration::from_millis(15))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind != KeyEventKind::Release && !self.key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+            self.advance();
+        }
+    }
+
+    /// Handles a key press, returns `false` to quit.
+    fn key(&mut self, code: KeyCode) -> bool {
+        let rows = self.pattern().rows.len();
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char(' ') => {
+                self.playing = !self.playing;
+                self.clock = Instant::now();
+            }
+            KeyCode::Char('f') => self.follow = !self.follow,
+            KeyCode::Up => self.scroll(-1),
+            KeyCode::Down => self.scroll(1),
+            KeyCode::PageUp => self.scroll(-16),
+            KeyCode::PageDown => self.scroll(16),
+            KeyCode::Left if self.order > 0 => self.goto(self.order - 1, 0),
+            KeyCode::Right if self.order + 1 < self.player.module().orders.len() => self.goto(self.order + 1, 0),
+            KeyCode::Home => self.row = 0,
+            KeyCode::End => self.row = rows.saturating_sub(1),
+            KeyCode::Char('[') => self.first_channel = self.first_channel.saturating_sub(1),
+            KeyCode::Char(']') => self.first_channel = (self.first_channel + 1).min(63),
+            _ => {}
+        }
+        true
+    }
+
+    /// Scrolls the rows by `delta` within the pattern and stops following the playback.
+    fn scroll(&mut self, delta: isize) {
+        let last = self.pattern().rows.len().saturating_sub(1);
+        self.row = self.row.saturating_add_signed(delta).min(last);
+        self.follow = false;
+    }
+
+    fn goto(&mut self, order: usize, row: usize) {
+        self.order = order;
+        self.row = row;
+        self.follow = false;
+    }
+
+    /// Renders the audio the player would have played since the last call and follows it.
+    fn advance(&mut self) {
+        let now = Instant::now();
+        if !self.playing || self.player.is_finished() {
+            self.clock = now;
+            return;
+        }
+        let sample_rate = f64::from(self.player.options().sample_rate);
+        // At most a second at a time so a stalled terminal doesn't render minutes at once.
+        let frames = ((now - self.clock).as_secs_f64() * sample_rate).min(sample_rate) as usize;
+        let mut buffer = vec![0.0; frames * 2];
+        self.player.render_f32(&mut buffer);
+        self.clock += Duration::from_secs_f64(frames as f64 / sample_rate);
+
+        for event in self.player.drain_events() {
+            match event.kind {
+                EventKind::Note { channel, .. } => self.notes[channel.as_usize()] = Some(now),
+                EventKind::Row(Position { order, row }) if self.follow => {
+                    self.order = order.as_usize();
+                    self.row = row;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn pattern(&self) -> &Pattern {
+        let module = self.player.module();
+        match module.orders.get(self.order) {
+            Some(Order::Index(pattern)) => module.get(*pattern).unwrap_or(&EMPTY),
+            _ => &EMPTY,
+        }
+    }
+
+    fn draw(&self, out: &mut impl Write) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (usize::from(width), usize::from(height));
+        let module = self.player.module();
+        let pattern = self.pattern();
+        let channels = ((width.saturating_sub(4)) / (CELL_WIDTH + 1)).max(1);
+        let last_channel = (self.first_channel + channels).min(64);
+
+        queue!(out, terminal::Clear(terminal::ClearType::All), cursor::MoveTo(0, 0))?;
+        let state = match (self.playing, self.follow) {
+            (true, true) => "playing, following",
+            (true, false) => "playing",
+            (false, _) => "paused",
+        };
+        let order = OrderIdx::new(u8::try_from(self.order).unwrap());
+        let title = match module.orders[self.order] {
+            Order::Index(pattern) => format!("order {order:?} pattern {pattern:?}"),
+            Order::Separator => format!("order {order:?} separator"),
+            Order::EndOfSong => format!("order {order:?} end of song"),
+        };
+        queue!(out, Print(format!("{}  {title}  row {}  [{state}]", module.name.decode(), self.row)))?;
+
+        // Channel numbers, flashing when a note starts.
+        queue!(out, cursor::MoveTo(0, 1), Print("   "))?;
+        let now = Instant::now();
+        for index in self.first_channel..last_channel {
+            let flash = self.notes[index].is_some_and(|time| now - time < FLASH);
+            if flash {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(out, Print(format!("|{:^width$}", index + 1, width = CELL_WIDTH)))?;
+            if flash {
+                queue!(out, SetAttribute(Attribute::NoReverse))?;
+            }
+        }
+
+        // Rows centred on the current one.
+        let visible = height.saturating_sub(2);
+        let first = self.row.saturating_sub(visible / 2);
+        let beat = usize::from(module.highlight.1).max(1);
+        for (line, row) in (first..pattern.rows.len()).take(visible).enumerate() {
+            let current = row == self.row;
+            queue!(out, cursor::MoveTo(0, u16::try_from(line + 2).unwrap()))?;
+            if current {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            } else if row % beat == 0 {
+                queue!(out, SetAttribute(Attribute::Bold))?;
+            }
+            let mut text = format!("{row:3}");
+            for index in self.first_channel..last_channel {
+                let channel = Channel::from_index(u8::try_from(index).unwrap()).unwrap();
+                let command = pattern.rows[row].get(channel).unwrap_or(&Command::EMPTY);
+                text.push('|');
+                text.push_str(&command.to_string());
+            }
+            queue!(out, Print(text), SetAttribute(Attribute::Reset))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
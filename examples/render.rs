@@ -49,7 +49,7 @@ fn resample(sample: &Sample, note: Note) -> Box<dyn Iterator<Item=f32> + '_> {
     let note_ratio = note.freq() / Note::C_5.freq();
     let sr_ratio = (SR as f32) / (sample.samplerate_c5 as f32);
     let incr = note_ratio / sr_ratio;
-    let table = sample.data.as_ref().unwrap();
+    let table = sample.data.as_ref().unwrap().to_f32();
     let mut offset = 0.0f32;
 
     Box::new(
@@ -94,8 +94,9 @@ fn render(module: Module) -> Result<Vec<f32>> {
                 match (*note, instrument) {
                     (Some(NoteCmd::Play(note)), Some(instrument)) => {
                         let instrument = &module[instrument];
-                        if let Some(sample) = instrument.sample_map[note] {
+                        if let Some(sample) = instrument.sample_map.sample_for(note) {
                             let sample = &module[sample];
+                            let note = instrument.sample_map.note_translation_for(note);
                             generators[chan.as_usize()] = Some(resample(sample, note));
                         }
                     }
@@ -0,0 +1,73 @@
+/*
+ * C API of ittech, an Impulse Tracker module parser and player
+ *
+ * Build the library with `cargo rustc --release --lib --crate-type cdylib --features ffi` and link
+ * against `libittech.so`, `libittech.dylib` or `ittech.dll`. See the documentation of the `ffi`
+ * module for the conventions of the functions.
+ */
+
+#ifndef ITTECH_H
+#define ITTECH_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Parsed module */
+typedef struct ItModule ItModule;
+
+/* Player of a module */
+typedef struct ItPlayer ItPlayer;
+
+/*
+ * Parses the module file in data[0..len], returns NULL if it's invalid. When parsing fails and
+ * error isn't NULL, *error is set to a message which has to be freed with ittech_string_free.
+ */
+ItModule *ittech_module_parse(const uint8_t *data, size_t len, char **error);
+void ittech_module_free(ItModule *module);
+void ittech_string_free(char *string);
+
+/*
+ * Copy the UTF-8 string into buf[0..size] NUL-terminated like snprintf, return the length of the
+ * whole string.
+ */
+size_t ittech_module_title(const ItModule *module, char *buf, size_t size);
+size_t ittech_module_message(const ItModule *module, char *buf, size_t size);
+
+uint8_t ittech_module_speed(const ItModule *module);
+uint8_t ittech_module_tempo(const ItModule *module);
+size_t ittech_module_order_count(const ItModule *module);
+size_t ittech_module_pattern_count(const ItModule *module);
+size_t ittech_module_instrument_count(const ItModule *module);
+size_t ittech_module_sample_count(const ItModule *module);
+
+/* Playing time in seconds until the song ends or starts repeating */
+double ittech_module_duration(const ItModule *module);
+
+/*
+ * Creates a player rendering at sample_rate Hz, NULL if the sample rate is zero. The player keeps
+ * its own reference to the module, the module can be freed while it plays.
+ */
+ItPlayer *ittech_player_new(const ItModule *module, uint32_t sample_rate);
+void ittech_player_free(ItPlayer *player);
+
+/*
+ * Render up to frames interleaved stereo frames into out, which holds 2 * frames samples, and
+ * return the number of rendered frames, fewer once the song has ended and 0 if the player failed.
+ */
+size_t ittech_player_render_f32(ItPlayer *player, float *out, size_t frames);
+size_t ittech_player_render_i16(ItPlayer *player, int16_t *out, size_t frames);
+
+/* Stores the order and row being played, returns false once the song has ended. */
+bool ittech_player_position(const ItPlayer *player, size_t *order, size_t *row);
+bool ittech_player_is_finished(const ItPlayer *player);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ITTECH_H */
@@ -0,0 +1,479 @@
+//! Module analysis
+//!
+//! Functions in this module compute properties of a module which aren't stored in the file but
+//! can be derived from it by simulating the playback.
+
+use crate::*;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+
+/// Position in the song
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    /// Position in the orders list
+    pub order: OrderIdx,
+
+    /// Row of the pattern played at `order`
+    pub row: usize,
+}
+
+/// Result of [`Module::duration`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SongDuration {
+    /// Playing time in seconds until the song ends or until it starts repeating
+    pub seconds: f64,
+
+    /// Position the song jumps back to when it's repeating forever, `None` if it ends
+    pub loop_start: Option<Position>,
+}
+
+/// Result of [`Module::channel_usage`] for a single channel
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChannelUsage {
+    /// Number of non-empty cells
+    pub commands: usize,
+
+    /// Number of played notes, note off, cut and fade are not counted
+    pub notes: usize,
+
+    /// Position of the first non-empty cell
+    pub first: Option<Position>,
+
+    /// Position of the last non-empty cell
+    pub last: Option<Position>,
+}
+
+/// Non-empty pattern cell played by the song, see [`Module::events`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    /// Time from the start of the song in seconds
+    pub time: f64,
+
+    /// Position the command is played at
+    pub position: Position,
+
+    pub channel: Channel,
+
+    pub command: Command,
+}
+
+impl ChannelUsage {
+    /// Returns `true` if the channel contains any command.
+    pub fn is_used(&self) -> bool {
+        self.commands > 0
+    }
+}
+
+/// Number of rows after which the simulation gives up, this is only reached for songs with
+/// pathological pattern loops since any other repetition is detected as a song loop.
+const MAX_ROWS: usize = 1 << 20;
+
+/// Length of rows played from an order which references a missing pattern
+///
+/// Impulse Tracker plays those as empty 64 row patterns.
+pub(crate) const MISSING_PATTERN_ROWS: usize = 64;
+
+/// Pattern loop (`SBx`) state of a channel
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub(crate) struct PatternLoop {
+    /// Row the loop jumps back to
+    pub(crate) start: usize,
+
+    /// Repetitions left, 0 when not looping
+    pub(crate) remaining: u8,
+}
+
+impl PatternLoop {
+    /// Applies `SBx` on `row`, returns the row to jump back to.
+    pub(crate) fn loopback(&mut self, row: usize, times: u8) -> Option<usize> {
+        if self.remaining == 0 {
+            self.remaining = times;
+            return Some(self.start);
+        }
+        self.remaining -= 1;
+        if self.remaining > 0 {
+            Some(self.start)
+        } else {
+            // Impulse Tracker continues the next loop after the finished one.
+            self.start = row + 1;
+            None
+        }
+    }
+}
+
+/// Row visited by [`Module::simulate`]
+pub(crate) struct PlayedRow<'m> {
+    /// Position in the orders list
+    pub(crate) order: OrderIdx,
+
+    /// Row of the pattern
+    pub(crate) row: usize,
+
+    /// Commands of the row, `None` for rows of missing patterns
+    pub(crate) commands: Option<&'m Row>,
+
+    /// Speed the row is played at, the first tick of every repetition of the row (`SEx`) is a
+    /// multiple of it
+    pub(crate) speed: u32,
+
+    /// Tempo of each tick of the row, including the ones added by row and tick delays
+    pub(crate) tempos: Vec<u32>,
+}
+
+impl Module {
+    /// Computes how long the song plays
+    ///
+    /// The orders list is simulated from its start, honoring speed (`Axx`) and tempo (`Txx`)
+    /// changes, order jumps (`Bxx`), pattern breaks (`Cxx`), pattern loops (`SBx`) and row and tick
+    /// delays (`SEx`, `S6x`). The song ends on the end of the orders list or at an end of song
+    /// order (`---`).
+    ///
+    /// The `Sxx` commands follow Impulse Tracker: every channel has its own loop start and
+    /// counter, a finished loop moves the loop start to the row after it and leaving the pattern
+    /// forgets the loops. A loop is played before an order jump or a pattern break on the same
+    /// row. Only the first row delay of a row is used, tick delays add up and a row repeated by a
+    /// row delay counts its loop once. `S00` repeats the last `Sxx` of the channel.
+    ///
+    /// When the song reaches a row which has been already played (outside of a pattern loop), it
+    /// would repeat forever. The duration then includes everything played before the repetition
+    /// and [`SongDuration::loop_start`] is the position the song repeats from.
+    pub fn duration(&self) -> SongDuration {
+        let mut seconds = 0.0f64;
+        let loop_start = self.simulate(|played| {
+            seconds += played.tempos.iter().map(|&tempo| 2.5 / f64::from(tempo)).sum::<f64>();
+        });
+        SongDuration { seconds, loop_start }
+    }
+
+    /// Simulates the playback of the song as described in [`Module::duration`], calling `visit`
+    /// for each played row
+    ///
+    /// Returns the position the song repeats from, `None` if it ends.
+    pub(crate) fn simulate<'m>(&'m self, mut visit: impl FnMut(PlayedRow<'m>)) -> Option<Position> {
+        let mut speed = u32::from(self.speed.as_u8());
+        let mut tempo = u32::from(self.tempo.as_u8());
+        let mut tempo_slides = [None; 64];
+
+        let mut visited = HashSet::new();
+        let mut loops = [PatternLoop::default(); 64];
+        let mut special_memory = [0u8; 64];
+
+        let mut position = (self.next_order(0)?, 0);
+
+        for _ in 0..MAX_ROWS {
+            let (order, row) = position;
+            let in_loop = loops.iter().any(|state| state.remaining > 0);
+            if !in_loop && !visited.insert(position) {
+                return Some(Position { order, row });
+            }
+
+            let pattern = self.pattern_at(order);
+            let rows = pattern.map_or(MISSING_PATTERN_ROWS, |pattern| pattern.rows.len());
+
+            let mut jump_order = None;
+            let mut break_row = None;
+            let mut loop_row = None;
+            let mut row_delay = None;
+            let mut tick_delay = 0;
+
+            let commands = pattern.and_then(|pattern| pattern.rows.as_slice().get(row));
+            for (channel, command) in commands.into_iter().flat_map(Row::iter) {
+                let effect = match command.effect {
+                    Some(effect) => effect,
+                    None => continue,
+                };
+                // `S00` repeats the last `Sxx` of the channel, e.g. `SB2` loops again.
+                let (number, param) = writer::effect(&effect);
+                let effect = if number != b'S' - b'@' {
+                    effect
+                } else if param == 0 {
+                    match parser::effect(number, special_memory[channel.as_usize()]) {
+                        Some(effect) => effect,
+                        None => continue,
+                    }
+                } else {
+                    special_memory[channel.as_usize()] = param;
+                    effect
+                };
+                match effect {
+                    EffectCmd::SetSpeed(xx) => speed = u32::from(xx.as_u8()),
+                    EffectCmd::Tempo(Some(Tempo::Set(xx))) => tempo = u32::from(xx.as_u8()),
+                    EffectCmd::Tempo(Some(slide)) => tempo_slides[channel.as_usize()] = Some(slide),
+                    EffectCmd::JumpOrder(xx) => jump_order = Some(xx),
+                    EffectCmd::BreakRow(xx) => break_row = Some(usize::from(xx)),
+                    EffectCmd::Special(Some(Special::SetLoopbackPoint)) => {
+                        loops[channel.as_usize()].start = row;
+                    }
+                    EffectCmd::Special(Some(Special::LoopbackTimes(x))) => {
+                        if let Some(start) = loops[channel.as_usize()].loopback(row, x.as_u8()) {
+                            loop_row = Some(start);
+                        }
+                    }
+                    EffectCmd::Special(Some(Special::PatternRowDelay(x))) => {
+                        // Only the first row delay on a row is used.
+                        row_delay.get_or_insert(u32::from(x.as_u8()));
+                    }
+                    EffectCmd::Special(Some(Special::PatternTickDelay(x))) => {
+                        tick_delay += u32::from(x.as_u8());
+                    }
+                    _ => {}
+                }
+            }
+
+            // Tempo slides are active only on the rows where the effect is present (`T00` reuses
+            // the slide stored in `tempo_slides`).
+            let slides = commands
+                .into_iter()
+                .flat_map(Row::iter)
+                .filter(|(_, command)| matches!(
+                    command.effect,
+                    Some(EffectCmd::Tempo(Some(Tempo::SlideUp(_) | Tempo::SlideDown(_))) | EffectCmd::Tempo(None))
+                ))
+                .filter_map(|(channel, _)| tempo_slides[channel.as_usize()])
+                .collect::<Vec<_>>();
+
+            let ticks = speed * (1 + row_delay.unwrap_or(0)) + tick_delay;
+            let mut tempos = Vec::with_capacity(usize::try_from(ticks).unwrap());
+            for tick in 0..ticks {
+                if tick % speed != 0 {
+                    for slide in &slides {
+                        tempo = match slide {
+                            Tempo::SlideUp(x) => (tempo + u32::from(x.as_u8())).min(255),
+                            Tempo::SlideDown(x) => tempo.saturating_sub(u32::from(x.as_u8())).max(32),
+                            Tempo::Set(_) => tempo,
+                        };
+                    }
+                }
+                tempos.push(tempo);
+            }
+            visit(PlayedRow { order, row, commands, speed, tempos });
+
+            position = if let Some(loop_row) = loop_row {
+                (order, loop_row)
+            } else if jump_order.is_some() || break_row.is_some() {
+                // Loops are forgotten when leaving the pattern.
+                loops = [PatternLoop::default(); 64];
+                match self.next_order(jump_order.map_or(order.as_usize() + 1, OrderIdx::as_usize)) {
+                    Some(next) => (next, break_row.unwrap_or(0)),
+                    None => return None,
+                }
+            } else if row + 1 < rows {
+                (order, row + 1)
+            } else {
+                loops = [PatternLoop::default(); 64];
+                match self.next_order(order.as_usize() + 1) {
+                    Some(next) => (next, 0),
+                    None => return None,
+                }
+            };
+
+            // Breaking to a row past the end of the pattern plays the first row instead.
+            if let Some(pattern) = self.pattern_at(position.0) {
+                if position.1 >= pattern.rows.len() {
+                    position.1 = 0;
+                }
+            } else if position.1 >= MISSING_PATTERN_ROWS {
+                position.1 = 0;
+            }
+        }
+
+        None
+    }
+
+    /// Lists the commands in the order they're played
+    ///
+    /// The song is played as described in [`Module::duration`] until it ends or starts repeating.
+    /// Rows repeated by pattern loops are listed every time they're played, empty cells are left
+    /// out.
+    pub fn events(&self) -> Vec<Event> {
+        let mut events = Vec::new();
+        let mut time = 0.0f64;
+        self.simulate(|played| {
+            let position = Position { order: played.order, row: played.row };
+            for (channel, command) in played.commands.into_iter().flat_map(Row::iter) {
+                events.push(Event { time, position, channel, command: *command });
+            }
+            time += played.tempos.iter().map(|&tempo| 2.5 / f64::from(tempo)).sum::<f64>();
+        });
+        events
+    }
+
+    /// Computes how each of the 64 channels is used
+    ///
+    /// Patterns are scanned in the order they appear in the orders list (without following jumps),
+    /// patterns which aren't in the orders list are not counted. A pattern is counted for every
+    /// order it appears on.
+    pub fn channel_usage(&self) -> [ChannelUsage; 64] {
+        let mut usage = [ChannelUsage::default(); 64];
+
+        for (order, pattern) in self.orders.iter().enumerate() {
+            let pattern = match pattern {
+                Order::Index(pattern) => self.get(pattern),
+                _ => None,
+            };
+            let (order, pattern) = match (u8::try_from(order).ok(), pattern) {
+                (Some(order), Some(pattern)) => (OrderIdx::new(order), pattern),
+                _ => continue,
+            };
+
+            for (row, commands) in pattern.rows.iter().enumerate() {
+                let position = Position { order, row };
+                for (channel, command) in commands.iter() {
+                    let usage = &mut usage[channel.as_usize()];
+                    usage.commands += 1;
+                    if matches!(command.note, Some(NoteCmd::Play(_))) {
+                        usage.notes += 1;
+                    }
+                    usage.first.get_or_insert(position);
+                    usage.last = Some(position);
+                }
+            }
+        }
+
+        usage
+    }
+
+    /// Returns the first order at or after `order` which plays a pattern, skipping separators
+    ///
+    /// Returns `None` if the song ends before reaching such order. Only the first 256 orders are
+    /// considered, Impulse Tracker can't play any further.
+    pub(crate) fn next_order(&self, mut order: usize) -> Option<OrderIdx> {
+        loop {
+            let index = u8::try_from(order).ok()?;
+            match self.orders.as_slice().get(order)? {
+                Order::Index(_) => return Some(OrderIdx::new(index)),
+                Order::Separator => order += 1,
+                Order::EndOfSong => return None,
+            }
+        }
+    }
+
+    /// Returns the pattern played at `order`, `None` for other orders and missing patterns
+    pub(crate) fn pattern_at(&self, order: OrderIdx) -> Option<&Pattern> {
+        match self.get(order)? {
+            Order::Index(pattern) => self.get(pattern),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    fn module(rows: Vec<Row>) -> Module {
+        const MODULE_DATA: &[u8] = include_bytes!("../tests/song_message.it");
+
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(MODULE_DATA).unwrap();
+        module.set_speed(6).unwrap();
+        module.set_tempo(125).unwrap();
+        module.orders = vec![Order::Index(PatternId::from_index(0).unwrap()), Order::EndOfSong];
+        module.patterns = vec![Pattern {
+            active_channels: ActiveChannels::all(),
+            rows,
+        }];
+        module
+    }
+
+    fn effect(effect: EffectCmd) -> Row {
+        Row::from_vec(vec![(Channel::new(1), Command { effect: Some(effect), ..Command::EMPTY })])
+    }
+
+    /// Returns a row with the effects `(channel, letter, param)`.
+    fn effects(effects: &[(u8, u8, u8)]) -> Row {
+        Row::from_vec(effects.iter().map(|&(channel, letter, param)| {
+            (Channel::new(channel), Command { effect: parser::effect(letter - b'@', param), ..Command::EMPTY })
+        }).collect())
+    }
+
+    /// Returns the `(order, row)` positions played by the song.
+    fn played(module: &Module) -> Vec<(usize, usize)> {
+        let mut played = Vec::new();
+        module.simulate(|row| played.push((row.order.as_usize(), row.row)));
+        played
+    }
+
+    #[test]
+    fn channel_usage() {
+        let mut rows = vec![Row::empty(); 64];
+        rows[3] = effect(EffectCmd::SetSpeed(RangedU8::new(3)));
+        rows[9] = effect(EffectCmd::BreakRow(0));
+        let usage = module(rows).channel_usage();
+
+        assert_eq!(usage[0].commands, 2);
+        assert_eq!(usage[0].notes, 0);
+        assert_eq!(usage[0].first.map(|pos| pos.row), Some(3));
+        assert_eq!(usage[0].last.map(|pos| pos.row), Some(9));
+        assert!(usage[1..].iter().all(|usage| !usage.is_used()));
+    }
+
+    #[test]
+    fn duration() {
+        // 64 rows * 6 ticks * 2.5 / 125 seconds
+        let song = module(vec![Row::empty(); 64]).duration();
+        assert!((song.seconds - 7.68).abs() < 1e-9);
+        assert_eq!(song.loop_start, None);
+
+        // Doubling the speed on the first row halves the duration.
+        let mut rows = vec![Row::empty(); 64];
+        rows[0] = effect(EffectCmd::SetSpeed(RangedU8::new(3)));
+        let song = module(rows).duration();
+        assert!((song.seconds - 3.84).abs() < 1e-9);
+
+        // Jumping back to the start after 32 rows loops forever.
+        let mut rows = vec![Row::empty(); 64];
+        rows[31] = effect(EffectCmd::JumpOrder(OrderIdx::new(0)));
+        let song = module(rows).duration();
+        assert!((song.seconds - 3.84).abs() < 1e-9);
+        assert_eq!(song.loop_start, Some(Position { order: OrderIdx::new(0), row: 0 }));
+    }
+
+    #[test]
+    fn pattern_loops() {
+        let rows = |played: Vec<(usize, usize)>| played.into_iter().map(|(_, row)| row).collect::<Vec<_>>();
+
+        // A loop without `SB0` after a finished loop starts on the row after it.
+        let mut pattern = vec![Row::empty(); 4];
+        pattern[1] = effects(&[(1, b'S', 0xB0)]);
+        pattern[2] = effects(&[(1, b'S', 0xB1)]);
+        pattern[3] = effects(&[(1, b'S', 0xB1)]);
+        assert_eq!(rows(played(&module(pattern))), [0, 1, 2, 1, 2, 3, 3]);
+
+        // Each channel counts its own repetitions, the channel which finishes first starts looping
+        // again from the row after its loop.
+        let mut pattern = vec![Row::empty(); 4];
+        pattern[1] = effects(&[(1, b'S', 0xB1), (2, b'S', 0xB2)]);
+        assert_eq!(rows(played(&module(pattern))), [0, 1, 0, 1, 0, 1, 2, 3]);
+
+        // `S00` repeats the loop of the channel.
+        let mut pattern = vec![Row::empty(); 4];
+        pattern[1] = effects(&[(1, b'S', 0xB1)]);
+        pattern[3] = effects(&[(1, b'S', 0x00)]);
+        assert_eq!(rows(played(&module(pattern))), [0, 1, 0, 1, 2, 3, 2, 3]);
+
+        // The loop is played before a pattern break on the same row, leaving the pattern forgets
+        // the loops so the next order loops back to its first row.
+        let mut pattern = vec![Row::empty(); 4];
+        pattern[2] = effects(&[(1, b'S', 0xB1), (2, b'C', 0x01)]);
+        let mut module = module(pattern);
+        module.orders.insert(1, Order::Index(PatternId::from_index(0).unwrap()));
+        assert_eq!(played(&module), [
+            (0, 0), (0, 1), (0, 2), (0, 0), (0, 1), (0, 2),
+            (1, 1), (1, 2), (1, 0), (1, 1), (1, 2),
+        ]);
+
+        // A row delay repeats the row without counting the loop again, only the first row delay
+        // of the row is used and tick delays add up.
+        let mut pattern = vec![Row::empty(); 4];
+        pattern[1] = effects(&[(1, b'S', 0xB1), (2, b'S', 0xE1), (3, b'S', 0xE3), (4, b'S', 0x62), (5, b'S', 0x61)]);
+        let module = module(pattern);
+        assert_eq!(rows(played(&module)), [0, 1, 0, 1, 2, 3]);
+        // 4 rows of 6 ticks and 2 rows of 15 ticks
+        assert!((module.duration().seconds - 54.0 * 0.02).abs() < 1e-9);
+    }
+}
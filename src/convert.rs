@@ -0,0 +1,209 @@
+//! Conversion between Impulse Tracker modules and other tracker formats
+//!
+//! Each format has its own module. The conversions work on the [`Module`] data model, features
+//! which can't be converted (exactly) are listed in a [`Report`] instead of failing the
+//! conversion.
+
+use crate::*;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+
+pub mod csv;
+pub mod dot;
+pub mod midi;
+pub mod mptm;
+pub mod protracker;
+pub mod s3m;
+pub mod sf2;
+pub mod sfz;
+pub mod xm;
+
+
+/// Features which were lost or approximated by a conversion
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub issues: Vec<Issue>,
+}
+
+/// Single feature which couldn't be converted exactly
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// Module-wide setting the target format doesn't have, e.g. `"global volume"`
+    ModuleFeature { feature: &'static str },
+
+    /// Instrument setting the target format doesn't have, e.g. `"new note action"`
+    ///
+    /// `instrument` is the instrument of the source module.
+    InstrumentFeature { instrument: InstrumentId, feature: &'static str },
+
+    /// Sample setting the target format doesn't have, e.g. `"sustain loop"`
+    ///
+    /// `sample` is the sample of the source module.
+    SampleFeature { sample: SampleId, feature: &'static str },
+
+    /// Channels above `max` were dropped
+    ChannelsDropped { channels: usize, max: usize },
+
+    /// Items (orders, patterns, instruments, samples) above `max` were dropped
+    ItemsDropped { kind: &'static str, count: usize, max: usize },
+
+    /// Rows after `max` were cut off the pattern
+    RowsDropped { pattern: PatternId, rows: usize, max: usize },
+
+    /// Notes outside of the range of the target format were dropped from the pattern
+    NotesDropped { pattern: PatternId, count: usize },
+
+    /// Effects (or volume column commands) without an equivalent were dropped from the pattern
+    EffectsDropped { pattern: PatternId, count: usize },
+
+    /// Effects (or volume column commands) were replaced with ones which behave differently in
+    /// some cases
+    EffectsApproximated { pattern: PatternId, count: usize },
+}
+
+impl Report {
+    /// Returns `true` if the conversion didn't lose anything.
+    pub fn is_lossless(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    pub(crate) fn push(&mut self, issue: Issue) {
+        self.issues.push(issue);
+    }
+
+    /// Adds the per-pattern counts if they are not zero.
+    pub(crate) fn pattern_counts(&mut self, pattern: PatternId, counts: &PatternCounts) {
+        if counts.notes_dropped > 0 {
+            self.push(Issue::NotesDropped { pattern, count: counts.notes_dropped });
+        }
+        if counts.effects_dropped > 0 {
+            self.push(Issue::EffectsDropped { pattern, count: counts.effects_dropped });
+        }
+        if counts.effects_approximated > 0 {
+            self.push(Issue::EffectsApproximated { pattern, count: counts.effects_approximated });
+        }
+    }
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::ModuleFeature { feature } => write!(f, "{} is not supported", feature),
+            Issue::InstrumentFeature { instrument, feature } => {
+                write!(f, "instrument {}: {} is not supported", instrument, feature)
+            }
+            Issue::SampleFeature { sample, feature } => write!(f, "sample {}: {} is not supported", sample, feature),
+            Issue::ChannelsDropped { channels, max } => {
+                write!(f, "module uses {} channels, channels above {} were dropped", channels, max)
+            }
+            Issue::ItemsDropped { kind, count, max } => {
+                write!(f, "module has {} {}, the ones above {} were dropped", count, kind, max)
+            }
+            Issue::RowsDropped { pattern, rows, max } => {
+                write!(f, "pattern {:?} has {} rows, the ones after {} were dropped", pattern, rows, max)
+            }
+            Issue::NotesDropped { pattern, count } => {
+                write!(f, "pattern {:?}: {} notes out of range were dropped", pattern, count)
+            }
+            Issue::EffectsDropped { pattern, count } => {
+                write!(f, "pattern {:?}: {} unsupported effects were dropped", pattern, count)
+            }
+            Issue::EffectsApproximated { pattern, count } => {
+                write!(f, "pattern {:?}: {} effects were approximated", pattern, count)
+            }
+        }
+    }
+}
+
+
+/// Lossy conversions counted while converting a pattern
+#[derive(Default)]
+pub(crate) struct PatternCounts {
+    pub(crate) notes_dropped: usize,
+    pub(crate) effects_dropped: usize,
+    pub(crate) effects_approximated: usize,
+}
+
+/// Result of converting a single pattern command
+pub(crate) enum Converted<T> {
+    Exact(T),
+    Approximated(T),
+    Dropped,
+}
+
+impl<T> Converted<T> {
+    /// Returns the converted value, counting the lossy conversions into `counts`.
+    pub(crate) fn count(self, counts: &mut PatternCounts) -> Option<T> {
+        match self {
+            Converted::Exact(value) => Some(value),
+            Converted::Approximated(value) => {
+                counts.effects_approximated += 1;
+                Some(value)
+            }
+            Converted::Dropped => {
+                counts.effects_dropped += 1;
+                None
+            }
+        }
+    }
+
+    /// Converts the value further, `None` from `f` drops it.
+    pub(crate) fn and_then<U>(self, f: impl FnOnce(T) -> Option<U>) -> Converted<U> {
+        match self {
+            Converted::Exact(value) => f(value).map_or(Converted::Dropped, Converted::Exact),
+            Converted::Approximated(value) => f(value).map_or(Converted::Dropped, Converted::Approximated),
+            Converted::Dropped => Converted::Dropped,
+        }
+    }
+}
+
+/// Null-padded text field, cut to `N` bytes
+pub(crate) fn text<const N: usize>(bytes: &[u8]) -> [u8; N] {
+    let mut field = [0; N];
+    let len = bytes.len().min(N);
+    field[..len].copy_from_slice(&bytes[..len]);
+    field
+}
+
+/// Name from a null-padded text field, cut to 25 bytes
+pub(crate) fn name(bytes: &[u8]) -> Name {
+    let mut field = text::<26>(null_terminated(bytes));
+    field[25] = 0;
+    Name { bytes: field }
+}
+
+fn null_terminated(bytes: &[u8]) -> &[u8] {
+    let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len());
+    &bytes[..len]
+}
+
+/// Converts the C-5 frequency of a sample into the pitch relative to `base` in semitones
+pub(crate) fn semitones_from(samplerate_c5: u32, base: u32) -> f64 {
+    if samplerate_c5 == 0 {
+        return 0.0;
+    }
+    12.0 * (f64::from(samplerate_c5) / f64::from(base)).log2()
+}
+
+/// Converts a pitch relative to `base` in semitones into the C-5 frequency of a sample
+pub(crate) fn samplerate_from(semitones: f64, base: u32) -> u32 {
+    round_clamp(f64::from(base) * (semitones / 12.0).exp2(), 0, 9_999_999)
+}
+
+/// Rounds `value` to the nearest integer, saturating at the limits of `i64`
+#[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+pub(crate) fn round_i64(value: f64) -> i64 {
+    // Float to integer `as` conversions saturate.
+    value.round() as i64
+}
+
+/// Rounds `value` and clamps it into `low..=high`
+pub(crate) fn round_clamp<T: TryFrom<i64>>(value: f64, low: T, high: T) -> T
+where
+    i64: From<T>,
+    T: Copy,
+{
+    let value = round_i64(value).clamp(i64::from(low), i64::from(high));
+    T::try_from(value).unwrap_or(low)
+}
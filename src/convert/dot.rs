@@ -0,0 +1,175 @@
+//! Graphviz graphs of the song structure (.dot)
+//!
+//! [`export`] draws the orders list as a directed graph for visualizing the arrangement. Every
+//! order playing a pattern is a node labelled with its position and the pattern number, separators
+//! are left out. Orders playing a pattern which is used more than once share a fill colour, orders
+//! the playback never reaches are drawn dashed.
+//!
+//! Edges show where the playback continues after the pattern:
+//!
+//! - plain edges lead to the next order,
+//! - bold edges are order jumps (`Bxx`) and dashed edges pattern breaks (`Cxx`), labelled with the
+//!   row of the pattern they're on and the effects,
+//! - a blue edge labelled `loop` marks where the song starts repeating, as found by simulating the
+//!   playback like [`Module::duration`].
+//!
+//! The jumps are found by scanning the patterns, the first row with a jump or a break ends the
+//! pattern. Pattern loops (`SBx`) are not followed.
+
+use crate::*;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+
+/// Fill colours for the patterns used more than once, repeated when there are more patterns
+const PALETTE: [&str; 8] = ["lightblue", "lightpink", "palegreen", "khaki", "plum", "lightsalmon", "lightcyan", "wheat"];
+
+/// Converts the song structure of `module` into a Graphviz DOT graph
+///
+/// See the [module documentation](self) for what's drawn.
+pub fn export(module: &Module) -> String {
+    let mut played = HashSet::new();
+    let mut last = None;
+    let loop_start = module.simulate(|row| {
+        played.insert(row.order.as_usize());
+        last = Some(row.order.as_usize());
+    });
+
+    let mut uses = HashMap::<PatternId, usize>::new();
+    for order in &module.orders {
+        if let Order::Index(pattern) = order {
+            *uses.entry(*pattern).or_default() += 1;
+        }
+    }
+    let mut colours = HashMap::new();
+
+    // Writing into a `String` never fails.
+    let mut text = String::new();
+    writeln!(text, "digraph song {{").unwrap();
+    writeln!(text, "    label={};", quote(&module.name.decode())).unwrap();
+    writeln!(text, "    rankdir=LR;").unwrap();
+    writeln!(text, "    node [shape=box];").unwrap();
+
+    let mut edges = String::new();
+    let mut ends = false;
+    for (idx, order) in module.orders.iter().enumerate() {
+        let Order::Index(pattern) = order else { continue };
+
+        let mut style = Vec::new();
+        let mut attributes = String::new();
+        if uses[pattern] > 1 {
+            let next_colour = colours.len() % PALETTE.len();
+            let colour = *colours.entry(*pattern).or_insert(PALETTE[next_colour]);
+            style.push("filled");
+            write!(attributes, ", fillcolor={}", colour).unwrap();
+        }
+        if !played.contains(&idx) {
+            style.push("dashed");
+            attributes.push_str(", fontcolor=gray");
+        }
+        if !style.is_empty() {
+            write!(attributes, ", style=\"{}\"", style.join(",")).unwrap();
+        }
+        let missing = if module.get(pattern).is_some() { "" } else { " (missing)" };
+        writeln!(text, "    o{} [label=\"{}\\nP{}{}\"{}];", idx, idx, pattern.as_u8(), missing, attributes).unwrap();
+
+        let mut target = |order: usize| match module.next_order(order) {
+            Some(next) => format!("o{}", next.as_usize()),
+            None => {
+                ends = true;
+                "end".to_string()
+            }
+        };
+        let rows = module.get(pattern).map_or(&[][..], |pattern| &pattern.rows[..]);
+        let jump = rows.iter().enumerate().find_map(|(row, commands)| {
+            let mut jump_order = None;
+            let mut effects = Vec::new();
+            for (_, command) in commands.iter() {
+                match command.effect {
+                    Some(effect @ EffectCmd::JumpOrder(xx)) => {
+                        jump_order = Some(xx.as_usize());
+                        effects.push(effect);
+                    }
+                    Some(effect @ EffectCmd::BreakRow(_)) => effects.push(effect),
+                    _ => {}
+                }
+            }
+            (!effects.is_empty()).then_some((row, jump_order, effects))
+        });
+        match jump {
+            Some((row, jump_order, effects)) => {
+                let label = effects.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ");
+                let style = if jump_order.is_some() { "bold" } else { "dashed" };
+                let target = target(jump_order.unwrap_or(idx + 1));
+                writeln!(edges, "    o{} -> {} [label=\"{}: {}\", style={}];", idx, target, row, label, style).unwrap();
+            }
+            None => {
+                let target = target(idx + 1);
+                writeln!(edges, "    o{} -> {};", idx, target).unwrap();
+            }
+        }
+    }
+    if ends {
+        writeln!(text, "    end [shape=doublecircle];").unwrap();
+    }
+
+    text.push_str(&edges);
+    if let (Some(start), Some(last)) = (loop_start, last) {
+        let label = match start.row {
+            0 => "loop".to_string(),
+            row => format!("loop to row {}", row),
+        };
+        writeln!(text, "    o{} -> o{} [label=\"{}\", color=blue, fontcolor=blue, constraint=false];", last, start.order.as_usize(), label).unwrap();
+    }
+    writeln!(text, "}}").unwrap();
+    text
+}
+
+/// Quotes a string as a DOT ID.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    fn cell(effect: EffectCmd) -> Row {
+        Row::from_vec(vec![(Channel::new(1), Command { effect: Some(effect), ..Command::EMPTY })])
+    }
+
+    #[test]
+    fn arrangement() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        module.name = Name::new("Song");
+        let pattern = |idx| Order::Index(PatternId::from_index(idx).unwrap());
+        module.orders = vec![pattern(0), pattern(1), Order::Separator, pattern(0), pattern(2), pattern(1), Order::EndOfSong];
+        module.patterns = vec![
+            Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 4] },
+            Pattern { active_channels: ActiveChannels::all(), rows: vec![Row::empty(), cell(EffectCmd::BreakRow(2)), Row::empty()] },
+            Pattern { active_channels: ActiveChannels::all(), rows: vec![cell(EffectCmd::JumpOrder(OrderIdx::new(1))), Row::empty()] },
+        ];
+
+        assert_eq!(export(&module), concat!(
+            "digraph song {\n",
+            "    label=\"Song\";\n",
+            "    rankdir=LR;\n",
+            "    node [shape=box];\n",
+            "    o0 [label=\"0\\nP0\", fillcolor=lightblue, style=\"filled\"];\n",
+            "    o1 [label=\"1\\nP1\", fillcolor=lightpink, style=\"filled\"];\n",
+            "    o3 [label=\"3\\nP0\", fillcolor=lightblue, style=\"filled\"];\n",
+            "    o4 [label=\"4\\nP2\"];\n",
+            "    o5 [label=\"5\\nP1\", fillcolor=lightpink, fontcolor=gray, style=\"filled,dashed\"];\n",
+            "    end [shape=doublecircle];\n",
+            "    o0 -> o1;\n",
+            "    o1 -> o3 [label=\"1: C02\", style=dashed];\n",
+            "    o3 -> o4;\n",
+            "    o4 -> o1 [label=\"0: B01\", style=bold];\n",
+            "    o5 -> end [label=\"1: C02\", style=dashed];\n",
+            "    o4 -> o1 [label=\"loop\", color=blue, fontcolor=blue, constraint=false];\n",
+            "}\n",
+        ));
+    }
+}
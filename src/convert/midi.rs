@@ -0,0 +1,1135 @@
+//! Standard MIDI Files (.mid)
+//!
+//! [`export`] renders the song into a type 1 MIDI file. The playback is simulated from the start
+//! of the orders list (following jumps, breaks, pattern loops and delays) until the song ends or
+//! starts repeating, so the file plays the song once.
+//!
+//! One MIDI tick is one module tick. The division is the initial speed times the rows per beat
+//! highlight, so the beats of the file line up with the beats of the module as long as the speed
+//! doesn't change, and every tempo change becomes a tempo event in the first track. IT note
+//! numbers are used as MIDI keys, IT `C-5` is key 60.
+//!
+//! Each track plays on its own MIDI channel, the percussion channel 10 is skipped and more than
+//! 15 tracks share the channels again. A note is played until the next note, note off or note
+//! cut in the same channel, New Note Actions are not simulated. Note delays (`SDx`), note cuts
+//! (`SCx`) and panning (volume column, `Xxx`, `S8x`) are converted, the other effects are listed
+//! in the [`Report`].
+//!
+//! [`import`] quantizes the notes of a MIDI file to the nearest row, with the speed and the rows
+//! per quarter note chosen in the [`ImportOptions`]. Tempo events become `Txx` effects in a
+//! channel after the notes. The notes of each track and MIDI channel are spread over as many
+//! module channels as they need to play all notes at once. Every MIDI program (and the drums on
+//! channel 10) becomes an instrument without samples, with the program stored as its MIDI
+//! program. Controllers, pitch bends and other messages are listed in the [`Report`].
+
+use super::{name, Issue, PatternCounts, Report};
+use crate::analysis::PlayedRow;
+use crate::error::ContextError;
+use crate::*;
+use nom::bytes::complete::{tag, take};
+use nom::error::ParseError;
+use nom::number::complete::{be_u16, be_u32, be_u8};
+use nom::sequence::tuple;
+use nom::{Err, IResult};
+use std::collections::{BTreeMap, HashSet};
+use std::convert::TryFrom;
+
+
+/// Options for [`export`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MidiOptions {
+    /// How the notes are split into tracks
+    pub tracks: TrackMapping,
+
+    /// How the volume of the notes is converted
+    pub volume: VolumeMapping,
+
+    /// How the MIDI programs of the tracks are chosen
+    pub programs: ProgramMapping,
+}
+
+/// How the notes are split into tracks, see [`MidiOptions::tracks`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrackMapping {
+    /// One track for each module channel
+    ///
+    /// The program changes whenever the channel plays another instrument, the channel volume and
+    /// panning are set at the start of the track.
+    #[default]
+    Channel,
+
+    /// One track for each instrument (or sample in modules without instruments)
+    ///
+    /// Notes of all channels are merged, this is usually easier to arrange.
+    Instrument,
+}
+
+/// How the volume of the notes is converted, see [`MidiOptions::volume`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VolumeMapping {
+    /// The volume (volume column or default volume of the sample) becomes the velocity
+    #[default]
+    Velocity,
+
+    /// Notes have a fixed velocity, the volume is sent as Expression (CC 11) before each note and
+    /// on every volume column volume
+    Expression,
+
+    /// Notes have a fixed velocity and the volume is dropped
+    Ignore,
+}
+
+/// How the MIDI programs of the tracks are chosen, see [`MidiOptions::programs`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgramMapping {
+    /// Instrument (or sample) number minus one, modulo 128
+    #[default]
+    Index,
+
+    /// MIDI program and bank of the instrument, instruments without a program fall back to
+    /// [`ProgramMapping::Index`]
+    Instrument,
+
+    /// The same program for all instruments
+    Fixed(u8),
+}
+
+
+/// Velocity of notes when the volume isn't converted to it
+const DEFAULT_VELOCITY: u8 = 100;
+
+/// Channel reserved for percussion in General MIDI, `10` counted from one
+const PERCUSSION_CHANNEL: u8 = 9;
+
+const DEFAULT_ROWS_PER_BEAT: u32 = 4;
+const MAX_DIVISION: u32 = 0x7FFF;
+
+/// Longest quarter note in microseconds the tempo event can store
+const MAX_QUARTER_LENGTH: u64 = 0xFF_FFFF;
+
+/// Channel messages
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+const PROGRAM_CHANGE: u8 = 0xC0;
+
+/// Controllers
+const BANK_SELECT: u8 = 0;
+const CHANNEL_VOLUME: u8 = 7;
+const PAN: u8 = 10;
+const EXPRESSION: u8 = 11;
+const BANK_SELECT_LSB: u8 = 32;
+
+/// Meta events
+const META: u8 = 0xFF;
+const TRACK_NAME: u8 = 0x03;
+const END_OF_TRACK: u8 = 0x2F;
+const SET_TEMPO: u8 = 0x51;
+
+
+/// Event at `time`, events at the same time are sorted by `priority` to end notes before
+/// starting new ones
+struct Event {
+    time: u64,
+    priority: u8,
+    data: Vec<u8>,
+}
+
+struct Track {
+    name: String,
+    channel: u8,
+
+    /// Program and bank sent last
+    program: Option<(u8, Option<u16>)>,
+    events: Vec<Event>,
+}
+
+impl Track {
+    fn push(&mut self, time: u64, data: Vec<u8>) {
+        self.events.push(Event { time, priority: 1, data });
+    }
+
+    fn control_change(&mut self, time: u64, controller: u8, value: u8) {
+        self.push(time, vec![CONTROL_CHANGE | self.channel, controller, value.min(127)]);
+    }
+
+    fn note_off(&mut self, time: u64, key: u8) {
+        self.events.push(Event { time, priority: 0, data: vec![NOTE_OFF | self.channel, key, 0] });
+    }
+}
+
+/// State of a module channel
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    instrument: Option<InstrumentId>,
+
+    /// Track and key of the playing note
+    playing: Option<(usize, u8)>,
+}
+
+struct Exporter<'m> {
+    module: &'m Module,
+    options: MidiOptions,
+
+    /// Tracks by their channel or instrument index
+    tracks: BTreeMap<usize, Track>,
+    tempo_events: Vec<Event>,
+    channels: [ChannelState; 64],
+    time: u64,
+}
+
+
+/// Renders the song into a Standard MIDI File (.mid) of type 1
+///
+/// See the [module documentation](self) for how the song is converted.
+pub fn export(module: &Module, options: MidiOptions) -> (Vec<u8>, Report) {
+    let mut report = Report::default();
+    let rows_per_beat = match u32::from(module.highlight.1) {
+        0 => DEFAULT_ROWS_PER_BEAT,
+        rows => rows,
+    };
+    let division = (u32::from(module.speed.as_u8()) * rows_per_beat).clamp(1, MAX_DIVISION);
+
+    let mut exporter = Exporter {
+        module,
+        options,
+        tracks: BTreeMap::new(),
+        tempo_events: Vec::new(),
+        channels: [ChannelState::default(); 64],
+        time: 0,
+    };
+    let mut counted = HashSet::new();
+    let mut last_tempo = None;
+    module.simulate(|played| {
+        if let Some(Order::Index(pattern)) = module.get(played.order) {
+            if counted.insert(*pattern) {
+                let mut counts = PatternCounts::default();
+                count_dropped(&module[pattern], &mut counts);
+                report.pattern_counts(*pattern, &counts);
+            }
+        }
+        for (time, &tempo) in (exporter.time..).zip(&played.tempos) {
+            if last_tempo != Some(tempo) {
+                last_tempo = Some(tempo);
+                let quarter = (u64::from(division) * 2_500_000 / u64::from(tempo)).min(MAX_QUARTER_LENGTH);
+                let mut data = vec![META, SET_TEMPO, 3];
+                data.extend_from_slice(&quarter.to_be_bytes()[5..]);
+                exporter.tempo_events.push(Event { time, priority: 1, data });
+            }
+        }
+        exporter.row(&played);
+    });
+    exporter.stop_all();
+
+    let mut conductor = Track {
+        name: module.name.decode().trim().to_string(),
+        channel: 0,
+        program: None,
+        events: exporter.tempo_events,
+    };
+    let end = exporter.time;
+    let tracks = std::iter::once(&mut conductor).chain(exporter.tracks.values_mut()).collect::<Vec<_>>();
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"MThd");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    // Format 1, simultaneous tracks.
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&u16::try_from(tracks.len()).unwrap().to_be_bytes());
+    out.extend_from_slice(&u16::try_from(division).unwrap().to_be_bytes());
+    for track in tracks {
+        write_track(&mut out, track, end);
+    }
+    (out, report)
+}
+
+impl Exporter<'_> {
+    fn row(&mut self, played: &PlayedRow) {
+        let start = self.time;
+        for (channel, command) in played.commands.into_iter().flat_map(Row::iter) {
+            let index = channel.as_usize();
+            if let Some(instrument) = command.instrument {
+                self.channels[index].instrument = Some(instrument);
+            }
+
+            let mut delay = 0;
+            let mut cut = None;
+            let mut panning = None;
+            match &command.effect {
+                Some(EffectCmd::Special(Some(Special::NoteDelay(x)))) => delay = u32::from(x.as_u8()),
+                Some(EffectCmd::Special(Some(Special::NoteCut(x)))) => cut = Some(u64::from(x.as_u8()).max(1)),
+                Some(EffectCmd::Special(Some(Special::SetPanning(x)))) => panning = Some(x.as_u8() * 17 / 2),
+                Some(EffectCmd::SetPanningPosition(xx)) => panning = Some(xx / 2),
+                _ => {}
+            }
+            // Delays longer than the row skip the note.
+            if delay >= played.speed {
+                continue;
+            }
+            let time = start + u64::from(delay);
+
+            let volume = match command.volume {
+                Some(VolumeCmd::SetVolume(volume)) => Some(volume.as_u8()),
+                Some(VolumeCmd::Panning(position)) => {
+                    panning = Some(scale(position.as_u8()));
+                    None
+                }
+                _ => None,
+            };
+
+            match command.note {
+                Some(NoteCmd::Play(note)) => {
+                    self.stop(index, time);
+                    if let Some(instrument) = self.channels[index].instrument {
+                        self.start(index, instrument, note, volume, time);
+                    }
+                }
+                Some(NoteCmd::Off | NoteCmd::Cut | NoteCmd::Fade) => self.stop(index, time),
+                None => {
+                    if let (Some(volume), VolumeMapping::Expression) = (volume, self.options.volume) {
+                        if let Some(track) = self.current_track(index) {
+                            track.control_change(time, EXPRESSION, scale(volume));
+                        }
+                    }
+                }
+            }
+            if let Some(panning) = panning {
+                if let Some(track) = self.current_track(index) {
+                    track.control_change(time, PAN, panning);
+                }
+            }
+            if let Some(cut) = cut {
+                self.stop(index, start + cut);
+            }
+        }
+        self.time += u64::try_from(played.tempos.len()).unwrap();
+    }
+
+    fn start(&mut self, index: usize, instrument: InstrumentId, note: Note, volume: Option<u8>, time: u64) {
+        let volume = volume.unwrap_or_else(|| self.default_volume(instrument, note));
+        let velocity = match self.options.volume {
+            VolumeMapping::Velocity => scale(volume).max(1),
+            VolumeMapping::Expression | VolumeMapping::Ignore => DEFAULT_VELOCITY,
+        };
+        let program = self.program(instrument);
+        let key = u8::from(note);
+
+        let options = self.options;
+        let track_index = match options.tracks {
+            TrackMapping::Channel => index,
+            TrackMapping::Instrument => instrument.as_usize(),
+        };
+        let track = self.track(track_index, instrument);
+        if track.program != Some(program) {
+            let (number, bank) = program;
+            if let Some(bank) = bank {
+                track.control_change(time, BANK_SELECT, (bank >> 7).to_le_bytes()[0]);
+                track.control_change(time, BANK_SELECT_LSB, (bank & 0x7F).to_le_bytes()[0]);
+            }
+            track.push(time, vec![PROGRAM_CHANGE | track.channel, number]);
+            track.program = Some(program);
+        }
+        if options.volume == VolumeMapping::Expression {
+            track.control_change(time, EXPRESSION, scale(volume));
+        }
+        track.push(time, vec![NOTE_ON | track.channel, key, velocity]);
+        self.channels[index].playing = Some((track_index, key));
+    }
+
+    /// Ends the note playing in the channel.
+    fn stop(&mut self, index: usize, time: u64) {
+        if let Some((track, key)) = self.channels[index].playing.take() {
+            if let Some(track) = self.tracks.get_mut(&track) {
+                track.note_off(time, key);
+            }
+        }
+    }
+
+    fn stop_all(&mut self) {
+        for index in 0..self.channels.len() {
+            self.stop(index, self.time);
+        }
+    }
+
+    /// Track the channel plays to, `None` if it hasn't played anything yet
+    fn current_track(&mut self, index: usize) -> Option<&mut Track> {
+        let track = match self.options.tracks {
+            TrackMapping::Channel => index,
+            TrackMapping::Instrument => self.channels[index].playing?.0,
+        };
+        self.tracks.get_mut(&track)
+    }
+
+    /// Returns the track with `index`, creating it on the next free MIDI channel.
+    fn track(&mut self, index: usize, instrument: InstrumentId) -> &mut Track {
+        let count = self.tracks.len();
+        let module = self.module;
+        let tracks = self.options.tracks;
+        self.tracks.entry(index).or_insert_with(|| {
+            // Tracks use the channels 0..=15 without the percussion channel.
+            let channel = u8::try_from(count % 15).unwrap();
+            let channel = if channel >= PERCUSSION_CHANNEL { channel + 1 } else { channel };
+            let mut track = Track { name: String::new(), channel, program: None, events: Vec::new() };
+            match tracks {
+                TrackMapping::Channel => {
+                    track.name = format!("Channel {}", index + 1);
+                    let settings = &module.channels[index];
+                    track.control_change(0, CHANNEL_VOLUME, scale(settings.volume.as_u8()));
+                    if let Some(position) = settings.pan_position() {
+                        track.control_change(0, PAN, scale(position.as_u8()));
+                    }
+                }
+                TrackMapping::Instrument => {
+                    let name = instrument_name(module, instrument);
+                    track.name = if name.is_empty() { format!("Instrument {}", instrument.number()) } else { name };
+                }
+            }
+            track
+        })
+    }
+
+    fn program(&self, instrument: InstrumentId) -> (u8, Option<u16>) {
+        let index = u8::try_from(instrument.as_usize() % 128).unwrap();
+        match self.options.programs {
+            ProgramMapping::Index => (index, None),
+            ProgramMapping::Fixed(program) => (program.min(127), None),
+            ProgramMapping::Instrument => {
+                let uses_instruments = self.module.flags.contains(ModuleFlags::USE_INSTRUMENTS);
+                let instrument = self.module
+                    .get(instrument)
+                    .filter(|instrument| uses_instruments && instrument.mpr < 128);
+                match instrument {
+                    Some(instrument) => {
+                        // Banks are stored as a 14-bit number, larger values mean no bank.
+                        let bank = u16::from_le_bytes(instrument.mbank);
+                        (instrument.mpr, (bank < 0x4000).then_some(bank))
+                    }
+                    None => (index, None),
+                }
+            }
+        }
+    }
+
+    /// Default volume of the sample playing `note`
+    fn default_volume(&self, instrument: InstrumentId, note: Note) -> u8 {
+        let sample = if self.module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            self.module
+                .get(instrument)
+                .and_then(|instrument| instrument.sample_map.sample_for(note))
+                .and_then(|sample| self.module.get(sample))
+        } else {
+            self.module.samples.get(instrument.as_usize())
+        };
+        sample.map_or(64, |sample| sample.default_volume.min(64))
+    }
+}
+
+/// Name of the instrument, or of the sample in modules without instruments
+fn instrument_name(module: &Module, instrument: InstrumentId) -> String {
+    let name = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        module.get(instrument).map(|instrument| &instrument.name)
+    } else {
+        module.samples.get(instrument.as_usize()).map(|sample| &sample.name)
+    };
+    name.map_or_else(String::new, |name| name.decode().trim().to_string())
+}
+
+/// Counts the commands which aren't converted.
+fn count_dropped(pattern: &Pattern, counts: &mut PatternCounts) {
+    for (_, command) in pattern.rows.iter().flat_map(Row::iter) {
+        if !matches!(command.volume, None | Some(VolumeCmd::SetVolume(_) | VolumeCmd::Panning(_))) {
+            counts.effects_dropped += 1;
+        }
+        let converted = matches!(
+            command.effect,
+            None | Some(
+                EffectCmd::SetSpeed(_)
+                    | EffectCmd::Tempo(_)
+                    | EffectCmd::JumpOrder(_)
+                    | EffectCmd::BreakRow(_)
+                    | EffectCmd::SetPanningPosition(_)
+                    | EffectCmd::Special(Some(
+                        Special::SetLoopbackPoint
+                            | Special::LoopbackTimes(_)
+                            | Special::PatternRowDelay(_)
+                            | Special::PatternTickDelay(_)
+                            | Special::NoteCut(_)
+                            | Special::NoteDelay(_)
+                            | Special::SetPanning(_)
+                    ))
+            )
+        );
+        if !converted {
+            counts.effects_dropped += 1;
+        }
+    }
+}
+
+/// Scales a volume or panning `0..=64` to a MIDI value `0..=127`
+fn scale(value: u8) -> u8 {
+    u8::try_from((u16::from(value.min(64)) * 127 + 32) / 64).unwrap()
+}
+
+fn write_track(out: &mut Vec<u8>, track: &mut Track, end: u64) {
+    let mut data = Vec::new();
+    if !track.name.is_empty() {
+        write_variable(&mut data, 0);
+        data.extend_from_slice(&[META, TRACK_NAME]);
+        write_variable(&mut data, u64::try_from(track.name.len()).unwrap());
+        data.extend_from_slice(track.name.as_bytes());
+    }
+
+    // The sort is stable, events keep their order within the same time and priority.
+    track.events.sort_by_key(|event| (event.time, event.priority));
+    let mut time = 0;
+    for event in &track.events {
+        write_variable(&mut data, event.time - time);
+        data.extend_from_slice(&event.data);
+        time = event.time;
+    }
+    write_variable(&mut data, end.saturating_sub(time));
+    data.extend_from_slice(&[META, END_OF_TRACK, 0]);
+
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&u32::try_from(data.len()).unwrap().to_be_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// Writes a variable-length quantity, 7 bits per byte starting from the most significant ones
+fn write_variable(out: &mut Vec<u8>, value: u64) {
+    let mut bytes = vec![(value & 0x7F).to_le_bytes()[0]];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push(0x80 | (value & 0x7F).to_le_bytes()[0]);
+        value >>= 7;
+    }
+    out.extend(bytes.into_iter().rev());
+}
+
+
+/// Options for [`import`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportOptions {
+    /// Ticks per row of the module
+    pub speed: u8,
+
+    /// Tempo of the module, `None` follows the tempo of the file
+    ///
+    /// With a fixed tempo the rows still follow the beats of the file, the tempo changes of the
+    /// file are lost.
+    pub tempo: Option<u8>,
+
+    /// Rows per quarter note, notes are quantized to the nearest row
+    pub rows_per_beat: u8,
+
+    /// Rows of each pattern, at most 200
+    pub pattern_rows: u8,
+}
+
+impl Default for ImportOptions {
+    fn default() -> ImportOptions {
+        ImportOptions { speed: 6, tempo: None, rows_per_beat: 4, pattern_rows: 64 }
+    }
+}
+
+
+const MAX_IT_CHANNELS: usize = 64;
+const MAX_IT_PATTERNS: usize = 200;
+const MAX_IT_ITEMS: usize = 99;
+const MAX_PATTERN_ROWS: u8 = 200;
+
+/// Highest key which is an IT note, `B-9`
+const MAX_KEY: u8 = 119;
+
+/// Tempo of files without tempo events, 120 BPM
+const DEFAULT_QUARTER_LENGTH: u32 = 500_000;
+
+/// Bit of the division saying it's in SMPTE frames instead of ticks per quarter note
+const SMPTE_DIVISION: u16 = 0x8000;
+
+
+/// Event of a MIDI track, messages which aren't imported are only named
+enum RawEvent<'i> {
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    NoteOff { channel: u8, key: u8 },
+    Program { channel: u8, program: u8 },
+
+    /// Length of a quarter note in microseconds
+    Tempo(u32),
+    TrackName(&'i [u8]),
+    Unsupported(&'static str),
+}
+
+/// Note with the start and end in rows
+struct MidiNote {
+    track: usize,
+    channel: u8,
+    program: u8,
+    key: u8,
+    velocity: u8,
+    start: u64,
+    end: u64,
+}
+
+
+/// Reads a Standard MIDI File (.mid) into a [`Module`]
+///
+/// See the [module documentation](self) for how the file is converted.
+pub fn import<'i, E>(input: &'i [u8], options: ImportOptions) -> Result<(Module, Report), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut report = Report::default();
+    let speed = options.speed.max(1);
+    let rows_per_beat = u64::from(options.rows_per_beat.max(1));
+    let pattern_rows = options.pattern_rows.clamp(1, MAX_PATTERN_ROWS);
+
+    let (mut input, (track_count, division)) = context!(header, "in MIDI header")(input)?;
+    let mut tracks = Vec::with_capacity(usize::from(track_count));
+    while tracks.len() < usize::from(track_count) && !input.is_empty() {
+        let (rest, (id, data)) = chunk(input)?;
+        input = rest;
+        // Unknown chunks are skipped.
+        if id == b"MTrk" {
+            let idx = tracks.len();
+            let (_, events) = context!(track, "in track {}", idx)(data)?;
+            tracks.push(events);
+        }
+    }
+
+    // Files in SMPTE time are read as if they were at 120 BPM.
+    let ticks_per_beat = if division & SMPTE_DIVISION != 0 {
+        let [frames, ticks] = division.to_be_bytes();
+        let frames = u64::from(frames.wrapping_neg());
+        (frames * u64::from(ticks) / 2).max(1)
+    } else {
+        u64::from(division).max(1)
+    };
+    let row_at = |tick: u64| (2 * tick * rows_per_beat + ticks_per_beat) / (2 * ticks_per_beat);
+
+    let mut notes = Vec::new();
+    let mut tempos = Vec::new();
+    let mut track_names = Vec::new();
+    for (track_idx, events) in tracks.iter().enumerate() {
+        let mut programs = [0; 16];
+        let mut playing = Vec::<(u8, u8, u64, u8)>::new();
+        let mut name = None;
+        let mut last = 0;
+        for &(time, ref event) in events {
+            last = time;
+            match *event {
+                RawEvent::NoteOn { channel, key, velocity } => playing.push((channel, key, time, velocity)),
+                RawEvent::NoteOff { channel, key } => {
+                    // Overlapping notes of the same key end in the order they started.
+                    if let Some(idx) = playing.iter().position(|&(c, k, _, _)| (c, k) == (channel, key)) {
+                        let (_, _, start, velocity) = playing.remove(idx);
+                        let program = programs[usize::from(channel)];
+                        notes.push(MidiNote { track: track_idx, channel, program, key, velocity, start, end: time });
+                    }
+                }
+                RawEvent::Program { channel, program } => programs[usize::from(channel)] = program,
+                RawEvent::Tempo(quarter) => tempos.push((time, quarter)),
+                RawEvent::TrackName(bytes) => {
+                    name.get_or_insert(bytes);
+                }
+                RawEvent::Unsupported(feature) => {
+                    let issue = Issue::ModuleFeature { feature };
+                    if !report.issues.contains(&issue) {
+                        report.push(issue);
+                    }
+                }
+            }
+        }
+        // Notes still playing end with the track.
+        for (channel, key, start, velocity) in playing {
+            let program = programs[usize::from(channel)];
+            notes.push(MidiNote { track: track_idx, channel, program, key, velocity, start, end: last });
+        }
+        track_names.push(name);
+    }
+    for note in &mut notes {
+        note.start = row_at(note.start);
+        note.end = row_at(note.end).max(note.start + 1);
+    }
+    notes.sort_by_key(|note| (note.start, note.track, note.channel, note.key));
+
+    // Tempo changes on the same row are merged, the last one is used.
+    tempos.sort_by_key(|&(time, _)| time);
+    let tempo_of = |quarter: u32| {
+        let tempo = (2_500_000 * rows_per_beat * u64::from(speed) + u64::from(quarter) / 2) / u64::from(quarter.max(1));
+        u8::try_from(tempo.clamp(32, 255)).unwrap()
+    };
+    let mut tempo_changes = Vec::<(u64, u8)>::new();
+    if division & SMPTE_DIVISION == 0 {
+        for &(time, quarter) in &tempos {
+            let row = row_at(time);
+            match tempo_changes.last_mut() {
+                Some(last) if last.0 == row => last.1 = tempo_of(quarter),
+                _ => tempo_changes.push((row, tempo_of(quarter))),
+            }
+        }
+    }
+    let initial_tempo = match tempo_changes.first() {
+        Some(&(0, tempo)) => tempo,
+        _ => tempo_of(DEFAULT_QUARTER_LENGTH),
+    };
+    let tempo = options.tempo.map_or(initial_tempo, |tempo| tempo.max(32));
+    tempo_changes.retain(|&(row, _)| row > 0);
+    if options.tempo.is_some() && !tempo_changes.is_empty() {
+        report.push(Issue::ModuleFeature { feature: "tempo changes" });
+        tempo_changes.clear();
+    }
+
+    let total_rows = notes
+        .iter()
+        .map(|note| note.end + 1)
+        .chain(tempo_changes.last().map(|&(row, _)| row + 1))
+        .max()
+        .unwrap_or(1);
+    let pattern_count = usize::try_from((total_rows + u64::from(pattern_rows) - 1) / u64::from(pattern_rows)).unwrap();
+    if pattern_count > MAX_IT_PATTERNS {
+        report.push(Issue::ItemsDropped { kind: "patterns", count: pattern_count, max: MAX_IT_PATTERNS });
+    }
+    let pattern_count = pattern_count.min(MAX_IT_PATTERNS);
+    let max_rows = u64::try_from(pattern_count).unwrap() * u64::from(pattern_rows);
+    let pattern_of = |row: u64| usize::try_from(row / u64::from(pattern_rows)).unwrap();
+    let mut counts = (0..pattern_count).map(|_| PatternCounts::default()).collect::<Vec<_>>();
+
+    // One placeholder instrument for each program and the drums.
+    let mut programs = Vec::<(bool, u8)>::new();
+    let mut instrument_for = |note: &MidiNote| {
+        let key = (note.channel == PERCUSSION_CHANNEL, note.program);
+        match programs.iter().position(|&program| program == key) {
+            Some(idx) => Some(idx),
+            None if programs.len() < MAX_IT_ITEMS => {
+                programs.push(key);
+                Some(programs.len() - 1)
+            }
+            None => None,
+        }
+    };
+
+    // Notes of a track and MIDI channel get as many module channels as they play at once.
+    let mut voices = BTreeMap::<(usize, u8), Vec<(usize, u64)>>::new();
+    let mut used_channels = 0;
+    let mut note_ons = BTreeMap::<(u64, usize), Command>::new();
+    let mut note_offs = Vec::new();
+    let mut instruments_dropped = false;
+    for note in &notes {
+        if note.start >= max_rows {
+            continue;
+        }
+        let pattern_counts = &mut counts[pattern_of(note.start)];
+        if note.key > MAX_KEY {
+            pattern_counts.notes_dropped += 1;
+            continue;
+        }
+        let Some(instrument) = instrument_for(note) else {
+            instruments_dropped = true;
+            pattern_counts.notes_dropped += 1;
+            continue;
+        };
+        let group = voices.entry((note.track, note.channel)).or_default();
+        let channel = match group.iter_mut().find(|(_, busy)| *busy <= note.start) {
+            Some(voice) => {
+                voice.1 = note.end;
+                voice.0
+            }
+            None if used_channels < MAX_IT_CHANNELS => {
+                group.push((used_channels, note.end));
+                used_channels += 1;
+                used_channels - 1
+            }
+            None => {
+                pattern_counts.notes_dropped += 1;
+                continue;
+            }
+        };
+        let volume = u8::try_from((u16::from(note.velocity) * 64 + 63) / 127).unwrap();
+        note_ons.insert((note.start, channel), Command {
+            note: Some(NoteCmd::Play(Note::try_from(note.key).unwrap())),
+            instrument: Some(InstrumentId::from_index(u8::try_from(instrument).unwrap()).unwrap()),
+            volume: Some(VolumeCmd::SetVolume(RangedU8::try_from(volume).unwrap())),
+            effect: None,
+        });
+        note_offs.push((note.end, channel));
+    }
+    if instruments_dropped {
+        let count = notes.iter().map(|note| (note.channel == PERCUSSION_CHANNEL, note.program)).collect::<HashSet<_>>().len();
+        report.push(Issue::ItemsDropped { kind: "instruments", count, max: MAX_IT_ITEMS });
+    }
+
+    // A note starting on the row another one ends replaces the note off.
+    let mut cells = note_ons;
+    for (row, channel) in note_offs {
+        if row < max_rows {
+            cells.entry((row, channel)).or_insert(Command { note: Some(NoteCmd::Off), ..Command::EMPTY });
+        }
+    }
+    if !tempo_changes.is_empty() {
+        if used_channels < MAX_IT_CHANNELS {
+            for &(row, tempo) in tempo_changes.iter().filter(|&&(row, _)| row < max_rows) {
+                let effect = EffectCmd::Tempo(Some(Tempo::Set(RangedU8::try_from(tempo).unwrap())));
+                cells.insert((row, used_channels), Command { effect: Some(effect), ..Command::EMPTY });
+            }
+            used_channels += 1;
+        } else {
+            report.push(Issue::ModuleFeature { feature: "tempo changes" });
+        }
+    }
+
+    let mut rows = vec![Vec::new(); pattern_count * usize::from(pattern_rows)];
+    for ((row, channel), command) in cells {
+        let channel = Channel::from_index(u8::try_from(channel).unwrap()).unwrap();
+        rows[usize::try_from(row).unwrap()].push((channel, command));
+    }
+    let patterns = rows
+        .chunks(usize::from(pattern_rows))
+        .map(|rows| {
+            let mut active_channels = ActiveChannels::empty();
+            let rows = rows
+                .iter()
+                .map(|commands| {
+                    active_channels |= commands.iter().map(|(channel, _)| *channel).collect();
+                    Row::from_vec(commands.clone())
+                })
+                .collect();
+            Pattern { active_channels, rows }
+        })
+        .collect::<Vec<_>>();
+    for (idx, counts) in (0..).zip(&counts) {
+        report.pattern_counts(PatternId::from_index(idx).unwrap(), counts);
+    }
+
+    let instruments = programs.into_iter().map(|(drums, program)| placeholder_instrument(drums, program)).collect();
+
+    let mut orders = (0..)
+        .take(pattern_count)
+        .map(|idx| Order::Index(PatternId::from_index(idx).unwrap()))
+        .collect::<Vec<_>>();
+    orders.push(Order::EndOfSong);
+
+    let mut channels = [ChannelSettings { muted: true, ..ChannelSettings::DEFAULT }; MAX_IT_CHANNELS];
+    for settings in channels.iter_mut().take(used_channels) {
+        *settings = ChannelSettings::DEFAULT;
+    }
+
+    // The first track of a type 1 file usually holds the name of the song.
+    let song_name = track_names.first().copied().flatten().unwrap_or_default();
+    let rows_per_beat = u8::try_from(rows_per_beat).unwrap();
+
+    let module = Module {
+        name: name(song_name),
+        message: String::new(),
+        highlight: (rows_per_beat.saturating_mul(4), rows_per_beat),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags: ModuleFlags::STEREO | ModuleFlags::USE_INSTRUMENTS,
+        global_volume: RangedU8::new(128),
+        sample_volume: RangedU8::new(48),
+        speed: RangedU8::try_from(speed).unwrap(),
+        tempo: RangedU8::try_from(tempo).unwrap(),
+        pan_separation: RangedU8::new(128),
+        pitch_wheel_depth: 0,
+        channels,
+        orders,
+        instruments,
+        samples: Vec::new(),
+        patterns,
+    };
+    Ok((module, report))
+}
+
+/// Instrument without samples standing for a MIDI program
+///
+/// The program (and channel 10 for drums) is set as the MIDI program of the instrument.
+fn placeholder_instrument(drums: bool, program: u8) -> Instrument {
+    let label = if drums { String::from("Drums") } else { format!("Program {}", program + 1) };
+    let envelope = Envelope {
+        flags: EnvelopeFlags::empty(),
+        envelope_loop: None,
+        sustain_loop: None,
+        nodes: Vec::new(),
+    };
+    Instrument {
+        name: name(label.as_bytes()),
+        filename: DosFilename::sanitize(""),
+        flags: InstrumentFlags::empty(),
+        new_note_action: NewNoteAction::Cut,
+        duplicate_check_type: DuplicateCheckType::Off,
+        duplicate_check_action: DuplicateCheckAction::Cut,
+        instrument_fadeout: 0,
+        pitch_pan_separation: 0,
+        pitch_pan_centre: 60,
+        global_volume: 128,
+        default_panning: RangedU8::new(32),
+        random_volume_variation: RangedU8::new(0),
+        random_panning_variation: RangedU8::new(0),
+        trkver: 0x0214,
+        number_of_samples: 0,
+        initial_filter_cutoff: RangedU8::new(0),
+        initial_filter_resonance: RangedU8::new(0),
+        mch: if drums { PERCUSSION_CHANNEL + 1 } else { 0 },
+        mpr: program,
+        mbank: [0xFF, 0xFF],
+        sample_map: SampleMap::default(),
+        volume_envelope: envelope.clone(),
+        panning_envelope: envelope.clone(),
+        pitch_filter_envelope: envelope,
+    }
+}
+
+
+/// Reads the header, returns the number of tracks and the division.
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], (u16, u16), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, _) = tag(b"MThd")(input)?;
+    let (input, size) = be_u32(input)?;
+    if size < 6 {
+        bail!(input, "header has {} bytes, expected at least 6", size);
+    }
+    let (input, header) = take(size)(input)?;
+    // The format doesn't matter, the tracks of all of them are played at once.
+    let (_, (_format, tracks, division)) = tuple((be_u16, be_u16, be_u16))(header)?;
+    Ok((input, (tracks, division)))
+}
+
+/// Reads a chunk, returns its ID and data.
+fn chunk<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], (&'i [u8], &'i [u8]), E>
+where
+    E: ParseError<&'i [u8]>,
+{
+    let (input, id) = take(4usize)(input)?;
+    let (input, size) = be_u32(input)?;
+    let (input, data) = take(size)(input)?;
+    Ok((input, (id, data)))
+}
+
+/// Reads the events of a track with their time in ticks.
+fn track<'i, E>(mut input: &'i [u8]) -> IResult<&'i [u8], Vec<(u64, RawEvent<'i>)>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut events = Vec::new();
+    let mut time = 0u64;
+    let mut running_status = None;
+    while !input.is_empty() {
+        let (rest, delta) = variable(input)?;
+        time += u64::from(delta);
+        let (after_status, first) = be_u8(rest)?;
+        // Messages without a status byte repeat the last one.
+        let (rest, status) = match (first & 0x80 != 0, running_status) {
+            (true, _) => (after_status, first),
+            (false, Some(status)) => (rest, status),
+            (false, None) => bail!(rest, "data byte {:#04X} without a status byte", first),
+        };
+
+        let (rest, event) = match status {
+            0xFF => {
+                running_status = None;
+                let (rest, kind) = be_u8(rest)?;
+                let (rest, size) = variable(rest)?;
+                let (rest, data) = take(size)(rest)?;
+                match kind {
+                    0x2F => {
+                        input = rest;
+                        break;
+                    }
+                    0x51 if data.len() == 3 => (rest, RawEvent::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]]))),
+                    0x03 => (rest, RawEvent::TrackName(data)),
+                    _ => {
+                        input = rest;
+                        continue;
+                    }
+                }
+            }
+            0xF0 | 0xF7 => {
+                running_status = None;
+                let (rest, size) = variable(rest)?;
+                let (rest, _) = take(size)(rest)?;
+                (rest, RawEvent::Unsupported("system exclusive messages"))
+            }
+            0x80..=0xEF => {
+                running_status = Some(status);
+                let channel = status & 0x0F;
+                let kind = status & 0xF0;
+                let (rest, data) = take(if kind == 0xC0 || kind == 0xD0 { 1usize } else { 2 })(rest)?;
+                let first = data[0] & 0x7F;
+                let second = data.get(1).map_or(0, |byte| byte & 0x7F);
+                let event = match kind {
+                    0x80 => RawEvent::NoteOff { channel, key: first },
+                    0x90 if second == 0 => RawEvent::NoteOff { channel, key: first },
+                    0x90 => RawEvent::NoteOn { channel, key: first, velocity: second },
+                    0xA0 | 0xD0 => RawEvent::Unsupported("aftertouch"),
+                    0xB0 => RawEvent::Unsupported("controllers"),
+                    0xC0 => RawEvent::Program { channel, program: first },
+                    _ => RawEvent::Unsupported("pitch bend"),
+                };
+                (rest, event)
+            }
+            _ => bail!(rest, "unknown status byte {:#04X}", status),
+        };
+        events.push((time, event));
+        input = rest;
+    }
+    Ok((input, events))
+}
+
+/// Reads a variable-length quantity of at most 4 bytes.
+fn variable<'i, E>(mut input: &'i [u8]) -> IResult<&'i [u8], u32, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut value = 0;
+    for _ in 0..4 {
+        let (rest, byte) = be_u8(input)?;
+        input = rest;
+        value = (value << 7) | u32::from(byte & 0x7F);
+        if byte & 0x80 == 0 {
+            return Ok((input, value));
+        }
+    }
+    bail!(input, "variable-length number longer than 4 bytes");
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    fn module(rows: Vec<Row>) -> Module {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        module.set_speed(6).unwrap();
+        module.set_tempo(125).unwrap();
+        module.highlight = (16, 4);
+        module.orders = vec![Order::Index(PatternId::from_index(0).unwrap()), Order::EndOfSong];
+        module.patterns = vec![Pattern { active_channels: ActiveChannels::all(), rows }];
+        module
+    }
+
+    fn cell(channel: u8, command: Command) -> Row {
+        Row::from_vec(vec![(Channel::new(channel), command)])
+    }
+
+    #[test]
+    fn variable_length() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            write_variable(&mut out, value);
+            out
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7F), [0x7F]);
+        assert_eq!(encode(0x80), [0x81, 0x00]);
+        assert_eq!(encode(0x0FFF_FFFF), [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn export_notes() {
+        let mut rows = vec![Row::empty(); 8];
+        rows[0] = cell(1, Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            volume: Some(VolumeCmd::SetVolume(RangedU8::new(32))),
+            effect: None,
+        });
+        rows[4] = cell(1, Command { note: Some(NoteCmd::Off), ..Command::EMPTY });
+        let (midi, report) = export(&module(rows), MidiOptions::default());
+        assert!(report.is_lossless());
+
+        assert_eq!(&midi[..4], b"MThd");
+        // Format 1, two tracks, 24 ticks per quarter note.
+        assert_eq!(&midi[8..14], &[0, 1, 0, 2, 0, 24]);
+
+        let track = midi.windows(4).rposition(|window| window == b"MTrk").unwrap();
+        let events = &midi[track + 8..];
+        let note_on = events.windows(3).position(|window| window == [NOTE_ON, 60, 64]).unwrap();
+        // The note off follows 4 rows of 6 ticks later.
+        assert_eq!(&events[note_on + 3..note_on + 7], &[24, NOTE_OFF, 60, 0]);
+        assert!(events.ends_with(&[24, META, END_OF_TRACK, 0]));
+    }
+
+    #[test]
+    fn note_delay_and_cut() {
+        let mut rows = vec![Row::empty(); 2];
+        rows[0] = cell(2, Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            volume: None,
+            effect: Some(EffectCmd::Special(Some(Special::NoteDelay(RangedU8::new(2))))),
+        });
+        rows[1] = cell(2, Command { effect: Some(EffectCmd::Special(Some(Special::NoteCut(RangedU8::new(3))))), ..Command::EMPTY });
+        let options = MidiOptions { volume: VolumeMapping::Ignore, ..MidiOptions::default() };
+        let (midi, _) = export(&module(rows), options);
+
+        let track = midi.windows(4).rposition(|window| window == b"MTrk").unwrap();
+        let events = &midi[track + 8..];
+        let note_on = events.windows(3).position(|window| window == [NOTE_ON, 60, DEFAULT_VELOCITY]).unwrap();
+        // The program change and the note are delayed by 2 ticks.
+        assert_eq!(&events[note_on - 4..note_on], &[2, PROGRAM_CHANGE, 0, 0]);
+        // Cut on tick 3 of the second row.
+        assert_eq!(&events[note_on + 3..note_on + 7], &[7, NOTE_OFF, 60, 0]);
+    }
+
+    fn smf(division: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut file = b"MThd\0\0\0\x06\0\x01".to_vec();
+        file.extend_from_slice(&u16::try_from(tracks.len()).unwrap().to_be_bytes());
+        file.extend_from_slice(&division.to_be_bytes());
+        for track in tracks {
+            file.extend_from_slice(b"MTrk");
+            file.extend_from_slice(&u32::try_from(track.len()).unwrap().to_be_bytes());
+            file.extend_from_slice(track);
+        }
+        file
+    }
+
+    #[test]
+    fn import_notes() {
+        let conductor = b"\x00\xFF\x03\x04Song\x00\xFF\x51\x03\x07\xA1\x20\x00\xFF\x2F\x00";
+        let notes = [
+            0x00, 0xC0, 0x05,
+            0x00, 0x90, 0x3C, 0x7F,
+            // Running status, overlapping the first note.
+            0x30, 0x40, 0x64,
+            0x30, 0x80, 0x3C, 0x00,
+            0x00, 0xE0, 0x00, 0x40,
+            0x30, 0x80, 0x40, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let file = smf(96, &[&conductor[..], &notes[..]]);
+        let (module, report) = import::<VerboseError<&[u8]>>(&file, ImportOptions::default()).unwrap();
+
+        assert_eq!(module.name.as_bytes(), b"Song");
+        assert_eq!((module.speed.as_u8(), module.tempo.as_u8()), (6, 120));
+        assert_eq!(report.issues, [Issue::ModuleFeature { feature: "pitch bend" }]);
+        assert_eq!(module.instruments.len(), 1);
+        assert_eq!(module.instruments[0].name.as_bytes(), b"Program 6");
+        assert_eq!(module.instruments[0].mpr, 5);
+
+        let instrument = Some(InstrumentId::from_index(0).unwrap());
+        let rows = &module.patterns[0].rows;
+        assert_eq!(rows.len(), 64);
+        let play = |note, volume| Command {
+            note: Some(NoteCmd::Play(note)),
+            instrument,
+            volume: Some(VolumeCmd::SetVolume(RangedU8::new(volume))),
+            effect: None,
+        };
+        let off = Command { note: Some(NoteCmd::Off), ..Command::EMPTY };
+        assert_eq!(rows[0], cell(1, play(Note::C_5, 64)));
+        assert_eq!(rows[2], cell(2, play(Note::E_5, 50)));
+        assert_eq!(rows[4], cell(1, off));
+        assert_eq!(rows[6], cell(2, off));
+        assert!(rows[7..].iter().all(|row| row.iter().next().is_none()));
+    }
+
+    #[test]
+    fn import_errors() {
+        assert!(import::<VerboseError<&[u8]>>(b"RIFF", ImportOptions::default()).is_err());
+        let file = smf(96, &[&[0x00, 0x3C, 0x7F]]);
+        assert!(import::<VerboseError<&[u8]>>(&file, ImportOptions::default()).is_err());
+    }
+}
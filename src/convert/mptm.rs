@@ -0,0 +1,138 @@
+//! OpenMPT modules (.mptm)
+//!
+//! MPTM is the native format of OpenMPT: an Impulse Tracker file whose "Made With" and
+//! "Compatible With" versions are set to [`MADE_WITH_VERSION`] and [`COMPATIBLE_WITH_VERSION`],
+//! which tells OpenMPT to lift the limits of the IT format. [`check`] lists the features of a
+//! module which plain IT files can't store, [`export`] writes the module as IT when there are
+//! none and as MPTM otherwise.
+//!
+//! [`Module`] can't represent more than 64 channels or tempos above 255, the features left for
+//! MPTM are patterns with more than 200 rows, the initial tempo 31 and the MPTM extension effects
+//! (`S98`, `S99`, `S9A`...`S9F`). Higher tempos and the other song properties are written into
+//! the extension chunks from [`WriteOptions::song_properties`], see the
+//! [writer documentation](crate::writer#openmpt-extensions).
+
+use crate::error::WriteError;
+use crate::writer::{self, Target, WriteOptions, WriteReport};
+use crate::*;
+
+
+/// "Made With" version OpenMPT writes into MPTM files
+pub const MADE_WITH_VERSION: u16 = 0x0889;
+
+/// "Compatible With" version marking a file as MPTM
+pub const COMPATIBLE_WITH_VERSION: u16 = 0x0888;
+
+/// File format a module was written as by [`export`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    /// Plain Impulse Tracker module (.it)
+    It,
+
+    /// OpenMPT module (.mptm)
+    Mptm,
+}
+
+/// Feature which needs the MPTM format, see [`check`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MptmFeature {
+    /// Pattern has more than 200 rows
+    LongPattern { pattern: PatternId, rows: usize },
+
+    /// Initial tempo is below 32
+    Tempo { tempo: u8 },
+
+    /// Pattern contains MPTM extension effects
+    ExtensionEffects { pattern: PatternId, count: usize },
+}
+
+/// Lists the features of `module` which need the MPTM format
+///
+/// Plain IT files follow the limits of Schism Tracker (see [`Target::Schism`]), patterns shorter
+/// than the 32 rows Impulse Tracker needs are loaded by every current tracker.
+pub fn check(module: &Module) -> Vec<MptmFeature> {
+    let it = Target::Schism;
+    let mut features = Vec::new();
+
+    let tempo = module.tempo.as_u8();
+    if tempo < it.min_tempo() {
+        features.push(MptmFeature::Tempo { tempo });
+    }
+
+    for (pattern_id, pattern) in (0..=u8::MAX).filter_map(|idx| PatternId::from_index(idx).ok()).zip(&module.patterns) {
+        let rows = pattern.rows.len();
+        if rows > *it.rows().end() {
+            features.push(MptmFeature::LongPattern { pattern: pattern_id, rows });
+        }
+        let count = pattern.rows
+            .iter()
+            .flat_map(Row::iter)
+            .filter(|(_, command)| command.effect.as_ref().is_some_and(|effect| !it.supports(effect)))
+            .count();
+        if count > 0 {
+            features.push(MptmFeature::ExtensionEffects { pattern: pattern_id, count });
+        }
+    }
+
+    features
+}
+
+/// Writes the module as a plain IT file if it can stay one, as MPTM otherwise
+///
+/// Plain IT files are written as [`writer::module_file_with_options`] would. For MPTM the versions
+/// are set to the MPTM ones and the module is written for [`Target::OpenMPT`], patterns longer
+/// than the 1024 rows OpenMPT supports are cut and listed in the [`WriteReport`]. The
+/// [`WriteOptions::target`] of `options` is ignored.
+pub fn export(module: &Module, options: &WriteOptions) -> Result<(Vec<u8>, Container, WriteReport), WriteError> {
+    if check(module).is_empty() {
+        let options = WriteOptions { target: None, ..options.clone() };
+        let (bytes, report) = writer::module_file_with_options(module, &options)?;
+        return Ok((bytes, Container::It, report));
+    }
+
+    let mut module = module.clone();
+    module.made_with_version = MADE_WITH_VERSION;
+    module.compatible_with_version = COMPATIBLE_WITH_VERSION;
+    let options = WriteOptions { target: Some(Target::OpenMPT), ..options.clone() };
+    let (bytes, report) = writer::module_file_with_options(&module, &options)?;
+    Ok((bytes, Container::Mptm, report))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    #[test]
+    fn container() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        module.set_tempo(125).unwrap();
+        module.patterns = vec![Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 16] }];
+        assert_eq!(check(&module), []);
+        let (bytes, container, _) = export(&module, &WriteOptions::default()).unwrap();
+        assert_eq!(container, Container::It);
+        assert_eq!(bytes, writer::module_file(&module).unwrap());
+
+        let reverse = Command {
+            effect: Some(EffectCmd::Special(Some(Special::SetDirection(PlayDirection::Backward)))),
+            ..Command::EMPTY
+        };
+        let mut rows = vec![Row::empty(); 300];
+        rows[1] = Row::from_vec(vec![(Channel::new(1), reverse)]);
+        module.patterns.push(Pattern { active_channels: ActiveChannels::all(), rows });
+        let pattern = PatternId::from_index(1).unwrap();
+        assert_eq!(check(&module), [
+            MptmFeature::LongPattern { pattern, rows: 300 },
+            MptmFeature::ExtensionEffects { pattern, count: 1 },
+        ]);
+
+        let (bytes, container, report) = export(&module, &WriteOptions::default()).unwrap();
+        assert_eq!(container, Container::Mptm);
+        assert_eq!(report.adjustments, []);
+        let written = parser::module_file::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(written.compatible_with_version, COMPATIBLE_WITH_VERSION);
+        assert_eq!(written.patterns[1].rows.len(), 300);
+        assert_eq!(written.patterns[1].rows[1], module.patterns[1].rows[1]);
+    }
+}
@@ -0,0 +1,886 @@
+//! ProTracker modules (.mod)
+//!
+//! [`import`] reads a 31-sample module with 4, 6 or 8 channels (signatures `M.K.`, `M!K!`,
+//! `FLT4`, `4CHN`, `6CHN`, `8CHN`, `OCTA` and `CD81`) into a [`Module`]. The module doesn't use
+//! instruments, the MOD samples are played directly like in the original. Each sample gets the
+//! IT defaults with its volume, loop and finetune, the finetune is folded into the C-5 frequency.
+//!
+//! Notes are stored as Amiga periods, they are converted to the nearest IT note. Period 428
+//! (ProTracker `C-2`) played at 8363 Hz becomes IT `C-5`. Effects are mapped like the XM ones,
+//! which extend the ProTracker effects, `Cxx` is moved into the volume column.
+//!
+//! [`downgrade`] goes the other way for modules meant for Amiga players: it reduces a [`Module`]
+//! to what a 4-channel, 31-sample MOD can hold, so it can be played (or written out) with the
+//! ProTracker effects only. The first 4 channels with commands are kept and moved to channels
+//! 1 to 4, instruments are resolved to their samples like for S3M, patterns are cut or padded
+//! to 64 rows. Samples become 8-bit, at most 131070 bytes long and loop forward on word
+//! boundaries. Their C-5 frequency is split into a transposition of the notes played with the
+//! sample and a ProTracker finetune, notes ending up outside of ProTracker `C-1` to `B-3` are
+//! dropped. The volume column moves into the effect column when it's free, effects ProTracker
+//! doesn't remember the parameter of (`1xx`, `2xx`, `5xy`, `6xy` and `Axy`) get the last
+//! parameter used in the pattern.
+
+use super::{name, round_i64, s3m, samplerate_from, semitones_from, xm, Converted, Issue, PatternCounts, Report};
+use crate::error::ContextError;
+use crate::*;
+use nom::bytes::complete::take;
+use nom::error::ParseError;
+use nom::multi::count;
+use nom::number::complete::{be_u16, le_i8, le_u8};
+use nom::{Err, IResult};
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+
+
+/// Frequency of a sample with finetune 0 played at period [`BASE_PERIOD`]
+const BASE_FREQUENCY: u32 = 8363;
+
+/// Period of ProTracker `C-2`, which is IT `C-5`
+const BASE_PERIOD: f64 = 428.0;
+
+const SAMPLES: usize = 31;
+const ORDERS: usize = 128;
+const ROWS: usize = 64;
+
+/// Limits of the modules made by [`downgrade`], 64 patterns fit the `M.K.` signature
+const CHANNELS: usize = 4;
+const PATTERNS: usize = 64;
+const MAX_SAMPLE_LENGTH: usize = 0xFFFF * 2;
+const MAX_NAME_LENGTH: usize = 20;
+const MAX_SAMPLE_NAME_LENGTH: usize = 22;
+
+/// IT notes ProTracker plays, its `C-1` to `B-3`
+const NOTES: RangeInclusive<i64> = 48..=83;
+
+/// Limits of the IT format
+const MAX_IT_PATTERNS: usize = 200;
+
+/// XM note number of IT `C-5`, patterns are converted as XM cells
+const XM_C_5: i64 = 49;
+const XM_NOTES: i64 = 96;
+const IT_C_5: i64 = 60;
+
+
+/// Reads a ProTracker module file (.mod) into a [`Module`]
+///
+/// See the [module documentation](self) for how the MOD features are mapped.
+pub fn import<'i, E>(input: &'i [u8]) -> Result<(Module, Report), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut report = Report::default();
+
+    let (mut input, header) = context!(header, "in MOD header")(input)?;
+    if header.restart != 0 && usize::from(header.restart) < header.orders.len() {
+        report.push(Issue::ModuleFeature { feature: "restart position" });
+    }
+
+    // All 128 orders count for the number of patterns, not only the played ones.
+    let pattern_count = header.all_orders.iter().max().map_or(0, |&max| usize::from(max) + 1);
+    let mut mod_patterns = Vec::with_capacity(pattern_count);
+    for idx in 0..pattern_count {
+        let (rest, pattern) = context!(|input| mod_pattern(input, header.channels), "in pattern {}", idx)(input)?;
+        mod_patterns.push(pattern);
+        input = rest;
+    }
+    if mod_patterns.len() > MAX_IT_PATTERNS {
+        report.push(Issue::ItemsDropped { kind: "patterns", count: mod_patterns.len(), max: MAX_IT_PATTERNS });
+        mod_patterns.truncate(MAX_IT_PATTERNS);
+    }
+
+    let patterns = (0..)
+        .zip(mod_patterns)
+        .map(|(idx, rows)| {
+            let pattern_id = PatternId::from_index(idx).unwrap();
+            let mut counts = PatternCounts::default();
+            let rows = rows
+                .into_iter()
+                .map(|cells| cells.into_iter().map(|cell| xm_cell(cell, &mut counts)).collect())
+                .collect();
+            let pattern = xm::pattern_from_xm(rows, &mut counts);
+            report.pattern_counts(pattern_id, &counts);
+            pattern
+        })
+        .collect::<Vec<_>>();
+
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for (idx, raw) in (0..).zip(&header.samples) {
+        let sample_id = SampleId::from_index(idx).unwrap();
+        // Sample data is often cut short at the end of the file.
+        let (rest, data) = take(raw.length.min(input.len()))(input)?;
+        input = rest;
+        if data.len() < raw.length {
+            report.push(Issue::SampleFeature { sample: sample_id, feature: "truncated sample data" });
+        }
+        samples.push(sample_from_mod(raw, data));
+    }
+
+    let orders = header.orders
+        .iter()
+        .filter_map(|&order| PatternId::from_index(order).ok())
+        .map(Order::Index)
+        .chain(Some(Order::EndOfSong))
+        .collect::<Vec<_>>();
+
+    let module = Module {
+        name: name(&header.name),
+        message: String::new(),
+        highlight: (4, 16),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags: ModuleFlags::STEREO,
+        global_volume: RangedU8::new(128),
+        sample_volume: RangedU8::new(48),
+        speed: RangedU8::new(6),
+        tempo: RangedU8::new(125),
+        pan_separation: RangedU8::new(128),
+        pitch_wheel_depth: 0,
+        channels: amiga_channels(usize::from(header.channels)),
+        orders,
+        instruments: Vec::new(),
+        samples,
+        patterns,
+    };
+    Ok((module, report))
+}
+
+/// Reduces the module to the features of a 4-channel ProTracker module
+///
+/// See the [module documentation](self) for what's kept, everything lost or approximated is
+/// listed in the [`Report`]. The initial speed and tempo are kept in the module, a MOD file has
+/// to set them with `Fxx` on the first row.
+pub fn downgrade(module: &Module) -> (Module, Report) {
+    let mut report = Report::default();
+    module_features(module, &mut report);
+    let channels = kept_channels(module, &mut report);
+
+    let pattern_count = module.patterns.len().min(PATTERNS);
+    if pattern_count < module.patterns.len() {
+        report.push(Issue::ItemsDropped { kind: "patterns", count: module.patterns.len(), max: PATTERNS });
+    }
+    let sample_count = module.samples.len().min(SAMPLES);
+    if sample_count < module.samples.len() {
+        report.push(Issue::ItemsDropped { kind: "samples", count: module.samples.len(), max: SAMPLES });
+    }
+
+    let mut transpose = Vec::with_capacity(sample_count);
+    let samples = (0..)
+        .zip(&module.samples[..sample_count])
+        .map(|(idx, sample)| {
+            let (sample, semitones) = sample_to_mod(SampleId::from_index(idx).unwrap(), sample, &mut report);
+            transpose.push(semitones);
+            sample
+        })
+        .collect();
+
+    // Jumps to a removed order go to the next kept one, the song ends at the first `---`.
+    let mut order_map = Vec::with_capacity(module.orders.len());
+    let mut orders = Vec::new();
+    for order in &module.orders {
+        order_map.push(orders.len());
+        match order {
+            Order::Index(pattern) if pattern.as_usize() < pattern_count => orders.push(*order),
+            Order::EndOfSong => break,
+            _ => {}
+        }
+    }
+    if orders.len() > ORDERS {
+        report.push(Issue::ItemsDropped { kind: "orders", count: orders.len(), max: ORDERS });
+        orders.truncate(ORDERS);
+    }
+    orders.push(Order::EndOfSong);
+
+    let downgrade = Downgrade { module, channels, transpose, order_map };
+    let patterns = (0..)
+        .zip(&module.patterns[..pattern_count])
+        .map(|(idx, pattern)| {
+            let pattern_id = PatternId::from_index(idx).unwrap();
+            let mut counts = PatternCounts::default();
+            let pattern = downgrade.pattern(pattern, pattern_id, &mut counts, &mut report);
+            report.pattern_counts(pattern_id, &counts);
+            pattern
+        })
+        .collect();
+
+    let name_length = module.name.as_bytes().len().min(MAX_NAME_LENGTH);
+    let module = Module {
+        name: name(&module.name.as_bytes()[..name_length]),
+        message: String::new(),
+        highlight: module.highlight,
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags: ModuleFlags::STEREO,
+        global_volume: RangedU8::new(128),
+        sample_volume: module.sample_volume,
+        speed: RangedU8::new(module.speed.as_u8().min(31)),
+        tempo: RangedU8::new(module.tempo.as_u8().max(32)),
+        pan_separation: module.pan_separation,
+        pitch_wheel_depth: 0,
+        channels: amiga_channels(CHANNELS),
+        orders,
+        instruments: Vec::new(),
+        samples,
+        patterns,
+    };
+    (module, report)
+}
+
+
+/// Initial channel settings of a module with `count` Amiga channels
+fn amiga_channels(count: usize) -> [ChannelSettings; 64] {
+    // Amiga channels are panned left, right, right, left, softened like most players do.
+    let mut channels = [ChannelSettings { muted: true, ..ChannelSettings::DEFAULT }; 64];
+    for (idx, channel) in channels.iter_mut().enumerate().take(count) {
+        let pan = if idx % 4 == 0 || idx % 4 == 3 { 16 } else { 48 };
+        *channel = ChannelSettings { panning: ChannelPanning::Position(RangedU8::new(pan)), ..ChannelSettings::DEFAULT };
+    }
+    channels
+}
+
+
+struct ModHeader {
+    name: [u8; 20],
+    samples: Vec<RawSample>,
+    restart: u8,
+
+    /// Played orders
+    orders: Vec<u8>,
+    all_orders: [u8; ORDERS],
+    channels: u8,
+}
+
+/// Sample header, lengths and positions are in bytes
+struct RawSample {
+    name: [u8; 22],
+    length: usize,
+    finetune: i8,
+    volume: u8,
+    loop_start: usize,
+    loop_length: usize,
+}
+
+/// Cell as it's stored in the MOD pattern
+#[derive(Clone, Copy)]
+struct Cell {
+    sample: u8,
+    period: u16,
+    effect: u8,
+    param: u8,
+}
+
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], ModHeader, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, name) = take(20usize)(input)?;
+    let (input, samples) = count(raw_sample, SAMPLES)(input)?;
+    let (input, song_length) = le_u8(input)?;
+    let (input, restart) = le_u8(input)?;
+    let (input, all_orders) = take(ORDERS)(input)?;
+    let (input, signature) = take(4usize)(input)?;
+    let channels = match signature {
+        b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => 4,
+        b"6CHN" => 6,
+        b"8CHN" | b"OCTA" | b"CD81" => 8,
+        _ => bail!(signature, "unsupported MOD signature {:?}", String::from_utf8_lossy(signature)),
+    };
+
+    Ok((
+        input,
+        ModHeader {
+            name: name.try_into().unwrap(),
+            samples,
+            restart,
+            orders: all_orders[..usize::from(song_length).min(ORDERS)].to_vec(),
+            all_orders: all_orders.try_into().unwrap(),
+            channels,
+        },
+    ))
+}
+
+fn raw_sample<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], RawSample, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, name) = take(22usize)(input)?;
+    let (input, length) = be_u16(input)?;
+    let (input, finetune) = le_i8(input)?;
+    let (input, volume) = le_u8(input)?;
+    let (input, loop_start) = be_u16(input)?;
+    let (input, loop_length) = be_u16(input)?;
+
+    // Lengths are stored in words, the finetune is a signed nibble.
+    Ok((
+        input,
+        RawSample {
+            name: name.try_into().unwrap(),
+            length: usize::from(length) * 2,
+            finetune: (finetune << 4) >> 4,
+            volume,
+            loop_start: usize::from(loop_start) * 2,
+            loop_length: usize::from(loop_length) * 2,
+        },
+    ))
+}
+
+fn mod_pattern<'i, E>(input: &'i [u8], channels: u8) -> IResult<&'i [u8], Vec<Vec<Cell>>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, bytes) = take(ROWS * usize::from(channels) * 4)(input)?;
+    let rows = bytes
+        .chunks_exact(usize::from(channels) * 4)
+        .map(|row| {
+            row.chunks_exact(4)
+                .map(|cell| Cell {
+                    sample: (cell[0] & 0xF0) | (cell[2] >> 4),
+                    period: u16::from_be_bytes([cell[0] & 0x0F, cell[1]]),
+                    effect: cell[2] & 0x0F,
+                    param: cell[3],
+                })
+                .collect()
+        })
+        .collect();
+    Ok((input, rows))
+}
+
+/// Converts the cell into XM note, instrument, volume, effect and parameter bytes
+fn xm_cell(cell: Cell, counts: &mut PatternCounts) -> [u8; 5] {
+    let note = match cell.period {
+        0 => 0,
+        period => {
+            let note = XM_C_5 + round_i64(12.0 * (BASE_PERIOD / f64::from(period)).log2());
+            match note {
+                1..=XM_NOTES => u8::try_from(note).unwrap(),
+                _ => {
+                    counts.notes_dropped += 1;
+                    0
+                }
+            }
+        }
+    };
+
+    // ProTracker has no effect memory for these, `00` only continues the portamento or vibrato.
+    let (effect, param) = match (cell.effect, cell.param) {
+        (0x1 | 0x2 | 0xA, 0) => (0x0, 0),
+        (0x5, 0) => (0x3, 0),
+        (0x6, 0) => (0x4, 0),
+        (effect, param) => (effect, param),
+    };
+    [note, cell.sample, 0, effect, param]
+}
+
+fn sample_from_mod(raw: &RawSample, data: &[u8]) -> Sample {
+    let length = u32::try_from(data.len()).unwrap();
+    // Loops of one word are the ProTracker way of not looping.
+    let loop_end = (raw.loop_start + raw.loop_length).min(data.len());
+    let loop_ = if raw.loop_length > 2 && raw.loop_start < loop_end {
+        let sample_loop = SampleLoop {
+            start: u32::try_from(raw.loop_start).unwrap(),
+            end: u32::try_from(loop_end).unwrap(),
+            bidi: false,
+        };
+        sample_loop.validate(length).is_ok().then_some(sample_loop)
+    } else {
+        None
+    };
+
+    let pcm = data.iter().map(|&byte| i8::from_le_bytes([byte])).collect::<Vec<_>>();
+    Sample {
+        name: name(&raw.name),
+        filename: DosFilename::sanitize(""),
+        global_volume: 64,
+        default_volume: raw.volume.min(64),
+        default_panning: 32,
+        loop_,
+        sustain_loop: None,
+        samplerate_c5: samplerate_from(f64::from(raw.finetune) / 8.0, BASE_FREQUENCY),
+        vibrato_speed: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        vibrato_type: 0,
+        data: (!pcm.is_empty()).then(|| SampleData::from(pcm)),
+        lazy_data: None,
+    }
+}
+
+
+/// Module being reduced by [`downgrade`]
+struct Downgrade<'m> {
+    module: &'m Module,
+
+    /// Channels moved to channels 1 to 4
+    channels: Vec<Channel>,
+
+    /// Semitones the notes of each kept sample are moved by
+    transpose: Vec<i64>,
+
+    /// New position of each order up to the end of the song
+    order_map: Vec<usize>,
+}
+
+/// State of a channel while converting a pattern
+#[derive(Clone, Copy, Default)]
+struct ChannelState {
+    /// Transposition of the last sample played
+    transpose: i64,
+
+    /// The last sample played was dropped
+    dropped_sample: bool,
+
+    /// Last parameters of the portamentos and of the volume slides
+    memory: [u8; 2],
+}
+
+impl Downgrade<'_> {
+    fn pattern(&self, pattern: &Pattern, pattern_id: PatternId, counts: &mut PatternCounts, report: &mut Report) -> Pattern {
+        let rows = &pattern.rows[..pattern.rows.len().min(ROWS)];
+        if rows.len() < pattern.rows.len() {
+            report.push(Issue::RowsDropped { pattern: pattern_id, rows: pattern.rows.len(), max: ROWS });
+        }
+
+        let mut states = [ChannelState::default(); CHANNELS];
+        let mut cells = rows
+            .iter()
+            .map(|row| {
+                let mut cells = vec![[0; 5]; CHANNELS];
+                for (channel, command) in row.iter() {
+                    if let Some(idx) = self.channels.iter().position(|&kept| kept == channel) {
+                        cells[idx] = self.cell(command, &mut states[idx], counts);
+                    }
+                }
+                cells
+            })
+            .collect::<Vec<_>>();
+
+        // Shorter patterns are padded and end with a break to the next pattern.
+        if cells.len() < ROWS {
+            let empty = vec![[0; 5]; CHANNELS];
+            if cells.is_empty() {
+                cells.push(empty.clone());
+            }
+            let last = cells.last_mut().unwrap();
+            let has_jump = last.iter().any(|cell| cell[3] == 0xB || cell[3] == 0xD);
+            match last.iter_mut().find(|cell| cell[3..] == [0, 0]) {
+                _ if has_jump => {}
+                Some(cell) => cell[3] = 0xD,
+                None => {
+                    let issue = Issue::ModuleFeature { feature: "patterns shorter than 64 rows" };
+                    if !report.issues.contains(&issue) {
+                        report.push(issue);
+                    }
+                }
+            }
+            cells.resize(ROWS, empty);
+        }
+
+        // Reading the cells back only repeats the conversions counted above.
+        xm::pattern_from_xm(cells, &mut PatternCounts::default())
+    }
+
+    /// Converts a command into XM bytes using only the ProTracker effects
+    fn cell(&self, command: &Command, state: &mut ChannelState, counts: &mut PatternCounts) -> [u8; 5] {
+        let (note, sample) = s3m::resolve_instrument(self.module, command);
+        let mut instrument = 0;
+        if let Some(sample) = sample.and_then(|number| SampleId::from_number(number).ok()) {
+            match self.transpose.get(sample.as_usize()) {
+                Some(&transpose) => {
+                    state.transpose = transpose;
+                    state.dropped_sample = false;
+                    instrument = sample.number();
+                }
+                None => state.dropped_sample = true,
+            }
+        }
+
+        let mut xm_note = 0;
+        let mut volume = command.volume;
+        match note {
+            Some(NoteCmd::Play(note)) => {
+                let note = i64::from(u8::from(note)) + state.transpose;
+                if !state.dropped_sample && NOTES.contains(&note) {
+                    xm_note = u8::try_from(XM_C_5 + note - IT_C_5).unwrap();
+                } else {
+                    counts.notes_dropped += 1;
+                }
+            }
+            // ProTracker can only silence the note with `C00`.
+            Some(note) if volume.is_none() => {
+                if note != NoteCmd::Cut {
+                    counts.effects_approximated += 1;
+                }
+                volume = Some(VolumeCmd::SetVolume(RangedU8::new(0)));
+            }
+            Some(_) => counts.effects_dropped += 1,
+            None => {}
+        }
+
+        let effect = command.effect.map(|effect| match effect {
+            EffectCmd::JumpOrder(order) => {
+                let position = self.order_map.get(order.as_usize()).copied().unwrap_or(0);
+                EffectCmd::JumpOrder(OrderIdx::new(u8::try_from(position.min(ORDERS - 1)).unwrap()))
+            }
+            effect => effect,
+        });
+        let [_, _, volume, effect, param] = xm::cell(&Command { note: None, instrument: None, volume, effect }, counts);
+        let (effect, param) = protracker_effect(volume, effect, param, &mut state.memory, counts);
+        [xm_note, instrument, 0, effect, param]
+    }
+}
+
+fn module_features(module: &Module, report: &mut Report) {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::ModuleFeature { feature });
+        }
+    };
+    feature(module.flags.contains(ModuleFlags::USE_INSTRUMENTS), "instruments");
+    feature(module.flags.contains(ModuleFlags::LINEAR_SLIDES), "linear slides");
+    feature(module.flags.contains(ModuleFlags::LINK_G_E_EFFECTS), "linked Gxx memory");
+    feature(module.global_volume.as_u8() != 128, "global volume");
+    feature(!module.message.is_empty(), "song message");
+    feature(module.name.as_bytes().len() > MAX_NAME_LENGTH, "song name longer than 20 characters");
+    feature(module.speed.as_u8() > 31, "speed above 31");
+    feature(module.tempo.as_u8() < 32, "tempo below 32");
+
+    if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        for (idx, instrument) in (0..).zip(&module.instruments) {
+            let instrument_id = InstrumentId::from_index(idx).unwrap();
+            let envelopes = [&instrument.volume_envelope, &instrument.panning_envelope, &instrument.pitch_filter_envelope];
+            if envelopes.iter().any(|envelope| envelope.flags.contains(EnvelopeFlags::ENABLED)) {
+                report.push(Issue::InstrumentFeature { instrument: instrument_id, feature: "envelopes" });
+            }
+            if instrument.new_note_action != NewNoteAction::Cut {
+                report.push(Issue::InstrumentFeature { instrument: instrument_id, feature: "new note action" });
+            }
+        }
+    }
+}
+
+/// Channels with commands, the first [`CHANNELS`] of them are kept
+fn kept_channels(module: &Module, report: &mut Report) -> Vec<Channel> {
+    let mut used = [false; 64];
+    for (channel, _) in module.patterns.iter().flat_map(|pattern| &pattern.rows).flat_map(Row::iter) {
+        used[channel.as_usize()] = true;
+    }
+    let mut channels = (0..)
+        .zip(used)
+        .filter_map(|(idx, used)| used.then_some(Channel::from_index(idx).unwrap()))
+        .collect::<Vec<_>>();
+    if channels.len() > CHANNELS {
+        report.push(Issue::ChannelsDropped { channels: channels.len(), max: CHANNELS });
+        channels.truncate(CHANNELS);
+    }
+
+    let amiga = amiga_channels(CHANNELS);
+    let settings = channels.iter().map(|channel| module.channels[channel.as_usize()]).collect::<Vec<_>>();
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::ModuleFeature { feature });
+        }
+    };
+    feature(settings.iter().zip(&amiga).any(|(channel, amiga)| channel.panning != amiga.panning), "channel panning");
+    feature(settings.iter().any(|channel| channel.volume.as_u8() != 64), "initial channel volume");
+    feature(settings.iter().any(|channel| channel.muted), "muted channels");
+    channels
+}
+
+/// Reduces the XM volume column and effect to a single ProTracker effect
+///
+/// The volume column is kept when the effect column is empty or has no ProTracker equivalent.
+/// `memory` holds the last parameters of the portamentos and of the volume slides.
+fn protracker_effect(volume: u8, effect: u8, param: u8, memory: &mut [u8; 2], counts: &mut PatternCounts) -> (u8, u8) {
+    use Converted::*;
+
+    /// XM `Rxy` multi retrigger
+    const RETRIGGER: u8 = 27;
+
+    let (x, y) = (param >> 4, param & 0x0F);
+    let effect = match effect {
+        _ if (effect, param) == (0, 0) => None,
+        0x0..=0x7 | 0x9..=0xD | 0xF => Some(Exact((effect, param))),
+        // `E0x` switches the Amiga filter and `E8x` does nothing in ProTracker.
+        0xE if x != 0x0 && x != 0x8 => Some(Exact((effect, param))),
+        RETRIGGER if (x == 0 || x == 8) && y > 0 => Some(Approximated((0xE, 0x90 | y))),
+        _ => Some(Dropped),
+    };
+
+    let (x, y) = (volume >> 4, volume & 0x0F);
+    let volume = match x {
+        0x0 => None,
+        0x1..=0x5 => Some(Exact((0xC, volume - 0x10))),
+        0x6 => Some(Exact((0xA, y))),
+        0x7 => Some(Exact((0xA, y << 4))),
+        0x8 => Some(Exact((0xE, 0xB0 | y))),
+        0x9 => Some(Exact((0xE, 0xA0 | y))),
+        0xB => Some(Exact((0x4, y))),
+        0xF => Some(Exact((0x3, y << 4))),
+        _ => Some(Dropped),
+    };
+
+    let converted = match (effect, volume) {
+        (None, None) => return (0, 0),
+        (Some(effect), None) => effect,
+        (None, Some(volume)) => volume,
+        (Some(Dropped), Some(volume)) => {
+            counts.effects_dropped += 1;
+            volume
+        }
+        (Some(effect), Some(_)) => {
+            counts.effects_dropped += 1;
+            effect
+        }
+    };
+    let Some((effect, param)) = converted.count(counts) else { return (0, 0) };
+
+    // ProTracker has no effect memory, `00` gets the last parameter of the channel in the pattern.
+    let slot = match effect {
+        0x1 | 0x2 => 0,
+        0x5 | 0x6 | 0xA => 1,
+        _ => return (effect, param),
+    };
+    if param != 0 {
+        memory[slot] = param;
+        return (effect, param);
+    }
+    match (effect, memory[slot]) {
+        // Without a volume slide only the portamento or vibrato continues.
+        (0x5, 0) => (0x3, 0),
+        (0x6, 0) => (0x4, 0),
+        (_, 0) => {
+            counts.effects_dropped += 1;
+            (0, 0)
+        }
+        (_, last) => {
+            counts.effects_approximated += 1;
+            (effect, last)
+        }
+    }
+}
+
+/// Converts a sample into one ProTracker can play
+///
+/// Returns the sample and the semitones its notes have to be moved by.
+fn sample_to_mod(sample_id: SampleId, sample: &Sample, report: &mut Report) -> (Sample, i64) {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::SampleFeature { sample: sample_id, feature });
+        }
+    };
+    let name_length = sample.name.as_bytes().len().min(MAX_SAMPLE_NAME_LENGTH);
+    feature(name_length < sample.name.as_bytes().len(), "name longer than 22 characters");
+    feature(sample.global_volume != 64, "global volume");
+    feature(sample.default_panning & 0x80 != 0, "default panning");
+    feature(sample.vibrato_depth != 0, "auto-vibrato");
+    feature(sample.sustain_loop.is_some(), "sustain loop");
+    feature(sample.loop_.is_some_and(|sample_loop| sample_loop.bidi), "bidirectional loop");
+    feature(sample.loop_.is_some_and(|sample_loop| sample_loop.start % 2 != 0 || sample_loop.end % 2 != 0), "loop not on word boundaries");
+
+    let length = usize::try_from(sample.length()).unwrap();
+    let kept = length.min(MAX_SAMPLE_LENGTH);
+    feature(kept < length, "length above 131070 samples");
+    feature(sample.data.as_ref().is_some_and(SampleData::is_16bit), "16-bit data");
+    let data = sample.data.as_ref().map(|data| match data {
+        SampleData::Pcm8(pcm) => SampleData::from(pcm[..kept].to_vec()),
+        SampleData::Pcm16(pcm) => {
+            SampleData::from(pcm[..kept].iter().map(|&value| i8::try_from(value >> 8).unwrap()).collect::<Vec<_>>())
+        }
+    });
+
+    // Amiga loops start and end on words.
+    let kept = u32::try_from(kept).unwrap();
+    let loop_ = sample.loop_.and_then(|sample_loop| {
+        let start = sample_loop.start & !1;
+        let end = sample_loop.end.min(kept) & !1;
+        (start < end).then_some(SampleLoop { start, end, bidi: false })
+    });
+
+    // Whole semitones are moved into the notes, ProTracker finetunes the rest in 1/8 semitones.
+    let eighths = round_i64(semitones_from(sample.samplerate_c5, BASE_FREQUENCY) * 8.0);
+    let transpose = (eighths + 4).div_euclid(8);
+    let finetune = i8::try_from(eighths - transpose * 8).unwrap();
+    let on_grid = samplerate_from(f64::from(i32::try_from(eighths).unwrap()) / 8.0, BASE_FREQUENCY);
+    feature(on_grid != sample.samplerate_c5, "C-5 frequency between finetune steps");
+
+    let default_volume = u16::from(sample.default_volume.min(64)) * u16::from(sample.global_volume.min(64)) / 64;
+    let sample = Sample {
+        name: name(&sample.name.as_bytes()[..name_length]),
+        filename: DosFilename::sanitize(""),
+        global_volume: 64,
+        default_volume: u8::try_from(default_volume).unwrap(),
+        default_panning: 32,
+        loop_,
+        sustain_loop: None,
+        samplerate_c5: samplerate_from(f64::from(finetune) / 8.0, BASE_FREQUENCY),
+        vibrato_speed: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        vibrato_type: 0,
+        data,
+        lazy_data: None,
+    };
+    (sample, transpose)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    fn module_file() -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(&[b'x'; 20]);
+        for idx in 0..SAMPLES {
+            file.extend_from_slice(&[b's'; 22]);
+            if idx == 0 {
+                // 4 words, finetune -1, volume 40, loop over the last 2 words.
+                file.extend_from_slice(&[0, 4, 0x0F, 40, 0, 2, 0, 2]);
+            } else {
+                file.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+            }
+        }
+        file.extend_from_slice(&[2, 0]);
+        file.extend_from_slice(&[0; ORDERS]);
+        file.extend_from_slice(b"M.K.");
+
+        let mut pattern = vec![0; ROWS * 4 * 4];
+        // Sample 1 at period 428 with `C20`, period 214 with `A00` in the next channel.
+        pattern[..4].copy_from_slice(&[0x01, 0xAC, 0x1C, 0x20]);
+        pattern[4..8].copy_from_slice(&[0x00, 0xD6, 0x0A, 0x00]);
+        file.extend_from_slice(&pattern);
+        file.extend_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        file
+    }
+
+    #[test]
+    fn import_module() {
+        let file = module_file();
+        let (module, report) = import::<VerboseError<&[u8]>>(&file).unwrap();
+        assert!(report.is_lossless(), "{:?}", report);
+
+        assert_eq!(module.orders, [Order::Index(PatternId::from_index(0).unwrap()), Order::Index(PatternId::from_index(0).unwrap()), Order::EndOfSong]);
+        assert_eq!(module.patterns.len(), 1);
+        assert!(module.channels[3].pan_position().is_some() && module.channels[4].muted);
+
+        let row = &module.patterns[0].rows[0];
+        let (channel, command) = row.iter().next().unwrap();
+        assert_eq!(channel, Channel::new(1));
+        assert_eq!(command.note, Some(NoteCmd::Play(Note::C_5)));
+        assert_eq!(command.instrument, Some(InstrumentId::from_number(1).unwrap()));
+        assert_eq!(command.volume, Some(VolumeCmd::SetVolume(RangedU8::new(32))));
+        let (channel, command) = row.iter().nth(1).unwrap();
+        assert_eq!(channel, Channel::new(2));
+        assert_eq!(command.note, Some(NoteCmd::Play(Note::C_6)));
+        assert_eq!(command.effect, None);
+
+        let sample = &module.samples[0];
+        assert_eq!(sample.default_volume, 40);
+        assert!(sample.samplerate_c5 < BASE_FREQUENCY);
+        assert_eq!(sample.loop_, Some(SampleLoop { start: 4, end: 8, bidi: false }));
+        assert_eq!(sample.data, Some(SampleData::from(vec![0i8, 1, 2, 3, 4, 5, 6, 7])));
+        assert!(module.samples[1].data.is_none());
+    }
+
+    #[test]
+    fn downgrade_module() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        module.name = Name::new("Song");
+        module.message.clear();
+        module.flags = ModuleFlags::STEREO;
+        module.global_volume = RangedU8::new(128);
+        module.set_speed(6).unwrap();
+        module.set_tempo(125).unwrap();
+        module.channels = amiga_channels(64);
+        module.instruments.clear();
+
+        let pattern = |idx| Order::Index(PatternId::from_index(idx).unwrap());
+        module.orders = vec![pattern(0), Order::Separator, pattern(1), Order::EndOfSong];
+        let play = |note| Command {
+            note: Some(NoteCmd::Play(note)),
+            instrument: Some(InstrumentId::from_number(1).unwrap()),
+            ..Command::EMPTY
+        };
+        let mut rows = vec![Row::empty(); 32];
+        rows[0] = Row::from_vec(vec![
+            (Channel::new(1), Command {
+                effect: Some(EffectCmd::PortamentoUp(Some(Portamento::Coarse(RangedU8::new(5))))),
+                ..play(Note::C_5)
+            }),
+            // Out of range once moved up an octave for the sample.
+            (Channel::new(2), play(Note::B_6)),
+            (Channel::new(3), play(Note::C_5)),
+            (Channel::new(4), play(Note::C_5)),
+            (Channel::new(5), play(Note::C_5)),
+        ]);
+        rows[1] = Row::from_vec(vec![
+            (Channel::new(1), Command { effect: Some(EffectCmd::PortamentoUp(None)), ..Command::EMPTY }),
+            (Channel::new(2), Command {
+                volume: Some(VolumeCmd::Panning(RangedU8::new(0))),
+                effect: Some(EffectCmd::JumpOrder(OrderIdx::new(2))),
+                ..Command::EMPTY
+            }),
+        ]);
+        module.patterns = vec![
+            Pattern { active_channels: ActiveChannels::all(), rows },
+            Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 70] },
+        ];
+
+        let mut sample = Sample {
+            name: Name::new("Lead"),
+            filename: DosFilename::sanitize("lead.wav"),
+            global_volume: 64,
+            default_volume: 64,
+            default_panning: 32,
+            loop_: None,
+            sustain_loop: None,
+            samplerate_c5: 2 * BASE_FREQUENCY,
+            vibrato_speed: 0,
+            vibrato_depth: 0,
+            vibrato_rate: 0,
+            vibrato_type: 0,
+            data: Some(SampleData::from(vec![0i16, 256, -512, 1000])),
+            lazy_data: None,
+        };
+        sample.set_loop(Some(SampleLoop { start: 1, end: 4, bidi: false })).unwrap();
+        module.samples = vec![sample];
+
+        let (downgraded, report) = downgrade(&module);
+        let pattern_id = |idx| PatternId::from_index(idx).unwrap();
+        let sample = SampleId::from_index(0).unwrap();
+        assert_eq!(report.issues, [
+            Issue::ChannelsDropped { channels: 5, max: CHANNELS },
+            Issue::SampleFeature { sample, feature: "loop not on word boundaries" },
+            Issue::SampleFeature { sample, feature: "16-bit data" },
+            Issue::NotesDropped { pattern: pattern_id(0), count: 1 },
+            Issue::EffectsDropped { pattern: pattern_id(0), count: 1 },
+            Issue::EffectsApproximated { pattern: pattern_id(0), count: 1 },
+            Issue::RowsDropped { pattern: pattern_id(1), rows: 70, max: ROWS },
+        ]);
+
+        assert_eq!(downgraded.orders, [pattern(0), pattern(1), Order::EndOfSong]);
+        let effect = |row: &Row, channel| row[Channel::new(channel)].effect.unwrap().to_string();
+        let rows = &downgraded.patterns[0].rows;
+        assert_eq!(rows.len(), ROWS);
+        assert_eq!(rows[0].iter().count(), 4);
+        assert_eq!(rows[0][Channel::new(1)].note, Some(NoteCmd::Play(Note::C_6)));
+        assert_eq!(effect(&rows[0], 1), "F05");
+        assert_eq!(rows[0][Channel::new(2)], Command { instrument: Some(InstrumentId::from_number(1).unwrap()), ..Command::EMPTY });
+        assert_eq!(effect(&rows[1], 1), "F05");
+        assert_eq!(rows[1][Channel::new(2)], Command { effect: Some(EffectCmd::JumpOrder(OrderIdx::new(1))), ..Command::EMPTY });
+        assert_eq!(effect(&rows[31], 1), "C00");
+        assert_eq!(downgraded.patterns[1].rows.len(), ROWS);
+
+        let sample = &downgraded.samples[0];
+        assert_eq!(sample.samplerate_c5, BASE_FREQUENCY);
+        assert_eq!(sample.loop_, Some(SampleLoop { start: 0, end: 4, bidi: false }));
+        assert_eq!(sample.data, Some(SampleData::from(vec![0i8, 1, -2, 3])));
+    }
+
+    #[test]
+    fn unknown_signature() {
+        let mut file = module_file();
+        file[1080..1084].copy_from_slice(b"FLT8");
+        assert!(import::<VerboseError<&[u8]>>(&file).is_err());
+    }
+}
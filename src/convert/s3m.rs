@@ -0,0 +1,826 @@
+//! Scream Tracker 3 modules (.s3m)
+//!
+//! S3M is the predecessor of IT and shares most of its effects, so the conversion is nearly
+//! lossless in both directions. [`import`] reads an S3M file into a [`Module`] without
+//! instruments, [`export`] writes a module into an S3M file.
+//!
+//! S3M has no instruments, no volume column commands besides the volume, at most 32 channels
+//! and patterns of exactly 64 rows. Modules using instruments are exported with the instrument
+//! column resolved through the sample map of each instrument. Shorter patterns are padded and
+//! end with a `C00` break. S3M `C-4` plays a sample at its C2 frequency like IT `C-5` plays it at
+//! its C-5 frequency, so notes and sample frequencies are the same in both formats.
+
+use super::{name, text, Converted, Issue, PatternCounts, Report};
+use crate::error::ContextError;
+use crate::parser;
+use crate::writer;
+use crate::*;
+use nom::bytes::complete::{tag, take};
+use nom::error::ParseError;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::{Err, IResult};
+use std::convert::TryFrom;
+
+
+const MAX_CHANNELS: usize = 32;
+const MAX_PATTERNS: usize = 100;
+const MAX_IT_PATTERNS: usize = 200;
+const MAX_SAMPLES: usize = 99;
+const MAX_ORDERS: usize = 256;
+const ROWS: usize = 64;
+
+/// IT note index of S3M `C-0`
+const FIRST_NOTE: u8 = 12;
+const NOTES: u8 = 96;
+
+/// Note cut `^^`
+const NOTE_CUT: u8 = 254;
+const NO_NOTE: u8 = 255;
+
+const HEADER_SIZE: usize = 0x60;
+const SAMPLE_HEADER_SIZE: usize = 0x50;
+
+/// Default panning is stored in the panning table
+const PANNING_TABLE: u8 = 0xFC;
+
+/// Sample data is stored unsigned
+const FORMAT_UNSIGNED: u16 = 2;
+
+const SAMPLE_LOOP: u8 = 1 << 0;
+const SAMPLE_STEREO: u8 = 1 << 1;
+const SAMPLE_16BIT: u8 = 1 << 2;
+
+
+/// Converts the module into a Scream Tracker 3 module file (.s3m)
+///
+/// See the [module documentation](self) for how the IT features are mapped.
+pub fn export(module: &Module) -> (Vec<u8>, Report) {
+    let mut report = Report::default();
+    module_features(module, &mut report);
+
+    let patterns = &module.patterns[..module.patterns.len().min(MAX_PATTERNS)];
+    if patterns.len() < module.patterns.len() {
+        report.push(Issue::ItemsDropped { kind: "patterns", count: module.patterns.len(), max: MAX_PATTERNS });
+    }
+    let samples = &module.samples[..module.samples.len().min(MAX_SAMPLES)];
+    if samples.len() < module.samples.len() {
+        report.push(Issue::ItemsDropped { kind: "samples", count: module.samples.len(), max: MAX_SAMPLES });
+    }
+    for (idx, sample) in (0..).zip(samples) {
+        sample_features(SampleId::from_index(idx).unwrap(), sample, &mut report);
+    }
+
+    let used_channels = module.patterns
+        .iter()
+        .flat_map(|pattern| &pattern.rows)
+        .flat_map(Row::iter)
+        .map(|(channel, _)| channel.as_usize() + 1)
+        .max()
+        .unwrap_or(0);
+    if used_channels > MAX_CHANNELS {
+        report.push(Issue::ChannelsDropped { channels: used_channels, max: MAX_CHANNELS });
+    }
+
+    // ST3 expects an even number of orders.
+    let mut orders = module.orders
+        .iter()
+        .filter(|order| match order {
+            Order::Index(pattern) => pattern.as_usize() < patterns.len(),
+            _ => true,
+        })
+        .map(|order| match order {
+            Order::Index(pattern) => pattern.as_u8(),
+            Order::Separator => 254,
+            Order::EndOfSong => 255,
+        })
+        .take(MAX_ORDERS)
+        .collect::<Vec<_>>();
+    if orders.len() % 2 == 1 {
+        orders.push(255);
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&text::<28>(module.name.as_bytes()));
+    out.extend_from_slice(&[0x1A, 16, 0, 0]);
+    for value in [orders.len(), samples.len(), patterns.len()] {
+        out.extend_from_slice(&u16::try_from(value).unwrap().to_le_bytes());
+    }
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&0x1320u16.to_le_bytes());
+    out.extend_from_slice(&FORMAT_UNSIGNED.to_le_bytes());
+    out.extend_from_slice(b"SCRM");
+    out.push(module.global_volume.as_u8() / 2);
+    out.push(module.speed.as_u8());
+    out.push(module.tempo.as_u8().max(32));
+    let stereo = if module.flags.contains(ModuleFlags::STEREO) { 0x80 } else { 0x00 };
+    out.push(stereo | module.sample_volume.as_u8().clamp(0x10, 0x7F));
+    out.push(0);
+    out.push(PANNING_TABLE);
+    out.extend_from_slice(&[0; 10]);
+    for (idx, channel) in (0..).zip(&module.channels[..MAX_CHANNELS]) {
+        out.push(channel_setting(idx, channel));
+    }
+    debug_assert_eq!(out.len(), HEADER_SIZE);
+    out.extend_from_slice(&orders);
+
+    // Parapointers are filled in once the positions are known.
+    let sample_pointers = out.len();
+    out.resize(out.len() + 2 * (samples.len() + patterns.len()), 0);
+    let pattern_pointers = sample_pointers + 2 * samples.len();
+    for channel in &module.channels[..MAX_CHANNELS] {
+        let pan = channel.pan_position().map_or(32, RangedU8::as_u8);
+        out.push(0x20 | u8::try_from((u16::from(pan) * 15 + 32) / 64).unwrap());
+    }
+
+    let mut sample_headers = Vec::with_capacity(samples.len());
+    for (idx, sample) in samples.iter().enumerate() {
+        align(&mut out);
+        set_parapointer(&mut out, sample_pointers + 2 * idx);
+        sample_headers.push(out.len());
+        write_sample_header(&mut out, sample);
+    }
+
+    for (idx, pattern) in (0..).zip(patterns) {
+        let pattern_id = PatternId::from_index(idx).unwrap();
+        align(&mut out);
+        if out.len() / 16 > usize::from(u16::MAX) {
+            report.push(Issue::ModuleFeature { feature: "pattern data past the first megabyte" });
+            break;
+        }
+        set_parapointer(&mut out, pattern_pointers + 2 * usize::from(idx));
+        let mut counts = PatternCounts::default();
+        write_pattern(&mut out, module, pattern, pattern_id, &mut counts, &mut report);
+        report.pattern_counts(pattern_id, &counts);
+    }
+
+    for (header, sample) in sample_headers.into_iter().zip(samples) {
+        align(&mut out);
+        // The sample data position is stored as a 24-bit paragraph number.
+        let paragraph = u32::try_from(out.len() / 16).unwrap();
+        out[header + 13] = u8::try_from(paragraph >> 16).unwrap_or(u8::MAX);
+        out[header + 14..header + 16].copy_from_slice(&u16::try_from(paragraph & 0xFFFF).unwrap().to_le_bytes());
+        write_sample_data(&mut out, sample);
+    }
+
+    (out, report)
+}
+
+/// Reads a Scream Tracker 3 module file (.s3m) into a [`Module`]
+///
+/// See the [module documentation](self) for how the S3M features are mapped.
+pub fn import<'i, E>(input: &'i [u8]) -> Result<(Module, Report), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut report = Report::default();
+
+    let (rest, header) = context!(header, "in S3M header")(input)?;
+    let (rest, orders) = take(usize::from(header.orders))(rest)?;
+    let (rest, sample_pointers) = context!(parapointers(header.samples), "reading sample pointers")(rest)?;
+    let (rest, pattern_pointers) = context!(parapointers(header.patterns), "reading pattern pointers")(rest)?;
+    let panning_table = if header.default_panning == PANNING_TABLE {
+        rest.get(..MAX_CHANNELS)
+    } else {
+        None
+    };
+
+    if sample_pointers.len() > MAX_SAMPLES {
+        report.push(Issue::ItemsDropped { kind: "samples", count: sample_pointers.len(), max: MAX_SAMPLES });
+    }
+    let unsigned = header.format == FORMAT_UNSIGNED;
+    let mut samples = Vec::with_capacity(sample_pointers.len().min(MAX_SAMPLES));
+    for (idx, &offset) in (0..).zip(sample_pointers.iter().take(MAX_SAMPLES)) {
+        let sample_id = SampleId::from_index(idx).unwrap();
+        let mut features = Vec::new();
+        let sample = context!(
+            |file| sample(file, offset, unsigned, &mut features).map(|sample| (file, sample)),
+            "in sample {}",
+            sample_id,
+        )(input)?.1;
+        report.issues.extend(features.into_iter().map(|feature| Issue::SampleFeature { sample: sample_id, feature }));
+        samples.push(sample);
+    }
+
+    if pattern_pointers.len() > MAX_IT_PATTERNS {
+        report.push(Issue::ItemsDropped { kind: "patterns", count: pattern_pointers.len(), max: MAX_IT_PATTERNS });
+    }
+    let mut patterns = Vec::with_capacity(pattern_pointers.len().min(MAX_IT_PATTERNS));
+    for (idx, &offset) in (0..).zip(pattern_pointers.iter().take(MAX_IT_PATTERNS)) {
+        let pattern_id = PatternId::from_index(idx).unwrap();
+        let mut counts = PatternCounts::default();
+        let pattern = context!(
+            |file| pattern(file, offset, &mut counts).map(|pattern| (file, pattern)),
+            "in pattern {}",
+            idx,
+        )(input)?.1;
+        report.pattern_counts(pattern_id, &counts);
+        patterns.push(pattern);
+    }
+
+    let orders = orders
+        .iter()
+        .filter_map(|&order| match order {
+            254 => Some(Order::Separator),
+            255 => Some(Order::EndOfSong),
+            _ => PatternId::from_index(order).ok().map(Order::Index),
+        })
+        .collect();
+
+    let stereo = header.master_volume & 0x80 != 0;
+    let mut channels = [ChannelSettings { muted: true, ..ChannelSettings::DEFAULT }; 64];
+    for (idx, (channel, &setting)) in channels.iter_mut().zip(&header.channels).enumerate() {
+        // Channels 16 to 31 are AdLib channels, 255 is unused.
+        let code = setting & 0x7F;
+        if code >= 16 {
+            continue;
+        }
+        let pan = match panning_table.map(|table| table[idx]) {
+            Some(pan) if pan & 0x20 != 0 => u8::try_from((u16::from(pan & 0x0F) * 64 + 7) / 15).unwrap(),
+            _ if !stereo => 32,
+            _ if code < 8 => 12,
+            _ => 52,
+        };
+        channel.panning = ChannelPanning::Position(RangedU8::try_from(pan).unwrap());
+        channel.muted = setting & 0x80 != 0;
+    }
+
+    let mut flags = ModuleFlags::empty();
+    flags.set(ModuleFlags::STEREO, stereo);
+
+    let module = Module {
+        name: name(&header.name),
+        message: String::new(),
+        highlight: (4, 16),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags,
+        global_volume: RangedU8::try_from(header.global_volume.min(64) * 2).unwrap(),
+        sample_volume: RangedU8::try_from(header.master_volume & 0x7F).unwrap(),
+        speed: RangedU8::try_from(header.speed.max(1)).unwrap(),
+        tempo: RangedU8::try_from(header.tempo.max(31)).unwrap(),
+        pan_separation: RangedU8::new(128),
+        pitch_wheel_depth: 0,
+        channels,
+        orders,
+        instruments: Vec::new(),
+        samples,
+        patterns,
+    };
+    Ok((module, report))
+}
+
+
+fn module_features(module: &Module, report: &mut Report) {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::ModuleFeature { feature });
+        }
+    };
+    let channels = &module.channels[..MAX_CHANNELS];
+    feature(module.flags.contains(ModuleFlags::USE_INSTRUMENTS), "instruments");
+    feature(module.flags.contains(ModuleFlags::LINEAR_SLIDES), "linear slides");
+    feature(!module.message.is_empty(), "song message");
+    feature(module.tempo.as_u8() < 32, "tempo below 32");
+    feature(channels.iter().any(ChannelSettings::is_surround), "surround channels");
+    feature(channels.iter().any(|channel| channel.volume.as_u8() != 64), "initial channel volume");
+
+    if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        for (idx, instrument) in (0..).zip(&module.instruments) {
+            let instrument_id = InstrumentId::from_index(idx).unwrap();
+            let envelopes = [&instrument.volume_envelope, &instrument.panning_envelope, &instrument.pitch_filter_envelope];
+            if envelopes.iter().any(|envelope| envelope.flags.contains(EnvelopeFlags::ENABLED)) {
+                report.push(Issue::InstrumentFeature { instrument: instrument_id, feature: "envelopes" });
+            }
+            if instrument.new_note_action != NewNoteAction::Cut {
+                report.push(Issue::InstrumentFeature { instrument: instrument_id, feature: "new note action" });
+            }
+        }
+    }
+}
+
+fn sample_features(sample_id: SampleId, sample: &Sample, report: &mut Report) {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::SampleFeature { sample: sample_id, feature });
+        }
+    };
+    feature(sample.sustain_loop.is_some(), "sustain loop");
+    feature(sample.loop_.map_or(false, |sample_loop| sample_loop.bidi), "bidirectional loop");
+    feature(sample.global_volume != 64, "global volume");
+    feature(sample.default_panning & 0x80 != 0, "default panning");
+    feature(sample.vibrato_depth != 0, "auto-vibrato");
+}
+
+/// Channel setting byte, even channels are on the left and odd ones on the right
+fn channel_setting(idx: u8, channel: &ChannelSettings) -> u8 {
+    let code = if idx % 2 == 0 { (idx / 2) % 8 } else { 8 + (idx / 2) % 8 };
+    if channel.muted { code | 0x80 } else { code }
+}
+
+/// Pads the output to the next 16 byte paragraph.
+fn align(out: &mut Vec<u8>) {
+    out.resize((out.len() + 15) / 16 * 16, 0);
+}
+
+/// Stores the paragraph of the current position into the parapointer at `pointer`.
+fn set_parapointer(out: &mut [u8], pointer: usize) {
+    let paragraph = u16::try_from(out.len() / 16).unwrap();
+    out[pointer..pointer + 2].copy_from_slice(&paragraph.to_le_bytes());
+}
+
+fn write_sample_header(out: &mut Vec<u8>, sample: &Sample) {
+    let has_data = sample.length() > 0;
+    let mut flags = 0;
+    if sample.loop_.is_some() {
+        flags |= SAMPLE_LOOP;
+    }
+    if matches!(sample.data, Some(SampleData::Pcm16(_))) {
+        flags |= SAMPLE_16BIT;
+    }
+    let (loop_start, loop_end) = sample.loop_.map_or((0, 0), |sample_loop| (sample_loop.start, sample_loop.end));
+
+    out.push(u8::from(has_data));
+    out.extend_from_slice(&text::<12>(sample.filename.as_bytes()));
+    // Data position, filled in when the data is written.
+    out.extend_from_slice(&[0; 3]);
+    for value in [sample.length(), loop_start, loop_end] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.push(sample.default_volume.min(64));
+    out.push(0);
+    out.push(0);
+    out.push(flags);
+    out.extend_from_slice(&sample.samplerate_c5.to_le_bytes());
+    out.extend_from_slice(&[0; 12]);
+    out.extend_from_slice(&text::<28>(sample.name.as_bytes()));
+    out.extend_from_slice(b"SCRS");
+}
+
+/// Writes the sample data unsigned
+fn write_sample_data(out: &mut Vec<u8>, sample: &Sample) {
+    match &sample.data {
+        Some(SampleData::Pcm8(data)) => out.extend(data.iter().map(|&value| value.to_le_bytes()[0] ^ 0x80)),
+        Some(SampleData::Pcm16(data)) => {
+            for &value in data.iter() {
+                let [low, high] = value.to_le_bytes();
+                out.extend_from_slice(&[low, high ^ 0x80]);
+            }
+        }
+        None => {}
+    }
+}
+
+
+/// Cell as it's stored in the S3M pattern
+struct Cell {
+    note: u8,
+    instrument: u8,
+    volume: Option<u8>,
+    effect: Option<(u8, u8)>,
+}
+
+fn write_pattern(
+    out: &mut Vec<u8>,
+    module: &Module,
+    pattern: &Pattern,
+    pattern_id: PatternId,
+    counts: &mut PatternCounts,
+    report: &mut Report,
+) {
+    let rows = &pattern.rows[..pattern.rows.len().min(ROWS)];
+    if rows.len() < pattern.rows.len() {
+        report.push(Issue::RowsDropped { pattern: pattern_id, rows: pattern.rows.len(), max: ROWS });
+    }
+    let break_row = (pattern.rows.len() < ROWS).then_some(rows.len().max(1) - 1);
+
+    let start = out.len();
+    out.extend_from_slice(&[0; 2]);
+    for idx in 0..ROWS {
+        let mut cells = rows.get(idx).map_or_else(Vec::new, |row| {
+            row.iter()
+                .filter(|(channel, _)| channel.as_usize() < MAX_CHANNELS)
+                .map(|(channel, command)| (channel.number() - 1, cell(module, command, counts)))
+                .collect::<Vec<_>>()
+        });
+
+        // Shorter patterns end with a break to the next pattern.
+        let has_jump = cells.iter().any(|(_, cell)| matches!(cell.effect, Some((2 | 3, _))));
+        if break_row == Some(idx) && !has_jump {
+            let free = (0..).find(|&channel| {
+                cells.iter().all(|(other, cell)| *other != channel || cell.effect.is_none())
+            });
+            match cells.iter_mut().find(|(other, _)| Some(*other) == free) {
+                Some((_, cell)) => cell.effect = Some((3, 0)),
+                None => cells.push((free.unwrap(), Cell { note: NO_NOTE, instrument: 0, volume: None, effect: Some((3, 0)) })),
+            }
+        }
+
+        for (channel, cell) in cells {
+            let mut what = channel;
+            if cell.note != NO_NOTE || cell.instrument != 0 {
+                what |= 0x20;
+            }
+            if cell.volume.is_some() {
+                what |= 0x40;
+            }
+            if cell.effect.is_some() {
+                what |= 0x80;
+            }
+            if what == channel {
+                continue;
+            }
+            out.push(what);
+            if what & 0x20 != 0 {
+                out.extend_from_slice(&[cell.note, cell.instrument]);
+            }
+            out.extend(cell.volume);
+            if let Some((effect, param)) = cell.effect {
+                out.extend_from_slice(&[effect, param]);
+            }
+        }
+        out.push(0);
+    }
+    let length = u16::try_from(out.len() - start).unwrap();
+    out[start..start + 2].copy_from_slice(&length.to_le_bytes());
+}
+
+fn cell(module: &Module, command: &Command, counts: &mut PatternCounts) -> Cell {
+    let (note, sample) = resolve_instrument(module, command);
+    let note = match note {
+        Some(NoteCmd::Play(note)) => match u8::from(note).checked_sub(FIRST_NOTE) {
+            Some(note) if note < NOTES => ((note / 12) << 4) | (note % 12),
+            _ => {
+                counts.notes_dropped += 1;
+                NO_NOTE
+            }
+        },
+        Some(NoteCmd::Cut) => NOTE_CUT,
+        Some(NoteCmd::Off | NoteCmd::Fade) => {
+            counts.effects_approximated += 1;
+            NOTE_CUT
+        }
+        None => NO_NOTE,
+    };
+    let instrument = sample.filter(|&sample| usize::from(sample) <= MAX_SAMPLES).unwrap_or(0);
+
+    let mut effect = command.effect.and_then(|effect| effect_to_s3m(&effect).count(counts));
+    let volume = match command.volume {
+        Some(VolumeCmd::SetVolume(volume)) => Some(volume.as_u8()),
+        // Panning moves to the effect column if it's free.
+        Some(VolumeCmd::Panning(panning)) if effect.is_none() => {
+            effect = Some((b'X' - b'@', panning.as_u8() * 2));
+            None
+        }
+        Some(_) => {
+            counts.effects_dropped += 1;
+            None
+        }
+        None => None,
+    };
+    Cell { note, instrument, volume, effect }
+}
+
+/// Returns the note and the sample number the command plays
+///
+/// Modules using instruments play the sample and the note translation the instrument maps the
+/// note to, commands without a note use the sample of `C-5`.
+pub(super) fn resolve_instrument(module: &Module, command: &Command) -> (Option<NoteCmd>, Option<u8>) {
+    if !module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        return (command.note, command.instrument.map(InstrumentId::number));
+    }
+    let Some(instrument) = command.instrument.and_then(|instrument| module.get(instrument)) else {
+        return (command.note, None);
+    };
+    let sample_map = &instrument.sample_map;
+    match command.note {
+        Some(NoteCmd::Play(note)) => (
+            Some(NoteCmd::Play(sample_map.note_translation_for(note))),
+            sample_map.sample_for(note).map(SampleId::number),
+        ),
+        note => (note, sample_map.sample_for(Note::C_5).map(SampleId::number)),
+    }
+}
+
+fn effect_to_s3m(effect: &EffectCmd) -> Converted<(u8, u8)> {
+    use Converted::*;
+
+    let (number, param) = writer::effect(effect);
+    let (x, y) = (param >> 4, param & 0x0F);
+    match b'@' + number {
+        // The row is stored in BCD.
+        b'C' if param <= 99 => Exact((number, ((param / 10) << 4) | (param % 10))),
+        b'C' => Approximated((number, 0)),
+        // S3M global volume goes up to 64.
+        b'V' if param % 2 == 0 => Exact((number, param / 2)),
+        b'V' => Approximated((number, param / 2)),
+        // S3M panning goes up to 0x80.
+        b'X' if param % 2 == 0 || param == 0xFF => Exact((number, u8::try_from((u16::from(param) + 1) / 2).unwrap())),
+        b'X' => Approximated((number, param / 2)),
+        b'S' => match x {
+            0x1..=0x4 | 0x8 | 0xB..=0xE => Exact((number, param)),
+            // Surround is stored as panning `XA4`.
+            0x9 if y == 0x1 => Exact((b'X' - b'@', 0xA4)),
+            _ => Dropped,
+        },
+        b'T' if param < 0x20 => Dropped,
+        b'M' | b'N' | b'P' | b'W' | b'Y' | b'Z' => Dropped,
+        _ => Exact((number, param)),
+    }
+}
+
+
+struct S3mHeader {
+    name: [u8; 28],
+    orders: u16,
+    samples: u16,
+    patterns: u16,
+    format: u16,
+    global_volume: u8,
+    speed: u8,
+    tempo: u8,
+    master_volume: u8,
+    default_panning: u8,
+    channels: [u8; MAX_CHANNELS],
+}
+
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], S3mHeader, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, name) = take(28usize)(input)?;
+    let (input, _marker) = take(4usize)(input)?;
+    let (input, orders) = le_u16(input)?;
+    let (input, samples) = le_u16(input)?;
+    let (input, patterns) = le_u16(input)?;
+    let (input, _flags) = le_u16(input)?;
+    let (input, _version) = le_u16(input)?;
+    let (input, format) = le_u16(input)?;
+    let (input, _) = tag(b"SCRM")(input)?;
+    let (input, global_volume) = le_u8(input)?;
+    let (input, speed) = le_u8(input)?;
+    let (input, tempo) = le_u8(input)?;
+    let (input, master_volume) = le_u8(input)?;
+    let (input, _click_removal) = le_u8(input)?;
+    let (input, default_panning) = le_u8(input)?;
+    let (input, _reserved) = take(10usize)(input)?;
+    let (input, channels) = take(MAX_CHANNELS)(input)?;
+
+    Ok((
+        input,
+        S3mHeader {
+            name: name.try_into().unwrap(),
+            orders,
+            samples,
+            patterns,
+            format,
+            global_volume,
+            // Speed 0 and 255 are ignored by ST3.
+            speed: if speed == 0 || speed == 255 { 6 } else { speed },
+            tempo,
+            master_volume,
+            default_panning,
+            channels: channels.try_into().unwrap(),
+        },
+    ))
+}
+
+/// Parses `count` parapointers into file offsets
+fn parapointers<'i, E>(count: u16) -> impl FnMut(&'i [u8]) -> IResult<&'i [u8], Vec<usize>, E>
+where
+    E: ParseError<&'i [u8]>,
+{
+    move |input| {
+        let (input, pointers) = nom::multi::count(le_u16, usize::from(count))(input)?;
+        Ok((input, pointers.into_iter().map(|pointer| usize::from(pointer) * 16).collect()))
+    }
+}
+
+fn sample<'i, E>(file: &'i [u8], offset: usize, unsigned: bool, features: &mut Vec<&'static str>) -> Result<Sample, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let Some(input) = file.get(offset..).filter(|input| input.len() >= SAMPLE_HEADER_SIZE) else {
+        bail!(file, "sample header offset {} is past the end of the file", offset);
+    };
+    let (input, kind) = le_u8(input)?;
+    let (input, filename) = take(12usize)(input)?;
+    let (input, position_high) = le_u8(input)?;
+    let (input, position_low) = le_u16(input)?;
+    let (input, length) = le_u32(input)?;
+    let (input, loop_start) = le_u32(input)?;
+    let (input, loop_end) = le_u32(input)?;
+    let (input, volume) = le_u8(input)?;
+    let (input, _reserved) = le_u8(input)?;
+    let (input, packing) = le_u8(input)?;
+    let (input, flags) = le_u8(input)?;
+    let (input, samplerate) = le_u32(input)?;
+    let (input, _internal) = take(12usize)(input)?;
+    let (_, name_bytes) = take(28usize)(input)?;
+
+    // Type 1 is a PCM sample, 2 and up are AdLib instruments.
+    let data = match kind {
+        1 if packing != 0 => {
+            features.push("packed sample data");
+            None
+        }
+        1 if length > 0 => {
+            let position = ((usize::from(position_high) << 16) | usize::from(position_low)) * 16;
+            let width = if flags & SAMPLE_16BIT != 0 { 2 } else { 1 };
+            let Some(input) = file.get(position..) else {
+                bail!(file, "sample data offset {} is past the end of the file", position);
+            };
+            // Stereo samples store the right channel after the left one, only the left is kept.
+            if flags & SAMPLE_STEREO != 0 {
+                features.push("stereo sample data");
+            }
+            let (_, bytes) = take(usize::try_from(length).unwrap_or(usize::MAX).saturating_mul(width))(input)?;
+            let toggle = if unsigned { 0x80 } else { 0x00 };
+            Some(if width == 2 {
+                let data = bytes
+                    .chunks_exact(2)
+                    .map(|pair| i16::from_le_bytes([pair[0], pair[1] ^ toggle]))
+                    .collect::<Vec<_>>();
+                SampleData::from(data)
+            } else {
+                let data = bytes.iter().map(|&byte| i8::from_le_bytes([byte ^ toggle])).collect::<Vec<_>>();
+                SampleData::from(data)
+            })
+        }
+        2.. => {
+            features.push("AdLib instrument");
+            None
+        }
+        _ => None,
+    };
+
+    let length = data.as_ref().map_or(0, |data| u32::try_from(data.len()).unwrap());
+    let sample_loop = SampleLoop { start: loop_start, end: loop_end.min(length), bidi: false };
+    let loop_ = (flags & SAMPLE_LOOP != 0 && sample_loop.validate(length).is_ok()).then_some(sample_loop);
+
+    Ok(Sample {
+        name: name(name_bytes),
+        filename: DosFilename { bytes: text::<13>(filename) },
+        global_volume: 64,
+        default_volume: volume.min(64),
+        default_panning: 32,
+        loop_,
+        sustain_loop: None,
+        samplerate_c5: samplerate,
+        vibrato_speed: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        vibrato_type: 0,
+        data,
+        lazy_data: None,
+    })
+}
+
+fn pattern<'i, E>(file: &'i [u8], offset: usize, counts: &mut PatternCounts) -> Result<Pattern, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    // Parapointer 0 is an empty pattern.
+    if offset == 0 {
+        return Ok(Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); ROWS] });
+    }
+    let Some(input) = file.get(offset..) else {
+        bail!(file, "pattern offset {} is past the end of the file", offset);
+    };
+    let (input, length) = le_u16(input)?;
+    let (_, mut data) = take(usize::from(length).saturating_sub(2))(input)?;
+
+    let mut active_channels = ActiveChannels::empty();
+    let mut rows = Vec::with_capacity(ROWS);
+    for _ in 0..ROWS {
+        let mut commands = Vec::new();
+        // Some writers leave out the end markers of the last empty rows.
+        while let Some((&what, rest)) = data.split_first() {
+            data = rest;
+            if what == 0 {
+                break;
+            }
+            let (mut note_instrument, mut volume, mut effect) = ((NO_NOTE, 0), None, None);
+            if what & 0x20 != 0 {
+                let (rest, bytes) = nom::sequence::pair(le_u8, le_u8)(data)?;
+                note_instrument = bytes;
+                data = rest;
+            }
+            if what & 0x40 != 0 {
+                let (rest, volume_byte) = le_u8(data)?;
+                volume = Some(volume_byte);
+                data = rest;
+            }
+            if what & 0x80 != 0 {
+                let (rest, effect_bytes) = nom::sequence::pair(le_u8, le_u8)(data)?;
+                effect = Some(effect_bytes);
+                data = rest;
+            }
+            let channel = Channel::from_index(what & 0x1F).unwrap();
+            let (note, instrument) = note_instrument;
+            if let Some(command) = command_from_s3m(note, instrument, volume, effect, counts) {
+                commands.push((channel, command));
+            }
+        }
+        active_channels |= commands.iter().map(|(channel, _)| *channel).collect();
+        rows.push(Row::from_vec(commands));
+    }
+
+    Ok(Pattern { active_channels, rows })
+}
+
+fn command_from_s3m(
+    note: u8,
+    instrument: u8,
+    volume: Option<u8>,
+    effect: Option<(u8, u8)>,
+    counts: &mut PatternCounts,
+) -> Option<Command> {
+    let note = match note {
+        NOTE_CUT => Some(NoteCmd::Cut),
+        NO_NOTE => None,
+        _ => {
+            let (octave, key) = (note >> 4, note & 0x0F);
+            if octave < NOTES / 12 && key < 12 {
+                Some(NoteCmd::Play(Note::try_from(FIRST_NOTE + octave * 12 + key).unwrap()))
+            } else {
+                counts.notes_dropped += 1;
+                None
+            }
+        }
+    };
+    let volume = volume.and_then(|volume| {
+        let converted = if volume <= 64 { Converted::Exact(volume) } else { Converted::Dropped };
+        converted.and_then(parser::volume).count(counts)
+    });
+    let command = Command {
+        note,
+        instrument: InstrumentId::from_number(instrument).ok(),
+        volume,
+        effect: effect.and_then(|(effect, param)| effect_from_s3m(effect, param)?.count(counts)),
+    };
+    (command != Command::EMPTY).then_some(command)
+}
+
+/// Converts an S3M effect, `None` if it does nothing
+fn effect_from_s3m(effect: u8, param: u8) -> Option<Converted<EffectCmd>> {
+    use Converted::*;
+
+    if !(1..=26).contains(&effect) {
+        return None;
+    }
+    let (x, y) = (param >> 4, param & 0x0F);
+    // S3M and IT share the effect letters.
+    let converted = match b'@' + effect {
+        b'C' => Exact((effect, x * 10 + y)),
+        b'V' => Exact((effect, param.min(64) * 2)),
+        b'X' if param == 0xA4 => Exact((b'S' - b'@', 0x91)),
+        b'X' => Exact((effect, u8::try_from((u16::from(param) * 2).min(0xFF)).unwrap())),
+        // `S0x` sets the Amiga filter and `SAx` is the old stereo control.
+        b'S' if x == 0x0 || x == 0xA => Dropped,
+        _ => Exact((effect, param)),
+    };
+    Some(converted.and_then(|(effect, param)| parser::effect(effect, param)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    #[test]
+    fn roundtrip() {
+        let module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        let (bytes, report) = export(&module);
+        assert!(report.issues.contains(&Issue::ModuleFeature { feature: "song message" }));
+
+        let (imported, _) = import::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(imported.speed, module.speed);
+        assert_eq!(imported.samples.len(), module.samples.len());
+        for (imported, sample) in imported.samples.iter().zip(&module.samples) {
+            assert_eq!(imported.data, sample.data);
+            assert_eq!(imported.samplerate_c5, sample.samplerate_c5);
+        }
+        assert_eq!(imported.patterns.len(), module.patterns.len());
+    }
+
+    #[test]
+    fn effects() {
+        for effect in [
+            EffectCmd::BreakRow(32),
+            EffectCmd::SetGlobalVolume(RangedU8::new(0x40)),
+            EffectCmd::SetPanningPosition(0xFF),
+            EffectCmd::VolumeSlide(Some(VolumeSlide::FineUp(RangedU8::new(3)))),
+            EffectCmd::Special(Some(Special::SetSurround(true))),
+            EffectCmd::Special(Some(Special::NoteCut(RangedU8::new(2)))),
+        ] {
+            let (number, param) = match effect_to_s3m(&effect) {
+                Converted::Exact(s3m) => s3m,
+                _ => panic!("{:?} is not converted exactly", effect),
+            };
+            let imported = effect_from_s3m(number, param);
+            assert!(matches!(imported, Some(Converted::Exact(imported)) if imported == effect), "{:?}", effect);
+        }
+        assert!(matches!(effect_to_s3m(&EffectCmd::SetChannelVolume(RangedU8::new(10))), Converted::Dropped));
+    }
+}
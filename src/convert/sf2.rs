@@ -0,0 +1,468 @@
+//! SoundFont 2 files (.sf2)
+//!
+//! [`export`] bundles all instruments of a module into one SoundFont, each instrument becomes a
+//! preset numbered by its index (presets above 127 continue in the next bank). Modules which
+//! don't use instruments get one preset for each sample. Key mapping, volumes, panning, loops and
+//! the volume envelope are converted the same way as for the [SFZ export](super::sfz); the
+//! SoundFont can't limit the polyphony of a key, New Note Actions are listed in the [`Report`]
+//! along with the other instrument features.
+//!
+//! Samples are stored as 16-bit data (8-bit samples are scaled up) with their C-5 frequency as
+//! the sample rate and key 60 as the original pitch. A sample with a loop plays it continuously,
+//! a sample with only a sustain loop loops until the key is released and then plays to the end.
+
+use super::sfz::{amplitude_envelope, regions};
+use super::{round_clamp, text, Issue, Report};
+use crate::*;
+use std::convert::TryFrom;
+
+
+/// Zero samples after each sample, the minimum required by the specification
+const SAMPLE_PADDING: usize = 46;
+
+/// MIDI note the samples play unchanged at, IT `C-5`
+const ORIGINAL_PITCH: u8 = 60;
+
+/// Sample types
+const MONO_SAMPLE: u16 = 1;
+
+/// Sample modes
+const NO_LOOP: u16 = 0;
+const CONTINUOUS_LOOP: u16 = 1;
+const SUSTAIN_LOOP: u16 = 3;
+
+/// Generators used by the zones
+const PAN: u16 = 17;
+const ATTACK_VOL_ENV: u16 = 34;
+const DECAY_VOL_ENV: u16 = 36;
+const SUSTAIN_VOL_ENV: u16 = 37;
+const RELEASE_VOL_ENV: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const INITIAL_ATTENUATION: u16 = 48;
+const COARSE_TUNE: u16 = 51;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const OVERRIDING_ROOT_KEY: u16 = 58;
+
+/// Shortest and longest envelope times in timecents
+const MIN_TIMECENTS: i16 = -12000;
+const MAX_TIMECENTS: i16 = 8000;
+
+/// Highest attenuation in centibels
+const MAX_ATTENUATION: i16 = 1440;
+
+
+/// Instrument as it's stored in the SoundFont
+struct Sf2Instrument<'m> {
+    name: &'m Name,
+
+    /// Generators of each zone, in the order they are stored
+    zones: Vec<Vec<(u16, [u8; 2])>>,
+}
+
+/// Position of a sample in the `smpl` chunk, in samples
+struct Sf2Sample {
+    start: u32,
+    end: u32,
+    sample_loop: Option<SampleLoop>,
+    mode: u16,
+}
+
+
+/// Converts the instruments and samples of the module into a SoundFont 2 file (.sf2)
+///
+/// See the [module documentation](self) for how the IT features are mapped.
+pub fn export(module: &Module) -> (Vec<u8>, Report) {
+    let mut report = Report::default();
+
+    // Sample data, every sample followed by the padding.
+    let mut data = Vec::new();
+    let mut samples = Vec::with_capacity(module.samples.len());
+    for (idx, sample) in (0..).zip(&module.samples) {
+        let sample_id = SampleId::from_index(idx).unwrap();
+        samples.push(sample_from_it(sample_id, sample, &mut data, &mut report));
+    }
+
+    let instruments = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        (0..)
+            .zip(&module.instruments)
+            .map(|(idx, instrument)| {
+                let instrument_id = InstrumentId::from_index(idx).unwrap();
+                instrument_from_it(module, instrument_id, instrument, &samples, &mut report)
+            })
+            .collect::<Vec<_>>()
+    } else {
+        // Sample mode, each sample plays on all keys.
+        (0..)
+            .zip(&module.samples)
+            .zip(&samples)
+            .map(|((idx, sample), sf2)| Sf2Instrument {
+                name: &sample.name,
+                zones: sf2
+                    .iter()
+                    .map(|sf2| zone(0, 119, i16::from(ORIGINAL_PITCH), idx, sample, sf2, None, None))
+                    .collect(),
+            })
+            .collect()
+    };
+
+    let mut info = Vec::new();
+    chunk(&mut info, b"ifil", &[2, 0, 1, 0]);
+    chunk(&mut info, b"isng", &zero_terminated(b"EMU8000"));
+    let name = if module.name.as_bytes().is_empty() { &b"ittech"[..] } else { module.name.as_bytes() };
+    chunk(&mut info, b"INAM", &zero_terminated(name));
+    chunk(&mut info, b"ISFT", &zero_terminated(b"ittech"));
+
+    let mut sdta = Vec::new();
+    chunk(&mut sdta, b"smpl", &data);
+
+    let mut out = Vec::new();
+    list(&mut out, b"INFO", &info);
+    list(&mut out, b"sdta", &sdta);
+    list(&mut out, b"pdta", &hydra(module, &instruments, &samples));
+
+    let mut riff = Vec::with_capacity(out.len() + 12);
+    riff.extend_from_slice(b"RIFF");
+    riff.extend_from_slice(&u32::try_from(out.len() + 4).unwrap().to_le_bytes());
+    riff.extend_from_slice(b"sfbk");
+    riff.extend_from_slice(&out);
+    (riff, report)
+}
+
+
+/// Appends the sample data and returns its position, `None` for samples without data
+fn sample_from_it(sample_id: SampleId, sample: &Sample, data: &mut Vec<u8>, report: &mut Report) -> Option<Sf2Sample> {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::SampleFeature { sample: sample_id, feature });
+        }
+    };
+    feature(sample.loop_.is_some() && sample.sustain_loop.is_some(), "sustain loop");
+    feature(sample.loop_.or(sample.sustain_loop).map_or(false, |sample_loop| sample_loop.bidi), "bidirectional loop");
+    feature(sample.vibrato_depth != 0, "auto-vibrato");
+
+    if sample.length() == 0 {
+        return None;
+    }
+    let start = u32::try_from(data.len() / 2).unwrap();
+    match &sample.data {
+        Some(SampleData::Pcm8(pcm)) => data.extend(pcm.iter().flat_map(|&x| (i16::from(x) << 8).to_le_bytes())),
+        Some(SampleData::Pcm16(pcm)) => data.extend(pcm.iter().flat_map(|&x| x.to_le_bytes())),
+        None => {}
+    }
+    let end = start + sample.length();
+    data.resize(data.len() + 2 * SAMPLE_PADDING, 0);
+
+    let (sample_loop, mode) = match (sample.loop_, sample.sustain_loop) {
+        (Some(sample_loop), _) => (Some(sample_loop), CONTINUOUS_LOOP),
+        (None, Some(sustain_loop)) => (Some(sustain_loop), SUSTAIN_LOOP),
+        (None, None) => (None, NO_LOOP),
+    };
+    Some(Sf2Sample { start, end, sample_loop, mode })
+}
+
+fn instrument_from_it<'m>(
+    module: &'m Module,
+    instrument_id: InstrumentId,
+    instrument: &'m Instrument,
+    samples: &[Option<Sf2Sample>],
+    report: &mut Report,
+) -> Sf2Instrument<'m> {
+    let mut features = Vec::new();
+    let mut feature = |present: bool, feature| {
+        if present {
+            features.push(feature);
+        }
+    };
+    feature(instrument.new_note_action != NewNoteAction::Continue, "new note action");
+    feature(instrument.duplicate_check_type != DuplicateCheckType::Off, "duplicate note check");
+    feature(
+        instrument.flags.intersects(InstrumentFlags::ENABLE_FILTER_CUTOFF | InstrumentFlags::ENABLE_FILTER_RESONANCE),
+        "resonant filter",
+    );
+    feature(
+        instrument.random_volume_variation.as_u8() != 0 || instrument.random_panning_variation.as_u8() != 0,
+        "random variation",
+    );
+    feature(instrument.pitch_pan_separation != 0, "pitch-pan separation");
+    feature(instrument.panning_envelope.flags.contains(EnvelopeFlags::ENABLED), "panning envelope");
+    let pitch_envelope = &instrument.pitch_filter_envelope;
+    if pitch_envelope.flags.contains(EnvelopeFlags::ENABLED) {
+        if pitch_envelope.flags.contains(EnvelopeFlags::FILTER) {
+            feature(true, "filter envelope");
+        } else {
+            feature(true, "pitch envelope");
+        }
+    }
+    feature(instrument.volume_envelope.flags.contains(EnvelopeFlags::ENABLED), "volume envelope shape");
+    report.issues.extend(features.into_iter().map(|feature| Issue::InstrumentFeature { instrument: instrument_id, feature }));
+
+    let tick = 2.5 / f64::from(module.tempo.as_u8());
+    let adsr = amplitude_envelope(&instrument.volume_envelope, instrument.instrument_fadeout, tick);
+    let envelope = [
+        (ATTACK_VOL_ENV, timecents(Some(adsr.attack))),
+        (DECAY_VOL_ENV, timecents(Some(adsr.decay))),
+        (SUSTAIN_VOL_ENV, attenuation(adsr.sustain / 100.0)),
+        (RELEASE_VOL_ENV, timecents(adsr.release)),
+    ];
+    let panning = instrument.flags.contains(InstrumentFlags::ENABLE_PANNING).then_some(instrument.default_panning.as_u8());
+    let volume = f64::from(instrument.global_volume) / 128.0;
+
+    let zones = regions(&instrument.sample_map)
+        .into_iter()
+        .filter_map(|(sample_id, lokey, hikey, keycenter)| {
+            let sample = module.get(sample_id)?;
+            let sf2 = samples[sample_id.as_usize()].as_ref()?;
+            let mut zone = zone(lokey, hikey, keycenter, sample_id.as_u8(), sample, sf2, panning, Some(volume));
+            // The sample ID has to be the last generator.
+            let sample_generator = zone.pop().unwrap();
+            zone.extend(envelope.iter().map(|&(generator, amount)| (generator, amount.to_le_bytes())));
+            zone.push(sample_generator);
+            Some(zone)
+        })
+        .collect();
+
+    Sf2Instrument { name: &instrument.name, zones }
+}
+
+/// Generators of a zone playing `sample` on the keys `lokey..=hikey`
+///
+/// The sample plays unchanged at `keycenter`, the panning of the sample overrides the
+/// instrument `panning` and `volume` scales the volume of the sample.
+#[allow(clippy::too_many_arguments)]
+fn zone(
+    lokey: u8,
+    hikey: u8,
+    keycenter: i16,
+    sample_index: u8,
+    sample: &Sample,
+    sf2: &Sf2Sample,
+    panning: Option<u8>,
+    volume: Option<f64>,
+) -> Vec<(u16, [u8; 2])> {
+    let mut zone = vec![(KEY_RANGE, [lokey, hikey])];
+    let mut push = |generator, amount: i16| zone.push((generator, amount.to_le_bytes()));
+
+    let gain = f64::from(sample.default_volume) / 64.0 * f64::from(sample.global_volume) / 64.0 * volume.unwrap_or(1.0);
+    push(INITIAL_ATTENUATION, attenuation(gain));
+    let panning = if sample.default_panning & 0x80 != 0 { Some(sample.default_panning & 0x7F) } else { panning };
+    if let Some(panning) = panning {
+        push(PAN, ((i16::from(panning.min(64)) - 32) * 500) / 32);
+    }
+    // The root key is limited to MIDI keys, the rest of the transposition is done by tuning.
+    let root_key = keycenter.clamp(0, 127);
+    push(OVERRIDING_ROOT_KEY, root_key);
+    if root_key != keycenter {
+        push(COARSE_TUNE, root_key - keycenter);
+    }
+    push(SAMPLE_MODES, i16::try_from(sf2.mode).unwrap());
+    push(SAMPLE_ID, i16::from(sample_index));
+    zone
+}
+
+/// Converts seconds into timecents, `None` is the longest time
+fn timecents(seconds: Option<f64>) -> i16 {
+    match seconds {
+        Some(seconds) if seconds > 0.0 => round_clamp(1200.0 * seconds.log2(), MIN_TIMECENTS, MAX_TIMECENTS),
+        Some(_) => MIN_TIMECENTS,
+        None => MAX_TIMECENTS,
+    }
+}
+
+/// Converts a gain into an attenuation in centibels
+fn attenuation(gain: f64) -> i16 {
+    if gain > 0.0 {
+        round_clamp(-200.0 * gain.log10(), 0, MAX_ATTENUATION)
+    } else {
+        MAX_ATTENUATION
+    }
+}
+
+/// The `pdta` list with the presets, instruments and sample headers
+fn hydra(module: &Module, instruments: &[Sf2Instrument], samples: &[Option<Sf2Sample>]) -> Vec<u8> {
+    let mut phdr = Vec::new();
+    let mut pbag = Vec::new();
+    let mut pgen = Vec::new();
+    let mut inst = Vec::new();
+    let mut ibag = Vec::new();
+    let mut igen = Vec::new();
+    let mut shdr = Vec::new();
+
+    let mut preset_zones = 0u16;
+    let mut instrument_zones = 0u16;
+    let mut instrument_generators = 0u16;
+    let mut preset_generators = 0u16;
+    for (idx, instrument) in (0u16..).zip(instruments) {
+        // One preset with a single zone for each instrument.
+        phdr.extend_from_slice(&name_field(instrument.name.as_bytes()));
+        for value in [idx % 128, idx / 128, preset_zones] {
+            phdr.extend_from_slice(&value.to_le_bytes());
+        }
+        // Library, genre and morphology.
+        phdr.extend_from_slice(&[0; 12]);
+        bag(&mut pbag, preset_generators);
+        generator(&mut pgen, INSTRUMENT, idx.to_le_bytes());
+        preset_zones += 1;
+        preset_generators += 1;
+
+        inst.extend_from_slice(&name_field(instrument.name.as_bytes()));
+        inst.extend_from_slice(&instrument_zones.to_le_bytes());
+        for zone in &instrument.zones {
+            bag(&mut ibag, instrument_generators);
+            for &(kind, amount) in zone {
+                generator(&mut igen, kind, amount);
+            }
+            instrument_zones += 1;
+            instrument_generators += u16::try_from(zone.len()).unwrap();
+        }
+    }
+
+    // Every list ends with a terminal record pointing past the last entry.
+    phdr.extend_from_slice(&name_field(b"EOP"));
+    phdr.extend_from_slice(&[0; 4]);
+    phdr.extend_from_slice(&preset_zones.to_le_bytes());
+    phdr.extend_from_slice(&[0; 12]);
+    bag(&mut pbag, preset_generators);
+    generator(&mut pgen, 0, [0; 2]);
+    inst.extend_from_slice(&name_field(b"EOI"));
+    inst.extend_from_slice(&instrument_zones.to_le_bytes());
+    bag(&mut ibag, instrument_generators);
+    generator(&mut igen, 0, [0; 2]);
+
+    // Sample IDs are the indices of the IT samples, samples without data get empty headers.
+    for (sample, sf2) in module.samples.iter().zip(samples) {
+        shdr.extend_from_slice(&name_field(sample.name.as_bytes()));
+        let (start, end, (loop_start, loop_end)) = match sf2 {
+            Some(sf2) => (
+                sf2.start,
+                sf2.end,
+                sf2.sample_loop.map_or((0, 0), |sample_loop| (sf2.start + sample_loop.start, sf2.start + sample_loop.end)),
+            ),
+            None => (0, 0, (0, 0)),
+        };
+        for value in [start, end, loop_start, loop_end, sample.samplerate_c5.max(1)] {
+            shdr.extend_from_slice(&value.to_le_bytes());
+        }
+        // Original pitch, pitch correction and sample link.
+        shdr.extend_from_slice(&[ORIGINAL_PITCH, 0, 0, 0]);
+        shdr.extend_from_slice(&MONO_SAMPLE.to_le_bytes());
+    }
+    shdr.extend_from_slice(&name_field(b"EOS"));
+    shdr.extend_from_slice(&[0; 26]);
+
+    // No modulators, just the terminal records.
+    let modulators = [0; 10];
+
+    let mut pdta = Vec::new();
+    chunk(&mut pdta, b"phdr", &phdr);
+    chunk(&mut pdta, b"pbag", &pbag);
+    chunk(&mut pdta, b"pmod", &modulators);
+    chunk(&mut pdta, b"pgen", &pgen);
+    chunk(&mut pdta, b"inst", &inst);
+    chunk(&mut pdta, b"ibag", &ibag);
+    chunk(&mut pdta, b"imod", &modulators);
+    chunk(&mut pdta, b"igen", &igen);
+    chunk(&mut pdta, b"shdr", &shdr);
+    pdta
+}
+
+fn bag(out: &mut Vec<u8>, generator: u16) {
+    out.extend_from_slice(&generator.to_le_bytes());
+    // Modulator index.
+    out.extend_from_slice(&[0; 2]);
+}
+
+fn generator(out: &mut Vec<u8>, kind: u16, amount: [u8; 2]) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&amount);
+}
+
+/// Name cut to 19 bytes and null-padded to 20
+fn name_field(bytes: &[u8]) -> [u8; 20] {
+    text::<20>(&bytes[..bytes.len().min(19)])
+}
+
+/// String with a null terminator, padded to an even length
+fn zero_terminated(bytes: &[u8]) -> Vec<u8> {
+    let mut field = bytes.to_vec();
+    field.push(0);
+    if field.len() % 2 != 0 {
+        field.push(0);
+    }
+    field
+}
+
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 != 0 {
+        out.push(0);
+    }
+}
+
+fn list(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(b"LIST");
+    out.extend_from_slice(&u32::try_from(data.len() + 4).unwrap().to_le_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Finds the data of the chunk `id` in a RIFF file.
+    fn find_chunk<'a>(riff: &'a [u8], id: &[u8; 4]) -> &'a [u8] {
+        let position = riff.windows(4).position(|window| window == id).unwrap();
+        let size = u32::from_le_bytes(riff[position + 4..position + 8].try_into().unwrap());
+        &riff[position + 8..position + 8 + usize::try_from(size).unwrap()]
+    }
+
+    #[test]
+    fn export_instrument() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        let file = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/compression/compressed.iti")).unwrap();
+        let mut instrument = file.instrument;
+        let first = SampleId::from_index(0).unwrap();
+        for note in 0..120 {
+            let note = Note::try_from(note).unwrap();
+            instrument.sample_map.set(note, note, Some(first));
+        }
+        module.flags |= ModuleFlags::USE_INSTRUMENTS;
+        module.samples = file.samples;
+        module.instruments = vec![instrument];
+
+        let (sf2, _) = export(&module);
+        assert_eq!(&sf2[..4], b"RIFF");
+        assert_eq!(&sf2[8..12], b"sfbk");
+        assert_eq!(usize::try_from(u32::from_le_bytes(sf2[4..8].try_into().unwrap())).unwrap(), sf2.len() - 8);
+
+        // One preset and the terminal record.
+        assert_eq!(find_chunk(&sf2, b"phdr").len(), 2 * 38);
+        assert_eq!(find_chunk(&sf2, b"shdr").len(), (module.samples.len() + 1) * 46);
+        let smpl = find_chunk(&sf2, b"smpl");
+        let length = usize::try_from(module.samples[0].length()).unwrap();
+        assert!(smpl.len() >= 2 * (length + SAMPLE_PADDING));
+
+        // A single zone: key range first, sample ID last.
+        let igen = find_chunk(&sf2, b"igen");
+        assert_eq!(&igen[..4], &[43, 0, 0, 119]);
+        let last = igen.len() - 8;
+        assert_eq!(&igen[last..last + 4], &[53, 0, 0, 0]);
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(timecents(Some(1.0)), 0);
+        assert_eq!(timecents(Some(2.0)), 1200);
+        assert_eq!(timecents(Some(0.0)), MIN_TIMECENTS);
+        assert_eq!(timecents(None), MAX_TIMECENTS);
+        assert_eq!(attenuation(1.0), 0);
+        assert_eq!(attenuation(0.5), 60);
+        assert_eq!(attenuation(0.0), MAX_ATTENUATION);
+    }
+}
@@ -0,0 +1,288 @@
+//! SFZ instruments (.sfz)
+//!
+//! [`export`] converts an instrument into an SFZ file and the WAV files of the samples it uses.
+//! Every run of notes playing the same sample with the same note translation becomes a region.
+//! IT note numbers are used as MIDI keys, IT `C-5` is key 60, and the samples are written with
+//! their C-5 frequency as the sample rate so they play unchanged at the key where the note
+//! translation gives `C-5`.
+//!
+//! The volume envelope is approximated with the ADSR amplitude envelope, the instrument fadeout
+//! becomes the release when the envelope doesn't end the note itself. New Note Actions other than
+//! Continue limit the note to one voice per key (`note_polyphony=1`), cutting the old voice
+//! quickly or letting it release. Panning and pitch envelopes, filters, random variations and
+//! auto-vibrato are listed in the [`Report`].
+
+use super::{Issue, Report};
+use crate::*;
+use std::fmt::Write;
+
+
+/// SFZ file with the samples it references
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sfz {
+    /// Contents of the .sfz file
+    pub text: String,
+
+    /// WAV files referenced from the regions, the names are relative to the .sfz file
+    pub samples: Vec<(String, Vec<u8>)>,
+}
+
+/// Attack, decay and release in seconds, sustain in percent
+pub(super) struct Adsr {
+    pub(super) attack: f64,
+    pub(super) decay: f64,
+    pub(super) sustain: f64,
+    pub(super) release: Option<f64>,
+}
+
+
+/// Converts an instrument of `module` into an SFZ file
+///
+/// Returns `None` if the instrument doesn't exist. See the [module documentation](self) for how
+/// the IT features are mapped.
+pub fn export(module: &Module, instrument_id: InstrumentId) -> Option<(Sfz, Report)> {
+    let instrument = module.get(instrument_id)?;
+    let mut report = Report::default();
+    let mut features = Vec::new();
+    let mut feature = |present: bool, feature| {
+        if present {
+            features.push(feature);
+        }
+    };
+    feature(instrument.duplicate_check_type != DuplicateCheckType::Off, "duplicate note check");
+    feature(
+        instrument.flags.intersects(InstrumentFlags::ENABLE_FILTER_CUTOFF | InstrumentFlags::ENABLE_FILTER_RESONANCE),
+        "resonant filter",
+    );
+    feature(
+        instrument.random_volume_variation.as_u8() != 0 || instrument.random_panning_variation.as_u8() != 0,
+        "random variation",
+    );
+    feature(instrument.pitch_pan_separation != 0, "pitch-pan separation");
+    feature(instrument.panning_envelope.flags.contains(EnvelopeFlags::ENABLED), "panning envelope");
+    let pitch_envelope = &instrument.pitch_filter_envelope;
+    if pitch_envelope.flags.contains(EnvelopeFlags::ENABLED) {
+        if pitch_envelope.flags.contains(EnvelopeFlags::FILTER) {
+            feature(true, "filter envelope");
+        } else {
+            feature(true, "pitch envelope");
+        }
+    }
+    feature(instrument.volume_envelope.flags.contains(EnvelopeFlags::ENABLED), "volume envelope shape");
+    report.issues.extend(features.into_iter().map(|feature| Issue::InstrumentFeature { instrument: instrument_id, feature }));
+
+    // Writing into a `String` never fails.
+    let mut text = String::new();
+    writeln!(text, "// {}", instrument.name.decode()).unwrap();
+    writeln!(text).unwrap();
+    writeln!(text, "<group>").unwrap();
+    writeln!(text, "volume={:.2}", decibels(f64::from(instrument.global_volume) / 128.0)).unwrap();
+    if instrument.flags.contains(InstrumentFlags::ENABLE_PANNING) {
+        writeln!(text, "pan={:.1}", pan(instrument.default_panning.as_u8())).unwrap();
+    }
+    match instrument.new_note_action {
+        NewNoteAction::Continue => {}
+        NewNoteAction::Cut => writeln!(text, "note_polyphony=1\noff_mode=fast").unwrap(),
+        NewNoteAction::Off | NewNoteAction::Fade => writeln!(text, "note_polyphony=1\noff_mode=normal").unwrap(),
+    }
+    let tick = 2.5 / f64::from(module.tempo.as_u8());
+    let adsr = amplitude_envelope(&instrument.volume_envelope, instrument.instrument_fadeout, tick);
+    writeln!(text, "ampeg_attack={:.3}", adsr.attack).unwrap();
+    writeln!(text, "ampeg_decay={:.3}", adsr.decay).unwrap();
+    writeln!(text, "ampeg_sustain={:.1}", adsr.sustain).unwrap();
+    if let Some(release) = adsr.release {
+        writeln!(text, "ampeg_release={:.3}", release).unwrap();
+    }
+
+    let mut samples = Vec::<(SampleId, String)>::new();
+    for (sample_id, lokey, hikey, keycenter) in regions(&instrument.sample_map) {
+        let Some(sample) = module.get(sample_id).filter(|sample| sample.length() > 0) else {
+            continue;
+        };
+        let file_name = match samples.iter().find(|(id, _)| *id == sample_id) {
+            Some((_, file_name)) => file_name.clone(),
+            None => {
+                let file_name = format!("sample_{:02}.wav", sample_id.number());
+                samples.push((sample_id, file_name.clone()));
+                sample_features(sample_id, sample, &mut report);
+                file_name
+            }
+        };
+
+        writeln!(text).unwrap();
+        writeln!(text, "<region>").unwrap();
+        writeln!(text, "sample={}", file_name).unwrap();
+        writeln!(text, "lokey={} hikey={} pitch_keycenter={}", lokey, hikey, keycenter).unwrap();
+        let volume = f64::from(sample.default_volume) / 64.0 * f64::from(sample.global_volume) / 64.0;
+        writeln!(text, "volume={:.2}", decibels(volume)).unwrap();
+        if sample.default_panning & 0x80 != 0 {
+            writeln!(text, "pan={:.1}", pan(sample.default_panning & 0x7F)).unwrap();
+        }
+        match (sample.loop_, sample.sustain_loop) {
+            (Some(sample_loop), _) => write_loop(&mut text, "loop_continuous", sample_loop),
+            (None, Some(sustain_loop)) => write_loop(&mut text, "loop_sustain", sustain_loop),
+            (None, None) => writeln!(text, "loop_mode=no_loop").unwrap(),
+        }
+    }
+
+    let samples = samples
+        .into_iter()
+        .map(|(sample_id, file_name)| {
+            let mut wav = Vec::new();
+            // Writing into a `Vec` never fails.
+            module[sample_id].write_wav(&mut wav).unwrap();
+            (file_name, wav)
+        })
+        .collect();
+    Some((Sfz { text, samples }, report))
+}
+
+fn sample_features(sample_id: SampleId, sample: &Sample, report: &mut Report) {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::SampleFeature { sample: sample_id, feature });
+        }
+    };
+    feature(sample.loop_.is_some() && sample.sustain_loop.is_some(), "sustain loop");
+    feature(sample.vibrato_depth != 0, "auto-vibrato");
+}
+
+/// Runs of keys mapped to the same sample and note offset as `(sample, lokey, hikey, keycenter)`
+///
+/// Shared with the [SoundFont export](super::sf2).
+pub(super) fn regions(sample_map: &SampleMap) -> Vec<(SampleId, u8, u8, i16)> {
+    let mut regions = Vec::<(SampleId, u8, u8, i16)>::new();
+    for (note, translation, sample) in sample_map.iter() {
+        let Some(sample) = sample else {
+            continue;
+        };
+        let key = u8::from(note);
+        // The sample plays unchanged at the key translated to `C-5`.
+        let keycenter = i16::from(u8::from(Note::C_5)) - i16::from(u8::from(translation)) + i16::from(key);
+        match regions.last_mut() {
+            Some((last_sample, _, hikey, last_keycenter))
+                if *last_sample == sample && *hikey + 1 == key && *last_keycenter == keycenter =>
+            {
+                *hikey = key;
+            }
+            _ => regions.push((sample, key, key, keycenter)),
+        }
+    }
+    regions
+}
+
+fn write_loop(text: &mut String, mode: &str, sample_loop: SampleLoop) {
+    writeln!(text, "loop_mode={}", mode).unwrap();
+    // The loop end is inclusive in SFZ.
+    writeln!(text, "loop_start={} loop_end={}", sample_loop.start, sample_loop.end - 1).unwrap();
+    if sample_loop.bidi {
+        writeln!(text, "loop_type=alternate").unwrap();
+    }
+}
+
+/// Approximates the volume envelope with an ADSR envelope.
+///
+/// The attack ends at the loudest node and the decay at the sustain node, or the last node
+/// without a sustain loop. The release is the rest of the envelope if it falls silent, otherwise
+/// the time the fadeout takes.
+///
+/// Shared with the [SoundFont export](super::sf2).
+pub(super) fn amplitude_envelope(envelope: &Envelope, fadeout: u8, tick: f64) -> Adsr {
+    // The fadeout volume starts at 1024 and decreases by `fadeout` every tick.
+    let fadeout = (fadeout > 0).then(|| 1024.0 / f64::from(fadeout) * tick);
+    let nodes = &envelope.nodes;
+    if !envelope.flags.contains(EnvelopeFlags::ENABLED) || nodes.is_empty() {
+        return Adsr { attack: 0.0, decay: 0.0, sustain: 100.0, release: fadeout };
+    }
+
+    let peak = nodes
+        .iter()
+        .enumerate()
+        .max_by_key(|&(idx, node)| (node.value, std::cmp::Reverse(idx)))
+        .map(|(idx, _)| idx)
+        .unwrap();
+    let sustain = envelope.sustain_loop
+        .filter(|_| envelope.flags.contains(EnvelopeFlags::SUSTAIN))
+        .map_or(nodes.len() - 1, |sustain| usize::from(sustain.start).min(nodes.len() - 1))
+        .max(peak);
+    let last = nodes[nodes.len() - 1];
+    let seconds = |from: usize, to: usize| f64::from(nodes[to].tick.saturating_sub(nodes[from].tick)) * tick;
+
+    Adsr {
+        attack: f64::from(nodes[peak].tick) * tick,
+        decay: seconds(peak, sustain),
+        sustain: f64::from(nodes[sustain].value) / 64.0 * 100.0,
+        release: if last.value == 0 && sustain < nodes.len() - 1 {
+            Some(seconds(sustain, nodes.len() - 1))
+        } else {
+            fadeout
+        },
+    }
+}
+
+/// Converts a gain into decibels, silence is -144 dB in SFZ.
+fn decibels(gain: f64) -> f64 {
+    if gain > 0.0 {
+        (20.0 * gain.log10()).max(-144.0)
+    } else {
+        -144.0
+    }
+}
+
+/// Converts IT panning `0..=64` into SFZ panning `-100..=100`.
+fn pan(panning: u8) -> f64 {
+    ((f64::from(panning) - 32.0) / 32.0 * 100.0).clamp(-100.0, 100.0)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    #[test]
+    fn export_instrument() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        let file = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/compression/compressed.iti")).unwrap();
+        let mut instrument = file.instrument;
+        let first = SampleId::from_index(0).unwrap();
+        for note in 0..120 {
+            let note = Note::try_from(note).unwrap();
+            let sample = (note >= Note::C_5).then_some(first);
+            instrument.sample_map.set(note, note, sample);
+        }
+        module.samples = file.samples;
+        module.instruments = vec![instrument];
+
+        let instrument_id = InstrumentId::from_index(0).unwrap();
+        let (sfz, _) = export(&module, instrument_id).unwrap();
+        assert_eq!(sfz.samples.len(), 1);
+        assert_eq!(sfz.samples[0].0, "sample_01.wav");
+        assert!(sfz.samples[0].1.starts_with(b"RIFF"));
+        assert!(sfz.text.contains("sample=sample_01.wav\nlokey=60 hikey=119 pitch_keycenter=60\n"));
+        assert_eq!(sfz.text.matches("<region>").count(), 1);
+
+        assert!(export(&module, InstrumentId::from_index(1).unwrap()).is_none());
+    }
+
+    #[test]
+    fn envelope() {
+        let envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::SUSTAIN,
+            envelope_loop: None,
+            sustain_loop: Some(EnvelopeLoop { start: 2, end: 2 }),
+            nodes: vec![
+                Node { value: 0, tick: 0 },
+                Node { value: 64, tick: 10 },
+                Node { value: 32, tick: 30 },
+                Node { value: 0, tick: 70 },
+            ],
+        };
+        let adsr = amplitude_envelope(&envelope, 0, 0.5);
+        assert_eq!((adsr.attack, adsr.decay, adsr.sustain), (5.0, 10.0, 50.0));
+        assert_eq!(adsr.release, Some(20.0));
+
+        let adsr = amplitude_envelope(&Envelope { flags: EnvelopeFlags::empty(), ..envelope }, 128, 0.5);
+        assert_eq!((adsr.attack, adsr.sustain, adsr.release), (0.0, 100.0, Some(4.0)));
+    }
+}
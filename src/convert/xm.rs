@@ -0,0 +1,1415 @@
+//! FastTracker 2 extended modules (.xm)
+//!
+//! [`export`] converts a [`Module`] into an XM file. XM has no New Note Actions, duplicate note
+//! checks, pitch and filter envelopes, resonant filters or channel volume, instruments hold their
+//! own samples (at most 16) and the song plays on at most 32 channels. What is lost is listed in
+//! the [`Report`].
+//!
+//! Instruments are converted with all the samples they map notes to. A sample mapped with more
+//! than one note translation is stored once for each translation, with the translation moved
+//! into the relative note of the sample. Modules which don't use instruments get one instrument
+//! for each sample.
+//!
+//! XM notes are numbered an octave lower than IT notes, IT `C-5` (played at the C-5 frequency of
+//! the sample) is XM `C-4`. Notes below `C-1` and above `B-8` (IT numbering) can't be
+//! represented.
+//!
+//! [`import`] reads an XM file (version 0x0104) into a [`Module`] using instruments. Every XM
+//! sample becomes an IT sample with the relative note and finetune folded into its C-5
+//! frequency, the auto-vibrato of the instrument is copied to each of its samples. Effects are
+//! mapped onto their IT equivalents, `Cxx` is moved into the volume column when it's free.
+
+use super::{name, round_clamp, samplerate_from, semitones_from, text, Converted, Issue, PatternCounts, Report};
+use crate::error::ContextError;
+use crate::parser;
+use crate::*;
+use nom::bytes::complete::{tag, take};
+use nom::error::ParseError;
+use nom::multi::count;
+use nom::number::complete::{le_i16, le_i8, le_u16, le_u32, le_u8};
+use nom::sequence::tuple;
+use nom::{Err, IResult};
+use std::convert::TryFrom;
+
+
+/// Frequency of a sample with relative note and finetune 0 played at XM `C-4`
+const BASE_FREQUENCY: u32 = 8363;
+
+const MAX_CHANNELS: usize = 32;
+const MAX_ROWS: usize = 256;
+const MAX_ORDERS: usize = 256;
+const MAX_SAMPLES_PER_INSTRUMENT: usize = 16;
+const MAX_ENVELOPE_POINTS: usize = 12;
+
+/// Limits of the IT format for [`import`]
+const MAX_IT_CHANNELS: usize = 64;
+const MAX_IT_PATTERNS: usize = 200;
+const MAX_IT_ITEMS: usize = 99;
+
+/// Number of notes, XM notes are stored as `1..=96`
+const NOTES: usize = 96;
+
+/// IT note index of XM note `1` (`C-0`)
+const FIRST_NOTE: u8 = 12;
+
+/// Note off
+const KEY_OFF: u8 = 97;
+
+/// Size of the header counted from the header size field
+const HEADER_SIZE: u32 = 276;
+const PATTERN_HEADER_SIZE: u32 = 9;
+const INSTRUMENT_HEADER_SIZE: u32 = 263;
+const SAMPLE_HEADER_SIZE: u32 = 40;
+
+/// `Gxx` speeds of the `g0x` volume command
+const TONE_PORTAMENTO_SPEEDS: [u16; 10] = [0x00, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40, 0x60, 0x80, 0xFF];
+
+
+/// Converts the module into a FastTracker 2 module file (.xm)
+///
+/// See the [module documentation](self) for how the IT features are mapped.
+pub fn export(module: &Module) -> (Vec<u8>, Report) {
+    let mut report = Report::default();
+    module_features(module, &mut report);
+
+    let channels = channel_count(module, &mut report);
+    let orders = orders(module, &mut report);
+    let instruments = instruments(module, &mut report);
+    let speed = module.speed.as_u8().min(31);
+    if speed != module.speed.as_u8() {
+        report.push(Issue::ModuleFeature { feature: "speed above 31" });
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"Extended Module: ");
+    out.extend_from_slice(&text::<20>(module.name.as_bytes()));
+    out.push(0x1A);
+    out.extend_from_slice(&text::<20>(b"ittech"));
+    out.extend_from_slice(&0x0104u16.to_le_bytes());
+    out.extend_from_slice(&HEADER_SIZE.to_le_bytes());
+    let pattern_count = module.patterns.len().max(1);
+    for value in [
+        orders.len(),
+        0,
+        channels,
+        pattern_count,
+        instruments.len(),
+        usize::from(module.flags.contains(ModuleFlags::LINEAR_SLIDES)),
+        usize::from(speed),
+        usize::from(module.tempo.as_u8().max(32)),
+    ] {
+        out.extend_from_slice(&u16::try_from(value).unwrap().to_le_bytes());
+    }
+    let mut order_table = [0; MAX_ORDERS];
+    order_table[..orders.len()].copy_from_slice(&orders);
+    out.extend_from_slice(&order_table);
+
+    let empty = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 64] };
+    for idx in 0..pattern_count {
+        let pattern_id = PatternId::from_index(u8::try_from(idx).unwrap()).unwrap();
+        let pattern = module.patterns.get(idx).unwrap_or(&empty);
+        let mut counts = PatternCounts::default();
+        write_pattern(&mut out, pattern, pattern_id, channels, &mut counts, &mut report);
+        report.pattern_counts(pattern_id, &counts);
+    }
+
+    for instrument in &instruments {
+        write_instrument(&mut out, instrument);
+    }
+
+    (out, report)
+}
+
+
+/// Instrument as it's stored in the XM file
+struct XmInstrument<'m> {
+    name: &'m Name,
+    samples: Vec<XmSample<'m>>,
+
+    /// Index into `samples` for each note
+    sample_map: [u8; NOTES],
+
+    volume_envelope: XmEnvelope,
+    panning_envelope: XmEnvelope,
+    fadeout: u16,
+}
+
+struct XmSample<'m> {
+    sample: &'m Sample,
+
+    /// Pitch relative to [`BASE_FREQUENCY`] in semitones
+    pitch: f64,
+}
+
+#[derive(Default)]
+struct XmEnvelope {
+    /// Tick and value of the points
+    points: Vec<(u16, u16)>,
+    sustain: u8,
+    loop_start: u8,
+    loop_end: u8,
+
+    /// Bit 0 enabled, bit 1 sustain, bit 2 loop
+    flags: u8,
+}
+
+
+fn module_features(module: &Module, report: &mut Report) {
+    let mut feature = |present: bool, feature| {
+        if present {
+            report.push(Issue::ModuleFeature { feature });
+        }
+    };
+    feature(module.global_volume.as_u8() != 128, "global volume");
+    feature(module.flags.contains(ModuleFlags::OLD_EFFECTS), "old effects");
+    feature(module.flags.contains(ModuleFlags::LINK_G_E_EFFECTS), "linked Gxx memory");
+    feature(!module.message.is_empty(), "song message");
+    feature(module.tempo.as_u8() < 32, "tempo below 32");
+    feature(
+        module.channels.iter().any(|channel| *channel != ChannelSettings::DEFAULT),
+        "initial channel panning and volume",
+    );
+}
+
+/// Number of channels written, the last used channel rounded up to an even number
+fn channel_count(module: &Module, report: &mut Report) -> usize {
+    let used = module.patterns
+        .iter()
+        .flat_map(|pattern| &pattern.rows)
+        .flat_map(Row::iter)
+        .map(|(channel, _)| channel.as_usize() + 1)
+        .max()
+        .unwrap_or(1);
+    if used > MAX_CHANNELS {
+        report.push(Issue::ChannelsDropped { channels: used, max: MAX_CHANNELS });
+    }
+    (used + used % 2).clamp(2, MAX_CHANNELS)
+}
+
+/// Orders up to the end of the song without the separators
+fn orders(module: &Module, report: &mut Report) -> Vec<u8> {
+    let mut orders = module.orders
+        .iter()
+        .take_while(|order| **order != Order::EndOfSong)
+        .filter_map(|order| match order {
+            Order::Index(pattern) => Some(pattern.as_u8()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if orders.len() > MAX_ORDERS {
+        report.push(Issue::ItemsDropped { kind: "orders", count: orders.len(), max: MAX_ORDERS });
+        orders.truncate(MAX_ORDERS);
+    }
+    if orders.is_empty() {
+        orders.push(0);
+    }
+    orders
+}
+
+fn instruments<'m>(module: &'m Module, report: &mut Report) -> Vec<XmInstrument<'m>> {
+    for (idx, sample) in (0..).zip(&module.samples) {
+        let sample_id = SampleId::from_index(idx).unwrap();
+        let mut feature = |present: bool, feature| {
+            if present {
+                report.push(Issue::SampleFeature { sample: sample_id, feature });
+            }
+        };
+        feature(sample.sustain_loop.is_some(), "sustain loop");
+        feature(sample.global_volume != 64, "global volume");
+        feature(sample.vibrato_type == 3, "random auto-vibrato");
+    }
+
+    if !module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        // Sample mode, the instrument column contains sample numbers.
+        return module.samples
+            .iter()
+            .map(|sample| XmInstrument {
+                name: &sample.name,
+                samples: vec![XmSample { sample, pitch: semitones_from(sample.samplerate_c5, BASE_FREQUENCY) }],
+                sample_map: [0; NOTES],
+                volume_envelope: XmEnvelope::default(),
+                panning_envelope: XmEnvelope::default(),
+                fadeout: 0,
+            })
+            .collect();
+    }
+
+    (0..)
+        .zip(&module.instruments)
+        .map(|(idx, instrument)| {
+            let instrument_id = InstrumentId::from_index(idx).unwrap();
+            let mut features = Vec::new();
+            let xm = instrument_from_it(module, instrument, &mut features);
+            report.issues.extend(
+                features.into_iter().map(|feature| Issue::InstrumentFeature { instrument: instrument_id, feature })
+            );
+            xm
+        })
+        .collect()
+}
+
+fn instrument_from_it<'m>(module: &'m Module, instrument: &'m Instrument, features: &mut Vec<&'static str>) -> XmInstrument<'m> {
+    let mut feature = |present: bool, feature| {
+        if present && !features.contains(&feature) {
+            features.push(feature);
+        }
+    };
+    feature(instrument.new_note_action != NewNoteAction::Cut, "new note action");
+    feature(instrument.duplicate_check_type != DuplicateCheckType::Off, "duplicate note check");
+    feature(instrument.global_volume != 128, "global volume");
+    feature(instrument.flags.contains(InstrumentFlags::ENABLE_PANNING), "default panning");
+    feature(
+        instrument.flags.intersects(InstrumentFlags::ENABLE_FILTER_CUTOFF | InstrumentFlags::ENABLE_FILTER_RESONANCE),
+        "resonant filter",
+    );
+    feature(
+        instrument.random_volume_variation.as_u8() != 0 || instrument.random_panning_variation.as_u8() != 0,
+        "random variation",
+    );
+    feature(instrument.pitch_pan_separation != 0, "pitch-pan separation");
+    let pitch_envelope = &instrument.pitch_filter_envelope;
+    if pitch_envelope.flags.contains(EnvelopeFlags::ENABLED) {
+        if pitch_envelope.flags.contains(EnvelopeFlags::FILTER) {
+            feature(true, "filter envelope");
+        } else {
+            feature(true, "pitch envelope");
+        }
+    }
+
+    // Every combination of a sample and a note translation becomes an XM sample.
+    let mut keys = Vec::<(SampleId, i16)>::new();
+    let mut sample_map = [0; NOTES];
+    for (xm_note, entry) in sample_map.iter_mut().enumerate() {
+        let note = Note::try_from(FIRST_NOTE + u8::try_from(xm_note).unwrap()).unwrap();
+        let Some(sample) = instrument.sample_map.sample_for(note).filter(|&sample| module.get(sample).is_some()) else {
+            continue;
+        };
+        let translation = instrument.sample_map.note_translation_for(note);
+        let key = (sample, i16::from(u8::from(translation)) - i16::from(u8::from(note)));
+        let position = match keys.iter().position(|&other| other == key) {
+            Some(position) => position,
+            None if keys.len() < MAX_SAMPLES_PER_INSTRUMENT => {
+                keys.push(key);
+                keys.len() - 1
+            }
+            None => {
+                feature(true, "more than 16 samples");
+                continue;
+            }
+        };
+        *entry = u8::try_from(position).unwrap();
+    }
+    let samples = keys
+        .into_iter()
+        .map(|(sample, transpose)| {
+            let sample = &module[sample];
+            let pitch = semitones_from(sample.samplerate_c5, BASE_FREQUENCY) + f64::from(transpose);
+            XmSample { sample, pitch }
+        })
+        .collect();
+
+    XmInstrument {
+        name: &instrument.name,
+        samples,
+        sample_map,
+        volume_envelope: envelope(&instrument.volume_envelope, 0, &mut feature),
+        panning_envelope: envelope(&instrument.panning_envelope, 32, &mut feature),
+        // IT fadeout is in units of 32 XM fadeout steps.
+        fadeout: (u16::from(instrument.instrument_fadeout) * 32).min(0xFFF),
+    }
+}
+
+/// Converts an IT envelope, `offset` is added to the values to get them into `0..=64`.
+fn envelope(envelope: &Envelope, offset: i16, feature: &mut impl FnMut(bool, &'static str)) -> XmEnvelope {
+    feature(envelope.nodes.len() > MAX_ENVELOPE_POINTS, "envelope with more than 12 nodes");
+    feature(envelope.flags.contains(EnvelopeFlags::CARRY), "envelope carry");
+    let last = u8::try_from(envelope.nodes.len().clamp(1, MAX_ENVELOPE_POINTS) - 1).unwrap();
+
+    let mut flags = 0;
+    let mut xm = XmEnvelope::default();
+    if envelope.flags.contains(EnvelopeFlags::ENABLED) && !envelope.nodes.is_empty() {
+        flags |= 1;
+    }
+    if let Some(sustain) = envelope.sustain_loop.filter(|_| envelope.flags.contains(EnvelopeFlags::SUSTAIN)) {
+        feature(sustain.start != sustain.end, "envelope sustain loop over multiple nodes");
+        flags |= 2;
+        xm.sustain = sustain.start.min(last);
+    }
+    if let Some(envelope_loop) = envelope.envelope_loop.filter(|_| envelope.flags.contains(EnvelopeFlags::LOOP)) {
+        flags |= 4;
+        xm.loop_start = envelope_loop.start.min(last);
+        xm.loop_end = envelope_loop.end.min(last);
+    }
+    xm.flags = flags;
+    xm.points = envelope.nodes
+        .iter()
+        .take(MAX_ENVELOPE_POINTS)
+        .map(|node| (node.tick, u16::try_from((i16::from(node.value) + offset).clamp(0, 64)).unwrap()))
+        .collect();
+    xm
+}
+
+
+fn write_pattern(
+    out: &mut Vec<u8>,
+    pattern: &Pattern,
+    pattern_id: PatternId,
+    channels: usize,
+    counts: &mut PatternCounts,
+    report: &mut Report,
+) {
+    let rows = &pattern.rows[..pattern.rows.len().min(MAX_ROWS)];
+    if rows.len() < pattern.rows.len() {
+        report.push(Issue::RowsDropped { pattern: pattern_id, rows: pattern.rows.len(), max: MAX_ROWS });
+    }
+
+    let mut data = Vec::new();
+    for row in rows {
+        for idx in 0..channels {
+            let channel = Channel::from_index(u8::try_from(idx).unwrap()).unwrap();
+            let cell = row.get(channel).map_or([0; 5], |command| cell(command, counts));
+            pack_cell(&mut data, cell);
+        }
+    }
+    // One empty row, XM patterns can't have zero rows.
+    if rows.is_empty() {
+        data.extend(std::iter::repeat(0x80).take(channels));
+    }
+
+    out.extend_from_slice(&PATTERN_HEADER_SIZE.to_le_bytes());
+    out.push(0);
+    out.extend_from_slice(&u16::try_from(rows.len().max(1)).unwrap().to_le_bytes());
+    out.extend_from_slice(&u16::try_from(data.len()).unwrap().to_le_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// Packs note, instrument, volume, effect and parameter bytes of a cell
+fn pack_cell(out: &mut Vec<u8>, cell: [u8; 5]) {
+    let mask = (0..5)
+        .filter(|&idx| cell[idx] != 0)
+        .fold(0u8, |mask, idx| mask | (1 << idx));
+    if mask == 0x1F {
+        out.extend_from_slice(&cell);
+    } else {
+        out.push(0x80 | mask);
+        out.extend(cell.iter().copied().filter(|&byte| byte != 0));
+    }
+}
+
+/// Converts a command into the note, instrument, volume, effect and parameter bytes
+///
+/// Also used to reduce modules to the ProTracker effects.
+pub(super) fn cell(command: &Command, counts: &mut PatternCounts) -> [u8; 5] {
+    let note = match command.note {
+        Some(NoteCmd::Play(note)) => match u8::from(note).checked_sub(FIRST_NOTE - 1) {
+            Some(note @ 1..=96) => note,
+            _ => {
+                counts.notes_dropped += 1;
+                0
+            }
+        },
+        Some(NoteCmd::Off) => KEY_OFF,
+        Some(NoteCmd::Cut | NoteCmd::Fade) => {
+            counts.effects_approximated += 1;
+            KEY_OFF
+        }
+        None => 0,
+    };
+    let instrument = command.instrument.map_or(0, InstrumentId::number);
+    let volume = command.volume.and_then(|volume| volume_command(volume).count(counts)).unwrap_or(0);
+    let (effect, param) = command.effect.and_then(|effect| effect_command(effect).count(counts)).unwrap_or((0, 0));
+    [note, instrument, volume, effect, param]
+}
+
+fn volume_command(volume: VolumeCmd) -> Converted<u8> {
+    use Converted::*;
+
+    fn param(x: Option<RangedU8<1, 9>>) -> u8 {
+        x.map_or(0, RangedU8::as_u8)
+    }
+
+    match volume {
+        VolumeCmd::SetVolume(volume) => Exact(0x10 + volume.as_u8()),
+        VolumeCmd::Panning(panning) => {
+            // XM sets the panning in steps of 16 out of 256, IT in steps of 4.
+            let xm = (panning.as_u8() / 4).min(0x0F);
+            if xm * 4 == panning.as_u8() { Exact(0xC0 + xm) } else { Approximated(0xC0 + xm) }
+        }
+        VolumeCmd::FineVolumeDown(Some(x)) => Exact(0x80 + x.as_u8()),
+        VolumeCmd::FineVolumeUp(Some(x)) => Exact(0x90 + x.as_u8()),
+        // XM has no memory for the volume column fine slides.
+        VolumeCmd::FineVolumeDown(None) | VolumeCmd::FineVolumeUp(None) => Dropped,
+        VolumeCmd::VolumeSlideDown(x) => Exact(0x60 + param(x)),
+        VolumeCmd::VolumeSlideUp(x) => Exact(0x70 + param(x)),
+        VolumeCmd::Vibrato(x) => Exact(0xB0 + param(x)),
+        VolumeCmd::TonePortamento(None) => Exact(0xF0),
+        VolumeCmd::TonePortamento(Some(x)) => {
+            // XM uses speed `x * 16`.
+            let speed = TONE_PORTAMENTO_SPEEDS[usize::from(x.as_u8())];
+            let xm = u8::try_from(((speed + 8) / 16).clamp(1, 15)).unwrap();
+            if u16::from(xm) * 16 == speed { Exact(0xF0 + xm) } else { Approximated(0xF0 + xm) }
+        }
+        VolumeCmd::PortamentoDown(_) | VolumeCmd::PortamentoUp(_) => Dropped,
+    }
+}
+
+fn effect_command(effect: EffectCmd) -> Converted<(u8, u8)> {
+    use Converted::*;
+
+    fn nibbles(x: u8, y: u8) -> u8 {
+        (x << 4) | y
+    }
+
+    fn param<const LOW: u8, const HIGH: u8>(x: Option<RangedU8<LOW, HIGH>>) -> u8 {
+        x.map_or(0, RangedU8::as_u8)
+    }
+
+    /// Normal slides in the XM `Axy` encoding, XM has no fine variants of `5xy` and `6xy`
+    fn slide(slide: Option<VolumeSlide>) -> Option<u8> {
+        match slide {
+            None => Some(0),
+            Some(VolumeSlide::Up(x)) => Some(nibbles(x.as_u8(), 0)),
+            Some(VolumeSlide::Down(y)) => Some(y.as_u8()),
+            Some(VolumeSlide::FineUp(_) | VolumeSlide::FineDown(_)) => None,
+        }
+    }
+
+    fn waveform(waveform: Waveform) -> u8 {
+        match waveform {
+            Waveform::Sine => 0,
+            Waveform::Sawtooth => 1,
+            Waveform::Square => 2,
+            Waveform::Random => 3,
+        }
+    }
+
+    // XM effect numbers, letters are numbered from 10.
+    const EXTENDED: u8 = 0xE;
+    const SPEED: u8 = 0xF;
+    const GLOBAL_VOLUME: u8 = 16;
+    const GLOBAL_VOLUME_SLIDE: u8 = 17;
+    const PANNING_SLIDE: u8 = 25;
+    const RETRIGGER: u8 = 27;
+    const TREMOR: u8 = 29;
+    const EXTRA_FINE_PORTAMENTO: u8 = 33;
+
+    match effect {
+        EffectCmd::SetSpeed(speed) if speed.as_u8() < 0x20 => Exact((SPEED, speed.as_u8())),
+        EffectCmd::SetSpeed(_) => Dropped,
+        EffectCmd::Tempo(Some(Tempo::Set(tempo))) => Exact((SPEED, tempo.as_u8())),
+        EffectCmd::Tempo(_) => Dropped,
+        EffectCmd::JumpOrder(order) => Exact((0xB, order.as_u8())),
+        EffectCmd::BreakRow(row) => {
+            // The row is stored in BCD.
+            let bcd = nibbles(row.min(99) / 10, row.min(99) % 10);
+            if row <= 99 { Exact((0xD, bcd)) } else { Approximated((0xD, bcd)) }
+        }
+        EffectCmd::VolumeSlide(None) => Exact((0xA, 0)),
+        EffectCmd::VolumeSlide(Some(VolumeSlide::Up(x))) => Exact((0xA, nibbles(x.as_u8(), 0))),
+        EffectCmd::VolumeSlide(Some(VolumeSlide::Down(y))) => Exact((0xA, y.as_u8())),
+        EffectCmd::VolumeSlide(Some(VolumeSlide::FineUp(x))) => Exact((EXTENDED, nibbles(0xA, x.as_u8()))),
+        EffectCmd::VolumeSlide(Some(VolumeSlide::FineDown(y))) => Exact((EXTENDED, nibbles(0xB, y.as_u8()))),
+        EffectCmd::PortamentoDown(None) => Exact((0x2, 0)),
+        EffectCmd::PortamentoDown(Some(Portamento::Coarse(x))) => Exact((0x2, x.as_u8())),
+        EffectCmd::PortamentoDown(Some(Portamento::Fine(x))) => Exact((EXTENDED, nibbles(0x2, x.as_u8()))),
+        EffectCmd::PortamentoDown(Some(Portamento::ExtraFine(x))) => Exact((EXTRA_FINE_PORTAMENTO, nibbles(0x2, x.as_u8()))),
+        EffectCmd::PortamentoUp(None) => Exact((0x1, 0)),
+        EffectCmd::PortamentoUp(Some(Portamento::Coarse(x))) => Exact((0x1, x.as_u8())),
+        EffectCmd::PortamentoUp(Some(Portamento::Fine(x))) => Exact((EXTENDED, nibbles(0x1, x.as_u8()))),
+        EffectCmd::PortamentoUp(Some(Portamento::ExtraFine(x))) => Exact((EXTRA_FINE_PORTAMENTO, nibbles(0x1, x.as_u8()))),
+        EffectCmd::TonePortamento(speed) => Exact((0x3, param(speed))),
+        EffectCmd::Vibrato(speed, depth) => Exact((0x4, nibbles(param(speed), param(depth)))),
+        // XM has no fine vibrato, the depth is 4 times coarser.
+        EffectCmd::FineVibrato(speed, depth) => Approximated((0x4, nibbles(param(speed), (param(depth) + 3) / 4))),
+        EffectCmd::Tremor(times) => {
+            let (on, off) = times.map_or((0, 0), |(on, off)| (on.as_u8(), off.as_u8()));
+            Approximated((TREMOR, nibbles(on, off)))
+        }
+        EffectCmd::Arpeggio(Some((x, y))) if (x.as_u8(), y.as_u8()) != (0, 0) => Exact((0x0, nibbles(x.as_u8(), y.as_u8()))),
+        // `000` is an empty effect in XM, arpeggio has no memory.
+        EffectCmd::Arpeggio(_) => Dropped,
+        EffectCmd::VolumeSlideAndVibrato(volume_slide) => match slide(volume_slide) {
+            Some(param) => Exact((0x6, param)),
+            None => Dropped,
+        },
+        EffectCmd::VolumeSlideAndPortamento(volume_slide) => match slide(volume_slide) {
+            Some(param) => Exact((0x5, param)),
+            None => Dropped,
+        },
+        EffectCmd::SetSampleOffset(SetSampleOffset::Low(offset)) => Exact((0x9, offset)),
+        EffectCmd::SetSampleOffset(SetSampleOffset::High(_)) => Dropped,
+        // IT slides the panning on the `0..=64` scale, XM on `0..=255`.
+        EffectCmd::PanningSlide(None) => Exact((PANNING_SLIDE, 0)),
+        EffectCmd::PanningSlide(Some(PanningSlide::Right(x))) => Approximated((PANNING_SLIDE, nibbles(x.as_u8(), 0))),
+        EffectCmd::PanningSlide(Some(PanningSlide::Left(x))) => Approximated((PANNING_SLIDE, x.as_u8())),
+        EffectCmd::PanningSlide(Some(PanningSlide::FineRight(_) | PanningSlide::FineLeft(_))) => Dropped,
+        EffectCmd::Retrigger(retrigger) => {
+            let (x, y) = retrigger.map_or((0, 0), |(x, y)| (x.as_u8(), y.as_u8()));
+            Exact((RETRIGGER, nibbles(x, y)))
+        }
+        EffectCmd::Tremolo(speed, depth) => Exact((0x7, nibbles(param(speed), param(depth)))),
+        EffectCmd::SetPanningPosition(panning) => Exact((0x8, panning)),
+        // XM global volume goes up to 64.
+        EffectCmd::SetGlobalVolume(volume) if volume.as_u8() % 2 == 0 => Exact((GLOBAL_VOLUME, volume.as_u8() / 2)),
+        EffectCmd::SetGlobalVolume(volume) => Approximated((GLOBAL_VOLUME, volume.as_u8() / 2)),
+        EffectCmd::GlobalVolumeSlide(volume_slide) => match slide(volume_slide) {
+            Some(param) => Approximated((GLOBAL_VOLUME_SLIDE, param)),
+            None => Dropped,
+        },
+        EffectCmd::Special(Some(special)) => match special {
+            Special::SetGlissando(on) => Exact((EXTENDED, nibbles(0x3, u8::from(on)))),
+            Special::SetFinetune(finetune) => Approximated((EXTENDED, nibbles(0x5, finetune.as_u8()))),
+            Special::SetVibratoWaveform(Waveform::Random) => Approximated((EXTENDED, nibbles(0x4, 3))),
+            Special::SetVibratoWaveform(form) => Exact((EXTENDED, nibbles(0x4, waveform(form)))),
+            Special::SetTremoloWaveform(Waveform::Random) => Approximated((EXTENDED, nibbles(0x7, 3))),
+            Special::SetTremoloWaveform(form) => Exact((EXTENDED, nibbles(0x7, waveform(form)))),
+            Special::SetPanning(panning) => Exact((0x8, panning.as_u8() * 0x11)),
+            Special::SetLoopbackPoint => Exact((EXTENDED, 0x60)),
+            Special::LoopbackTimes(times) => Exact((EXTENDED, nibbles(0x6, times.as_u8()))),
+            Special::NoteCut(ticks) => Exact((EXTENDED, nibbles(0xC, ticks.as_u8()))),
+            Special::NoteDelay(ticks) => Exact((EXTENDED, nibbles(0xD, ticks.as_u8()))),
+            Special::PatternRowDelay(rows) => Exact((EXTENDED, nibbles(0xE, rows.as_u8()))),
+            _ => Dropped,
+        },
+        EffectCmd::Special(None)
+        | EffectCmd::SetChannelVolume(_)
+        | EffectCmd::ChannelVolumeSlide(_)
+        | EffectCmd::Panbrello(_, _)
+        | EffectCmd::Midi(_) => Dropped,
+    }
+}
+
+
+fn write_instrument(out: &mut Vec<u8>, instrument: &XmInstrument) {
+    out.extend_from_slice(&INSTRUMENT_HEADER_SIZE.to_le_bytes());
+    out.extend_from_slice(&text::<22>(instrument.name.as_bytes()));
+    out.push(0);
+    out.extend_from_slice(&u16::try_from(instrument.samples.len()).unwrap().to_le_bytes());
+    out.extend_from_slice(&SAMPLE_HEADER_SIZE.to_le_bytes());
+    out.extend_from_slice(&instrument.sample_map);
+    for envelope in [&instrument.volume_envelope, &instrument.panning_envelope] {
+        for idx in 0..MAX_ENVELOPE_POINTS {
+            let (tick, value) = envelope.points.get(idx).copied().unwrap_or((0, 0));
+            out.extend_from_slice(&tick.to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+    for envelope in [&instrument.volume_envelope, &instrument.panning_envelope] {
+        out.push(u8::try_from(envelope.points.len()).unwrap());
+    }
+    for envelope in [&instrument.volume_envelope, &instrument.panning_envelope] {
+        out.extend_from_slice(&[envelope.sustain, envelope.loop_start, envelope.loop_end]);
+    }
+    out.push(instrument.volume_envelope.flags);
+    out.push(instrument.panning_envelope.flags);
+
+    // Auto-vibrato is set on the instrument in XM, use the one of the first sample.
+    let vibrato = instrument.samples.first().map_or([0; 4], |xm| {
+        let sample = xm.sample;
+        // IT has sine, ramp down, square and random, XM has sine, square, ramp down and ramp up.
+        let waveform = match sample.vibrato_type {
+            1 => 2,
+            2 => 1,
+            _ => 0,
+        };
+        [waveform, sample.vibrato_rate, sample.vibrato_depth, sample.vibrato_speed]
+    });
+    out.extend_from_slice(&vibrato);
+    out.extend_from_slice(&instrument.fadeout.to_le_bytes());
+    out.extend_from_slice(&[0; 22]);
+
+    for xm in &instrument.samples {
+        write_sample_header(out, xm);
+    }
+    for xm in &instrument.samples {
+        write_sample_data(out, xm.sample);
+    }
+}
+
+fn write_sample_header(out: &mut Vec<u8>, xm: &XmSample) {
+    let sample = xm.sample;
+    let width = match &sample.data {
+        Some(SampleData::Pcm16(_)) => 2,
+        _ => 1,
+    };
+    let bytes = |samples: u32| samples.saturating_mul(width);
+    let (loop_start, loop_length, loop_type) = match sample.loop_ {
+        Some(SampleLoop { start, end, bidi }) => (bytes(start), bytes(end - start), if bidi { 2 } else { 1 }),
+        None => (0, 0, 0),
+    };
+    let relative_note = round_clamp(xm.pitch, -96i8, 95);
+    let finetune = round_clamp((xm.pitch - f64::from(relative_note)) * 128.0, -128i8, 127);
+    let panning = if sample.default_panning & 0x80 != 0 {
+        u8::try_from((u16::from(sample.default_panning & 0x7F) * 4).min(255)).unwrap()
+    } else {
+        128
+    };
+
+    for value in [bytes(sample.length()), loop_start, loop_length] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.push(sample.default_volume.min(64));
+    out.extend_from_slice(&finetune.to_le_bytes());
+    out.push(loop_type | if width == 2 { 0x10 } else { 0 });
+    out.push(panning);
+    out.extend_from_slice(&relative_note.to_le_bytes());
+    out.push(0);
+    out.extend_from_slice(&text::<22>(sample.name.as_bytes()));
+}
+
+/// Writes the sample data delta encoded
+fn write_sample_data(out: &mut Vec<u8>, sample: &Sample) {
+    match &sample.data {
+        Some(SampleData::Pcm8(data)) => {
+            let mut last = 0i8;
+            for &value in data.iter() {
+                out.extend_from_slice(&value.wrapping_sub(last).to_le_bytes());
+                last = value;
+            }
+        }
+        Some(SampleData::Pcm16(data)) => {
+            let mut last = 0i16;
+            for &value in data.iter() {
+                out.extend_from_slice(&value.wrapping_sub(last).to_le_bytes());
+                last = value;
+            }
+        }
+        None => {}
+    }
+}
+
+/// Reads a FastTracker 2 module file (.xm) into a [`Module`]
+///
+/// See the [module documentation](self) for how the XM features are mapped.
+pub fn import<'i, E>(input: &'i [u8]) -> Result<(Module, Report), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut report = Report::default();
+
+    let (mut input, header) = context!(header, "in XM header")(input)?;
+    let channels = usize::from(header.channels);
+    if channels > MAX_IT_CHANNELS {
+        report.push(Issue::ChannelsDropped { channels, max: MAX_IT_CHANNELS });
+    }
+    if header.restart != 0 {
+        report.push(Issue::ModuleFeature { feature: "restart position" });
+    }
+
+    let mut xm_patterns = Vec::with_capacity(usize::from(header.patterns));
+    for idx in 0..header.patterns {
+        let (rest, pattern) = context!(|input| xm_pattern(input, channels), "in pattern {}", idx)(input)?;
+        xm_patterns.push(pattern);
+        input = rest;
+    }
+    let mut xm_instruments = Vec::with_capacity(usize::from(header.instruments));
+    for idx in 1..=header.instruments {
+        let (rest, instrument) = context!(xm_instrument, "in instrument {}", idx)(input)?;
+        xm_instruments.push(instrument);
+        input = rest;
+    }
+
+    if xm_patterns.len() > MAX_IT_PATTERNS {
+        report.push(Issue::ItemsDropped { kind: "patterns", count: xm_patterns.len(), max: MAX_IT_PATTERNS });
+        xm_patterns.truncate(MAX_IT_PATTERNS);
+    }
+    if xm_instruments.len() > MAX_IT_ITEMS {
+        report.push(Issue::ItemsDropped { kind: "instruments", count: xm_instruments.len(), max: MAX_IT_ITEMS });
+        xm_instruments.truncate(MAX_IT_ITEMS);
+    }
+
+    let patterns = (0..)
+        .zip(xm_patterns)
+        .map(|(idx, rows)| {
+            let pattern_id = PatternId::from_index(idx).unwrap();
+            let mut counts = PatternCounts::default();
+            let pattern = pattern_from_xm(rows, &mut counts);
+            report.pattern_counts(pattern_id, &counts);
+            pattern
+        })
+        .collect::<Vec<_>>();
+
+    let (instruments, samples) = instruments_from_xm(xm_instruments, &mut report);
+
+    // Orders of the dropped patterns are skipped, missing patterns play as empty in both formats.
+    let mut orders = header.orders
+        .iter()
+        .filter_map(|&order| PatternId::from_index(order).ok())
+        .map(Order::Index)
+        .collect::<Vec<_>>();
+    if orders.len() < MAX_ORDERS {
+        orders.push(Order::EndOfSong);
+    }
+
+    let mut channel_settings = [ChannelSettings { muted: true, ..ChannelSettings::DEFAULT }; MAX_IT_CHANNELS];
+    for settings in channel_settings.iter_mut().take(channels) {
+        *settings = ChannelSettings::DEFAULT;
+    }
+
+    let mut flags = ModuleFlags::STEREO | ModuleFlags::USE_INSTRUMENTS;
+    flags.set(ModuleFlags::LINEAR_SLIDES, header.flags & 1 != 0);
+
+    let module = Module {
+        name: name(&header.name),
+        message: String::new(),
+        highlight: (4, 16),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags,
+        global_volume: RangedU8::new(128),
+        sample_volume: RangedU8::new(48),
+        speed: RangedU8::try_from(u8::try_from(header.speed).unwrap_or(255).max(1)).unwrap(),
+        tempo: RangedU8::try_from(u8::try_from(header.bpm).unwrap_or(255).max(31)).unwrap(),
+        pan_separation: RangedU8::new(128),
+        pitch_wheel_depth: 0,
+        channels: channel_settings,
+        orders,
+        instruments,
+        samples,
+        patterns,
+    };
+    Ok((module, report))
+}
+
+
+struct XmHeader {
+    name: [u8; 20],
+    restart: u16,
+    channels: u16,
+    patterns: u16,
+    instruments: u16,
+    flags: u16,
+    speed: u16,
+    bpm: u16,
+    orders: Vec<u8>,
+}
+
+/// Envelope as it's stored in the XM file
+struct RawEnvelope {
+    points: Vec<(u16, u16)>,
+    sustain: u8,
+    loop_start: u8,
+    loop_end: u8,
+    flags: u8,
+}
+
+struct RawInstrument {
+    name: [u8; 22],
+    sample_map: [u8; NOTES],
+    volume_envelope: RawEnvelope,
+    panning_envelope: RawEnvelope,
+
+    /// Type, sweep, depth and rate
+    vibrato: [u8; 4],
+    fadeout: u16,
+    samples: Vec<RawSample>,
+}
+
+struct RawSample {
+    name: [u8; 22],
+    loop_start: u32,
+    loop_length: u32,
+    volume: u8,
+    finetune: i8,
+    kind: u8,
+    panning: u8,
+    relative_note: i8,
+    data: SampleData,
+}
+
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], XmHeader, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, _) = tag(b"Extended Module: ")(input)?;
+    let (input, name) = take(20usize)(input)?;
+    let (input, _) = le_u8(input)?;
+    let (input, _tracker) = take(20usize)(input)?;
+    let (input, version) = le_u16(input)?;
+    if version != 0x0104 {
+        bail!(input, "XM version {:#06x} is not supported, only 0x0104", version);
+    }
+    let start = input;
+    let (input, header_size) = le_u32(input)?;
+    let (input, song_length) = le_u16(input)?;
+    let (input, restart) = le_u16(input)?;
+    let (input, channels) = le_u16(input)?;
+    let (input, patterns) = le_u16(input)?;
+    let (input, instruments) = le_u16(input)?;
+    let (input, flags) = le_u16(input)?;
+    let (input, speed) = le_u16(input)?;
+    let (input, bpm) = le_u16(input)?;
+    let (_, orders) = take(usize::from(song_length).min(MAX_ORDERS))(input)?;
+
+    let header_size = usize::try_from(header_size).unwrap_or(usize::MAX);
+    if header_size > start.len() {
+        bail!(start, "header size {} is past the end of the file", header_size);
+    }
+
+    Ok((
+        &start[header_size..],
+        XmHeader {
+            name: name.try_into().unwrap(),
+            restart,
+            channels,
+            patterns,
+            instruments,
+            flags,
+            speed,
+            bpm,
+            orders: orders.to_vec(),
+        },
+    ))
+}
+
+/// Parses a pattern into rows of note, instrument, volume, effect and parameter bytes
+fn xm_pattern<'i, E>(input: &'i [u8], channels: usize) -> IResult<&'i [u8], Vec<Vec<[u8; 5]>>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let start = input;
+    let (input, header_size) = le_u32(input)?;
+    let (input, _packing) = le_u8(input)?;
+    let (input, rows) = le_u16(input)?;
+    let (_, size) = le_u16(input)?;
+    let header_size = usize::try_from(header_size).unwrap_or(usize::MAX);
+    if header_size > start.len() {
+        bail!(start, "pattern header size {} is past the end of the file", header_size);
+    }
+    let (rest, mut data) = take(usize::from(size))(&start[header_size..])?;
+
+    let mut pattern = vec![vec![[0; 5]; channels]; usize::from(rows)];
+    // Patterns without data are empty.
+    if size > 0 {
+        for cell in pattern.iter_mut().flatten() {
+            let (input, first) = le_u8(data)?;
+            if first & 0x80 == 0 {
+                let (input, bytes) = take(4usize)(input)?;
+                cell[0] = first;
+                cell[1..].copy_from_slice(bytes);
+                data = input;
+            } else {
+                let mut input = input;
+                for (idx, byte) in cell.iter_mut().enumerate() {
+                    if first & (1 << idx) != 0 {
+                        let (rest, value) = le_u8(input)?;
+                        *byte = value;
+                        input = rest;
+                    }
+                }
+                data = input;
+            }
+        }
+    }
+
+    Ok((rest, pattern))
+}
+
+fn xm_instrument<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], RawInstrument, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    fn envelope<'i, E: ParseError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], Vec<(u16, u16)>, E> {
+        count(tuple((le_u16, le_u16)), MAX_ENVELOPE_POINTS)(input)
+    }
+
+    let start = input;
+    let (input, size) = le_u32(input)?;
+    let (input, name) = take(22usize)(input)?;
+    let (input, _kind) = le_u8(input)?;
+    let (input, sample_count) = le_u16(input)?;
+
+    let mut instrument = RawInstrument {
+        name: name.try_into().unwrap(),
+        sample_map: [0; NOTES],
+        volume_envelope: RawEnvelope { points: Vec::new(), sustain: 0, loop_start: 0, loop_end: 0, flags: 0 },
+        panning_envelope: RawEnvelope { points: Vec::new(), sustain: 0, loop_start: 0, loop_end: 0, flags: 0 },
+        vibrato: [0; 4],
+        fadeout: 0,
+        samples: Vec::new(),
+    };
+    // Some writers store a smaller header size than the fields they write.
+    let size = usize::try_from(size).unwrap_or(usize::MAX);
+    if sample_count == 0 {
+        let rest = start.get(size.max(start.len() - input.len())..).unwrap_or(input);
+        return Ok((rest, instrument));
+    }
+
+    let (input, sample_header_size) = le_u32(input)?;
+    let (input, sample_map) = take(NOTES)(input)?;
+    let (input, volume_points) = envelope(input)?;
+    let (input, panning_points) = envelope(input)?;
+    let (input, volume_count) = le_u8(input)?;
+    let (input, panning_count) = le_u8(input)?;
+    let (input, volume_loop) = take(3usize)(input)?;
+    let (input, panning_loop) = take(3usize)(input)?;
+    let (input, volume_flags) = le_u8(input)?;
+    let (input, panning_flags) = le_u8(input)?;
+    let (input, vibrato) = take(4usize)(input)?;
+    let (input, fadeout) = le_u16(input)?;
+
+    let raw_envelope = |mut points: Vec<(u16, u16)>, count: u8, loops: &[u8], flags| {
+        points.truncate(usize::from(count));
+        RawEnvelope { points, sustain: loops[0], loop_start: loops[1], loop_end: loops[2], flags }
+    };
+    instrument.sample_map.copy_from_slice(sample_map);
+    instrument.volume_envelope = raw_envelope(volume_points, volume_count, volume_loop, volume_flags);
+    instrument.panning_envelope = raw_envelope(panning_points, panning_count, panning_loop, panning_flags);
+    instrument.vibrato.copy_from_slice(vibrato);
+    instrument.fadeout = fadeout;
+
+    // The sample headers follow the instrument header, the sample data follows all the headers.
+    let mut input = start.get(size.max(start.len() - input.len())..).unwrap_or(input);
+    let sample_header_size = usize::try_from(sample_header_size).unwrap_or(usize::MAX);
+    let mut headers = Vec::with_capacity(usize::from(sample_count));
+    for _ in 0..sample_count {
+        let header_start = input;
+        let (rest, length) = le_u32(input)?;
+        let (rest, loop_start) = le_u32(rest)?;
+        let (rest, loop_length) = le_u32(rest)?;
+        let (rest, volume) = le_u8(rest)?;
+        let (rest, finetune) = le_i8(rest)?;
+        let (rest, kind) = le_u8(rest)?;
+        let (rest, panning) = le_u8(rest)?;
+        let (rest, relative_note) = le_i8(rest)?;
+        let (rest, compression) = le_u8(rest)?;
+        let (rest, name) = take(22usize)(rest)?;
+        if compression == 0xAD {
+            bail!(header_start, "ADPCM compressed samples are not supported");
+        }
+        let sample = RawSample {
+            name: name.try_into().unwrap(),
+            loop_start,
+            loop_length,
+            volume,
+            finetune,
+            kind,
+            panning,
+            relative_note,
+            data: SampleData::from(Vec::<i8>::new()),
+        };
+        headers.push((length, sample));
+        input = header_start.get(sample_header_size.max(header_start.len() - rest.len())..).unwrap_or(rest);
+    }
+
+    for (length, mut sample) in headers {
+        let (rest, bytes) = take(usize::try_from(length).unwrap_or(usize::MAX))(input)?;
+        input = rest;
+
+        // Samples are delta encoded.
+        sample.data = if sample.kind & 0x10 != 0 {
+            let (_, deltas) = count(le_i16, bytes.len() / 2)(bytes)?;
+            let mut last = 0i16;
+            SampleData::from(deltas.into_iter().map(|delta| { last = last.wrapping_add(delta); last }).collect::<Vec<_>>())
+        } else {
+            let (_, deltas) = count(le_i8, bytes.len())(bytes)?;
+            let mut last = 0i8;
+            SampleData::from(deltas.into_iter().map(|delta| { last = last.wrapping_add(delta); last }).collect::<Vec<_>>())
+        };
+        instrument.samples.push(sample);
+    }
+
+    Ok((input, instrument))
+}
+
+
+/// Converts rows of note, instrument, volume, effect and parameter bytes into a pattern
+///
+/// Also used for ProTracker modules, XM effects `0..=F` are the ProTracker ones.
+pub(super) fn pattern_from_xm(rows: Vec<Vec<[u8; 5]>>, counts: &mut PatternCounts) -> Pattern {
+    let mut active_channels = ActiveChannels::empty();
+    let rows = rows
+        .into_iter()
+        .map(|cells| {
+            let commands = (0..)
+                .zip(cells.into_iter().take(MAX_IT_CHANNELS))
+                .filter_map(|(idx, cell)| Some((Channel::from_index(idx).unwrap(), command_from_xm(cell, counts)?)))
+                .collect::<Vec<_>>();
+            active_channels |= commands.iter().map(|(channel, _)| *channel).collect();
+            Row::from_vec(commands)
+        })
+        .collect();
+    Pattern { active_channels, rows }
+}
+
+/// Converts the note, instrument, volume, effect and parameter bytes into a command
+fn command_from_xm([note, instrument, volume, effect, param]: [u8; 5], counts: &mut PatternCounts) -> Option<Command> {
+    const SET_VOLUME: u8 = 0xC;
+    const KEY_OFF_AT: u8 = 20;
+
+    let mut command = Command {
+        note: match note {
+            1..=96 => Some(NoteCmd::Play(Note::try_from(note + FIRST_NOTE - 1).unwrap())),
+            KEY_OFF => Some(NoteCmd::Off),
+            _ => None,
+        },
+        instrument: InstrumentId::from_number(instrument).ok(),
+        volume: volume_from_xm(volume).and_then(|volume| volume.count(counts)),
+        effect: None,
+    };
+    match (effect, param) {
+        // IT has no effect setting the volume, use the volume column if it's free.
+        (SET_VOLUME, _) if command.volume.is_none() => {
+            let volume = if param <= 64 { Converted::Exact(param) } else { Converted::Approximated(64) };
+            command.volume = volume.and_then(parser::volume).count(counts);
+        }
+        (KEY_OFF_AT, 0) if command.note.is_none() => command.note = Some(NoteCmd::Off),
+        (SET_VOLUME | KEY_OFF_AT, _) => counts.effects_dropped += 1,
+        _ => command.effect = effect_from_xm(effect, param).and_then(|effect| effect.count(counts)),
+    }
+    (command != Command::EMPTY).then_some(command)
+}
+
+/// Converts an XM volume column byte, `None` if it does nothing
+fn volume_from_xm(volume: u8) -> Option<Converted<VolumeCmd>> {
+    use Converted::*;
+
+    // IT volume column command `base + x` for the commands with `x` in `1..=9`
+    fn param(base: u8, x: u8) -> Converted<u8> {
+        if x <= 9 { Exact(base + x) } else { Approximated(base + 9) }
+    }
+
+    let (x, y) = (volume >> 4, volume & 0x0F);
+    // Values are converted to IT volume column bytes, see `parser::volume`.
+    let converted = match x {
+        0x1..=0x4 => Exact(volume - 0x10),
+        0x5 if y == 0 => Exact(64),
+        0x5 => Approximated(64),
+        // XM slides have no memory, zero slides do nothing.
+        0x6..=0x9 if y == 0 => return None,
+        0x6 => param(95, y),
+        0x7 => param(85, y),
+        0x8 => param(75, y),
+        0x9 => param(65, y),
+        0xB if y == 0 => Exact(203),
+        0xB => param(203, y),
+        0xC => Exact(128 + y * 4),
+        0xF if y == 0 => Exact(193),
+        0xF => {
+            let speed = u16::from(y) * 16;
+            let (idx, nearest) = (1..)
+                .zip(&TONE_PORTAMENTO_SPEEDS[1..])
+                .min_by_key(|(_, other)| other.abs_diff(speed))
+                .unwrap();
+            if *nearest == speed { Exact(193 + idx) } else { Approximated(193 + idx) }
+        }
+        // Vibrato speed and panning slides have no IT volume column equivalent.
+        0xA | 0xD | 0xE => Dropped,
+        _ => return None,
+    };
+    Some(converted.and_then(parser::volume))
+}
+
+/// Converts an XM effect, `None` if it does nothing
+fn effect_from_xm(effect: u8, param: u8) -> Option<Converted<EffectCmd>> {
+    use Converted::*;
+
+    let (x, y) = (param >> 4, param & 0x0F);
+
+    // XM slides up when both nibbles are set, IT would read some of these as fine slides.
+    let slide = |letter| if x > 0 && y > 0 { Approximated((letter, x << 4)) } else { Exact((letter, param)) };
+
+    // Effects are converted to the IT effect letters and parameters first, see `parser::effect`.
+    let converted = match effect {
+        0x0 if param == 0 => return None,
+        0x0 => Exact((b'J', param)),
+        0x1 if param <= 0xDF => Exact((b'F', param)),
+        0x1 => Approximated((b'F', 0xDF)),
+        0x2 if param <= 0xDF => Exact((b'E', param)),
+        0x2 => Approximated((b'E', 0xDF)),
+        0x3 => Exact((b'G', param)),
+        0x4 => Exact((b'H', param)),
+        0x5 => slide(b'L'),
+        0x6 => slide(b'K'),
+        0x7 => Exact((b'R', param)),
+        0x8 => Exact((b'X', param)),
+        0x9 => Exact((b'O', param)),
+        0xA => slide(b'D'),
+        0xB => Exact((b'B', param)),
+        // The row is stored in BCD.
+        0xD => Exact((b'C', x * 10 + y)),
+        0xE => match x {
+            0x1 => Exact((b'F', 0xF0 | y)),
+            0x2 => Exact((b'E', 0xF0 | y)),
+            0x3 => Exact((b'S', 0x10 | y)),
+            0x4 => Exact((b'S', 0x30 | y)),
+            0x5 => Approximated((b'S', 0x20 | y)),
+            0x6 => Exact((b'S', 0xB0 | y)),
+            0x7 => Exact((b'S', 0x40 | y)),
+            0x8 => Exact((b'S', 0x80 | y)),
+            // Retrigger without volume change, approximated like `R0y` below.
+            0x9 if y > 0 => Approximated((b'Q', y)),
+            // `D0F` and `DF0` are normal slides in IT, `DFF` is a fine slide up.
+            0xA if (1..=0xE).contains(&y) => Exact((b'D', (y << 4) | 0xF)),
+            0xA if y == 0xF => Approximated((b'D', 0xEF)),
+            0xB if (1..=0xE).contains(&y) => Exact((b'D', 0xF0 | y)),
+            0xB if y == 0xF => Approximated((b'D', 0xFE)),
+            0xC => Exact((b'S', 0xC0 | y)),
+            0xD => Exact((b'S', 0xD0 | y)),
+            0xE => Exact((b'S', 0xE0 | y)),
+            _ => Dropped,
+        },
+        0xF if param == 0 => Dropped,
+        0xF if param < 0x20 => Exact((b'A', param)),
+        0xF => Exact((b'T', param)),
+        // `Gxx` global volume goes up to 64.
+        16 => Exact((b'V', param.min(64) * 2)),
+        // XM global volume slides are twice as fast.
+        17 if x > 0 && y > 0 => Approximated((b'W', x << 4)),
+        17 => Approximated((b'W', param)),
+        // XM slides right with `x`, IT with `y`.
+        25 if x > 0 => Approximated((b'P', x)),
+        25 => Approximated((b'P', y << 4)),
+        // `Q0y` (retrigger without volume change) can't be represented.
+        27 if (x == 0 || x == 8) && y > 0 => Approximated((b'Q', param)),
+        27 => Exact((b'Q', param)),
+        29 => Approximated((b'I', param)),
+        33 => match x {
+            0x1 => Exact((b'F', 0xE0 | y)),
+            0x2 => Exact((b'E', 0xE0 | y)),
+            _ => Dropped,
+        },
+        _ => Dropped,
+    };
+    Some(converted.and_then(|(letter, param)| parser::effect(letter - b'A' + 1, param)))
+}
+
+fn instruments_from_xm(xm_instruments: Vec<RawInstrument>, report: &mut Report) -> (Vec<Instrument>, Vec<Sample>) {
+    let sample_count = xm_instruments.iter().map(|instrument| instrument.samples.len()).sum::<usize>();
+    if sample_count > MAX_IT_ITEMS {
+        report.push(Issue::ItemsDropped { kind: "samples", count: sample_count, max: MAX_IT_ITEMS });
+    }
+
+    let mut samples = Vec::new();
+    let instruments = (0..)
+        .zip(xm_instruments)
+        .map(|(idx, xm)| {
+            let instrument_id = InstrumentId::from_index(idx).unwrap();
+            let [vibrato_type, sweep, depth, rate] = xm.vibrato;
+            if vibrato_type == 3 {
+                report.push(Issue::InstrumentFeature { instrument: instrument_id, feature: "ramp up auto-vibrato" });
+            }
+            // XM has sine, square, ramp down and ramp up, IT has sine, ramp down, square and random.
+            let vibrato_type = match vibrato_type {
+                0 => 0,
+                1 => 2,
+                _ => 1,
+            };
+
+            let first = samples.len();
+            let xm_samples = xm.samples.len();
+            for sample in xm.samples.into_iter().take(MAX_IT_ITEMS.saturating_sub(first)) {
+                samples.push(sample_from_xm(sample, [vibrato_type, sweep, depth, rate]));
+            }
+
+            let mut sample_map = SampleMap::default();
+            for (note, &entry) in (FIRST_NOTE..).zip(&xm.sample_map) {
+                let index = first + usize::from(entry);
+                if usize::from(entry) < xm_samples && index < samples.len() {
+                    let sample = SampleId::from_index(u8::try_from(index).unwrap()).unwrap();
+                    sample_map.set_sample(Note::try_from(note).unwrap(), Some(sample));
+                }
+            }
+
+            Instrument {
+                name: name(&xm.name),
+                filename: DosFilename::sanitize(""),
+                flags: InstrumentFlags::empty(),
+                new_note_action: NewNoteAction::Cut,
+                duplicate_check_type: DuplicateCheckType::Off,
+                duplicate_check_action: DuplicateCheckAction::Cut,
+                // XM fadeout is 32 times finer.
+                instrument_fadeout: u8::try_from(xm.fadeout.min(0xFFF) / 32).unwrap(),
+                pitch_pan_separation: 0,
+                pitch_pan_centre: 60,
+                global_volume: 128,
+                default_panning: RangedU8::new(32),
+                random_volume_variation: RangedU8::new(0),
+                random_panning_variation: RangedU8::new(0),
+                trkver: 0x0214,
+                number_of_samples: u8::try_from(samples.len() - first).unwrap(),
+                initial_filter_cutoff: RangedU8::new(0),
+                initial_filter_resonance: RangedU8::new(0),
+                mch: 0,
+                mpr: 0xFF,
+                mbank: [0xFF, 0xFF],
+                sample_map,
+                volume_envelope: envelope_from_xm(&xm.volume_envelope, 0),
+                panning_envelope: envelope_from_xm(&xm.panning_envelope, 32),
+                pitch_filter_envelope: Envelope {
+                    flags: EnvelopeFlags::empty(),
+                    envelope_loop: None,
+                    sustain_loop: None,
+                    nodes: Vec::new(),
+                },
+            }
+        })
+        .collect();
+    (instruments, samples)
+}
+
+/// Converts an XM envelope, `offset` is subtracted from the values in `0..=64`.
+fn envelope_from_xm(xm: &RawEnvelope, offset: i16) -> Envelope {
+    let nodes = xm.points
+        .iter()
+        .map(|&(tick, value)| {
+            let value = i16::try_from(value.min(64)).unwrap() - offset;
+            Node { value: i8::try_from(value).unwrap(), tick }
+        })
+        .collect::<Vec<_>>();
+    let len = nodes.len();
+
+    let mut flags = EnvelopeFlags::empty();
+    flags.set(EnvelopeFlags::ENABLED, xm.flags & 1 != 0 && len > 0);
+    let sustain_loop = (xm.flags & 2 != 0 && usize::from(xm.sustain) < len)
+        .then_some(EnvelopeLoop { start: xm.sustain, end: xm.sustain });
+    let envelope_loop = (xm.flags & 4 != 0 && xm.loop_start <= xm.loop_end && usize::from(xm.loop_end) < len)
+        .then_some(EnvelopeLoop { start: xm.loop_start, end: xm.loop_end });
+    flags.set(EnvelopeFlags::SUSTAIN, sustain_loop.is_some());
+    flags.set(EnvelopeFlags::LOOP, envelope_loop.is_some());
+
+    Envelope { flags, envelope_loop, sustain_loop, nodes }
+}
+
+fn sample_from_xm(xm: RawSample, [vibrato_type, sweep, depth, rate]: [u8; 4]) -> Sample {
+    let width = if xm.data.is_16bit() { 2 } else { 1 };
+    let length = u32::try_from(xm.data.len()).unwrap();
+    let sample_loop = SampleLoop {
+        start: xm.loop_start / width,
+        end: xm.loop_start.saturating_add(xm.loop_length) / width,
+        bidi: xm.kind & 0x02 != 0,
+    };
+    let loop_ = (xm.kind & 0x03 != 0 && sample_loop.validate(length).is_ok()).then_some(sample_loop);
+
+    let pitch = f64::from(xm.relative_note) + f64::from(xm.finetune) / 128.0;
+
+    Sample {
+        name: name(&xm.name),
+        filename: DosFilename::sanitize(""),
+        global_volume: 64,
+        default_volume: xm.volume.min(64),
+        // XM samples always set the panning.
+        default_panning: 0x80 | u8::try_from(((u16::from(xm.panning) + 2) / 4).min(64)).unwrap(),
+        loop_,
+        sustain_loop: None,
+        samplerate_c5: samplerate_from(pitch, BASE_FREQUENCY),
+        vibrato_speed: rate,
+        vibrato_depth: depth,
+        vibrato_rate: sweep,
+        vibrato_type,
+        data: (length > 0).then_some(xm.data),
+        lazy_data: None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn export_module() {
+        let module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/effect_alphabet.it")).unwrap();
+        let (bytes, report) = export(&module);
+
+        assert!(bytes.starts_with(b"Extended Module: "));
+        assert_eq!(bytes[37], 0x1A);
+        assert_eq!(u16::from_le_bytes([bytes[58], bytes[59]]), 0x0104);
+        let pattern_count = u16::from_le_bytes([bytes[70], bytes[71]]);
+        assert_eq!(usize::from(pattern_count), module.patterns.len());
+        // Effects without an XM equivalent are reported, not silently dropped.
+        assert!(report.issues.iter().any(|issue| matches!(issue, Issue::EffectsDropped { .. })));
+    }
+
+    #[test]
+    fn cells() {
+        let mut counts = PatternCounts::default();
+        let command = Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_number(3).unwrap()),
+            volume: Some(VolumeCmd::SetVolume(RangedU8::new(32))),
+            effect: Some(EffectCmd::BreakRow(16)),
+        };
+        assert_eq!(cell(&command, &mut counts), [49, 3, 0x30, 0xD, 0x16]);
+
+        let command = Command {
+            note: Some(NoteCmd::Play(Note::C_0)),
+            effect: Some(EffectCmd::SetChannelVolume(RangedU8::new(10))),
+            ..Command::EMPTY
+        };
+        assert_eq!(cell(&command, &mut counts), [0; 5]);
+        assert_eq!((counts.notes_dropped, counts.effects_dropped), (1, 1));
+
+        let mut packed = Vec::new();
+        pack_cell(&mut packed, [49, 3, 0, 0, 0]);
+        pack_cell(&mut packed, [49, 3, 0x30, 0xD, 0x16]);
+        assert_eq!(packed, [0x83, 49, 3, 49, 3, 0x30, 0xD, 0x16]);
+    }
+
+    #[test]
+    fn import_exported() {
+        let module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/effect_alphabet.it")).unwrap();
+        let (bytes, _) = export(&module);
+        let (imported, _) = import::<VerboseError<&[u8]>>(&bytes).unwrap();
+
+        let orders = |module: &Module| module.orders
+            .iter()
+            .take_while(|order| **order != Order::EndOfSong)
+            .filter(|order| **order != Order::Separator)
+            .copied()
+            .collect::<Vec<_>>();
+        let notes = |module: &Module| module.patterns
+            .iter()
+            .flat_map(|pattern| &pattern.rows)
+            .flat_map(Row::iter)
+            .filter_map(|(channel, command)| match command.note {
+                Some(NoteCmd::Play(note)) if (12..=107).contains(&u8::from(note)) => Some((channel, note)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(imported.patterns.len(), module.patterns.len());
+        assert_eq!(orders(&imported), orders(&module));
+        assert_eq!(notes(&imported), notes(&module));
+    }
+
+    #[test]
+    fn effects_roundtrip() {
+        for effect in [
+            EffectCmd::SetSpeed(RangedU8::new(6)),
+            EffectCmd::JumpOrder(OrderIdx::new(3)),
+            EffectCmd::BreakRow(16),
+            EffectCmd::VolumeSlide(Some(VolumeSlide::Up(RangedU8::new(4)))),
+            EffectCmd::VolumeSlide(Some(VolumeSlide::FineDown(RangedU8::new(2)))),
+            EffectCmd::PortamentoUp(Some(Portamento::Fine(RangedU8::new(3)))),
+            EffectCmd::PortamentoDown(Some(Portamento::ExtraFine(RangedU8::new(1)))),
+            EffectCmd::TonePortamento(Some(RangedU8::new(0x20))),
+            EffectCmd::Vibrato(Some(RangedU8::new(4)), Some(RangedU8::new(8))),
+            EffectCmd::Special(Some(Special::NoteDelay(RangedU8::new(3)))),
+            EffectCmd::Tempo(Some(Tempo::Set(RangedU8::new(0x80)))),
+        ] {
+            let (xm_effect, param) = match effect_command(effect) {
+                Converted::Exact(xm) => xm,
+                _ => panic!("{:?} is not converted exactly", effect),
+            };
+            let imported = effect_from_xm(xm_effect, param);
+            assert!(matches!(imported, Some(Converted::Exact(imported)) if imported == effect), "{:?}", effect);
+        }
+
+        for volume in [
+            VolumeCmd::SetVolume(RangedU8::new(40)),
+            VolumeCmd::Panning(RangedU8::new(16)),
+            VolumeCmd::VolumeSlideDown(Some(RangedU8::new(3))),
+            VolumeCmd::FineVolumeUp(Some(RangedU8::new(9))),
+            VolumeCmd::TonePortamento(Some(RangedU8::new(4))),
+        ] {
+            let xm = match volume_command(volume) {
+                Converted::Exact(xm) => xm,
+                _ => panic!("{:?} is not converted exactly", volume),
+            };
+            let imported = volume_from_xm(xm);
+            assert!(matches!(imported, Some(Converted::Exact(imported)) if imported == volume), "{:?}", volume);
+        }
+    }
+}
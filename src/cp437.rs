@@ -0,0 +1,141 @@
+//! Code page 437 text encoding
+//!
+//! Impulse Tracker is a DOS program and all of its text (song message, names) is stored in the
+//! IBM PC code page 437. The lower half matches ASCII, the upper half contains accented letters,
+//! box drawing characters and symbols.
+
+use crate::prelude::*;
+
+/// Unicode characters for the upper half (`0x80..=0xFF`) of the code page.
+const UPPER_HALF: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Character used in place of characters which can't be encoded.
+pub const REPLACEMENT: u8 = b'?';
+
+/// Decodes a single byte
+pub fn decode_byte(byte: u8) -> char {
+    match byte {
+        0x00..=0x7F => char::from(byte),
+        0x80..=0xFF => UPPER_HALF[usize::from(byte - 0x80)],
+    }
+}
+
+/// Encodes a single character, returns `None` if the code page doesn't contain it
+pub fn encode_char(ch: char) -> Option<u8> {
+    if ch.is_ascii() {
+        return u8::try_from(ch).ok();
+    }
+    let idx = UPPER_HALF.iter().position(|&upper| upper == ch)?;
+    Some(0x80 + u8::try_from(idx).unwrap())
+}
+
+/// Decodes bytes into a string
+pub fn decode(bytes: &[u8]) -> String {
+    bytes.iter().copied().map(decode_byte).collect()
+}
+
+/// Encodes a string, characters which can't be encoded are replaced with [`REPLACEMENT`]
+///
+/// Returns the encoded bytes and the list of replaced characters (in the order they appeared,
+/// including duplicates).
+pub fn encode(text: &str) -> (Vec<u8>, Vec<char>) {
+    let mut unmappable = Vec::new();
+    let bytes = text
+        .chars()
+        .map(|ch| {
+            encode_char(ch).unwrap_or_else(|| {
+                unmappable.push(ch);
+                REPLACEMENT
+            })
+        })
+        .collect();
+    (bytes, unmappable)
+}
+
+/// Characters lost when encoding text
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Lossy {
+    /// Characters replaced with [`REPLACEMENT`] (in the order they appeared, including duplicates)
+    pub replaced: Vec<char>,
+
+    /// Number of characters cut off because they didn't fit
+    pub truncated: usize,
+}
+
+impl Lossy {
+    /// Returns `true` if the text was encoded without losing anything.
+    pub fn is_lossless(&self) -> bool {
+        self.replaced.is_empty() && self.truncated == 0
+    }
+}
+
+/// Encodes a string into a fixed size field like the names of samples and instruments
+///
+/// The field is null-terminated and padded with nulls, so at most `field.len() - 1` characters
+/// fit. Trailing spaces are left out, they can't be told apart from the space padding some
+/// trackers use. Control characters (including null which would end the text early) and
+/// characters which can't be encoded are replaced with [`REPLACEMENT`].
+pub fn encode_field(text: &str, field: &mut [u8]) -> Lossy {
+    let mut lossy = Lossy::default();
+    let mut chars = text.trim_end_matches(' ').chars();
+    let capacity = field.len().saturating_sub(1);
+    field.fill(0);
+    for (byte, ch) in field[..capacity].iter_mut().zip(chars.by_ref()) {
+        *byte = match encode_char(ch) {
+            Some(encoded) if !ch.is_ascii_control() => encoded,
+            _ => {
+                lossy.replaced.push(ch);
+                REPLACEMENT
+            }
+        };
+    }
+    lossy.truncated = chars.count();
+    lossy
+}
+
+/// Decodes a fixed size field like the names of samples and instruments
+///
+/// The text ends at the first null, trailing space padding is removed.
+pub fn decode_field(field: &[u8]) -> String {
+    let text = field.split(|&byte| byte == 0).next().unwrap_or_default();
+    let mut text = decode(text);
+    text.truncate(text.trim_end_matches(' ').len());
+    text
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for byte in 0..=255 {
+            assert_eq!(encode_char(decode_byte(byte)), Some(byte));
+        }
+        assert_eq!(encode("Naïve ♪"), (b"Na\x8bve ?".to_vec(), vec!['♪']));
+    }
+
+    #[test]
+    fn fields() {
+        let mut field = [0xFF; 8];
+        assert!(encode_field("Café  ", &mut field).is_lossless());
+        assert_eq!(field, *b"Caf\x82\0\0\0\0");
+        assert_eq!(decode_field(&field), "Café");
+
+        let lossy = encode_field("a\0b♪cdefgh", &mut field);
+        assert_eq!(lossy, Lossy { replaced: vec!['\0', '♪'], truncated: 3 });
+        assert_eq!(field, *b"a?b?cde\0");
+
+        assert_eq!(decode_field(b"padded   \0junk"), "padded");
+    }
+}
@@ -2,17 +2,29 @@
 
 
 use crate::error::OutOfRangeError;
+use crate::prelude::*;
 pub(crate) use bitflags::bitflags;
-use std::convert::TryFrom;
-use std::fmt::{self, Debug};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug, Display};
 
 
 macro_rules! ranged_u8_newtype {
-    ( $name: ident, $low: literal ..= $high: literal ) => {
-        #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+    ( $(#[$meta: meta])* $name: ident, $low: literal ..= $high: literal ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq, Hash)]
         pub struct $name(u8);
 
         impl $name {
+            /// Create the identifier from a 0 based index, as stored in the [`Module`] lists
+            pub fn from_index(index: u8) -> Result<$name, OutOfRangeError<$low, $high>> {
+                $name::try_from(index)
+            }
+
+            /// Returns 0 based index into the corresponding [`Module`] list
+            pub fn as_usize(self) -> usize {
+                self.0.into()
+            }
+
             pub(crate) fn as_u8(self) -> u8 {
                 self.0
             }
@@ -41,15 +53,95 @@ macro_rules! ranged_u8_newtype {
                 Debug::fmt(&self.as_u8(), f)
             }
         }
+
+        #[cfg(feature = "arbitrary")]
+        impl<'a> ::arbitrary::Arbitrary<'a> for $name {
+            fn arbitrary(u: &mut ::arbitrary::Unstructured<'a>) -> ::arbitrary::Result<Self> {
+                Ok($name(u.int_in_range($low..=$high)?))
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl ::serde::Serialize for $name {
+            fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_u8(self.0)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl<'de> ::serde::Deserialize<'de> for $name {
+            fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let index = <u8 as ::serde::Deserialize>::deserialize(deserializer)?;
+                $name::try_from(index).map_err(::serde::de::Error::custom)
+            }
+        }
+    };
+
+    // Identifiers which are displayed as 1 based numbers in trackers.
+    ( $(#[$meta: meta])* $name: ident, $low: literal ..= $high: literal, number $nlow: literal ..= $nhigh: literal ) => {
+        ranged_u8_newtype!( $(#[$meta])* $name, $low ..= $high );
+
+        impl $name {
+            /// Create the identifier from a 1 based number, as displayed in trackers and stored in
+            /// pattern data
+            pub fn from_number(number: u8) -> Result<$name, OutOfRangeError<$nlow, $nhigh>> {
+                if ($nlow..=$nhigh).contains(&number) {
+                    Ok($name(number - 1))
+                } else {
+                    Err(OutOfRangeError(number))
+                }
+            }
+
+            /// Returns 1 based number, as displayed in trackers and stored in pattern data
+            pub fn number(self) -> u8 {
+                self.0 + 1
+            }
+        }
+
+        impl Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{:02}", self.number())
+            }
+        }
     };
 }
 
 // TODO We're already using range-limited opaque numbers for the IDs, ideally we'd be also
 //      versioning them (like <https://crates.io/crates/slotmap>) to make sure we always catch
 //      nonsensical values both during parsing and during serialization.
-ranged_u8_newtype!(InstrumentId, 0..=98);
-ranged_u8_newtype!(PatternId, 0..=199);
-ranged_u8_newtype!(SampleId, 0..=98);
+ranged_u8_newtype!(
+    /// Instrument identifier
+    ///
+    /// Stored as a 0 based index into [`Module::instruments`], while trackers and pattern data use
+    /// 1 based numbers. Use [`InstrumentId::from_number`] and [`InstrumentId::number`] for those.
+    InstrumentId, 0..=98, number 1..=99
+);
+ranged_u8_newtype!(
+    /// Pattern identifier
+    ///
+    /// Patterns are numbered from 0 both in trackers and in the orders list.
+    PatternId, 0..=199
+);
+ranged_u8_newtype!(
+    /// Sample identifier
+    ///
+    /// Stored as a 0 based index into [`Module::samples`], while trackers and pattern data use 1
+    /// based numbers. Use [`SampleId::from_number`] and [`SampleId::number`] for those.
+    SampleId, 0..=98, number 1..=99
+);
+ranged_u8_newtype!(
+    /// Position in the orders list
+    ///
+    /// Orders are numbered from 0 both in trackers and in effects such as `Bxx`.
+    OrderIdx, 0..=255
+);
+
+impl OrderIdx {
+    /// Create the position from a 0 based index, every `u8` is a valid position
+    pub fn new(index: u8) -> OrderIdx {
+        OrderIdx(index)
+    }
+}
 
 
 pub trait Get<I> {
@@ -70,37 +162,47 @@ where
 
 macro_rules! impl_index_from_get {
     ( $for: ty, $idx: ty ) => {
-        impl ::std::ops::Index<$idx> for $for {
+        impl ::core::ops::Index<$idx> for $for {
             type Output = <$for as $crate::data::Get<$idx>>::Output;
             fn index(&self, index: $idx) -> &Self::Output {
                 self.get(index)
-                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::std::any::type_name::<$idx>(), &index))
+                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::core::any::type_name::<$idx>(), &index))
             }
         }
 
-        impl ::std::ops::Index<&$idx> for $for {
+        impl ::core::ops::Index<&$idx> for $for {
             type Output = <$for as $crate::data::Get<$idx>>::Output;
             fn index(&self, index: &$idx) -> &Self::Output {
                 self.get(index)
-                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::std::any::type_name::<$idx>(), &index))
+                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::core::any::type_name::<$idx>(), &index))
             }
         }
     };
 }
 
 
+#[cfg(feature = "arbitrary")]
+mod arbitrary;
 mod channel;
+mod columns;
 mod envelope;
 mod instrument;
+mod midi_macro;
 mod module;
 mod pattern;
+mod preserved;
 mod sample;
+#[cfg(feature = "serde")]
+pub(crate) mod serde;
 mod util;
 
 pub use channel::*;
+pub use columns::*;
 pub use envelope::*;
 pub use instrument::*;
+pub use midi_macro::*;
 pub use module::*;
 pub use pattern::*;
+pub use preserved::*;
 pub use sample::*;
 pub use util::*;
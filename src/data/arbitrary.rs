@@ -0,0 +1,408 @@
+//! [`Arbitrary`] implementations for the module tree
+//!
+//! The generated values are structurally valid: references between the parts of a [`Module`]
+//! point to existing items, loops fit the sample data, and envelopes and pattern commands only
+//! contain values the parser could have produced. This makes it possible to write property tests
+//! like `parse(write(module)) == module` without filtering the inputs.
+
+use super::*;
+use crate::parser;
+use ::arbitrary::{Arbitrary, Result, Unstructured};
+use std::convert::TryFrom;
+use std::ops::RangeInclusive;
+
+
+/// Generates a collection length of at most `max`.
+fn len<'a, T: Arbitrary<'a>>(u: &mut Unstructured<'a>, max: usize) -> Result<usize> {
+    Ok(u.arbitrary_len::<T>()?.min(max))
+}
+
+/// Fills at most `max_len` bytes from the start of `bytes` with printable ASCII.
+fn text(u: &mut Unstructured<'_>, bytes: &mut [u8], max_len: usize) -> Result<()> {
+    let length = len::<u8>(u, max_len)?;
+    for byte in &mut bytes[..length] {
+        *byte = u.int_in_range(b' '..=b'~')?;
+    }
+    Ok(())
+}
+
+/// Generates a random flag set, unknown bits are dropped.
+macro_rules! arbitrary_flags {
+    ( $( $name: ident ),* ) => {
+        $(
+            impl<'a> Arbitrary<'a> for $name {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    Ok($name::from_bits_truncate(u.arbitrary()?))
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_flags!(ModuleFlags, InstrumentFlags, EnvelopeFlags);
+
+
+impl<'a, const LOW: u8, const HIGH: u8> Arbitrary<'a> for RangedU8<LOW, HIGH> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(RangedU8::try_from(u.int_in_range(LOW..=HIGH)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for Name {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Last byte is always kept as the null terminator.
+        let mut bytes = [0; 26];
+        text(u, &mut bytes, 25)?;
+        Ok(Name { bytes })
+    }
+}
+
+impl<'a> Arbitrary<'a> for DosFilename {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut bytes = [0; 13];
+        text(u, &mut bytes, 12)?;
+        Ok(DosFilename { bytes })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Channel {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Channel::from_u8_index(u.int_in_range(0..=63)?))
+    }
+}
+
+impl<'a> Arbitrary<'a> for ChannelPanning {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(if u.ratio(1, 8)? {
+            ChannelPanning::Surround
+        } else {
+            ChannelPanning::Position(u.arbitrary()?)
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for ChannelSettings {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(ChannelSettings {
+            panning: u.arbitrary()?,
+            muted: u.arbitrary()?,
+            volume: u.arbitrary()?,
+        })
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for Note {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Note::try_from(u.int_in_range(0..=119)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for NoteCmd {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=7)? {
+            0 => NoteCmd::Off,
+            1 => NoteCmd::Cut,
+            2 => NoteCmd::Fade,
+            _ => NoteCmd::Play(u.arbitrary()?),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for VolumeCmd {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Going through the parser guarantees only canonical commands are generated, the raw
+        // values outside of the valid ranges are folded into the volume range.
+        let raw = u.arbitrary()?;
+        Ok(match parser::volume(raw) {
+            Some(volume) => volume,
+            None => VolumeCmd::SetVolume(RangedU8::try_from(raw % 65).unwrap()),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for EffectCmd {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Same as for volume, invalid parameters become an empty `S00`.
+        let effect = u.int_in_range(1..=26)?;
+        let param = u.arbitrary()?;
+        Ok(parser::effect(effect, param).unwrap_or(EffectCmd::Special(None)))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Command {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Command {
+            note: u.arbitrary()?,
+            instrument: u.arbitrary()?,
+            volume: u.arbitrary()?,
+            effect: u.arbitrary()?,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Row {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Each channel appears at most once, `from_vec` takes care of the ordering.
+        let mut commands = Vec::new();
+        for idx in 0..64 {
+            if u.ratio(1, 8)? {
+                commands.push((Channel::from_u8_index(idx), u.arbitrary()?));
+            }
+        }
+        Ok(Row::from_vec(commands))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Pattern {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let rows = u.int_in_range(1..=200)?;
+        let rows = (0..rows)
+            .map(|_| u.arbitrary())
+            .collect::<Result<Vec<Row>>>()?;
+        let active_channels = ActiveChannels::new(
+            rows.iter().flat_map(|row| row.iter().map(|(chan, _)| chan))
+        );
+        Ok(Pattern {
+            active_channels,
+            rows,
+        })
+    }
+}
+
+
+/// Generates an envelope with node values in the `values` range.
+fn envelope(u: &mut Unstructured<'_>, values: RangeInclusive<i8>) -> Result<Envelope> {
+    // IT envelopes have 2..=25 nodes, the first one is always on tick 0 and the ticks are
+    // increasing.
+    let count = u.int_in_range(2..=25)?;
+    let mut tick = 0;
+    let mut nodes = Vec::with_capacity(count);
+    for idx in 0..count {
+        if idx > 0 {
+            tick += u.int_in_range(1..=64)?;
+        }
+        nodes.push(Node {
+            value: u.int_in_range(values.clone())?,
+            tick,
+        });
+    }
+
+    let last = u8::try_from(count - 1).unwrap();
+    let mut arbitrary_loop = || -> Result<Option<EnvelopeLoop>> {
+        Ok(if u.arbitrary()? {
+            let start = u.int_in_range(0..=last)?;
+            let end = u.int_in_range(start..=last)?;
+            Some(EnvelopeLoop { start, end })
+        } else {
+            None
+        })
+    };
+
+    let envelope_loop = arbitrary_loop()?;
+    let sustain_loop = arbitrary_loop()?;
+
+    Ok(Envelope {
+        flags: u.arbitrary()?,
+        envelope_loop,
+        sustain_loop,
+        nodes,
+    })
+}
+
+impl<'a> Arbitrary<'a> for Envelope {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        envelope(u, -32..=32)
+    }
+}
+
+impl<'a> Arbitrary<'a> for NewNoteAction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(NewNoteAction::try_from(u.int_in_range(0..=3)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for DuplicateCheckType {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(DuplicateCheckType::try_from(u.int_in_range(0..=3)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for DuplicateCheckAction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(DuplicateCheckAction::try_from(u.int_in_range(0..=2)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for SampleMap {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut map = SampleMap::default();
+        for entry in &mut map.map {
+            *entry = (u.arbitrary()?, u.arbitrary()?);
+        }
+        Ok(map)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Instrument {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Instrument {
+            name: u.arbitrary()?,
+            filename: u.arbitrary()?,
+            flags: u.arbitrary()?,
+            new_note_action: u.arbitrary()?,
+            duplicate_check_type: u.arbitrary()?,
+            duplicate_check_action: u.arbitrary()?,
+            instrument_fadeout: u.arbitrary()?,
+            pitch_pan_separation: u.int_in_range(-32..=32)?,
+            pitch_pan_centre: u.int_in_range(0..=119)?,
+            global_volume: u.int_in_range(0..=128)?,
+            default_panning: u.arbitrary()?,
+            random_volume_variation: u.arbitrary()?,
+            random_panning_variation: u.arbitrary()?,
+            trkver: u.arbitrary()?,
+            number_of_samples: u.int_in_range(0..=99)?,
+            initial_filter_cutoff: u.arbitrary()?,
+            initial_filter_resonance: u.arbitrary()?,
+            mch: u.int_in_range(0..=16)?,
+            mpr: u.arbitrary()?,
+            mbank: [u.arbitrary()?, u.arbitrary()?],
+            sample_map: u.arbitrary()?,
+            volume_envelope: envelope(u, 0..=64)?,
+            panning_envelope: envelope(u, -32..=32)?,
+            pitch_filter_envelope: envelope(u, -32..=32)?,
+        })
+    }
+}
+
+
+/// Generates a loop which fits into a sample of `length` samples.
+fn sample_loop(u: &mut Unstructured<'_>, length: u32) -> Result<Option<SampleLoop>> {
+    if length < 2 || !u.arbitrary()? {
+        return Ok(None);
+    }
+    let start = u.int_in_range(0..=length - 2)?;
+    let end = u.int_in_range(start + 1..=length)?;
+    let bidi = end - start >= 2 && u.arbitrary()?;
+    Ok(Some(SampleLoop { start, end, bidi }))
+}
+
+impl<'a> Arbitrary<'a> for Sample {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let data = if u.arbitrary()? {
+            let length = len::<i16>(u, 0x10000)?;
+            let data = if u.arbitrary()? {
+                SampleData::from((0..length).map(|_| u.arbitrary()).collect::<Result<Vec<i16>>>()?)
+            } else {
+                SampleData::from((0..length).map(|_| u.arbitrary()).collect::<Result<Vec<i8>>>()?)
+            };
+            Some(data)
+        } else {
+            None
+        };
+        let length = data.as_ref().map_or(0, |data| u32::try_from(data.len()).unwrap());
+
+        let default_panning = u.int_in_range(0..=64)? | if u.arbitrary()? { 0x80 } else { 0x00 };
+
+        Ok(Sample {
+            name: u.arbitrary()?,
+            filename: u.arbitrary()?,
+            global_volume: u.int_in_range(0..=64)?,
+            default_volume: u.int_in_range(0..=64)?,
+            default_panning,
+            loop_: sample_loop(u, length)?,
+            sustain_loop: sample_loop(u, length)?,
+            samplerate_c5: u.int_in_range(0..=9_999_999)?,
+            vibrato_speed: u.int_in_range(0..=64)?,
+            vibrato_depth: u.int_in_range(0..=32)?,
+            vibrato_rate: u.arbitrary()?,
+            vibrato_type: u.int_in_range(0..=3)?,
+            data,
+            lazy_data: None,
+        })
+    }
+}
+
+
+impl<'a> Arbitrary<'a> for Module {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let patterns = (0..len::<Pattern>(u, 200)?)
+            .map(|_| u.arbitrary())
+            .collect::<Result<Vec<Pattern>>>()?;
+        let samples = (0..len::<Sample>(u, 99)?)
+            .map(|_| u.arbitrary())
+            .collect::<Result<Vec<Sample>>>()?;
+        let mut instruments = (0..len::<Instrument>(u, 99)?)
+            .map(|_| u.arbitrary())
+            .collect::<Result<Vec<Instrument>>>()?;
+
+        // Drop references to samples which don't exist.
+        for instrument in &mut instruments {
+            let count = samples.len();
+            instrument.sample_map.remap_samples(|id| (id.as_usize() < count).then_some(id));
+        }
+
+        // Orders can only reference existing patterns, the orders list holds at most 256 entries.
+        let mut orders = Vec::new();
+        for _ in 0..len::<Order>(u, 256)? {
+            let order = match u.int_in_range(0..=7)? {
+                0 => Order::Separator,
+                1 => Order::EndOfSong,
+                _ if patterns.is_empty() => Order::Separator,
+                _ => {
+                    let index = u.int_in_range(0..=patterns.len() - 1)?;
+                    Order::Index(PatternId::try_from(u8::try_from(index).unwrap()).unwrap())
+                }
+            };
+            orders.push(order);
+        }
+
+        let mut message = String::new();
+        for _ in 0..len::<u8>(u, 8000)? {
+            message.push(if u.ratio(1, 64)? {
+                '\r'
+            } else {
+                char::from(u.int_in_range(b' '..=b'~')?)
+            });
+        }
+
+        // The message flag is derived from the message when writing.
+        let mut flags: ModuleFlags = u.arbitrary()?;
+        flags.set(ModuleFlags::MESSAGE_ATTACHED, !message.is_empty());
+
+        let mut channels = [ChannelSettings::DEFAULT; 64];
+        for channel in &mut channels {
+            *channel = u.arbitrary()?;
+        }
+
+        Ok(Module {
+            name: u.arbitrary()?,
+            message,
+            highlight: u.arbitrary()?,
+            made_with_version: u.arbitrary()?,
+            compatible_with_version: u.arbitrary()?,
+            flags,
+            global_volume: u.arbitrary()?,
+            sample_volume: u.arbitrary()?,
+            speed: u.arbitrary()?,
+            tempo: u.arbitrary()?,
+            pan_separation: u.arbitrary()?,
+            pitch_wheel_depth: u.arbitrary()?,
+            channels,
+            orders,
+            instruments,
+            samples,
+            patterns,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Order {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=7)? {
+            0 => Order::Separator,
+            1 => Order::EndOfSong,
+            _ => Order::Index(u.arbitrary()?),
+        })
+    }
+}
@@ -1,13 +1,15 @@
 use super::*;
-use std::borrow::Borrow;
-use std::convert::TryInto;
-use std::fmt::{self, Debug};
-use std::iter::FromIterator;
-use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign};
+use crate::error::OutOfRangeError;
+use core::borrow::Borrow;
+use core::convert::{TryFrom, TryInto};
+use core::fmt::{self, Debug};
+use core::iter::FromIterator;
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign};
 
 
 /// Channel number
 #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize), serde(transparent))]
 pub struct Channel(RangedU8<0, 63>);
 
 impl Channel {
@@ -19,6 +21,29 @@ impl Channel {
         Channel::from_u8_index(number - 1)
     }
 
+    /// Create a channel identifier with the given number
+    ///
+    /// Accepted range is 1..=64, returns an error for values out of the range.
+    pub fn from_number(number: u8) -> Result<Channel, OutOfRangeError<1, 64>> {
+        if (1..=64).contains(&number) {
+            Ok(Channel::from_u8_index(number - 1))
+        } else {
+            Err(OutOfRangeError(number))
+        }
+    }
+
+    /// Create a channel identifier from a 0 based channel index
+    ///
+    /// Accepted range is 0..=63, returns an error for values out of the range.
+    pub fn from_index(index: u8) -> Result<Channel, OutOfRangeError<0, 63>> {
+        RangedU8::try_from(index).map(Channel)
+    }
+
+    /// Returns the channel number (1..=64), as displayed in trackers
+    pub fn number(self) -> u8 {
+        self.0.as_u8() + 1
+    }
+
     /// Returns 0 based channel index (0..=63), as opposed to channel number (1..=64)
     pub fn as_usize(self) -> usize {
         self.0.as_u8().into()
@@ -37,6 +62,70 @@ impl Debug for Channel {
 }
 
 
+/// Initial channel settings
+///
+/// Module header stores the initial panning and volume for all 64 channels. The panning byte
+/// additionally encodes the surround and mute flags, these are split out into separate values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct ChannelSettings {
+    /// Initial channel panning
+    pub panning: ChannelPanning,
+
+    /// Channel is muted (disabled)
+    ///
+    /// Notes on a muted channel are not played, effects in muted channels are still processed.
+    ///
+    /// This is stored as `+128` on the panning value in the file.
+    pub muted: bool,
+
+    /// Initial channel volume (0..=64)
+    pub volume: RangedU8<0, 64>,
+}
+
+/// Initial channel panning
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum ChannelPanning {
+    /// Panning position from `0` (absolute left) to `64` (absolute right), `32` is central pan.
+    Position(RangedU8<0, 64>),
+
+    /// Surround sound
+    ///
+    /// This is stored as the value `100` in the file.
+    Surround,
+}
+
+
+impl ChannelSettings {
+    /// Central panning, unmuted, full volume
+    pub const DEFAULT: ChannelSettings = ChannelSettings {
+        panning: ChannelPanning::Position(RangedU8::new(32)),
+        muted: false,
+        volume: RangedU8::new(64),
+    };
+
+    /// Returns the panning position or `None` if the channel is surround.
+    pub fn pan_position(&self) -> Option<RangedU8<0, 64>> {
+        match self.panning {
+            ChannelPanning::Position(position) => Some(position),
+            ChannelPanning::Surround => None,
+        }
+    }
+
+    /// Returns `true` if the channel has surround enabled.
+    pub fn is_surround(&self) -> bool {
+        matches!(self.panning, ChannelPanning::Surround)
+    }
+}
+
+impl Default for ChannelSettings {
+    fn default() -> ChannelSettings {
+        ChannelSettings::DEFAULT
+    }
+}
+
+
 /// Active channels in a particular pattern or module.
 #[derive(Clone, Copy, PartialEq)]
 pub struct ActiveChannels(u64);
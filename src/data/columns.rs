@@ -0,0 +1,147 @@
+use super::*;
+
+
+/// Pattern stored as a separate array for each column
+///
+/// [`Pattern`] stores each row as a sparse list of commands, which is compact and easy to edit.
+/// `PatternColumns` stores the notes, instruments, volumes and effects of every cell in four
+/// arrays instead, indexed by `row * channels + channel`. Statistics and searches over one
+/// column of many patterns, e.g. counting the notes or looking for an effect, walk contiguous
+/// arrays the compiler can vectorize.
+///
+/// Only the channels up to the last one used in the pattern are stored. Converting back to a
+/// [`Pattern`] gives the original pattern.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatternColumns {
+    rows: usize,
+    channels: usize,
+    notes: Vec<Option<NoteCmd>>,
+    instruments: Vec<Option<InstrumentId>>,
+    volumes: Vec<Option<VolumeCmd>>,
+    effects: Vec<Option<EffectCmd>>,
+}
+
+impl PatternColumns {
+    /// Number of rows
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Number of stored channels, the number of the last channel used in the pattern
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Returns the index of the cell of `channel` on `row` in the columns, `None` outside of the
+    /// stored cells.
+    pub fn index(&self, row: usize, channel: Channel) -> Option<usize> {
+        (row < self.rows && channel.as_usize() < self.channels).then(|| row * self.channels + channel.as_usize())
+    }
+
+    /// Returns the command of `channel` on `row`, `None` outside of the stored cells.
+    pub fn command(&self, row: usize, channel: Channel) -> Option<Command> {
+        self.index(row, channel).map(|idx| Command {
+            note: self.notes[idx],
+            instrument: self.instruments[idx],
+            volume: self.volumes[idx],
+            effect: self.effects[idx],
+        })
+    }
+
+    /// Note column of every cell
+    pub fn notes(&self) -> &[Option<NoteCmd>] {
+        &self.notes
+    }
+
+    /// Instrument column of every cell
+    pub fn instruments(&self) -> &[Option<InstrumentId>] {
+        &self.instruments
+    }
+
+    /// Volume column of every cell
+    pub fn volumes(&self) -> &[Option<VolumeCmd>] {
+        &self.volumes
+    }
+
+    /// Effect column of every cell
+    pub fn effects(&self) -> &[Option<EffectCmd>] {
+        &self.effects
+    }
+}
+
+impl From<&Pattern> for PatternColumns {
+    fn from(pattern: &Pattern) -> PatternColumns {
+        let rows = pattern.rows.len();
+        let channels = pattern.rows
+            .iter()
+            .flat_map(|row| row.iter().map(|(chan, _)| chan.as_usize() + 1))
+            .max()
+            .unwrap_or(0);
+        let mut columns = PatternColumns {
+            rows,
+            channels,
+            notes: vec![None; rows * channels],
+            instruments: vec![None; rows * channels],
+            volumes: vec![None; rows * channels],
+            effects: vec![None; rows * channels],
+        };
+        for (idx, row) in pattern.rows.iter().enumerate() {
+            for (chan, command) in row.iter() {
+                let cell = idx * channels + chan.as_usize();
+                columns.notes[cell] = command.note;
+                columns.instruments[cell] = command.instrument;
+                columns.volumes[cell] = command.volume;
+                columns.effects[cell] = command.effect;
+            }
+        }
+        columns
+    }
+}
+
+impl From<&PatternColumns> for Pattern {
+    fn from(columns: &PatternColumns) -> Pattern {
+        let rows = (0..columns.rows)
+            .map(|row| {
+                let commands = (0..columns.channels)
+                    .map(|idx| Channel::from_index(u8::try_from(idx).unwrap()).unwrap())
+                    .filter_map(|chan| Some((chan, columns.command(row, chan)?)))
+                    .filter(|(_, command)| *command != Command::EMPTY)
+                    .collect();
+                Row::from_vec(commands)
+            })
+            .collect::<Vec<_>>();
+        let active_channels = ActiveChannels::new(rows.iter().flat_map(|row| row.iter().map(|(chan, _)| chan)));
+        Pattern {
+            active_channels,
+            rows,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn columns() {
+        let pattern = Pattern::from_text(concat!(
+            "ModPlug Tracker  IT\r\n",
+            "|C-501v64...|...........|E-502...H44\r\n",
+            "|...........|...........|...........\r\n",
+            "|===........|...........|.....v32D04\r\n",
+        )).unwrap();
+        let columns = PatternColumns::from(&pattern);
+        assert_eq!((columns.rows(), columns.channels()), (3, 3));
+        assert_eq!(columns.notes().iter().filter(|note| matches!(note, Some(NoteCmd::Play(_)))).count(), 2);
+        assert_eq!(columns.index(2, Channel::new(3)), Some(8));
+        assert_eq!(columns.command(2, Channel::new(1)), Some(Command { note: Some(NoteCmd::Off), ..Command::EMPTY }));
+        assert_eq!(columns.command(3, Channel::new(1)), None);
+        assert_eq!(Pattern::from(&columns), pattern);
+
+        let empty = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 64] };
+        let columns = PatternColumns::from(&empty);
+        assert_eq!((columns.rows(), columns.channels()), (64, 0));
+        assert_eq!(Pattern::from(&columns), empty);
+    }
+}
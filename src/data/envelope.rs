@@ -1,7 +1,10 @@
 use super::*;
+use core::fmt::Write;
+use core::ops::RangeInclusive;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Envelope {
     /// Envelope Flags
     pub flags: EnvelopeFlags,
@@ -36,15 +39,20 @@ bitflags! {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Node {
     pub value: i8,
     pub tick: u16,
 }
 
-// TODO Is the loop an inclusive "interval"? That is, is the node marked as `end` used in the loop?
-//      Our guess would be yes, but check with OpenMPT code or interface first.
-#[derive(Clone, Copy, Debug)]
+/// Envelope loop
+///
+/// The loop is an inclusive interval of nodes, the tick of the `end` node is played before the
+/// envelope position jumps back to the tick of the `start` node. This matches how Impulse Tracker
+/// and OpenMPT (in IT mode) play envelope loops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct EnvelopeLoop {
     /// Start - offset of the node
     pub start: u8,
@@ -54,3 +62,246 @@ pub struct EnvelopeLoop {
     /// Must be always `>= start`
     pub end: u8,
 }
+
+/// Envelope prepared for plotting, see [`Envelope::plot`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnvelopePlot {
+    /// Nodes as `(tick, value)` points
+    pub points: Vec<(u16, i8)>,
+
+    /// Envelope loop as a range of ticks, `None` if it's disabled or its nodes don't exist
+    pub envelope_loop: Option<(u16, u16)>,
+
+    /// Sustain loop as a range of ticks, `None` if it's disabled or its nodes don't exist
+    pub sustain_loop: Option<(u16, u16)>,
+
+    /// [`EnvelopeFlags::ENABLED`] is set
+    pub enabled: bool,
+}
+
+/// Width of the envelope plots in [`Envelope::to_svg`] and [`Instrument::envelopes_svg`]
+const PLOT_WIDTH: f64 = 400.0;
+
+/// Height of a single envelope plot
+const PLOT_HEIGHT: f64 = 100.0;
+
+
+impl Envelope {
+    /// Returns the nodes and the enabled loops as plottable point series.
+    pub fn plot(&self) -> EnvelopePlot {
+        let ticks = |envelope_loop: Option<EnvelopeLoop>, flag| {
+            let envelope_loop = envelope_loop.filter(|_| self.flags.contains(flag))?;
+            let start = self.nodes.as_slice().get(usize::from(envelope_loop.start))?.tick;
+            let end = self.nodes.as_slice().get(usize::from(envelope_loop.end))?.tick;
+            Some((start, end))
+        };
+        EnvelopePlot {
+            points: self.nodes.iter().map(|node| (node.tick, node.value)).collect(),
+            envelope_loop: ticks(self.envelope_loop, EnvelopeFlags::LOOP),
+            sustain_loop: ticks(self.sustain_loop, EnvelopeFlags::SUSTAIN),
+            enabled: self.flags.contains(EnvelopeFlags::ENABLED),
+        }
+    }
+
+    /// Renders the envelope as an SVG image
+    ///
+    /// `range` is the range of values the envelope can have, `0..=64` for volume envelopes and
+    /// `-32..=32` for panning and pitch envelopes. The nodes are connected with a line, the
+    /// envelope loop is marked with solid blue lines and the sustain loop with dashed red lines.
+    /// Disabled envelopes are drawn grey.
+    pub fn to_svg(&self, range: RangeInclusive<i8>) -> String {
+        let mut svg = svg_start(PLOT_HEIGHT);
+        self.write_svg_plot(&mut svg, range, 0.0, None);
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// Writes the plot as an SVG group moved down by `top`.
+    fn write_svg_plot(&self, svg: &mut String, range: RangeInclusive<i8>, top: f64, label: Option<&str>) {
+        let plot = self.plot();
+        let last_tick = plot.points.last().map_or(0, |&(tick, _)| tick).max(1);
+        let x = |tick: u16| f64::from(tick) * PLOT_WIDTH / f64::from(last_tick);
+        let (min, max) = (f64::from(*range.start()), f64::from(*range.end()));
+        let y = |value: i8| (max - f64::from(value).clamp(min, max)) * PLOT_HEIGHT / (max - min);
+
+        // Writing into a `String` never fails.
+        writeln!(svg, r#"<g transform="translate(0 {:.1})">"#, top).unwrap();
+        writeln!(svg, r#"<rect width="{:.1}" height="{:.1}" fill="none" stroke="lightgrey"/>"#, PLOT_WIDTH, PLOT_HEIGHT).unwrap();
+        if let Some(label) = label {
+            writeln!(svg, r#"<text x="4" y="14" font-size="12">{}</text>"#, label).unwrap();
+        }
+        for (envelope_loop, style) in [
+            (plot.envelope_loop, r#"stroke="blue""#),
+            (plot.sustain_loop, r#"stroke="red" stroke-dasharray="4 2""#),
+        ] {
+            let Some((start, end)) = envelope_loop else { continue };
+            for tick in [start, end] {
+                writeln!(svg, r#"<line x1="{0:.1}" y1="0" x2="{0:.1}" y2="{1:.1}" {2}/>"#, x(tick), PLOT_HEIGHT, style).unwrap();
+            }
+        }
+        let colour = if plot.enabled { "black" } else { "grey" };
+        let points = plot.points
+            .iter()
+            .map(|&(tick, value)| format!("{:.1},{:.1}", x(tick), y(value)))
+            .collect::<Vec<_>>();
+        writeln!(svg, r#"<polyline points="{}" fill="none" stroke="{}"/>"#, points.join(" "), colour).unwrap();
+        for &(tick, value) in &plot.points {
+            writeln!(svg, r#"<circle cx="{:.1}" cy="{:.1}" r="2" fill="{}"/>"#, x(tick), y(value), colour).unwrap();
+        }
+        svg.push_str("</g>\n");
+    }
+
+    /// Evaluates the envelope `tick` ticks after the note started playing.
+    ///
+    /// Values between nodes are linearly interpolated, before the first node the value of the
+    /// first node is used and after the last node the envelope holds the value of the last node.
+    ///
+    /// When the note is held (`sustain_released` is `false`) and the envelope has an enabled
+    /// sustain loop, the position loops in the sustain loop. Otherwise if the envelope has an
+    /// enabled loop the position loops in that.
+    ///
+    /// The release state is applied to the whole elapsed time, `value_at(t, true)` returns the
+    /// value of a note which has been released from the start. Players releasing a note in the
+    /// middle of a sustain loop have to keep track of the envelope position themselves because it
+    /// depends on when the release happened.
+    ///
+    /// This function doesn't look at [`EnvelopeFlags::ENABLED`], it's up to the caller to decide
+    /// what to do with disabled envelopes. Returns `None` if the envelope has no nodes.
+    pub fn value_at(&self, tick: u32, sustain_released: bool) -> Option<f32> {
+        let position = self.position_at(tick, sustain_released);
+        self.value_at_position(position)
+    }
+
+    /// Returns the active loop as a range of ticks.
+    pub(crate) fn active_loop(&self, sustain_released: bool) -> Option<(u16, u16)> {
+        let envelope_loop = if !sustain_released && self.flags.contains(EnvelopeFlags::SUSTAIN) {
+            self.sustain_loop
+        } else {
+            None
+        };
+        let envelope_loop = envelope_loop.or_else(|| {
+            if self.flags.contains(EnvelopeFlags::LOOP) {
+                self.envelope_loop
+            } else {
+                None
+            }
+        })?;
+        let start = self.nodes.as_slice().get(usize::from(envelope_loop.start))?.tick;
+        let end = self.nodes.as_slice().get(usize::from(envelope_loop.end))?.tick;
+        (start <= end).then_some((start, end))
+    }
+
+    /// Converts ticks since the note start into the envelope position (in ticks).
+    fn position_at(&self, tick: u32, sustain_released: bool) -> u32 {
+        match self.active_loop(sustain_released) {
+            Some((start, end)) if tick > u32::from(end) => {
+                let (start, end) = (u32::from(start), u32::from(end));
+                // The end node is part of the loop, see `EnvelopeLoop`.
+                start + (tick - start) % (end - start + 1)
+            }
+            _ => tick,
+        }
+    }
+
+    /// Interpolates the envelope value at a position.
+    pub(crate) fn value_at_position(&self, position: u32) -> Option<f32> {
+        let first = self.nodes.first()?;
+        if position <= u32::from(first.tick) {
+            return Some(f32::from(first.value));
+        }
+        for pair in self.nodes.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let (start, end) = (u32::from(a.tick), u32::from(b.tick));
+            if start <= position && position < end {
+                let (va, vb) = (f32::from(a.value), f32::from(b.value));
+                // Both values are at most `u16::MAX` and therefore fit into `f32` exactly.
+                #[allow(clippy::cast_precision_loss, clippy::as_conversions)]
+                let frac = (position - start) as f32 / (end - start) as f32;
+                return Some(va + (vb - va) * frac);
+            }
+        }
+        self.nodes.last().map(|last| f32::from(last.value))
+    }
+}
+
+impl Instrument {
+    /// Renders the volume, panning and pitch (or filter) envelopes below each other as an SVG
+    /// image, see [`Envelope::to_svg`].
+    pub fn envelopes_svg(&self) -> String {
+        let pitch = if self.pitch_filter_envelope.flags.contains(EnvelopeFlags::FILTER) { "Filter" } else { "Pitch" };
+        let plots = [
+            (&self.volume_envelope, 0..=64, "Volume"),
+            (&self.panning_envelope, -32..=32, "Panning"),
+            (&self.pitch_filter_envelope, -32..=32, pitch),
+        ];
+        let spacing = PLOT_HEIGHT + 10.0;
+        let mut svg = svg_start(spacing * 3.0 - 10.0);
+        for ((envelope, range, label), top) in plots.into_iter().zip([0.0, spacing, spacing * 2.0]) {
+            envelope.write_svg_plot(&mut svg, range, top, Some(label));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+/// Starts an SVG document with the plot width and the given height.
+fn svg_start(height: f64) -> String {
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0:.0}\" height=\"{1:.0}\" viewBox=\"0 0 {0:.0} {1:.0}\">\n",
+        PLOT_WIDTH, height,
+    )
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn value_at_loops() {
+        let envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::LOOP | EnvelopeFlags::SUSTAIN,
+            envelope_loop: Some(EnvelopeLoop { start: 0, end: 2 }),
+            sustain_loop: Some(EnvelopeLoop { start: 1, end: 1 }),
+            nodes: vec![
+                Node { value: 0, tick: 0 },
+                Node { value: 64, tick: 4 },
+                Node { value: 32, tick: 8 },
+            ],
+        };
+
+        // Interpolation between nodes.
+        assert_eq!(envelope.value_at(2, true), Some(32.0));
+        assert_eq!(envelope.value_at(6, true), Some(48.0));
+
+        // Held note stays on the one-node sustain loop.
+        assert_eq!(envelope.value_at(100, false), Some(64.0));
+
+        // Released note loops over ticks 0..=8.
+        assert_eq!(envelope.value_at(8, true), Some(32.0));
+        assert_eq!(envelope.value_at(9, true), Some(0.0));
+        assert_eq!(envelope.value_at(11, true), Some(32.0));
+    }
+
+    #[test]
+    fn plot() {
+        let envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::SUSTAIN,
+            envelope_loop: Some(EnvelopeLoop { start: 0, end: 1 }),
+            sustain_loop: Some(EnvelopeLoop { start: 1, end: 1 }),
+            nodes: vec![Node { value: 64, tick: 0 }, Node { value: 32, tick: 10 }, Node { value: 0, tick: 20 }],
+        };
+        assert_eq!(envelope.plot(), EnvelopePlot {
+            points: vec![(0, 64), (10, 32), (20, 0)],
+            envelope_loop: None,
+            sustain_loop: Some((10, 10)),
+            enabled: true,
+        });
+
+        let svg = envelope.to_svg(0..=64);
+        assert!(svg.starts_with("<svg "));
+        assert!(svg.contains(r#"<polyline points="0.0,0.0 200.0,50.0 400.0,100.0" fill="none" stroke="black"/>"#));
+        assert!(svg.contains(r#"<line x1="200.0" y1="0" x2="200.0" y2="100.0" stroke="red" stroke-dasharray="4 2"/>"#));
+        assert!(svg.ends_with("</svg>\n"));
+    }
+}
@@ -1,15 +1,18 @@
 use super::*;
-use std::convert::TryFrom;
-use std::fmt::{self, Debug};
-use std::ops::Index;
+use crate::error::{MissingSampleError, OutOfRangeError};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug};
+use core::ops::Index;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct InstrumentFile {
     pub instrument: Instrument,
     pub samples: Vec<Sample>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Instrument {
     /// Instrument Name, null-terminated (but may also contain nulls)
     pub name: Name,
@@ -20,13 +23,13 @@ pub struct Instrument {
     pub flags: InstrumentFlags,
 
     /// New Note Action
-    pub new_note_action: u8,
+    pub new_note_action: NewNoteAction,
 
     /// Duplicate Note Check Type
-    pub duplicate_check_type: u8,
+    pub duplicate_check_type: DuplicateCheckType,
 
     /// Duplicate Note Check Action
-    pub duplicate_check_action: u8,
+    pub duplicate_check_action: DuplicateCheckAction,
 
     /// Instrument Fadeout
     ///
@@ -112,9 +115,74 @@ bitflags! {
     }
 }
 
-#[derive(Clone, Copy)]
+/// New Note Action
+///
+/// Decides what happens with a note still playing on a channel when a new note is played on the
+/// same channel.
+///
+/// The action can be also changed for the currently playing note using the `S73..=S76` effects,
+/// see [`SetNewNoteAction`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum NewNoteAction {
+    /// `0` The old note is cut
+    Cut,
+
+    /// `1` The old note continues playing in the background
+    Continue,
+
+    /// `2` The old note is released (like with a note off command)
+    Off,
+
+    /// `3` The old note fades out
+    Fade,
+}
+
+/// Duplicate Check Type
+///
+/// Decides which background notes are considered to be duplicates of a new note. Duplicates are
+/// then handled according to the [`DuplicateCheckAction`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum DuplicateCheckType {
+    /// `0` Duplicate check is disabled
+    Off,
+
+    /// `1` Notes with the same pitch and instrument are duplicates
+    Note,
+
+    /// `2` Notes with the same sample and instrument are duplicates
+    Sample,
+
+    /// `3` Notes with the same instrument are duplicates
+    Instrument,
+}
+
+/// Duplicate Check Action
+///
+/// Decides what happens to the previous notes found by the [`DuplicateCheckType`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum DuplicateCheckAction {
+    /// `0` The duplicate note is cut
+    Cut,
+
+    /// `1` The duplicate note is released (like with a note off command)
+    Off,
+
+    /// `2` The duplicate note fades out
+    Fade,
+}
+
+/// Keyboard table (note → sample map)
+///
+/// For each of the 120 notes the instrument can be played at, the table stores which sample is
+/// played and at which note (pitch) it is played. The default table plays every note
+/// untransposed and has no samples assigned.
+#[derive(Clone, Copy, PartialEq)]
 pub struct SampleMap {
-    pub(crate) map: [Option<SampleId>; 120],
+    /// Indexed by the played note, holds the translated note and the sample.
+    pub(crate) map: [(Note, Option<SampleId>); 120],
 }
 
 
@@ -125,21 +193,176 @@ impl Instrument {
     pub(crate) const ifr_enableResonance: u8 = 0x80;
 }
 
+impl TryFrom<u8> for NewNoteAction {
+    type Error = OutOfRangeError<0, 3>;
+
+    fn try_from(raw: u8) -> Result<Self, Self::Error> {
+        match raw {
+            0 => Ok(NewNoteAction::Cut),
+            1 => Ok(NewNoteAction::Continue),
+            2 => Ok(NewNoteAction::Off),
+            3 => Ok(NewNoteAction::Fade),
+            _ => Err(OutOfRangeError(raw)),
+        }
+    }
+}
+
+impl From<NewNoteAction> for u8 {
+    fn from(nna: NewNoteAction) -> u8 {
+        match nna {
+            NewNoteAction::Cut => 0,
+            NewNoteAction::Continue => 1,
+            NewNoteAction::Off => 2,
+            NewNoteAction::Fade => 3,
+        }
+    }
+}
+
+impl From<SetNewNoteAction> for NewNoteAction {
+    fn from(nna: SetNewNoteAction) -> NewNoteAction {
+        match nna {
+            SetNewNoteAction::Cut => NewNoteAction::Cut,
+            SetNewNoteAction::Continue => NewNoteAction::Continue,
+            SetNewNoteAction::Off => NewNoteAction::Off,
+            SetNewNoteAction::Fade => NewNoteAction::Fade,
+        }
+    }
+}
+
+impl TryFrom<u8> for DuplicateCheckType {
+    type Error = OutOfRangeError<0, 3>;
+
+    fn try_from(raw: u8) -> Result<Self, Self::Error> {
+        match raw {
+            0 => Ok(DuplicateCheckType::Off),
+            1 => Ok(DuplicateCheckType::Note),
+            2 => Ok(DuplicateCheckType::Sample),
+            3 => Ok(DuplicateCheckType::Instrument),
+            _ => Err(OutOfRangeError(raw)),
+        }
+    }
+}
+
+impl From<DuplicateCheckType> for u8 {
+    fn from(dct: DuplicateCheckType) -> u8 {
+        match dct {
+            DuplicateCheckType::Off => 0,
+            DuplicateCheckType::Note => 1,
+            DuplicateCheckType::Sample => 2,
+            DuplicateCheckType::Instrument => 3,
+        }
+    }
+}
+
+impl TryFrom<u8> for DuplicateCheckAction {
+    type Error = OutOfRangeError<0, 2>;
+
+    fn try_from(raw: u8) -> Result<Self, Self::Error> {
+        match raw {
+            0 => Ok(DuplicateCheckAction::Cut),
+            1 => Ok(DuplicateCheckAction::Off),
+            2 => Ok(DuplicateCheckAction::Fade),
+            _ => Err(OutOfRangeError(raw)),
+        }
+    }
+}
+
+impl From<DuplicateCheckAction> for u8 {
+    fn from(dca: DuplicateCheckAction) -> u8 {
+        match dca {
+            DuplicateCheckAction::Cut => 0,
+            DuplicateCheckAction::Off => 1,
+            DuplicateCheckAction::Fade => 2,
+        }
+    }
+}
+
+impl SampleMap {
+    /// Returns the sample played for `note`, `None` if no sample is mapped.
+    pub fn sample_for(&self, note: Note) -> Option<SampleId> {
+        self.map[usize::from(u8::from(note))].1
+    }
+
+    /// Returns the note (pitch) the sample is played at when `note` is played.
+    pub fn note_translation_for(&self, note: Note) -> Note {
+        self.map[usize::from(u8::from(note))].0
+    }
+
+    /// Maps `note` to play `sample` at the pitch `translation`.
+    pub fn set(&mut self, note: Note, translation: Note, sample: Option<SampleId>) {
+        self.map[usize::from(u8::from(note))] = (translation, sample);
+    }
+
+    /// Changes the sample played for `note`, keeping the note translation.
+    pub fn set_sample(&mut self, note: Note, sample: Option<SampleId>) {
+        self.map[usize::from(u8::from(note))].1 = sample;
+    }
+
+    /// Changes the pitch the sample is played at for `note`, keeping the sample.
+    pub fn set_note_translation(&mut self, note: Note, translation: Note) {
+        self.map[usize::from(u8::from(note))].0 = translation;
+    }
+
+    /// Iterates over all 120 entries as `(note, translated note, sample)`.
+    pub fn iter(&self) -> impl Iterator<Item = (Note, Note, Option<SampleId>)> + '_ {
+        self.map
+            .iter()
+            .zip(0..)
+            .map(|(&(translation, sample), note)| (Note::try_from(note).unwrap(), translation, sample))
+    }
+
+    /// Maps all notes to `sample`.
+    pub fn fill_sample(&mut self, sample: Option<SampleId>) {
+        for entry in &mut self.map {
+            entry.1 = sample;
+        }
+    }
+
+    /// Resets the note translation so every note plays at its own pitch.
+    pub fn reset_note_translation(&mut self) {
+        for (note, entry) in (0..).zip(self.map.iter_mut()) {
+            entry.0 = Note::try_from(note).unwrap();
+        }
+    }
+
+    /// Replaces every mapped sample with the result of `f`, e.g. after samples were reordered or
+    /// removed from a module.
+    pub fn remap_samples(&mut self, mut f: impl FnMut(SampleId) -> Option<SampleId>) {
+        for entry in &mut self.map {
+            entry.1 = entry.1.and_then(&mut f);
+        }
+    }
+
+    /// Checks that all mapped samples exist in a module with `sample_count` samples.
+    ///
+    /// Returns the first note which maps to a missing sample.
+    pub fn validate(&self, sample_count: usize) -> Result<(), MissingSampleError> {
+        match self.iter().find(|(_, _, sample)| sample.is_some_and(|sample| sample.as_usize() >= sample_count)) {
+            Some((note, _, Some(sample))) => Err(MissingSampleError { note, sample }),
+            _ => Ok(()),
+        }
+    }
+}
+
 impl Default for SampleMap {
     fn default() -> SampleMap {
-        SampleMap {
-            map: [None; 120],
-        }
+        let mut map = SampleMap {
+            map: [(Note::C_0, None); 120],
+        };
+        map.reset_note_translation();
+        map
     }
 }
 
+/// Lists the entries which have a sample or a note translation, as
+/// `note: (translated note, sample)`.
 impl Debug for SampleMap {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_map()
             .entries(
-                (0..120)
-                    .zip(self.map.iter())
-                    .filter_map(|(k, v)| v.map(|v| (Note::try_from(k).unwrap(), v)))
+                self.iter()
+                    .filter(|&(note, translation, sample)| sample.is_some() || note != translation)
+                    .map(|(note, translation, sample)| (note, (translation, sample)))
             )
             .finish()
     }
@@ -148,7 +371,7 @@ impl Debug for SampleMap {
 impl Index<Note> for SampleMap {
     type Output = Option<SampleId>;
     fn index(&self, index: Note) -> &Self::Output {
-        &self.map[usize::from(u8::from(index))]
+        &self.map[usize::from(u8::from(index))].1
     }
 }
 
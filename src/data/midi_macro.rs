@@ -0,0 +1,210 @@
+use super::util::debug_bytestring;
+use super::*;
+use crate::parser::SPECIAL_EDIT_HISTORY;
+use core::array;
+use core::convert::TryFrom;
+use core::fmt;
+
+
+/// Size of a macro in the embedded configuration
+const MACRO_SIZE: usize = 32;
+
+/// Size of the MIDI configuration embedded in a module: 9 global macros, 16 parametered macros
+/// and 128 fixed macros
+pub const MIDI_CONFIG_SIZE: usize = (9 + 16 + 128) * MACRO_SIZE;
+
+
+/// MIDI macros of a module, used by `SFx` and `Zxx`
+///
+/// Modules without an embedded configuration use the defaults of Impulse Tracker, returned by
+/// [`MidiConfig::default`]: `SF0` selects the filter cutoff macro and `Z80`..`Z8F` set the filter
+/// resonance. The configuration is read from the header by [`Preserved::midi_config`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MidiConfig {
+    /// Macros sent by the tracker: start, stop, tick, note on, note off, volume, panning, bank
+    /// change and program change
+    pub global: [MidiMacro; 9],
+
+    /// Macros selected by `SF0`..`SFF`, sent by `Z00`..`Z7F` with the parameter as `z`
+    pub parametered: [MidiMacro; 16],
+
+    /// Macros sent by `Z80`..`ZFF`
+    pub fixed: [MidiMacro; 128],
+}
+
+/// MIDI macro, bytes written as hexadecimal digits and parameter letters
+///
+/// Stored as 32 bytes, null-terminated.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MidiMacro {
+    pub bytes: [u8; MACRO_SIZE],
+}
+
+/// MIDI message produced by a [`MidiMacro`]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct MidiMessage {
+    data: [u8; MACRO_SIZE / 2],
+    len: u8,
+}
+
+
+impl MidiConfig {
+    /// Parses the embedded configuration, `None` if `data` is shorter than
+    /// [`MIDI_CONFIG_SIZE`].
+    pub fn from_bytes(data: &[u8]) -> Option<MidiConfig> {
+        let data = data.get(..MIDI_CONFIG_SIZE)?;
+        let macro_at = |idx: usize| MidiMacro {
+            bytes: data[idx * MACRO_SIZE..(idx + 1) * MACRO_SIZE].try_into().unwrap(),
+        };
+        Some(MidiConfig {
+            global: array::from_fn(macro_at),
+            parametered: array::from_fn(|idx| macro_at(9 + idx)),
+            fixed: array::from_fn(|idx| macro_at(9 + 16 + idx)),
+        })
+    }
+}
+
+impl Default for MidiConfig {
+    fn default() -> MidiConfig {
+        let mut parametered = [MidiMacro::EMPTY; 16];
+        parametered[0] = MidiMacro::new("F0F000z");
+        MidiConfig {
+            global: [
+                MidiMacro::new("FF"),
+                MidiMacro::new("FC"),
+                MidiMacro::EMPTY,
+                MidiMacro::new("9c n v"),
+                MidiMacro::new("9c n 0"),
+                MidiMacro::EMPTY,
+                MidiMacro::EMPTY,
+                MidiMacro::EMPTY,
+                MidiMacro::new("Cc p"),
+            ],
+            parametered,
+            fixed: array::from_fn(|idx| match u8::try_from(idx).unwrap() {
+                idx @ 0..=0x0F => MidiMacro::new(&format!("F0F001{:02X}", idx * 8)),
+                _ => MidiMacro::EMPTY,
+            }),
+        }
+    }
+}
+
+impl MidiMacro {
+    pub const EMPTY: MidiMacro = MidiMacro { bytes: [0; MACRO_SIZE] };
+
+    /// # Panics
+    ///
+    /// Panics if `text` is longer than 32 bytes.
+    pub fn new(text: &str) -> MidiMacro {
+        let mut bytes = [0; MACRO_SIZE];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        MidiMacro { bytes }
+    }
+
+    /// Returns the macro up to the null terminator.
+    pub fn text(&self) -> &[u8] {
+        let len = self.bytes.iter().position(|&byte| byte == 0).unwrap_or(MACRO_SIZE);
+        &self.bytes[..len]
+    }
+
+    /// Evaluates the macro sent by a command with the parameter `param` in the pattern channel
+    /// with the index `channel`
+    ///
+    /// Hexadecimal digits are nibbles, `c` is the MIDI channel nibble (the pattern channel
+    /// modulo 16) and `z` the parameter byte, spaces are ignored. The other parameter letters
+    /// (note, velocity, volume...) are evaluated to zero.
+    pub fn evaluate(&self, param: u8, channel: usize) -> MidiMessage {
+        let mut message = MidiMessage { data: [0; MACRO_SIZE / 2], len: 0 };
+        let mut nibble = None;
+        let push = |message: &mut MidiMessage, byte: u8| {
+            if usize::from(message.len) < message.data.len() {
+                message.data[usize::from(message.len)] = byte;
+                message.len += 1;
+            }
+        };
+        for &letter in self.text() {
+            let value = match letter {
+                b'0'..=b'9' => letter - b'0',
+                b'A'..=b'F' => letter - b'A' + 10,
+                b'c' => u8::try_from(channel % 16).unwrap(),
+                b' ' => continue,
+                b'z' => {
+                    push(&mut message, param);
+                    nibble = None;
+                    continue;
+                }
+                _ => {
+                    push(&mut message, 0);
+                    nibble = None;
+                    continue;
+                }
+            };
+            match nibble.take() {
+                Some(high) => push(&mut message, (high << 4) | value),
+                None => nibble = Some(value),
+            }
+        }
+        if let Some(high) = nibble {
+            push(&mut message, high);
+        }
+        message
+    }
+}
+
+impl MidiMessage {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..usize::from(self.len)]
+    }
+}
+
+impl fmt::Debug for MidiMacro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        debug_bytestring(&self.bytes, f)
+    }
+}
+
+impl fmt::Debug for MidiMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X?}", self.as_bytes())
+    }
+}
+
+impl Preserved {
+    /// Returns the MIDI configuration embedded in the header, `None` if the module uses the
+    /// default one.
+    ///
+    /// The configuration follows the edit history, if there is one.
+    pub fn midi_config(&self) -> Option<MidiConfig> {
+        if !self.module.flags.contains(ModuleFlags::MIDI_CONIFG_EMBEDDED) {
+            return None;
+        }
+        let mut data = self.header_extra.as_slice();
+        if self.unknown_flags.1 & SPECIAL_EDIT_HISTORY != 0 {
+            let entries = usize::from(u16::from_le_bytes([*data.first()?, *data.get(1)?]));
+            data = data.get(2 + entries * 8..)?;
+        }
+        MidiConfig::from_bytes(data)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evaluate() {
+        let config = MidiConfig::default();
+        assert_eq!(config.parametered[0].evaluate(0x40, 0).as_bytes(), [0xF0, 0xF0, 0x00, 0x40]);
+        assert_eq!(config.fixed[0x03].evaluate(0, 0).as_bytes(), [0xF0, 0xF0, 0x01, 0x18]);
+        assert_eq!(config.global[3].evaluate(0, 17).as_bytes(), [0x91, 0x00, 0x00]);
+        assert_eq!(MidiMacro::EMPTY.evaluate(0, 0).as_bytes(), []);
+
+        let mut data = vec![0; MIDI_CONFIG_SIZE];
+        data[(9 + 16 + 5) * 32..][..6].copy_from_slice(b"B0 07z");
+        let config = MidiConfig::from_bytes(&data).unwrap();
+        assert_eq!(config.fixed[5].evaluate(0x85, 2).as_bytes(), [0xB0, 0x07, 0x85]);
+        assert!(MidiConfig::from_bytes(&data[1..]).is_none());
+    }
+}
@@ -1,12 +1,19 @@
 use super::*;
+use crate::cp437;
+use crate::error::OutOfRangeError;
+use core::convert::TryFrom;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Module {
     /// Song Name, null-terminated (but may also contain nulls)
     pub name: Name,
 
     /// Comment message
+    ///
+    /// Lines are separated by `\r`, use [`Module::set_message`] to change the message while
+    /// keeping it loadable by Impulse Tracker.
     pub message: String,
 
     /// Rows per Measure highlight, Rows per Beat highlight
@@ -24,7 +31,7 @@ pub struct Module {
     /// Global Volume (0...128)
     pub global_volume: RangedU8<0, 128>,
 
-    /// Sample Volume (0...128)
+    /// Sample (Mix) Volume (0...128)
     pub sample_volume: RangedU8<0, 128>,
 
     /// Initial Speed (1...255)
@@ -39,11 +46,11 @@ pub struct Module {
     /// Pitch Wheel Depth
     pub pitch_wheel_depth: u8,
 
-    /// Initial Channel Panning
-    pub init_channel_panning: [u8; 64],
-
-    /// Initial Channel Volume
-    pub init_channel_volume: [u8; 64],
+    /// Initial Channel Panning and Volume
+    ///
+    /// Can be also indexed with a [`Channel`] on the `Module` itself.
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serde::channels"))]
+    pub channels: [ChannelSettings; 64],
 
     /// Orders
     pub orders: Vec<Order>,
@@ -72,15 +79,21 @@ pub(crate) struct ModuleHeader {
     pub(crate) pitch_wheel_depth: u8,
     pub(crate) message_length: u16,
     pub(crate) message_offset: u32,
-    pub(crate) init_channel_panning: [u8; 64],
-    pub(crate) init_channel_volume: [u8; 64],
+    pub(crate) channels: [ChannelSettings; 64],
     pub(crate) orders: Vec<Order>,
     pub(crate) instrument_offsets: Vec<u32>,
     pub(crate) sample_offsets: Vec<u32>,
     pub(crate) pattern_offsets: Vec<u32>,
+
+    /// Size of the header including the orders and the offset tables
+    pub(crate) header_size: usize,
+
+    /// Bits of the `flags` and `special` fields which are not known to [`ModuleFlags`]
+    pub(crate) unknown_flags: (u16, u16),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Order {
     Index(PatternId),
     Separator,
@@ -152,12 +165,17 @@ impl ModuleFlags {
         let bits = u32::from(flags) | (u32::from(special) << 16);
         ModuleFlags::from_bits_truncate(bits)
     }
+
+    pub(crate) fn to_parts(self) -> (u16, u16) {
+        let bits = self.bits();
+        (u16::try_from(bits & 0xFFFF).unwrap(), u16::try_from(bits >> 16).unwrap())
+    }
 }
 
 impl Get<SampleId> for Module {
     type Output = Sample;
     fn get(&self, index: SampleId) -> Option<&Self::Output> {
-        self.samples.as_slice().get(usize::from(index.as_u8()))
+        self.samples.as_slice().get(index.as_usize())
     }
 }
 
@@ -166,7 +184,7 @@ impl_index_from_get!(Module, SampleId);
 impl Get<InstrumentId> for Module {
     type Output = Instrument;
     fn get(&self, index: InstrumentId) -> Option<&Self::Output> {
-        self.instruments.as_slice().get(usize::from(index.as_u8()))
+        self.instruments.as_slice().get(index.as_usize())
     }
 }
 
@@ -175,12 +193,175 @@ impl_index_from_get!(Module, InstrumentId);
 impl Get<PatternId> for Module {
     type Output = Pattern;
     fn get(&self, index: PatternId) -> Option<&Self::Output> {
-        self.patterns.as_slice().get(usize::from(index.as_u8()))
+        self.patterns.as_slice().get(index.as_usize())
     }
 }
 
 impl_index_from_get!(Module, PatternId);
 
+impl Get<OrderIdx> for Module {
+    type Output = Order;
+    fn get(&self, index: OrderIdx) -> Option<&Self::Output> {
+        self.orders.as_slice().get(index.as_usize())
+    }
+}
+
+impl_index_from_get!(Module, OrderIdx);
+
+impl Get<Channel> for Module {
+    type Output = ChannelSettings;
+    fn get(&self, index: Channel) -> Option<&Self::Output> {
+        self.channels.as_slice().get(index.as_usize())
+    }
+}
+
+impl_index_from_get!(Module, Channel);
+
+/// Result of [`Module::set_message`]
+///
+/// Describes all the changes which were necessary to fit the message into the IT constraints.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageReport {
+    /// Characters which can't be represented in CP437 and were replaced with `?`
+    pub unmappable: Vec<char>,
+
+    /// Number of lines which were longer than [`Module::MESSAGE_LINE_LENGTH`] and were wrapped
+    pub wrapped_lines: usize,
+
+    /// Message didn't fit into [`Module::MESSAGE_MAX_LENGTH`] bytes and was cut short
+    pub truncated: bool,
+}
+
+/// Global song parameters
+///
+/// Typed accessors for the global parameters stored in the module header. The setters check the
+/// range of the value and leave the module unchanged when it's out of range.
+impl Module {
+    /// Initial Speed (ticks per row)
+    pub fn speed(&self) -> u8 {
+        self.speed.as_u8()
+    }
+
+    /// Sets the initial speed, accepted range is 1..=255.
+    pub fn set_speed(&mut self, speed: u8) -> Result<(), OutOfRangeError<1, 255>> {
+        self.speed = RangedU8::try_from(speed)?;
+        Ok(())
+    }
+
+    /// Initial Tempo (beats per minute)
+    pub fn tempo(&self) -> u8 {
+        self.tempo.as_u8()
+    }
+
+    /// Sets the initial tempo, accepted range is 31..=255.
+    pub fn set_tempo(&mut self, tempo: u8) -> Result<(), OutOfRangeError<31, 255>> {
+        self.tempo = RangedU8::try_from(tempo)?;
+        Ok(())
+    }
+
+    /// Global Volume
+    pub fn global_volume(&self) -> u8 {
+        self.global_volume.as_u8()
+    }
+
+    /// Sets the global volume, accepted range is 0..=128.
+    pub fn set_global_volume(&mut self, global_volume: u8) -> Result<(), OutOfRangeError<0, 128>> {
+        self.global_volume = RangedU8::try_from(global_volume)?;
+        Ok(())
+    }
+
+    /// Mix Volume
+    ///
+    /// Stored in the [`Module::sample_volume`] field.
+    pub fn mix_volume(&self) -> u8 {
+        self.sample_volume.as_u8()
+    }
+
+    /// Sets the mix volume, accepted range is 0..=128.
+    pub fn set_mix_volume(&mut self, mix_volume: u8) -> Result<(), OutOfRangeError<0, 128>> {
+        self.sample_volume = RangedU8::try_from(mix_volume)?;
+        Ok(())
+    }
+
+    /// Stereo (Pan) Separation, 128 is the maximum separation
+    ///
+    /// Stored in the [`Module::pan_separation`] field.
+    pub fn stereo_separation(&self) -> u8 {
+        self.pan_separation.as_u8()
+    }
+
+    /// Sets the stereo separation, accepted range is 0..=128.
+    pub fn set_stereo_separation(&mut self, separation: u8) -> Result<(), OutOfRangeError<0, 128>> {
+        self.pan_separation = RangedU8::try_from(separation)?;
+        Ok(())
+    }
+}
+
+/// Song message
+impl Module {
+    /// Maximum line length of the message editor in Impulse Tracker
+    pub const MESSAGE_LINE_LENGTH: usize = 74;
+
+    /// Maximum length of the song message in bytes, without the null terminator
+    pub const MESSAGE_MAX_LENGTH: usize = 7999;
+
+    /// Sets the song message, adapting it to the IT constraints
+    ///
+    /// - Line endings (`\n`, `\r\n` or `\r`) are converted to `\r`.
+    /// - Lines longer than [`Module::MESSAGE_LINE_LENGTH`] are wrapped, on the last space if there
+    ///   is one.
+    /// - Characters which can't be encoded in CP437 are replaced with `?`.
+    /// - The message is truncated to [`Module::MESSAGE_MAX_LENGTH`] bytes.
+    ///
+    /// The [`ModuleFlags::MESSAGE_ATTACHED`] flag is set if the resulting message is non-empty and
+    /// cleared otherwise. The message length and offset stored in the file are computed by the
+    /// writer.
+    pub fn set_message(&mut self, message: &str) -> MessageReport {
+        let mut report = MessageReport::default();
+
+        let mut lines = Vec::new();
+        for line in message.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+            let mut line = line
+                .chars()
+                .map(|ch| match cp437::encode_char(ch) {
+                    Some(_) => ch,
+                    None => {
+                        report.unmappable.push(ch);
+                        char::from(cp437::REPLACEMENT)
+                    }
+                })
+                .collect::<Vec<char>>();
+
+            if line.len() > Module::MESSAGE_LINE_LENGTH {
+                report.wrapped_lines += 1;
+            }
+            while line.len() > Module::MESSAGE_LINE_LENGTH {
+                // Break after the last space which fits on the line, or hard break the word.
+                let split = line[..=Module::MESSAGE_LINE_LENGTH]
+                    .iter()
+                    .rposition(|&ch| ch == ' ')
+                    .filter(|&split| split > 0)
+                    .unwrap_or(Module::MESSAGE_LINE_LENGTH);
+                let rest = line.split_off(split);
+                lines.push(line.into_iter().collect::<String>().trim_end().to_owned());
+                line = rest.into_iter().skip_while(|&ch| ch == ' ').collect();
+            }
+            lines.push(line.into_iter().collect());
+        }
+
+        // Every character is a single byte in CP437.
+        let mut message = lines.join("\r");
+        if let Some((idx, _)) = message.char_indices().nth(Module::MESSAGE_MAX_LENGTH) {
+            message.truncate(idx);
+            report.truncated = true;
+        }
+
+        self.flags.set(ModuleFlags::MESSAGE_ATTACHED, !message.is_empty());
+        self.message = message;
+        report
+    }
+}
+
 impl Module {
     /// Returns an iterator over patterns as listed in the orders list.
     ///
@@ -198,7 +379,7 @@ impl Module {
     ///
     /// Does not account for channels in patterns which are not present in the orders list.
     pub fn active_channels(&self) -> ActiveChannels {
-        use std::ops::BitOr;
+        use core::ops::BitOr;
 
         self.ordered_patterns()
             .map(|pat| pat.active_channels)
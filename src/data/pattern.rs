@@ -1,8 +1,10 @@
 use super::*;
-use crate::error::OutOfRangeError;
-use std::convert::TryFrom;
-use std::fmt::{self, Debug, Display};
-use std::str::{self, FromStr};
+use crate::error::{OutOfRangeError, PatternTextError};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug, Display};
+#[cfg(feature = "std")]
+use core::fmt::Write as _;
+use core::str::{self, FromStr};
 
 
 /// Pattern
@@ -16,7 +18,8 @@ use std::str::{self, FromStr};
 ///
 /// **This API will change in the future because it doesn't impose the invariant that
 /// `active_channels` and `rows` stay in sync.**
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Pattern {
     /// Active channels
     ///
@@ -31,7 +34,7 @@ pub struct Pattern {
 /// Pattern row
 ///
 /// Row is represented by a sparse vector. It can be iterated or indexed by a [`Channel`].
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct Row {
     map: Vec<(Channel, Command)>,
 }
@@ -39,16 +42,22 @@ pub struct Row {
 /// Pattern command
 ///
 /// Command is one cell on the pattern table.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Command {
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
     pub note: Option<NoteCmd>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
     pub instrument: Option<InstrumentId>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
     pub volume: Option<VolumeCmd>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
     pub effect: Option<EffectCmd>,
 }
 
 /// Note column commands
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum NoteCmd {
     Play(Note),
     Off,
@@ -59,13 +68,14 @@ pub enum NoteCmd {
 /// Note pitch representation
 ///
 /// Ranges from C-0 to B-9, only exact pitches can be represented.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Note(RangedU8<0, 119>);
 
 /// Volume column commands
 ///
 /// All parameters are displayed in **decimal**.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum VolumeCmd {
     /// `vxx` Set volume
     ///
@@ -179,6 +189,7 @@ pub enum VolumeCmd {
 /// - <https://modarchive.org/forums/index.php?topic=2222.0>
 // Documentation for these is adapted from the Schism Tracker help text and OpenMPT wiki.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum EffectCmd {
     /// `Axx` Set Speed
     ///
@@ -196,7 +207,7 @@ pub enum EffectCmd {
     ///
     /// If `Cxx` is on the same row, the pattern specified by `Bxx` will be
     /// the pattern `Cxx` jumps into.
-    JumpOrder(u8),
+    JumpOrder(OrderIdx),
 
     /// `Cxx` Break to row `xx` of next pattern
     ///
@@ -490,6 +501,7 @@ pub enum EffectCategory {
 /// for compatibilty with these trackers.
 // TODO describe volume slide units
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum VolumeSlide {
     /// `D0x`, `K0x`, `L0x`, `N0x`, `W0x` Volume slide down by `x`
     ///
@@ -520,6 +532,7 @@ pub enum VolumeSlide {
 /// Slide can be either smooth or semitone-wise, see [`Special::SetGlissando`] for details.
 // TODO describe frequency units
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Portamento {
     /// `Exx`, `Fxx` Pitch slide down/up by `xx`
     ///
@@ -561,6 +574,7 @@ pub enum Portamento {
 ///
 /// We leave this encoded as a full range `u8` and don't treat the zero specially.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum SetSampleOffset {
     Low(u8),
     High(RangedU8<0, 0x0F>),
@@ -578,6 +592,7 @@ pub enum SetSampleOffset {
 /// Value `0xFF` gets parsed like `FineLeft(0xE)`, see [`VolumeSlide`] for details, it uses the
 /// same underlying encoding.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum PanningSlide {
     /// `P0x` Panning slide to right by `x`
     ///
@@ -606,6 +621,7 @@ pub enum PanningSlide {
 ///
 /// All the `Sxx` commands share the same memory, this should include the `SAy` command.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Special {
     // Schism Tracker and OpenMPT (IT Effects) documentation disagree on this one. OpenMPT says
     // `S00` recalls `Sxx` command memory but Schism Tracker says `S0x` sets filter, it is marked
@@ -737,6 +753,7 @@ pub enum Special {
 /// ## Canonicalization
 /// The valid values for waveforms are `0..=3`, all out-of-range values are parsed as `3`.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Waveform {
     /// Sine wave `0`
     Sine,
@@ -752,6 +769,7 @@ pub enum Waveform {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum SetPastNote {
     Cut,
     Off,
@@ -759,6 +777,7 @@ pub enum SetPastNote {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum SetNewNoteAction {
     Cut,
     Off,
@@ -767,6 +786,7 @@ pub enum SetNewNoteAction {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum SurroundMode {
     /// `S9A` Sets the surround mode to Center Surround for all channels.
     ///
@@ -782,6 +802,7 @@ pub enum SurroundMode {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum FilterMode {
     /// `S9C` Sets filter mode to Global on all channels (Impulse Tracker behaviour).
     ///
@@ -798,6 +819,7 @@ pub enum FilterMode {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum PlayDirection {
     /// `S9E` Forces the current sample to play forward.
     Forward,
@@ -816,6 +838,7 @@ pub enum PlayDirection {
 /// `0x00` is used for memory and parses as `None`, but `0x10` would increase tempo by 0 which is
 /// useless so the parser just skips the effect.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Tempo {
     /// `T0x` Tempo slide down by `x`
     SlideDown(RangedU8<1, 0x0F>),
@@ -828,6 +851,207 @@ pub enum Tempo {
 }
 
 
+/// Header of the ModPlug/OpenMPT pattern clipboard format for IT modules
+const CLIPBOARD_HEADER: &str = "ModPlug Tracker  IT";
+
+impl Pattern {
+    /// Formats the pattern in the ModPlug/OpenMPT pattern clipboard format
+    ///
+    /// The output contains all channels up to the last one used in the pattern and can be pasted
+    /// directly into OpenMPT.
+    #[cfg(feature = "std")]
+    pub fn to_text(&self) -> String {
+        let channels = self.rows
+            .iter()
+            .filter_map(|row| row.map.last())
+            .map(|(chan, _)| chan.as_usize() + 1)
+            .max()
+            .unwrap_or(1);
+
+        let mut text = String::from(CLIPBOARD_HEADER);
+        text.push_str("\r\n");
+        for row in &self.rows {
+            let mut commands = row.map.iter().peekable();
+            for idx in 0..channels {
+                text.push('|');
+                let command = match commands.next_if(|(chan, _)| chan.as_usize() == idx) {
+                    Some((_, command)) => command,
+                    None => &Command::EMPTY,
+                };
+                write_clipboard_cell(&mut text, command);
+            }
+            text.push_str("\r\n");
+        }
+        text
+    }
+
+    /// Parses a pattern from the ModPlug/OpenMPT pattern clipboard format
+    ///
+    /// Both `\n` and `\r\n` line endings are accepted, empty columns can be marked with dots or
+    /// spaces and partially copied cells are padded with empty columns. Effects which the parser
+    /// would ignore when reading an IT file (see [`parser::effect`](crate::parser::effect)) are
+    /// ignored here too.
+    pub fn from_text(text: &str) -> Result<Pattern, PatternTextError> {
+        let error = |line, reason| PatternTextError { line, reason };
+
+        let mut lines = text.lines().enumerate().map(|(idx, line)| (idx + 1, line));
+        match lines.next() {
+            Some((_, header)) if header.trim_end() == CLIPBOARD_HEADER => {},
+            _ => return Err(error(1, "missing \"ModPlug Tracker  IT\" header")),
+        }
+
+        let mut rows = Vec::new();
+        for (line_number, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let cells = line
+                .strip_prefix('|')
+                .ok_or_else(|| error(line_number, "row must start with '|'"))?;
+
+            let mut commands = Vec::new();
+            for (idx, cell) in cells.split('|').enumerate() {
+                let channel = u8::try_from(idx)
+                    .ok()
+                    .and_then(|idx| Channel::from_index(idx).ok())
+                    .ok_or_else(|| error(line_number, "too many channels, at most 64 are supported"))?;
+                let command = parse_clipboard_cell(cell)
+                    .map_err(|reason| error(line_number, reason))?;
+                if command.note.is_some()
+                    || command.instrument.is_some()
+                    || command.volume.is_some()
+                    || command.effect.is_some()
+                {
+                    commands.push((channel, command));
+                }
+            }
+            rows.push(Row::from_vec(commands));
+        }
+
+        if rows.len() > 200 {
+            return Err(error(rows.len() + 1, "too many rows, at most 200 are supported"));
+        }
+
+        let active_channels = ActiveChannels::new(
+            rows.iter().flat_map(|row| row.iter().map(|(chan, _)| chan))
+        );
+
+        Ok(Pattern {
+            active_channels,
+            rows,
+        })
+    }
+}
+
+/// Appends one command in the clipboard format, e.g. `C-501v64D04`
+#[cfg(feature = "std")]
+fn write_clipboard_cell(text: &mut String, command: &Command) {
+    // Writing into a `String` never fails.
+    let _ = match &command.note {
+        Some(note) => write!(text, "{note}"),
+        None => text.write_str("..."),
+    };
+    let _ = match &command.instrument {
+        Some(instrument) => write!(text, "{instrument}"),
+        None => text.write_str(".."),
+    };
+    let _ = match &command.volume {
+        Some(volume) => write!(text, "{volume}"),
+        None => text.write_str("..."),
+    };
+    let _ = match &command.effect {
+        Some(effect) => write!(text, "{effect}"),
+        None => text.write_str("..."),
+    };
+}
+
+/// Parses one command in the clipboard format, missing trailing columns are treated as empty
+fn parse_clipboard_cell(cell: &str) -> Result<Command, &'static str> {
+    fn is_empty(column: &[u8]) -> bool {
+        column.iter().all(|&ch| ch == b'.' || ch == b' ')
+    }
+
+    fn decimal(column: &[u8]) -> Option<u8> {
+        str::from_utf8(column).ok()?.parse().ok()
+    }
+
+    let cell = cell.as_bytes();
+    if cell.len() > 11 {
+        return Err("cell is longer than 11 characters");
+    }
+    let column = |start: usize, end: usize| cell.get(start..end.min(cell.len())).unwrap_or(&[]);
+    let (note, instrument, volume, effect) = (column(0, 3), column(3, 5), column(5, 8), column(8, 11));
+
+    let note = match note {
+        _ if is_empty(note) => None,
+        b"===" => Some(NoteCmd::Off),
+        b"^^^" => Some(NoteCmd::Cut),
+        b"~~~" => Some(NoteCmd::Fade),
+        _ => {
+            let note = str::from_utf8(note)
+                .ok()
+                .and_then(|note| note.parse().ok())
+                .ok_or("invalid note")?;
+            Some(NoteCmd::Play(note))
+        }
+    };
+
+    let instrument = if is_empty(instrument) {
+        None
+    } else {
+        let instrument = decimal(instrument)
+            .and_then(|number| InstrumentId::from_number(number).ok())
+            .ok_or("invalid instrument number, expected 01..=99")?;
+        Some(instrument)
+    };
+
+    let volume = match volume {
+        _ if is_empty(volume) => None,
+        [letter, param @ ..] => {
+            let param = decimal(param).ok_or("invalid volume parameter")?;
+            let (base, limit) = match letter {
+                b'v' => (0, 64),
+                b'p' => (128, 64),
+                b'a' => (65, 9),
+                b'b' => (75, 9),
+                b'c' => (85, 9),
+                b'd' => (95, 9),
+                b'e' => (105, 9),
+                b'f' => (115, 9),
+                b'g' => (193, 9),
+                b'h' => (203, 9),
+                _ => return Err("invalid volume command"),
+            };
+            if param > limit {
+                return Err("volume parameter out of range");
+            }
+            crate::parser::volume(base + param)
+        }
+        [] => None,
+    };
+
+    let effect = match effect {
+        _ if is_empty(effect) => None,
+        [letter @ b'A'..=b'Z', param @ ..] => {
+            let param = str::from_utf8(param)
+                .ok()
+                .filter(|param| param.len() == 2)
+                .and_then(|param| u8::from_str_radix(param, 16).ok())
+                .ok_or("invalid effect parameter")?;
+            crate::parser::effect(letter - b'A' + 1, param)
+        }
+        _ => return Err("invalid effect command"),
+    };
+
+    Ok(Command {
+        note,
+        instrument,
+        volume,
+        effect,
+    })
+}
+
+
 impl Row {
     /// Create new empty row
     pub const fn empty() -> Row {
@@ -858,6 +1082,30 @@ impl Debug for Row {
     }
 }
 
+/// Formats the row in tracker style, one column per channel up to the last used one
+///
+/// Columns are separated by `|`, empty cells are displayed as `... .. ... ...`.
+#[cfg(feature = "std")]
+impl Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let last = match self.map.last() {
+            Some((chan, _)) => chan.as_usize(),
+            None => return Ok(()),
+        };
+        let mut commands = self.map.iter().peekable();
+        for idx in 0..=last {
+            if idx > 0 {
+                f.write_str(" | ")?;
+            }
+            match commands.next_if(|(chan, _)| chan.as_usize() == idx) {
+                Some((_, command)) => Display::fmt(command, f)?,
+                None => Display::fmt(&Command::EMPTY, f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Get<Channel> for Row {
     type Output = Command;
     fn get(&self, index: Channel) -> Option<&Self::Output> {
@@ -871,6 +1119,86 @@ impl Get<Channel> for Row {
 impl_index_from_get!(Row, Channel);
 
 
+impl Command {
+    /// Command with all columns empty
+    pub const EMPTY: Command = Command {
+        note: None,
+        instrument: None,
+        volume: None,
+        effect: None,
+    };
+}
+
+/// Formats the command as tracker columns, e.g. `C-5 01 v64 D04`
+///
+/// Empty columns are filled with dots so that the columns stay aligned.
+#[cfg(feature = "std")]
+impl Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.note {
+            Some(note) => write!(f, "{note}")?,
+            None => f.write_str("...")?,
+        }
+        match &self.instrument {
+            Some(instrument) => write!(f, " {instrument}")?,
+            None => f.write_str(" ..")?,
+        }
+        match &self.volume {
+            Some(volume) => write!(f, " {volume}")?,
+            None => f.write_str(" ...")?,
+        }
+        match &self.effect {
+            Some(effect) => write!(f, " {effect}")?,
+            None => f.write_str(" ...")?,
+        }
+        Ok(())
+    }
+}
+
+/// Formats the note as `C-5`, note off as `===`, note cut as `^^^` and note fade as `~~~`.
+impl Display for NoteCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoteCmd::Play(note) => Display::fmt(note, f),
+            NoteCmd::Off => f.write_str("==="),
+            NoteCmd::Cut => f.write_str("^^^"),
+            NoteCmd::Fade => f.write_str("~~~"),
+        }
+    }
+}
+
+/// Formats the volume command as displayed in the tracker, e.g. `v64` or `c05`.
+impl Display for VolumeCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn param(x: &Option<RangedU8<1, 9>>) -> u8 {
+            x.map_or(0, RangedU8::as_u8)
+        }
+
+        match self {
+            VolumeCmd::SetVolume(x) => write!(f, "v{:02}", x.as_u8()),
+            VolumeCmd::Panning(x) => write!(f, "p{:02}", x.as_u8()),
+            VolumeCmd::FineVolumeUp(x) => write!(f, "a{:02}", param(x)),
+            VolumeCmd::FineVolumeDown(x) => write!(f, "b{:02}", param(x)),
+            VolumeCmd::VolumeSlideUp(x) => write!(f, "c{:02}", param(x)),
+            VolumeCmd::VolumeSlideDown(x) => write!(f, "d{:02}", param(x)),
+            VolumeCmd::PortamentoDown(x) => write!(f, "e{:02}", param(x)),
+            VolumeCmd::PortamentoUp(x) => write!(f, "f{:02}", param(x)),
+            VolumeCmd::TonePortamento(x) => write!(f, "g{:02}", param(x)),
+            VolumeCmd::Vibrato(x) => write!(f, "h{:02}", param(x)),
+        }
+    }
+}
+
+/// Formats the effect as displayed in the tracker, e.g. `D12`.
+#[cfg(feature = "std")]
+impl Display for EffectCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (effect, param) = crate::writer::effect(self);
+        write!(f, "{}{:02X}", char::from(b'A' + effect - 1), param)
+    }
+}
+
+
 impl TryFrom<u8> for Note {
     type Error = OutOfRangeError<0, 119>;
 
@@ -940,6 +1268,7 @@ impl Display for Note {
 
 impl Note {
     /// Convert note into its frequency in A=440Hz tuning
+    #[cfg(feature = "std")]
     pub fn freq(self) -> f32 {
         let (idx, base) = (self, Note::A_4);
         let exp = (f32::from(u8::from(idx)) - f32::from(u8::from(base))) / 12.0f32;
@@ -1051,3 +1380,67 @@ impl EffectCmd {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clipboard_roundtrip() {
+        let text = "ModPlug Tracker  IT\r\n\
+            |C-501v64D04|...........|===........\r\n\
+            |...........|G#310p32...|^^^..c05T80\r\n\
+            |...........|...........|...........\r\n";
+
+        let pattern = Pattern::from_text(text).unwrap();
+        assert_eq!(pattern.rows.len(), 3);
+        assert_eq!(
+            pattern.rows[0][Channel::new(1)].to_string(),
+            "C-5 01 v64 D04",
+        );
+        assert_eq!(pattern.to_text(), text);
+    }
+
+    #[test]
+    fn display() {
+        let command = Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            volume: Some(VolumeCmd::SetVolume(RangedU8::new(64))),
+            effect: Some(EffectCmd::VolumeSlide(Some(VolumeSlide::Down(RangedU8::new(4))))),
+        };
+        assert_eq!(command.to_string(), "C-5 01 v64 D04");
+        assert_eq!(Command::EMPTY.to_string(), "... .. ... ...");
+
+        assert_eq!(NoteCmd::Off.to_string(), "===");
+        assert_eq!(NoteCmd::Cut.to_string(), "^^^");
+        assert_eq!(NoteCmd::Fade.to_string(), "~~~");
+
+        let volumes = [
+            VolumeCmd::SetVolume(RangedU8::new(0)),
+            VolumeCmd::Panning(RangedU8::new(32)),
+            VolumeCmd::FineVolumeUp(Some(RangedU8::new(1))),
+            VolumeCmd::FineVolumeDown(Some(RangedU8::new(2))),
+            VolumeCmd::VolumeSlideUp(Some(RangedU8::new(3))),
+            VolumeCmd::VolumeSlideDown(Some(RangedU8::new(4))),
+            VolumeCmd::PortamentoDown(Some(RangedU8::new(5))),
+            VolumeCmd::PortamentoUp(Some(RangedU8::new(6))),
+            VolumeCmd::TonePortamento(Some(RangedU8::new(9))),
+            VolumeCmd::Vibrato(None),
+        ];
+        let volumes = volumes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(volumes, ["v00", "p32", "a01", "b02", "c03", "d04", "e05", "f06", "g09", "h00"]);
+
+        // Effects using their memory display a zero parameter.
+        assert_eq!(EffectCmd::VolumeSlide(None).to_string(), "D00");
+        assert_eq!(EffectCmd::SetSpeed(RangedU8::new(6)).to_string(), "A06");
+
+        let row = Row::from_vec(vec![
+            (Channel::new(1), command),
+            (Channel::new(3), Command { note: Some(NoteCmd::Off), effect: Some(EffectCmd::VolumeSlide(None)), ..Command::EMPTY }),
+        ]);
+        assert_eq!(row.to_string(), "C-5 01 v64 D04 | ... .. ... ... | === .. ... D00");
+        assert_eq!(Row::empty().to_string(), "");
+    }
+}
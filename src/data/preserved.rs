@@ -0,0 +1,145 @@
+use super::*;
+use alloc::sync::Arc;
+use core::fmt::{self, Debug};
+
+
+/// Parts of a module file which are not represented in [`Module`]
+///
+/// Returned by [`parser::module_file_preserving`](crate::parser::module_file_preserving) and used
+/// by [`writer::module_file_preserving`](crate::writer::module_file_preserving) to write the file
+/// back without losing data the parser doesn't understand.
+///
+/// - If the module is unchanged the original file is reproduced byte-for-byte, including the
+///   padding between structures and their order in the file.
+/// - If the module was modified the file is laid out anew, but the unknown data found between
+///   the offset tables and the first structure (edit history, OpenMPT pattern and channel names,
+///   plugins) and after the last structure (OpenMPT extension chunks) is kept, as well as the
+///   header flag bits the parser doesn't know about. The data is kept as is even if it no longer
+///   matches the module, e.g. after removing a pattern which had a name.
+#[derive(Clone, PartialEq)]
+pub struct Preserved {
+    /// Module as it was parsed, used to detect modifications
+    ///
+    /// Sample data is shared with the parsed module so keeping this copy is cheap.
+    pub(crate) module: Module,
+
+    /// The original file
+    pub(crate) original: Arc<[u8]>,
+
+    /// Data between the offset tables and the first structure referenced from the header
+    pub header_extra: Vec<u8>,
+
+    /// Data after the last structure referenced from the header
+    pub trailing: Vec<u8>,
+
+    /// Bits of the header `flags` and `special` fields which are not known to [`ModuleFlags`]
+    pub unknown_flags: (u16, u16),
+}
+
+impl Preserved {
+    /// Returns the original file.
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    /// Returns `true` if `module` is the same as the one parsed from the original file.
+    pub fn is_unmodified(&self, module: &Module) -> bool {
+        self.module == *module
+    }
+}
+
+impl Debug for Preserved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Preserved")
+            .field("original", &format_args!("<{} bytes>", self.original.len()))
+            .field("header_extra", &format_args!("<{} bytes>", self.header_extra.len()))
+            .field("trailing", &format_args!("<{} bytes>", self.trailing.len()))
+            .field("unknown_flags", &self.unknown_flags)
+            .finish()
+    }
+}
+
+/// Marker starting the OpenMPT instrument extensions in [`Preserved::trailing`]
+#[cfg(feature = "std")]
+const INSTRUMENT_EXTENSIONS: &[u8; 4] = b"XTPM";
+
+/// Marker starting the OpenMPT song properties in [`Preserved::trailing`]
+#[cfg(feature = "std")]
+pub(crate) const SONG_PROPERTIES: &[u8; 4] = b"STPM";
+
+/// OpenMPT extension chunk
+///
+/// Chunks are a 4 bytes code, a 16-bit little-endian size and the value. The codes are written as
+/// little-endian integers of their big-endian names, so the names appear reversed in the file.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ExtensionChunk<'a> {
+    /// The chunk as it's stored, header included
+    pub(crate) bytes: &'a [u8],
+}
+
+#[cfg(feature = "std")]
+impl<'a> ExtensionChunk<'a> {
+    /// Returns `true` if the code of the chunk is `name` reversed.
+    pub(crate) fn is(&self, name: &[u8; 4]) -> bool {
+        self.bytes[..4].iter().rev().eq(name)
+    }
+
+    /// Returns the value, the values of all instruments for the instrument extensions.
+    pub(crate) fn value(&self) -> &'a [u8] {
+        &self.bytes[6..]
+    }
+}
+
+/// Iterator over the OpenMPT extension chunks at the start of some data, see
+/// [`song_property_chunks`]
+///
+/// Stops at the first chunk which doesn't fit in the data.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub(crate) struct ExtensionChunks<'a> {
+    rest: &'a [u8],
+
+    /// Values stored in each chunk, the instrument extensions store one for each instrument
+    count: usize,
+}
+
+#[cfg(feature = "std")]
+impl<'a> ExtensionChunks<'a> {
+    /// Returns the data after the chunks iterated so far.
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a> Iterator for ExtensionChunks<'a> {
+    type Item = ExtensionChunk<'a>;
+
+    fn next(&mut self) -> Option<ExtensionChunk<'a>> {
+        let size = self.rest.get(4..6).map(|size| usize::from(u16::from_le_bytes([size[0], size[1]])))?;
+        let bytes = self.rest.get(..6 + size * self.count)?;
+        self.rest = &self.rest[bytes.len()..];
+        Some(ExtensionChunk { bytes })
+    }
+}
+
+/// Splits `trailing` at the `STPM` marker of the OpenMPT song properties, returns the data before
+/// the marker and the chunks after it or `None` if there's no marker.
+///
+/// The marker is expected at the start of `trailing` or after the instrument extensions starting
+/// with `XTPM`, whose chunks have a value for each of the `instruments` of the file. The chunks
+/// are stepped over rather than searched, so a value can't be mistaken for the marker.
+#[cfg(feature = "std")]
+pub(crate) fn song_property_chunks(trailing: &[u8], instruments: usize) -> Option<(&[u8], ExtensionChunks<'_>)> {
+    let mut start = 0;
+    if trailing.starts_with(INSTRUMENT_EXTENSIONS) {
+        let mut chunks = ExtensionChunks { rest: &trailing[4..], count: instruments };
+        while !chunks.rest.starts_with(SONG_PROPERTIES) {
+            chunks.next()?;
+        }
+        start = trailing.len() - chunks.rest.len();
+    }
+    let properties = trailing[start..].strip_prefix(SONG_PROPERTIES)?;
+    Some((&trailing[..start], ExtensionChunks { rest: properties, count: 1 }))
+}
@@ -1,7 +1,14 @@
 use super::*;
+use crate::error::InvalidLoopError;
+use alloc::sync::Arc;
+use core::convert::TryFrom;
+use core::fmt::{self, Debug};
+#[cfg(feature = "std")]
+use std::sync::OnceLock;
 
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct Sample {
     /// Sample Name, null-terminated (but may also contain nulls)
     pub name: Name,
@@ -20,9 +27,13 @@ pub struct Sample {
 
     /// Loop after the note has been released (Off ==) command, or directly after reaching the end
     /// point if the sustain loop is off.
+    ///
+    /// Use [`Sample::set_loop`] to change the loop with validation.
     pub loop_: Option<SampleLoop>,
 
     /// Loop after reching the end point while holding the note.
+    ///
+    /// Use [`Sample::set_sustain_loop`] to change the loop with validation.
     pub sustain_loop: Option<SampleLoop>,
 
     /// C-5 playback frequency.
@@ -43,11 +54,23 @@ pub struct Sample {
     /// Auto-Vibrato Type
     pub vibrato_type: u8,
 
-    /// Sample samples converted to a normalized `f32` representation (values from -1.0 (inclusive)
-    /// to 1.0 (exclusive))
-    pub data: Option<Vec<f32>>,
+    /// Sample data in its native bit depth
+    ///
+    /// The data is shared between clones of the sample, see [`SampleData`]. Samples parsed by
+    /// [`parser::module_file_lazy`](crate::parser::module_file_lazy) have no data here until
+    /// it's decoded, read it with [`Sample::decoded_data`].
+    pub data: Option<SampleData>,
+
+    /// Data of a sample parsed by [`parser::module_file_lazy`](crate::parser::module_file_lazy)
+    /// as it's stored in the file
+    ///
+    /// [`Sample::data`] takes precedence, setting it replaces the stored data. Not serialized.
+    #[cfg(feature = "std")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub lazy_data: Option<LazySampleData>,
 }
 
+#[derive(Clone)]
 pub(crate) struct SampleHeader {
     pub(crate) name: Name,
     pub(crate) filename: DosFilename,
@@ -67,14 +90,47 @@ pub(crate) struct SampleHeader {
     pub(crate) data_length: u32,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Sample PCM data
+///
+/// The samples are stored behind an [`Arc`] so cloning a [`Sample`] (or a whole module, e.g. to
+/// hand it to a worker thread) doesn't copy the data. Editing through [`SampleData::make_mut_8`]
+/// and [`SampleData::make_mut_16`] copies the data first if it's shared with another clone.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SampleData {
+    /// 8-bit signed samples
+    Pcm8(Arc<[i8]>),
+
+    /// 16-bit signed samples
+    Pcm16(Arc<[i16]>),
+}
+
+#[cfg(feature = "std")]
+/// Sample data decoded on first access, kept in [`Sample::lazy_data`] by
+/// [`parser::module_file_lazy`](crate::parser::module_file_lazy)
+///
+/// Holds the bytes of the sample as they're stored in the file, compressed or not. The first call
+/// to [`LazySampleData::get`] decodes them and caches the result, clones share the cache.
+#[derive(Clone)]
+pub struct LazySampleData(Arc<LazyData>);
+
+#[cfg(feature = "std")]
+struct LazyData {
+    /// Header of the sample with the data offset rebased to the start of `stored`
+    header: SampleHeader,
+    stored: Box<[u8]>,
+    decoded: OnceLock<Option<SampleData>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub struct SampleLoop {
     /// Start - offset into the sample in samples
     pub start: u32,
 
     /// End - offset into the sample in samples.
     ///
-    /// Must be always `>= start`
+    /// The end is exclusive, it points to the first sample *after* the loop. Must be always
+    /// `> start` and at most the length of the sample.
     pub end: u32,
 
     /// Bidirectional loop (also ping-pong loop)
@@ -87,6 +143,218 @@ pub struct SampleLoop {
     pub bidi: bool,
 }
 
+impl Sample {
+    /// Length of the sample data in samples, `0` if the sample has no data.
+    ///
+    /// The length of [`Sample::lazy_data`] is known without decoding it.
+    pub fn length(&self) -> u32 {
+        #[cfg(feature = "std")]
+        {
+            if let (None, Some(lazy_data)) = (&self.data, &self.lazy_data) {
+                return lazy_data.length();
+            }
+        }
+        // Sample length is stored as an `u32` in the file, larger data cannot be represented.
+        self.data
+            .as_ref()
+            .map_or(0, |data| u32::try_from(data.len()).unwrap_or(u32::MAX))
+    }
+
+    /// Returns the sample data, decoding [`Sample::lazy_data`] on the first call if there is no
+    /// [`Sample::data`].
+    ///
+    /// Returns `None` if the sample has no data or the stored data can't be decoded. The player
+    /// and the writer read the data through this method.
+    pub fn decoded_data(&self) -> Option<&SampleData> {
+        #[cfg(feature = "std")]
+        {
+            if self.data.is_none() {
+                return self.lazy_data.as_ref().and_then(LazySampleData::get);
+            }
+        }
+        self.data.as_ref()
+    }
+
+    /// Sets the sample loop after validating it against the sample data.
+    ///
+    /// The flags describing the loop in the file are derived from the loop when serializing, the
+    /// loop type is therefore always consistent with the loop points.
+    ///
+    /// See [`SampleLoop::validate`] for the conditions the loop has to satisfy, the current loop
+    /// is left unchanged if the validation fails.
+    pub fn set_loop(&mut self, loop_: Option<SampleLoop>) -> Result<(), InvalidLoopError> {
+        if let Some(loop_) = &loop_ {
+            loop_.validate(self.length())?;
+        }
+        self.loop_ = loop_;
+        Ok(())
+    }
+
+    /// Sets the sample sustain loop after validating it against the sample data.
+    ///
+    /// See [`Sample::set_loop`] for details.
+    pub fn set_sustain_loop(&mut self, sustain_loop: Option<SampleLoop>) -> Result<(), InvalidLoopError> {
+        if let Some(sustain_loop) = &sustain_loop {
+            sustain_loop.validate(self.length())?;
+        }
+        self.sustain_loop = sustain_loop;
+        Ok(())
+    }
+}
+
+impl SampleData {
+    /// Number of samples
+    pub fn len(&self) -> usize {
+        match self {
+            SampleData::Pcm8(data) => data.len(),
+            SampleData::Pcm16(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` for 16-bit samples.
+    pub fn is_16bit(&self) -> bool {
+        matches!(self, SampleData::Pcm16(_))
+    }
+
+    /// Returns the sample at `index` converted to a normalized `f32` (values from -1.0 (inclusive)
+    /// to 1.0 (exclusive)).
+    pub fn get(&self, index: usize) -> Option<f32> {
+        match self {
+            SampleData::Pcm8(data) => data[..].get(index).map(|&x| f32::from(x) / 128.0),
+            SampleData::Pcm16(data) => data[..].get(index).map(|&x| f32::from(x) / 32768.0),
+        }
+    }
+
+    /// Iterates over the samples converted to a normalized `f32`, see [`SampleData::get`].
+    pub fn iter(&self) -> impl Iterator<Item = f32> + '_ {
+        (0..self.len()).map(move |index| self.get(index).unwrap())
+    }
+
+    /// Copies the samples into a normalized `f32` representation, see [`SampleData::get`].
+    pub fn to_f32(&self) -> Vec<f32> {
+        self.iter().collect()
+    }
+
+    /// Returns mutable access to 8-bit samples, copying them first if they're shared.
+    ///
+    /// Returns `None` for 16-bit samples.
+    pub fn make_mut_8(&mut self) -> Option<&mut [i8]> {
+        match self {
+            SampleData::Pcm8(data) => Some(make_mut(data)),
+            SampleData::Pcm16(_) => None,
+        }
+    }
+
+    /// Returns mutable access to 16-bit samples, copying them first if they're shared.
+    ///
+    /// Returns `None` for 8-bit samples.
+    pub fn make_mut_16(&mut self) -> Option<&mut [i16]> {
+        match self {
+            SampleData::Pcm8(_) => None,
+            SampleData::Pcm16(data) => Some(make_mut(data)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl LazySampleData {
+    pub(crate) fn new(mut header: SampleHeader, stored: &[u8]) -> LazySampleData {
+        header.data_offset = 0;
+        LazySampleData(Arc::new(LazyData {
+            header,
+            stored: stored.into(),
+            decoded: OnceLock::new(),
+        }))
+    }
+
+    /// Returns the sample data, decoding it on the first call.
+    ///
+    /// Returns `None` if the stored data can't be decoded, e.g. because the file was truncated.
+    pub fn get(&self) -> Option<&SampleData> {
+        let LazyData { header, stored, decoded } = &*self.0;
+        decoded.get_or_init(|| crate::parser::stored_sample_data(header, stored)).as_ref()
+    }
+
+    /// Returns `true` if the data has been decoded already.
+    pub fn is_decoded(&self) -> bool {
+        self.0.decoded.get().is_some()
+    }
+
+    /// Length of the data in samples as stored in the sample header
+    pub fn length(&self) -> u32 {
+        self.0.header.data_length
+    }
+
+    /// Size of the stored data in bytes
+    pub fn stored_size(&self) -> usize {
+        self.0.stored.len()
+    }
+}
+
+#[cfg(feature = "std")]
+impl PartialEq for LazySampleData {
+    /// Compares the stored data without decoding it.
+    fn eq(&self, other: &LazySampleData) -> bool {
+        let (this, other) = (&*self.0, &*other.0);
+        (this.header.flags, this.header.data_length, &this.stored) == (other.header.flags, other.header.data_length, &other.stored)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Debug for LazySampleData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LazySampleData")
+            .field("stored_size", &self.stored_size())
+            .field("decoded", &self.0.decoded.get())
+            .finish()
+    }
+}
+
+/// Copy-on-write access to a shared slice.
+fn make_mut<T: Copy>(data: &mut Arc<[T]>) -> &mut [T] {
+    if Arc::get_mut(data).is_none() {
+        *data = Arc::from(&data[..]);
+    }
+    Arc::get_mut(data).unwrap()
+}
+
+impl From<Vec<i8>> for SampleData {
+    fn from(data: Vec<i8>) -> SampleData {
+        SampleData::Pcm8(data.into())
+    }
+}
+
+impl From<Vec<i16>> for SampleData {
+    fn from(data: Vec<i16>) -> SampleData {
+        SampleData::Pcm16(data.into())
+    }
+}
+
+impl SampleLoop {
+    /// Checks that the loop can be played on a sample of `length` samples.
+    ///
+    /// - The loop must not be empty, `start < end`.
+    /// - The loop must fit into the sample, `end <= length`.
+    /// - Bidirectional loops must be at least 2 samples long, otherwise there is nothing to
+    ///   reverse and players disagree on how to handle them.
+    pub fn validate(&self, length: u32) -> Result<(), InvalidLoopError> {
+        if self.start >= self.end {
+            return Err(InvalidLoopError::Empty { start: self.start, end: self.end });
+        }
+        if self.end > length {
+            return Err(InvalidLoopError::OutOfBounds { end: self.end, length });
+        }
+        if self.bidi && self.end - self.start < 2 {
+            return Err(InvalidLoopError::BidiTooShort { start: self.start, end: self.end });
+        }
+        Ok(())
+    }
+}
+
 bitflags! {
     pub(crate) struct SampleFlags: u16 {
         // Originally `flags` field.
@@ -164,4 +432,9 @@ impl SampleFlags {
         let bits = u16::from(flags) | (u16::from(cvt) << 8);
         SampleFlags::from_bits_truncate(bits)
     }
+
+    pub(crate) fn to_parts(self) -> (u8, u8) {
+        let [flags, cvt] = self.bits().to_le_bytes();
+        (flags, cvt)
+    }
 }
@@ -0,0 +1,393 @@
+//! [`Serialize`] and [`Deserialize`] implementations for the module tree
+//!
+//! Most types derive the implementations, the ones here are written by hand to keep the
+//! serialized form readable and diffable:
+//!
+//! - names are strings when they can be decoded and encoded again without changing the bytes,
+//!   otherwise byte arrays,
+//! - notes are note names like `"C-5"`,
+//! - flags are their raw bits, active channels a list of channel indices,
+//! - rows and keyboard tables only list the channels and notes which are used,
+//! - sample data is base64 encoded little endian PCM.
+//!
+//! Identifiers are serialized as 0 based indices into the [`Module`] lists. Deserializing checks
+//! the value ranges, references between the parts of the module are checked by
+//! [`json::from_json`](crate::json::from_json).
+
+use super::*;
+use crate::cp437;
+use ::serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use ::serde::ser::{SerializeMap, SerializeSeq, Serializer};
+use ::serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+
+/// Serializes flags as their bits, unknown bits are rejected when deserializing.
+macro_rules! serde_flags {
+    ( $( $name: ident: $bits: ty ),* ) => {
+        $(
+            impl Serialize for $name {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    self.bits().serialize(serializer)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $name {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    let bits = <$bits>::deserialize(deserializer)?;
+                    $name::from_bits(bits)
+                        .ok_or_else(|| de::Error::custom(format_args!("unknown {} bits {:#x}", stringify!($name), bits)))
+                }
+            }
+        )*
+    };
+}
+
+serde_flags!(ModuleFlags: u32, InstrumentFlags: u8, EnvelopeFlags: u8);
+
+
+impl<const LOW: u8, const HIGH: u8> Serialize for RangedU8<LOW, HIGH> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.as_u8())
+    }
+}
+
+impl<'de, const LOW: u8, const HIGH: u8> Deserialize<'de> for RangedU8<LOW, HIGH> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        RangedU8::try_from(u8::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+
+/// Serializes fixed size text fields, see the module documentation.
+macro_rules! serde_text_field {
+    ( $( $name: ident: $len: literal ),* ) => {
+        $(
+            impl Serialize for $name {
+                fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                    serialize_text_field(&self.bytes, serializer)
+                }
+            }
+
+            impl<'de> Deserialize<'de> for $name {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    let mut bytes = [0; $len];
+                    deserializer.deserialize_any(TextFieldVisitor(&mut bytes))?;
+                    Ok($name { bytes })
+                }
+            }
+        )*
+    };
+}
+
+serde_text_field!(Name: 26, DosFilename: 13);
+
+fn serialize_text_field<S: Serializer>(field: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let text = cp437::decode_field(field);
+    let mut encoded = vec![0; field.len()];
+    if cp437::encode_field(&text, &mut encoded).is_lossless() && encoded == field {
+        serializer.serialize_str(&text)
+    } else {
+        field.serialize(serializer)
+    }
+}
+
+struct TextFieldVisitor<'f>(&'f mut [u8]);
+
+impl<'de> Visitor<'de> for TextFieldVisitor<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string of at most {} characters or an array of {} bytes", self.0.len() - 1, self.0.len())
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<(), E> {
+        if cp437::encode_field(text, self.0).is_lossless() {
+            Ok(())
+        } else {
+            Err(E::invalid_value(de::Unexpected::Str(text), &self))
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let len = self.0.len();
+        for (idx, byte) in self.0.iter_mut().enumerate() {
+            *byte = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(idx, &"a text field"))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(len + 1, &"a text field"));
+        }
+        Ok(())
+    }
+}
+
+
+impl Serialize for Note {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Note {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Note::from_str(&name)
+            .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(&name), &"a note name like C-5"))
+    }
+}
+
+
+impl Serialize for ActiveChannels {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for ActiveChannels {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Channel>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+
+/// Rows are maps from the channel index to the command.
+impl Serialize for Row {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Row {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RowVisitor;
+
+        impl<'de> Visitor<'de> for RowVisitor {
+            type Value = Row;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map from channels to commands")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Row, A::Error> {
+                let mut commands = Vec::new();
+                while let Some((channel, command)) = map.next_entry::<Channel, Command>()? {
+                    if commands.iter().any(|&(other, _)| other == channel) {
+                        return Err(de::Error::custom(format_args!("duplicate channel {:?}", channel)));
+                    }
+                    commands.push((channel, command));
+                }
+                Ok(Row::from_vec(commands))
+            }
+        }
+
+        deserializer.deserialize_map(RowVisitor)
+    }
+}
+
+
+/// Keyboard tables only list the notes which play a sample or are translated, as
+/// `note: [translated note, sample]`.
+impl Serialize for SampleMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (note, translation, sample) in self.iter() {
+            if sample.is_some() || note != translation {
+                map.serialize_entry(&note, &(translation, sample))?;
+            }
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for SampleMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SampleMapVisitor;
+
+        impl<'de> Visitor<'de> for SampleMapVisitor {
+            type Value = SampleMap;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map from notes to translated notes and samples")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SampleMap, A::Error> {
+                let mut sample_map = SampleMap::default();
+                while let Some((note, (translation, sample))) = map.next_entry::<Note, (Note, Option<SampleId>)>()? {
+                    sample_map.set(note, translation, sample);
+                }
+                Ok(sample_map)
+            }
+        }
+
+        deserializer.deserialize_map(SampleMapVisitor)
+    }
+}
+
+
+/// Initial channel settings, serialized as a list because `serde` only supports arrays of up to
+/// 32 elements.
+pub(crate) mod channels {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(channels: &[ChannelSettings; 64], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(channels.len()))?;
+        for channel in channels {
+            seq.serialize_element(channel)?;
+        }
+        seq.end()
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[ChannelSettings; 64], D::Error> {
+        let channels = Vec::<ChannelSettings>::deserialize(deserializer)?;
+        let len = channels.len();
+        <[ChannelSettings; 64]>::try_from(channels)
+            .map_err(|_| de::Error::invalid_length(len, &"64 channels"))
+    }
+}
+
+
+/// Format of the sample data
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SampleFormat {
+    Pcm8,
+    Pcm16,
+}
+
+impl SampleFormat {
+    pub(crate) fn of(data: &SampleData) -> SampleFormat {
+        if data.is_16bit() { SampleFormat::Pcm16 } else { SampleFormat::Pcm8 }
+    }
+}
+
+/// Returns the samples as little endian bytes.
+pub(crate) fn sample_bytes(data: &SampleData) -> Vec<u8> {
+    match data {
+        SampleData::Pcm8(data) => data.iter().map(|&x| x.to_le_bytes()[0]).collect(),
+        SampleData::Pcm16(data) => data.iter().flat_map(|&x| x.to_le_bytes()).collect(),
+    }
+}
+
+/// Reads samples from little endian bytes, returns `None` if the length doesn't match the format.
+pub(crate) fn sample_data(format: SampleFormat, bytes: &[u8]) -> Option<SampleData> {
+    match format {
+        SampleFormat::Pcm8 => Some(SampleData::from(bytes.iter().map(|&x| i8::from_le_bytes([x])).collect::<Vec<_>>())),
+        SampleFormat::Pcm16 => {
+            let chunks = bytes.chunks_exact(2);
+            chunks.remainder().is_empty().then(|| {
+                SampleData::from(chunks.map(|x| i16::from_le_bytes([x[0], x[1]])).collect::<Vec<_>>())
+            })
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmbeddedSampleData {
+    format: SampleFormat,
+    base64: String,
+}
+
+impl Serialize for SampleData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        EmbeddedSampleData {
+            format: SampleFormat::of(self),
+            base64: base64::encode(&sample_bytes(self)),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SampleData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let EmbeddedSampleData { format, base64 } = EmbeddedSampleData::deserialize(deserializer)?;
+        let bytes = base64::decode(&base64).ok_or_else(|| de::Error::custom("invalid base64 sample data"))?;
+        sample_data(format, &bytes).ok_or_else(|| de::Error::custom("16-bit sample data has an odd length"))
+    }
+}
+
+
+/// Standard base64 with padding, the sample data is the only user so it's not worth a dependency.
+pub(crate) mod base64 {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub(crate) fn encode(bytes: &[u8]) -> String {
+        let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let group = chunk.iter().enumerate().fold(0u32, |group, (idx, &byte)| group | (u32::from(byte) << (16 - 8 * idx)));
+            for idx in 0..4 {
+                if idx <= chunk.len() {
+                    let digit = (group >> (18 - 6 * idx)) & 0x3F;
+                    text.push(char::from(ALPHABET[usize::try_from(digit).unwrap()]));
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    /// Returns `None` for invalid characters or lengths.
+    pub(crate) fn decode(text: &str) -> Option<Vec<u8>> {
+        let text = text.as_bytes();
+        if text.len() % 4 != 0 {
+            return None;
+        }
+        let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+        for (chunk_idx, chunk) in text.chunks(4).enumerate() {
+            let last = chunk_idx + 1 == text.len() / 4;
+            let padding = chunk.iter().rev().take_while(|&&ch| ch == b'=').count();
+            if padding > 2 || (padding > 0 && !last) {
+                return None;
+            }
+            let mut group = 0u32;
+            for (idx, &ch) in chunk[..4 - padding].iter().enumerate() {
+                let digit = ALPHABET.iter().position(|&x| x == ch)?;
+                group |= u32::try_from(digit).unwrap() << (18 - 6 * idx);
+            }
+            bytes.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+        }
+        Some(bytes)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        for (bytes, text) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"\xFF\x00\xFE", "/wD+"),
+        ] {
+            assert_eq!(base64::encode(bytes), text);
+            assert_eq!(base64::decode(text).as_deref(), Some(bytes));
+        }
+        assert_eq!(base64::decode("Zg="), None);
+        assert_eq!(base64::decode("Zg==Zg=="), None);
+        assert_eq!(base64::decode("Z!=="), None);
+    }
+
+    #[test]
+    fn text_fields() {
+        let name = Name::new("Drum loop");
+        assert_eq!(serde_json::to_string(&name).unwrap(), r#""Drum loop""#);
+        assert_eq!(serde_json::from_str::<Name>(r#""Drum loop""#).unwrap(), name);
+
+        // Garbage after the terminator can't be represented as a string.
+        let mut garbage = name;
+        garbage.bytes[20] = b'x';
+        let json = serde_json::to_string(&garbage).unwrap();
+        assert!(json.starts_with('['));
+        assert_eq!(serde_json::from_str::<Name>(&json).unwrap(), garbage);
+
+        assert!(serde_json::from_str::<Name>(r#""this name is far too long to fit""#).is_err());
+    }
+}
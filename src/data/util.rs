@@ -1,13 +1,22 @@
+use crate::cp437;
 use crate::error::OutOfRangeError;
-use std::convert::TryFrom;
-use std::fmt::{self, Write};
-
-#[derive(Clone, Copy)]
+use crate::prelude::*;
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
+
+/// Name of a module, instrument or sample
+///
+/// Stored as 26 bytes, null-terminated (but may also contain nulls).
+#[derive(Clone, Copy, PartialEq)]
 pub struct Name {
     pub bytes: [u8; 26],
 }
 
-#[derive(Clone, Copy)]
+/// DOS filename of an instrument or sample
+///
+/// Stored as 13 bytes, an 8.3 filename (12 characters) followed by a null terminator. Trackers
+/// use it to remember where the instrument or sample was loaded from.
+#[derive(Clone, Copy, PartialEq)]
 pub struct DosFilename {
     pub bytes: [u8; 13],
 }
@@ -49,6 +58,98 @@ impl<const LOW: u8, const HIGH: u8> From<RangedU8<LOW, HIGH>> for u8 {
     }
 }
 
+impl Name {
+    /// Creates a name from a string
+    ///
+    /// The name is encoded into code page 437 and truncated to 25 characters so there is always a
+    /// null terminator, see [`cp437::encode_field`] for the details. Use [`Name::encode`] to find
+    /// out what was lost.
+    pub fn new(name: &str) -> Name {
+        Name::encode(name).0
+    }
+
+    /// Creates a name from a string, reporting the characters which were replaced or cut off
+    pub fn encode(name: &str) -> (Name, cp437::Lossy) {
+        let mut bytes = [0; 26];
+        let lossy = cp437::encode_field(name, &mut bytes);
+        (Name { bytes }, lossy)
+    }
+
+    /// Decodes the name from code page 437, see [`cp437::decode_field`].
+    pub fn decode(&self) -> String {
+        cp437::decode_field(&self.bytes)
+    }
+
+    /// Returns the bytes before the null terminator.
+    pub fn as_bytes(&self) -> &[u8] {
+        null_terminated(&self.bytes)
+    }
+}
+
+/// Characters allowed in DOS filenames besides letters and digits
+const DOS_FILENAME_SPECIAL: &[u8] = b"!#$%&'()-@^_`{}~";
+
+impl DosFilename {
+    /// Creates an 8.3 filename from an arbitrary string
+    ///
+    /// Any directories are stripped, letters are converted to uppercase and characters which are
+    /// not allowed in DOS filenames are replaced with `_`. The part before the last dot is
+    /// truncated to 8 characters and the extension to 3 characters. An empty string gives an empty
+    /// filename.
+    pub fn sanitize(name: &str) -> DosFilename {
+        fn sanitize_part(part: &str, max_len: usize) -> impl Iterator<Item = u8> + '_ {
+            part.chars()
+                .filter(|&ch| ch != ' ')
+                .map(|ch| match u8::try_from(ch.to_ascii_uppercase()) {
+                    Ok(byte) if byte.is_ascii_alphanumeric() || DOS_FILENAME_SPECIAL.contains(&byte) => byte,
+                    _ => b'_',
+                })
+                .take(max_len)
+        }
+
+        let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+        let (base, extension) = match name.rsplit_once('.') {
+            Some((base, extension)) if !base.is_empty() => (base, extension),
+            _ => (name, ""),
+        };
+
+        let mut filename = Vec::with_capacity(12);
+        filename.extend(sanitize_part(base, 8));
+        let extension = sanitize_part(extension, 3).collect::<Vec<u8>>();
+        if !extension.is_empty() {
+            filename.push(b'.');
+            filename.extend(extension);
+        }
+
+        let mut bytes = [0; 13];
+        bytes[..filename.len()].copy_from_slice(&filename);
+        DosFilename { bytes }
+    }
+
+    /// Returns `true` if the filename is empty or a valid 8.3 DOS filename.
+    ///
+    /// Lowercase letters are accepted, DOS itself is case insensitive.
+    pub fn is_valid(&self) -> bool {
+        let valid_char = |byte: &u8| byte.is_ascii_alphanumeric() || DOS_FILENAME_SPECIAL.contains(byte);
+        let filename = self.as_bytes();
+        let (base, extension) = match filename.iter().position(|&byte| byte == b'.') {
+            Some(dot) => (&filename[..dot], &filename[dot + 1..]),
+            None => (filename, &[][..]),
+        };
+        filename.is_empty()
+            || (!base.is_empty()
+                && base.len() <= 8
+                && extension.len() <= 3
+                && base.iter().all(valid_char)
+                && extension.iter().all(valid_char))
+    }
+
+    /// Returns the bytes before the null terminator.
+    pub fn as_bytes(&self) -> &[u8] {
+        null_terminated(&self.bytes)
+    }
+}
+
 fn null_terminated(bytes: &[u8]) -> &[u8] {
     let null_pos = bytes.iter()
         .position(|&b| b == 0)
@@ -56,7 +157,7 @@ fn null_terminated(bytes: &[u8]) -> &[u8] {
     &bytes[..null_pos]
 }
 
-fn debug_bytestring(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
+pub(super) fn debug_bytestring(bytes: &[u8], f: &mut fmt::Formatter) -> fmt::Result {
     f.write_char('"')?;
     for &byte in null_terminated(bytes) {
         if byte.is_ascii_graphic() || byte == b' ' {
@@ -82,13 +183,13 @@ impl fmt::Debug for DosFilename {
 
 impl fmt::Display for Name {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        String::from_utf8_lossy(null_terminated(&self.bytes)).fmt(f)
+        self.decode().fmt(f)
     }
 }
 
 impl fmt::Display for DosFilename {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        String::from_utf8_lossy(null_terminated(&self.bytes)).fmt(f)
+        cp437::decode_field(&self.bytes).fmt(f)
     }
 }
 
@@ -97,3 +198,30 @@ impl<const LOW: u8, const HIGH: u8> fmt::Debug for RangedU8<LOW, HIGH> {
         self.as_u8().fmt(f)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sanitize_dos_filename() {
+        let sanitize = |name| DosFilename::sanitize(name).to_string();
+
+        assert_eq!(sanitize("kick.wav"), "KICK.WAV");
+        assert_eq!(sanitize("C:\\samples\\long snare name.aiff"), "LONGSNAR.AIF");
+        assert_eq!(sanitize("bass+lead.raw"), "BASS_LEA.RAW");
+        assert_eq!(sanitize(".hidden"), "_HIDDEN");
+        assert_eq!(sanitize(""), "");
+        assert!(DosFilename::sanitize("some/path/to a file.it.bak").is_valid());
+    }
+
+    #[test]
+    fn encode_name() {
+        let (name, lossy) = Name::encode("Ünïcode ♪ name which is way too long");
+        assert_eq!(name.to_string(), "Ünïcode ? name which is w");
+        assert_eq!(lossy, cp437::Lossy { replaced: vec!['♪'], truncated: 11 });
+        assert_eq!(name.bytes[25], 0);
+        assert_eq!(Name::new("bass   ").as_bytes(), b"bass");
+    }
+}
@@ -0,0 +1,308 @@
+//! Structural comparison of two modules
+//!
+//! [`diff`] lists the differences between two [`Module`]s on the level of the data model instead
+//! of bytes. It's meant for reviewing what a conversion or cleanup pass changed.
+
+use crate::*;
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+
+/// Single difference between two modules
+///
+/// Items are identified by their position in the old module, additions by their position in the
+/// new one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Difference {
+    /// Header field (global song parameter, name, message...) differs
+    Header { field: &'static str },
+
+    /// Initial settings of a channel differ
+    Channel { channel: Channel },
+
+    /// Order was added (`old` is `None`), removed (`new` is `None`) or changed
+    Order { order: OrderIdx, old: Option<Order>, new: Option<Order> },
+
+    SampleAdded { sample: SampleId },
+
+    SampleRemoved { sample: SampleId },
+
+    /// Sample exists in both modules, `fields` lists the names of the fields which differ
+    SampleModified { sample: SampleId, fields: Vec<&'static str> },
+
+    InstrumentAdded { instrument: InstrumentId },
+
+    InstrumentRemoved { instrument: InstrumentId },
+
+    /// Instrument exists in both modules, `fields` lists the names of the fields which differ
+    InstrumentModified { instrument: InstrumentId, fields: Vec<&'static str> },
+
+    PatternAdded { pattern: PatternId },
+
+    PatternRemoved { pattern: PatternId },
+
+    /// Pattern exists in both modules but the number of rows differs
+    ///
+    /// Cells of the rows present in both versions are still compared.
+    PatternRows { pattern: PatternId, old: usize, new: usize },
+
+    /// Cell in a row present in both versions of the pattern differs, empty cells are `None`
+    Cell {
+        pattern: PatternId,
+        row: usize,
+        channel: Channel,
+        old: Option<Command>,
+        new: Option<Command>,
+    },
+}
+
+/// Collect names of the listed fields which differ between `$old` and `$new`.
+macro_rules! changed_fields {
+    ( $old: expr, $new: expr, [ $( $field: ident ),* $(,)? ] ) => {{
+        let mut fields = Vec::new();
+        $(
+            if $old.$field != $new.$field {
+                fields.push(stringify!($field));
+            }
+        )*
+        fields
+    }};
+}
+
+/// Compares two modules
+///
+/// Returns an empty list if the modules are equal. Header differences come first, followed by
+/// orders, samples, instruments and patterns.
+pub fn diff(old: &Module, new: &Module) -> Vec<Difference> {
+    let mut differences = Vec::new();
+
+    differences.extend(
+        changed_fields!(old, new, [
+            name,
+            message,
+            highlight,
+            made_with_version,
+            compatible_with_version,
+            flags,
+            global_volume,
+            sample_volume,
+            speed,
+            tempo,
+            pan_separation,
+            pitch_wheel_depth,
+        ])
+        .into_iter()
+        .map(|field| Difference::Header { field })
+    );
+
+    for (idx, (old_channel, new_channel)) in old.channels.iter().zip(new.channels.iter()).enumerate() {
+        if old_channel != new_channel {
+            let channel = Channel::from_u8_index(u8::try_from(idx).unwrap());
+            differences.push(Difference::Channel { channel });
+        }
+    }
+
+    for (order, old_order, new_order) in pairs(&old.orders, &new.orders, OrderIdx::from_index) {
+        if old_order != new_order {
+            differences.push(Difference::Order {
+                order,
+                old: old_order.copied(),
+                new: new_order.copied(),
+            });
+        }
+    }
+
+    for (sample, old_sample, new_sample) in pairs(&old.samples, &new.samples, SampleId::from_index) {
+        match (old_sample, new_sample) {
+            (Some(old_sample), Some(new_sample)) => {
+                let fields = changed_fields!(old_sample, new_sample, [
+                    name,
+                    filename,
+                    global_volume,
+                    default_volume,
+                    default_panning,
+                    loop_,
+                    sustain_loop,
+                    samplerate_c5,
+                    vibrato_speed,
+                    vibrato_depth,
+                    vibrato_rate,
+                    vibrato_type,
+                    data,
+                    lazy_data,
+                ]);
+                if !fields.is_empty() {
+                    differences.push(Difference::SampleModified { sample, fields });
+                }
+            }
+            (None, Some(_)) => differences.push(Difference::SampleAdded { sample }),
+            (Some(_), None) => differences.push(Difference::SampleRemoved { sample }),
+            (None, None) => {}
+        }
+    }
+
+    for (instrument, old_instrument, new_instrument) in pairs(&old.instruments, &new.instruments, InstrumentId::from_index) {
+        match (old_instrument, new_instrument) {
+            (Some(old_instrument), Some(new_instrument)) => {
+                let fields = changed_fields!(old_instrument, new_instrument, [
+                    name,
+                    filename,
+                    flags,
+                    new_note_action,
+                    duplicate_check_type,
+                    duplicate_check_action,
+                    instrument_fadeout,
+                    pitch_pan_separation,
+                    pitch_pan_centre,
+                    global_volume,
+                    default_panning,
+                    random_volume_variation,
+                    random_panning_variation,
+                    trkver,
+                    number_of_samples,
+                    initial_filter_cutoff,
+                    initial_filter_resonance,
+                    mch,
+                    mpr,
+                    mbank,
+                    sample_map,
+                    volume_envelope,
+                    panning_envelope,
+                    pitch_filter_envelope,
+                ]);
+                if !fields.is_empty() {
+                    differences.push(Difference::InstrumentModified { instrument, fields });
+                }
+            }
+            (None, Some(_)) => differences.push(Difference::InstrumentAdded { instrument }),
+            (Some(_), None) => differences.push(Difference::InstrumentRemoved { instrument }),
+            (None, None) => {}
+        }
+    }
+
+    for (pattern, old_pattern, new_pattern) in pairs(&old.patterns, &new.patterns, PatternId::from_index) {
+        match (old_pattern, new_pattern) {
+            (Some(old_pattern), Some(new_pattern)) => {
+                diff_pattern(&mut differences, pattern, old_pattern, new_pattern);
+            }
+            (None, Some(_)) => differences.push(Difference::PatternAdded { pattern }),
+            (Some(_), None) => differences.push(Difference::PatternRemoved { pattern }),
+            (None, None) => {}
+        }
+    }
+
+    differences
+}
+
+fn diff_pattern(differences: &mut Vec<Difference>, pattern: PatternId, old: &Pattern, new: &Pattern) {
+    if old.rows.len() != new.rows.len() {
+        differences.push(Difference::PatternRows {
+            pattern,
+            old: old.rows.len(),
+            new: new.rows.len(),
+        });
+    }
+
+    for (row, (old_row, new_row)) in old.rows.iter().zip(new.rows.iter()).enumerate() {
+        if old_row == new_row {
+            continue;
+        }
+        for idx in 0..64 {
+            let channel = Channel::from_u8_index(idx);
+            let (old_cell, new_cell) = (old_row.get(channel), new_row.get(channel));
+            if old_cell != new_cell {
+                differences.push(Difference::Cell {
+                    pattern,
+                    row,
+                    channel,
+                    old: old_cell.copied(),
+                    new: new_cell.copied(),
+                });
+            }
+        }
+    }
+}
+
+/// Pairs up items of two lists by their position, the shorter list is padded with `None`.
+///
+/// Positions which can't be represented by the ID type (only possible in invalid modules) are
+/// skipped.
+fn pairs<'m, T, I, E>(
+    old: &'m [T],
+    new: &'m [T],
+    id: impl Fn(u8) -> Result<I, E> + 'm,
+) -> impl Iterator<Item = (I, Option<&'m T>, Option<&'m T>)> + 'm {
+    (0..old.len().max(new.len()))
+        .map_while(move |idx| {
+            let id = id(u8::try_from(idx).ok()?).ok()?;
+            Some((id, old.get(idx), new.get(idx)))
+        })
+}
+
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn cell(f: &mut fmt::Formatter, cell: &Option<Command>) -> fmt::Result {
+            match cell {
+                Some(command) => Display::fmt(command, f),
+                None => Display::fmt(&Command::EMPTY, f),
+            }
+        }
+
+        match self {
+            Difference::Header { field } => write!(f, "header field `{field}` changed"),
+            Difference::Channel { channel } => write!(f, "channel {} settings changed", channel.number()),
+            Difference::Order { order, old, new } => write!(f, "order {order:?} changed: {old:?} -> {new:?}"),
+            Difference::SampleAdded { sample } => write!(f, "sample {sample} added"),
+            Difference::SampleRemoved { sample } => write!(f, "sample {sample} removed"),
+            Difference::SampleModified { sample, fields } => {
+                write!(f, "sample {sample} changed: {}", fields.join(", "))
+            }
+            Difference::InstrumentAdded { instrument } => write!(f, "instrument {instrument} added"),
+            Difference::InstrumentRemoved { instrument } => write!(f, "instrument {instrument} removed"),
+            Difference::InstrumentModified { instrument, fields } => {
+                write!(f, "instrument {instrument} changed: {}", fields.join(", "))
+            }
+            Difference::PatternAdded { pattern } => write!(f, "pattern {pattern:?} added"),
+            Difference::PatternRemoved { pattern } => write!(f, "pattern {pattern:?} removed"),
+            Difference::PatternRows { pattern, old, new } => {
+                write!(f, "pattern {pattern:?} rows changed: {old} -> {new}")
+            }
+            Difference::Cell { pattern, row, channel, old, new } => {
+                write!(f, "pattern {pattern:?} row {row} channel {}: ", channel.number())?;
+                cell(f, old)?;
+                f.write_str(" -> ")?;
+                cell(f, new)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn diff_changes() {
+        const MODULE_DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+
+        let old = parser::module_file::<VerboseError<&[u8]>>(MODULE_DATA).unwrap();
+        assert_eq!(diff(&old, &old), Vec::new());
+
+        let mut new = old.clone();
+        new.set_speed(1).unwrap();
+        new.patterns[0].rows[0] = Row::empty();
+        new.samples.clear();
+
+        let differences = diff(&old, &new);
+        assert_eq!(differences[0], Difference::Header { field: "speed" });
+        assert!(differences.iter().any(|d| matches!(d, Difference::Cell { row: 0, .. })));
+        assert_eq!(
+            differences.iter().filter(|d| matches!(d, Difference::SampleRemoved { .. })).count(),
+            old.samples.len(),
+        );
+    }
+}
@@ -3,13 +3,18 @@
 //! This module reimplements/modifies a lot of the default `nom` error behaviour to make it
 //! actually suitable for debugging a binary parser such as this. It may be slower than the nom
 //! version but what use is a parser that's fast but gives useless output..
+//!
+//! When only success matters, e.g. scanning a large collection for valid files, the parsers also
+//! accept `()` and [`nom::error::Error`] as the error type. They drop the context and skip
+//! collecting the error trace, which makes failing files cheaper to reject.
 
 use nom::error::{ErrorKind, ParseError};
 use nom::{Err, IResult};
 use nom::{Offset, Parser};
-use std::borrow::Cow;
-use std::fmt::{self, Debug, Display, Write};
-use std::iter;
+use crate::prelude::*;
+use alloc::borrow::Cow;
+use core::fmt::{self, Debug, Display, Write};
+use core::iter;
 
 pub use crate::parser::scan::ScanError;
 
@@ -23,7 +28,254 @@ impl<const LOW: u8, const HIGH: u8> Display for OutOfRangeError<LOW, HIGH> {
     }
 }
 
-impl<const LOW: u8, const HIGH: u8> std::error::Error for OutOfRangeError<LOW, HIGH> {}
+impl<const LOW: u8, const HIGH: u8> core::error::Error for OutOfRangeError<LOW, HIGH> {}
+
+
+/// Error returned when a [`SampleLoop`](crate::SampleLoop) doesn't fit the sample it's set on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvalidLoopError {
+    /// Loop start is not before the loop end.
+    Empty { start: u32, end: u32 },
+
+    /// Loop end is past the end of the sample data.
+    OutOfBounds { end: u32, length: u32 },
+
+    /// Bidirectional loop is shorter than 2 samples.
+    BidiTooShort { start: u32, end: u32 },
+}
+
+impl Display for InvalidLoopError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidLoopError::Empty { start, end } => {
+                write!(f, "loop start {} must be before loop end {}", start, end)
+            }
+            InvalidLoopError::OutOfBounds { end, length } => {
+                write!(f, "loop end {} is past the sample length {}", end, length)
+            }
+            InvalidLoopError::BidiTooShort { start, end } => {
+                write!(f, "bidirectional loop {}..{} must be at least 2 samples long", start, end)
+            }
+        }
+    }
+}
+
+impl core::error::Error for InvalidLoopError {}
+
+
+/// Error returned when a [`SampleMap`](crate::SampleMap) references a sample which doesn't exist.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MissingSampleError {
+    /// Note which maps to the missing sample.
+    pub note: crate::Note,
+
+    /// The missing sample.
+    pub sample: crate::SampleId,
+}
+
+impl Display for MissingSampleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "note {} maps to sample {} which doesn't exist", self.note, self.sample)
+    }
+}
+
+impl core::error::Error for MissingSampleError {}
+
+
+/// Error returned when a module doesn't fit into the limits of the IT file format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WriteError {
+    /// List of orders, instruments, samples or patterns is longer than the format allows.
+    TooManyItems { kind: &'static str, count: usize, max: usize },
+
+    /// Pattern has more rows than can be stored.
+    TooManyRows { pattern: crate::PatternId, rows: usize },
+
+    /// Packed pattern data is longer than 65535 bytes.
+    PatternTooLarge { pattern: crate::PatternId, size: usize },
+
+    /// Envelope has more than 25 nodes.
+    ///
+    /// `instrument` is `None` when writing an instrument file.
+    TooManyNodes { instrument: Option<crate::InstrumentId>, nodes: usize },
+
+    /// Encoded song message is longer than 65535 bytes.
+    MessageTooLong { length: usize },
+
+    /// File would be larger than 4 GiB, offsets can't point past that.
+    FileTooLarge { size: usize },
+
+    /// Sample data kept by [`parser::module_file_lazy`](crate::parser::module_file_lazy) can't be
+    /// decoded, e.g. because the file was truncated.
+    ///
+    /// `sample` is `None` when writing an instrument or a sample file.
+    InvalidSampleData { sample: Option<crate::SampleId> },
+
+    /// Offset in the written header points outside of the file.
+    ///
+    /// The writer checks its layout before writing anything, this is a bug in the writer rather
+    /// than a limit of the format.
+    InvalidOffset { structure: &'static str, offset: usize, length: usize, size: usize },
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::TooManyItems { kind, count, max } => {
+                write!(f, "module has {} {}, at most {} can be stored", count, kind, max)
+            }
+            WriteError::TooManyRows { pattern, rows } => {
+                write!(f, "pattern {:?} has {} rows, at most 65535 can be stored", pattern, rows)
+            }
+            WriteError::PatternTooLarge { pattern, size } => {
+                write!(f, "pattern {:?} packs into {} bytes, at most 65535 can be stored", pattern, size)
+            }
+            WriteError::TooManyNodes { instrument: Some(instrument), nodes } => {
+                write!(f, "instrument {} has an envelope with {} nodes, at most 25 can be stored", instrument, nodes)
+            }
+            WriteError::TooManyNodes { instrument: None, nodes } => {
+                write!(f, "instrument has an envelope with {} nodes, at most 25 can be stored", nodes)
+            }
+            WriteError::MessageTooLong { length } => {
+                write!(f, "song message encodes into {} bytes, at most 65535 can be stored", length)
+            }
+            WriteError::FileTooLarge { size } => {
+                write!(f, "file would be {} bytes long, offsets can't point past 4 GiB", size)
+            }
+            WriteError::InvalidSampleData { sample: Some(sample) } => {
+                write!(f, "stored data of sample {} can't be decoded", sample)
+            }
+            WriteError::InvalidSampleData { sample: None } => write!(f, "stored sample data can't be decoded"),
+            WriteError::InvalidOffset { structure, offset, length, size } => {
+                write!(f, "{} at {:#x} with length {} is outside of the file ({} bytes)", structure, offset, length, size)
+            }
+        }
+    }
+}
+
+impl core::error::Error for WriteError {}
+
+
+/// Error returned when writing a file into an [`io::Write`](std::io::Write)
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum StreamWriteError {
+    /// Module doesn't fit into the file format, nothing has been written.
+    Format(WriteError),
+
+    /// Writing failed, a part of the file may have been written.
+    Io(std::io::Error),
+}
+
+#[cfg(feature = "std")]
+impl From<WriteError> for StreamWriteError {
+    fn from(err: WriteError) -> StreamWriteError {
+        StreamWriteError::Format(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for StreamWriteError {
+    fn from(err: std::io::Error) -> StreamWriteError {
+        StreamWriteError::Io(err)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Display for StreamWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StreamWriteError::Format(err) => Display::fmt(err, f),
+            StreamWriteError::Io(err) => write!(f, "writing failed: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl core::error::Error for StreamWriteError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            StreamWriteError::Format(err) => Some(err),
+            StreamWriteError::Io(err) => Some(err),
+        }
+    }
+}
+
+
+/// Error returned when [`Pattern::from_text`](crate::Pattern::from_text) can't parse its input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PatternTextError {
+    /// Line number (starting from 1) the error occurred on.
+    pub line: usize,
+
+    /// Description of the problem.
+    pub reason: &'static str,
+}
+
+impl Display for PatternTextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid pattern text on line {}: {}", self.line, self.reason)
+    }
+}
+
+impl core::error::Error for PatternTextError {}
+
+
+/// Error returned when [`json::from_json`](crate::json::from_json) can't load a module.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum JsonError {
+    /// Text is not valid JSON or doesn't describe a module.
+    Syntax(serde_json::Error),
+
+    /// Sample data stored in an external file couldn't be loaded.
+    MissingFile { sample: usize, file: String },
+
+    /// External 16-bit sample data has an odd length.
+    InvalidFile { sample: usize, file: String },
+
+    /// Sample loop doesn't fit the sample data.
+    InvalidLoop { sample: usize, error: InvalidLoopError },
+
+    /// Keyboard table of an instrument references a sample which doesn't exist.
+    MissingSample { instrument: usize, error: MissingSampleError },
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for JsonError {
+    fn from(err: serde_json::Error) -> JsonError {
+        JsonError::Syntax(err)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonError::Syntax(err) => write!(f, "invalid module JSON: {}", err),
+            JsonError::MissingFile { sample, file } => {
+                write!(f, "data of sample {} couldn't be loaded from {:?}", sample, file)
+            }
+            JsonError::InvalidFile { sample, file } => {
+                write!(f, "data of sample {} loaded from {:?} has an odd length for 16-bit samples", sample, file)
+            }
+            JsonError::InvalidLoop { sample, error } => write!(f, "sample {}: {}", sample, error),
+            JsonError::MissingSample { instrument, error } => write!(f, "instrument {}: {}", instrument, error),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl core::error::Error for JsonError {
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        match self {
+            JsonError::Syntax(err) => Some(err),
+            JsonError::InvalidLoop { error, .. } => Some(error),
+            JsonError::MissingSample { error, .. } => Some(error),
+            JsonError::MissingFile { .. } | JsonError::InvalidFile { .. } => None,
+        }
+    }
+}
 
 
 /// This error type accumulates errors and their position when backtracking
@@ -81,6 +333,24 @@ impl<I> ContextError<I> for VerboseError<I> {
     }
 }
 
+/// Discards the context, for parsing with `()` errors when only success matters
+impl<I> ContextError<I> for () {
+    fn add_context(_input: I, _ctx: Cow<'static, str>, _other: Self) -> Self {}
+
+    fn new(_input: I, _ctx: Cow<'static, str>) -> Self {}
+}
+
+/// Keeps the innermost error and discards the context
+impl<I> ContextError<I> for nom::error::Error<I> {
+    fn add_context(_input: I, _ctx: Cow<'static, str>, other: Self) -> Self {
+        other
+    }
+
+    fn new(input: I, _ctx: Cow<'static, str>) -> Self {
+        nom::error::Error::new(input, ErrorKind::Fail)
+    }
+}
+
 /// Create a new error from an input position, a static string and an existing error.
 /// This is used mainly in the [context!] combinator, to add user friendly information
 /// to errors when backtracking through a parse tree
@@ -103,31 +373,31 @@ where
 
 macro_rules! context {
     ( $parser: expr, $msg: literal $(,)? ) => {
-        $crate::error::context(move || ::std::borrow::Cow::Borrowed($msg), $parser)
+        $crate::error::context(move || ::alloc::borrow::Cow::Borrowed($msg), $parser)
     };
     ( $parser: expr, $fmt: literal $(, $args: expr )+ $(,)? ) => {
-        $crate::error::context(move || ::std::borrow::Cow::Owned(::std::format!($fmt, $($args),+)), $parser)
+        $crate::error::context(move || ::alloc::borrow::Cow::Owned(::alloc::format!($fmt, $($args),+)), $parser)
     };
     ( $parser: expr, $payload: expr $(,)? ) => {
-        $crate::error::context(move || ::std::borrow::Cow::Owned($payload.to_string()), $parser)
+        $crate::error::context(move || ::alloc::borrow::Cow::Owned($payload.to_string()), $parser)
     };
 }
 
 macro_rules! error {
     ( $input: expr, $msg: literal $(,)? ) => {
-        E::new($input, ::std::borrow::Cow::Borrowed($msg))
+        E::new($input, ::alloc::borrow::Cow::Borrowed($msg))
     };
     ( $input: expr, $fmt: literal $(, $args: expr )+ $(,)? ) => {
-        E::new($input, ::std::borrow::Cow::Owned(::std::format!($fmt, $($args),+)))
+        E::new($input, ::alloc::borrow::Cow::Owned(::alloc::format!($fmt, $($args),+)))
     };
     ( $input: expr, $payload: expr $(,)? ) => {
-        E::new($input, ::std::borrow::Cow::Owned($payload.to_string()))
+        E::new($input, ::alloc::borrow::Cow::Owned($payload.to_string()))
     };
 }
 
 macro_rules! bail {
     ($($tt:tt)*) => {
-        return ::std::result::Result::Err(::nom::Err::Error(error!($($tt)*)))
+        return ::core::result::Result::Err(::nom::Err::Error(error!($($tt)*)))
     };
 }
 
@@ -0,0 +1,336 @@
+//! C API
+//!
+//! Enabled by the feature `ffi`, the functions are declared in `include/ittech.h`. The manifest
+//! doesn't declare a `cdylib` so that depending on the crate, including `no_std` builds, doesn't
+//! build one, request the shared library with
+//! `cargo rustc --release --lib --crate-type cdylib --features ffi`.
+//!
+//! Modules and players are opaque pointers created by [`ittech_module_parse`] and
+//! [`ittech_player_new`] and released with [`ittech_module_free`] and [`ittech_player_free`]. A
+//! player keeps its own reference to the module, so the module can be freed while it plays.
+//!
+//! Strings are copied into buffers provided by the caller like `snprintf` does it: the return
+//! value is the length of the whole string and at most `size - 1` bytes followed by a NUL are
+//! written. Error messages are allocated and released with [`ittech_string_free`].
+//!
+//! Functions accept null pointers for the module and the player and return zero, `false` or null
+//! for them.
+
+use crate::error::{convert_error, VerboseError};
+use crate::player::{Player, PlayerOptions};
+use crate::{parser, Module, Order};
+use nom::Err;
+use std::ffi::{c_char, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+
+/// Parsed module, `ItModule` in C
+pub struct ItModule {
+    module: Arc<Module>,
+}
+
+/// Player of a module, `ItPlayer` in C
+pub struct ItPlayer {
+    player: Player,
+}
+
+
+/// Parses the module file in `data[..len]`, returns null if it's invalid.
+///
+/// When parsing fails and `error` isn't null, `*error` is set to a message describing the
+/// problem, free it with [`ittech_string_free`].
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes and `error` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_parse(data: *const u8, len: usize, error: *mut *mut c_char) -> *mut ItModule {
+    let input: &[u8] = if len == 0 { &[] } else { slice::from_raw_parts(data, len) };
+    // The parser panics on some structures it doesn't support yet, unwinding into C is undefined.
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        match parser::module_file::<VerboseError<_>>(input) {
+            Ok(module) => Ok(module),
+            Err(Err::Error(e)) | Err(Err::Failure(e)) => Err(convert_error(input, e)),
+            Err(Err::Incomplete(_)) => unreachable!(),
+        }
+    }));
+    let message = match result {
+        Ok(Ok(module)) => return Box::into_raw(Box::new(ItModule { module: Arc::new(module) })),
+        Ok(Err(message)) => message,
+        Err(_) => String::from("the parser panicked, the file uses an unsupported feature"),
+    };
+    if !error.is_null() {
+        // The messages don't contain NUL bytes, but don't trust the input which ends up in them.
+        *error = CString::new(message.replace('\0', " ")).unwrap().into_raw();
+    }
+    ptr::null_mut()
+}
+
+/// Frees a module returned by [`ittech_module_parse`], null is ignored.
+///
+/// # Safety
+///
+/// `module` must be null or returned by [`ittech_module_parse`] and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_free(module: *mut ItModule) {
+    if !module.is_null() {
+        drop(Box::from_raw(module));
+    }
+}
+
+/// Frees an error message, null is ignored.
+///
+/// # Safety
+///
+/// `string` must be null or a message returned by this library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Copies the song name as UTF-8 into `buf[..size]`, returns its length in bytes.
+///
+/// # Safety
+///
+/// `module` must be null or valid and `buf` must be null or point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_title(module: *const ItModule, buf: *mut c_char, size: usize) -> usize {
+    match module.as_ref() {
+        Some(module) => copy_string(&module.module.name.decode(), buf, size),
+        None => 0,
+    }
+}
+
+/// Copies the song message as UTF-8 into `buf[..size]`, returns its length in bytes.
+///
+/// # Safety
+///
+/// `module` must be null or valid and `buf` must be null or point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_message(module: *const ItModule, buf: *mut c_char, size: usize) -> usize {
+    match module.as_ref() {
+        Some(module) => copy_string(&module.module.message, buf, size),
+        None => 0,
+    }
+}
+
+/// Returns the initial speed in ticks per row.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_speed(module: *const ItModule) -> u8 {
+    module.as_ref().map_or(0, |module| module.module.speed.as_u8())
+}
+
+/// Returns the initial tempo in BPM.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_tempo(module: *const ItModule) -> u8 {
+    module.as_ref().map_or(0, |module| module.module.tempo.as_u8())
+}
+
+/// Returns the number of pattern entries in the orders list.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_order_count(module: *const ItModule) -> usize {
+    module.as_ref().map_or(0, |module| {
+        module.module.orders.iter().filter(|order| matches!(order, Order::Index(_))).count()
+    })
+}
+
+/// Returns the number of patterns.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_pattern_count(module: *const ItModule) -> usize {
+    module.as_ref().map_or(0, |module| module.module.patterns.len())
+}
+
+/// Returns the number of instruments.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_instrument_count(module: *const ItModule) -> usize {
+    module.as_ref().map_or(0, |module| module.module.instruments.len())
+}
+
+/// Returns the number of samples.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_sample_count(module: *const ItModule) -> usize {
+    module.as_ref().map_or(0, |module| module.module.samples.len())
+}
+
+/// Returns the playing time in seconds until the song ends or starts repeating.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_duration(module: *const ItModule) -> f64 {
+    module.as_ref().map_or(0.0, |module| module.module.duration().seconds)
+}
+
+/// Creates a player of `module` rendering at `sample_rate` Hz, returns null if the sample rate is
+/// zero.
+///
+/// The player stops when the song ends or starts repeating.
+///
+/// # Safety
+///
+/// `module` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_player_new(module: *const ItModule, sample_rate: u32) -> *mut ItPlayer {
+    match module.as_ref() {
+        Some(module) if sample_rate > 0 => {
+            let options = PlayerOptions { sample_rate, ..PlayerOptions::default() };
+            let player = Player::new(Arc::clone(&module.module), options);
+            Box::into_raw(Box::new(ItPlayer { player }))
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+/// Frees a player returned by [`ittech_player_new`], null is ignored.
+///
+/// # Safety
+///
+/// `player` must be null or returned by [`ittech_player_new`] and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_player_free(player: *mut ItPlayer) {
+    if !player.is_null() {
+        drop(Box::from_raw(player));
+    }
+}
+
+/// Renders up to `frames` interleaved stereo frames into `out`, returns the number of rendered
+/// frames, fewer once the song has ended and 0 if the player panicked.
+///
+/// # Safety
+///
+/// `player` must be null or valid and `out` must point to `2 * frames` writable floats.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_player_render_f32(player: *mut ItPlayer, out: *mut f32, frames: usize) -> usize {
+    match player.as_mut() {
+        Some(player) if frames > 0 => {
+            let out = slice::from_raw_parts_mut(out, 2 * frames);
+            // Like the parser the player must never unwind into C.
+            panic::catch_unwind(AssertUnwindSafe(|| player.player.render_f32(out))).unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Renders like [`ittech_player_render_f32`] as 16-bit samples.
+///
+/// # Safety
+///
+/// `player` must be null or valid and `out` must point to `2 * frames` writable samples.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_player_render_i16(player: *mut ItPlayer, out: *mut i16, frames: usize) -> usize {
+    match player.as_mut() {
+        Some(player) if frames > 0 => {
+            let out = slice::from_raw_parts_mut(out, 2 * frames);
+            panic::catch_unwind(AssertUnwindSafe(|| player.player.render_i16(out))).unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+/// Stores the order and row being played, returns `false` once the song has ended.
+///
+/// # Safety
+///
+/// `player` must be null or valid, `order` and `row` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_player_position(player: *const ItPlayer, order: *mut usize, row: *mut usize) -> bool {
+    let position = match player.as_ref().and_then(|player| player.player.position()) {
+        Some(position) => position,
+        None => return false,
+    };
+    if !order.is_null() {
+        *order = position.order.as_usize();
+    }
+    if !row.is_null() {
+        *row = position.row;
+    }
+    true
+}
+
+/// Returns `true` once the song has ended.
+///
+/// # Safety
+///
+/// `player` must be null or valid.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_player_is_finished(player: *const ItPlayer) -> bool {
+    player.as_ref().map_or(true, |player| player.player.is_finished())
+}
+
+
+/// Copies `string` into `buf[..size]` NUL-terminated, returns the length of `string`.
+unsafe fn copy_string(string: &str, buf: *mut c_char, size: usize) -> usize {
+    if !buf.is_null() && size > 0 {
+        let len = string.len().min(size - 1);
+        ptr::copy_nonoverlapping(string.as_ptr().cast::<c_char>(), buf, len);
+        *buf.add(len) = 0;
+    }
+    string.len()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn parse_and_render() {
+        let data = include_bytes!("../tests/song_message.it");
+        unsafe {
+            let module = ittech_module_parse(data.as_ptr(), data.len(), ptr::null_mut());
+            assert!(!module.is_null());
+            assert_eq!(ittech_module_pattern_count(module), 1);
+
+            let mut title = [0; 8];
+            let len = ittech_module_title(module, title.as_mut_ptr(), title.len());
+            let expected = (*module).module.name.decode();
+            assert_eq!(len, expected.len());
+            assert_eq!(CStr::from_ptr(title.as_ptr()).to_bytes(), &expected.as_bytes()[..len.min(7)]);
+
+            let player = ittech_player_new(module, 44100);
+            ittech_module_free(module);
+            let mut out = [0.0f32; 256];
+            assert_eq!(ittech_player_render_f32(player, out.as_mut_ptr(), 128), 128);
+            let (mut order, mut row) = (usize::MAX, usize::MAX);
+            assert!(ittech_player_position(player, &mut order, &mut row));
+            assert_eq!(order, 0);
+            ittech_player_free(player);
+
+            let mut error = ptr::null_mut();
+            assert!(ittech_module_parse(data.as_ptr(), 10, &mut error).is_null());
+            assert!(!CStr::from_ptr(error).to_bytes().is_empty());
+            ittech_string_free(error);
+        }
+    }
+}
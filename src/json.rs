@@ -0,0 +1,199 @@
+//! JSON representation of modules
+//!
+//! [`to_json`] dumps the whole [`Module`] as pretty-printed JSON meant for keeping modules in git
+//! and for web frontends, [`from_json`] loads it back. See the `serde` implementations of the
+//! data types for the details of the format; notes are written as `"C-5"`, identifiers as 0 based
+//! indices into the module lists and rows list only the used channels.
+//!
+//! Sample data is either embedded as base64 encoded little endian PCM
+//! (`{"format": "pcm16", "base64": "..."}`), or written into separate raw files referenced from
+//! the JSON (`{"format": "pcm16", "file": "sample_01.raw"}`). External files keep the JSON small
+//! and the diffs readable when only the patterns change.
+
+use crate::data::serde::{sample_bytes, sample_data, SampleFormat};
+use crate::error::JsonError;
+use crate::*;
+use serde_json::{json, Value};
+
+
+/// Where [`to_json`] puts the sample data
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SampleStorage {
+    /// Sample data is embedded in the JSON, base64 encoded
+    #[default]
+    Embedded,
+
+    /// Sample data is returned as separate files referenced from the JSON
+    External,
+}
+
+/// Module serialized by [`to_json`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Json {
+    /// The JSON text
+    pub text: String,
+
+    /// Files with the sample data as `(file name, raw little endian PCM)`, empty for
+    /// [`SampleStorage::Embedded`]
+    pub samples: Vec<(String, Vec<u8>)>,
+}
+
+/// Serializes the module into JSON.
+pub fn to_json(module: &Module, storage: SampleStorage) -> Json {
+    let (value, samples) = match storage {
+        SampleStorage::Embedded => (to_value(module), Vec::new()),
+        SampleStorage::External => {
+            let mut stripped = module.clone();
+            let data = stripped.samples
+                .iter_mut()
+                .map(|sample| sample.data.take())
+                .collect::<Vec<_>>();
+            let mut value = to_value(&stripped);
+
+            let mut samples = Vec::new();
+            for (idx, data) in data.iter().enumerate() {
+                let Some(data) = data else { continue };
+                let file = format!("sample_{:02}.raw", idx + 1);
+                value["samples"][idx]["data"] = json!({
+                    "format": SampleFormat::of(data),
+                    "file": file,
+                });
+                samples.push((file, sample_bytes(data)));
+            }
+            (value, samples)
+        }
+    };
+    let text = serde_json::to_string_pretty(&value)
+        .unwrap_or_else(|err| unreachable!("BUG: module JSON failed to format: {err}"));
+    Json { text, samples }
+}
+
+fn to_value(module: &Module) -> Value {
+    // Every part of the module serializes into plain JSON values, there's nothing to fail on.
+    serde_json::to_value(module)
+        .unwrap_or_else(|err| unreachable!("BUG: module failed to serialize: {err}"))
+}
+
+/// Loads a module from JSON written by [`to_json`].
+///
+/// `load` is called with the file names of externally stored sample data and returns their
+/// contents, or `None` if the file is missing. Sample loops and keyboard tables are validated
+/// against the loaded samples.
+pub fn from_json(text: &str, mut load: impl FnMut(&str) -> Option<Vec<u8>>) -> Result<Module, JsonError> {
+    let mut value = serde_json::from_str::<Value>(text)?;
+
+    let mut external = Vec::new();
+    if let Some(samples) = value.get_mut("samples").and_then(Value::as_array_mut) {
+        for (idx, sample) in samples.iter_mut().enumerate() {
+            let Some(data) = sample.get_mut("data") else { continue };
+            let Some(file) = data.get("file").and_then(Value::as_str).map(str::to_owned) else { continue };
+            let format = serde_json::from_value::<SampleFormat>(data["format"].take())?;
+            let bytes = load(&file).ok_or_else(|| JsonError::MissingFile { sample: idx, file: file.clone() })?;
+            let loaded = sample_data(format, &bytes).ok_or(JsonError::InvalidFile { sample: idx, file })?;
+            *data = Value::Null;
+            external.push((idx, loaded));
+        }
+    }
+
+    let mut module = serde_json::from_value::<Module>(value)?;
+    for (idx, data) in external {
+        module.samples[idx].data = Some(data);
+    }
+
+    for (idx, sample) in module.samples.iter().enumerate() {
+        for sample_loop in sample.loop_.iter().chain(&sample.sustain_loop) {
+            sample_loop
+                .validate(sample.length())
+                .map_err(|error| JsonError::InvalidLoop { sample: idx, error })?;
+        }
+    }
+    for (idx, instrument) in module.instruments.iter().enumerate() {
+        instrument.sample_map
+            .validate(module.samples.len())
+            .map_err(|error| JsonError::MissingSample { instrument: idx, error })?;
+    }
+
+    Ok(module)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use std::collections::HashMap;
+
+    fn module() -> Module {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.orders = vec![Order::Index(PatternId::from_index(0).unwrap()), Order::EndOfSong];
+        module.patterns = vec![Pattern {
+            active_channels: ActiveChannels::new([Channel::new(1)]),
+            rows: vec![
+                Row::from_vec(vec![(Channel::new(1), Command {
+                    note: Some(NoteCmd::Play(Note::C_5)),
+                    instrument: Some(InstrumentId::from_index(0).unwrap()),
+                    volume: None,
+                    effect: Some(EffectCmd::SetSpeed(RangedU8::new(3))),
+                })]),
+                Row::empty(),
+            ],
+        }];
+        let mut sample = Sample {
+            name: Name::new("Kick"),
+            filename: DosFilename::sanitize("kick.wav"),
+            global_volume: 64,
+            default_volume: 64,
+            default_panning: 32,
+            loop_: None,
+            sustain_loop: None,
+            samplerate_c5: 8363,
+            vibrato_speed: 0,
+            vibrato_depth: 0,
+            vibrato_rate: 0,
+            vibrato_type: 0,
+            data: Some(SampleData::from(vec![0i16, 1000, -1000, i16::MAX, i16::MIN])),
+            lazy_data: None,
+        };
+        sample.set_loop(Some(SampleLoop { start: 1, end: 5, bidi: false })).unwrap();
+        module.samples = vec![sample.clone(), Sample { data: Some(SampleData::from(vec![-1i8, 2, 3])), loop_: None, ..sample }];
+        module.instruments.clear();
+        module
+    }
+
+    #[test]
+    fn roundtrip_embedded() {
+        let module = module();
+        let json = to_json(&module, SampleStorage::Embedded);
+        assert!(json.samples.is_empty());
+        assert!(json.text.contains(r#""Play": "C-5""#));
+        assert!(json.text.contains(r#""base64": "/wID""#));
+        assert_eq!(from_json(&json.text, |_| None).unwrap(), module);
+    }
+
+    #[test]
+    fn roundtrip_external() {
+        let module = module();
+        let json = to_json(&module, SampleStorage::External);
+        assert!(!json.text.contains("base64"));
+        assert_eq!(json.samples[0].0, "sample_01.raw");
+        assert_eq!(json.samples[0].1.len(), 10);
+        assert_eq!(json.samples[1].1, [0xFF, 2, 3]);
+
+        let files = json.samples.into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(from_json(&json.text, |file| files.get(file).cloned()).unwrap(), module);
+        assert!(matches!(
+            from_json(&json.text, |_| None),
+            Err(JsonError::MissingFile { sample: 0, .. }),
+        ));
+    }
+
+    #[test]
+    fn invalid() {
+        let module = module();
+        let json = to_json(&module, SampleStorage::Embedded).text;
+
+        assert!(matches!(from_json("{}", |_| None), Err(JsonError::Syntax(_))));
+        let short = json.replace(r#""end": 5"#, r#""end": 6"#);
+        assert!(matches!(from_json(&short, |_| None), Err(JsonError::InvalidLoop { sample: 0, .. })));
+    }
+}
@@ -7,6 +7,7 @@
 #![warn(clippy::cast_precision_loss)]
 #![warn(clippy::cast_sign_loss)]
 #![warn(clippy::unnecessary_cast)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! # Impulse Tracker module file parser and writer
 //!
@@ -16,9 +17,9 @@
 //! >
 //! > -- Wikipedia ([article link](https://en.wikipedia.org/wiki/Impulse_Tracker))
 //!
-//! This crate is a parser (and in the near future also hopefuly a writer) for the Impulse Tracker
-//! native module file format. The Rust representation attempts to both express the module file in
-//! a manner that's lossless for valid files and that forbids creating invalid files.
+//! This crate is a parser and writer for the Impulse Tracker native module file format. The Rust
+//! representation attempts to both express the module file in a manner that's lossless for valid
+//! files and that forbids creating invalid files.
 //!
 //!
 //! # Lossy parsing and canonicalization
@@ -34,13 +35,47 @@
 //! input is lost and should explain what value was found, what is wrong with it and how it has
 //! been fixed. The canonicalization logic is documented under the "Canonicalization" section on
 //! each specific value type. Please report issues with any inconsistencies between the parsed
-//! results of and the documentation.
+//! results of and the documentation. [`parser::module_file_with_repairs`] returns the same
+//! information as a list, with or without the feature.
+//!
+//! If the feature `arbitrary` is enabled, the module tree implements `arbitrary::Arbitrary`. The
+//! generated modules are structurally valid, so they can be used for fuzzing and round-trip
+//! property tests.
+//!
+//! If the feature `serde` is enabled, the module tree implements `serde::Serialize` and
+//! `serde::Deserialize`, and the [`json`] module can dump modules to JSON and load them back.
+//!
+//! If the feature `rodio` is enabled, `player::ItSource` plays modules as a `rodio::Source`.
+//!
+//! If the feature `rayon` is enabled, the samples of a module are decoded in parallel, which
+//! speeds up loading modules with many compressed samples.
+//!
+//! If the feature `bumpalo` is enabled, `parser::module_file_in` parses the patterns of a module
+//! into a `bumpalo::Bump` arena.
+//!
+//! The crate builds for `wasm32-unknown-unknown`. If the feature `wasm` is enabled, the `wasm`
+//! module exports a `wasm-bindgen` API for parsing, reading the metadata and rendering modules
+//! from JavaScript.
+//!
+//! If the feature `ffi` is enabled, the `ffi` module exports a C API for parsing, querying and
+//! rendering modules, declared in `include/ittech.h`.
+//!
+//! If the feature `python` is enabled, the `python` module is a `pyo3` extension module exposing
+//! parsing, the metadata and the patterns to Python.
+//!
+//! The feature `std` is enabled by default. Without it the crate is `no_std` and only needs
+//! `alloc`: the data model, [`cp437`] and the module, instrument and sample parsers are available.
+//! The writer, the player, the converters and the analysis need `std`, as do
+//! [`parser::module_file_lazy`], [`parser::module_file_with_repairs`], the WAV and AIFF parsers and
+//! formatting patterns as text. All the other features enable `std`.
 //!
 //!
 //! ## Structure and modfile representation
 //!
 //! The general structure of a complete modfile (.it) can be simplified to this self-referencing tree.
-//! Complete modfiles are parsed using the [`parser::module_file`] function.
+//! Complete modfiles are parsed using the [`parser::module_file`] function and written using the
+//! [`writer::module_file`] function. [`parser::byte_map`] tells which structure each byte of a
+//! modfile belongs to.
 //!
 //! ```txt
 //! Module
@@ -78,6 +113,14 @@
 //! Samples can also exist in their own files (.its) as just a lone [`Sample`]. These files are
 //! parsed using the [`parser::sample_file`] function.
 //!
+//! Modules can be converted to other tracker formats with the [`convert`] modules, e.g.
+//! [`convert::xm::export`].
+//!
+//! Modules can be played and rendered to PCM audio with [`player::Player`].
+//!
+//! Unused patterns, instruments and samples and the silence at the end of samples can be removed
+//! with [`optimize::optimize`].
+//!
 //!
 //! ## Additional resources
 //!
@@ -94,6 +137,17 @@
 #[doc = include_str!("../ITTECH.txt")]
 pub mod ittech_txt {}
 
+extern crate alloc;
+
+/// Items of the `std` prelude which have to be imported from `alloc` without `std`
+mod prelude {
+    pub(crate) use alloc::borrow::ToOwned;
+    pub(crate) use alloc::boxed::Box;
+    pub(crate) use alloc::string::{String, ToString};
+    pub(crate) use alloc::vec::Vec;
+    pub(crate) use alloc::{format, vec};
+}
+
 #[macro_use]
 // Macro exporting is still weird. We want the macros to be `pub(crate)`, the combination of
 // `#[macro_use]`, the module containing them being lexically first, never importing the macros
@@ -103,7 +157,29 @@ pub mod error;
 mod data;
 pub use data::*;
 
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
+pub mod convert;
+pub mod cp437;
+#[cfg(feature = "std")]
+pub mod diff;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "serde")]
+pub mod json;
+#[cfg(feature = "std")]
+pub mod metadata;
+#[cfg(feature = "std")]
+pub mod optimize;
 pub mod parser;
+#[cfg(feature = "std")]
+pub mod player;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "std")]
 pub mod writer;
 
 pub use parser::scan::FileType;
@@ -0,0 +1,269 @@
+//! Descriptive metadata of modules
+//!
+//! [`Metadata`] collects what's useful for cataloguing a module (title, message, tempo, channel
+//! count, sample names and lengths) without the pattern and sample data. It can be written as a
+//! small TOML or YAML document with [`Metadata::to_toml`] and [`Metadata::to_yaml`], both formats
+//! are simple enough here to not need a serialization library.
+
+use crate::*;
+use std::fmt::Write;
+
+
+/// Descriptive data of a module, see [`Metadata::new`]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct Metadata {
+    /// Song name
+    pub title: String,
+
+    /// Song message, lines are separated by `\n`
+    pub message: String,
+
+    /// Initial speed
+    pub speed: u8,
+
+    /// Initial tempo
+    pub tempo: u8,
+
+    /// Number of channels used by the patterns in the orders list
+    pub channels: usize,
+
+    /// Number of pattern entries in the orders list, counting repetitions
+    pub orders: usize,
+
+    /// Number of patterns
+    pub patterns: usize,
+
+    /// Playing time in seconds, see [`Module::duration`]
+    pub duration: f64,
+
+    /// Instrument names
+    pub instruments: Vec<String>,
+
+    /// Samples
+    pub samples: Vec<SampleMetadata>,
+}
+
+/// Descriptive data of a sample
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct SampleMetadata {
+    /// Sample name
+    pub name: String,
+
+    /// DOS filename
+    pub filename: String,
+
+    /// Length in samples
+    pub length: u32,
+
+    /// Bits per sample, 8 for samples without data
+    pub bits: u8,
+
+    /// Sample rate of C-5
+    pub samplerate: u32,
+}
+
+impl Metadata {
+    /// Collects the metadata of `module`.
+    pub fn new(module: &Module) -> Metadata {
+        Metadata {
+            title: module.name.decode(),
+            message: module.message.replace("\r\n", "\n").replace('\r', "\n"),
+            speed: module.speed.as_u8(),
+            tempo: module.tempo.as_u8(),
+            channels: module.channel_usage().iter().filter(|usage| usage.is_used()).count(),
+            orders: module.orders.iter().filter(|order| matches!(order, Order::Index(_))).count(),
+            patterns: module.patterns.len(),
+            duration: module.duration().seconds,
+            instruments: module.instruments.iter().map(|instrument| instrument.name.decode()).collect(),
+            samples: module.samples
+                .iter()
+                .map(|sample| SampleMetadata {
+                    name: sample.name.decode(),
+                    filename: cp437::decode_field(&sample.filename.bytes),
+                    length: sample.length(),
+                    bits: if sample.data.as_ref().is_some_and(SampleData::is_16bit) { 16 } else { 8 },
+                    samplerate: sample.samplerate_c5,
+                })
+                .collect(),
+        }
+    }
+
+    /// Writes the metadata as a TOML document, samples are an array of tables.
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        self.write_header(&mut out, " = ");
+        write_list(&mut out, "instruments = [", ", ", "]", &self.instruments);
+        for sample in &self.samples {
+            out.push_str("\n[[samples]]\n");
+            sample.write(&mut out, "", " = ");
+        }
+        out
+    }
+
+    /// Writes the metadata as a YAML document.
+    pub fn to_yaml(&self) -> String {
+        let mut out = String::new();
+        self.write_header(&mut out, ": ");
+        if self.instruments.is_empty() {
+            out.push_str("instruments: []\n");
+        } else {
+            write_list(&mut out, "instruments:\n  - ", "\n  - ", "", &self.instruments);
+        }
+        if self.samples.is_empty() {
+            out.push_str("samples: []\n");
+        } else {
+            out.push_str("samples:\n");
+            for sample in &self.samples {
+                out.push_str("  - ");
+                sample.write(&mut out, "    ", ": ");
+            }
+        }
+        out
+    }
+
+    /// Writes the scalar fields as `key<separator>value` lines, the same for both formats.
+    fn write_header(&self, out: &mut String, separator: &str) {
+        // Writing into a `String` never fails.
+        writeln!(out, "title{}{}", separator, quote(&self.title)).unwrap();
+        writeln!(out, "message{}{}", separator, quote(&self.message)).unwrap();
+        writeln!(out, "speed{}{}", separator, self.speed).unwrap();
+        writeln!(out, "tempo{}{}", separator, self.tempo).unwrap();
+        writeln!(out, "channels{}{}", separator, self.channels).unwrap();
+        writeln!(out, "orders{}{}", separator, self.orders).unwrap();
+        writeln!(out, "patterns{}{}", separator, self.patterns).unwrap();
+        // Always written with a decimal point so it's read back as a float.
+        writeln!(out, "duration{}{:.3}", separator, self.duration).unwrap();
+    }
+}
+
+impl SampleMetadata {
+    /// Writes the fields as `key<separator>value` lines, the first line is not indented.
+    fn write(&self, out: &mut String, indent: &str, separator: &str) {
+        writeln!(out, "name{}{}", separator, quote(&self.name)).unwrap();
+        writeln!(out, "{}filename{}{}", indent, separator, quote(&self.filename)).unwrap();
+        writeln!(out, "{}length{}{}", indent, separator, self.length).unwrap();
+        writeln!(out, "{}bits{}{}", indent, separator, self.bits).unwrap();
+        writeln!(out, "{}samplerate{}{}", indent, separator, self.samplerate).unwrap();
+    }
+}
+
+fn write_list(out: &mut String, start: &str, separator: &str, end: &str, items: &[String]) {
+    out.push_str(start);
+    for (idx, item) in items.iter().enumerate() {
+        if idx > 0 {
+            out.push_str(separator);
+        }
+        out.push_str(&quote(item));
+    }
+    out.push_str(end);
+    out.push('\n');
+}
+
+/// Quotes a string so it's valid both as a TOML basic string and as a YAML double-quoted scalar.
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            ch if ch.is_control() => write!(quoted, "\\u{:04X}", u32::from(ch)).unwrap(),
+            ch => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    fn metadata() -> Metadata {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.name = Name::new("The \"song\"");
+        module.message = "Hello\rWorld".to_string();
+        module.set_speed(6).unwrap();
+        module.set_tempo(125).unwrap();
+        module.orders = vec![Order::Index(PatternId::from_index(0).unwrap()), Order::EndOfSong];
+        module.patterns = vec![Pattern {
+            active_channels: ActiveChannels::all(),
+            rows: vec![
+                Row::from_vec(vec![
+                    (Channel::new(1), Command { note: Some(NoteCmd::Play(Note::C_5)), ..Command::EMPTY }),
+                    (Channel::new(3), Command { note: Some(NoteCmd::Play(Note::E_5)), ..Command::EMPTY }),
+                ]),
+                Row::empty(),
+            ],
+        }];
+        module.instruments.clear();
+        module.samples = vec![Sample {
+            name: Name::new("Kick"),
+            filename: DosFilename::sanitize("kick.wav"),
+            global_volume: 64,
+            default_volume: 64,
+            default_panning: 32,
+            loop_: None,
+            sustain_loop: None,
+            samplerate_c5: 22050,
+            vibrato_speed: 0,
+            vibrato_depth: 0,
+            vibrato_rate: 0,
+            vibrato_type: 0,
+            data: Some(SampleData::from(vec![0i16; 100])),
+            lazy_data: None,
+        }];
+        Metadata::new(&module)
+    }
+
+    #[test]
+    fn toml() {
+        assert_eq!(metadata().to_toml(), concat!(
+            "title = \"The \\\"song\\\"\"\n",
+            "message = \"Hello\\nWorld\"\n",
+            "speed = 6\n",
+            "tempo = 125\n",
+            "channels = 2\n",
+            "orders = 1\n",
+            "patterns = 1\n",
+            "duration = 0.240\n",
+            "instruments = []\n",
+            "\n",
+            "[[samples]]\n",
+            "name = \"Kick\"\n",
+            "filename = \"KICK.WAV\"\n",
+            "length = 100\n",
+            "bits = 16\n",
+            "samplerate = 22050\n",
+        ));
+    }
+
+    #[test]
+    fn yaml() {
+        assert_eq!(metadata().to_yaml(), concat!(
+            "title: \"The \\\"song\\\"\"\n",
+            "message: \"Hello\\nWorld\"\n",
+            "speed: 6\n",
+            "tempo: 125\n",
+            "channels: 2\n",
+            "orders: 1\n",
+            "patterns: 1\n",
+            "duration: 0.240\n",
+            "instruments: []\n",
+            "samples:\n",
+            "  - name: \"Kick\"\n",
+            "    filename: \"KICK.WAV\"\n",
+            "    length: 100\n",
+            "    bits: 16\n",
+            "    samplerate: 22050\n",
+        ));
+        assert_eq!(quote("a\u{1}"), "\"a\\u0001\"");
+    }
+}
@@ -0,0 +1,278 @@
+//! Size optimization of modules
+//!
+//! [`optimize`] removes what doesn't contribute to playing the song: patterns missing from the
+//! orders list, instruments and samples no note plays, and the silence at the end of samples.
+//! The items which are kept are renumbered and the references to them updated, the song plays
+//! the same before and after. Recompressing the samples is left to the writer, see
+//! [`Compression::It215`](crate::writer::Compression::It215).
+
+use crate::*;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+
+/// Settings of [`optimize`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OptimizeOptions {
+    /// Removes the patterns, instruments and samples the song doesn't use
+    pub remove_unused: bool,
+
+    /// Removes the silence at the end of samples, up to the end of their loops
+    pub trim_silence: bool,
+
+    /// Largest absolute value of a normalized sample considered silent, `0.0` trims only digital
+    /// silence
+    pub silence_threshold: f32,
+}
+
+/// Changes made by [`optimize`]
+///
+/// Items are identified by their position before the optimization.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OptimizeReport {
+    pub removed_patterns: Vec<PatternId>,
+    pub removed_instruments: Vec<InstrumentId>,
+    pub removed_samples: Vec<SampleId>,
+
+    /// Samples which were shortened with the number of samples removed from their end
+    pub trimmed_samples: Vec<(SampleId, u32)>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> OptimizeOptions {
+        OptimizeOptions {
+            remove_unused: true,
+            trim_silence: true,
+            silence_threshold: 0.0,
+        }
+    }
+}
+
+impl OptimizeReport {
+    /// Returns `true` if the module wasn't changed.
+    pub fn is_empty(&self) -> bool {
+        self.removed_patterns.is_empty()
+            && self.removed_instruments.is_empty()
+            && self.removed_samples.is_empty()
+            && self.trimmed_samples.is_empty()
+    }
+}
+
+/// Removes the unused parts of `module`, see the [module documentation](self).
+///
+/// References to items missing from the module, e.g. an order playing a pattern which doesn't
+/// exist, would point to another item after renumbering. Items of that kind are then all kept.
+pub fn optimize(module: &mut Module, options: &OptimizeOptions) -> OptimizeReport {
+    let mut report = OptimizeReport::default();
+    if options.remove_unused {
+        report.removed_patterns = remove_unused_patterns(module);
+        if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            report.removed_instruments = remove_unused_instruments(module);
+            report.removed_samples = remove_unused_samples_of_instruments(module);
+        } else {
+            report.removed_samples = remove_unused_samples_of_patterns(module);
+        }
+    }
+    if options.trim_silence {
+        for (idx, sample) in module.samples.iter_mut().enumerate() {
+            let removed = trim_silence(sample, options.silence_threshold);
+            if removed > 0 {
+                report.trimmed_samples.push((SampleId::from_index(u8::try_from(idx).unwrap()).unwrap(), removed));
+            }
+        }
+    }
+    report
+}
+
+fn remove_unused_patterns(module: &mut Module) -> Vec<PatternId> {
+    let mut used = vec![false; module.patterns.len()];
+    for order in &module.orders {
+        if let Order::Index(pattern) = order {
+            match used.get_mut(pattern.as_usize()) {
+                Some(used) => *used = true,
+                None => return Vec::new(),
+            }
+        }
+    }
+
+    let (removed, map) = renumber(&used, |idx| PatternId::from_index(idx).unwrap());
+    for order in &mut module.orders {
+        if let Order::Index(pattern) = order {
+            *pattern = map[pattern.as_usize()].unwrap();
+        }
+    }
+    retain(&mut module.patterns, &used);
+    removed
+}
+
+fn remove_unused_instruments(module: &mut Module) -> Vec<InstrumentId> {
+    let mut used = vec![false; module.instruments.len()];
+    for instrument in module.patterns.iter().flat_map(pattern_instruments) {
+        match used.get_mut(instrument.as_usize()) {
+            Some(used) => *used = true,
+            None => return Vec::new(),
+        }
+    }
+
+    let (removed, map) = renumber(&used, |idx| InstrumentId::from_index(idx).unwrap());
+    remap_pattern_instruments(module, |instrument| map[instrument.as_usize()].unwrap());
+    retain(&mut module.instruments, &used);
+    removed
+}
+
+fn remove_unused_samples_of_instruments(module: &mut Module) -> Vec<SampleId> {
+    let mut used = vec![false; module.samples.len()];
+    for instrument in &module.instruments {
+        for (_, _, sample) in instrument.sample_map.iter() {
+            match sample.map(|sample| used.get_mut(sample.as_usize())) {
+                Some(Some(used)) => *used = true,
+                Some(None) => return Vec::new(),
+                None => {}
+            }
+        }
+    }
+
+    let (removed, map) = renumber(&used, |idx| SampleId::from_index(idx).unwrap());
+    for instrument in &mut module.instruments {
+        instrument.sample_map.remap_samples(|sample| map[sample.as_usize()]);
+    }
+    retain(&mut module.samples, &used);
+    removed
+}
+
+/// Without instruments the instrument column of the patterns holds sample numbers.
+fn remove_unused_samples_of_patterns(module: &mut Module) -> Vec<SampleId> {
+    let mut used = vec![false; module.samples.len()];
+    for sample in module.patterns.iter().flat_map(pattern_instruments) {
+        match used.get_mut(sample.as_usize()) {
+            Some(used) => *used = true,
+            None => return Vec::new(),
+        }
+    }
+
+    let (removed, map) = renumber(&used, |idx| SampleId::from_index(idx).unwrap());
+    remap_pattern_instruments(module, |sample| {
+        InstrumentId::from_index(map[sample.as_usize()].unwrap().as_u8()).unwrap()
+    });
+    retain(&mut module.samples, &used);
+    removed
+}
+
+fn pattern_instruments(pattern: &Pattern) -> impl Iterator<Item = InstrumentId> + '_ {
+    pattern.rows
+        .iter()
+        .flat_map(|row| row.iter().filter_map(|(_, command)| command.instrument))
+}
+
+fn remap_pattern_instruments(module: &mut Module, f: impl Fn(InstrumentId) -> InstrumentId) {
+    for pattern in &mut module.patterns {
+        for row in &mut pattern.rows {
+            let commands = row.iter()
+                .map(|(channel, command)| {
+                    let instrument = command.instrument.map(&f);
+                    (channel, Command { instrument, ..*command })
+                })
+                .collect();
+            *row = Row::from_vec(commands);
+        }
+    }
+}
+
+/// Returns the removed items and the new identifier of each kept one.
+fn renumber<Id>(used: &[bool], id: impl Fn(u8) -> Id) -> (Vec<Id>, Vec<Option<Id>>) {
+    let mut removed = Vec::new();
+    let mut map = Vec::with_capacity(used.len());
+    let mut next = 0;
+    for (idx, &used) in used.iter().enumerate() {
+        if used {
+            map.push(Some(id(next)));
+            next += 1;
+        } else {
+            removed.push(id(u8::try_from(idx).unwrap()));
+            map.push(None);
+        }
+    }
+    (removed, map)
+}
+
+fn retain<T>(items: &mut Vec<T>, used: &[bool]) {
+    let mut used = used.iter();
+    items.retain(|_| *used.next().unwrap());
+}
+
+/// Removes the silent samples at the end of `sample` after its loops, returns their number.
+///
+/// At least one sample of the data is kept.
+fn trim_silence(sample: &mut Sample, threshold: f32) -> u32 {
+    let Some(data) = &sample.data else {
+        return 0;
+    };
+    let loops_end = [&sample.loop_, &sample.sustain_loop]
+        .into_iter()
+        .flatten()
+        .map(|loop_| usize::try_from(loop_.end).unwrap())
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let mut length = data.len();
+    while length > loops_end && data.get(length - 1).unwrap().abs() <= threshold {
+        length -= 1;
+    }
+    if length == data.len() {
+        return 0;
+    }
+
+    let removed = u32::try_from(data.len() - length).unwrap();
+    sample.data = Some(match data {
+        SampleData::Pcm8(data) => SampleData::Pcm8(Arc::from(&data[..length])),
+        SampleData::Pcm16(data) => SampleData::Pcm16(Arc::from(&data[..length])),
+    });
+    removed
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn optimize_module() {
+        const MODULE_DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+
+        let original = parser::module_file::<VerboseError<&[u8]>>(MODULE_DATA).unwrap();
+        let mut optimized = original.clone();
+        optimize(&mut optimized, &OptimizeOptions::default());
+        assert!(optimize(&mut optimized.clone(), &OptimizeOptions::default()).is_empty());
+
+        // An unused pattern in front of the others and an unused sample at the end.
+        let mut module = optimized.clone();
+        module.patterns.insert(0, module.patterns[0].clone());
+        for order in &mut module.orders {
+            if let Order::Index(pattern) = order {
+                *pattern = PatternId::from_index(pattern.as_u8() + 1).unwrap();
+            }
+        }
+        let unused = SampleId::from_index(u8::try_from(module.samples.len()).unwrap()).unwrap();
+        module.samples.push(Sample { data: None, ..original.samples[0].clone() });
+
+        let report = optimize(&mut module, &OptimizeOptions::default());
+        assert_eq!(report.removed_patterns, vec![PatternId::from_index(0).unwrap()]);
+        assert_eq!(report.removed_samples, vec![unused]);
+        assert_eq!(module, optimized);
+
+        // Silence after the end of the sample, and after the end of its loop.
+        let mut sample = Sample {
+            loop_: None,
+            sustain_loop: None,
+            data: Some(SampleData::Pcm8(Arc::from(&[3, -2, 1, 0, 0, 0][..]))),
+            ..original.samples[0].clone()
+        };
+        assert_eq!(trim_silence(&mut sample.clone(), 0.0), 3);
+        assert_eq!(trim_silence(&mut sample, 0.01), 4);
+        assert_eq!(sample.length(), 2);
+        sample.loop_ = Some(SampleLoop { start: 0, end: 2, bidi: false });
+        assert_eq!(trim_silence(&mut sample, 1.0), 0);
+    }
+}
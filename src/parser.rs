@@ -1,42 +1,182 @@
 //! Parsing functions
 
+use crate::cp437;
 use crate::data::*;
 use crate::error::ContextError;
+use crate::prelude::*;
 use bitflags::bitflags;
 use nom::bytes::complete::{tag, take};
-use nom::combinator::{all_consuming, map};
+use nom::combinator::map;
 use nom::error::{ErrorKind, ParseError};
-use nom::multi::{count, many_till};
+use nom::multi::count;
 use nom::number::complete::{be_i16, le_i16, le_i8, le_u16, le_u32, le_u8};
 use nom::sequence::tuple;
 use nom::{Err, IResult};
 use pattern::pattern;
-use std::cmp::min;
-use std::convert::{TryFrom, TryInto};
-use std::num::Wrapping;
-use std::ops::{RangeInclusive, Add};
+use alloc::borrow::Cow;
+use alloc::sync::Arc;
+use core::cmp::min;
+use core::convert::{TryFrom, TryInto};
+use core::num::Wrapping;
+use core::ops::{RangeInclusive, Add};
 
 
 macro_rules! info {
-    ( $($tt:tt)* ) => {
+    ( @value $field: ident ) => { $field };
+    ( @value $field: ident = $value: expr ) => { $value };
+
+    ( $message: literal $(,)? ) => {{
+        $crate::parser::repair::record($message, String::new);
         #[cfg(feature = "tracing")]
-        ::tracing::info!($($tt)*);
-    };
+        ::tracing::info!($message);
+    }};
+    ( $( $field: ident $( = $value: expr )? ),+ , $message: literal $(,)? ) => {{
+        $crate::parser::repair::record($message, || {
+            let values = [ $( format!("{} = {:?}", stringify!($field), info!(@value $field $( = $value )?)) ),+ ];
+            values.join(", ")
+        });
+        #[cfg(feature = "tracing")]
+        ::tracing::info!($( $field $( = $value )? ),+ , $message);
+    }};
 }
 
 
+#[cfg(feature = "bumpalo")]
+mod arena;
+#[cfg(feature = "std")]
+mod audio;
+mod incremental;
+mod layout;
 mod pattern;
+mod repair;
 pub(crate) mod scan;
 mod util;
 
+#[cfg(feature = "bumpalo")]
+pub use arena::{module_file_in, ArenaModule, ArenaPattern};
+#[cfg(feature = "aiff")]
+pub use audio::aiff_file;
+#[cfg(feature = "std")]
+pub use audio::{wav_file, StereoMode};
+pub use incremental::IncrementalParser;
+pub use layout::{byte_map, ByteMap, Region};
 pub use pattern::parse_effect as effect;
+pub use pattern::parse_volume as volume;
+pub use pattern::{module_patterns, pattern_cells, ModulePatterns, PatternCells};
+pub use repair::Repair;
 
 use util::*;
 pub use scan::scan;
 
 
+/// Size of an instrument header
+pub(crate) const INSTRUMENT_SIZE: usize = 554;
+
+/// Size of a sample header
+pub(crate) const SAMPLE_HEADER_SIZE: usize = 80;
+
+/// Size of the pattern header preceding the packed data
+pub(crate) const PATTERN_HEADER_SIZE: usize = 8;
+
+/// Bit of the header `special` field saying the edit history follows the offset tables
+pub(crate) const SPECIAL_EDIT_HISTORY: u16 = 1 << 1;
+
+
+/// Limits on the sizes a module file can declare, see [`module_file_with_limits`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum total size of the decoded sample data in bytes
+    pub max_sample_bytes: usize,
+
+    /// Maximum size of the packed data of a pattern in bytes
+    pub max_pattern_bytes: usize,
+
+    /// Maximum number of rows of a pattern
+    pub max_rows: usize,
+}
+
+impl Limits {
+    /// No limits, used by [`module_file`]
+    pub const NONE: Limits = Limits {
+        max_sample_bytes: usize::MAX,
+        max_pattern_bytes: usize::MAX,
+        max_rows: usize::MAX,
+    };
+}
+
+impl Default for Limits {
+    /// 64 MiB of sample data and the largest patterns OpenMPT creates
+    fn default() -> Limits {
+        Limits {
+            max_sample_bytes: 64 << 20,
+            max_pattern_bytes: 0xFFFF,
+            max_rows: 1024,
+        }
+    }
+}
+
+
 /// Parse Impulse Tracker module file (.it)
 pub fn module_file<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    module(input, false, &Limits::NONE).map(|(module, _)| module)
+}
+
+/// Parse Impulse Tracker module file (.it) rejecting files which declare more data than `limits`
+///
+/// Sample lengths and pattern sizes are checked before anything is decoded or allocated, so a
+/// small malicious file can't make the parser allocate gigabytes. Files over the limits fail with
+/// [`ErrorKind::TooLarge`], which makes this suitable for parsing untrusted files on a server.
+pub fn module_file_with_limits<'i, E>(input: &'i [u8], limits: &Limits) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    module(input, false, limits).map(|(module, _)| module)
+}
+
+/// Parse Impulse Tracker module file (.it) without decoding the sample data
+///
+/// The samples of the returned module keep their data as it's stored in the file in
+/// [`Sample::lazy_data`] instead of [`Sample::data`], [`Sample::decoded_data`] decodes it on the
+/// first access. The [`Player`](crate::player::Player) and the [`writer`](crate::writer) read the
+/// data that way, so tools reading only the metadata or playing a part of the song don't pay for
+/// decompressing the samples they never touch. Code reading [`Sample::data`] directly sees no
+/// data for these samples.
+///
+/// Errors in the sample data are only detected when decoding it, the writer refuses to write
+/// samples which can't be decoded.
+#[cfg(feature = "std")]
+pub fn module_file_lazy<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let (mut module, sample_headers) = module(input, true, &Limits::NONE)?;
+    for (sample, header) in module.samples.iter_mut().zip(&sample_headers) {
+        sample.lazy_data = lazy_sample_data(header, input)?;
+    }
+    Ok(module)
+}
+
+/// Parse Impulse Tracker module file (.it) and list the values which were repaired
+///
+/// The parser accepts out-of-range values found in files in the wild and replaces or skips them,
+/// see the "Canonicalization" sections of the data types. The returned list has an entry for each
+/// value repaired while parsing `input`, an empty list means the file is within the
+/// specification as far as the parser checks it.
+#[cfg(feature = "std")]
+pub fn module_file_with_repairs<'i, E>(input: &'i [u8]) -> Result<(Module, Vec<Repair>), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let (result, repairs) = repair::collect(|| module_file(input));
+    result.map(|module| (module, repairs))
+}
+
+/// Parses the module, with `lazy` the samples are parsed without their data and their headers are
+/// returned for reading the data later.
+fn module<'i, E>(input: &'i [u8], lazy: bool, limits: &Limits) -> Result<(Module, Vec<SampleHeader>), Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
 {
@@ -60,15 +200,37 @@ where
             if offset >= input.len() {
                 return Err(Err::Error(E::from_error_kind(input, ErrorKind::Eof)));
             }
+            let (_, (length, rows)) = tuple((le_u16, le_u16))(&input[offset..])?;
+            if usize::from(length) > limits.max_pattern_bytes || usize::from(rows) > limits.max_rows {
+                return Err(too_large(&input[offset..], "pattern exceeds the limits"));
+            }
             let (_, pat) = pattern(&input[offset..])?;
             patterns.push(pat);
         }
         patterns
     };
 
-    let samples = sample_headers.into_iter()
-        .map(|header| sample_data(header, input))
-        .collect::<Result<Vec<_>, _>>()?;
+    let sample_bytes = sample_headers
+        .iter()
+        .filter(|header| header.flags.contains(SampleFlags::DATA_PRESENT))
+        .map(|header| {
+            let bytes_per_sample = if header.flags.contains(SampleFlags::DATA_16BIT) { 2 } else { 1 };
+            header.data_length.cast::<usize>().saturating_mul(bytes_per_sample)
+        })
+        .fold(0usize, usize::saturating_add);
+    if sample_bytes > limits.max_sample_bytes {
+        return Err(too_large(input, "sample data exceeds the limits"));
+    }
+
+    let (samples, sample_headers) = if lazy {
+        let headers = sample_headers.iter().cloned().map(|mut header| {
+            header.flags.remove(SampleFlags::DATA_PRESENT);
+            header
+        });
+        (samples(headers.collect(), input)?, sample_headers)
+    } else {
+        (samples(sample_headers, input)?, Vec::new())
+    };
 
     let message = {
         let offset = header.message_offset.cast::<usize>();
@@ -78,12 +240,11 @@ where
             let (_, bytes) = take(header.message_length.cast::<usize>())(&input[offset..])?;
             //according to ITTECH.TXT, a \0 is always the end of a message
             let bytes_before_terminator = bytes.split(|&x| x == b'\0').next().unwrap();
-            String::from_utf8_lossy(bytes_before_terminator)
-                .to_string()
+            cp437::decode(bytes_before_terminator)
         }
     };
 
-    Ok(Module {
+    let module = Module {
         name: header.name,
         highlight: header.highlight,
         made_with_version: header.made_with_version,
@@ -97,12 +258,65 @@ where
         pitch_wheel_depth: header.pitch_wheel_depth,
         message,
         orders: header.orders,
-        init_channel_panning: header.init_channel_panning,
-        init_channel_volume: header.init_channel_volume,
+        channels: header.channels,
         instruments,
         samples,
         patterns,
-    })
+    };
+    Ok((module, sample_headers))
+}
+
+/// Parse Impulse Tracker module file (.it) keeping the parts of the file [`Module`] doesn't
+/// represent
+///
+/// Passing the returned [`Preserved`] to
+/// [`writer::module_file_preserving`](crate::writer::module_file_preserving) reproduces the input
+/// byte-for-byte as long as the module is not modified.
+pub fn module_file_preserving<'i, E>(input: &'i [u8]) -> Result<(Module, Preserved), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let module = module_file(input)?;
+
+    // The module parsed successfully, all the offsets below point into the input.
+    let (_, header) = module_header(input)?;
+    let (_, sample_headers) = offset_list(sample_header, header.sample_offsets.clone())(input)?;
+
+    // Collect the byte ranges of all structures referenced from the header.
+    let mut spans = Vec::new();
+    if header.message_offset != 0 && header.message_length != 0 {
+        let offset = header.message_offset.cast::<usize>();
+        spans.push((offset, offset + header.message_length.cast::<usize>()));
+    }
+    for offset in header.instrument_offsets.iter().map(|&offset| offset.cast::<usize>()) {
+        spans.push((offset, offset + INSTRUMENT_SIZE));
+    }
+    for offset in header.sample_offsets.iter().map(|&offset| offset.cast::<usize>()) {
+        spans.push((offset, offset + SAMPLE_HEADER_SIZE));
+    }
+    for offset in header.pattern_offsets.iter().map(|&offset| offset.cast::<usize>()) {
+        if offset != 0 {
+            let (_, length) = le_u16(&input[offset..])?;
+            spans.push((offset, offset + PATTERN_HEADER_SIZE + usize::from(length)));
+        }
+    }
+    for sample in sample_headers.iter().filter(|sample| sample.flags.contains(SampleFlags::DATA_PRESENT)) {
+        let offset = sample.data_offset.cast::<usize>();
+        spans.push((offset, offset + sample_data_size(sample, &input[offset..])));
+    }
+
+    let header_size = header.header_size;
+    let first = spans.iter().map(|span| span.0).min().unwrap_or(input.len()).clamp(header_size, input.len());
+    let last = spans.iter().map(|span| span.1).max().unwrap_or(header_size).clamp(header_size, input.len());
+
+    let preserved = Preserved {
+        module: module.clone(),
+        original: Arc::from(input),
+        header_extra: input[header_size..first].to_vec(),
+        trailing: input[last..].to_vec(),
+        unknown_flags: header.unknown_flags,
+    };
+    Ok((module, preserved))
 }
 
 /// Parse Impulse Tracker instrument file (.iti)
@@ -112,9 +326,7 @@ where
 {
     let (input2, instrument) = instrument(input)?;
     let (_, sample_headers) = count(sample_header, instrument.number_of_samples.into())(input2)?;
-    let samples = sample_headers.into_iter()
-        .map(|header| sample_data(header, input))
-        .collect::<Result<Vec<_>, _>>()?;
+    let samples = samples(sample_headers, input)?;
     Ok(InstrumentFile { instrument, samples })
 }
 
@@ -132,6 +344,8 @@ fn module_header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], ModuleHeader, E>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
 {
+    let input_start = input;
+
     // Parse static parts.
     let (input, _) = tag(b"IMPM")(input)?;
     let (input, songname) = name(input)?;
@@ -154,16 +368,19 @@ where
     let (input, msglength) = le_u16(input)?;
     let (input, msgoffset) = le_u32(input)?;
     let (input, _reserved) = le_u32(input)?;
-    let (input, chnpan) = byte_array(input)?;
-    let (input, chnvol) = byte_array(input)?;
+    let (input, chnpan): (_, [u8; 64]) = byte_array(input)?;
+    let (input, chnvol): (_, [u8; 64]) = byte_array(input)?;
 
     // Parse dynamic parts of the header.
     let (input, orders) = count(order, ordnum.into())(input)?;
     let orders = orders.into_iter().flatten().collect();
     let (input, ins_offsets) = count(le_u32, insnum.into())(input)?;
     let (input, sam_offsets) = count(le_u32, smpnum.into())(input)?;
-    let (_rest, pat_offsets) = count(le_u32, patnum.into())(input)?;
+    let (rest, pat_offsets) = count(le_u32, patnum.into())(input)?;
+    let header_size = input_start.len() - rest.len();
 
+    let (known_flags, known_special) = ModuleFlags::all().to_parts();
+    let unknown_flags = (flags & !known_flags, special & !known_special);
     let flags = ModuleFlags::from_parts(flags, special);
 
     // Check ranged values and canonicalize out-of-range values.
@@ -197,6 +414,14 @@ where
         128
     });
 
+    let channels = {
+        let mut channels = [ChannelSettings::DEFAULT; 64];
+        for (channel, (&pan, &vol)) in channels.iter_mut().zip(chnpan.iter().zip(chnvol.iter())) {
+            *channel = channel_settings(pan, vol);
+        }
+        channels
+    };
+
     Ok((
         input,
         ModuleHeader {
@@ -213,16 +438,44 @@ where
             pitch_wheel_depth: pwd,
             message_length: msglength,
             message_offset: msgoffset,
-            init_channel_panning: chnpan,
-            init_channel_volume: chnvol,
+            channels,
             orders,
             instrument_offsets: ins_offsets,
             sample_offsets: sam_offsets,
             pattern_offsets: pat_offsets,
+            header_size,
+            unknown_flags,
         },
     ))
 }
 
+fn channel_settings(pan: u8, vol: u8) -> ChannelSettings {
+    const MUTED: u8 = 128;
+    const SURROUND: u8 = 100;
+
+    let muted = pan & MUTED != 0;
+    let panning = match pan & !MUTED {
+        position @ 0..=64 => ChannelPanning::Position(position.cast()),
+        SURROUND => ChannelPanning::Surround,
+        _ => {
+            info!(pan, "channel panning is out of range 0..=64,100, using 32 (center)");
+            ChannelPanning::Position(32.cast())
+        }
+    };
+    let volume = if vol > 64 {
+        info!(vol, "channel volume cannot be more than 64, clipping");
+        64
+    } else {
+        vol
+    };
+
+    ChannelSettings {
+        panning,
+        muted,
+        volume: volume.cast(),
+    }
+}
+
 fn order<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], Option<Order>, E> {
     map(
         le_u8,
@@ -294,6 +547,28 @@ fn instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [
     }
     let ifr = ifr & !Instrument::ifr_enableResonance;
 
+    let nna = match NewNoteAction::try_from(nna) {
+        Ok(nna) => nna,
+        Err(_) => {
+            info!(nna, "new note action is out of range 0..=3, using 0 (cut)");
+            NewNoteAction::Cut
+        }
+    };
+    let dct = match DuplicateCheckType::try_from(dct) {
+        Ok(dct) => dct,
+        Err(_) => {
+            info!(dct, "duplicate check type is out of range 0..=3, using 0 (off)");
+            DuplicateCheckType::Off
+        }
+    };
+    let dca = match DuplicateCheckAction::try_from(dca) {
+        Ok(dca) => dca,
+        Err(_) => {
+            info!(dca, "duplicate check action is out of range 0..=2, using 0 (cut)");
+            DuplicateCheckAction::Cut
+        }
+    };
+
     Ok((
         input,
         Instrument {
@@ -326,28 +601,27 @@ fn instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [
 }
 
 fn sample_map<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], SampleMap, E> {
-    scan_count(
-        120,
-        tuple((le_u8, le_u8)),
-        SampleMap::default(),
-        |sm: &mut SampleMap, (note, sample)| {
-            match (note, sample) {
-                (0..=119, 0) => {
-                    // Explicit map to None, but don't override previous mapping.
-                }
-                (0..=119, 1..=99) => {
-                    let sample = SampleId::try_from(sample - 1).unwrap();
-                    sm.map[usize::from(note)] = Some(sample);
-                }
-                _ => {
-                    info!(
-                        note, sample,
-                        "note or sample out of range 0..=119 and 0..=99 respectively"
-                    );
-                }
+    let (input, entries): (_, [(u8, u8); 120]) = array(tuple((le_u8, le_u8)))(input)?;
+
+    // Entries are indexed by the played note and contain the translated note and the sample.
+    let mut sm = SampleMap::default();
+    for (note, (translation, sample)) in (0..).zip(entries) {
+        let note = Note::try_from(note).unwrap();
+        if let Ok(translation) = Note::try_from(translation) {
+            sm.set_note_translation(note, translation);
+        } else {
+            info!(translation, "note translation out of range 0..=119, playing untransposed");
+        }
+        match sample {
+            0 => {}
+            1..=99 => sm.set_sample(note, Some(SampleId::from_number(sample).unwrap())),
+            _ => {
+                info!(sample, "sample out of range 0..=99, parsing as no sample");
             }
-        },
-    )(input)
+        }
+    }
+
+    Ok((input, sm))
 }
 
 fn envelope<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], Envelope, E> {
@@ -437,27 +711,39 @@ where
     let (input, vit) = le_u8(input)?;
 
     let loop_ = if flags.contains(SampleFlags::LOOP) {
-        // TODO canonicalize/skip invalid values
-        assert!(loopbegin < loopend);
-        assert!(loopend <= length);
-        Some(SampleLoop {
+        let loop_ = SampleLoop {
             start: loopbegin,
             end: loopend,
             bidi: flags.contains(SampleFlags::BIDI_LOOP),
-        })
+        };
+        if loop_.validate(length).is_ok() {
+            Some(loop_)
+        } else {
+            info!(
+                start = loopbegin, end = loopend, length,
+                "invalid loop points, ignoring sample loop",
+            );
+            None
+        }
     } else {
         None
     };
 
     let sustain_loop = if flags.contains(SampleFlags::SUSTAIN) {
-        // TODO canonicalize/skip invalid values
-        assert!(susloopbegin < susloopend);
-        assert!(susloopend <= length);
-        Some(SampleLoop {
+        let sustain_loop = SampleLoop {
             start: susloopbegin,
             end: susloopend,
             bidi: flags.contains(SampleFlags::BIDI_SUSTAIN),
-        })
+        };
+        if sustain_loop.validate(length).is_ok() {
+            Some(sustain_loop)
+        } else {
+            info!(
+                start = susloopbegin, end = susloopend, length,
+                "invalid loop points, ignoring sustain loop",
+            );
+            None
+        }
     } else {
         None
     };
@@ -528,16 +814,12 @@ where
 }
 
 trait SampleValue {
-    fn normalize(&self) -> f32;
     fn bits() -> usize;
     fn bits_log2() -> usize;
     fn as_signed_int(unsigned: usize) -> Self;
 }
 
 impl SampleValue for i8 {
-    fn normalize(&self) -> f32 {
-        f32::from(*self) / -f32::from(Self::MIN)
-    }
     fn bits() -> usize {
         8
     }
@@ -551,9 +833,6 @@ impl SampleValue for i8 {
 }
 
 impl SampleValue for i16 {
-    fn normalize(&self) -> f32 {
-        f32::from(*self) / -f32::from(Self::MIN)
-    }
     fn bits() -> usize {
         16
     }
@@ -566,10 +845,10 @@ impl SampleValue for i16 {
     }
 }
 
-fn decompress_block<'i, T, E>(input: &'i [u8], samples: usize, delta: bool) -> Result<Vec<f32>, Err<E>>
+fn decompress_block<'i, T, E>(input: &'i [u8], samples: usize, delta: bool) -> Result<Vec<T>, Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
-    T: SampleValue + std::ops::Shr<usize, Output = T> + Copy + Default,
+    T: SampleValue + core::ops::Shr<usize, Output = T> + Copy + Default,
     Wrapping<T>: Add<Output = Wrapping<T>>
 {
     let mut decompressed_block: Vec<T> = Vec::with_capacity(samples);
@@ -621,30 +900,118 @@ where
         decompressed_block.into_iter()
             .scan(Wrapping(T::default()), integrate_with_wrap::<T>)
             .scan(Wrapping(T::default()), |state, x| if delta {integrate_with_wrap(state, x)} else {Some(x)})
-            .map(|x| x.normalize())
             .collect()
     )
 }
 
-fn decompress<'i, T, E>(mut input: &'i [u8], length: usize, delta: bool) -> Result<Vec<f32>, Err<E>>
+/// Maximum size of a decompressed block in bytes
+const BLOCK_SAMPLES_MAX_BYTE_LENGTH: usize = 0x8000;
+
+fn decompress<'i, T, E>(mut input: &'i [u8], length: usize, delta: bool) -> Result<Vec<T>, Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
-    T: SampleValue + std::ops::Shr<usize, Output = T> + Default + Copy,
+    T: SampleValue + core::ops::Shr<usize, Output = T> + Default + Copy,
     Wrapping<T>: Add<Output = Wrapping<T>>
 {
-    let mut decompressed_sample: Vec<f32> = Vec::with_capacity(length);
+    let mut decompressed_sample: Vec<T> = Vec::with_capacity(length);
     while decompressed_sample.len() < length {
         let block_data_length;
         (input, block_data_length) = le_u16(input)?;
         let block_data: &[u8];
         (input, block_data) = take(block_data_length)(input)?;
-        const BLOCK_SAMPLES_MAX_BYTE_LENGTH: usize = 0x8000;
         let block_samples = min(length - decompressed_sample.len(), BLOCK_SAMPLES_MAX_BYTE_LENGTH / (T::bits() / 8));
         decompressed_sample.append(&mut decompress_block::<T, _>(block_data, block_samples, delta)?)
     }
     Ok(decompressed_sample)
 }
 
+/// Size of the stored sample data in bytes, `input` starts at the sample data.
+///
+/// Compressed samples are measured by walking the block headers, a truncated input stops at the
+/// last complete block header.
+fn sample_data_size(header: &SampleHeader, input: &[u8]) -> usize {
+    let length = header.data_length.cast::<usize>();
+    let bytes_per_sample = if header.flags.contains(SampleFlags::DATA_16BIT) { 2 } else { 1 };
+    if !header.flags.contains(SampleFlags::COMPRESSED) {
+        return length * bytes_per_sample;
+    }
+
+    let block_samples = BLOCK_SAMPLES_MAX_BYTE_LENGTH / bytes_per_sample;
+    let mut size = 0;
+    let mut remaining = length;
+    while remaining > 0 {
+        let block_length = match input.get(size..size + 2) {
+            Some(bytes) => usize::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+            None => break,
+        };
+        size += 2 + block_length;
+        remaining -= min(remaining, block_samples);
+    }
+    size
+}
+
+/// Error for data exceeding the [`Limits`]
+fn too_large<'i, E>(input: &'i [u8], context: &'static str) -> Err<E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    Err::Error(E::add_context(input, Cow::Borrowed(context), E::from_error_kind(input, ErrorKind::TooLarge)))
+}
+
+/// Keeps the stored data of a sample for decoding it later
+#[cfg(feature = "std")]
+fn lazy_sample_data<'i, E>(header: &SampleHeader, input: &'i [u8]) -> Result<Option<LazySampleData>, Err<E>>
+where
+    E: ParseError<&'i [u8]>,
+{
+    if !header.flags.contains(SampleFlags::DATA_PRESENT) {
+        return Ok(None);
+    }
+    let offset = header.data_offset.cast::<usize>();
+    let input = input.get(offset..).ok_or_else(|| Err::Error(E::from_error_kind(input, ErrorKind::Eof)))?;
+    let stored = &input[..min(sample_data_size(header, input), input.len())];
+    Ok(Some(LazySampleData::new(header.clone(), stored)))
+}
+
+/// Decodes the data kept by [`LazySampleData`], `None` if it's invalid
+#[cfg(feature = "std")]
+pub(crate) fn stored_sample_data(header: &SampleHeader, stored: &[u8]) -> Option<SampleData> {
+    sample_data::<crate::error::VerboseError<&[u8]>>(header.clone(), stored).ok().and_then(|sample| sample.data)
+}
+
+/// Decodes the data of the samples
+#[cfg(not(feature = "rayon"))]
+fn samples<'i, E>(headers: Vec<SampleHeader>, input: &'i [u8]) -> Result<Vec<Sample>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    headers.into_iter().map(|header| sample_data(header, input)).collect()
+}
+
+/// Decodes the data of the samples in parallel
+///
+/// The parallel pass reports errors as [`VerboseError`](crate::error::VerboseError)s, which can be
+/// sent between threads unlike `E`. The samples it fails on are decoded again to return the error
+/// as `E`.
+#[cfg(feature = "rayon")]
+fn samples<'i, E>(headers: Vec<SampleHeader>, input: &'i [u8]) -> Result<Vec<Sample>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    use crate::error::VerboseError;
+    use rayon::prelude::*;
+
+    let decoded = headers
+        .par_iter()
+        .map(|header| sample_data::<VerboseError<&[u8]>>(header.clone(), input).ok())
+        .collect::<Vec<_>>();
+    headers
+        .into_iter()
+        .zip(decoded)
+        .map(|(header, sample)| sample.map_or_else(|| sample_data(header, input), Ok))
+        .collect()
+}
+
 fn sample_data<'i, E>(header: SampleHeader, input: &'i [u8]) -> Result<Sample, Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
@@ -673,11 +1040,11 @@ where
             flags.contains(SampleFlags::DATA_BIG_ENDIAN),
             flags.contains(SampleFlags::COMPRESSED)
         ) {
-            (true, true, false) => count(map(be_i16, |s| s.normalize()), length)(input)?.1,
-            (true, false, false) => count(map(le_i16, |s| s.normalize()), length)(input)?.1,
-            (false, _, false) => count(map(le_i8, |s| s.normalize()), length)(input)?.1,
-            (true, false, true) => decompress::<i16, _>(input, length, flags.contains(SampleFlags::DELTA))?,
-            (false, _, true) => decompress::<i8, _>(input, length, flags.contains(SampleFlags::DELTA))?,
+            (true, true, false) => SampleData::from(count(be_i16, length)(input)?.1),
+            (true, false, false) => SampleData::from(count(le_i16, length)(input)?.1),
+            (false, _, false) => SampleData::from(count(le_i8, length)(input)?.1),
+            (true, false, true) => SampleData::from(decompress::<i16, _>(input, length, flags.contains(SampleFlags::DELTA))?),
+            (false, _, true) => SampleData::from(decompress::<i8, _>(input, length, flags.contains(SampleFlags::DELTA))?),
             (true, true, true) => todo!("compressed 16 bit big endian samples not supported")
         };
 
@@ -698,13 +1065,15 @@ where
         vibrato_rate: header.vibrato_rate,
         vibrato_type: header.vibrato_type,
         data,
+        #[cfg(feature = "std")]
+        lazy_data: None,
     })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::error::{VerboseError, convert_error};
+    use crate::error::{VerboseError, VerboseErrorKind, convert_error};
     use nom::Err;
     use pretty_assertions::assert_eq;
 
@@ -721,6 +1090,23 @@ mod test {
         }
     }
 
+    #[test]
+    fn keyboard_table() {
+        let mut input = Vec::new();
+        for note in 0..120u8 {
+            // Transpose everything up an octave and play sample 2 from the middle C up.
+            input.extend([(note + 12).min(119), if note >= 60 { 2 } else { 0 }]);
+        }
+        let (_, map) = ensure_parse(sample_map, &input);
+
+        let note = |n| Note::try_from(n).unwrap();
+        assert_eq!(map.sample_for(note(59)), None);
+        assert_eq!(map.sample_for(note(60)), SampleId::from_number(2).ok());
+        assert_eq!(map.note_translation_for(note(0)), note(12));
+        assert_eq!(map.validate(2), Ok(()));
+        assert_eq!(map.validate(1).map_err(|err| err.note), Err(note(60)));
+    }
+
     #[test]
     fn compressed_samples() {
         const COMPRESSED_INST_DATA: &[u8] = include_bytes!("../tests/compression/compressed.iti");
@@ -729,12 +1115,108 @@ mod test {
 
         let instrument = ensure_parse(instrument_file, COMPRESSED_INST_DATA);
         let mut samples = instrument.samples.into_iter();
-        assert_eq!(samples.next().unwrap().data.unwrap(), SAMPLE_8_DATA.iter().map(|x| i8::from_le_bytes([*x]).normalize()).collect::<Vec<_>>());
-        assert_eq!(samples.next().unwrap().data.unwrap(), SAMPLE_16_DATA.chunks_exact(2).map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]]).normalize()).collect::<Vec<_>>());
+        assert_eq!(samples.next().unwrap().data.unwrap(), SampleData::from(SAMPLE_8_DATA.iter().map(|x| i8::from_le_bytes([*x])).collect::<Vec<_>>()));
+        assert_eq!(samples.next().unwrap().data.unwrap(), SampleData::from(SAMPLE_16_DATA.chunks_exact(2).map(|chunk| i16::from_le_bytes([chunk[0], chunk[1]])).collect::<Vec<_>>()));
 
         // todo: checking if the samples are in fact compressed wouldn't hurt :)
     }
 
+    #[test]
+    fn lazy_samples() {
+        let mut module = ensure_parse(module_file, include_bytes!("../tests/song_message.it"));
+        module.samples = ensure_parse(instrument_file, include_bytes!("../tests/compression/compressed.iti")).samples;
+        let options = crate::writer::WriteOptions {
+            compression: crate::writer::Compression::It215,
+            ..crate::writer::WriteOptions::default()
+        };
+        let (bytes, _) = crate::writer::module_file_with_options(&module, &options).unwrap();
+
+        let parsed = ensure_parse(module_file_lazy, &bytes);
+        assert!(parsed.samples.iter().all(|sample| sample.data.is_none()));
+        let lazy = || parsed.samples.iter().map(|sample| sample.lazy_data.as_ref().unwrap());
+        assert_eq!(parsed.samples.iter().map(Sample::length).collect::<Vec<_>>(), module.samples.iter().map(Sample::length).collect::<Vec<_>>());
+        assert!(lazy().all(|data| !data.is_decoded()));
+        for (sample, parsed) in module.samples.iter().zip(&parsed.samples) {
+            assert_eq!(parsed.decoded_data(), sample.data.as_ref());
+        }
+        assert!(lazy().all(LazySampleData::is_decoded));
+
+        // The player and the writer decode the data.
+        let render = |module: &Module| {
+            let mut player = crate::player::Player::new(module.clone(), crate::player::PlayerOptions::default());
+            let mut out = vec![0.0; 2 * 4096];
+            player.render_f32(&mut out);
+            out
+        };
+        assert_eq!(render(&ensure_parse(module_file_lazy, &bytes)), render(&module));
+        let written = crate::writer::module_file(&parsed).unwrap();
+        let reparsed = ensure_parse(module_file, &written);
+        let data = |module: &Module| module.samples.iter().map(|sample| sample.data.clone()).collect::<Vec<_>>();
+        assert_eq!(data(&reparsed), data(&module));
+
+        // Truncated data is only noticed when decoding it.
+        let truncated = ensure_parse(module_file_lazy, &bytes[..bytes.len() - 10]);
+        assert_eq!(truncated.samples[1].decoded_data(), None);
+        assert_eq!(
+            crate::writer::module_file(&truncated),
+            Err(crate::error::WriteError::InvalidSampleData { sample: Some(SampleId::from_index(1).unwrap()) }),
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let mut module = ensure_parse(module_file, include_bytes!("../tests/effect_alphabet.it"));
+        module.samples = ensure_parse(instrument_file, include_bytes!("../tests/compression/compressed.iti")).samples;
+        let bytes = crate::writer::module_file(&module).unwrap();
+        let too_large = |limits: Limits| match module_file_with_limits::<VerboseError<&[u8]>>(&bytes, &limits) {
+            Err(Err::Error(error)) => error.errors.iter().any(|(_, kind)| *kind == VerboseErrorKind::Nom(ErrorKind::TooLarge)),
+            _ => false,
+        };
+
+        let sample_bytes = module.samples.iter().map(|sample| {
+            let data = sample.data.as_ref().unwrap();
+            data.len() * if data.is_16bit() { 2 } else { 1 }
+        }).sum::<usize>();
+        let rows = module.patterns.iter().map(|pattern| pattern.rows.len()).max().unwrap();
+        let limits = Limits { max_sample_bytes: sample_bytes, max_rows: rows, ..Limits::default() };
+        let parsed = module_file_with_limits::<VerboseError<&[u8]>>(&bytes, &limits).unwrap();
+        assert_eq!((parsed.samples, parsed.patterns), (module.samples, module.patterns));
+        assert!(too_large(Limits { max_sample_bytes: sample_bytes - 1, ..limits }));
+        assert!(too_large(Limits { max_rows: rows - 1, ..limits }));
+        assert!(too_large(Limits { max_pattern_bytes: 16, ..limits }));
+    }
+
+    #[test]
+    fn cheap_errors() {
+        const MODULE_DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let module = ensure_parse(module_file, MODULE_DATA);
+        assert_eq!(module_file::<()>(MODULE_DATA), Ok(module.clone()));
+        assert_eq!(module_file::<nom::error::Error<&[u8]>>(MODULE_DATA), Ok(module));
+
+        let truncated = &MODULE_DATA[..0x40];
+        assert!(module_file::<()>(truncated).is_err());
+        assert!(module_file::<nom::error::Error<&[u8]>>(truncated).is_err());
+    }
+
+    #[test]
+    fn repairs() {
+        const MODULE_DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let (module, file_repairs) = ensure_parse(module_file_with_repairs, MODULE_DATA);
+        assert_eq!(module, ensure_parse(module_file, MODULE_DATA));
+
+        // Global volume over 128 and tempo under 31.
+        let mut data = MODULE_DATA.to_vec();
+        data[0x30] = 200;
+        data[0x33] = 20;
+        let (module, repairs) = ensure_parse(module_file_with_repairs, &data);
+        assert_eq!((module.global_volume(), module.tempo()), (128, 120));
+        assert_eq!(repairs[..2], [
+            Repair { message: "global_volume cannot be more than 128, clipping", values: String::from("globalvol = 200") },
+            Repair { message: "tempo must be at least 31, using default of 120", values: String::new() },
+        ]);
+        assert_eq!(repairs[2..], file_repairs[..]);
+    }
+
     #[test]
     fn song_message() {
         const MODULE_DATA: &[u8] = include_bytes!("../tests/song_message.it");
@@ -743,4 +1225,53 @@ mod test {
         let module = ensure_parse(module_file, MODULE_DATA);
         assert_eq!(module.message, MODULE_SONG_MESSAGE.to_string());
     }
+
+    #[test]
+    fn set_message() {
+        let mut module = ensure_parse(module_file, include_bytes!("../tests/song_message.it"));
+
+        // The second line is 20 words, 99 characters, wrapped on the space at the limit.
+        let words = |count| vec!["word"; count].join(" ");
+        let report = module.set_message(&format!("Naïve ♪\n{}\r\nx", words(20)));
+        assert_eq!(module.message, format!("Naïve ?\r{}\r{}\rx", words(15), words(5)));
+        assert_eq!(report, MessageReport { unmappable: vec!['♪'], wrapped_lines: 1, truncated: false });
+        assert!(module.flags.contains(ModuleFlags::MESSAGE_ATTACHED));
+
+        let bytes = crate::writer::module_file(&module).unwrap();
+        assert_eq!(ensure_parse(module_file, &bytes).message, module.message);
+
+        // Words longer than a line are broken, the message is cut at the maximum length.
+        let report = module.set_message(&"a".repeat(8100));
+        assert_eq!(module.message.len(), Module::MESSAGE_MAX_LENGTH);
+        assert!(module.message.split('\r').all(|line| line.len() <= Module::MESSAGE_LINE_LENGTH));
+        assert_eq!(report, MessageReport { unmappable: Vec::new(), wrapped_lines: 1, truncated: true });
+
+        let report = module.set_message("");
+        assert_eq!(report, MessageReport::default());
+        assert!(module.message.is_empty());
+        assert!(!module.flags.contains(ModuleFlags::MESSAGE_ATTACHED));
+    }
+
+    #[test]
+    fn channel_settings() {
+        // Channel 2 is muted and panned left, 3 is surround, 4 is muted surround at half volume
+        // and 5 has an invalid panning and volume.
+        let mut data = include_bytes!("../tests/song_message.it").to_vec();
+        data[0x41] = 128 | 16;
+        data[0x42] = 100;
+        data[0x43] = 128 | 100;
+        data[0x83] = 32;
+        data[0x44] = 80;
+        data[0x84] = 100;
+
+        let module = ensure_parse(module_file, &data);
+        let volume = |idx: usize| RangedU8::try_from(data[0x80 + idx]).unwrap();
+        let position = |pan: u8| ChannelPanning::Position(RangedU8::try_from(pan).unwrap());
+        assert_eq!(module.channels[1], ChannelSettings { panning: position(16), muted: true, volume: volume(1) });
+        assert_eq!(module.channels[2], ChannelSettings { panning: ChannelPanning::Surround, muted: false, volume: volume(2) });
+        assert_eq!(module.channels[3], ChannelSettings { panning: ChannelPanning::Surround, muted: true, volume: volume(3) });
+        assert_eq!(module.channels[4], ChannelSettings { panning: position(32), muted: false, volume: RangedU8::try_from(64).unwrap() });
+        assert_eq!(module.channels[2].pan_position(), None);
+        assert_eq!(module.channels[3].volume.as_u8(), 32);
+    }
 }
@@ -0,0 +1,118 @@
+//! Parsing into a bump arena
+
+use super::*;
+use bumpalo::collections::{String as BumpString, Vec as BumpVec};
+use bumpalo::Bump;
+use std::mem;
+
+
+/// Module parsed by [`module_file_in`]
+///
+/// Holds the song name, the message, the orders and the patterns with everything they reference
+/// allocated in the arena. Instruments and samples aren't part of it, use [`module_file`] when
+/// they're needed.
+#[derive(Clone, Copy, Debug)]
+pub struct ArenaModule<'b> {
+    pub name: Name,
+    pub message: &'b str,
+    pub orders: &'b [Order],
+    pub patterns: &'b [ArenaPattern<'b>],
+}
+
+/// Pattern allocated in a bump arena
+///
+/// Each row is a slice of commands sorted by channel, like [`Row`].
+#[derive(Clone, Copy, Debug)]
+pub struct ArenaPattern<'b> {
+    pub rows: &'b [&'b [(Channel, Command)]],
+}
+
+/// Parse Impulse Tracker module file (.it) allocating the patterns, rows and strings from `bump`
+///
+/// Parsing many modules into one arena and resetting it between batches avoids most of the
+/// allocations of [`module_file`], which allocates every row of every pattern separately.
+pub fn module_file_in<'b, 'i, E>(bump: &'b Bump, input: &'i [u8]) -> Result<ArenaModule<'b>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let (_, header) = module_header(input)?;
+
+    let mut patterns = BumpVec::with_capacity_in(header.pattern_offsets.len(), bump);
+    for cells in module_patterns(input)? {
+        patterns.push(pattern_in(bump, cells?)?);
+    }
+
+    let message = {
+        let offset = header.message_offset.cast::<usize>();
+        let mut message = BumpString::new_in(bump);
+        if offset != 0 && offset < input.len() {
+            let (_, bytes) = take(header.message_length.cast::<usize>())(&input[offset..])?;
+            let bytes_before_terminator = bytes.split(|&x| x == b'\0').next().unwrap();
+            message.extend(bytes_before_terminator.iter().copied().map(cp437::decode_byte));
+        }
+        message.into_bump_str()
+    };
+
+    Ok(ArenaModule {
+        name: header.name,
+        message,
+        orders: bump.alloc_slice_copy(&header.orders),
+        patterns: patterns.into_bump_slice(),
+    })
+}
+
+/// Collects the commands of a pattern into rows allocated from `bump`.
+fn pattern_in<'b, 'i, E>(bump: &'b Bump, cells: PatternCells<'i, E>) -> Result<ArenaPattern<'b>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let rows = usize::from(cells.rows());
+    let mut commands = BumpVec::new_in(bump);
+    let mut ends = BumpVec::with_capacity_in(rows, bump);
+    for cell in cells {
+        let (row, channel, command) = cell?;
+        ends.resize(usize::from(row), commands.len());
+        commands.push((channel, command));
+    }
+    ends.resize(rows, commands.len());
+
+    let mut rest = commands.into_bump_slice_mut();
+    let mut start = 0;
+    let rows = bump.alloc_slice_fill_iter(ends.iter().map(|&end| {
+        let (row, tail) = mem::take(&mut rest).split_at_mut(end - start);
+        row.sort_unstable_by_key(|(channel, _)| *channel);
+        rest = tail;
+        start = end;
+        &*row
+    }));
+    Ok(ArenaPattern { rows })
+}
+
+impl ArenaPattern<'_> {
+    /// Copies the pattern out of the arena.
+    pub fn to_pattern(&self) -> Pattern {
+        Pattern {
+            active_channels: self.rows.iter().flat_map(|row| row.iter().map(|(channel, _)| *channel)).collect(),
+            rows: self.rows.iter().map(|row| Row::from_vec(row.to_vec())).collect(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    #[test]
+    fn arena() {
+        let bump = Bump::new();
+        for data in [&include_bytes!("../../tests/effect_alphabet.it")[..], include_bytes!("../../tests/song_message.it")] {
+            let module = module_file::<VerboseError<&[u8]>>(data).unwrap();
+            let parsed = module_file_in::<VerboseError<&[u8]>>(&bump, data).unwrap();
+            assert_eq!((parsed.name, parsed.message, parsed.orders), (module.name, &module.message[..], &module.orders[..]));
+            let patterns = parsed.patterns.iter().map(ArenaPattern::to_pattern).collect::<Vec<_>>();
+            assert_eq!(patterns, module.patterns);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Import of WAV and AIFF files as samples
+
+use crate::convert::round_clamp;
+use crate::data::*;
+use crate::error::ContextError;
+use nom::bytes::complete::{tag, take};
+use nom::error::ParseError;
+use nom::number::complete::{le_u16, le_u32};
+#[cfg(feature = "aiff")]
+use nom::number::complete::{be_u16, be_u32};
+use nom::{Err, IResult};
+use std::convert::TryFrom;
+
+
+/// How samples with more than one channel are converted to mono
+///
+/// IT samples are always mono.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StereoMode {
+    /// Average of all channels
+    #[default]
+    Mix,
+
+    /// First channel
+    Left,
+
+    /// Second channel, the first one if there is only one
+    Right,
+}
+
+/// Encoding of the sample values in the file
+#[derive(Clone, Copy)]
+enum Encoding {
+    Unsigned8,
+    Signed { bytes: usize, big_endian: bool },
+    Float32,
+}
+
+/// `WAVE_FORMAT_PCM`
+const FORMAT_PCM: u16 = 1;
+/// `WAVE_FORMAT_IEEE_FLOAT`
+const FORMAT_FLOAT: u16 = 3;
+/// `WAVE_FORMAT_EXTENSIBLE`, the format is the start of the sub-format GUID
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+
+/// Parse a PCM WAV file into a sample
+///
+/// 8-bit and 16-bit data is kept as it is, 24-bit, 32-bit and floating point data is converted
+/// to 16 bits. Channels are mixed or picked according to `stereo`. The sample rate becomes the
+/// C-5 frequency. The first loop of the `smpl` chunk becomes the loop and the second one the
+/// sustain loop (the order [`Sample::write_wav`] writes them in), loops which don't fit the data
+/// are left out.
+pub fn wav_file<'i, E>(input: &'i [u8], stereo: StereoMode) -> Result<Sample, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let file = input;
+    let (input, _) = tag(b"RIFF")(input)?;
+    let (input, _size) = le_u32(input)?;
+    let (mut input, _) = tag(b"WAVE")(input)?;
+
+    let mut format = None;
+    let mut data = None;
+    let mut loops = Vec::new();
+    while !input.is_empty() {
+        let (rest, (id, body)) = chunk(le_u32)(input)?;
+        input = rest;
+        match id {
+            b"fmt " => format = Some(context!(wav_format, "in fmt chunk")(body)?.1),
+            b"data" => data = Some(body),
+            b"smpl" => loops = context!(smpl_loops, "in smpl chunk")(body)?.1,
+            _ => {}
+        }
+    }
+
+    let Some((encoding, channels, samplerate)) = format else {
+        bail!(file, "WAV file has no fmt chunk");
+    };
+    let Some(data) = data else {
+        bail!(file, "WAV file has no data chunk");
+    };
+    Ok(sample(data, encoding, channels, stereo, samplerate, &loops))
+}
+
+/// Parse an AIFF file into a sample
+///
+/// Sample data is converted like in [`wav_file`]. The sustain loop of the `INST` chunk becomes
+/// the sustain loop and the release loop the loop, files with only a sustain loop (as
+/// [`Sample::write_aiff`] writes loops of samples without a sustain loop) get it as the loop.
+/// Compressed AIFF-C files are not supported.
+#[cfg(feature = "aiff")]
+pub fn aiff_file<'i, E>(input: &'i [u8], stereo: StereoMode) -> Result<Sample, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let file = input;
+    let (input, _) = tag(b"FORM")(input)?;
+    let (input, _size) = be_u32(input)?;
+    let (mut input, _) = tag(b"AIFF")(input)?;
+
+    let mut format = None;
+    let mut data = None;
+    let mut markers = Vec::new();
+    let mut instrument_loops = None;
+    while !input.is_empty() {
+        let (rest, (id, body)) = chunk(be_u32)(input)?;
+        input = rest;
+        match id {
+            b"COMM" => format = Some(context!(aiff_format, "in COMM chunk")(body)?.1),
+            b"SSND" => {
+                let (body, offset) = be_u32(body)?;
+                let (body, _block_size) = be_u32(body)?;
+                data = Some(body.get(usize::try_from(offset).unwrap_or(usize::MAX)..).unwrap_or(&[]));
+            }
+            b"MARK" => markers = context!(aiff_markers, "in MARK chunk")(body)?.1,
+            b"INST" => instrument_loops = Some(context!(aiff_loops, "in INST chunk")(body)?.1),
+            _ => {}
+        }
+    }
+
+    let Some((channels, frames, bits, samplerate)) = format else {
+        bail!(file, "AIFF file has no COMM chunk");
+    };
+    let data = data.unwrap_or(&[]);
+    let bytes = (usize::from(bits) + 7) / 8;
+    let encoding = match bytes {
+        1..=4 => Encoding::Signed { bytes, big_endian: true },
+        _ => bail!(file, "unsupported AIFF sample size {}", bits),
+    };
+    let length = frames.saturating_mul(bytes * channels).min(data.len());
+
+    // Loops are stored as pairs of marker IDs.
+    let position = |id| markers.iter().find(|&&(marker, _)| marker == id).map(|&(_, position)| position);
+    let to_loop = |(mode, begin, end): (u16, u16, u16)| {
+        let bidi = match mode {
+            1 => false,
+            2 => true,
+            _ => return None,
+        };
+        Some(SampleLoop { start: position(begin)?, end: position(end)?, bidi })
+    };
+    let [sustain_loop, release_loop] = instrument_loops.unwrap_or_default().map(to_loop);
+    let loops = match (sustain_loop, release_loop) {
+        (sustain_loop, None) => vec![sustain_loop],
+        (sustain_loop, release_loop) => vec![release_loop, sustain_loop],
+    };
+    Ok(sample(&data[..length], encoding, channels, stereo, samplerate, &loops))
+}
+
+/// Parses a chunk with the size read by `size`, skipping the padding byte of odd sizes.
+fn chunk<'i, E>(
+    size: impl Fn(&'i [u8]) -> IResult<&'i [u8], u32, E>,
+) -> impl FnMut(&'i [u8]) -> IResult<&'i [u8], (&'i [u8], &'i [u8]), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    move |input| {
+        let (input, id) = take(4usize)(input)?;
+        let (input, length) = size(input)?;
+        // Truncated last chunks are common, take what is there.
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        let (input, data) = take(length.min(input.len()))(input)?;
+        let input = if length % 2 == 1 { input.get(1..).unwrap_or(input) } else { input };
+        Ok((input, (id, data)))
+    }
+}
+
+/// Parses the `fmt ` chunk into the encoding, number of channels and sample rate.
+fn wav_format<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], (Encoding, usize, u32), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, format) = le_u16(input)?;
+    let (rest, channels) = le_u16(rest)?;
+    let (rest, samplerate) = le_u32(rest)?;
+    let (rest, _byte_rate) = le_u32(rest)?;
+    let (rest, _block_align) = le_u16(rest)?;
+    let (rest, bits) = le_u16(rest)?;
+    let format = if format == FORMAT_EXTENSIBLE {
+        // Extension size, valid bits and channel mask precede the sub-format.
+        let (_, sub_format) = le_u16(rest.get(8..).unwrap_or(&[]))?;
+        sub_format
+    } else {
+        format
+    };
+
+    let encoding = match (format, bits) {
+        (FORMAT_PCM, 8) => Encoding::Unsigned8,
+        (FORMAT_PCM, 16 | 24 | 32) => Encoding::Signed { bytes: usize::from(bits / 8), big_endian: false },
+        (FORMAT_FLOAT, 32) => Encoding::Float32,
+        _ => bail!(input, "unsupported WAV format {} with {} bits", format, bits),
+    };
+    if channels == 0 {
+        bail!(input, "WAV file has no channels");
+    }
+    Ok((rest, (encoding, usize::from(channels), samplerate)))
+}
+
+/// Parses the loops of the `smpl` chunk.
+fn smpl_loops<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Vec<Option<SampleLoop>>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, header) = take(36usize)(input)?;
+    let count = u32::from_le_bytes(header[28..32].try_into().unwrap());
+    let mut loops = Vec::new();
+    let mut input = input;
+    for _ in 0..count.min(2) {
+        let (rest, _cue) = le_u32(input)?;
+        let (rest, kind) = le_u32(rest)?;
+        let (rest, start) = le_u32(rest)?;
+        let (rest, end) = le_u32(rest)?;
+        let (rest, _) = take(8usize)(rest)?;
+        input = rest;
+        // The end is inclusive in the chunk.
+        let bidi = kind == 1;
+        loops.push((kind <= 1).then_some(SampleLoop { start, end: end.saturating_add(1), bidi }));
+    }
+    Ok((input, loops))
+}
+
+/// Parses the `COMM` chunk into the number of channels, frames, bits and the sample rate.
+#[cfg(feature = "aiff")]
+fn aiff_format<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], (usize, usize, u16, u32), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, channels) = be_u16(input)?;
+    let (input, frames) = be_u32(input)?;
+    let (input, bits) = be_u16(input)?;
+    let (input, exponent) = be_u16(input)?;
+    let (input, mantissa) = take(8usize)(input)?;
+    if channels == 0 {
+        bail!(input, "AIFF file has no channels");
+    }
+
+    // 80-bit extended precision sample rate, only the integer part is used.
+    let mantissa = u64::from_be_bytes(mantissa.try_into().unwrap());
+    let shift = (16383 + 63u32).checked_sub(u32::from(exponent & 0x7FFF)).filter(|&shift| shift < 64);
+    let samplerate = shift.map_or(0, |shift| u32::try_from(mantissa >> shift).unwrap_or(u32::MAX));
+
+    let frames = usize::try_from(frames).unwrap_or(usize::MAX);
+    Ok((input, (usize::from(channels), frames, bits, samplerate)))
+}
+
+/// Parses the `MARK` chunk into marker IDs and positions.
+#[cfg(feature = "aiff")]
+fn aiff_markers<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Vec<(u16, u32)>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (mut input, count) = be_u16(input)?;
+    let mut markers = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        let (rest, id) = be_u16(input)?;
+        let (rest, position) = be_u32(rest)?;
+        let (rest, name_length) = nom::number::complete::u8(rest)?;
+        // The name is padded to make the count and the text an even length.
+        let (rest, _name) = take(usize::from(name_length) | 1)(rest)?;
+        input = rest;
+        markers.push((id, position));
+    }
+    Ok((input, markers))
+}
+
+/// Parses the sustain and release loops of the `INST` chunk as play mode and marker IDs.
+#[cfg(feature = "aiff")]
+fn aiff_loops<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], [(u16, u16, u16); 2], E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (mut input, _) = take(8usize)(input)?;
+    let mut loops = [(0, 0, 0); 2];
+    for sample_loop in &mut loops {
+        let (rest, mode) = be_u16(input)?;
+        let (rest, begin) = be_u16(rest)?;
+        let (rest, end) = be_u16(rest)?;
+        input = rest;
+        *sample_loop = (mode, begin, end);
+    }
+    Ok((input, loops))
+}
+
+/// Converts interleaved frames into a mono sample.
+fn sample(
+    data: &[u8],
+    encoding: Encoding,
+    channels: usize,
+    stereo: StereoMode,
+    samplerate: u32,
+    loops: &[Option<SampleLoop>],
+) -> Sample {
+    let bytes = match encoding {
+        Encoding::Unsigned8 => 1,
+        Encoding::Signed { bytes, .. } => bytes,
+        Encoding::Float32 => 4,
+    };
+    let frames = data
+        .chunks_exact(bytes * channels)
+        .map(|frame| {
+            let mut values = frame.chunks_exact(bytes).map(|value| decode(value, encoding));
+            match stereo {
+                StereoMode::Mix => {
+                    let sum = values.map(i64::from).sum::<i64>();
+                    i32::try_from(sum / i64::try_from(channels).unwrap()).unwrap()
+                }
+                StereoMode::Left => values.next().unwrap(),
+                StereoMode::Right => values.nth(1.min(channels - 1)).unwrap(),
+            }
+        });
+    let data = if bytes == 1 {
+        SampleData::from(frames.map(|value| i8::try_from(value).unwrap()).collect::<Vec<_>>())
+    } else {
+        SampleData::from(frames.map(|value| i16::try_from(value).unwrap()).collect::<Vec<_>>())
+    };
+
+    let length = u32::try_from(data.len()).unwrap_or(u32::MAX);
+    let valid = |sample_loop: &Option<SampleLoop>| sample_loop.filter(|sample_loop| sample_loop.validate(length).is_ok());
+    Sample {
+        name: Name { bytes: [0; 26] },
+        filename: DosFilename::sanitize(""),
+        global_volume: 64,
+        default_volume: 64,
+        default_panning: 32,
+        loop_: loops.first().and_then(valid),
+        sustain_loop: loops.get(1).and_then(valid),
+        samplerate_c5: samplerate,
+        vibrato_speed: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        vibrato_type: 0,
+        data: (!data.is_empty()).then_some(data),
+        lazy_data: None,
+    }
+}
+
+/// Decodes a single value, in the 8-bit range for 8-bit data and the 16-bit range otherwise
+fn decode(value: &[u8], encoding: Encoding) -> i32 {
+    match encoding {
+        Encoding::Unsigned8 => i32::from(i8::from_le_bytes([value[0] ^ 0x80])),
+        Encoding::Signed { bytes: 1, .. } => i32::from(i8::from_le_bytes([value[0]])),
+        // Only the two most significant bytes are kept.
+        Encoding::Signed { big_endian: false, bytes } => i32::from(i16::from_le_bytes([value[bytes - 2], value[bytes - 1]])),
+        Encoding::Signed { big_endian: true, .. } => i32::from(i16::from_be_bytes([value[0], value[1]])),
+        Encoding::Float32 => {
+            let value = f32::from_le_bytes(value.try_into().unwrap());
+            i32::from(round_clamp(f64::from(value) * 32768.0, i16::MIN, i16::MAX))
+        }
+    }
+}
+
+impl Sample {
+    /// Parse a WAV file mixing stereo data down to mono, see [`wav_file`].
+    pub fn from_wav<'i, E>(input: &'i [u8]) -> Result<Sample, Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+    {
+        wav_file(input, StereoMode::Mix)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    fn test_sample(data: SampleData) -> Sample {
+        let mut sample = Sample::from_wav::<VerboseError<&[u8]>>(&wav(1, 8, &[0x80])).unwrap();
+        sample.data = Some(data);
+        sample.samplerate_c5 = 22050;
+        sample
+    }
+
+    /// Minimal WAV file without loops
+    fn wav(channels: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"RIFF");
+        file.extend_from_slice(&u32::try_from(28 + 8 + data.len()).unwrap().to_le_bytes());
+        file.extend_from_slice(b"WAVEfmt ");
+        file.extend_from_slice(&16u32.to_le_bytes());
+        file.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        file.extend_from_slice(&channels.to_le_bytes());
+        file.extend_from_slice(&44100u32.to_le_bytes());
+        file.extend_from_slice(&(44100 * u32::from(channels * bits / 8)).to_le_bytes());
+        file.extend_from_slice(&(channels * bits / 8).to_le_bytes());
+        file.extend_from_slice(&bits.to_le_bytes());
+        file.extend_from_slice(b"data");
+        file.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+        file.extend_from_slice(data);
+        file
+    }
+
+    #[test]
+    fn wav_roundtrip() {
+        let mut sample = test_sample(SampleData::from(vec![0i16, 1000, -1000, 32767, -32768]));
+        sample.set_loop(Some(SampleLoop { start: 1, end: 5, bidi: true })).unwrap();
+        sample.set_sustain_loop(Some(SampleLoop { start: 0, end: 2, bidi: false })).unwrap();
+        let mut file = Vec::new();
+        sample.write_wav(&mut file).unwrap();
+        assert_eq!(Sample::from_wav::<VerboseError<&[u8]>>(&file).unwrap(), sample);
+
+        let sample = test_sample(SampleData::from(vec![-128i8, 0, 127]));
+        let mut file = Vec::new();
+        sample.write_wav(&mut file).unwrap();
+        assert_eq!(Sample::from_wav::<VerboseError<&[u8]>>(&file).unwrap(), sample);
+    }
+
+    #[test]
+    fn stereo_24bit() {
+        let data = [
+            0x00, 0x00, 0x40, 0x00, 0x00, 0xC0,
+            0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x00,
+        ];
+        let file = wav(2, 24, &data);
+        let parse = |stereo| wav_file::<VerboseError<&[u8]>>(&file, stereo).unwrap().data.unwrap();
+        assert_eq!(parse(StereoMode::Mix), SampleData::from(vec![0i16, 16383]));
+        assert_eq!(parse(StereoMode::Left), SampleData::from(vec![0x4000i16, 0x7FFF]));
+        assert_eq!(parse(StereoMode::Right), SampleData::from(vec![-0x4000i16, 0]));
+    }
+
+    #[cfg(feature = "aiff")]
+    #[test]
+    fn aiff_roundtrip() {
+        let mut sample = test_sample(SampleData::from(vec![0i16, 1000, -1000, 32767, -32768]));
+        sample.set_loop(Some(SampleLoop { start: 1, end: 5, bidi: true })).unwrap();
+        let mut file = Vec::new();
+        sample.write_aiff(&mut file).unwrap();
+        assert_eq!(aiff_file::<VerboseError<&[u8]>>(&file, StereoMode::Mix).unwrap(), sample);
+    }
+}
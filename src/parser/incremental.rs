@@ -0,0 +1,183 @@
+use super::*;
+use crate::error::VerboseError;
+use core::ops::Range;
+
+
+/// Parser for module files arriving in pieces, e.g. through HTTP range requests
+///
+/// Feed it the bytes received so far with [`IncrementalParser::feed`] and ask which bytes are
+/// needed next with [`IncrementalParser::needed`]. The needed ranges are found by reading the
+/// structures received so far: the header, then the instruments, the sample headers, the
+/// patterns and the sample data they point at. Bytes the parser doesn't read, like padding or
+/// unreferenced data, are never requested. Once nothing more is needed,
+/// [`IncrementalParser::finish`] parses the module.
+///
+/// A file shorter than the structures it declares keeps needing the same range, stop requesting
+/// when the server has no more data and call [`IncrementalParser::finish`] for the error.
+#[derive(Clone, Debug, Default)]
+pub struct IncrementalParser {
+    data: Vec<u8>,
+
+    /// Received byte ranges, sorted and merged
+    received: Vec<Range<usize>>,
+}
+
+impl IncrementalParser {
+    pub fn new() -> IncrementalParser {
+        IncrementalParser::default()
+    }
+
+    /// Adds `bytes` received from the file starting at `offset`.
+    pub fn feed(&mut self, offset: usize, bytes: &[u8]) {
+        let range = offset..offset + bytes.len();
+        if range.is_empty() {
+            return;
+        }
+        if self.data.len() < range.end {
+            self.data.resize(range.end, 0);
+        }
+        self.data[range.clone()].copy_from_slice(bytes);
+
+        // Merge the range with the received ranges it overlaps or touches.
+        let first = self.received.partition_point(|received| received.end < range.start);
+        let last = self.received.partition_point(|received| received.start <= range.end);
+        let merged = self.received[first..last]
+            .iter()
+            .fold(range, |merged, received| merged.start.min(received.start)..merged.end.max(received.end));
+        self.received.splice(first..last, [merged]);
+    }
+
+    /// Returns the next byte range of the file the parser needs, `None` when it has everything.
+    ///
+    /// The range starts at the first missing byte of the next structure, the parser may need
+    /// more ranges after receiving it. `None` is also returned when a received structure is
+    /// invalid, [`IncrementalParser::finish`] reports the error then.
+    pub fn needed(&self) -> Option<Range<usize>> {
+        self.walk().err()
+    }
+
+    /// Returns `true` when the parser has all the bytes it needs.
+    pub fn is_complete(&self) -> bool {
+        self.needed().is_none()
+    }
+
+    /// Parses the module from the bytes received, fails with [`ErrorKind::Eof`] if some are
+    /// still missing.
+    pub fn finish<'i, E>(&'i self) -> Result<Module, Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+    {
+        if let Some(range) = self.needed() {
+            let input = self.data.get(range.start..).unwrap_or(&[]);
+            return Err(Err::Error(E::from_error_kind(input, ErrorKind::Eof)));
+        }
+        // The bytes between the structures are never read, it doesn't matter they're missing.
+        module_file(&self.data)
+    }
+
+    /// Returns the bytes of `range`, or the part of it starting at the first missing byte.
+    fn get(&self, range: Range<usize>) -> Result<&[u8], Range<usize>> {
+        let covering = self.received.iter().find(|received| received.contains(&range.start));
+        match covering {
+            Some(received) if received.end >= range.end => Ok(&self.data[range]),
+            Some(received) => Err(received.end..range.end),
+            None if range.is_empty() => Ok(&[]),
+            None => Err(range),
+        }
+    }
+
+    /// Walks the structures of the module, fails with the first missing byte range.
+    fn walk(&self) -> Result<(), Range<usize>> {
+        // The counts at the start of the header give the size of the rest of it.
+        let fixed = self.get(0..0xC0)?;
+        let count = |offset: usize| usize::from(u16::from_le_bytes([fixed[offset], fixed[offset + 1]]));
+        let size = 0xC0 + count(0x20) + 4 * (count(0x22) + count(0x24) + count(0x26));
+        let Ok((_, header)) = module_header::<VerboseError<&[u8]>>(self.get(0..size)?) else {
+            return Ok(());
+        };
+
+        let message_offset = header.message_offset.cast::<usize>();
+        if message_offset != 0 {
+            self.get(message_offset..message_offset + header.message_length.cast::<usize>())?;
+        }
+        for offset in header.instrument_offsets.iter().map(|&offset| offset.cast::<usize>()) {
+            self.get(offset..offset + INSTRUMENT_SIZE)?;
+        }
+        for offset in header.pattern_offsets.iter().map(|&offset| offset.cast::<usize>()).filter(|&offset| offset != 0) {
+            let pattern_header = self.get(offset..offset + PATTERN_HEADER_SIZE)?;
+            let length = usize::from(u16::from_le_bytes([pattern_header[0], pattern_header[1]]));
+            self.get(offset..offset + PATTERN_HEADER_SIZE + length)?;
+        }
+        for offset in header.sample_offsets.iter().map(|&offset| offset.cast::<usize>()) {
+            let bytes = self.get(offset..offset + SAMPLE_HEADER_SIZE)?;
+            let Ok((_, sample)) = sample_header::<VerboseError<&[u8]>>(bytes) else {
+                return Ok(());
+            };
+            if sample.flags.contains(SampleFlags::DATA_PRESENT) {
+                self.sample_data(&sample)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the stored data of a sample, compressed data block by block.
+    fn sample_data(&self, header: &SampleHeader) -> Result<(), Range<usize>> {
+        let offset = header.data_offset.cast::<usize>();
+        let length = header.data_length.cast::<usize>();
+        let bytes_per_sample = if header.flags.contains(SampleFlags::DATA_16BIT) { 2 } else { 1 };
+        if !header.flags.contains(SampleFlags::COMPRESSED) {
+            self.get(offset..offset + length * bytes_per_sample)?;
+            return Ok(());
+        }
+
+        let block_samples = BLOCK_SAMPLES_MAX_BYTE_LENGTH / bytes_per_sample;
+        let mut position = offset;
+        let mut remaining = length;
+        while remaining > 0 {
+            let block = self.get(position..position + 2)?;
+            let block_length = usize::from(u16::from_le_bytes([block[0], block[1]]));
+            self.get(position + 2..position + 2 + block_length)?;
+            position += 2 + block_length;
+            remaining -= min(remaining, block_samples);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn incremental() {
+        let mut module = module_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/song_message.it")).unwrap();
+        module.samples = instrument_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/compression/compressed.iti")).unwrap().samples;
+        let options = crate::writer::WriteOptions {
+            compression: crate::writer::Compression::It215,
+            ..crate::writer::WriteOptions::default()
+        };
+        let (bytes, _) = crate::writer::module_file_with_options(&module, &options).unwrap();
+
+        let mut parser = IncrementalParser::new();
+        parser.feed(0, &bytes[..100]);
+        assert_eq!(parser.needed(), Some(100..0xC0));
+
+        // Fetch exactly the requested ranges.
+        let mut requests = 0;
+        while let Some(range) = parser.needed() {
+            parser.feed(range.start, &bytes[range]);
+            requests += 1;
+        }
+        assert!(requests > 4);
+        assert!(parser.is_complete());
+        assert_eq!(parser.finish::<VerboseError<&[u8]>>().unwrap(), module_file::<VerboseError<&[u8]>>(&bytes).unwrap());
+
+        // Ranges received out of order are merged.
+        let mut parser = IncrementalParser::new();
+        parser.feed(200, &bytes[200..]);
+        assert_eq!(parser.needed(), Some(0..0xC0));
+        parser.feed(0, &bytes[..200]);
+        assert!(parser.is_complete());
+    }
+}
@@ -0,0 +1,286 @@
+use super::*;
+use core::ops::Range;
+use core::slice;
+
+
+/// Fields of the fixed part of the module header
+const MODULE_HEADER: &[(&str, usize)] = &[
+    ("magic", 4),
+    ("name", 26),
+    ("highlight_minor", 1),
+    ("highlight_major", 1),
+    ("order_count", 2),
+    ("instrument_count", 2),
+    ("sample_count", 2),
+    ("pattern_count", 2),
+    ("made_with_version", 2),
+    ("compatible_with_version", 2),
+    ("flags", 2),
+    ("special", 2),
+    ("global_volume", 1),
+    ("sample_volume", 1),
+    ("speed", 1),
+    ("tempo", 1),
+    ("pan_separation", 1),
+    ("pitch_wheel_depth", 1),
+    ("message_length", 2),
+    ("message_offset", 4),
+    ("reserved", 4),
+    ("channel_panning", 64),
+    ("channel_volume", 64),
+];
+
+/// Fields of an instrument header up to the envelopes
+const INSTRUMENT: &[(&str, usize)] = &[
+    ("magic", 4),
+    ("filename", 13),
+    ("new_note_action", 1),
+    ("duplicate_check_type", 1),
+    ("duplicate_check_action", 1),
+    ("instrument_fadeout", 2),
+    ("pitch_pan_separation", 1),
+    ("pitch_pan_centre", 1),
+    ("global_volume", 1),
+    ("default_panning", 1),
+    ("random_volume_variation", 1),
+    ("random_panning_variation", 1),
+    ("trkver", 2),
+    ("number_of_samples", 1),
+    ("reserved", 1),
+    ("name", 26),
+    ("initial_filter_cutoff", 1),
+    ("initial_filter_resonance", 1),
+    ("mch", 1),
+    ("mpr", 1),
+    ("mbank", 2),
+    ("sample_map", 240),
+];
+
+/// Fields of an envelope, the instrument header has three
+const ENVELOPE: &[(&str, usize)] = &[
+    ("flags", 1),
+    ("node_count", 1),
+    ("loop_start", 1),
+    ("loop_end", 1),
+    ("sustain_start", 1),
+    ("sustain_end", 1),
+    ("nodes", 75),
+    ("reserved", 1),
+];
+
+/// Fields of a sample header
+const SAMPLE_HEADER: &[(&str, usize)] = &[
+    ("magic", 4),
+    ("filename", 13),
+    ("global_volume", 1),
+    ("flags", 1),
+    ("default_volume", 1),
+    ("name", 26),
+    ("convert", 1),
+    ("default_panning", 1),
+    ("length", 4),
+    ("loop_start", 4),
+    ("loop_end", 4),
+    ("samplerate_c5", 4),
+    ("sustain_start", 4),
+    ("sustain_end", 4),
+    ("data_offset", 4),
+    ("vibrato_speed", 1),
+    ("vibrato_depth", 1),
+    ("vibrato_rate", 1),
+    ("vibrato_type", 1),
+];
+
+/// Fields of the pattern header preceding the packed data
+const PATTERN_HEADER: &[(&str, usize)] = &[
+    ("length", 2),
+    ("rows", 2),
+    ("reserved", 4),
+];
+
+
+/// Byte range of a module file and the structure it belongs to, see [`byte_map`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub range: Range<usize>,
+
+    /// Path of the structure, e.g. `header.speed`, `instrument[0].volume_envelope.nodes`,
+    /// `pattern[2].data` or `sample[3].header.length`, indices start from 0
+    pub path: String,
+}
+
+/// Regions of a module file sorted by their offset, see [`byte_map`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ByteMap {
+    regions: Vec<Region>,
+}
+
+impl ByteMap {
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, Region> {
+        self.regions.iter()
+    }
+
+    /// Returns the region containing the byte at `offset`.
+    ///
+    /// When structures overlap, which happens when a writer shares data between them, the region
+    /// starting last is returned.
+    pub fn lookup(&self, offset: usize) -> Option<&Region> {
+        let end = self.regions.partition_point(|region| region.range.start <= offset);
+        self.regions[..end].iter().rev().find(|region| region.range.contains(&offset))
+    }
+
+    /// Returns the path of the structure containing the byte at `offset`.
+    pub fn path(&self, offset: usize) -> Option<&str> {
+        self.lookup(offset).map(|region| region.path.as_str())
+    }
+}
+
+impl<'a> IntoIterator for &'a ByteMap {
+    type Item = &'a Region;
+    type IntoIter = slice::Iter<'a, Region>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+
+/// Map the bytes of an Impulse Tracker module file (.it) to the structures they belong to
+///
+/// Every byte of the file is in at least one [`Region`]. The headers are split into their fields,
+/// the pattern and sample data are a region each, the edit history and other data after the header
+/// tables is `header.extra` and bytes nothing references are `unknown`.
+///
+/// Only the module header has to be valid, the structures it points at are mapped as far as they
+/// can be read. Use [`ByteMap::path`] with the offset of a parse error to find the structure the
+/// parser failed on, or the regions to annotate the file in a hex editor.
+pub fn byte_map<'i, E>(input: &'i [u8]) -> Result<ByteMap, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (_, header) = module_header::<E>(input)?;
+    let mut regions = Vec::new();
+    let mut add = |range: Range<usize>, path: String| {
+        let range = range.start.min(input.len())..range.end.min(input.len());
+        if !range.is_empty() {
+            regions.push(Region { range, path });
+        }
+    };
+
+    // The parsed header skips invalid orders, count them from the header itself.
+    let offset = fields(&mut add, "header", 0, MODULE_HEADER);
+    let order_count = usize::from(u16::from_le_bytes([input[0x20], input[0x21]]));
+    add(offset..offset + order_count, "header.orders".to_owned());
+    let mut offset = offset + order_count;
+    for (name, offsets) in [
+        ("instrument_offsets", &header.instrument_offsets),
+        ("sample_offsets", &header.sample_offsets),
+        ("pattern_offsets", &header.pattern_offsets),
+    ] {
+        add(offset..offset + 4 * offsets.len(), format!("header.{name}"));
+        offset += 4 * offsets.len();
+    }
+
+    if header.message_offset != 0 {
+        let start = header.message_offset.cast::<usize>();
+        add(start..start + usize::from(header.message_length), "message".to_owned());
+    }
+
+    for (idx, start) in header.instrument_offsets.iter().map(|&offset| offset.cast::<usize>()).enumerate() {
+        let prefix = format!("instrument[{idx}]");
+        let offset = fields(&mut add, &prefix, start, INSTRUMENT);
+        let offset = ["volume_envelope", "panning_envelope", "pitch_filter_envelope"]
+            .into_iter()
+            .fold(offset, |offset, envelope| fields(&mut add, &format!("{prefix}.{envelope}"), offset, ENVELOPE));
+        add(offset..offset + 4, format!("{prefix}.reserved"));
+    }
+
+    for (idx, start) in header.sample_offsets.iter().map(|&offset| offset.cast::<usize>()).enumerate() {
+        fields(&mut add, &format!("sample[{idx}].header"), start, SAMPLE_HEADER);
+        let sample = match input.get(start..).map(sample_header::<E>) {
+            Some(Ok((_, sample))) if sample.flags.contains(SampleFlags::DATA_PRESENT) => sample,
+            _ => continue,
+        };
+        let data = sample.data_offset.cast::<usize>();
+        if let Some(stored) = input.get(data..) {
+            add(data..data + sample_data_size(&sample, stored), format!("sample[{idx}].data"));
+        }
+    }
+
+    for (idx, start) in header.pattern_offsets.iter().map(|&offset| offset.cast::<usize>()).enumerate() {
+        // Offset 0 is an empty pattern without any data.
+        if start == 0 {
+            continue;
+        }
+        let prefix = format!("pattern[{idx}]");
+        let offset = fields(&mut add, &format!("{prefix}.header"), start, PATTERN_HEADER);
+        if let Some(bytes) = input.get(start..start + 2) {
+            let length = usize::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+            add(offset..offset + length, format!("{prefix}.data"));
+        }
+    }
+
+    regions.sort_by_key(|region| (region.range.start, region.range.end));
+
+    // Fill the gaps, the first one after the header tables holds the edit history and the OpenMPT
+    // extensions.
+    let mut gaps = Vec::new();
+    let mut covered = 0;
+    for region in &regions {
+        if region.range.start > covered {
+            let path = if covered == header.header_size { "header.extra" } else { "unknown" };
+            gaps.push(Region { range: covered..region.range.start, path: path.to_owned() });
+        }
+        covered = covered.max(region.range.end);
+    }
+    if covered < input.len() {
+        gaps.push(Region { range: covered..input.len(), path: "unknown".to_owned() });
+    }
+    regions.extend(gaps);
+    regions.sort_by_key(|region| (region.range.start, region.range.end));
+
+    Ok(ByteMap { regions })
+}
+
+/// Adds a region for each of the `fields` laid out from `offset`, returns the offset after them.
+fn fields(add: &mut impl FnMut(Range<usize>, String), prefix: &str, offset: usize, fields: &[(&str, usize)]) -> usize {
+    fields.iter().fold(offset, |offset, &(name, size)| {
+        add(offset..offset + size, format!("{prefix}.{name}"));
+        offset + size
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    #[test]
+    fn byte_map_covers_file() {
+        let input = include_bytes!("../../tests/song_message.it");
+        let map = byte_map::<VerboseError<&[u8]>>(input).unwrap();
+
+        let mut covered = 0;
+        for region in &map {
+            assert!(region.range.start <= covered, "gap before {region:?}");
+            covered = covered.max(region.range.end);
+        }
+        assert_eq!(covered, input.len());
+
+        assert_eq!(map.path(0), Some("header.magic"));
+        assert_eq!(map.path(0x32), Some("header.speed"));
+        assert_eq!(map.lookup(0x40).map(|region| region.range.clone()), Some(0x40..0x80));
+
+        let (_, header) = module_header::<VerboseError<&[u8]>>(input).unwrap();
+        let sample = header.sample_offsets[0].cast::<usize>();
+        assert_eq!(map.path(sample), Some("sample[0].header.magic"));
+        assert_eq!(map.path(sample + 0x30), Some("sample[0].header.length"));
+        let message = header.message_offset.cast::<usize>();
+        assert_eq!(map.path(message), Some("message"));
+    }
+}
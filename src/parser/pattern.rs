@@ -1,4 +1,6 @@
 use super::*;
+use core::iter::FusedIterator;
+use core::marker::PhantomData;
 
 
 bitflags! {
@@ -60,31 +62,32 @@ impl Default for State {
 }
 
 
+/// Parses a pattern in a single pass over the packed data
+///
+/// The commands come decoded from [`PatternCells`], which tracks the mask and the last values of
+/// each channel, and are collected into rows as they arrive. Each row is allocated once with its
+/// final size, empty rows don't allocate.
 pub(super) fn pattern<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Pattern, E>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
 {
-    let (input, length) = le_u16(input)?;
-    let (input, rows) = le_u16(input)?;
-    let (input, _padding) = take(4usize)(input)?;
-    let (rest, input) = take(length)(input)?;
+    let (rest, cells) = packed(input)?;
 
+    let row_count = usize::from(cells.rows());
     let mut active_channels = ActiveChannels::empty();
-    let mut state = State::default();
-
-    let (_empty, rows) = context!(
-        all_consuming(count(
-            map(
-                many_till(command(&mut state), tag(b"\0")),
-                |(commands, _)| {
-                    active_channels |= commands.iter().map(|(chan, _)| *chan).collect();
-                    Row::from_vec(commands)
-                },
-            ),
-            rows.into(),
-        )),
-        "in pattern",
-    )(input)?;
+    let mut rows = Vec::with_capacity(row_count);
+    let mut commands = Vec::with_capacity(64);
+    for cell in cells {
+        let (row, channel, command) = cell?;
+        while rows.len() < usize::from(row) {
+            rows.push(Row::from_vec(commands.drain(..).collect()));
+        }
+        active_channels |= ActiveChannels::new([channel]);
+        commands.push((channel, command));
+    }
+    while rows.len() < row_count {
+        rows.push(Row::from_vec(commands.drain(..).collect()));
+    }
 
     Ok((
         rest,
@@ -95,6 +98,162 @@ where
     ))
 }
 
+/// Reads the pattern header, returns the input after the pattern and its commands.
+fn packed<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], PatternCells<'i, E>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let (input, length) = le_u16(input)?;
+    let (input, rows) = le_u16(input)?;
+    let (input, _padding) = take(4usize)(input)?;
+    let (rest, input) = take(length)(input)?;
+    Ok((
+        rest,
+        PatternCells {
+            input,
+            rows,
+            row: 0,
+            state: State::default(),
+            _error: PhantomData,
+        },
+    ))
+}
+
+/// Iterator over the commands of a packed pattern, see [`pattern_cells`]
+pub struct PatternCells<'i, E> {
+    input: &'i [u8],
+    rows: u16,
+    row: u16,
+    state: State,
+    _error: PhantomData<fn() -> E>,
+}
+
+/// Iterator over the patterns of a module file, see [`module_patterns`]
+pub struct ModulePatterns<'i, E> {
+    input: &'i [u8],
+    offsets: &'i [u8],
+    _error: PhantomData<fn() -> E>,
+}
+
+/// Decode the commands of a pattern straight from its packed bytes
+///
+/// `input` starts with the pattern header, like at the pattern offsets of a module file. The
+/// iterator yields the commands with their row and channel in the order they are stored, rows
+/// without commands are skipped. Unlike [`module_file`] nothing is allocated, which suits analysis
+/// passes over large archives. The iteration ends after the first error.
+pub fn pattern_cells<'i, E>(input: &'i [u8]) -> Result<PatternCells<'i, E>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let (_, cells) = packed(input)?;
+    Ok(cells)
+}
+
+/// Decode the patterns of a module file without parsing the rest of it
+///
+/// The iterator yields a [`PatternCells`] for each pattern of the module, patterns missing from the
+/// file have 64 empty rows like with [`module_file`]. Only the header fields locating the pattern
+/// offsets are read and nothing is allocated.
+pub fn module_patterns<'i, E>(input: &'i [u8]) -> Result<ModulePatterns<'i, E>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    // The counts follow the magic number, the song name and the highlight.
+    let (rest, _) = tag(b"IMPM")(input)?;
+    let (rest, _) = take(28usize)(rest)?;
+    let (_, (ordnum, insnum, smpnum, patnum)) = tuple((le_u16, le_u16, le_u16, le_u16))(rest)?;
+    let table = 0xC0 + usize::from(ordnum) + 4 * (usize::from(insnum) + usize::from(smpnum));
+    if table > input.len() {
+        return Err(Err::Error(E::from_error_kind(input, ErrorKind::Eof)));
+    }
+    let (_, offsets) = take(4 * usize::from(patnum))(&input[table..])?;
+    Ok(ModulePatterns {
+        input,
+        offsets,
+        _error: PhantomData,
+    })
+}
+
+impl<'i, E> PatternCells<'i, E> {
+    /// Number of rows of the pattern
+    pub fn rows(&self) -> u16 {
+        self.rows
+    }
+
+    /// Pattern with `rows` empty rows
+    fn empty(rows: u16) -> PatternCells<'i, E> {
+        PatternCells {
+            input: &[],
+            rows,
+            row: rows,
+            state: State::default(),
+            _error: PhantomData,
+        }
+    }
+
+    /// Stops the iteration after an error.
+    fn fail(&mut self, error: Err<E>) -> Option<Result<(u16, Channel, Command), Err<E>>> {
+        self.input = &[];
+        self.row = self.rows;
+        Some(Err(error))
+    }
+}
+
+impl<'i, E> Iterator for PatternCells<'i, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    type Item = Result<(u16, Channel, Command), Err<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match (self.input, self.row < self.rows) {
+                ([], false) => return None,
+                (input, false) => return self.fail(Err::Error(error!(input, "data past the last row"))),
+                ([], true) => return self.fail(Err::Error(error!(self.input, "pattern ends before the last row"))),
+                ([0, rest @ ..], true) => {
+                    self.input = rest;
+                    self.row += 1;
+                }
+                (input, true) => match context!(command(&mut self.state), "in pattern")(input) {
+                    Ok((rest, (channel, command))) => {
+                        self.input = rest;
+                        return Some(Ok((self.row, channel, command)));
+                    }
+                    Err(error) => return self.fail(error),
+                },
+            }
+        }
+    }
+}
+
+impl<'i, E> FusedIterator for PatternCells<'i, E> where E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i {}
+
+impl<'i, E> Iterator for ModulePatterns<'i, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    type Item = Result<PatternCells<'i, E>, Err<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = u32::from_le_bytes(self.offsets.get(..4)?.try_into().unwrap());
+        self.offsets = &self.offsets[4..];
+        let offset = usize::try_from(offset).unwrap();
+        Some(match offset {
+            0 => Ok(PatternCells::empty(64)),
+            offset if offset >= self.input.len() => Err(Err::Error(E::from_error_kind(self.input, ErrorKind::Eof))),
+            offset => pattern_cells(&self.input[offset..]),
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.offsets.len() / 4;
+        (len, Some(len))
+    }
+}
+
+impl<'i, E> ExactSizeIterator for ModulePatterns<'i, E> where E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i {}
+
 fn command<'i, 's, E>(state: &'s mut State) -> impl FnMut(&'i [u8]) -> IResult<&'i [u8], (Channel, Command), E> + 's
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
@@ -204,7 +363,7 @@ fn instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
         let (input, instrument) = context!(le_u8, "reading instrument id")(input)?;
         let instrument = match instrument {
             0 => None,
-            1 ..= 99 => Some((instrument - 1).try_into().unwrap()),
+            1 ..= 99 => Some(InstrumentId::from_number(instrument).unwrap()),
             _ => {
                 info!(instrument, "instrument id is out of range 1..=99, parsing as None");
                 None
@@ -227,21 +386,9 @@ fn volume<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
 ) -> IResult<&'i [u8], Option<VolumeCmd>, E> {
     if mask_var.contains(Mask::READ_VOLUME) && !mask_var.contains(Mask::LAST_VOLUME) {
         let (input, x) = le_u8(input)?;
-        let volume = match x {
-              0 ..=  64 => VolumeCmd::SetVolume(x.cast()),
-            128 ..= 192 => VolumeCmd::Panning((x - 128).cast()),
-             65 ..=  74 => VolumeCmd::FineVolumeUp((x > 65).then(|| (x - 65).cast())),
-             75 ..=  84 => VolumeCmd::FineVolumeDown((x > 75).then(|| (x - 75).cast())),
-             85 ..=  94 => VolumeCmd::VolumeSlideUp((x > 85).then(|| (x - 85).cast())),
-             95 ..= 104 => VolumeCmd::VolumeSlideDown((x > 95).then(|| (x - 95).cast())),
-            105 ..= 114 => VolumeCmd::PortamentoDown((x > 105).then(|| (x - 105).cast())),
-            115 ..= 124 => VolumeCmd::PortamentoUp((x > 115).then(|| (x - 115).cast())),
-            193 ..= 202 => VolumeCmd::TonePortamento((x > 193).then(|| (x - 193).cast())),
-            203 ..= 212 => VolumeCmd::Vibrato((x > 203).then(|| (x - 203).cast())),
-            _ => {
-                // There is a gap in between the intervals so we can't simply use `ranged`.
-                bail!(input, "value is not a valid volume");
-            },
+        let volume = match parse_volume(x) {
+            Some(volume) => volume,
+            None => bail!(input, "value is not a valid volume"),
         };
         state.last_volume[channel.as_usize()] = Some(volume);
         Ok((input, Some(volume)))
@@ -252,6 +399,26 @@ fn volume<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
     }
 }
 
+/// Parse structured volume column command from the raw volume byte
+///
+/// Returns `None` for values that don't represent any volume command.
+pub fn parse_volume(x: u8) -> Option<VolumeCmd> {
+    // There is a gap in between the intervals so we can't simply use `ranged`.
+    Some(match x {
+          0 ..=  64 => VolumeCmd::SetVolume(x.cast()),
+        128 ..= 192 => VolumeCmd::Panning((x - 128).cast()),
+         65 ..=  74 => VolumeCmd::FineVolumeUp((x > 65).then(|| (x - 65).cast())),
+         75 ..=  84 => VolumeCmd::FineVolumeDown((x > 75).then(|| (x - 75).cast())),
+         85 ..=  94 => VolumeCmd::VolumeSlideUp((x > 85).then(|| (x - 85).cast())),
+         95 ..= 104 => VolumeCmd::VolumeSlideDown((x > 95).then(|| (x - 95).cast())),
+        105 ..= 114 => VolumeCmd::PortamentoDown((x > 105).then(|| (x - 105).cast())),
+        115 ..= 124 => VolumeCmd::PortamentoUp((x > 115).then(|| (x - 115).cast())),
+        193 ..= 202 => VolumeCmd::TonePortamento((x > 193).then(|| (x - 193).cast())),
+        203 ..= 212 => VolumeCmd::Vibrato((x > 203).then(|| (x - 203).cast())),
+        _ => return None,
+    })
+}
+
 fn effect<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
     state: &mut State,
     channel: Channel,
@@ -308,7 +475,7 @@ pub fn parse_effect(effect: u8, param: u8) -> Option<EffectCmd> {
             return None;
         },
         'A' => EffectCmd::SetSpeed(param.cast()),
-        'B' => EffectCmd::JumpOrder(param),
+        'B' => EffectCmd::JumpOrder(OrderIdx::new(param)),
         'C' => EffectCmd::BreakRow(param),
         'D' | 'K' | 'L' | 'N' | 'W' => {
             // OpenMPT code for this part is extremely confusing, Schism Tracker code is
@@ -563,7 +730,7 @@ mod test {
             EffectCmd::SetSpeed(0x12.cast()),
 
             // B
-            EffectCmd::JumpOrder(0x12),
+            EffectCmd::JumpOrder(OrderIdx::new(0x12)),
 
             // C
             EffectCmd::BreakRow(0x12),
@@ -696,4 +863,29 @@ mod test {
 
         assert_eq!(effects, alphabet);
     }
+
+    #[test]
+    fn streaming() {
+        for data in [&include_bytes!("../../tests/effect_alphabet.it")[..], include_bytes!("../../tests/song_message.it")] {
+            let module = ensure_parse(module_file, data);
+            let patterns = module_patterns::<VerboseError<&[u8]>>(data).unwrap();
+            assert_eq!(patterns.len(), module.patterns.len());
+            for (cells, pattern) in patterns.zip(&module.patterns) {
+                let cells = cells.unwrap();
+                assert_eq!(usize::from(cells.rows()), pattern.rows.len());
+                let expected = pattern.rows.iter().enumerate().flat_map(|(idx, row)| {
+                    row.iter().map(move |(channel, command)| (u16::try_from(idx).unwrap(), channel, *command))
+                });
+                assert_eq!(cells.collect::<Result<Vec<_>, _>>().unwrap(), expected.collect::<Vec<_>>());
+            }
+        }
+
+        // The second row is missing, the iteration stops after the error.
+        let data = [4, 0, 2, 0, 0, 0, 0, 0, 0x81, 0x01, 60, 0];
+        let mut cells = pattern_cells::<VerboseError<&[u8]>>(&data).unwrap();
+        let command = Command { note: Some(NoteCmd::Play(Note::C_5)), ..Command::EMPTY };
+        assert_eq!(cells.next().unwrap().unwrap(), (0, Channel::new(1), command));
+        assert!(cells.next().unwrap().is_err());
+        assert!(cells.next().is_none());
+    }
 }
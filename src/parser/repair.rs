@@ -0,0 +1,59 @@
+//! Collecting the values the parser repaired
+
+use crate::prelude::*;
+#[cfg(feature = "std")]
+use core::cell::RefCell;
+use core::fmt::{self, Display};
+
+
+/// Value the parser replaced or skipped because it was out of range, see
+/// [`module_file_with_repairs`](super::module_file_with_repairs)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Repair {
+    /// What was wrong with the value and how it was repaired
+    pub message: &'static str,
+
+    /// Values involved, e.g. `start = 10, end = 4`, empty if there are none
+    pub values: String,
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    /// Repairs made by the parser on this thread, `None` when nobody is collecting them
+    static REPAIRS: RefCell<Option<Vec<Repair>>> = const { RefCell::new(None) };
+}
+
+/// Records a repair if the current thread is collecting them.
+///
+/// The values are only formatted when they're collected.
+#[cfg(feature = "std")]
+pub(crate) fn record(message: &'static str, values: impl FnOnce() -> String) {
+    REPAIRS.with(|repairs| {
+        if let Some(repairs) = repairs.borrow_mut().as_mut() {
+            repairs.push(Repair { message, values: values() });
+        }
+    });
+}
+
+/// Repairs are only collected with `std`, which has thread-local storage.
+#[cfg(not(feature = "std"))]
+pub(crate) fn record(_message: &'static str, _values: impl FnOnce() -> String) {}
+
+/// Runs `f` collecting the repairs made on this thread, nested calls collect separately.
+#[cfg(feature = "std")]
+pub(crate) fn collect<T>(f: impl FnOnce() -> T) -> (T, Vec<Repair>) {
+    let outer = REPAIRS.with(|repairs| repairs.replace(Some(Vec::new())));
+    let result = f();
+    let repairs = REPAIRS.with(|repairs| repairs.replace(outer));
+    (result, repairs.unwrap_or_default())
+}
+
+impl Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.values.is_empty() {
+            f.write_str(self.message)
+        } else {
+            write!(f, "{} ({})", self.message, self.values)
+        }
+    }
+}
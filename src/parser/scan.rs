@@ -1,4 +1,4 @@
-use std::fmt::{self, Display};
+use core::fmt::{self, Display};
 
 
 /// Impulse Tracker file types
@@ -49,4 +49,4 @@ impl Display for ScanError {
     }
 }
 
-impl std::error::Error for ScanError {}
+impl core::error::Error for ScanError {}
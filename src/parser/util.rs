@@ -3,7 +3,8 @@ use nom::error::{make_error, ErrorKind, ParseError};
 use nom::multi::count;
 use nom::Err::Error;
 use nom::{IResult, Parser};
-use std::convert::{TryFrom, TryInto};
+use crate::prelude::*;
+use core::convert::{TryFrom, TryInto};
 
 
 /// Helper trait for `.try_into().unwrap()` for cases where a panic is meant to be a bug.
@@ -13,7 +14,7 @@ pub(crate) trait Cast {
     where
         Self: Sized,
         T: TryFrom<Self>,
-        <T as TryFrom<Self>>::Error: std::fmt::Debug,
+        <T as TryFrom<Self>>::Error: core::fmt::Debug,
     {
         T::try_from(self).unwrap()
     }
@@ -69,27 +70,3 @@ where
         Ok((input, output_list))
     }
 }
-
-pub(crate) fn scan_count<I, O, E, F, G, R>(
-    count: usize,
-    mut parse: F,
-    init: R,
-    fold: G,
-) -> impl FnMut(I) -> IResult<I, R, E>
-where
-    I: Clone + PartialEq,
-    F: Parser<I, O, E>,
-    G: Fn(&mut R, O),
-    E: ParseError<I>,
-    R: Clone,
-{
-    move |mut input: I| {
-        let mut acc = init.clone();
-        for _ in 0..count {
-            let (tail, value) = parse.parse(input.clone())?;
-            fold(&mut acc, value);
-            input = tail;
-        }
-        Ok((input, acc))
-    }
-}
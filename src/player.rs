@@ -0,0 +1,1554 @@
+//! Software playback of modules
+//!
+//! [`Player`] plays a [`Module`] and renders it into interleaved stereo PCM buffers (left sample
+//! first) at a sample rate chosen by the caller. The song is played from the start of the orders
+//! list like in [`Module::duration`]: speed (`Axx`), tempo (`Txx`), order jumps (`Bxx`) and
+//! pattern breaks (`Cxx`) drive the tick, row and order state machine, the song ends on the end
+//! of the orders list or at an end of song order (`---`). [`Player::seek`] moves the playback to
+//! any row, e.g. for seek bars, and [`Player::timing_map`] lists when every row starts.
+//! [`render_to_wav`] renders a whole song to a WAV file in one call. The player is also an
+//! [`Iterator`] of `[left, right]` frames, so it can be pulled from by any audio stack.
+//! [`Player::render_stems`] renders the channels separately along with the mixdown.
+//! [`Player::snapshot`] saves the playback state and [`Player::restore`] continues from it, e.g.
+//! to save a game mid-song.
+//!
+//! Songs which jump back to a row they already played with the same speed, tempo and global
+//! volume would play forever. [`PlayerOptions::repeat`] sets how many times they're repeated, the
+//! last repetition ends right away or with a fade-out of [`PlayerOptions::fade_out`] seconds.
+//!
+//! Every tick lasts `2.5 / tempo` seconds and every row `speed` ticks, pattern loops (`SBx`) and
+//! row and tick delays (`SEx`, `S6x`) are played like in [`Module::duration`]. OpenMPT's
+//! alternative and modern tempo modes are played with [`PlayerOptions::tempo_mode`],
+//! [`TempoMode::detect`] reads the mode of a file from its OpenMPT extensions.
+//! [`PlayerOptions::tempo_scale`] and [`PlayerOptions::transpose`] change the speed and the pitch
+//! of the song independently. Samples are mixed honoring their loops and sustain loops,
+//! [`PlayerOptions::interpolation`] selects the quality of their resampling.
+//!
+//! All the effects and volume column commands of Impulse Tracker are played: notes and
+//! instruments take effect on the first tick of the row (or on the tick of a note delay, `SDx`),
+//! fine slides on the first tick and the other slides on the following ticks. Effects with a
+//! parameter of zero reuse the last parameter of the effect in the channel, vibrato, tremolo and
+//! panbrello remember their speed and depth separately. Pitch slides are linear or change the
+//! Amiga period depending on [`ModuleFlags::LINEAR_SLIDES`], [`ModuleFlags::OLD_EFFECTS`] and
+//! [`ModuleFlags::LINK_G_E_EFFECTS`] change tremor, sample offsets and the effect memory like in
+//! Impulse Tracker.
+//!
+//! The panning is scaled by the stereo separation of the module and
+//! [`PlayerOptions::stereo_separation`], surround channels are played phase-inverted on the right
+//! side unless [`PlayerOptions::surround`] is disabled.
+//!
+//! [`PlayerOptions::volume_ramping`] smooths the changes of volume, note starts and note cuts
+//! over a millisecond to avoid clicks. Without it, [`PlayerOptions::declick`] still fades cut
+//! notes out and notes started from a sample offset in.
+//!
+//! The resonant low-pass filter of Impulse Tracker is applied to the voices, its cutoff and
+//! resonance are set by the instrument and changed by `Zxx` through the MIDI macros of
+//! [`PlayerOptions::midi_config`], by default `Z00`..`Z7F` set the cutoff while `SF0` is selected
+//! and `Z80`..`Z8F` set the resonance. The other macros are recorded as [`EventKind::Midi`]
+//! events.
+//!
+//! In instrument mode the New Note Action of the instrument (or `S73`..`S76`) decides what
+//! happens to a playing note when the channel plays a new one: it's cut, or it continues
+//! playing, is released or fades out in the background. The duplicate check of the instrument
+//! applies its action to the notes of the channel which are duplicates of the new one, `S70`..`S72`
+//! to all the background notes of the channel.
+//!
+//! The volume, panning and pitch (or filter) envelopes of the instrument advance every tick,
+//! looping in their sustain loops until the note is released and in their envelope loops after.
+//! Envelopes with [`EnvelopeFlags::CARRY`] continue from the position of the previous note of
+//! the instrument. Released notes fade out by the fadeout of their instrument, right away if the
+//! volume envelope is disabled or loops, otherwise once it reaches its end. [`Player::envelopes`]
+//! returns the envelope positions of a channel for visualizations.
+//!
+//! [`Player::channel_status`] returns the note, volume, envelopes and peak level of each channel,
+//! e.g. for VU meters and tracker-style pattern views. Channels can be muted and soloed while
+//! playing with [`Player::set_channel_muted`] and [`Player::set_channel_solo`].
+//! [`PlayerOptions::events`] records the row changes, notes and tempo changes with the frame they
+//! happen at, for synchronizing visuals to the music, [`PlayerOptions::sync_markers`] records
+//! chosen cells like the sync commands of demos.
+//!
+//! Rendering doesn't allocate or lock after [`Player::new`], unless events pile up without being
+//! drained. See [`realtime`] for playing from audio callbacks and controlling the player from
+//! other threads.
+
+mod channel;
+mod envelope;
+mod events;
+mod filter;
+mod realtime;
+mod simd;
+mod snapshot;
+#[cfg(feature = "rodio")]
+mod source;
+mod voice;
+mod wav;
+
+use crate::analysis::{PatternLoop, Position, MISSING_PATTERN_ROWS};
+use crate::convert::round_clamp;
+use crate::*;
+use std::array;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::iter::FusedIterator;
+use std::ops::Range;
+use channel::{ChannelState, MAX_BACKGROUND_VOICES};
+use std::sync::Arc;
+use voice::Voice;
+
+pub use channel::ChannelStatus;
+pub use envelope::{EnvelopePosition, VoiceEnvelopes};
+pub use events::{EventKind, PlayerEvent, SyncMarker};
+pub use realtime::{realtime, ControlMessage, Controller, RealtimePlayer};
+pub use snapshot::PlayerSnapshot;
+#[cfg(feature = "rodio")]
+pub use source::ItSource;
+pub use wav::{render_to_wav, RenderOptions, WavFormat};
+
+
+/// Settings of a [`Player`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlayerOptions {
+    /// Output sample rate in Hz
+    pub sample_rate: u32,
+
+    /// Interpolation used when resampling the samples
+    pub interpolation: Interpolation,
+
+    /// Times a song which loops back is repeated, `None` plays it forever
+    ///
+    /// The default `Some(0)` stops the song when it starts repeating.
+    pub repeat: Option<u32>,
+
+    /// Length in seconds of the fade-out which ends the last repetition, `0.0` stops right away
+    pub fade_out: f64,
+
+    /// Meaning of the tempo
+    pub tempo_mode: TempoMode,
+
+    /// Speed of the playback without changing the pitch, e.g. `2.0` plays twice as fast
+    ///
+    /// The tick lengths are divided by the scale, see [`Player::set_tempo_scale`] to change it
+    /// while playing.
+    pub tempo_scale: f64,
+
+    /// Semitones every note is transposed by without changing the speed, fractions detune
+    ///
+    /// See [`Player::set_transpose`] to change it while playing.
+    pub transpose: f64,
+
+    /// Keeps Amiga slides in the period range of ProTracker, C-4 to B-6 of the sample
+    ///
+    /// Modules converted from MOD rely on slides stopping at the limits, typically together with
+    /// [`ModuleFlags::OLD_EFFECTS`] and [`ModuleFlags::LINK_G_E_EFFECTS`]. Linear slides are not
+    /// limited.
+    pub amiga_limits: bool,
+
+    /// Stereo separation in percent, on top of the separation of the module like libopenmpt
+    ///
+    /// `0` plays the song in mono, the default `100` as the module sets it and `200` twice as
+    /// wide, e.g. `50` for headphones.
+    pub stereo_separation: u32,
+
+    /// Plays surround channels (`S91` and [`ChannelPanning::Surround`]) from the centre with the
+    /// right side phase-inverted, like the software mixers of Impulse Tracker
+    ///
+    /// Otherwise, and always in mono, they're played from the centre.
+    pub surround: bool,
+
+    /// Records the [`PlayerEvent`]s, see [`Player::drain_events`]
+    pub events: bool,
+
+    /// Ramps the volume when notes start, stop and change their volume to avoid clicks, like
+    /// OpenMPT
+    ///
+    /// Disabled by default so the output is sample-exact: every gain change then happens at the
+    /// start of a tick.
+    pub volume_ramping: bool,
+
+    /// Length in seconds of the fade which ends cut notes (`SCx`, `^^^`, New Note Actions and
+    /// duplicate checks) and starts notes played from a sample offset (`Oxx`), `0.0` cuts and
+    /// starts them abruptly
+    ///
+    /// The default of a millisecond removes the clicks without audibly softening the cuts. With
+    /// [`PlayerOptions::volume_ramping`] the ramps are used instead.
+    pub declick: f64,
+
+    /// MIDI macros of `Zxx`, see [`Preserved::midi_config`] for the configuration of a file
+    pub midi_config: Arc<MidiConfig>,
+
+    /// Cells recorded as [`EventKind::Sync`] events, also when [`PlayerOptions::events`] is
+    /// disabled
+    pub sync_markers: Vec<SyncMarker>,
+}
+
+/// Start of a row played by the song, see [`Player::timing_map`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RowTiming {
+    pub position: Position,
+
+    /// Time from the start of the song in seconds, exact whatever the sample rate
+    pub seconds: f64,
+
+    /// Frames rendered before the row at the sample rate of the player
+    pub frame: u64,
+}
+
+/// How the tempo and speed set the length of the ticks
+///
+/// Files saved by OpenMPT store their mode in the extension chunks, see [`TempoMode::detect`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TempoMode {
+    /// Impulse Tracker timing, every tick lasts `2.5 / tempo` seconds
+    Classic,
+
+    /// The tempo is the number of ticks per second
+    Alternative,
+
+    /// The tempo is in beats per minute, every row lasts `60 / (tempo * rows_per_beat)` seconds
+    /// whatever the speed
+    Modern { rows_per_beat: u32 },
+}
+
+/// Sample interpolation, from the cheapest to the highest quality
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Nearest sample, no interpolation
+    Nearest,
+
+    /// Linear interpolation between the two nearest samples
+    Linear,
+
+    /// Cubic (Catmull-Rom) interpolation over 4 samples
+    Cubic,
+
+    /// Windowed sinc interpolation over 8 samples, filtering out aliasing when playing samples
+    /// above the output sample rate
+    Sinc,
+}
+
+/// How [`Player::seek`] restores the playback state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekMode {
+    /// The rows before the target are played without mixing
+    ///
+    /// Speed, tempo, global volume, channel volumes and panning, effect memory and playing notes
+    /// are restored, but the notes playing at the target restart from the start of their samples.
+    Fast,
+
+    /// The rows before the target are mixed and the audio is discarded
+    ///
+    /// The notes playing at the target continue exactly where they would be, seeking takes as
+    /// long as rendering the skipped part of the song.
+    Exact,
+}
+
+impl Default for PlayerOptions {
+    fn default() -> PlayerOptions {
+        PlayerOptions {
+            sample_rate: 48000,
+            interpolation: Interpolation::Linear,
+            repeat: Some(0),
+            fade_out: 0.0,
+            tempo_mode: TempoMode::Classic,
+            tempo_scale: 1.0,
+            transpose: 0.0,
+            amiga_limits: false,
+            stereo_separation: 100,
+            surround: true,
+            volume_ramping: false,
+            declick: 0.001,
+            midi_config: Arc::new(MidiConfig::default()),
+            events: false,
+            sync_markers: Vec::new(),
+        }
+    }
+}
+
+impl TempoMode {
+    /// Returns the tempo mode of a file saved by OpenMPT, read from the song properties in the
+    /// extension chunks of [`Preserved::trailing`]
+    ///
+    /// Files without the property use [`TempoMode::Classic`]. In modern mode the rows per beat
+    /// are also read from the extensions, falling back to the rows per beat highlight of
+    /// `module`.
+    pub fn detect(module: &Module, preserved: &Preserved) -> TempoMode {
+        match song_property(preserved, b"TM..") {
+            Some(1) => TempoMode::Alternative,
+            Some(2) => {
+                let rows_per_beat = song_property(preserved, b"RPB.")
+                    .filter(|&rows| rows > 0)
+                    .unwrap_or_else(|| u32::from(module.highlight.1));
+                TempoMode::Modern { rows_per_beat: rows_per_beat.max(1) }
+            }
+            _ => TempoMode::Classic,
+        }
+    }
+}
+
+/// Plays a module, see the [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct Player {
+    module: Arc<Module>,
+    options: PlayerOptions,
+
+    /// Order and row being played, `None` once the song has ended
+    position: Option<(OrderIdx, usize)>,
+
+    /// Tick of the row to be played next
+    tick: u32,
+
+    /// Ticks of the current row, including the row and tick delays
+    row_ticks: u32,
+
+    speed: u32,
+    globals: Globals,
+
+    /// Order jump (`Bxx`), pattern break (`Cxx`) and pattern loop (`SBx`) of the current row
+    jump_order: Option<OrderIdx>,
+    break_row: Option<usize>,
+    loop_row: Option<usize>,
+
+    /// Pattern loops of the channels
+    loops: [PatternLoop; 64],
+
+    channels: [ChannelState; 64],
+
+    /// Voices moved out of their channels by New Note Actions
+    background: Vec<Voice>,
+
+    /// Frames left to render of the current tick
+    frames_left: usize,
+
+    /// Fraction of a frame carried between ticks, in units of the divisor of the tick length, see
+    /// [`Player::tick_length`]
+    tick_remainder: u64,
+
+    /// Rows played since the last repetition with the speed, tempo and global volume they started
+    /// with
+    visited: HashSet<(OrderIdx, usize, u32, u32, u8)>,
+
+    /// Times the song has repeated
+    repetitions: u32,
+
+    /// Frames left and total frames of the fade-out
+    fade: Option<(usize, usize)>,
+
+    /// Peak levels of the channels in the last render call
+    peaks: [[f32; 2]; 64],
+
+    /// Channels muted and soloed at runtime
+    muted: [bool; 64],
+    solo: [bool; 64],
+
+    /// Frames rendered since the start or the last seek
+    frame: u64,
+
+    /// Events not drained yet, if enabled, and the order of the last row event
+    events: Vec<PlayerEvent>,
+    event_order: Option<OrderIdx>,
+}
+
+/// Song state changed by the effects of the channels
+#[derive(Clone, Debug)]
+struct Globals {
+    tempo: u32,
+    global_volume: u8,
+}
+
+impl Player {
+    /// Starts playing `module` from the start of the orders list
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`PlayerOptions::sample_rate`] is zero.
+    pub fn new(module: impl Into<Arc<Module>>, options: PlayerOptions) -> Player {
+        assert!(options.sample_rate > 0, "sample rate must not be zero");
+        let module = module.into();
+        // Rows are only played in more than one state by songs changing the speed or tempo when
+        // they jump back, the set doesn't grow while rendering other songs.
+        let rows = (0..module.orders.len())
+            .filter_map(|order| module.next_order(order).filter(|next| next.as_usize() == order))
+            .map(|order| module.pattern_at(order).map_or(MISSING_PATTERN_ROWS, |pattern| pattern.rows.len()))
+            .sum::<usize>();
+        Player {
+            position: module.next_order(0).map(|order| (order, 0)),
+            tick: 0,
+            row_ticks: 0,
+            speed: u32::from(module.speed.as_u8()),
+            globals: Globals {
+                tempo: u32::from(module.tempo.as_u8()),
+                global_volume: module.global_volume.as_u8(),
+            },
+            jump_order: None,
+            break_row: None,
+            loop_row: None,
+            loops: [PatternLoop::default(); 64],
+            channels: array::from_fn(|idx| ChannelState::new(&module.channels[idx], idx, &options)),
+            background: Vec::with_capacity(MAX_BACKGROUND_VOICES),
+            frames_left: 0,
+            tick_remainder: 0,
+            visited: HashSet::with_capacity(rows),
+            repetitions: 0,
+            fade: None,
+            peaks: [[0.0; 2]; 64],
+            muted: [false; 64],
+            solo: [false; 64],
+            frame: 0,
+            events: Vec::with_capacity(if options.events || !options.sync_markers.is_empty() { 256 } else { 0 }),
+            event_order: None,
+            module,
+            options,
+        }
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn options(&self) -> &PlayerOptions {
+        &self.options
+    }
+
+    /// Returns the row being played, `None` once the song has ended.
+    pub fn position(&self) -> Option<Position> {
+        let (order, row) = self.position?;
+        Some(Position { order, row })
+    }
+
+    /// Moves the playback to the start of the row `target`
+    ///
+    /// The song is played from the start until it reaches the target, so the state of the player
+    /// is the same as if the song was played up to it, `mode` chooses between speed and accuracy.
+    /// Returns `false` if the song never plays the target row, the player then jumps to it with
+    /// the initial state of the song. Seeking past the end of the orders list, to an end of song
+    /// order or past the end of a pattern ends the song.
+    pub fn seek(&mut self, target: Position, mode: SeekMode) -> bool {
+        let target = (target.order, target.row);
+        self.restart();
+
+        let mut visited = HashSet::new();
+        let mut discarded = Vec::new();
+        loop {
+            self.advance_row();
+            let Some(position) = self.position else {
+                break;
+            };
+            if self.tick == 0 {
+                if position == target {
+                    self.events.clear();
+                    self.event_order = None;
+                    return true;
+                }
+                // The song repeats without reaching the target, see `Module::duration`.
+                let in_loop = self.loops.iter().any(|state| state.remaining > 0);
+                if !in_loop && !visited.insert(position) {
+                    break;
+                }
+            }
+            self.next_tick();
+            if mode == SeekMode::Exact {
+                discarded.clear();
+                discarded.resize(self.frames_left * 2, 0.0);
+                self.mix(&mut discarded, &mut [], 0..0);
+            }
+            self.frames_left = 0;
+        }
+
+        self.restart();
+        let valid = self.module.next_order(target.0.as_usize()) == Some(target.0) && target.1 < self.rows(target.0);
+        self.position = valid.then_some(target);
+        false
+    }
+
+    /// Plays the song from the start without mixing and returns the start of every row in the
+    /// order they're played
+    ///
+    /// The song is played with the options of the player until it ends or reaches a row it
+    /// already played outside of a pattern loop, like [`Module::duration`]. Rows repeated by
+    /// pattern loops are listed every time they're played. The map is meant for seek tables and
+    /// for syncing lyrics or subtitles, the state of this player doesn't change.
+    pub fn timing_map(&self) -> Vec<RowTiming> {
+        let options = PlayerOptions { events: false, sync_markers: Vec::new(), ..self.options.clone() };
+        let mut player = Player::new(Arc::clone(&self.module), options);
+
+        let mut timing = Vec::new();
+        let mut visited = HashSet::new();
+        let mut seconds = 0.0;
+        loop {
+            player.advance_row();
+            let Some(position) = player.position else {
+                break;
+            };
+            if player.tick == 0 {
+                let in_loop = player.loops.iter().any(|state| state.remaining > 0);
+                if !in_loop && !visited.insert(position) {
+                    break;
+                }
+                timing.push(RowTiming { position: player.position().unwrap(), seconds, frame: player.frame });
+            }
+            player.next_tick();
+            let (numerator, divisor) = player.tick_length();
+            #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+            let length = numerator as f64 / divisor as f64;
+            seconds += length;
+            player.frame += u64::try_from(player.frames_left).unwrap();
+            player.frames_left = 0;
+        }
+        timing
+    }
+
+    /// Returns the envelope positions of the note playing in `channel`, `None` if the channel is
+    /// silent.
+    ///
+    /// The positions are the ones used for the last rendered tick.
+    pub fn envelopes(&self, channel: Channel) -> Option<VoiceEnvelopes> {
+        self.channels[channel.as_usize()].voice.as_ref().map(Voice::envelopes)
+    }
+
+    /// Returns the state of `channel` after the last render call, for VU meters and tracker-style
+    /// pattern views.
+    pub fn channel_status(&self, channel: Channel) -> ChannelStatus {
+        ChannelStatus {
+            peak: self.peaks[channel.as_usize()],
+            ..self.channels[channel.as_usize()].status()
+        }
+    }
+
+    /// Mutes or unmutes `channel`
+    ///
+    /// The change applies from the next rendered frame, muted channels continue playing silently
+    /// so they can be unmuted at any time. This is independent of [`ChannelSettings::muted`] of
+    /// the module, e.g. muting the lead channel renders a minus-one mix.
+    pub fn set_channel_muted(&mut self, channel: Channel, muted: bool) {
+        self.muted[channel.as_usize()] = muted;
+    }
+
+    /// Solos or unsolos `channel`
+    ///
+    /// While any channel is soloed only the soloed channels are audible. The change applies from
+    /// the next rendered frame.
+    pub fn set_channel_solo(&mut self, channel: Channel, solo: bool) {
+        self.solo[channel.as_usize()] = solo;
+    }
+
+    /// Returns `true` if `channel` is neither muted nor silenced by the solo of another channel.
+    pub fn is_channel_audible(&self, channel: Channel) -> bool {
+        self.audible(channel.as_usize())
+    }
+
+    /// Changes [`PlayerOptions::tempo_scale`] from the next tick, e.g. to speed the music up with
+    /// the action of a game
+    pub fn set_tempo_scale(&mut self, scale: f64) {
+        self.options.tempo_scale = scale;
+    }
+
+    /// Changes [`PlayerOptions::transpose`] from the next tick
+    pub fn set_transpose(&mut self, semitones: f64) {
+        self.options.transpose = semitones;
+        for channel in &mut self.channels {
+            channel.set_transpose(semitones);
+        }
+    }
+
+    /// Returns the events of the rendered audio since the last call, in the order they happened,
+    /// if [`PlayerOptions::events`] is enabled or [`PlayerOptions::sync_markers`] are set.
+    ///
+    /// The events of a tick are recorded when the tick starts, before its audio is rendered: the
+    /// frame of the last events can be past the rendered frames. The events accumulate until
+    /// they're drained.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = PlayerEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    /// Returns how many times the song has repeated, see [`PlayerOptions::repeat`].
+    pub fn repetitions(&self) -> u32 {
+        self.repetitions
+    }
+
+    /// Ends the song, the render functions return no more frames.
+    pub fn stop(&mut self) {
+        self.position = None;
+        self.fade = None;
+    }
+
+    /// Returns `true` once the song has ended.
+    ///
+    /// The render functions return less frames than requested when the song ends, the rest of the
+    /// buffer is left untouched.
+    pub fn is_finished(&self) -> bool {
+        self.position.is_none()
+    }
+
+    /// Renders interleaved stereo frames into `out`, returns the number of rendered frames.
+    ///
+    /// The samples are normalized to `-1.0..=1.0`, loud modules can exceed this range. A trailing
+    /// odd sample of `out` is left untouched.
+    pub fn render_f32(&mut self, out: &mut [f32]) -> usize {
+        self.peaks = [[0.0; 2]; 64];
+        self.render(out, &mut [])
+    }
+
+    /// Renders like [`Player::render_f32`] and the part of every channel into `stems`, returns
+    /// the number of rendered frames.
+    ///
+    /// `stems[0]` receives the first channel, `stems[1]` the second one and so on, including the
+    /// notes of the channel playing in the background. The stems add up to `out`, which also has
+    /// the channels without a stem. Muted channels have silent stems.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than 64 stems or a stem is shorter than `out`.
+    pub fn render_stems(&mut self, out: &mut [f32], stems: &mut [&mut [f32]]) -> usize {
+        assert!(stems.len() <= 64, "more stems than channels");
+        assert!(stems.iter().all(|stem| stem.len() >= out.len()), "stem shorter than the output");
+        self.peaks = [[0.0; 2]; 64];
+        self.render(out, stems)
+    }
+
+    /// Renders interleaved stereo frames into `out` as 16-bit samples, returns the number of
+    /// rendered frames.
+    ///
+    /// Samples outside of the range of `i16` are clipped.
+    pub fn render_i16(&mut self, out: &mut [i16]) -> usize {
+        self.peaks = [[0.0; 2]; 64];
+        let mut buffer = [0.0f32; 512];
+        let mut rendered = 0;
+        for chunk in out.chunks_mut(buffer.len()) {
+            let frames = self.render(&mut buffer[..chunk.len()], &mut []);
+            for (sample, &value) in chunk.iter_mut().zip(&buffer[..frames * 2]) {
+                *sample = round_clamp(f64::from(value) * 32767.0, i16::MIN, i16::MAX);
+            }
+            rendered += frames;
+            if frames < chunk.len() / 2 {
+                break;
+            }
+        }
+        rendered
+    }
+
+    /// Renders interleaved stereo frames into `out` and `stems` without resetting the peaks, see
+    /// [`Player::render_stems`].
+    fn render(&mut self, out: &mut [f32], stems: &mut [&mut [f32]]) -> usize {
+        let frames = out.len() / 2;
+        let mut rendered = 0;
+        while rendered < frames {
+            if self.frames_left == 0 {
+                if !self.next_tick() {
+                    break;
+                }
+                continue;
+            }
+            let mut count = (frames - rendered).min(self.frames_left);
+            if let Some((left, _)) = self.fade {
+                count = count.min(left);
+            }
+            let range = rendered * 2..(rendered + count) * 2;
+            let buffer = &mut out[range.clone()];
+            buffer.fill(0.0);
+            self.mix(buffer, stems, range.clone());
+            if let Some((left, total)) = self.fade {
+                fade_out(buffer, left, total);
+                stems.iter_mut().for_each(|stem| fade_out(&mut stem[range.clone()], left, total));
+                self.fade = Some((left - count, total));
+                if left == count {
+                    self.position = None;
+                    self.frames_left = count;
+                }
+            }
+            self.frames_left -= count;
+            self.frame += u64::try_from(count).unwrap();
+            rendered += count;
+        }
+        rendered
+    }
+
+    fn audible(&self, channel: usize) -> bool {
+        !self.muted[channel] && (self.solo[channel] || !self.solo.contains(&true))
+    }
+
+    /// Restarts the song from the start, keeping the muted and soloed channels.
+    fn restart(&mut self) {
+        let mut player = Player::new(Arc::clone(&self.module), self.options.clone());
+        player.muted = self.muted;
+        player.solo = self.solo;
+        *self = player;
+    }
+
+    /// Mixes the playing voices into `out`, the voices of the channels with a stem through the
+    /// `range` of their stem.
+    fn mix(&mut self, out: &mut [f32], stems: &mut [&mut [f32]], range: Range<usize>) {
+        let (sample_rate, interpolation) = (self.options.sample_rate, self.options.interpolation);
+        let muted: [bool; 64] = array::from_fn(|idx| !self.audible(idx));
+        stems.iter_mut().for_each(|stem| stem[range.clone()].fill(0.0));
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            if let Some(voice) = &mut channel.voice {
+                let target = match stems.get_mut(idx) {
+                    Some(stem) => &mut stem[range.clone()],
+                    None => &mut *out,
+                };
+                if !voice.mix(target, sample_rate, interpolation, muted[idx], &mut self.peaks[idx]) {
+                    channel.voice = None;
+                }
+            }
+        }
+        let peaks = &mut self.peaks;
+        self.background.retain_mut(|voice| {
+            let channel = voice.origin.channel;
+            let target = match stems.get_mut(channel) {
+                Some(stem) => &mut stem[range.clone()],
+                None => &mut *out,
+            };
+            voice.mix(target, sample_rate, interpolation, muted[channel], &mut peaks[channel])
+        });
+        for stem in stems.iter() {
+            simd::add(out, &stem[range.clone()]);
+        }
+    }
+
+    /// Starts the next tick, returns `false` if the song has ended.
+    fn next_tick(&mut self) -> bool {
+        self.advance_row();
+        if self.tick == 0 {
+            self.detect_repeat();
+        }
+        if self.position.is_none() {
+            return false;
+        }
+
+        let module = Arc::clone(&self.module);
+        let timing = (self.speed, self.globals.tempo);
+        if self.tick == 0 {
+            self.row_events();
+            self.process_row();
+        } else {
+            // The first tick of every repetition of a delayed row is played again.
+            let repeat = self.tick % self.speed == 0;
+            for (idx, channel) in self.channels.iter_mut().enumerate() {
+                if repeat {
+                    channel.repeat(&module, idx, &mut self.background, &mut self.globals);
+                } else {
+                    channel.tick(&module, self.tick, idx, &mut self.background, &mut self.globals);
+                }
+            }
+        }
+        for channel in &mut self.channels {
+            channel.update(&module, self.globals.global_volume, self.options.sample_rate);
+        }
+        let sample_rate = self.options.sample_rate;
+        self.background.retain_mut(|voice| voice.update(&module, sample_rate));
+        self.tick_events(timing);
+
+        let (seconds, divisor) = self.tick_length();
+        // The remainder is kept when the divisor changes, an error of less than a frame.
+        let frames = u64::from(self.options.sample_rate) * seconds + self.tick_remainder.min(divisor - 1);
+        self.frames_left = usize::try_from(frames / divisor).unwrap();
+        self.tick_remainder = frames % divisor;
+        self.tick += 1;
+        true
+    }
+
+    /// Returns the length of a tick in seconds as a fraction.
+    ///
+    /// The tempo scale is applied in steps of 1/65536.
+    fn tick_length(&self) -> (u64, u64) {
+        let tempo = u64::from(self.globals.tempo.max(1));
+        let (seconds, divisor) = match self.options.tempo_mode {
+            TempoMode::Classic => (5, 2 * tempo),
+            TempoMode::Alternative => (1, tempo),
+            TempoMode::Modern { rows_per_beat } => {
+                (60, tempo * u64::from(rows_per_beat.max(1)) * u64::from(self.speed.max(1)))
+            }
+        };
+        let scale = round_clamp(self.options.tempo_scale * 65536.0, 1, 1 << 24);
+        (seconds * 65536, divisor.saturating_mul(u64::from(scale)))
+    }
+
+    /// Records the events of the row about to be played.
+    fn row_events(&mut self) {
+        let Some(position) = self.position() else {
+            return;
+        };
+        if !self.options.events {
+            return;
+        }
+        let frame = self.frame;
+        let order = Some(position.order);
+        if self.event_order != order {
+            self.event_order = order;
+            self.events.push(PlayerEvent { frame, kind: EventKind::Order(position.order) });
+        }
+        self.events.push(PlayerEvent { frame, kind: EventKind::Row(position) });
+    }
+
+    /// Records the sync events of the cells of the current row.
+    fn sync_events(&mut self, commands: &[Option<&Command>; 64]) {
+        let Some(position) = self.position() else {
+            return;
+        };
+        for (idx, command) in commands.iter().enumerate() {
+            let channel = Channel::from_index(u8::try_from(idx).unwrap()).unwrap();
+            let command = command.copied().unwrap_or(Command::EMPTY);
+            for (marker, sync) in self.options.sync_markers.iter().enumerate() {
+                if sync.matches(position, channel, &command) {
+                    let kind = EventKind::Sync { marker, channel, command };
+                    self.events.push(PlayerEvent { frame: self.frame, kind });
+                }
+            }
+        }
+    }
+
+    /// Records the MIDI messages and the notes started by the tick and the change of the speed and tempo from
+    /// `timing`.
+    fn tick_events(&mut self, timing: (u32, u32)) {
+        let frame = self.frame;
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            let message = channel.midi_message.take();
+            if let Some(message) = message.filter(|_| self.options.events) {
+                let channel = Channel::from_index(u8::try_from(idx).unwrap()).unwrap();
+                self.events.push(PlayerEvent { frame, kind: EventKind::Midi { channel, message } });
+            }
+            if !std::mem::take(&mut channel.triggered) || !self.options.events {
+                continue;
+            }
+            if let Some(voice) = &channel.voice {
+                let kind = EventKind::Note {
+                    channel: Channel::from_index(u8::try_from(idx).unwrap()).unwrap(),
+                    note: voice.origin.note,
+                    instrument: channel.status().instrument,
+                };
+                self.events.push(PlayerEvent { frame, kind });
+            }
+        }
+        if self.options.events && timing != (self.speed, self.globals.tempo) {
+            let kind = EventKind::Tempo { speed: self.speed, tempo: self.globals.tempo };
+            self.events.push(PlayerEvent { frame, kind });
+        }
+    }
+
+    /// Counts a repetition when the row about to be played has been played before in the same
+    /// state, and ends the song after the last repetition.
+    ///
+    /// Rows repeated by pattern loops (`SBx`) aren't repetitions of the song.
+    fn detect_repeat(&mut self) {
+        let Some((order, row)) = self.position else {
+            return;
+        };
+        if self.loops.iter().any(|state| state.remaining > 0) {
+            return;
+        }
+        let state = (order, row, self.speed, self.globals.tempo, self.globals.global_volume);
+        if self.visited.insert(state) {
+            return;
+        }
+        self.visited.clear();
+        self.visited.insert(state);
+        self.repetitions += 1;
+
+        let last = self.options.repeat.is_some_and(|repeat| self.repetitions > repeat);
+        if last && self.fade.is_none() {
+            let frames = round_clamp(self.options.fade_out * f64::from(self.options.sample_rate), 0, u32::MAX);
+            let frames = usize::try_from(frames).unwrap();
+            if frames == 0 {
+                self.position = None;
+            } else {
+                self.fade = Some((frames, frames));
+            }
+        }
+    }
+
+    /// Moves to the next row once all the ticks of the current one have been played.
+    fn advance_row(&mut self) {
+        if self.position.is_some() && self.tick >= self.row_ticks {
+            if self.row_ticks > 0 {
+                self.next_row();
+            }
+            self.tick = 0;
+        }
+    }
+
+    /// Applies the commands of the current row and computes its number of ticks.
+    fn process_row(&mut self) {
+        let Some((order, row)) = self.position else {
+            return;
+        };
+        let module = Arc::clone(&self.module);
+        let mut commands = [None; 64];
+        let cells = module.pattern_at(order).and_then(|pattern| pattern.rows.as_slice().get(row));
+        for (channel, command) in cells.into_iter().flat_map(Row::iter) {
+            commands[channel.as_usize()] = Some(command);
+        }
+        self.sync_events(&commands);
+
+        let mut row_delay = None;
+        let mut tick_delay = 0;
+        for (idx, command) in commands.into_iter().enumerate() {
+            let channel = &mut self.channels[idx];
+            channel.row(&module, command, idx, &mut self.background, &mut self.globals);
+
+            // The effect memory applies to the global effects too, e.g. `S00` repeats `SB2`.
+            match channel.effect() {
+                Some(EffectCmd::SetSpeed(xx)) => self.speed = u32::from(xx.as_u8()),
+                Some(EffectCmd::Tempo(Some(Tempo::Set(xx)))) => self.globals.tempo = u32::from(xx.as_u8()),
+                Some(EffectCmd::JumpOrder(xx)) => self.jump_order = Some(xx),
+                Some(EffectCmd::BreakRow(xx)) => self.break_row = Some(usize::from(xx)),
+                Some(EffectCmd::SetGlobalVolume(xx)) => self.globals.global_volume = xx.as_u8(),
+                Some(EffectCmd::Special(Some(Special::SetLoopbackPoint))) => self.loops[idx].start = row,
+                Some(EffectCmd::Special(Some(Special::LoopbackTimes(x)))) => {
+                    if let Some(start) = self.loops[idx].loopback(row, x.as_u8()) {
+                        self.loop_row = Some(start);
+                    }
+                }
+                Some(EffectCmd::Special(Some(Special::PatternRowDelay(x)))) => {
+                    // Only the first row delay on a row is used.
+                    row_delay.get_or_insert(u32::from(x.as_u8()));
+                }
+                Some(EffectCmd::Special(Some(Special::PatternTickDelay(x)))) => tick_delay += u32::from(x.as_u8()),
+                _ => {}
+            }
+        }
+        self.row_ticks = self.speed * (1 + row_delay.unwrap_or(0)) + tick_delay;
+    }
+
+    /// Moves to the row played after the current one.
+    fn next_row(&mut self) {
+        let Some((order, row)) = self.position else {
+            return;
+        };
+        let (jump_order, break_row) = (self.jump_order.take(), self.break_row.take());
+        let next = if let Some(loop_row) = self.loop_row.take() {
+            Some((order, loop_row))
+        } else if jump_order.is_some() || break_row.is_some() {
+            // Loops are forgotten when leaving the pattern.
+            self.loops = [PatternLoop::default(); 64];
+            self.module.next_order(jump_order.map_or(order.as_usize() + 1, OrderIdx::as_usize)).map(|next| (next, break_row.unwrap_or(0)))
+        } else if row + 1 < self.rows(order) {
+            Some((order, row + 1))
+        } else {
+            self.loops = [PatternLoop::default(); 64];
+            self.module.next_order(order.as_usize() + 1).map(|next| (next, 0))
+        };
+
+        // Breaking to a row past the end of the pattern plays the first row instead.
+        self.position = next.map(|(order, row)| (order, if row < self.rows(order) { row } else { 0 }));
+    }
+
+    /// Number of rows played at `order`
+    fn rows(&self, order: OrderIdx) -> usize {
+        self.module.pattern_at(order).map_or(MISSING_PATTERN_ROWS, |pattern| pattern.rows.len())
+    }
+}
+
+/// Returns the integer value of the OpenMPT song property `name`, `None` if the trailing data of
+/// `preserved` doesn't have it.
+fn song_property(preserved: &Preserved, name: &[u8; 4]) -> Option<u32> {
+    let (_, mut chunks) = song_property_chunks(&preserved.trailing, preserved.module.instruments.len())?;
+    let value = chunks.find(|chunk| chunk.is(name))?.value();
+    let mut bytes = [0; 4];
+    let len = value.len().min(4);
+    bytes[..len].copy_from_slice(&value[..len]);
+    Some(u32::from_le_bytes(bytes))
+}
+
+/// Renders the song frame by frame, like [`Player::render_f32`]
+///
+/// The peak levels of [`Player::channel_status`] aren't reset between frames, they're the peaks
+/// since the last call of a render function.
+impl Iterator for Player {
+    type Item = [f32; 2];
+
+    fn next(&mut self) -> Option<[f32; 2]> {
+        let mut frame = [0.0; 2];
+        (self.render(&mut frame, &mut []) == 1).then_some(frame)
+    }
+}
+
+impl FusedIterator for Player {}
+
+/// Applies a linear fade-out of `total` frames to `buffer`, which starts with `left` frames to
+/// the end of the fade-out.
+fn fade_out(buffer: &mut [f32], left: usize, total: usize) {
+    // The fade-out is at most `u32::MAX` frames long, see `Player::detect_repeat`.
+    let total = f64::from(u32::try_from(total).unwrap());
+    for (idx, frame) in buffer.chunks_exact_mut(2).enumerate() {
+        let left = f64::from(u32::try_from(left - idx).unwrap());
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+        let gain = (left / total) as f32;
+        frame.iter_mut().for_each(|value| *value *= gain);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Returns a module playing a constant sample from its first row.
+    pub(super) fn module() -> Module {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.flags = ModuleFlags::STEREO;
+        module.set_speed(6).unwrap();
+        module.set_tempo(125).unwrap();
+        module.set_global_volume(128).unwrap();
+        module.set_mix_volume(128).unwrap();
+        module.set_stereo_separation(128).unwrap();
+        module.channels = [ChannelSettings::DEFAULT; 64];
+
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap();
+        let mut sample = instrument.samples[0].clone();
+        sample.global_volume = 64;
+        sample.default_volume = 64;
+        sample.default_panning = 32;
+        sample.samplerate_c5 = 48000;
+        sample.sustain_loop = None;
+        sample.data = Some(SampleData::from(vec![16384i16; 100]));
+        sample.set_loop(Some(SampleLoop { start: 0, end: 100, bidi: false })).unwrap();
+        module.samples = vec![sample];
+
+        let mut rows = vec![Row::empty(); 4];
+        rows[0] = Row::from_vec(vec![(Channel::new(1), Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            ..Command::EMPTY
+        })]);
+        module.patterns = vec![Pattern { active_channels: ActiveChannels::all(), rows }];
+        module.orders = vec![Order::Index(PatternId::from_index(0).unwrap()), Order::EndOfSong];
+        module
+    }
+
+    /// Returns [`module`] in instrument mode, with an instrument without envelopes playing the
+    /// sample.
+    fn instrument_module() -> Module {
+        let mut module = module();
+        module.flags |= ModuleFlags::USE_INSTRUMENTS;
+        let mut instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap().instrument;
+        instrument.flags = InstrumentFlags::empty();
+        instrument.global_volume = 128;
+        instrument.new_note_action = NewNoteAction::Cut;
+        instrument.duplicate_check_type = DuplicateCheckType::Off;
+        instrument.duplicate_check_action = DuplicateCheckAction::Cut;
+        for envelope in [&mut instrument.volume_envelope, &mut instrument.panning_envelope, &mut instrument.pitch_filter_envelope] {
+            envelope.flags = EnvelopeFlags::empty();
+        }
+        instrument.sample_map.fill_sample(Some(SampleId::from_index(0).unwrap()));
+        instrument.sample_map.reset_note_translation();
+        module.instruments = vec![instrument];
+        module
+    }
+
+    #[test]
+    fn render() {
+        let mut player = Player::new(module(), PlayerOptions::default());
+        assert_eq!(player.position(), Some(Position { order: OrderIdx::new(0), row: 0 }));
+
+        // 4 rows of 6 ticks, each tick is 2.5 / 125 seconds long.
+        let mut out = vec![1.0f32; 30000 * 2];
+        assert_eq!(player.render_f32(&mut out), 23040);
+        assert!(player.is_finished());
+        assert!(out[..23040 * 2].iter().all(|&value| value == 0.25));
+        assert!(out[23040 * 2..].iter().all(|&value| value == 1.0));
+        assert_eq!(player.render_f32(&mut out), 0);
+
+        let mut player = Player::new(module(), PlayerOptions::default());
+        let mut out = [0i16; 1000];
+        assert_eq!(player.render_i16(&mut out), 500);
+        assert_eq!(out, [8192; 1000]);
+    }
+
+    #[test]
+    fn frames() {
+        let mut player = Player::new(module(), PlayerOptions::default());
+        let mut out = vec![0.0f32; 30000 * 2];
+        let rendered = player.render_f32(&mut out);
+
+        let frames = Player::new(module(), PlayerOptions::default()).collect::<Vec<_>>();
+        assert_eq!(frames.len(), rendered);
+        assert!(frames.iter().flatten().eq(&out[..rendered * 2]));
+    }
+
+    #[test]
+    fn stems() {
+        let mut module = module();
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(2), Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            volume: Some(VolumeCmd::SetVolume(RangedU8::new(32))),
+            ..Command::EMPTY
+        })]);
+        let mut out = vec![0.0f32; 30000 * 2];
+        let mut stem = vec![1.0f32; 30000 * 2];
+
+        // Only the first channel has a stem, the second one is only in the mixdown.
+        let mut player = Player::new(module, PlayerOptions::default());
+        assert_eq!(player.render_stems(&mut out, &mut [&mut stem]), 23040);
+        assert!(stem[..23040 * 2].iter().all(|&value| value == 0.25));
+        assert!(stem[23040 * 2..].iter().all(|&value| value == 1.0));
+        assert_eq!(out[5759 * 2], 0.25);
+        assert_eq!(out[5760 * 2], 0.375);
+    }
+
+    #[test]
+    fn pattern_break() {
+        let mut module = module();
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(2), Command {
+            effect: Some(EffectCmd::BreakRow(3)),
+            ..Command::EMPTY
+        })]);
+        module.orders.insert(1, Order::Index(PatternId::from_index(0).unwrap()));
+
+        // Rows 0, 1 and 3 of both orders are played.
+        let mut player = Player::new(module, PlayerOptions::default());
+        let mut out = vec![0.0f32; 30000 * 2];
+        assert_eq!(player.render_f32(&mut out[..5760 * 3 * 2]), 5760 * 3);
+        assert_eq!(player.position(), Some(Position { order: OrderIdx::new(0), row: 3 }));
+        assert_eq!(player.render_f32(&mut out), 5760 * 3);
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn loops_and_delays() {
+        let mut module = module();
+        let rows = &mut module.patterns[0].rows;
+        let effect = |letter: u8, param: u8| Command { effect: parser::effect(letter - b'@', param), ..Command::EMPTY };
+        rows[1] = Row::from_vec(vec![(Channel::new(2), effect(b'S', 0xB1))]);
+        rows[2] = Row::from_vec(vec![(Channel::new(2), effect(b'S', 0xE1))]);
+        rows[3] = Row::from_vec(vec![(Channel::new(2), effect(b'S', 0x63))]);
+
+        // Rows 0, 1, 0, 1, 2 twice and 3 with 3 more ticks, like the duration of the song.
+        let frames = 5760 * 7 + 960 * 3;
+        assert!((module.duration().seconds * 48000.0 - f64::from(frames)).abs() < 1e-6);
+        let mut player = Player::new(module, PlayerOptions::default());
+        let mut out = vec![0.0f32; 60000 * 2];
+        assert_eq!(player.render_f32(&mut out), usize::try_from(frames).unwrap());
+        assert!(player.is_finished());
+
+        // Loops of two channels, the second one repeated by `S00`: rows 0, 1 three times, then 2, 3
+        // three times.
+        let mut module = super::test::module();
+        let rows = &mut module.patterns[0].rows;
+        rows[1] = Row::from_vec(vec![(Channel::new(2), effect(b'S', 0xB1)), (Channel::new(3), effect(b'S', 0xB2))]);
+        rows[3] = Row::from_vec(vec![(Channel::new(3), effect(b'S', 0x00))]);
+        assert!((module.duration().seconds * 48000.0 - 5760.0 * 12.0).abs() < 1e-6);
+        let mut player = Player::new(module, PlayerOptions::default());
+        assert_eq!(player.render_f32(&mut out), 5760 * 12);
+    }
+
+    #[test]
+    fn timing_map() {
+        let mut module = module();
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(2), Command {
+            effect: parser::effect(b'S' - b'@', 0xB1),
+            ..Command::EMPTY
+        })]);
+        let options = PlayerOptions { sample_rate: 44100, ..PlayerOptions::default() };
+        let timing = Player::new(module, options).timing_map();
+
+        // Rows 0, 1, 0, 1, 2 and 3, every row lasts 0.12 seconds.
+        let rows = timing.iter().map(|timing| timing.position.row).collect::<Vec<_>>();
+        assert_eq!(rows, [0, 1, 0, 1, 2, 3]);
+        for (idx, timing) in timing.iter().enumerate() {
+            let expected = 0.12 * f64::from(u32::try_from(idx).unwrap());
+            assert!((timing.seconds - expected).abs() < 1e-9);
+            assert_eq!(timing.frame, 5292 * u64::try_from(idx).unwrap());
+        }
+    }
+
+    #[test]
+    fn seek() {
+        let mut module = module();
+        let tempo = Command { effect: Some(EffectCmd::Tempo(Some(Tempo::Set(RangedU8::new(250))))), ..Command::EMPTY };
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(2), tempo)]);
+        let position = |order, row| Position { order: OrderIdx::new(order), row };
+
+        // The tempo set on row 1 is restored, the last 2 rows are twice as fast.
+        let mut out = vec![0.0f32; 30000 * 2];
+        for mode in [SeekMode::Fast, SeekMode::Exact] {
+            let mut player = Player::new(module.clone(), PlayerOptions::default());
+            assert!(player.seek(position(0, 2), mode));
+            assert_eq!(player.position(), Some(position(0, 2)));
+            assert_eq!(player.render_f32(&mut out), 2880 * 2);
+            assert!(out[..2880 * 2 * 2].iter().all(|&value| value == 0.25));
+        }
+
+        // Rows skipped by a pattern break are jumped to with the initial state.
+        let pattern_break = Command { effect: Some(EffectCmd::BreakRow(3)), ..Command::EMPTY };
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(2), tempo), (Channel::new(3), pattern_break)]);
+        let mut player = Player::new(module, PlayerOptions::default());
+        assert!(!player.seek(position(0, 2), SeekMode::Fast));
+        assert_eq!(player.render_f32(&mut out), 5760 * 2);
+        assert!(out[..5760 * 2 * 2].iter().all(|&value| value == 0.0));
+        assert!(!player.seek(position(1, 0), SeekMode::Fast));
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn repeat() {
+        let mut module = module();
+        module.patterns[0].rows[3] = Row::from_vec(vec![(Channel::new(2), Command {
+            effect: Some(EffectCmd::JumpOrder(OrderIdx::new(0))),
+            ..Command::EMPTY
+        })]);
+        let mut out = vec![0.0f32; 100000 * 2];
+        let mut render = |options: PlayerOptions| {
+            let mut player = Player::new(module.clone(), options);
+            let frames = player.render_f32(&mut out);
+            (frames, player.repetitions(), player.is_finished())
+        };
+
+        assert_eq!(render(PlayerOptions::default()), (23040, 1, true));
+        assert_eq!(render(PlayerOptions { repeat: Some(2), ..PlayerOptions::default() }), (23040 * 3, 3, true));
+        assert_eq!(render(PlayerOptions { repeat: None, ..PlayerOptions::default() }), (100000, 4, false));
+
+        // The fade-out continues playing the song for 0.1 seconds.
+        assert_eq!(render(PlayerOptions { fade_out: 0.1, ..PlayerOptions::default() }), (23040 + 4800, 1, true));
+        assert_eq!(out[23039 * 2], 0.25);
+        assert_eq!(out[23040 * 2], 0.25);
+        assert_eq!(out[(23040 + 2400) * 2], 0.125);
+        assert!(out[(23040 + 4799) * 2] < 0.001);
+    }
+
+    #[test]
+    fn tempo_modes() {
+        let mut module = module();
+        module.set_tempo(120).unwrap();
+        let mut out = vec![0.0f32; 30000 * 2];
+        let mut render = |tempo_mode| Player::new(module.clone(), PlayerOptions { tempo_mode, ..PlayerOptions::default() }).render_f32(&mut out);
+
+        // 24 ticks of 2.5 / 120 seconds, of 1 / 120 seconds, and 4 rows of 60 / (120 * 4) seconds.
+        assert_eq!(render(TempoMode::Classic), 24000);
+        assert_eq!(render(TempoMode::Alternative), 9600);
+        assert_eq!(render(TempoMode::Modern { rows_per_beat: 4 }), 24000);
+        assert_eq!(render(TempoMode::Modern { rows_per_beat: 8 }), 12000);
+
+        let mut module = self::module();
+        module.highlight = (16, 4);
+        // The song properties follow an instrument extension whose value looks like the marker.
+        let mut parsed = module.clone();
+        parsed.instruments.push(parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap().instrument);
+        let mut preserved = Preserved {
+            module: parsed,
+            original: Arc::from(&[][..]),
+            header_extra: Vec::new(),
+            trailing: b"XTPM..MG\x04\x00STPMSTPM..TD\x04\x00\x7d\x00\x00\x00".to_vec(),
+            unknown_flags: (0, 0),
+        };
+        assert_eq!(TempoMode::detect(&module, &preserved), TempoMode::Classic);
+        preserved.trailing.extend_from_slice(b"..MT\x04\x00\x02\x00\x00\x00");
+        assert_eq!(TempoMode::detect(&module, &preserved), TempoMode::Modern { rows_per_beat: 4 });
+        preserved.trailing.extend_from_slice(b".BPR\x04\x00\x06\x00\x00\x00");
+        assert_eq!(TempoMode::detect(&module, &preserved), TempoMode::Modern { rows_per_beat: 6 });
+        preserved.trailing[34] = 1;
+        assert_eq!(TempoMode::detect(&module, &preserved), TempoMode::Alternative);
+    }
+
+    #[test]
+    fn tempo_scale_and_transpose() {
+        let frames = |tempo_scale| {
+            let mut player = Player::new(module(), PlayerOptions { tempo_scale, ..PlayerOptions::default() });
+            let mut out = vec![0.0f32; 50000 * 2];
+            player.render_f32(&mut out)
+        };
+        assert_eq!(frames(2.0), 11520);
+        assert_eq!(frames(0.5), 46080);
+
+        // The sample of C-5 plays at 48000 Hz, an octave up at 96000 Hz.
+        let mut player = Player::new(module(), PlayerOptions { transpose: 12.0, ..PlayerOptions::default() });
+        player.next();
+        assert_eq!(player.channels[0].voice.as_ref().unwrap().frequency, 96000.0);
+        // The changes apply from the next tick, which lasts half as long.
+        player.set_transpose(-12.0);
+        player.set_tempo_scale(2.0);
+        let mut out = vec![0.0f32; 959 * 2];
+        assert_eq!(player.render_f32(&mut out), 959);
+        assert_eq!(player.channels[0].voice.as_ref().unwrap().frequency, 96000.0);
+        assert_eq!(player.render_f32(&mut out[..480 * 2]), 480);
+        assert_eq!(player.channels[0].voice.as_ref().unwrap().frequency, 24000.0);
+        assert_eq!(player.frames_left, 0);
+    }
+
+    #[test]
+    fn events() {
+        let mut module = module();
+        let tempo = Command { effect: Some(EffectCmd::Tempo(Some(Tempo::Set(RangedU8::new(250))))), ..Command::EMPTY };
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(2), tempo)]);
+        let position = |row| Position { order: OrderIdx::new(0), row };
+        let event = |frame, kind| PlayerEvent { frame, kind };
+
+        let mut player = Player::new(module.clone(), PlayerOptions { events: true, ..PlayerOptions::default() });
+        let mut out = vec![0.0f32; 5760 * 2 * 2];
+        assert_eq!(player.render_f32(&mut out), 5760 * 2);
+        assert_eq!(player.drain_events().collect::<Vec<_>>(), [
+            event(0, EventKind::Order(OrderIdx::new(0))),
+            event(0, EventKind::Row(position(0))),
+            event(0, EventKind::Note {
+                channel: Channel::new(1),
+                note: Note::C_5,
+                instrument: Some(InstrumentId::from_index(0).unwrap()),
+            }),
+            event(5760, EventKind::Row(position(1))),
+            event(5760, EventKind::Tempo { speed: 6, tempo: 250 }),
+        ]);
+        assert_eq!(player.drain_events().count(), 0);
+
+        let mut player = Player::new(module, PlayerOptions::default());
+        player.render_f32(&mut out);
+        assert_eq!(player.drain_events().count(), 0);
+    }
+
+    #[test]
+    fn sync_markers() {
+        let mut module = module();
+        let sync = Command { effect: Some(EffectCmd::Midi(0x90)), ..Command::EMPTY };
+        module.patterns[0].rows[2] = Row::from_vec(vec![(Channel::new(3), sync)]);
+        let position = Position { order: OrderIdx::new(0), row: 3 };
+        let options = PlayerOptions {
+            sync_markers: vec![SyncMarker::Midi(0x90..=0xFF), SyncMarker::Cell { position, channel: Channel::new(5) }],
+            ..PlayerOptions::default()
+        };
+
+        let mut player = Player::new(module, options);
+        let mut out = vec![0.0f32; 30000 * 2];
+        player.render_f32(&mut out);
+        assert_eq!(player.drain_events().collect::<Vec<_>>(), [
+            PlayerEvent { frame: 5760 * 2, kind: EventKind::Sync { marker: 0, channel: Channel::new(3), command: sync } },
+            PlayerEvent { frame: 5760 * 3, kind: EventKind::Sync { marker: 1, channel: Channel::new(5), command: Command::EMPTY } },
+        ]);
+    }
+
+    #[test]
+    fn volume_ramping() {
+        let mut module = module();
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(1), Command {
+            note: Some(NoteCmd::Cut),
+            ..Command::EMPTY
+        })]);
+        let mut out = vec![0.0f32; 30000 * 2];
+        let mut player = Player::new(module.clone(), PlayerOptions::default());
+        player.render_f32(&mut out);
+        assert_eq!((out[0], out[5759 * 2], out[5760 * 2]), (0.25, 0.25, 0.0));
+
+        // The note ramps up over 17 frames and down over 46 frames.
+        let options = PlayerOptions { volume_ramping: true, ..PlayerOptions::default() };
+        let mut player = Player::new(module, options);
+        player.render_f32(&mut out);
+        assert!(out[0] > 0.0 && out[0] < 0.25);
+        assert!(out[16 * 2] == 0.25 && out[5759 * 2] == 0.25);
+        assert!(out[5760 * 2] > 0.2 && out[5760 * 2] < 0.25);
+        assert!(out[5805 * 2] < 0.01);
+        assert_eq!(out[5806 * 2], 0.0);
+    }
+
+    #[test]
+    fn stereo_separation() {
+        let first_frame = |param: u8, options: PlayerOptions| {
+            let mut module = module();
+            module.patterns[0].rows[0] = Row::from_vec(vec![(Channel::new(1), Command {
+                note: Some(NoteCmd::Play(Note::C_5)),
+                instrument: Some(InstrumentId::from_index(0).unwrap()),
+                effect: parser::effect(b'S' - b'@', param),
+                ..Command::EMPTY
+            })]);
+            Player::new(module, options).next().unwrap()
+        };
+
+        // `S80` pans the channel to the left.
+        let separation = |stereo_separation| PlayerOptions { stereo_separation, ..PlayerOptions::default() };
+        assert_eq!(first_frame(0x80, PlayerOptions::default()), [0.5, 0.0]);
+        assert_eq!(first_frame(0x80, separation(50)), [0.375, 0.125]);
+        assert_eq!(first_frame(0x80, separation(0)), [0.25, 0.25]);
+        assert_eq!(first_frame(0x80, separation(200)), [0.5, 0.0]);
+
+        // `S91` plays the channel in surround.
+        assert_eq!(first_frame(0x91, PlayerOptions::default()), [0.25, -0.25]);
+        assert_eq!(first_frame(0x91, PlayerOptions { surround: false, ..PlayerOptions::default() }), [0.25, 0.25]);
+        assert_eq!(first_frame(0x91, separation(0)), [0.25, 0.25]);
+    }
+
+    #[test]
+    fn declick() {
+        let render = |effect: u8, param: u8, declick: f64| {
+            let mut module = module();
+            module.samples[0].data = Some(SampleData::from(vec![16384i16; 1000]));
+            module.samples[0].set_loop(Some(SampleLoop { start: 0, end: 1000, bidi: false })).unwrap();
+            module.patterns[0].rows[0] = Row::from_vec(vec![(Channel::new(1), Command {
+                note: Some(NoteCmd::Play(Note::C_5)),
+                instrument: Some(InstrumentId::from_index(0).unwrap()),
+                effect: parser::effect(effect - b'@', param),
+                ..Command::EMPTY
+            })]);
+            let mut player = Player::new(module, PlayerOptions { declick, ..PlayerOptions::default() });
+            let mut out = vec![0.0f32; 2000 * 2];
+            player.render_f32(&mut out);
+            out
+        };
+
+        // `SC1` cuts the note after a tick, fading out over 48 frames.
+        let out = render(b'S', 0xC1, 0.001);
+        assert_eq!(out[959 * 2], 0.25);
+        assert!(out[960 * 2] > 0.24);
+        assert_eq!(out[1007 * 2], 0.0);
+        assert_eq!(render(b'S', 0xC1, 0.0)[960 * 2], 0.0);
+
+        // `O01` starts the note from the 256th sample, fading in.
+        let out = render(b'O', 0x01, 0.001);
+        assert!(out[0] < 0.01);
+        assert_eq!(out[47 * 2], 0.25);
+        assert_eq!(render(b'O', 0x01, 0.0)[0], 0.25);
+    }
+
+    #[test]
+    fn channel_status() {
+        let mut player = Player::new(module(), PlayerOptions::default());
+        let mut out = vec![0.0f32; 1000 * 2];
+        player.render_f32(&mut out);
+        assert_eq!(player.channel_status(Channel::new(1)), ChannelStatus {
+            note: Some(Note::C_5),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            sample: Some(SampleId::from_index(0).unwrap()),
+            volume: 64,
+            channel_volume: 64,
+            panning: ChannelPanning::Position(RangedU8::new(32)),
+            envelopes: Some(VoiceEnvelopes { volume: None, panning: None, pitch_filter: None }),
+            peak: [0.25, 0.25],
+        });
+        assert_eq!(player.channel_status(Channel::new(2)).note, None);
+        assert_eq!(player.channel_status(Channel::new(2)).peak, [0.0, 0.0]);
+    }
+
+    #[test]
+    fn mute_and_solo() {
+        let mut module = module();
+        let note = module.patterns[0].rows[0].iter().next().map(|(_, command)| *command).unwrap();
+        module.patterns[0].rows[0] = Row::from_vec(vec![(Channel::new(1), note), (Channel::new(2), note)]);
+
+        let mut player = Player::new(module, PlayerOptions::default());
+        let mut out = [0.0f32; 2];
+        let mut render = |player: &mut Player| {
+            assert_eq!(player.render_f32(&mut out), 1);
+            out[0]
+        };
+        assert_eq!(render(&mut player), 0.5);
+
+        player.set_channel_muted(Channel::new(1), true);
+        assert_eq!(render(&mut player), 0.25);
+        assert_eq!(player.channel_status(Channel::new(1)).peak, [0.0, 0.0]);
+
+        // Solo silences the other channels, muting still applies to soloed channels.
+        player.set_channel_solo(Channel::new(2), true);
+        assert_eq!(render(&mut player), 0.25);
+        player.set_channel_muted(Channel::new(1), false);
+        player.set_channel_muted(Channel::new(2), true);
+        assert_eq!(render(&mut player), 0.0);
+        assert!(!player.is_channel_audible(Channel::new(1)));
+        assert!(!player.is_channel_audible(Channel::new(2)));
+
+        // Seeking keeps the muted and soloed channels.
+        player.set_channel_solo(Channel::new(2), false);
+        player.seek(Position { order: OrderIdx::new(0), row: 0 }, SeekMode::Fast);
+        assert_eq!(render(&mut player), 0.25);
+    }
+
+    #[test]
+    fn filter_macro() {
+        let mut module = module();
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(1), Command {
+            effect: Some(EffectCmd::Midi(0x00)),
+            ..Command::EMPTY
+        })]);
+
+        // The filter is disabled until `Z00` closes it, the output then restarts from silence.
+        let mut player = Player::new(module, PlayerOptions::default());
+        let mut out = vec![0.0f32; 5760 * 2 * 2];
+        assert_eq!(player.render_f32(&mut out), 5760 * 2);
+        assert_eq!(out[5759 * 2], 0.25);
+        assert!(out[5760 * 2] < 0.01);
+        assert_eq!(player.channels[0].cutoff, 0);
+    }
+
+    #[test]
+    fn midi_config() {
+        let mut module = module();
+        let midi = |xx| Command { effect: Some(EffectCmd::Midi(xx)), ..Command::EMPTY };
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(1), midi(0x90)), (Channel::new(2), midi(0x91))]);
+        let mut config = MidiConfig::default();
+        config.fixed[0x10] = MidiMacro::new("F0F00140");
+        config.fixed[0x11] = MidiMacro::new("Bc 07 z");
+        let options = PlayerOptions { midi_config: Arc::new(config), events: true, ..PlayerOptions::default() };
+
+        let mut player = Player::new(module, options);
+        let mut out = vec![0.0f32; 5760 * 2 * 2];
+        player.render_f32(&mut out);
+        assert_eq!(player.channels[0].resonance, 0x40);
+        let message = player.drain_events().find_map(|event| match event.kind {
+            EventKind::Midi { channel, message } => Some((event.frame, channel, message)),
+            _ => None,
+        });
+        let (frame, channel, message) = message.unwrap();
+        assert_eq!((frame, channel, message.as_bytes()), (5760, Channel::new(2), &[0xB1, 0x07, 0x91][..]));
+    }
+
+    #[test]
+    fn new_note_action() {
+        let mut module = instrument_module();
+        module.instruments[0].new_note_action = NewNoteAction::Continue;
+        module.patterns[0].rows[1] = module.patterns[0].rows[0].clone();
+
+        // Returns the last sample of the second row.
+        let render = |module: &Module| {
+            let mut player = Player::new(module.clone(), PlayerOptions::default());
+            let mut out = vec![0.0f32; 5760 * 2 * 2];
+            assert_eq!(player.render_f32(&mut out), 5760 * 2);
+            out[5760 * 2 * 2 - 1]
+        };
+
+        // The first note continues in the background.
+        assert_eq!(render(&module), 0.5);
+
+        module.instruments[0].duplicate_check_type = DuplicateCheckType::Note;
+        assert_eq!(render(&module), 0.25);
+
+        module.instruments[0].duplicate_check_type = DuplicateCheckType::Off;
+        module.instruments[0].new_note_action = NewNoteAction::Cut;
+        assert_eq!(render(&module), 0.25);
+    }
+
+    #[test]
+    fn envelopes() {
+        let mut module = instrument_module();
+        module.instruments[0].instrument_fadeout = 128;
+        module.instruments[0].volume_envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::SUSTAIN,
+            envelope_loop: None,
+            sustain_loop: Some(EnvelopeLoop { start: 1, end: 1 }),
+            nodes: vec![Node { value: 64, tick: 0 }, Node { value: 32, tick: 4 }, Node { value: 16, tick: 8 }],
+        };
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(1), Command {
+            note: Some(NoteCmd::Off),
+            ..Command::EMPTY
+        })]);
+
+        let mut player = Player::new(module, PlayerOptions::default());
+        let mut out = vec![0.0f32; 960 * 2];
+        let mut render = |player: &mut Player, ticks: usize| {
+            for _ in 0..ticks {
+                assert_eq!(player.render_f32(&mut out), 960);
+            }
+            out[960 * 2 - 1]
+        };
+        let volume = |player: &Player| player.envelopes(Channel::new(1)).and_then(|envelopes| envelopes.volume);
+
+        assert_eq!(render(&mut player, 3), 0.5 * 0.75 * 0.5);
+        assert_eq!(volume(&player), Some(EnvelopePosition { tick: 2, value: 48.0 }));
+        assert_eq!(player.envelopes(Channel::new(1)).unwrap().panning, None);
+
+        // The note is held on the sustain node until the note-off.
+        render(&mut player, 3);
+        assert_eq!(volume(&player), Some(EnvelopePosition { tick: 4, value: 32.0 }));
+
+        // After the note-off the envelope continues to its end, then the note fades out.
+        assert_eq!(render(&mut player, 5), 0.5 * 0.25 * 0.875 * 0.5);
+        assert_eq!(volume(&player), Some(EnvelopePosition { tick: 8, value: 16.0 }));
+        render(&mut player, 7);
+        assert_eq!(volume(&player), None);
+    }
+}
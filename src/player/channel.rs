@@ -0,0 +1,1406 @@
+//! Playback state of the pattern channels
+
+use super::envelope::VoiceEnvelopes;
+use super::voice::{Origin, Voice};
+use super::{filter, Globals, PlayerOptions};
+use crate::convert::round_i64;
+use crate::*;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+
+/// Most voices playing in the background, the oldest one is cut to make room for another
+///
+/// Impulse Tracker has 256 virtual channels, 64 of which are taken by the pattern channels.
+pub(super) const MAX_BACKGROUND_VOICES: usize = 192;
+
+/// Frequency times the period of a note, in the 4 times finer periods Impulse Tracker uses for
+/// Amiga slides (C-5 at 8363 Hz has the period 1712)
+const AMIGA_CLOCK: f64 = 1712.0 * 8363.0;
+
+/// Lowest and highest periods of ProTracker, C-1 and B-3, with C-2 (428) playing the sample at
+/// its C-5 frequency
+const AMIGA_LIMITS: (f64, f64) = (856.0, 113.0);
+
+/// `Gxx` speeds of the `g0x` volume column commands
+const TONE_PORTAMENTO_SPEEDS: [u8; 10] = [0x00, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40, 0x60, 0x80, 0xFF];
+
+/// State of a pattern channel, returned by [`Player::channel_status`](super::Player::channel_status)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelStatus {
+    /// Note playing in the channel as written in the pattern, `None` if the channel is silent
+    pub note: Option<Note>,
+
+    /// Last instrument played on the channel, samples in sample mode
+    pub instrument: Option<InstrumentId>,
+
+    /// Sample of the last played note
+    pub sample: Option<SampleId>,
+
+    /// Note volume (0..=64)
+    pub volume: u8,
+
+    /// Channel volume (0..=64)
+    pub channel_volume: u8,
+
+    pub panning: ChannelPanning,
+
+    /// Envelope positions of the playing note
+    pub envelopes: Option<VoiceEnvelopes>,
+
+    /// Highest absolute values of the left and right output of the channel in the last render
+    /// call, including the notes of the channel playing in the background
+    pub peak: [f32; 2],
+}
+
+/// Playback state of a pattern channel
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub(super) struct ChannelState {
+    /// Note volume (0..=64)
+    volume: u8,
+
+    /// Channel volume (0..=64)
+    channel_volume: u8,
+
+    panning: ChannelPanning,
+    muted: bool,
+
+    /// Last instrument played on the channel, samples in sample mode
+    instrument: Option<InstrumentId>,
+
+    /// Sample and note of the last played note
+    sample: Option<SampleId>,
+    note: Option<Note>,
+
+    /// Pitch of the playing note in Hz, changed by the pitch slides
+    frequency: f64,
+
+    /// Pitch the tone portamento (`Gxx`) slides to
+    target_frequency: Option<f64>,
+
+    /// Keeps Amiga slides in the range of [`AMIGA_LIMITS`]
+    amiga_limits: bool,
+
+    /// Ramps the volume of the voices, see [`PlayerOptions::volume_ramping`], and the length of
+    /// the fades of [`PlayerOptions::declick`]
+    ramping: bool,
+    declick: f64,
+
+    /// Stereo separation (0..=2) of [`PlayerOptions::stereo_separation`] and
+    /// [`PlayerOptions::surround`]
+    separation: f32,
+    surround: bool,
+
+    /// Pitch ratio of [`PlayerOptions::transpose`]
+    transpose: f64,
+
+    /// Filter cutoff and resonance (0..=127)
+    pub(super) cutoff: u8,
+    pub(super) resonance: u8,
+
+    /// Parametered MIDI macro selected by `SFx`
+    midi_macro: u8,
+
+    /// Macros of `Zxx`, and the last message sent which isn't a filter change
+    #[cfg_attr(feature = "serde", serde(skip))]
+    midi_config: Arc<MidiConfig>,
+    pub(super) midi_message: Option<MidiMessage>,
+
+    /// Action applied to the voice when the next note is played
+    new_note_action: NewNoteAction,
+
+    /// Effect of the current row with the effect memory applied, and its raw parameter
+    effect: Option<EffectCmd>,
+    param: u8,
+
+    /// Volume column command of the current row
+    volume_command: Option<VolumeCmd>,
+
+    /// Command of the current row waiting for the tick of its note delay (`SDx`)
+    delayed: Option<(u32, Command)>,
+
+    /// Tick of the note cut (`SCx`) of the current row
+    cut_tick: Option<u32>,
+
+    /// Last non-zero parameter of each effect, indexed by the effect number (see
+    /// [`memory_slot`])
+    memory: [u8; 27],
+
+    /// Last non-zero parameter of the volume column slides (`a0x`...`d0x`)
+    volume_column_memory: u8,
+
+    vibrato: Oscillator,
+    tremolo: Oscillator,
+    panbrello: Oscillator,
+
+    /// Glissando (`S1x`), tone portamento slides by semitones
+    glissando: bool,
+
+    /// High byte of the sample offset (`SAy`)
+    offset_high: u8,
+
+    /// Ticks counted by tremor (`Ixy`) and retrigger (`Qxy`)
+    tremor_counter: u32,
+    retrigger_counter: u32,
+
+    /// Temporary changes of the current tick by vibrato, tremolo, panbrello, tremor and arpeggio
+    modulation: Modulation,
+
+    /// State of the random waveform
+    random: u32,
+
+    /// Voice controlled by the channel
+    pub(super) voice: Option<Voice>,
+
+    /// A note started since the player last reset the flag
+    pub(super) triggered: bool,
+}
+
+/// Waveform state of vibrato, tremolo and panbrello
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+struct Oscillator {
+    speed: u8,
+
+    /// Depth, in 1/64 semitones for vibrato
+    depth: u8,
+
+    waveform: Waveform,
+
+    /// Position in the waveform, 256 steps per period
+    position: u8,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+struct Modulation {
+    volume: i32,
+    panning: i32,
+
+    /// Pitch change in the units of [`slide`]
+    pitch: i32,
+
+    /// Arpeggio semitones
+    semitones: u8,
+
+    /// Muted by tremor
+    silent: bool,
+}
+
+impl ChannelState {
+    pub(super) fn new(settings: &ChannelSettings, index: usize, options: &PlayerOptions) -> ChannelState {
+        let oscillator = Oscillator { speed: 0, depth: 0, waveform: Waveform::Sine, position: 0 };
+        ChannelState {
+            volume: 64,
+            channel_volume: settings.volume.as_u8(),
+            panning: settings.panning,
+            muted: settings.muted,
+            instrument: None,
+            sample: None,
+            note: None,
+            frequency: 0.0,
+            target_frequency: None,
+            amiga_limits: options.amiga_limits,
+            ramping: options.volume_ramping,
+            declick: options.declick,
+            separation: separation(options),
+            surround: options.surround,
+            transpose: transpose(options.transpose),
+            cutoff: filter::MAX_CUTOFF,
+            resonance: 0,
+            midi_macro: 0,
+            midi_config: Arc::clone(&options.midi_config),
+            midi_message: None,
+            new_note_action: NewNoteAction::Cut,
+            effect: None,
+            param: 0,
+            volume_command: None,
+            delayed: None,
+            cut_tick: None,
+            memory: [0; 27],
+            volume_column_memory: 0,
+            vibrato: oscillator,
+            tremolo: oscillator,
+            panbrello: oscillator,
+            glissando: false,
+            offset_high: 0,
+            tremor_counter: 0,
+            retrigger_counter: 0,
+            modulation: Modulation::default(),
+            random: 0x9E37_79B9 ^ u32::try_from(index).unwrap(),
+            voice: None,
+            triggered: false,
+        }
+    }
+
+    /// Returns the status of the channel, the peak is left to the player.
+    pub(super) fn status(&self) -> ChannelStatus {
+        ChannelStatus {
+            note: self.voice.as_ref().map(|voice| voice.origin.note),
+            instrument: self.instrument,
+            sample: self.sample,
+            volume: self.volume,
+            channel_volume: self.channel_volume,
+            panning: self.panning,
+            envelopes: self.voice.as_ref().map(Voice::envelopes),
+            peak: [0.0; 2],
+        }
+    }
+
+    /// Applies `options` to a channel restored from a snapshot and gives its voice the sample
+    /// data of `module`, returns `false` if the voice doesn't fit the module.
+    pub(super) fn attach(&mut self, module: &Module, options: &PlayerOptions) -> bool {
+        self.amiga_limits = options.amiga_limits;
+        self.ramping = options.volume_ramping;
+        self.declick = options.declick;
+        self.separation = separation(options);
+        self.surround = options.surround;
+        self.transpose = transpose(options.transpose);
+        self.midi_config = Arc::clone(&options.midi_config);
+        self.voice.as_mut().map_or(true, |voice| {
+            voice.ramping = self.ramping;
+            voice.declick = self.declick;
+            voice.attach(module)
+        })
+    }
+
+    /// Transposes the notes by `semitones` from the next tick.
+    pub(super) fn set_transpose(&mut self, semitones: f64) {
+        self.transpose = transpose(semitones);
+    }
+
+    /// Returns the effect of the current row with the effect memory applied.
+    pub(super) fn effect(&self) -> Option<EffectCmd> {
+        self.effect
+    }
+
+    /// Starts a row, `command` is the cell of the channel and `channel` its index.
+    ///
+    /// Voices moved out of the channel by New Note Actions are added to `background`.
+    pub(super) fn row(
+        &mut self,
+        module: &Module,
+        command: Option<&Command>,
+        channel: usize,
+        background: &mut Vec<Voice>,
+        globals: &mut Globals,
+    ) {
+        let command = command.copied().unwrap_or(Command::EMPTY);
+        self.delayed = None;
+        self.cut_tick = None;
+        self.resolve_effect(module, command.effect);
+        self.volume_command = command.volume;
+
+        match self.effect {
+            Some(EffectCmd::Special(Some(Special::NoteDelay(x)))) if x.as_u8() > 0 => {
+                self.delayed = Some((u32::from(x.as_u8()), command));
+            }
+            _ => self.trigger(module, &command, channel, background),
+        }
+        self.first_tick(module, channel, background, globals);
+        self.limit_frequency(module);
+        self.modulate(module, 0);
+    }
+
+    /// Repeats the first tick of the row without its note, for the repetitions of the row delay
+    /// (`SEx`).
+    pub(super) fn repeat(
+        &mut self,
+        module: &Module,
+        channel: usize,
+        background: &mut Vec<Voice>,
+        globals: &mut Globals,
+    ) {
+        self.first_tick(module, channel, background, globals);
+        self.limit_frequency(module);
+        self.modulate(module, 0);
+    }
+
+    /// Processes a tick after the first one of the row.
+    pub(super) fn tick(
+        &mut self,
+        module: &Module,
+        tick: u32,
+        channel: usize,
+        background: &mut Vec<Voice>,
+        globals: &mut Globals,
+    ) {
+        if let Some((delay, command)) = self.delayed {
+            if tick == delay {
+                self.delayed = None;
+                self.trigger(module, &command, channel, background);
+            }
+        }
+        if self.cut_tick == Some(tick) {
+            self.cut(background);
+        }
+
+        let linear = module.flags.contains(ModuleFlags::LINEAR_SLIDES);
+        match self.effect {
+            Some(EffectCmd::VolumeSlide(slide) | EffectCmd::VolumeSlideAndVibrato(slide) | EffectCmd::VolumeSlideAndPortamento(slide)) => {
+                self.volume = volume_slide(self.volume, slide, false, 64);
+            }
+            Some(EffectCmd::PortamentoDown(Some(Portamento::Coarse(xx)))) => {
+                self.frequency = slide(self.frequency, -4 * i32::from(xx.as_u8()), linear);
+            }
+            Some(EffectCmd::PortamentoUp(Some(Portamento::Coarse(xx)))) => {
+                self.frequency = slide(self.frequency, 4 * i32::from(xx.as_u8()), linear);
+            }
+            Some(EffectCmd::ChannelVolumeSlide(slide)) => {
+                self.channel_volume = volume_slide(self.channel_volume, slide, false, 64);
+            }
+            Some(EffectCmd::GlobalVolumeSlide(slide)) => {
+                globals.global_volume = volume_slide(globals.global_volume, slide, false, 128);
+            }
+            Some(EffectCmd::PanningSlide(slide)) => self.panning_slide(slide, false),
+            Some(EffectCmd::Tempo(Some(Tempo::SlideUp(x)))) => {
+                globals.tempo = (globals.tempo + u32::from(x.as_u8())).min(255);
+            }
+            Some(EffectCmd::Tempo(Some(Tempo::SlideDown(x)))) => {
+                globals.tempo = globals.tempo.saturating_sub(u32::from(x.as_u8())).max(32);
+            }
+            Some(EffectCmd::Retrigger(_)) => self.retrigger(module),
+            _ => {}
+        }
+        if matches!(self.effect, Some(EffectCmd::TonePortamento(_) | EffectCmd::VolumeSlideAndPortamento(_))) {
+            let speed = self.memory[memory_slot(0x7, module)];
+            self.tone_portamento(speed, linear);
+        }
+
+        match self.volume_command {
+            Some(VolumeCmd::VolumeSlideUp(_)) => self.volume = (self.volume + self.volume_column_memory).min(64),
+            Some(VolumeCmd::VolumeSlideDown(_)) => self.volume = self.volume.saturating_sub(self.volume_column_memory),
+            Some(VolumeCmd::PortamentoDown(_)) => {
+                let speed = self.memory[memory_slot(0x5, module)];
+                self.frequency = slide(self.frequency, -4 * i32::from(speed), linear);
+            }
+            Some(VolumeCmd::PortamentoUp(_)) => {
+                let speed = self.memory[memory_slot(0x5, module)];
+                self.frequency = slide(self.frequency, 4 * i32::from(speed), linear);
+            }
+            Some(VolumeCmd::TonePortamento(_)) => {
+                let speed = self.memory[memory_slot(0x7, module)];
+                self.tone_portamento(speed, linear);
+            }
+            _ => {}
+        }
+
+        self.limit_frequency(module);
+        self.modulate(module, tick);
+    }
+
+    /// Applies the effect memory to `effect` and stores the result in `self.effect`.
+    ///
+    /// Effects with a parameter of zero use the last non-zero parameter of the effect, vibrato,
+    /// tremolo and panbrello remember each of their parameters separately.
+    fn resolve_effect(&mut self, module: &Module, effect: Option<EffectCmd>) {
+        self.effect = None;
+        self.param = 0;
+        let Some(effect) = effect else {
+            return;
+        };
+        let (number, mut param) = writer::effect(&effect);
+        if b"DEFGIJKLNOPQSTW".contains(&(b'@' + number)) {
+            let slot = memory_slot(number, module);
+            if param == 0 {
+                param = self.memory[slot];
+            } else {
+                self.memory[slot] = param;
+            }
+        }
+        self.effect = parser::effect(number, param);
+        self.param = param;
+
+        match self.effect {
+            Some(EffectCmd::Vibrato(speed, depth)) => self.vibrato.set(speed, depth.map(|depth| depth.as_u8() * 4)),
+            Some(EffectCmd::FineVibrato(speed, depth)) => self.vibrato.set(speed, depth.map(RangedU8::as_u8)),
+            Some(EffectCmd::Tremolo(speed, depth)) => self.tremolo.set(speed, depth.map(RangedU8::as_u8)),
+            Some(EffectCmd::Panbrello(speed, depth)) => self.panbrello.set(speed, depth.map(RangedU8::as_u8)),
+            _ => {}
+        }
+    }
+
+    /// Applies the note, instrument and volume column of `command`.
+    fn trigger(&mut self, module: &Module, command: &Command, channel: usize, background: &mut Vec<Voice>) {
+        if let Some(instrument) = command.instrument {
+            self.instrument = Some(instrument);
+            let note = match command.note {
+                Some(NoteCmd::Play(note)) => note,
+                _ => self.note.unwrap_or(Note::C_5),
+            };
+            if let Some((sample, _)) = resolve(module, instrument, note) {
+                self.instrument_defaults(module, instrument, sample);
+            }
+        }
+
+        let tone_portamento = matches!(self.effect, Some(EffectCmd::TonePortamento(_) | EffectCmd::VolumeSlideAndPortamento(_)))
+            || matches!(command.volume, Some(VolumeCmd::TonePortamento(_)));
+        match command.note {
+            Some(NoteCmd::Play(note)) if tone_portamento && self.voice.is_some() => {
+                // The playing note slides to the new one instead of being replaced.
+                self.note = Some(note);
+                self.target_frequency = self.instrument
+                    .and_then(|instrument| resolve(module, instrument, note))
+                    .and_then(|(sample, translated)| Some(note_frequency(module.get(sample)?, translated)));
+            }
+            Some(NoteCmd::Play(note)) => {
+                self.play(module, note, channel, background);
+                if let Some(EffectCmd::SetSampleOffset(SetSampleOffset::Low(xx))) = self.effect {
+                    self.sample_offset(module, xx);
+                }
+            }
+            Some(NoteCmd::Off) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.release();
+                }
+            }
+            Some(NoteCmd::Cut) => self.cut(background),
+            Some(NoteCmd::Fade) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.fade();
+                }
+            }
+            None => {}
+        }
+
+        match command.volume {
+            Some(VolumeCmd::SetVolume(volume)) => self.volume = volume.as_u8(),
+            Some(VolumeCmd::Panning(panning)) => self.panning = ChannelPanning::Position(panning),
+            _ => {}
+        }
+    }
+
+    /// Applies the effects and volume column commands of the first tick of the row.
+    fn first_tick(&mut self, module: &Module, channel: usize, background: &mut Vec<Voice>, globals: &mut Globals) {
+        let linear = module.flags.contains(ModuleFlags::LINEAR_SLIDES);
+        match self.effect {
+            Some(EffectCmd::VolumeSlide(slide) | EffectCmd::VolumeSlideAndVibrato(slide) | EffectCmd::VolumeSlideAndPortamento(slide)) => {
+                self.volume = volume_slide(self.volume, slide, true, 64);
+            }
+            Some(EffectCmd::PortamentoDown(Some(portamento))) => {
+                self.frequency = slide(self.frequency, -fine_portamento(portamento), linear);
+            }
+            Some(EffectCmd::PortamentoUp(Some(portamento))) => {
+                self.frequency = slide(self.frequency, fine_portamento(portamento), linear);
+            }
+            Some(EffectCmd::SetChannelVolume(xx)) => self.channel_volume = xx.as_u8(),
+            Some(EffectCmd::ChannelVolumeSlide(slide)) => {
+                self.channel_volume = volume_slide(self.channel_volume, slide, true, 64);
+            }
+            Some(EffectCmd::GlobalVolumeSlide(slide)) => {
+                globals.global_volume = volume_slide(globals.global_volume, slide, true, 128);
+            }
+            Some(EffectCmd::SetSampleOffset(SetSampleOffset::High(y))) => self.offset_high = y.as_u8(),
+            Some(EffectCmd::PanningSlide(slide)) => self.panning_slide(slide, true),
+            Some(EffectCmd::SetPanningPosition(xx)) => {
+                let panning = ((u16::from(xx) + 2) / 4).min(64);
+                self.panning = ChannelPanning::Position(RangedU8::new(u8::try_from(panning).unwrap()));
+            }
+            Some(EffectCmd::Special(Some(special))) => self.special(special, channel, background),
+            Some(EffectCmd::Midi(xx)) => self.midi(xx, channel),
+            _ => {}
+        }
+
+        match self.volume_command {
+            Some(
+                VolumeCmd::FineVolumeUp(x)
+                | VolumeCmd::FineVolumeDown(x)
+                | VolumeCmd::VolumeSlideUp(x)
+                | VolumeCmd::VolumeSlideDown(x),
+            ) => {
+                // The volume column slides share their own memory, separate from `Dxy`.
+                if let Some(x) = x {
+                    self.volume_column_memory = x.as_u8();
+                }
+            }
+            Some(VolumeCmd::PortamentoDown(Some(x)) | VolumeCmd::PortamentoUp(Some(x))) => {
+                self.memory[memory_slot(0x5, module)] = x.as_u8() * 4;
+            }
+            Some(VolumeCmd::TonePortamento(Some(x))) => {
+                self.memory[memory_slot(0x7, module)] = TONE_PORTAMENTO_SPEEDS[usize::from(x.as_u8())];
+            }
+            Some(VolumeCmd::Vibrato(Some(x))) => self.vibrato.depth = x.as_u8() * 4,
+            _ => {}
+        }
+        match self.volume_command {
+            Some(VolumeCmd::FineVolumeUp(_)) => self.volume = (self.volume + self.volume_column_memory).min(64),
+            Some(VolumeCmd::FineVolumeDown(_)) => self.volume = self.volume.saturating_sub(self.volume_column_memory),
+            _ => {}
+        }
+    }
+
+    /// Applies a special (`Sxx`) command on the first tick of the row.
+    ///
+    /// The pattern delays and loops are handled by the player. Finetune (`S2x`) has no effect in
+    /// Impulse Tracker, reverb and the surround and filter modes only affect hardware mixers.
+    fn special(&mut self, special: Special, channel: usize, background: &mut Vec<Voice>) {
+        match special {
+            Special::SetGlissando(glissando) => self.glissando = glissando,
+            Special::SetVibratoWaveform(waveform) => self.vibrato.waveform = waveform,
+            Special::SetTremoloWaveform(waveform) => self.tremolo.waveform = waveform,
+            Special::SetPanbrelloWaveform(waveform) => self.panbrello.waveform = waveform,
+            Special::PastNote(action) => {
+                let action = match action {
+                    SetPastNote::Cut => DuplicateCheckAction::Cut,
+                    SetPastNote::Off => DuplicateCheckAction::Off,
+                    SetPastNote::Fade => DuplicateCheckAction::Fade,
+                };
+                background_action(background, action, |voice| voice.origin.channel == channel);
+            }
+            Special::SetNewNoteAction(action) => {
+                self.new_note_action = match action {
+                    SetNewNoteAction::Cut => NewNoteAction::Cut,
+                    SetNewNoteAction::Off => NewNoteAction::Off,
+                    SetNewNoteAction::Fade => NewNoteAction::Fade,
+                    SetNewNoteAction::Continue => NewNoteAction::Continue,
+                };
+            }
+            Special::SetPanning(x) => {
+                let panning = (u16::from(x.as_u8()) * 64 + 7) / 15;
+                self.panning = ChannelPanning::Position(RangedU8::new(u8::try_from(panning).unwrap()));
+            }
+            Special::SetSurround(true) => self.panning = ChannelPanning::Surround,
+            Special::SetSurround(false) => {
+                if matches!(self.panning, ChannelPanning::Surround) {
+                    self.panning = ChannelPanning::Position(RangedU8::new(32));
+                }
+            }
+            Special::SetVolumeEnvelope(enabled) => self.enable_envelope(0, enabled),
+            Special::SetPanningEnvelope(enabled) => self.enable_envelope(1, enabled),
+            Special::SetPitchEnvelope(enabled) => self.enable_envelope(2, enabled),
+            Special::SetDirection(direction) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.set_direction(direction == PlayDirection::Forward);
+                }
+            }
+            // `SC0` cuts the note on the next tick like `SC1`.
+            Special::NoteCut(x) => self.cut_tick = Some(u32::from(x.as_u8().max(1))),
+            Special::SetMidiParam(x) => self.midi_macro = x.as_u8(),
+            _ => {}
+        }
+    }
+
+    /// Turns an envelope of the playing note on or off (`S77`..`S7C`).
+    fn enable_envelope(&mut self, envelope: usize, enabled: bool) {
+        if let Some(voice) = &mut self.voice {
+            voice.envelope_enabled[envelope] = Some(enabled);
+        }
+    }
+
+    /// Sends the MIDI macro of `Zxx`: `Z00`..`Z7F` the one selected by `SFx`, `Z80`..`ZFF` the
+    /// fixed ones.
+    ///
+    /// The filter messages of the internal MIDI device (`F0 F0 00 xx` and `F0 F0 01 xx`) set the
+    /// cutoff and the resonance, the other messages are left to the player's events.
+    fn midi(&mut self, xx: u8, channel: usize) {
+        let midi_macro = match xx {
+            0x00..=0x7F => &self.midi_config.parametered[usize::from(self.midi_macro & 0x0F)],
+            _ => &self.midi_config.fixed[usize::from(xx - 0x80)],
+        };
+        let message = midi_macro.evaluate(xx, channel);
+        match *message.as_bytes() {
+            [0xF0, 0xF0, 0x00, value] => self.cutoff = value.min(filter::MAX_CUTOFF),
+            [0xF0, 0xF0, 0x01, value] => self.resonance = value.min(127),
+            [] => {}
+            _ => self.midi_message = Some(message),
+        }
+    }
+
+    /// Resets the volume and panning to the defaults of the instrument and sample.
+    fn instrument_defaults(&mut self, module: &Module, instrument: InstrumentId, sample: SampleId) {
+        if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            if let Some(instrument) = module.get(instrument) {
+                if instrument.flags.contains(InstrumentFlags::ENABLE_PANNING) {
+                    let panning = instrument.default_panning.as_u8().min(64);
+                    self.panning = ChannelPanning::Position(RangedU8::new(panning));
+                }
+                if instrument.flags.contains(InstrumentFlags::ENABLE_FILTER_CUTOFF) {
+                    self.cutoff = instrument.initial_filter_cutoff.as_u8().min(filter::MAX_CUTOFF);
+                }
+                if instrument.flags.contains(InstrumentFlags::ENABLE_FILTER_RESONANCE) {
+                    self.resonance = instrument.initial_filter_resonance.as_u8().min(127);
+                }
+            }
+        }
+        if let Some(sample) = module.get(sample) {
+            self.volume = sample.default_volume.min(64);
+            if sample.default_panning & 0x80 != 0 {
+                let panning = (sample.default_panning & 0x7F).min(64);
+                self.panning = ChannelPanning::Position(RangedU8::new(panning));
+            }
+        }
+    }
+
+    /// Starts playing `note` with the last instrument.
+    ///
+    /// The duplicate check of the instrument is applied to the voices of the channel, then the
+    /// New Note Action to the playing voice.
+    fn play(&mut self, module: &Module, note: Note, channel: usize, background: &mut Vec<Voice>) {
+        self.note = Some(note);
+        let resolved = self.instrument.and_then(|instrument| resolve(module, instrument, note));
+        let instrument = self.instrument
+            .filter(|_| module.flags.contains(ModuleFlags::USE_INSTRUMENTS))
+            .and_then(|id| Some((id, module.get(id)?)));
+
+        // Envelopes with carry continue from the previous note of the same instrument.
+        let previous = self.voice
+            .as_ref()
+            .filter(|voice| instrument.is_some_and(|(id, _)| voice.origin.instrument == Some(id)))
+            .map(|voice| voice.envelopes);
+
+        if let (Some((id, instrument)), Some((sample, _))) = (instrument, resolved) {
+            let duplicate = |voice: &Voice| {
+                voice.origin.channel == channel
+                    && voice.origin.instrument == Some(id)
+                    && match instrument.duplicate_check_type {
+                        DuplicateCheckType::Off => false,
+                        DuplicateCheckType::Note => voice.origin.note == note,
+                        DuplicateCheckType::Sample => voice.origin.sample == sample,
+                        DuplicateCheckType::Instrument => true,
+                    }
+            };
+            let action = instrument.duplicate_check_action;
+            background_action(background, action, duplicate);
+            if self.voice.as_ref().is_some_and(duplicate) {
+                match action {
+                    DuplicateCheckAction::Cut => self.cut(background),
+                    DuplicateCheckAction::Off => self.voice.iter_mut().for_each(Voice::release),
+                    DuplicateCheckAction::Fade => self.voice.iter_mut().for_each(Voice::fade),
+                }
+            }
+        }
+
+        if let Some(mut voice) = self.voice.take() {
+            match self.new_note_action {
+                NewNoteAction::Cut => {
+                    if voice.cut() {
+                        push_background(background, voice);
+                    }
+                }
+                NewNoteAction::Continue => push_background(background, voice),
+                NewNoteAction::Off => {
+                    voice.release();
+                    push_background(background, voice);
+                }
+                NewNoteAction::Fade => {
+                    voice.fade();
+                    push_background(background, voice);
+                }
+            }
+        }
+
+        let Some((sample_id, translated)) = resolved else {
+            return;
+        };
+        self.sample = Some(sample_id);
+        self.new_note_action = instrument.map_or(NewNoteAction::Cut, |(_, instrument)| instrument.new_note_action);
+        self.target_frequency = None;
+        for oscillator in [&mut self.vibrato, &mut self.tremolo, &mut self.panbrello] {
+            oscillator.position = 0;
+        }
+        let origin = Origin { channel, instrument: instrument.map(|(id, _)| id), sample: sample_id, note };
+        self.voice = module.get(sample_id).and_then(|sample| {
+            self.frequency = note_frequency(sample, translated);
+            Voice::new(sample, origin, self.frequency)
+        });
+        self.triggered |= self.voice.is_some();
+        if let Some(voice) = &mut self.voice {
+            voice.ramping = self.ramping;
+            voice.declick = self.declick;
+        }
+        if let (Some(voice), Some((_, instrument))) = (&mut self.voice, instrument) {
+            voice.fadeout = u16::from(instrument.instrument_fadeout);
+            if let Some(previous) = previous {
+                voice.carry(previous, instrument);
+            }
+        }
+    }
+
+    /// Cuts the note of the channel, with volume ramping or declicking it fades out in the
+    /// background.
+    fn cut(&mut self, background: &mut Vec<Voice>) {
+        if let Some(mut voice) = self.voice.take() {
+            if voice.cut() {
+                push_background(background, voice);
+            }
+        }
+    }
+
+    /// Starts the new note at the offset `0xyxx00` of `Oxx` and `SAy`.
+    ///
+    /// Offsets past the end of the sample are ignored, with old effects they stop the note.
+    fn sample_offset(&mut self, module: &Module, xx: u8) {
+        let offset = (u32::from(self.offset_high) << 16) | (u32::from(xx) << 8);
+        if let Some(voice) = &mut self.voice {
+            if !voice.set_offset(offset) && module.flags.contains(ModuleFlags::OLD_EFFECTS) {
+                self.voice = None;
+            }
+        }
+    }
+
+    /// Slides the panning by `Pxy`.
+    fn panning_slide(&mut self, slide: Option<PanningSlide>, first_tick: bool) {
+        let ChannelPanning::Position(position) = self.panning else {
+            return;
+        };
+        let position = position.as_u8();
+        let position = match slide {
+            Some(PanningSlide::Right(x)) if !first_tick => position.saturating_add(x.as_u8()),
+            Some(PanningSlide::Left(x)) if !first_tick => position.saturating_sub(x.as_u8()),
+            Some(PanningSlide::FineRight(x)) if first_tick => position.saturating_add(x.as_u8()),
+            Some(PanningSlide::FineLeft(x)) if first_tick => position.saturating_sub(x.as_u8()),
+            _ => position,
+        };
+        self.panning = ChannelPanning::Position(RangedU8::new(position.min(64)));
+    }
+
+    /// Slides the pitch towards the target of the tone portamento by `speed` (`Gxx`).
+    fn tone_portamento(&mut self, speed: u8, linear: bool) {
+        let Some(target) = self.target_frequency else {
+            return;
+        };
+        let units = 4 * i32::from(speed);
+        if self.frequency < target {
+            self.frequency = slide(self.frequency, units, linear).min(target);
+        } else {
+            self.frequency = slide(self.frequency, -units, linear).max(target);
+        }
+        if self.frequency == target {
+            self.target_frequency = None;
+        }
+    }
+
+    /// Clamps the frequency to the periods of [`AMIGA_LIMITS`] when enabled with Amiga slides.
+    fn limit_frequency(&mut self, module: &Module) {
+        if !self.amiga_limits || module.flags.contains(ModuleFlags::LINEAR_SLIDES) || self.frequency <= 0.0 {
+            return;
+        }
+        if let Some(sample) = self.sample.and_then(|sample| module.get(sample)) {
+            let c5 = f64::from(sample.samplerate_c5);
+            let (low, high) = AMIGA_LIMITS;
+            self.frequency = self.frequency.clamp(c5 * 428.0 / low, c5 * 428.0 / high);
+        }
+    }
+
+    /// Counts a tick of the retrigger (`Qxy`), restarting the note every `y` ticks.
+    fn retrigger(&mut self, module: &Module) {
+        let (x, y) = (self.param >> 4, self.param & 0x0F);
+        self.retrigger_counter += 1;
+        if self.retrigger_counter < u32::from(y.max(1)) {
+            return;
+        }
+        self.retrigger_counter = 0;
+
+        let volume = i32::from(self.volume);
+        let volume = match x {
+            0x1..=0x5 => volume - (1 << (x - 1)),
+            0x6 => volume * 2 / 3,
+            0x7 => volume / 2,
+            0x9..=0xD => volume + (1 << (x - 9)),
+            0xE => volume * 3 / 2,
+            0xF => volume * 2,
+            _ => volume,
+        };
+        self.volume = u8::try_from(volume.clamp(0, 64)).unwrap();
+        if let (Some(voice), Some(sample)) = (&mut self.voice, self.sample.and_then(|sample| module.get(sample))) {
+            voice.retrigger(sample);
+        }
+    }
+
+    /// Computes the temporary changes of vibrato, tremolo, panbrello, tremor and arpeggio for a
+    /// tick of the row.
+    ///
+    /// The waveforms advance on every tick except the first.
+    fn modulate(&mut self, module: &Module, tick: u32) {
+        self.modulation = Modulation::default();
+        let vibrato = matches!(
+            self.effect,
+            Some(EffectCmd::Vibrato(..) | EffectCmd::FineVibrato(..) | EffectCmd::VolumeSlideAndVibrato(_))
+        ) || matches!(self.volume_command, Some(VolumeCmd::Vibrato(_)));
+
+        if vibrato {
+            if tick > 0 {
+                self.vibrato.advance();
+            }
+            let value = self.vibrato.value(&mut self.random);
+            self.modulation.pitch = value * i32::from(self.vibrato.depth) / 64;
+        }
+        match self.effect {
+            Some(EffectCmd::Tremolo(..)) => {
+                if tick > 0 {
+                    self.tremolo.advance();
+                }
+                self.modulation.volume = self.tremolo.value(&mut self.random) * i32::from(self.tremolo.depth) / 32;
+            }
+            Some(EffectCmd::Panbrello(..)) => {
+                if tick > 0 {
+                    self.panbrello.advance();
+                }
+                self.modulation.panning = self.panbrello.value(&mut self.random) * i32::from(self.panbrello.depth) / 32;
+            }
+            Some(EffectCmd::Tremor(_)) => {
+                // Old effects add a tick to both times.
+                let extra = u32::from(module.flags.contains(ModuleFlags::OLD_EFFECTS));
+                let on = u32::from(self.param >> 4).max(1) + extra;
+                let off = u32::from(self.param & 0x0F).max(1) + extra;
+                self.modulation.silent = self.tremor_counter % (on + off) >= on;
+                self.tremor_counter += 1;
+            }
+            Some(EffectCmd::Arpeggio(_)) => {
+                self.modulation.semitones = match tick % 3 {
+                    0 => 0,
+                    1 => self.param >> 4,
+                    _ => self.param & 0x0F,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Updates the volume, panning, pitch and filter of the voice for the current tick, then
+    /// its envelopes and fadeout.
+    pub(super) fn update(&mut self, module: &Module, global_volume: u8, sample_rate: u32) {
+        let Some(voice) = &mut self.voice else {
+            return;
+        };
+        voice.cutoff = self.cutoff;
+        voice.resonance = self.resonance;
+
+        let mut frequency = self.frequency;
+        if self.glissando && self.target_frequency.is_some() {
+            // Glissando rounds the slide to the semitones of the sample.
+            if let Some(sample) = self.sample.and_then(|sample| module.get(sample)) {
+                let c5 = f64::from(sample.samplerate_c5);
+                if c5 > 0.0 && frequency > 0.0 {
+                    frequency = c5 * ((12.0 * (frequency / c5).log2()).round() / 12.0).exp2();
+                }
+            }
+        }
+        let linear = module.flags.contains(ModuleFlags::LINEAR_SLIDES);
+        frequency = slide(frequency, self.modulation.pitch, linear);
+        voice.frequency = frequency * (f64::from(self.modulation.semitones) / 12.0).exp2() * self.transpose;
+
+        let note_volume = (i32::from(self.volume) + self.modulation.volume).clamp(0, 64);
+        let mut volume = f32::from(u8::try_from(note_volume).unwrap()) / 64.0
+            * f32::from(self.channel_volume) / 64.0
+            * f32::from(global_volume) / 128.0
+            * f32::from(module.sample_volume.as_u8()) / 128.0;
+        if let Some(sample) = self.sample.and_then(|sample| module.get(sample)) {
+            volume *= f32::from(sample.global_volume.min(64)) / 64.0;
+        }
+        if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            if let Some(instrument) = self.instrument.and_then(|instrument| module.get(instrument)) {
+                volume *= f32::from(instrument.global_volume.min(128)) / 128.0;
+            }
+        }
+        if self.muted || self.modulation.silent {
+            volume = 0.0;
+        }
+
+        let pan = match self.panning {
+            ChannelPanning::Position(position) => {
+                let position = (i32::from(position.as_u8()) + self.modulation.panning).clamp(0, 64);
+                f32::from(u8::try_from(position).unwrap()) / 64.0
+            }
+            ChannelPanning::Surround => 0.5,
+        };
+        voice.volume = volume;
+        voice.pan = pan;
+        voice.separation = if module.flags.contains(ModuleFlags::STEREO) {
+            f32::from(module.pan_separation.as_u8()) / 128.0 * self.separation
+        } else {
+            0.0
+        };
+        voice.surround = self.surround && voice.separation > 0.0 && matches!(self.panning, ChannelPanning::Surround);
+        if !voice.update(module, sample_rate) {
+            self.voice = None;
+        }
+    }
+}
+
+impl Oscillator {
+    /// Sets the parameters, `None` keeps the previous value.
+    fn set(&mut self, speed: Option<RangedU8<1, 0x0F>>, depth: Option<u8>) {
+        if let Some(speed) = speed {
+            self.speed = speed.as_u8();
+        }
+        if let Some(depth) = depth {
+            self.depth = depth;
+        }
+    }
+
+    fn advance(&mut self) {
+        self.position = self.position.wrapping_add(self.speed * 4);
+    }
+
+    /// Returns the value of the waveform at the position, from -64 to 64.
+    fn value(&self, random: &mut u32) -> i32 {
+        let position = i32::from(self.position);
+        match self.waveform {
+            Waveform::Sine => {
+                let value = round_i64(64.0 * (2.0 * PI * f64::from(self.position) / 256.0).sin());
+                i32::try_from(value).unwrap()
+            }
+            Waveform::Sawtooth => 64 - position / 2,
+            Waveform::Square => if position < 128 { 64 } else { -64 },
+            Waveform::Random => {
+                // xorshift32
+                *random ^= *random << 13;
+                *random ^= *random >> 17;
+                *random ^= *random << 5;
+                i32::try_from(*random % 129).unwrap() - 64
+            }
+        }
+    }
+}
+
+/// Stereo separation (0..=2) of [`PlayerOptions::stereo_separation`]
+fn separation(options: &PlayerOptions) -> f32 {
+    f32::from(u8::try_from(options.stereo_separation.min(200)).unwrap()) / 100.0
+}
+
+/// Pitch ratio of a transposition by `semitones`
+fn transpose(semitones: f64) -> f64 {
+    (semitones / 12.0).exp2()
+}
+
+/// Slot of `number` in [`ChannelState::memory`]
+///
+/// `Kxy` and `Lxy` share the memory of `Dxy`, `Fxx` the memory of `Exx` and with linked effects
+/// `Gxx` too.
+fn memory_slot(number: u8, module: &Module) -> usize {
+    let slot = match b'@' + number {
+        b'K' | b'L' => b'D',
+        b'F' => b'E',
+        b'G' if module.flags.contains(ModuleFlags::LINK_G_E_EFFECTS) => b'E',
+        letter => letter,
+    };
+    usize::from(slot - b'@')
+}
+
+/// Slides `frequency` up by `units`
+///
+/// With linear slides a unit is 1/64 of a semitone, otherwise the slides change the period of
+/// the note (see [`AMIGA_CLOCK`]).
+fn slide(frequency: f64, units: i32, linear: bool) -> f64 {
+    if units == 0 || frequency <= 0.0 {
+        return frequency;
+    }
+    if linear {
+        return frequency * (f64::from(units) / 768.0).exp2();
+    }
+    let period = AMIGA_CLOCK / frequency - f64::from(units);
+    AMIGA_CLOCK / period.max(1.0)
+}
+
+/// Returns the slide units of the fine and extra fine portamentos, zero for coarse ones.
+fn fine_portamento(portamento: Portamento) -> i32 {
+    match portamento {
+        Portamento::Coarse(_) => 0,
+        Portamento::Fine(x) => 4 * i32::from(x.as_u8()),
+        Portamento::ExtraFine(x) => i32::from(x.as_u8()),
+    }
+}
+
+/// Applies a volume slide (`Dxy`, `Nxy`, `Wxy`) to a volume in `0..=max`
+///
+/// Fine slides apply on the first tick, the others on the other ticks, slides by `F` on all
+/// ticks.
+fn volume_slide(volume: u8, slide: Option<VolumeSlide>, first_tick: bool, max: u8) -> u8 {
+    let (up, down) = match slide {
+        Some(VolumeSlide::Up(x)) if !first_tick || x.as_u8() == 0x0F => (x.as_u8(), 0),
+        Some(VolumeSlide::Down(y)) if !first_tick || y.as_u8() == 0x0F => (0, y.as_u8()),
+        Some(VolumeSlide::FineUp(x)) if first_tick => (x.as_u8(), 0),
+        Some(VolumeSlide::FineDown(y)) if first_tick => (0, y.as_u8()),
+        _ => (0, 0),
+    };
+    volume.saturating_add(up).min(max).saturating_sub(down)
+}
+
+/// Frequency of `note` played with `sample`
+fn note_frequency(sample: &Sample, note: Note) -> f64 {
+    let semitones = f64::from(u8::from(note)) - f64::from(u8::from(Note::C_5));
+    f64::from(sample.samplerate_c5) * (semitones / 12.0).exp2()
+}
+
+/// Adds a voice to the background voices, cutting the oldest one if there are too many.
+fn push_background(background: &mut Vec<Voice>, voice: Voice) {
+    if background.len() >= MAX_BACKGROUND_VOICES {
+        background.remove(0);
+    }
+    background.push(voice);
+}
+
+/// Applies `action` to the background voices selected by `filter`.
+fn background_action(background: &mut Vec<Voice>, action: DuplicateCheckAction, filter: impl Fn(&Voice) -> bool) {
+    match action {
+        DuplicateCheckAction::Cut => background.retain_mut(|voice| !filter(voice) || voice.cut()),
+        DuplicateCheckAction::Off => background.iter_mut().filter(|voice| filter(voice)).for_each(Voice::release),
+        DuplicateCheckAction::Fade => background.iter_mut().filter(|voice| filter(voice)).for_each(Voice::fade),
+    }
+}
+
+/// Returns the sample played by `note` on `instrument` and the note it's played at
+///
+/// In sample mode the instrument column selects the sample directly.
+fn resolve(module: &Module, instrument: InstrumentId, note: Note) -> Option<(SampleId, Note)> {
+    if !module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        return Some((SampleId::from_index(instrument.as_u8()).ok()?, note));
+    }
+    let sample_map = &module.get(instrument)?.sample_map;
+    Some((sample_map.sample_for(note)?, sample_map.note_translation_for(note)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{test::module, Player, PlayerOptions};
+
+    /// Plays `commands` on the first channel from the first row, after the note of the module.
+    fn player(module: &mut Module, commands: &[Command]) -> Player {
+        for (row, command) in commands.iter().enumerate() {
+            let mut command = *command;
+            if row == 0 {
+                command.note = Some(NoteCmd::Play(Note::C_5));
+                command.instrument = Some(InstrumentId::from_index(0).unwrap());
+            }
+            module.patterns[0].rows[row] = Row::from_vec(vec![(Channel::new(1), command)]);
+        }
+        Player::new(module.clone(), PlayerOptions::default())
+    }
+
+    /// Plays `ticks` ticks of 960 frames.
+    fn render(player: &mut Player, ticks: usize) {
+        let mut out = vec![0.0f32; 960 * 2 * ticks];
+        assert_eq!(player.render_f32(&mut out), 960 * ticks);
+    }
+
+    fn effect(letter: u8, param: u8) -> Command {
+        Command { effect: parser::effect(letter - b'@', param), ..Command::EMPTY }
+    }
+
+    fn frequency(player: &Player) -> f64 {
+        player.channels[0].voice.as_ref().unwrap().frequency
+    }
+
+    fn volume(player: &Player) -> f32 {
+        player.channels[0].voice.as_ref().unwrap().volume
+    }
+
+    fn pan(player: &Player) -> f32 {
+        player.channels[0].voice.as_ref().unwrap().pan
+    }
+
+    #[test]
+    fn slides() {
+        assert_eq!(slide(8363.0, 768, true), 16726.0);
+        assert_eq!(slide(8363.0, -768, true), 4181.5);
+        // Amiga slides change the period, 1712 for C-5.
+        assert_eq!(slide(8363.0, 856, false), 16726.0);
+
+        assert_eq!(volume_slide(32, Some(VolumeSlide::Up(RangedU8::new(4))), false, 64), 36);
+        assert_eq!(volume_slide(32, Some(VolumeSlide::Up(RangedU8::new(4))), true, 64), 32);
+        assert_eq!(volume_slide(32, Some(VolumeSlide::Down(RangedU8::new(15))), true, 64), 17);
+        assert_eq!(volume_slide(62, Some(VolumeSlide::FineUp(RangedU8::new(4))), true, 64), 64);
+        assert_eq!(volume_slide(2, Some(VolumeSlide::FineDown(RangedU8::new(4))), true, 64), 0);
+    }
+
+    #[test]
+    fn waveforms() {
+        let mut random = 1;
+        let mut oscillator = Oscillator { speed: 16, depth: 0, waveform: Waveform::Sine, position: 0 };
+        let sine = (0..4).map(|_| {
+            let value = oscillator.value(&mut random);
+            oscillator.advance();
+            value
+        }).collect::<Vec<_>>();
+        assert_eq!(sine, [0, 64, 0, -64]);
+
+        oscillator.waveform = Waveform::Square;
+        assert_eq!(oscillator.value(&mut random), 64);
+        oscillator.waveform = Waveform::Sawtooth;
+        assert_eq!(oscillator.value(&mut random), 64);
+        oscillator.waveform = Waveform::Random;
+        assert!((-64..=64).contains(&oscillator.value(&mut random)));
+    }
+
+    #[test]
+    fn volume_slide_memory() {
+        let mut module = module();
+        let mut first = effect(b'D', 0x40);
+        first.volume = Some(VolumeCmd::SetVolume(RangedU8::new(8)));
+        let mut player = player(&mut module, &[first, effect(b'D', 0x00), effect(b'D', 0xF4)]);
+
+        // The slide applies on the 5 ticks after the first one, `D00` repeats it.
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].volume, 28);
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].volume, 48);
+
+        // Fine slides apply on the first tick only.
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].volume, 44);
+    }
+
+    #[test]
+    fn portamento() {
+        let mut module = module();
+        module.flags |= ModuleFlags::LINEAR_SLIDES;
+        let mut player = player(&mut module, &[effect(b'F', 0x01), effect(b'E', 0xF1)]);
+        render(&mut player, 6);
+        assert!((frequency(&player) - 48000.0 * (20.0f64 / 768.0).exp2()).abs() < 1e-6);
+        render(&mut player, 6);
+        assert!((frequency(&player) - 48000.0 * (16.0f64 / 768.0).exp2()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn amiga_limits() {
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'E', 0xFF), effect(b'F', 0xFF)]);
+        let options = PlayerOptions { amiga_limits: true, ..PlayerOptions::default() };
+        let mut limited = Player::new(module.clone(), options.clone());
+
+        // The slides stop an octave below and almost two octaves above the C-5 of the sample.
+        render(&mut player, 6);
+        render(&mut limited, 6);
+        assert!(frequency(&player) < 24000.0);
+        assert_eq!(frequency(&limited), 24000.0);
+        render(&mut limited, 6);
+        assert!((frequency(&limited) - 48000.0 * 428.0 / 113.0).abs() < 1e-6);
+
+        // Linear slides aren't limited.
+        module.flags |= ModuleFlags::LINEAR_SLIDES;
+        let mut limited = Player::new(module, options);
+        render(&mut limited, 6);
+        assert!(frequency(&limited) < 24000.0);
+    }
+
+    #[test]
+    fn arpeggio_and_vibrato() {
+        let mut module = module();
+        module.flags |= ModuleFlags::LINEAR_SLIDES;
+        let mut player = player(&mut module, &[effect(b'J', 0x47), effect(b'H', 0x44)]);
+        render(&mut player, 1);
+        assert_eq!(frequency(&player), 48000.0);
+        render(&mut player, 1);
+        assert!((frequency(&player) - 48000.0 * (4.0f64 / 12.0).exp2()).abs() < 1e-6);
+        render(&mut player, 1);
+        assert!((frequency(&player) - 48000.0 * (7.0f64 / 12.0).exp2()).abs() < 1e-6);
+
+        // The vibrato rises a quarter period after 4 ticks of speed 4.
+        render(&mut player, 3 + 5);
+        let depth = 16.0f64 / 768.0;
+        assert!((frequency(&player) - 48000.0 * depth.exp2()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn retrigger() {
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'Q', 0x24)]);
+        render(&mut player, 4);
+        assert_eq!(player.channels[0].volume, 64);
+        render(&mut player, 1);
+        assert_eq!(player.channels[0].volume, 62);
+    }
+
+    #[test]
+    fn note_delay_and_cut() {
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'S', 0xD2), effect(b'S', 0xC3)]);
+        render(&mut player, 2);
+        assert!(player.channels[0].voice.is_none());
+        render(&mut player, 1);
+        assert!(player.channels[0].voice.is_some());
+
+        render(&mut player, 3 + 3);
+        assert!(player.channels[0].voice.is_some());
+        render(&mut player, 1);
+        assert!(player.channels[0].voice.is_none());
+    }
+
+    #[test]
+    fn sample_offset() {
+        // The offset of 256 samples is past the end of the sample.
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'O', 0x01)]);
+        render(&mut player, 1);
+        assert!(player.channels[0].voice.is_some());
+
+        module.flags |= ModuleFlags::OLD_EFFECTS;
+        let mut player = Player::new(module, PlayerOptions::default());
+        render(&mut player, 1);
+        assert!(player.channels[0].voice.is_none());
+    }
+
+    #[test]
+    fn tone_portamento() {
+        let mut module = module();
+        module.flags |= ModuleFlags::LINEAR_SLIDES;
+        let slide = Command { note: Some(NoteCmd::Play(Note::D_5)), ..effect(b'G', 0x04) };
+        let mut player = player(&mut module, &[Command::EMPTY, slide, effect(b'G', 0x00)]);
+
+        // The note slides by 16 units on the 5 ticks after the first one instead of restarting.
+        render(&mut player, 6 + 6);
+        assert!((frequency(&player) - 48000.0 * (80.0f64 / 768.0).exp2()).abs() < 1e-6);
+
+        // `G00` continues up to the 2 semitones of D-5 and stops there.
+        render(&mut player, 6);
+        assert!((frequency(&player) - 48000.0 * (2.0f64 / 12.0).exp2()).abs() < 1e-6);
+        assert!(player.channels[0].target_frequency.is_none());
+    }
+
+    #[test]
+    fn glissando() {
+        let mut module = module();
+        module.flags |= ModuleFlags::LINEAR_SLIDES;
+        let slide = Command { note: Some(NoteCmd::Play(Note::D_5)), ..effect(b'G', 0x04) };
+        let mut player = player(&mut module, &[effect(b'S', 0x11), slide]);
+        render(&mut player, 6 + 6);
+
+        // The slide is 1.25 semitones up, the voice plays the nearest semitone.
+        assert!((player.channels[0].frequency - 48000.0 * (1.25f64 / 12.0).exp2()).abs() < 1e-6);
+        assert!((frequency(&player) - 48000.0 * (1.0f64 / 12.0).exp2()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tremor() {
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'I', 0x21)]);
+
+        // 2 ticks on, 1 tick off.
+        let volumes = (0..6).map(|_| {
+            render(&mut player, 1);
+            volume(&player)
+        }).collect::<Vec<_>>();
+        assert_eq!(volumes, [1.0, 1.0, 0.0, 1.0, 1.0, 0.0]);
+        assert_eq!(player.channels[0].volume, 64);
+    }
+
+    #[test]
+    fn tremolo() {
+        let mut module = module();
+        let mut first = effect(b'R', 0x48);
+        first.volume = Some(VolumeCmd::SetVolume(RangedU8::new(32)));
+        let mut player = player(&mut module, &[first]);
+
+        // The sine reaches its top after 4 ticks of speed 4, adding 64 * 8 / 32 to the volume.
+        render(&mut player, 1);
+        assert_eq!(volume(&player), 0.5);
+        render(&mut player, 4);
+        assert_eq!(volume(&player), 0.75);
+        assert_eq!(player.channels[0].volume, 32);
+    }
+
+    #[test]
+    fn panbrello() {
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'Y', 0x48)]);
+
+        render(&mut player, 1);
+        assert_eq!(pan(&player), 0.5);
+        render(&mut player, 4);
+        assert_eq!(pan(&player), 0.75);
+        assert_eq!(player.channels[0].panning, ChannelPanning::Position(RangedU8::new(32)));
+    }
+
+    #[test]
+    fn vibrato_waveform() {
+        // The square starts at its top where the sine starts at zero.
+        let mut module = module();
+        module.flags |= ModuleFlags::LINEAR_SLIDES;
+        let mut player = player(&mut module, &[effect(b'S', 0x32), effect(b'H', 0x11)]);
+        render(&mut player, 6 + 1);
+        assert!((frequency(&player) - 48000.0 * (4.0f64 / 768.0).exp2()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tremolo_waveform() {
+        let mut module = module();
+        let mut first = effect(b'S', 0x42);
+        first.volume = Some(VolumeCmd::SetVolume(RangedU8::new(32)));
+        let mut player = player(&mut module, &[first, effect(b'R', 0x11)]);
+        render(&mut player, 6 + 1);
+        assert_eq!(volume(&player), 34.0 / 64.0);
+    }
+
+    #[test]
+    fn panbrello_waveform() {
+        let mut module = module();
+        let mut player = player(&mut module, &[effect(b'S', 0x52), effect(b'Y', 0x11)]);
+        render(&mut player, 6 + 1);
+        assert_eq!(pan(&player), 34.0 / 64.0);
+    }
+
+    #[test]
+    fn channel_volume() {
+        let mut module = module();
+        let mut player = player(&mut module, &[
+            effect(b'M', 0x20),
+            effect(b'N', 0x40),
+            effect(b'N', 0xF2),
+            effect(b'N', 0x00),
+        ]);
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].channel_volume, 32);
+        assert_eq!(volume(&player), 0.5);
+
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].channel_volume, 52);
+
+        // Fine slides apply on the first tick only, `N00` repeats them.
+        render(&mut player, 1);
+        assert_eq!(player.channels[0].channel_volume, 50);
+        render(&mut player, 5 + 6);
+        assert_eq!(player.channels[0].channel_volume, 48);
+        assert_eq!(volume(&player), 0.75);
+    }
+
+    #[test]
+    fn panning_slide() {
+        let mut module = module();
+        let mut player = player(&mut module, &[
+            effect(b'P', 0x04),
+            effect(b'P', 0x2F),
+            effect(b'P', 0x00),
+            effect(b'P', 0x0F),
+        ]);
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].panning, ChannelPanning::Position(RangedU8::new(52)));
+
+        render(&mut player, 1);
+        assert_eq!(player.channels[0].panning, ChannelPanning::Position(RangedU8::new(50)));
+        render(&mut player, 5 + 6);
+        assert_eq!(player.channels[0].panning, ChannelPanning::Position(RangedU8::new(48)));
+        assert_eq!(pan(&player), 0.75);
+
+        // The position stops at the right.
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].panning, ChannelPanning::Position(RangedU8::new(64)));
+    }
+
+    #[test]
+    fn high_sample_offset() {
+        // `SA1` adds 65536 samples to the offset of `O00`, past the end of the sample.
+        let mut module = module();
+        module.flags |= ModuleFlags::OLD_EFFECTS;
+        let second = Command { note: Some(NoteCmd::Play(Note::C_5)), ..effect(b'O', 0x00) };
+        let mut player = player(&mut module, &[effect(b'S', 0xA1), second]);
+        render(&mut player, 6);
+        assert_eq!(player.channels[0].offset_high, 1);
+        assert!(player.channels[0].voice.is_some());
+        render(&mut player, 1);
+        assert!(player.channels[0].voice.is_none());
+
+        module.patterns[0].rows[0] = Row::from_vec(vec![(Channel::new(1), Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: Some(InstrumentId::from_index(0).unwrap()),
+            ..Command::EMPTY
+        })]);
+        let mut player = Player::new(module, PlayerOptions::default());
+        render(&mut player, 6 + 1);
+        assert!(player.channels[0].voice.is_some());
+    }
+}
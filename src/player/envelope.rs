@@ -0,0 +1,110 @@
+//! Playback of instrument envelopes
+//!
+//! Envelope positions advance by one tick per player tick. While the note is held the position
+//! loops in the sustain loop, after a note-off in the envelope loop, see
+//! [`Envelope::value_at`] for the loop semantics.
+
+use crate::*;
+
+
+/// Envelope positions of the voice of a channel, returned by
+/// [`Player::envelopes`](super::Player::envelopes)
+///
+/// Disabled envelopes, including the ones turned off by `S77`..`S7C`, and notes played in
+/// sample mode have `None`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoiceEnvelopes {
+    pub volume: Option<EnvelopePosition>,
+    pub panning: Option<EnvelopePosition>,
+
+    /// Pitch envelope, or filter envelope if it has [`EnvelopeFlags::FILTER`]
+    pub pitch_filter: Option<EnvelopePosition>,
+}
+
+/// Position of a voice in an envelope
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct EnvelopePosition {
+    /// Position in ticks, comparable to [`Node::tick`]
+    pub tick: u32,
+
+    /// Value of the envelope at the position
+    pub value: f32,
+}
+
+/// Playback state of an envelope
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub(super) struct EnvelopeState {
+    /// Position in ticks
+    pub(super) position: u32,
+}
+
+impl EnvelopeState {
+    /// Returns the position and value of the envelope, `None` if it has no nodes.
+    ///
+    /// The envelope is played even if it's disabled, `S77`..`S7C` can turn it on for a note.
+    pub(super) fn value(self, envelope: &Envelope) -> Option<EnvelopePosition> {
+        let value = envelope.value_at_position(self.position)?;
+        Some(EnvelopePosition { tick: self.position, value })
+    }
+
+    /// Advances the position by a tick.
+    pub(super) fn advance(&mut self, envelope: &Envelope, released: bool) {
+        self.position = self.position.saturating_add(1);
+        if let Some((start, end)) = envelope.active_loop(released) {
+            if self.position > u32::from(end) {
+                self.position = u32::from(start);
+            }
+        }
+    }
+
+    /// Returns `true` once the position is on the last node and the envelope doesn't loop.
+    pub(super) fn ended(self, envelope: &Envelope, released: bool) -> bool {
+        envelope.active_loop(released).is_none()
+            && envelope.nodes.last().map_or(true, |last| self.position >= u32::from(last.tick))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sustain_and_loop() {
+        let envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::LOOP | EnvelopeFlags::SUSTAIN,
+            envelope_loop: Some(EnvelopeLoop { start: 0, end: 2 }),
+            sustain_loop: Some(EnvelopeLoop { start: 1, end: 1 }),
+            nodes: vec![
+                Node { value: 0, tick: 0 },
+                Node { value: 64, tick: 4 },
+                Node { value: 32, tick: 8 },
+            ],
+        };
+
+        // The held note stays on the sustain node.
+        let mut state = EnvelopeState::default();
+        for _ in 0..10 {
+            state.advance(&envelope, false);
+        }
+        assert_eq!(state.value(&envelope), Some(EnvelopePosition { tick: 4, value: 64.0 }));
+
+        // Released, the position continues from the sustain loop into the envelope loop.
+        for _ in 0..4 {
+            state.advance(&envelope, true);
+        }
+        assert_eq!(state.value(&envelope), Some(EnvelopePosition { tick: 8, value: 32.0 }));
+        state.advance(&envelope, true);
+        assert_eq!(state.position, 0);
+        assert!(!state.ended(&envelope, true));
+
+        let mut envelope = envelope;
+        envelope.flags = EnvelopeFlags::ENABLED;
+        state.position = 8;
+        assert!(state.ended(&envelope, true));
+        envelope.nodes.clear();
+        assert_eq!(state.value(&envelope), None);
+    }
+}
@@ -0,0 +1,74 @@
+//! Events of the playback for synchronizing with the audio
+
+use crate::analysis::Position;
+use crate::*;
+use std::ops::RangeInclusive;
+
+
+/// Something which happened during the playback, returned by
+/// [`Player::drain_events`](super::Player::drain_events)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlayerEvent {
+    /// Frame the event happens at, counted from the start of the song or the last seek
+    pub frame: u64,
+
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// The song moved to another order, followed by the [`EventKind::Row`] of the first row
+    Order(OrderIdx),
+
+    /// A row started
+    Row(Position),
+
+    /// A note started in a channel, on the tick of its note delay for delayed notes
+    ///
+    /// Notes played with a tone portamento slide the playing note and don't start a new one.
+    Note {
+        channel: Channel,
+        note: Note,
+
+        /// Instrument of the note, the sample in sample mode
+        instrument: Option<InstrumentId>,
+    },
+
+    /// The speed (ticks per row) or the tempo changed
+    Tempo { speed: u32, tempo: u32 },
+
+    /// `Zxx` sent a MIDI macro which isn't one of the filter messages the player interprets,
+    /// e.g. for custom macro handlers
+    Midi { channel: Channel, message: MidiMessage },
+
+    /// A cell matched the sync marker with the index `marker` in
+    /// [`PlayerOptions::sync_markers`](super::PlayerOptions::sync_markers)
+    Sync {
+        marker: usize,
+        channel: Channel,
+        command: Command,
+    },
+}
+
+/// Cells which produce an [`EventKind::Sync`] when they're played, for syncing demo effects to
+/// the music
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncMarker {
+    /// `Zxx` commands with a parameter in the range
+    ///
+    /// The default MIDI macros only use `Z00`..`Z8F`, `Z90`..`ZFF` have no effect on the audio.
+    Midi(RangeInclusive<u8>),
+
+    /// The cell of `channel` at `position`, every time the row is played
+    Cell { position: Position, channel: Channel },
+}
+
+impl SyncMarker {
+    /// Returns `true` if the marker matches the cell of `channel` at `position`.
+    pub(super) fn matches(&self, position: Position, channel: Channel, command: &Command) -> bool {
+        match self {
+            SyncMarker::Midi(range) => matches!(command.effect, Some(EffectCmd::Midi(xx)) if range.contains(&xx)),
+            SyncMarker::Cell { position: cell, channel: cell_channel } => *cell == position && *cell_channel == channel,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Resonant low-pass filter of Impulse Tracker
+//!
+//! The coefficients are computed with the formulas of Impulse Tracker's filter mixer as
+//! reproduced by OpenMPT (`CSoundFile::SetupChannelFilter`), a 2-pole IIR filter with the cutoff
+//! frequency `110 * 2^(0.25 + cutoff / 48)` Hz and up to 24 dB of resonance.
+
+use std::f64::consts::PI;
+
+
+/// Highest cutoff, the filter is disabled at this cutoff without resonance
+pub(super) const MAX_CUTOFF: u8 = 127;
+
+/// Filter state of a voice
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub(super) struct Filter {
+    /// `[a0, b0, b1]` of `y[n] = a0 * x[n] + b0 * y[n-1] + b1 * y[n-2]`, `None` when disabled
+    coefficients: Option<[f64; 3]>,
+
+    /// `[y[n-1], y[n-2]]`
+    history: [f64; 2],
+}
+
+impl Filter {
+    /// Sets the cutoff (0..=127) and resonance (0..=127) of the filter
+    ///
+    /// The filter keeps its history so the parameters can change while a sample is playing.
+    pub(super) fn set(&mut self, cutoff: u8, resonance: u8, sample_rate: u32) {
+        if cutoff >= MAX_CUTOFF && resonance == 0 {
+            self.coefficients = None;
+            self.history = [0.0; 2];
+            return;
+        }
+
+        let frequency = 110.0 * (0.25 + f64::from(cutoff.min(MAX_CUTOFF)) / 48.0).exp2();
+        let frequency = frequency.clamp(120.0, 20000.0).min(f64::from(sample_rate) / 2.0);
+        let damping = 10.0f64.powf(-f64::from(resonance.min(127)) * (24.0 / 128.0) / 20.0);
+
+        let r = f64::from(sample_rate) / (2.0 * PI * frequency);
+        let d = damping * r + damping - 1.0;
+        let e = r * r;
+        let scale = 1.0 + d + e;
+        self.coefficients = Some([1.0 / scale, (d + e + e) / scale, -e / scale]);
+    }
+
+    /// Filters the next sample.
+    pub(super) fn process(&mut self, x: f32) -> f32 {
+        let Some([a0, b0, b1]) = self.coefficients else {
+            return x;
+        };
+        let y = a0 * f64::from(x) + b0 * self.history[0] + b1 * self.history[1];
+        self.history = [y, self.history[0]];
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+        let y = y as f32;
+        y
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn low_pass() {
+        let mut filter = Filter::default();
+        filter.set(MAX_CUTOFF, 0, 48000);
+        assert_eq!(filter.process(0.5), 0.5);
+
+        // The filter passes DC, but a low cutoff makes it rise slowly.
+        filter.set(0, 0, 48000);
+        let output = (0..48000).map(|_| filter.process(1.0)).collect::<Vec<_>>();
+        assert!(output[0] < 0.01);
+        assert!((output[47999] - 1.0).abs() < 1e-3);
+
+        // Resonance makes the step response overshoot.
+        let mut filter = Filter::default();
+        filter.set(64, 127, 48000);
+        let peak = (0..4800).map(|_| filter.process(1.0)).fold(0.0f32, f32::max);
+        assert!(peak > 1.5);
+    }
+}
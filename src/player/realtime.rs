@@ -0,0 +1,142 @@
+//! Playback from realtime audio callbacks
+
+use super::Player;
+use crate::*;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+
+/// Change sent to a [`RealtimePlayer`] by its [`Controller`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControlMessage {
+    /// See [`Player::set_channel_muted`]
+    SetChannelMuted(Channel, bool),
+
+    /// See [`Player::set_channel_solo`]
+    SetChannelSolo(Channel, bool),
+
+    /// See [`Player::set_tempo_scale`]
+    SetTempoScale(f64),
+
+    /// See [`Player::set_transpose`]
+    SetTranspose(f64),
+
+    /// See [`Player::stop`]
+    Stop,
+}
+
+/// [`Player`] controlled by [`ControlMessage`]s, created by [`realtime`]
+#[derive(Debug)]
+pub struct RealtimePlayer {
+    player: Player,
+    receiver: Receiver<ControlMessage>,
+}
+
+/// Sends [`ControlMessage`]s to a [`RealtimePlayer`] from other threads
+#[derive(Clone, Debug)]
+pub struct Controller {
+    sender: SyncSender<ControlMessage>,
+}
+
+/// Splits `player` into a realtime player and its controller, up to `capacity` messages can be
+/// waiting for the next render call.
+///
+/// Audio callbacks (cpal, JACK...) run on a high priority thread which must not wait for other
+/// threads, so they can't lock a `Mutex<Player>` shared with the UI. The [`RealtimePlayer`] is
+/// moved into the callback and the [`Controller`] kept by the rest of the application. The
+/// controller sends [`ControlMessage`]s through a bounded channel whose buffer is allocated up
+/// front, the realtime player applies them at the start of every render call without blocking.
+///
+/// Rendering doesn't allocate, lock or do I/O. Seeking restarts the song, which allocates, so
+/// it's not offered as a message.
+///
+/// The `itplay` example is a complete player built this way.
+///
+/// ```ignore
+/// // With cpal.
+/// let (mut player, controller) = ittech::player::realtime(Player::new(module, options), 64);
+/// let stream = device.build_output_stream(
+///     &config,
+///     move |out: &mut [f32], _| {
+///         let frames = player.render_f32(out);
+///         out[frames * 2..].fill(0.0);
+///     },
+///     |error| eprintln!("{error}"),
+///     None,
+/// )?;
+/// stream.play()?;
+/// controller.send(ControlMessage::SetChannelMuted(Channel::new(1), true));
+/// ```
+///
+/// # Panics
+///
+/// Panics if `capacity` is zero.
+pub fn realtime(player: Player, capacity: usize) -> (RealtimePlayer, Controller) {
+    assert!(capacity > 0, "capacity must not be zero");
+    let (sender, receiver) = mpsc::sync_channel(capacity);
+    (RealtimePlayer { player, receiver }, Controller { sender })
+}
+
+impl RealtimePlayer {
+    pub fn player(&self) -> &Player {
+        &self.player
+    }
+
+    /// Applies the waiting messages and renders like [`Player::render_f32`].
+    pub fn render_f32(&mut self, out: &mut [f32]) -> usize {
+        self.receive();
+        self.player.render_f32(out)
+    }
+
+    /// Applies the waiting messages and renders like [`Player::render_i16`].
+    pub fn render_i16(&mut self, out: &mut [i16]) -> usize {
+        self.receive();
+        self.player.render_i16(out)
+    }
+
+    fn receive(&mut self) {
+        while let Ok(message) = self.receiver.try_recv() {
+            match message {
+                ControlMessage::SetChannelMuted(channel, muted) => self.player.set_channel_muted(channel, muted),
+                ControlMessage::SetChannelSolo(channel, solo) => self.player.set_channel_solo(channel, solo),
+                ControlMessage::SetTempoScale(scale) => self.player.set_tempo_scale(scale),
+                ControlMessage::SetTranspose(semitones) => self.player.set_transpose(semitones),
+                ControlMessage::Stop => self.player.stop(),
+            }
+        }
+    }
+}
+
+impl Controller {
+    /// Sends `message` without blocking, returns `false` if the channel is full or the realtime
+    /// player was dropped.
+    pub fn send(&self, message: ControlMessage) -> bool {
+        self.sender.try_send(message).is_ok()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::test::module;
+    use super::super::PlayerOptions;
+
+    #[test]
+    fn messages() {
+        let (mut player, controller) = realtime(Player::new(module(), PlayerOptions::default()), 2);
+        assert!(controller.send(ControlMessage::SetChannelMuted(Channel::new(1), true)));
+        assert!(controller.send(ControlMessage::SetChannelSolo(Channel::new(2), true)));
+        assert!(!controller.send(ControlMessage::Stop));
+
+        let mut out = [1.0f32; 960 * 2];
+        assert_eq!(player.render_f32(&mut out), 960);
+        assert!(out.iter().all(|&value| value == 0.0));
+        assert!(!player.player().is_channel_audible(Channel::new(1)));
+
+        assert!(controller.send(ControlMessage::Stop));
+        assert_eq!(player.render_f32(&mut out), 0);
+        assert!(player.player().is_finished());
+        drop(player);
+        assert!(!controller.send(ControlMessage::Stop));
+    }
+}
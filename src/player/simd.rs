@@ -0,0 +1,121 @@
+//! Vectorized loops of the mixer
+//!
+//! The loops use SSE2 on x86 and NEON on AArch64 when the target enables them, which is the
+//! default for `x86_64` and `aarch64` targets, and plain scalar code elsewhere.
+
+#[cfg(all(target_arch = "x86", target_feature = "sse2"))]
+use std::arch::x86::*;
+#[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+use std::arch::x86_64::*;
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+use std::arch::aarch64::*;
+
+
+/// Returns the sum of the products of `values` and `weights`, the 8 taps of the sinc
+/// interpolation.
+#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"))]
+pub(super) fn dot(values: &[f32; 8], weights: &[f32; 8]) -> f32 {
+    // SAFETY: SSE2 is enabled at compile time and every load reads 4 floats of an array of 8.
+    unsafe {
+        let low = _mm_mul_ps(_mm_loadu_ps(values.as_ptr()), _mm_loadu_ps(weights.as_ptr()));
+        let high = _mm_mul_ps(_mm_loadu_ps(values.as_ptr().add(4)), _mm_loadu_ps(weights.as_ptr().add(4)));
+        let sum = _mm_add_ps(low, high);
+        let sum = _mm_add_ps(sum, _mm_movehl_ps(sum, sum));
+        _mm_cvtss_f32(_mm_add_ss(sum, _mm_shuffle_ps(sum, sum, 1)))
+    }
+}
+
+/// Returns the sum of the products of `values` and `weights`, the 8 taps of the sinc
+/// interpolation.
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+pub(super) fn dot(values: &[f32; 8], weights: &[f32; 8]) -> f32 {
+    // SAFETY: NEON is enabled at compile time and every load reads 4 floats of an array of 8.
+    unsafe {
+        let low = vmulq_f32(vld1q_f32(values.as_ptr()), vld1q_f32(weights.as_ptr()));
+        let high = vmulq_f32(vld1q_f32(values.as_ptr().add(4)), vld1q_f32(weights.as_ptr().add(4)));
+        vaddvq_f32(vaddq_f32(low, high))
+    }
+}
+
+#[cfg(not(any(
+    all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"),
+    all(target_arch = "aarch64", target_feature = "neon"),
+)))]
+pub(super) use self::dot_scalar as dot;
+
+#[cfg(any(
+    test,
+    not(any(
+        all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"),
+        all(target_arch = "aarch64", target_feature = "neon"),
+    )),
+))]
+pub(super) fn dot_scalar(values: &[f32; 8], weights: &[f32; 8]) -> f32 {
+    // Summed in the same order as the SSE2 path.
+    let sums: [f32; 4] = std::array::from_fn(|lane| values[lane] * weights[lane] + values[lane + 4] * weights[lane + 4]);
+    (sums[0] + sums[2]) + (sums[1] + sums[3])
+}
+
+/// Adds `input` to `out` sample by sample.
+///
+/// # Panics
+///
+/// Panics if the slices have different lengths.
+pub(super) fn add(out: &mut [f32], input: &[f32]) {
+    assert_eq!(out.len(), input.len(), "slices of different lengths");
+    let mut out = out.chunks_exact_mut(4);
+    let mut input = input.chunks_exact(4);
+    for (out, input) in (&mut out).zip(&mut input) {
+        add4(out, input);
+    }
+    for (value, input) in out.into_remainder().iter_mut().zip(input.remainder()) {
+        *value += input;
+    }
+}
+
+#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"))]
+fn add4(out: &mut [f32], input: &[f32]) {
+    debug_assert!(out.len() == 4 && input.len() == 4);
+    // SAFETY: SSE2 is enabled at compile time and both slices hold 4 floats.
+    unsafe {
+        let sum = _mm_add_ps(_mm_loadu_ps(out.as_ptr()), _mm_loadu_ps(input.as_ptr()));
+        _mm_storeu_ps(out.as_mut_ptr(), sum);
+    }
+}
+
+#[cfg(all(target_arch = "aarch64", target_feature = "neon"))]
+fn add4(out: &mut [f32], input: &[f32]) {
+    debug_assert!(out.len() == 4 && input.len() == 4);
+    // SAFETY: NEON is enabled at compile time and both slices hold 4 floats.
+    unsafe {
+        let sum = vaddq_f32(vld1q_f32(out.as_ptr()), vld1q_f32(input.as_ptr()));
+        vst1q_f32(out.as_mut_ptr(), sum);
+    }
+}
+
+#[cfg(not(any(
+    all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"),
+    all(target_arch = "aarch64", target_feature = "neon"),
+)))]
+fn add4(out: &mut [f32], input: &[f32]) {
+    out.iter_mut().zip(input).for_each(|(value, input)| *value += input);
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vector_paths() {
+        let values = [0.5, -0.25, 1.0, 0.125, -1.0, 0.75, 0.0, 0.375];
+        let weights = [-0.01, 0.03, -0.12, 0.87, 0.23, -0.08, 0.02, -0.005];
+        assert!((dot(&values, &weights) - dot_scalar(&values, &weights)).abs() < 1e-6);
+        assert!((dot(&values, &weights) + 0.315625).abs() < 1e-6);
+
+        // The slices are longer than a vector and not a multiple of its length.
+        let mut out: Vec<f32> = (0..11u8).map(f32::from).collect();
+        add(&mut out, &[0.5; 11]);
+        assert_eq!(out, (0..11u8).map(|value| f32::from(value) + 0.5).collect::<Vec<_>>());
+    }
+}
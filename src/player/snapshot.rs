@@ -0,0 +1,189 @@
+//! Saving and restoring the playback state
+
+use super::channel::ChannelState;
+use super::voice::Voice;
+use super::{Globals, Player};
+use crate::analysis::PatternLoop;
+use crate::OrderIdx;
+use std::collections::HashSet;
+
+
+/// Playback state of a [`Player`], returned by [`Player::snapshot`]
+///
+/// The snapshot holds everything that changes while playing: the position and tick, the speed,
+/// tempo and global volume, the pattern loops, the channels with their effect memory and random
+/// waveforms, the voices with their sample and envelope positions, the repetitions of the song
+/// and the channels muted and soloed at runtime. The module, the options and the sample data are
+/// not part of it, with the `serde` feature it serializes in a few kilobytes.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct PlayerSnapshot {
+    position: Option<(OrderIdx, usize)>,
+    tick: u32,
+    row_ticks: u32,
+    speed: u32,
+    tempo: u32,
+    global_volume: u8,
+    jump_order: Option<OrderIdx>,
+    break_row: Option<usize>,
+    loop_row: Option<usize>,
+    loops: Vec<PatternLoop>,
+    channels: Vec<ChannelState>,
+    background: Vec<Voice>,
+    frames_left: usize,
+    tick_remainder: u64,
+    visited: Vec<(OrderIdx, usize, u32, u32, u8)>,
+    repetitions: u32,
+    fade: Option<(usize, usize)>,
+    muted: Vec<bool>,
+    solo: Vec<bool>,
+    frame: u64,
+}
+
+impl Player {
+    /// Returns the playback state, which [`Player::restore`] continues from
+    ///
+    /// Rendering after restoring the snapshot produces exactly the same audio as rendering after
+    /// taking it. The peak levels and the events not drained yet aren't saved.
+    pub fn snapshot(&self) -> PlayerSnapshot {
+        PlayerSnapshot {
+            position: self.position,
+            tick: self.tick,
+            row_ticks: self.row_ticks,
+            speed: self.speed,
+            tempo: self.globals.tempo,
+            global_volume: self.globals.global_volume,
+            jump_order: self.jump_order,
+            break_row: self.break_row,
+            loop_row: self.loop_row,
+            loops: self.loops.to_vec(),
+            channels: self.channels.to_vec(),
+            background: self.background.clone(),
+            frames_left: self.frames_left,
+            tick_remainder: self.tick_remainder,
+            visited: self.visited.iter().copied().collect(),
+            repetitions: self.repetitions,
+            fade: self.fade,
+            muted: self.muted.to_vec(),
+            solo: self.solo.to_vec(),
+            frame: self.frame,
+        }
+    }
+
+    /// Continues playing from `snapshot`
+    ///
+    /// The snapshot can come from another player of the same module, e.g. loaded from a saved
+    /// game, the options of this player stay in effect. Pending events are cleared like by
+    /// [`Player::seek`]. Returns `false` and leaves the player unchanged if the snapshot doesn't
+    /// fit the module: the position is outside of the song or a playing sample is missing or
+    /// shorter.
+    pub fn restore(&mut self, snapshot: &PlayerSnapshot) -> bool {
+        let snapshot = snapshot.clone();
+        if let Some((order, row)) = snapshot.position {
+            if self.module.next_order(order.as_usize()) != Some(order) || row >= self.rows(order) {
+                return false;
+            }
+        }
+        let (Ok(loops), Ok(mut channels), Ok(muted), Ok(solo)) = (
+            <[PatternLoop; 64]>::try_from(snapshot.loops),
+            <[ChannelState; 64]>::try_from(snapshot.channels),
+            <[bool; 64]>::try_from(snapshot.muted),
+            <[bool; 64]>::try_from(snapshot.solo),
+        ) else {
+            return false;
+        };
+        let mut background = snapshot.background;
+        let attached = channels.iter_mut().all(|channel| channel.attach(&self.module, &self.options))
+            && background.iter_mut().all(|voice| {
+                voice.ramping = self.options.volume_ramping;
+                voice.declick = self.options.declick;
+                voice.attach(&self.module)
+            });
+        if !attached {
+            return false;
+        }
+
+        self.position = snapshot.position;
+        self.tick = snapshot.tick;
+        self.row_ticks = snapshot.row_ticks;
+        self.speed = snapshot.speed;
+        self.globals = Globals { tempo: snapshot.tempo, global_volume: snapshot.global_volume };
+        self.jump_order = snapshot.jump_order;
+        self.break_row = snapshot.break_row;
+        self.loop_row = snapshot.loop_row;
+        self.loops = loops;
+        self.channels = channels;
+        self.background = background;
+        self.frames_left = snapshot.frames_left;
+        self.tick_remainder = snapshot.tick_remainder;
+        self.visited = snapshot.visited.into_iter().collect::<HashSet<_>>();
+        self.repetitions = snapshot.repetitions;
+        self.fade = snapshot.fade;
+        self.peaks = [[0.0; 2]; 64];
+        self.muted = muted;
+        self.solo = solo;
+        self.frame = snapshot.frame;
+        self.events.clear();
+        self.event_order = None;
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::test::module;
+    use super::super::PlayerOptions;
+    use crate::analysis::Position;
+    use crate::*;
+
+    #[test]
+    fn restore() {
+        let mut module = module();
+        module.patterns[0].rows[1] = Row::from_vec(vec![(Channel::new(1), Command {
+            effect: parser::effect(b'H' - b'@', 0x48),
+            ..Command::EMPTY
+        })]);
+
+        let mut player = Player::new(module.clone(), PlayerOptions::default());
+        let mut out = vec![0.0f32; 7000 * 2];
+        player.render_f32(&mut out);
+        let snapshot = player.snapshot();
+        let mut expected = vec![0.0f32; 20000 * 2];
+        assert_eq!(player.render_f32(&mut expected), 23040 - 7000);
+
+        // Another player of the module continues exactly where the snapshot was taken.
+        let mut restored = Player::new(module.clone(), PlayerOptions::default());
+        assert!(restored.restore(&snapshot));
+        assert_eq!(restored.position(), Some(Position { order: OrderIdx::new(0), row: 1 }));
+        let mut rendered = vec![0.0f32; 20000 * 2];
+        assert_eq!(restored.render_f32(&mut rendered), 23040 - 7000);
+        assert_eq!(rendered, expected);
+
+        // The playing sample is missing.
+        module.samples[0].data = None;
+        let mut other = Player::new(module, PlayerOptions::default());
+        assert!(!other.restore(&snapshot));
+        assert_eq!(other.position(), Some(Position { order: OrderIdx::new(0), row: 0 }));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize() {
+        let mut player = Player::new(module(), PlayerOptions::default());
+        let mut out = vec![0.0f32; 7000 * 2];
+        player.render_f32(&mut out);
+        let json = serde_json::to_string(&player.snapshot()).unwrap();
+        let snapshot = serde_json::from_str::<PlayerSnapshot>(&json).unwrap();
+
+        let mut restored = Player::new(module(), PlayerOptions::default());
+        assert!(restored.restore(&snapshot));
+        assert_eq!(restored.channel_status(Channel::new(1)).note, Some(Note::C_5));
+        let mut expected = vec![0.0f32; 20000 * 2];
+        assert_eq!(player.render_f32(&mut expected), 23040 - 7000);
+        let mut rendered = vec![0.0f32; 20000 * 2];
+        assert_eq!(restored.render_f32(&mut rendered), 23040 - 7000);
+        assert!(rendered.iter().zip(&expected).all(|(value, expected)| (value - expected).abs() < 1e-6));
+    }
+}
@@ -0,0 +1,93 @@
+//! Playback with rodio
+
+use super::{Player, PlayerOptions};
+use crate::*;
+use rodio::Source;
+use std::sync::Arc;
+use std::time::Duration;
+
+
+/// [`Player`] as a [`rodio::Source`] of interleaved stereo samples
+///
+/// ```no_run
+/// # fn play(module: ittech::Module, sink: &rodio::Sink) {
+/// sink.append(ittech::player::ItSource::new(module));
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ItSource {
+    player: Player,
+
+    /// Right sample of the frame whose left sample was returned last
+    right: Option<f32>,
+}
+
+impl ItSource {
+    /// Plays `module` with the default [`PlayerOptions`]
+    pub fn new(module: impl Into<Arc<Module>>) -> ItSource {
+        ItSource::from(Player::new(module, PlayerOptions::default()))
+    }
+
+    pub fn player(&self) -> &Player {
+        &self.player
+    }
+
+    /// Returns the player, e.g. to seek or mute channels while playing.
+    pub fn player_mut(&mut self) -> &mut Player {
+        &mut self.player
+    }
+}
+
+impl From<Player> for ItSource {
+    fn from(player: Player) -> ItSource {
+        ItSource { player, right: None }
+    }
+}
+
+impl Iterator for ItSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let [left, right] = self.player.next()?;
+        self.right = Some(right);
+        Some(left)
+    }
+}
+
+impl Source for ItSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.player.options().sample_rate
+    }
+
+    /// The length isn't known in advance, songs can repeat and jump.
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::test::module;
+
+    #[test]
+    fn interleaved() {
+        let source = ItSource::new(module());
+        assert_eq!((source.channels(), source.sample_rate()), (2, 48000));
+        let samples = source.collect::<Vec<_>>();
+        assert_eq!(samples.len(), 23040 * 2);
+        assert!(samples.iter().all(|&sample| sample == 0.25));
+    }
+}
@@ -0,0 +1,563 @@
+//! Playback of a single sample
+
+use super::envelope::{EnvelopePosition, EnvelopeState, VoiceEnvelopes};
+use super::filter::{self, Filter};
+use super::simd;
+use super::Interpolation;
+use crate::convert::round_clamp;
+use crate::*;
+use std::array;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+
+
+/// Number of samples on each side of the position used by [`Interpolation::Sinc`]
+const SINC_TAPS: i8 = 4;
+
+/// Volume of a note before it starts fading out, see [`Voice::fade`]
+const FADE_VOLUME: u16 = 1024;
+
+/// Length in microseconds of the volume ramps raising and lowering the gains, the defaults of
+/// OpenMPT
+const RAMP_UP_MICROS: f64 = 363.0;
+const RAMP_DOWN_MICROS: f64 = 952.0;
+
+/// Pattern channel and note which started a voice
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub(super) struct Origin {
+    /// Index of the pattern channel
+    pub(super) channel: usize,
+
+    /// Instrument, `None` in sample mode
+    pub(super) instrument: Option<InstrumentId>,
+
+    pub(super) sample: SampleId,
+
+    /// Note as written in the pattern, before the note translation of the instrument
+    pub(super) note: Note,
+}
+
+/// Sample being played
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub(super) struct Voice {
+    pub(super) origin: Origin,
+
+    /// Sample data, left out of snapshots and set again by [`Voice::attach`]
+    #[cfg_attr(feature = "serde", serde(skip, default = "no_data"))]
+    data: SampleData,
+    loop_: Option<SampleLoop>,
+
+    /// Sustain loop, `None` once the note is released
+    sustain_loop: Option<SampleLoop>,
+
+    /// Position in samples
+    position: f64,
+
+    /// Playing forward, only changes in bidirectional loops
+    forward: bool,
+
+    /// Playback frequency in Hz, before the pitch envelope
+    pub(super) frequency: f64,
+
+    /// Volume (0..=1) and panning (0..=1, 0.5 is the centre) set by the channel every tick,
+    /// before the envelopes
+    pub(super) volume: f32,
+    pub(super) pan: f32,
+
+    /// Stereo separation (0..=2) applied to the panning
+    pub(super) separation: f32,
+
+    /// Played from the centre with the right side phase-inverted, ignoring the panning
+    pub(super) surround: bool,
+
+    /// Filter cutoff and resonance (0..=127) set by the channel, before the filter envelope
+    pub(super) cutoff: u8,
+    pub(super) resonance: u8,
+
+    /// Note has been released, the envelopes leave their sustain loops
+    released: bool,
+
+    /// Volume, panning and pitch (or filter) envelopes of the instrument
+    pub(super) envelopes: [EnvelopeState; 3],
+
+    /// Envelopes turned on or off for this note by `S77`..`S7C`, `None` uses
+    /// [`EnvelopeFlags::ENABLED`]
+    pub(super) envelope_enabled: [Option<bool>; 3],
+
+    /// Envelope positions and values of the current tick
+    envelope_values: [Option<EnvelopePosition>; 3],
+
+    /// Gains and frequency of the current tick with the envelopes and the fadeout applied
+    left: f32,
+    right: f32,
+    playback_frequency: f64,
+
+    /// Resonant filter applied before the gains
+    filter: Filter,
+
+    /// Ramps the gains to avoid clicks, set by the channel
+    pub(super) ramping: bool,
+
+    /// Length in seconds of the fades of cuts and sample offsets without ramping, set by the
+    /// channel, and a fade-in pending or in progress
+    pub(super) declick: f64,
+    fade_in: bool,
+
+    /// Gains of the last mixed frame, the gains the ramp goes to, and the frames left and the
+    /// change of the gains per frame of the ramp
+    gains: [f32; 2],
+    ramp_target: [f32; 2],
+    ramp: (u32, [f32; 2]),
+
+    /// Cut while ramping, the voice ends once it has ramped down to silence
+    cut: bool,
+
+    /// Fade volume subtracted every tick while fading out
+    pub(super) fadeout: u16,
+
+    /// Fade volume from [`FADE_VOLUME`] to 0, `None` until the voice starts fading out
+    fade_volume: Option<u16>,
+}
+
+impl Voice {
+    /// Starts playing `sample` at `frequency`, `None` if the sample has no data.
+    pub(super) fn new(sample: &Sample, origin: Origin, frequency: f64) -> Option<Voice> {
+        let data = sample.decoded_data().filter(|data| !data.is_empty())?.clone();
+        Some(Voice {
+            origin,
+            data,
+            loop_: sample.loop_,
+            sustain_loop: sample.sustain_loop,
+            position: 0.0,
+            forward: true,
+            frequency,
+            volume: 0.0,
+            pan: 0.5,
+            separation: 1.0,
+            surround: false,
+            cutoff: filter::MAX_CUTOFF,
+            resonance: 0,
+            released: false,
+            envelopes: [EnvelopeState::default(); 3],
+            envelope_enabled: [None; 3],
+            envelope_values: [None; 3],
+            left: 0.0,
+            right: 0.0,
+            playback_frequency: frequency,
+            filter: Filter::default(),
+            ramping: false,
+            declick: 0.0,
+            fade_in: false,
+            gains: [0.0; 2],
+            ramp_target: [0.0; 2],
+            ramp: (0, [0.0; 2]),
+            cut: false,
+            fadeout: 0,
+            fade_volume: None,
+        })
+    }
+
+    /// Sets the sample data of a voice restored from a snapshot, returns `false` if `module`
+    /// doesn't have the sample or the sample is shorter than the position and loops of the voice.
+    pub(super) fn attach(&mut self, module: &Module) -> bool {
+        let Some(data) = module.get(self.origin.sample).and_then(|sample| sample.decoded_data().cloned()) else {
+            return false;
+        };
+        self.data = data;
+        let length = self.length();
+        self.position < f64::from(length)
+            && [self.loop_, self.sustain_loop].into_iter().flatten().all(|loop_| loop_.start < loop_.end && loop_.end <= length)
+    }
+
+    /// Releases the note (note-off)
+    ///
+    /// The sample leaves its sustain loop and continues to the normal loop or to its end, the
+    /// envelopes leave their sustain loops. Impulse Tracker fades out released notes of
+    /// instruments without a volume envelope, or with a looping one.
+    pub(super) fn release(&mut self) {
+        self.sustain_loop = None;
+        self.released = true;
+    }
+
+    /// Continues the `previous` envelopes which have [`EnvelopeFlags::CARRY`] in `instrument`.
+    pub(super) fn carry(&mut self, previous: [EnvelopeState; 3], instrument: &Instrument) {
+        let envelopes = [&instrument.volume_envelope, &instrument.panning_envelope, &instrument.pitch_filter_envelope];
+        for ((state, previous), envelope) in self.envelopes.iter_mut().zip(previous).zip(envelopes) {
+            if envelope.flags.contains(EnvelopeFlags::CARRY) {
+                *state = previous;
+            }
+        }
+    }
+
+    /// Returns the envelope positions of the current tick.
+    pub(super) fn envelopes(&self) -> VoiceEnvelopes {
+        let [volume, panning, pitch_filter] = self.envelope_values;
+        VoiceEnvelopes { volume, panning, pitch_filter }
+    }
+
+    /// Restarts the sample from its start.
+    pub(super) fn retrigger(&mut self, sample: &Sample) {
+        self.position = 0.0;
+        self.forward = true;
+        self.sustain_loop = sample.sustain_loop;
+    }
+
+    /// Moves the position to `offset` samples, returns `false` if it's past the end of the data.
+    ///
+    /// Starting in the middle of the sample fades the voice in when declicking.
+    pub(super) fn set_offset(&mut self, offset: u32) -> bool {
+        if offset >= self.length() {
+            return false;
+        }
+        self.position = f64::from(offset);
+        self.fade_in = offset > 0 && self.declick > 0.0;
+        true
+    }
+
+    /// Sets the playing direction, samples played backwards from their start play from the end.
+    pub(super) fn set_direction(&mut self, forward: bool) {
+        if !forward && self.position == 0.0 {
+            self.position = f64::from(self.length() - 1);
+        }
+        self.forward = forward;
+    }
+
+    /// Cuts the note, returns `true` if the voice has to keep playing to ramp down to silence.
+    pub(super) fn cut(&mut self) -> bool {
+        self.cut = true;
+        self.ramping || self.declick > 0.0
+    }
+
+    /// Starts fading out the voice by [`Voice::fadeout`] every tick.
+    pub(super) fn fade(&mut self) {
+        self.fade_volume.get_or_insert(FADE_VOLUME);
+    }
+
+    /// Applies the envelopes, the fadeout and the filter for the current tick and advances the
+    /// envelopes, returns `false` when the voice has ended.
+    pub(super) fn update(&mut self, module: &Module, sample_rate: u32) -> bool {
+        let instrument = self.origin.instrument.and_then(|instrument| module.get(instrument));
+        let envelopes = instrument.map(|instrument| {
+            [&instrument.volume_envelope, &instrument.panning_envelope, &instrument.pitch_filter_envelope]
+        });
+        let enabled = |idx: usize, envelope: &Envelope| {
+            self.envelope_enabled[idx].unwrap_or_else(|| envelope.flags.contains(EnvelopeFlags::ENABLED))
+        };
+        for (idx, value) in self.envelope_values.iter_mut().enumerate() {
+            *value = envelopes
+                .map(|envelopes| envelopes[idx])
+                .filter(|envelope| enabled(idx, envelope))
+                .and_then(|envelope| self.envelopes[idx].value(envelope));
+        }
+
+        let volume_envelope = envelopes
+            .map(|[volume, _, _]| volume)
+            .filter(|envelope| enabled(0, envelope) && !envelope.nodes.is_empty());
+        match volume_envelope {
+            Some(envelope) if self.envelopes[0].ended(envelope, self.released) => {
+                // A volume envelope ending at zero ends the note, released notes fade out at the
+                // end of the envelope.
+                if envelope.nodes.last().is_some_and(|last| last.value == 0) {
+                    return false;
+                }
+                if self.released {
+                    self.fade();
+                }
+            }
+            Some(envelope) if self.released && envelope.flags.contains(EnvelopeFlags::LOOP) => self.fade(),
+            None if self.released => self.fade(),
+            _ => {}
+        }
+        if !self.update_fade() {
+            return false;
+        }
+
+        let [volume, panning, pitch_filter] = self.envelope_values.map(|value| value.map(|position| position.value));
+        let fade = f32::from(self.fade_volume.unwrap_or(FADE_VOLUME)) / f32::from(FADE_VOLUME);
+        let volume = self.volume * fade * volume.map_or(1.0, |volume| volume / 64.0);
+
+        // The panning envelope moves the panning at most to the nearest side.
+        let pan = panning.map_or(self.pan, |panning| {
+            (self.pan + panning / 32.0 * (0.5 - (self.pan - 0.5).abs())).clamp(0.0, 1.0)
+        });
+        if self.surround {
+            self.left = volume * 0.5;
+            self.right = -self.left;
+        } else {
+            let pan = (0.5 + (pan - 0.5) * self.separation).clamp(0.0, 1.0);
+            self.left = volume * (1.0 - pan);
+            self.right = volume * pan;
+        }
+
+        // The pitch envelope slides up to 16 semitones, the filter envelope scales the cutoff by
+        // up to 2 times.
+        let filter_envelope = envelopes.is_some_and(|[_, _, envelope]| envelope.flags.contains(EnvelopeFlags::FILTER));
+        let mut cutoff = self.cutoff;
+        self.playback_frequency = self.frequency;
+        match pitch_filter {
+            Some(value) if filter_envelope => {
+                let scaled = f64::from(self.cutoff) * f64::from(value + 32.0) / 32.0;
+                cutoff = round_clamp(scaled, 0, filter::MAX_CUTOFF);
+            }
+            Some(value) => self.playback_frequency *= (f64::from(value) / 24.0).exp2(),
+            None => {}
+        }
+        self.filter.set(cutoff, self.resonance, sample_rate);
+
+        if let Some(envelopes) = envelopes {
+            for (state, envelope) in self.envelopes.iter_mut().zip(envelopes) {
+                state.advance(envelope, self.released);
+            }
+        }
+        true
+    }
+
+    /// Advances the fadeout by a tick, returns `false` when the voice has faded out.
+    fn update_fade(&mut self) -> bool {
+        match &mut self.fade_volume {
+            Some(volume) => {
+                *volume = volume.saturating_sub(self.fadeout);
+                *volume > 0
+            }
+            None => true,
+        }
+    }
+
+    /// Adds the sample to the interleaved stereo `out` played at `sample_rate`, raising `peak` to
+    /// the highest absolute values added to the left and right channels
+    ///
+    /// Muted voices advance without adding anything. With ramping the gains change linearly to
+    /// the ones of the current tick, otherwise only cuts and sample offsets ramp when declicking.
+    /// Returns `false` when the sample has ended, or a cut voice has ramped down.
+    pub(super) fn mix(
+        &mut self,
+        out: &mut [f32],
+        sample_rate: u32,
+        interpolation: Interpolation,
+        muted: bool,
+        peak: &mut [f32; 2],
+    ) -> bool {
+        let step = self.playback_frequency / f64::from(sample_rate);
+        let target = if self.cut { [0.0; 2] } else { [self.left, self.right] };
+        let up = target[0] > self.gains[0] || target[1] > self.gains[1];
+        let ramp = if self.ramping {
+            Some(if up { RAMP_UP_MICROS } else { RAMP_DOWN_MICROS } / 1e6)
+        } else if self.declick > 0.0 && (self.cut || self.fade_in) {
+            Some(self.declick)
+        } else {
+            None
+        };
+        match ramp {
+            Some(seconds) if target != self.ramp_target => self.start_ramp(target, seconds, sample_rate),
+            Some(_) => {}
+            None => {
+                self.gains = target;
+                self.ramp_target = target;
+            }
+        }
+        let audible = if muted { 0.0 } else { 1.0 };
+        for frame in out.chunks_exact_mut(2) {
+            if self.cut && self.ramp.0 == 0 {
+                return false;
+            }
+            if self.ramp.0 > 0 {
+                self.ramp.0 -= 1;
+                self.gains = if self.ramp.0 == 0 {
+                    self.fade_in = false;
+                    self.ramp_target
+                } else {
+                    [self.gains[0] + self.ramp.1[0], self.gains[1] + self.ramp.1[1]]
+                };
+            }
+            let value = self.filter.process(self.interpolate(interpolation, step)) * audible;
+            let (left, right) = (value * self.gains[0], value * self.gains[1]);
+            frame[0] += left;
+            frame[1] += right;
+            peak[0] = peak[0].max(left.abs());
+            peak[1] = peak[1].max(right.abs());
+            if !self.advance(step) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Starts ramping the gains to `target` over `seconds`.
+    fn start_ramp(&mut self, target: [f32; 2], seconds: f64, sample_rate: u32) {
+        let frames = round_clamp(f64::from(sample_rate) * seconds, 1, u32::MAX);
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+        let steps = f64::from(frames) as f32;
+        self.ramp_target = target;
+        self.ramp = (frames, [(target[0] - self.gains[0]) / steps, (target[1] - self.gains[1]) / steps]);
+    }
+
+    fn active_loop(&self) -> Option<SampleLoop> {
+        self.sustain_loop.or(self.loop_)
+    }
+
+    /// Value of the sample at the position
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    fn interpolate(&self, interpolation: Interpolation, step: f64) -> f32 {
+        // Float to integer `as` conversions saturate, the position is far from the limits.
+        let index = self.position.floor() as i64;
+        let fraction = self.position - self.position.floor();
+        match interpolation {
+            Interpolation::Nearest => self.sample_at(index),
+            Interpolation::Linear => {
+                let (current, next) = (self.sample_at(index), self.sample_at(index + 1));
+                current + (next - current) * fraction as f32
+            }
+            Interpolation::Cubic => {
+                // Catmull-Rom spline through the two samples on each side.
+                let [p0, p1, p2, p3] = [-1, 0, 1, 2].map(|offset| f64::from(self.sample_at(index + offset)));
+                let a = -0.5 * p0 + 1.5 * p1 - 1.5 * p2 + 0.5 * p3;
+                let b = p0 - 2.5 * p1 + 2.0 * p2 - 0.5 * p3;
+                let c = -0.5 * p0 + 0.5 * p2;
+                (((a * fraction + b) * fraction + c) * fraction + p1) as f32
+            }
+            Interpolation::Sinc => {
+                // Lanczos windowed sinc, the cutoff is lowered when playing faster than the output
+                // sample rate to avoid aliasing.
+                let cutoff = if step > 1.0 { 1.0 / step } else { 1.0 };
+                let offsets: [i8; 8] = array::from_fn(|tap| i8::try_from(tap).unwrap() + 1 - SINC_TAPS);
+                let weights = offsets.map(|offset| {
+                    let x = f64::from(offset) - fraction;
+                    (cutoff * sinc(cutoff * x) * sinc(x / f64::from(SINC_TAPS))) as f32
+                });
+                simd::dot(&self.window(index, offsets), &weights)
+            }
+        }
+    }
+
+    /// Returns the samples at `index` plus each of `offsets`, read straight from the data when
+    /// none of them needs wrapping into the active loop.
+    fn window(&self, index: i64, offsets: [i8; 8]) -> [f32; 8] {
+        let end = self.active_loop().map_or(self.data.len(), |loop_| usize::try_from(loop_.end).unwrap());
+        match usize::try_from(index + i64::from(offsets[0])) {
+            Ok(start) if start + offsets.len() <= end => {
+                array::from_fn(|tap| self.data.get(start + tap).unwrap_or(0.0))
+            }
+            _ => offsets.map(|offset| self.sample_at(index + i64::from(offset))),
+        }
+    }
+
+    /// Returns the sample at `index`, indices past the end of the active loop are wrapped into
+    /// the loop and silence is returned outside of the data.
+    fn sample_at(&self, index: i64) -> f32 {
+        let index = match self.active_loop() {
+            Some(SampleLoop { start, end, bidi }) if index >= i64::from(end) => {
+                let (start, end) = (i64::from(start), i64::from(end));
+                if !bidi {
+                    start + (index - start).rem_euclid(end - start)
+                } else if end - 1 == start {
+                    start
+                } else {
+                    // Bidirectional loops are mirrored around their first and last sample.
+                    let length = end - 1 - start;
+                    let offset = (index - start).rem_euclid(2 * length);
+                    if offset <= length { start + offset } else { start + 2 * length - offset }
+                }
+            }
+            _ => index,
+        };
+        usize::try_from(index).ok().and_then(|index| self.data.get(index)).unwrap_or(0.0)
+    }
+
+    /// Moves the position by `step`, returns `false` when the sample has ended.
+    fn advance(&mut self, step: f64) -> bool {
+        if self.forward {
+            self.position += step;
+        } else {
+            self.position -= step;
+        }
+
+        let Some(SampleLoop { start, end, bidi }) = self.active_loop() else {
+            return self.position >= 0.0 && self.position < f64::from(self.length());
+        };
+        let (start, end) = (f64::from(start), f64::from(end));
+        if !bidi {
+            if self.position >= end {
+                self.position = start + (self.position - start) % (end - start);
+            } else if self.position < start && !self.forward {
+                // Samples played backwards wrap around the loop too.
+                self.position = end - (start - self.position) % (end - start);
+            }
+        } else if (self.forward && self.position > end - 1.0) || (!self.forward && self.position < start) {
+            // Bidirectional loops turn around on the first and the last sample, the position is
+            // unfolded into a forward and a backward pass of the loop.
+            let length = end - 1.0 - start;
+            if length <= 0.0 {
+                self.position = start;
+                return true;
+            }
+            let offset = if self.forward {
+                self.position - start
+            } else {
+                2.0 * length - (self.position - start)
+            };
+            let offset = offset.rem_euclid(2.0 * length);
+            self.forward = offset < length;
+            self.position = if self.forward { start + offset } else { start + 2.0 * length - offset };
+        }
+        true
+    }
+
+    fn length(&self) -> u32 {
+        u32::try_from(self.data.len()).unwrap_or(u32::MAX)
+    }
+}
+
+/// Data of a deserialized voice until it's attached
+#[cfg(feature = "serde")]
+fn no_data() -> SampleData {
+    SampleData::from(Vec::<i8>::new())
+}
+
+/// Normalized sinc function, `sin(pi * x) / (pi * x)`
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn interpolation() {
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/compression/compressed.iti")).unwrap();
+        let mut sample = instrument.samples[0].clone();
+        sample.loop_ = None;
+        sample.sustain_loop = None;
+        sample.data = Some(SampleData::from(vec![0i16, 8192, 16384, 24576]));
+        let origin = Origin {
+            channel: 0,
+            instrument: None,
+            sample: SampleId::from_index(0).unwrap(),
+            note: Note::C_5,
+        };
+        let mut voice = Voice::new(&sample, origin, 8363.0).unwrap();
+
+        voice.position = 1.5;
+        assert_eq!(voice.interpolate(Interpolation::Nearest, 1.0), 0.25);
+        assert_eq!(voice.interpolate(Interpolation::Linear, 1.0), 0.375);
+        assert!((voice.interpolate(Interpolation::Cubic, 1.0) - 0.375).abs() < 1e-6);
+
+        // Sinc interpolation passes through the samples.
+        voice.position = 2.0;
+        assert!((voice.interpolate(Interpolation::Sinc, 1.0) - 0.5).abs() < 1e-6);
+
+        // Past the end of a bidirectional loop the samples are mirrored.
+        sample.set_loop(Some(SampleLoop { start: 1, end: 4, bidi: true })).unwrap();
+        let voice = Voice::new(&sample, origin, 8363.0).unwrap();
+        assert_eq!([4, 5, 6, 7].map(|index| voice.sample_at(index)), [0.5, 0.25, 0.5, 0.75]);
+    }
+}
@@ -0,0 +1,189 @@
+//! Offline rendering of songs to WAV files
+
+use super::{Player, PlayerOptions};
+use crate::*;
+use std::convert::TryFrom;
+use std::io::{self, Seek, SeekFrom, Write};
+
+
+/// `WAVE_FORMAT_PCM`
+const FORMAT_PCM: u16 = 1;
+
+/// `WAVE_FORMAT_IEEE_FLOAT`
+const FORMAT_IEEE_FLOAT: u16 = 3;
+
+/// Frames rendered at once
+const CHUNK_FRAMES: usize = 1024;
+
+
+/// Settings of [`render_to_wav`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    /// Settings of the player, [`PlayerOptions::repeat`] must not be `None`
+    pub player: PlayerOptions,
+
+    /// Sample format of the file
+    pub format: WavFormat,
+}
+
+/// Sample format of a rendered WAV file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WavFormat {
+    /// 16-bit PCM, clipped like [`Player::render_i16`]
+    Pcm16,
+
+    /// 32-bit floating point, like [`Player::render_f32`]
+    Float32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            player: PlayerOptions::default(),
+            format: WavFormat::Pcm16,
+        }
+    }
+}
+
+/// Plays `module` and writes it to `out` as a stereo WAV file, returns the number of frames.
+///
+/// The song ends like with [`Player`]: at the end of the orders list, or after the repetitions
+/// and the fade-out set by the player options. The audio is written as it's rendered and the
+/// sizes in the header are filled in at the end, `out` can be a [`File`](std::fs::File), a
+/// [`BufWriter`](std::io::BufWriter) of one or a [`Cursor`](std::io::Cursor).
+///
+/// # Errors
+///
+/// Returns an [`io::ErrorKind::InvalidInput`] error if the options play the song forever or the
+/// song is too long for a WAV file, and the errors of `out`.
+pub fn render_to_wav<W: Write + Seek>(module: &Module, out: &mut W, options: &RenderOptions) -> io::Result<u32> {
+    if options.player.repeat.is_none() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the song is repeated forever"));
+    }
+    let start = out.stream_position()?;
+    out.write_all(&header(options.format, options.player.sample_rate, 0))?;
+
+    let mut player = Player::new(module.clone(), options.player.clone());
+    let mut float = [0.0f32; CHUNK_FRAMES * 2];
+    let mut pcm = [0i16; CHUNK_FRAMES * 2];
+    let mut bytes = Vec::with_capacity(float.len() * 4);
+    let mut frames = 0u32;
+    loop {
+        bytes.clear();
+        let count = match options.format {
+            WavFormat::Pcm16 => {
+                let count = player.render_i16(&mut pcm);
+                pcm[..count * 2].iter().for_each(|sample| bytes.extend_from_slice(&sample.to_le_bytes()));
+                count
+            }
+            WavFormat::Float32 => {
+                let count = player.render_f32(&mut float);
+                float[..count * 2].iter().for_each(|sample| bytes.extend_from_slice(&sample.to_le_bytes()));
+                count
+            }
+        };
+        frames = u32::try_from(count)
+            .ok()
+            .and_then(|count| frames.checked_add(count))
+            .filter(|&frames| data_size(options.format, frames).is_some())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "song too long for a WAV file"))?;
+        out.write_all(&bytes)?;
+        if count < CHUNK_FRAMES {
+            break;
+        }
+    }
+
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&header(options.format, options.player.sample_rate, frames))?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(frames)
+}
+
+/// Size of the `data` chunk of `frames` frames, `None` if it doesn't fit in a WAV file
+fn data_size(format: WavFormat, frames: u32) -> Option<u32> {
+    let block_align = match format {
+        WavFormat::Pcm16 => 4,
+        WavFormat::Float32 => 8,
+    };
+    frames.checked_mul(block_align).filter(|&size| size <= u32::MAX - 0x1000)
+}
+
+/// Returns the RIFF header and the chunks up to the start of the sample data.
+///
+/// Float files have the extended `fmt ` chunk and the `fact` chunk non-PCM formats require.
+fn header(format: WavFormat, sample_rate: u32, frames: u32) -> Vec<u8> {
+    let (tag, bits, fmt_size, fact_size) = match format {
+        WavFormat::Pcm16 => (FORMAT_PCM, 16u16, 16u32, 0u32),
+        WavFormat::Float32 => (FORMAT_IEEE_FLOAT, 32, 18, 12),
+    };
+    let block_align = 2 * bits / 8;
+    let data_size = data_size(format, frames).unwrap();
+    let riff_size = 4 + (8 + fmt_size) + fact_size + (8 + data_size);
+
+    let mut header = Vec::new();
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&riff_size.to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&fmt_size.to_le_bytes());
+    header.extend_from_slice(&tag.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&sample_rate.saturating_mul(u32::from(block_align)).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits.to_le_bytes());
+    if format == WavFormat::Float32 {
+        // Size of the extension.
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(b"fact");
+        header.extend_from_slice(&4u32.to_le_bytes());
+        header.extend_from_slice(&frames.to_le_bytes());
+    }
+
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::test::module;
+    use std::io::Cursor;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn render() {
+        let mut out = Cursor::new(Vec::new());
+        assert_eq!(render_to_wav(&module(), &mut out, &RenderOptions::default()).unwrap(), 23040);
+        let wav = out.into_inner();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(usize::try_from(u32_at(&wav, 4)).unwrap(), wav.len() - 8);
+        assert_eq!(u32_at(&wav, 24), 48000);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 23040 * 4);
+        assert_eq!(&wav[44..46], &8192i16.to_le_bytes());
+
+        let options = RenderOptions { format: WavFormat::Float32, ..RenderOptions::default() };
+        let mut out = Cursor::new(Vec::new());
+        assert_eq!(render_to_wav(&module(), &mut out, &options).unwrap(), 23040);
+        let wav = out.into_inner();
+        assert_eq!(usize::try_from(u32_at(&wav, 4)).unwrap(), wav.len() - 8);
+        assert_eq!((&wav[38..42], u32_at(&wav, 46)), (&b"fact"[..], 23040));
+        assert_eq!(&wav[50..54], b"data");
+        assert_eq!(&wav[58..62], &0.25f32.to_le_bytes());
+
+        let options = RenderOptions {
+            player: PlayerOptions { repeat: None, ..PlayerOptions::default() },
+            ..RenderOptions::default()
+        };
+        let error = render_to_wav(&module(), &mut Cursor::new(Vec::new()), &options).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,158 @@
+//! Python bindings through `pyo3`
+//!
+//! Enabled by the feature `python`. `maturin develop` builds the crate with
+//! [maturin](https://www.maturin.rs/) as configured in `pyproject.toml` and installs the `ittech`
+//! Python module. The manifest doesn't declare a `cdylib`, maturin requests it when building the
+//! extension module:
+//!
+//! ```python
+//! import ittech
+//!
+//! module = ittech.parse(open("song.it", "rb").read())
+//! print(module.metadata()["title"])
+//! for row, cells in enumerate(module.pattern(0)):
+//!     for channel, note, instrument, volume, effect in cells:
+//!         print(row, channel, note, instrument, volume, effect)
+//! ```
+
+use crate::error::{convert_error, VerboseError};
+use crate::metadata::Metadata;
+use crate::{parser, Module, Order};
+use nom::Err;
+use pyo3::exceptions::{PyIndexError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+
+/// Cell of a pattern as a Python tuple: 1-based channel, note, instrument number, volume and
+/// effect as displayed in the tracker, e.g. `(1, "C-5", 1, "v64", "D04")`
+type Cell = (usize, Option<String>, Option<u8>, Option<String>, Option<String>);
+
+
+/// Parsed module, `ittech.Module` in Python
+#[pyclass(name = "Module")]
+pub struct PyItModule {
+    module: Module,
+}
+
+/// Parse Impulse Tracker module file (.it), raises `ValueError` describing where the parser
+/// failed for invalid files
+#[pyfunction]
+fn parse(data: &[u8]) -> PyResult<PyItModule> {
+    match parser::module_file::<VerboseError<_>>(data) {
+        Ok(module) => Ok(PyItModule { module }),
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => Err(PyValueError::new_err(convert_error(data, e))),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    }
+}
+
+#[pymethods]
+impl PyItModule {
+    #[getter]
+    fn title(&self) -> String {
+        self.module.name.decode()
+    }
+
+    #[getter]
+    fn message(&self) -> String {
+        self.module.message.clone()
+    }
+
+    /// Orders list as pattern numbers, separators and the end of the song are `None`
+    #[getter]
+    fn orders(&self) -> Vec<Option<usize>> {
+        self.module.orders
+            .iter()
+            .map(|order| match order {
+                Order::Index(pattern) => Some(pattern.as_usize()),
+                Order::Separator | Order::EndOfSong => None,
+            })
+            .collect()
+    }
+
+    #[getter]
+    fn pattern_count(&self) -> usize {
+        self.module.patterns.len()
+    }
+
+    /// Returns the descriptive metadata as a dictionary, see [`Metadata`].
+    fn metadata<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let metadata = Metadata::new(&self.module);
+        let samples = metadata.samples
+            .iter()
+            .map(|sample| {
+                let dict = PyDict::new(py);
+                dict.set_item("name", &sample.name)?;
+                dict.set_item("filename", &sample.filename)?;
+                dict.set_item("length", sample.length)?;
+                dict.set_item("bits", sample.bits)?;
+                dict.set_item("samplerate", sample.samplerate)?;
+                Ok(dict)
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+
+        let dict = PyDict::new(py);
+        dict.set_item("title", metadata.title)?;
+        dict.set_item("message", metadata.message)?;
+        dict.set_item("speed", metadata.speed)?;
+        dict.set_item("tempo", metadata.tempo)?;
+        dict.set_item("channels", metadata.channels)?;
+        dict.set_item("orders", metadata.orders)?;
+        dict.set_item("patterns", metadata.patterns)?;
+        dict.set_item("duration", metadata.duration)?;
+        dict.set_item("instruments", metadata.instruments)?;
+        dict.set_item("samples", samples)?;
+        Ok(dict)
+    }
+
+    /// Returns the rows of the pattern `index`, each a list of the non-empty cells as
+    /// `(channel, note, instrument, volume, effect)` tuples.
+    fn pattern(&self, index: usize) -> PyResult<Vec<Vec<Cell>>> {
+        let pattern = self.module.patterns
+            .get(index)
+            .ok_or_else(|| PyIndexError::new_err(format!("pattern {index} doesn't exist")))?;
+        let rows = pattern.rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|(channel, command)| (
+                        channel.as_usize() + 1,
+                        command.note.as_ref().map(ToString::to_string),
+                        command.instrument.map(|instrument| instrument.number()),
+                        command.volume.as_ref().map(ToString::to_string),
+                        command.effect.as_ref().map(ToString::to_string),
+                    ))
+                    .collect()
+            })
+            .collect();
+        Ok(rows)
+    }
+
+    /// Returns the cells of the patterns in the orders list as `(order, row, cell)` tuples, the
+    /// cells are the tuples returned by `pattern`.
+    fn cells(&self) -> PyResult<Vec<(usize, usize, Cell)>> {
+        let mut cells = Vec::new();
+        for (order, pattern) in self.module.orders.iter().enumerate() {
+            let pattern = match pattern {
+                Order::Index(pattern) if pattern.as_usize() < self.module.patterns.len() => pattern.as_usize(),
+                _ => continue,
+            };
+            for (row, commands) in self.pattern(pattern)?.into_iter().enumerate() {
+                cells.extend(commands.into_iter().map(|cell| (order, row, cell)));
+            }
+        }
+        Ok(cells)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<ittech.Module {:?}>", self.module.name.decode())
+    }
+}
+
+/// The `ittech` Python module
+#[pymodule]
+fn ittech(_py: Python, module: &PyModule) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(parse, module)?)?;
+    module.add_class::<PyItModule>()?;
+    Ok(())
+}
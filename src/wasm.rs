@@ -0,0 +1,178 @@
+//! JavaScript bindings through `wasm-bindgen`
+//!
+//! Enabled by the feature `wasm`. The bindings export [`parse`], the [`Module`](WasmModule) it
+//! returns with its [`Metadata`](WasmMetadata) and a [`Player`](WasmPlayer) rendering the module
+//! in chunks, e.g. from an `AudioWorkletProcessor`.
+//!
+//! The manifest doesn't declare a `cdylib`, request it when building the WebAssembly module and
+//! generate the JavaScript glue with the `wasm-bindgen` CLI:
+//!
+//! ```sh
+//! cargo rustc --release --lib --crate-type cdylib --target wasm32-unknown-unknown --features wasm
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/release/ittech.wasm
+//! ```
+//!
+//! ```js
+//! const module = parse(new Uint8Array(await file.arrayBuffer()));
+//! console.log(module.metadata().title);
+//! const player = new Player(module, sampleRate);
+//! // In `process`, one chunk of 128 frames per call:
+//! player.renderPlanar(outputs[0][0], outputs[0][1]);
+//! ```
+
+use crate::error::{convert_error, VerboseError};
+use crate::metadata::Metadata;
+use crate::player::{Player, PlayerOptions};
+use crate::{parser, Module};
+use nom::Err;
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+
+
+/// Parse Impulse Tracker module file (.it)
+///
+/// Throws an error describing where the parser failed for invalid files.
+#[wasm_bindgen]
+pub fn parse(bytes: &[u8]) -> Result<WasmModule, JsError> {
+    match parser::module_file::<VerboseError<_>>(bytes) {
+        Ok(module) => Ok(WasmModule { module: Arc::new(module) }),
+        Err(Err::Error(e)) | Err(Err::Failure(e)) => Err(JsError::new(&convert_error(bytes, e))),
+        Err(Err::Incomplete(_)) => unreachable!(),
+    }
+}
+
+
+/// Parsed module, `Module` in JavaScript
+#[wasm_bindgen(js_name = Module)]
+pub struct WasmModule {
+    module: Arc<Module>,
+}
+
+#[wasm_bindgen(js_class = Module)]
+impl WasmModule {
+    /// Returns the descriptive metadata of the module.
+    pub fn metadata(&self) -> WasmMetadata {
+        WasmMetadata(Metadata::new(&self.module))
+    }
+}
+
+
+/// [`Metadata`] of a module, `Metadata` in JavaScript
+#[wasm_bindgen(js_name = Metadata)]
+pub struct WasmMetadata(Metadata);
+
+#[wasm_bindgen(js_class = Metadata)]
+impl WasmMetadata {
+    #[wasm_bindgen(getter)]
+    pub fn title(&self) -> String {
+        self.0.title.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn message(&self) -> String {
+        self.0.message.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn speed(&self) -> u8 {
+        self.0.speed
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn tempo(&self) -> u8 {
+        self.0.tempo
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn channels(&self) -> usize {
+        self.0.channels
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn orders(&self) -> usize {
+        self.0.orders
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn patterns(&self) -> usize {
+        self.0.patterns
+    }
+
+    /// Playing time in seconds
+    #[wasm_bindgen(getter)]
+    pub fn duration(&self) -> f64 {
+        self.0.duration
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn instruments(&self) -> Vec<String> {
+        self.0.instruments.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn samples(&self) -> Vec<String> {
+        self.0.samples.iter().map(|sample| sample.name.clone()).collect()
+    }
+}
+
+
+/// [`Player`] of a module, `Player` in JavaScript
+#[wasm_bindgen(js_name = Player)]
+pub struct WasmPlayer {
+    player: Player,
+
+    /// Interleaved frames for [`WasmPlayer::render_planar`]
+    buffer: Vec<f32>,
+}
+
+#[wasm_bindgen(js_class = Player)]
+impl WasmPlayer {
+    /// Creates a player of `module` rendering at `sample_rate` Hz, it stops when the song ends or
+    /// starts repeating.
+    #[wasm_bindgen(constructor)]
+    pub fn new(module: &WasmModule, sample_rate: u32) -> Result<WasmPlayer, JsError> {
+        if sample_rate == 0 {
+            return Err(JsError::new("sample rate must not be zero"));
+        }
+        let options = PlayerOptions { sample_rate, ..PlayerOptions::default() };
+        Ok(WasmPlayer {
+            player: Player::new(Arc::clone(&module.module), options),
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Renders interleaved stereo frames into `out`, returns the number of rendered frames.
+    pub fn render(&mut self, out: &mut [f32]) -> usize {
+        self.player.render_f32(out)
+    }
+
+    /// Renders the left and right channel into separate arrays like the Web Audio API uses them,
+    /// returns the number of rendered frames.
+    #[wasm_bindgen(js_name = renderPlanar)]
+    pub fn render_planar(&mut self, left: &mut [f32], right: &mut [f32]) -> usize {
+        self.buffer.resize(2 * left.len().min(right.len()), 0.0);
+        let frames = self.player.render_f32(&mut self.buffer);
+        for (frame, (left, right)) in self.buffer.chunks_exact(2).zip(left.iter_mut().zip(right)).take(frames) {
+            *left = frame[0];
+            *right = frame[1];
+        }
+        frames
+    }
+
+    /// Order being played, `undefined` once the song has ended
+    #[wasm_bindgen(getter)]
+    pub fn order(&self) -> Option<usize> {
+        self.player.position().map(|position| position.order.as_usize())
+    }
+
+    /// Row being played, `undefined` once the song has ended
+    #[wasm_bindgen(getter)]
+    pub fn row(&self) -> Option<usize> {
+        self.player.position().map(|position| position.row)
+    }
+
+    #[wasm_bindgen(getter, js_name = isFinished)]
+    pub fn is_finished(&self) -> bool {
+        self.player.is_finished()
+    }
+}
@@ -1,3 +1,1499 @@
 //! Writing functions
 //!
-//! **TODO** serialization is not yet implemented
+//! [`module_file`] serializes a complete [`Module`] into an Impulse Tracker module file,
+//! [`instrument_file`] and [`sample_file`] write the standalone instrument and sample files. The
+//! pattern commands can be also converted back to their raw representation on their own.
+//! Samples can be also exported to WAV files with [`Sample::write_wav`], and with the `aiff` and
+//! `flac` features to AIFF and FLAC files with `Sample::write_aiff` and `Sample::write_flac`.
+//! [`Sample::write_raw`] dumps the bare PCM data, described by [`Sample::raw_descriptor`].
+//!
+//! The writer produces files in the layout Impulse Tracker itself uses: the header with the offset
+//! tables is followed by the song message, instrument headers, sample headers, patterns and
+//! finally the sample data. Samples are stored uncompressed unless [`WriteOptions`] select a
+//! [`Compression`].
+//!
+//!
+//! # Reproducible output
+//!
+//! The output only depends on the written value and the options: writing equal modules always
+//! produces identical bytes. Structures are laid out in a fixed order, reserved fields and unused
+//! envelope nodes are zeroed and nothing is derived from the environment, such as the current
+//! time. The timestamp of an [edit history entry](WriteOptions::edit_history) is provided by the
+//! caller as well. Bytes after the null terminator of names are part of [`Name`] and are written as they
+//! are.
+//!
+//!
+//! # Offsets
+//!
+//! The file is always laid out from scratch, the offset tables, the message offset and the sample
+//! data offsets are computed from the sizes of the structures being written. Editing a pattern or
+//! replacing a sample of a parsed module (also one parsed with
+//! [`parser::module_file_preserving`](crate::parser::module_file_preserving)) therefore doesn't
+//! need any fixup. Debug builds check every written offset points inside the file.
+//!
+//!
+//! # OpenMPT extensions
+//!
+//! OpenMPT stores the features Impulse Tracker doesn't have (tempos above 255, more than 64
+//! channels, plugins...) in extension chunks (`STPM`, `XTPM`) appended to the file. [`Module`]
+//! can't represent more than 64 channels or plugins, the song properties which extend it are
+//! written from [`WriteOptions::song_properties`]. Chunks found in a parsed file are kept by
+//! [`module_file_preserving`], see [`Preserved`]. The extensions are left out for the
+//! [targets](WriteOptions::target) other than OpenMPT.
+
+use crate::cp437;
+use crate::data::*;
+use crate::error::{StreamWriteError, WriteError};
+use crate::parser::{INSTRUMENT_SIZE, PATTERN_HEADER_SIZE, SAMPLE_HEADER_SIZE, SPECIAL_EDIT_HISTORY};
+use crate::player::TempoMode;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::time::Duration;
+
+
+#[cfg(feature = "aiff")]
+mod aiff;
+mod compat;
+mod compression;
+mod extensions;
+#[cfg(feature = "flac")]
+mod flac;
+mod pattern;
+mod raw;
+mod wav;
+
+pub use pattern::serialize_effect as effect;
+pub use pattern::serialize_volume as volume;
+
+
+/// Size of the fixed part of the module header
+const MODULE_HEADER_SIZE: usize = 0xC0;
+
+/// Maximum number of nodes in an envelope
+const ENVELOPE_NODES: usize = 25;
+
+/// Size of a single edit history entry
+const EDIT_HISTORY_ENTRY_SIZE: usize = 8;
+
+
+/// Sample compression format
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Compression {
+    /// Raw PCM data
+    #[default]
+    None,
+
+    /// Compression introduced in Impulse Tracker 2.14
+    It214,
+
+    /// Like [`Compression::It214`] but the data is delta encoded once more before compressing,
+    /// introduced in Impulse Tracker 2.15. This usually compresses better.
+    It215,
+}
+
+/// Options for [`module_file_with_options`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteOptions {
+    /// Compression used for the samples without an entry in `sample_compression`
+    pub compression: Compression,
+
+    /// Compression used for specific samples
+    pub sample_compression: HashMap<SampleId, Compression>,
+
+    /// Leave out the sample data
+    ///
+    /// The sample headers are written as they are (including the length and the loops) but
+    /// without the flag saying the data is present, so the file only contains the structure of
+    /// the module. Useful for comparing the structure of modules or attaching them to bug
+    /// reports.
+    ///
+    /// Parsing the file gives samples without data. The names, tunings, loops and vibrato
+    /// survive, but [`Sample`] only knows its length and bit depth from the data, so
+    /// [`Sample::length`] is 0. The declared length is still in the header, e.g. at the
+    /// `sample[0].header.length` region of [`parser::byte_map`](crate::parser::byte_map).
+    pub strip_sample_data: bool,
+
+    /// Tracker the file is meant for
+    ///
+    /// Features the tracker doesn't support are downgraded or removed before writing, the changes
+    /// are listed in [`WriteReport::adjustments`]. With `None` the module is written as it is.
+    ///
+    /// The OpenMPT extensions kept by [`module_file_preserving_with_options`] (see [`Preserved`])
+    /// are dropped for the trackers other than OpenMPT, the edit history and the MIDI
+    /// configuration are kept.
+    pub target: Option<Target>,
+
+    /// Entry appended to the edit history
+    ///
+    /// Impulse Tracker and OpenMPT add an entry to the edit history every time they save a
+    /// module. The history of a parsed module is kept by
+    /// [`module_file_preserving_with_options`], the other functions start a new one.
+    pub edit_history: Option<EditHistoryEntry>,
+
+    /// OpenMPT song properties written into the extension chunks at the end of the file
+    ///
+    /// They replace the same properties in the chunks kept by
+    /// [`module_file_preserving_with_options`], a later property replaces an earlier one.
+    pub song_properties: Vec<SongProperty>,
+}
+
+/// Editing session recorded in the edit history, see [`WriteOptions::edit_history`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct EditHistoryEntry {
+    /// Date the session started in the MS-DOS format, `day | month << 5 | (year - 1980) << 9`
+    pub dos_date: u16,
+
+    /// Time the session started in the MS-DOS format, `second / 2 | minute << 5 | hour << 11`
+    pub dos_time: u16,
+
+    /// Length of the session in DOS timer ticks, there are about 18.2 ticks per second
+    pub run_time: u32,
+}
+
+/// Tracker the written file is meant to be loaded in, see [`WriteOptions::target`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// Impulse Tracker 2.14
+    ///
+    /// Patterns have 32 to 200 rows, the tempo is at least 32, the "Compatible With" version is
+    /// at most 2.14 and `S90`, the MPTM extension effects and the OpenMPT extensions are removed.
+    ImpulseTracker214,
+
+    /// Schism Tracker
+    ///
+    /// Patterns have 1 to 200 rows, the tempo is at least 32 and the MPTM extension effects and
+    /// the OpenMPT extensions are removed.
+    Schism,
+
+    /// OpenMPT
+    ///
+    /// Patterns have 1 to 1024 rows.
+    OpenMPT,
+}
+
+/// OpenMPT song property, see [`WriteOptions::song_properties`]
+///
+/// OpenMPT reads the properties instead of the header fields they extend, the other trackers
+/// ignore them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SongProperty {
+    /// Initial tempo, unlike [`Module::tempo`] it can be above 255 (`DT..`)
+    Tempo(u32),
+
+    /// How the tempo and speed set the length of the ticks (`TM..`, and `RPB.` for the rows per
+    /// beat of the modern mode), read back by [`TempoMode::detect`]
+    TempoMode(TempoMode),
+
+    /// Rows per measure, unlike the highlight in [`Module::highlight`] it can be above 255
+    /// (`RPM.`)
+    RowsPerMeasure(u32),
+}
+
+/// Change made to fit the module into the [`Target`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Adjustment {
+    /// Initial tempo was raised to the lowest tempo of the target
+    Tempo { tempo: u8, adjusted: u8 },
+
+    /// "Compatible With" version was lowered to the version of the target
+    CompatibleWithVersion { version: u16, adjusted: u16 },
+
+    /// Effects the target doesn't support were removed from the pattern
+    EffectsRemoved { pattern: PatternId, count: usize },
+
+    /// Pattern was cut or extended with empty rows to a length supported by the target
+    PatternRows { pattern: PatternId, rows: usize, adjusted: usize },
+
+    /// OpenMPT extensions were left out, `header` bytes after the offset tables and `trailing`
+    /// bytes at the end of the file
+    ExtensionsRemoved { header: usize, trailing: usize },
+}
+
+/// Information about a written file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WriteReport {
+    /// Storage of every sample in the module, in the order of the samples
+    pub samples: Vec<SampleReport>,
+
+    /// Changes made for [`WriteOptions::target`]
+    pub adjustments: Vec<Adjustment>,
+
+    /// Characters of the song message which were replaced because they can't be stored
+    pub message: cp437::Lossy,
+}
+
+/// How a sample was stored, see [`WriteReport`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SampleReport {
+    pub sample: SampleId,
+
+    /// Compression used, samples without data are always reported as [`Compression::None`].
+    pub compression: Compression,
+
+    /// Size of the uncompressed data in bytes
+    pub raw_size: usize,
+
+    /// Size of the data stored in the file in bytes
+    pub stored_size: usize,
+}
+
+impl WriteOptions {
+    /// Returns the compression used for `sample`.
+    pub fn compression_for(&self, sample: SampleId) -> Compression {
+        self.sample_compression.get(&sample).copied().unwrap_or(self.compression)
+    }
+}
+
+impl EditHistoryEntry {
+    /// Creates an entry for a session started at `date` (year, month, day) and `time` (hour,
+    /// minute, second) in local time which lasted for `session`
+    ///
+    /// The values are clamped to what the format can represent: years 1980 to 2107, time with a
+    /// two second resolution and sessions up to about 7.5 years.
+    pub fn new(date: (u16, u8, u8), time: (u8, u8, u8), session: Duration) -> EditHistoryEntry {
+        /// Frequency of the DOS timer is 1193182 / 65536 Hz
+        const TICKS_PER_SECOND: (u128, u128) = (1_193_182, 65_536);
+
+        let (year, month, day) = date;
+        let (hour, minute, second) = time;
+        let dos_date = u16::from(day.clamp(1, 31))
+            | u16::from(month.clamp(1, 12)) << 5
+            | (year.clamp(1980, 2107) - 1980) << 9;
+        let dos_time = u16::from(second.min(59) / 2)
+            | u16::from(minute.min(59)) << 5
+            | u16::from(hour.min(23)) << 11;
+        let ticks = session.as_nanos() * TICKS_PER_SECOND.0 / (TICKS_PER_SECOND.1 * 1_000_000_000);
+        EditHistoryEntry {
+            dos_date,
+            dos_time,
+            run_time: u32::try_from(ticks).unwrap_or(u32::MAX),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; EDIT_HISTORY_ENTRY_SIZE] {
+        let mut bytes = [0; EDIT_HISTORY_ENTRY_SIZE];
+        bytes[0..2].copy_from_slice(&self.dos_date.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.dos_time.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.run_time.to_le_bytes());
+        bytes
+    }
+}
+
+impl WriteReport {
+    /// Size of the uncompressed data of all samples in bytes
+    pub fn raw_sample_size(&self) -> usize {
+        self.samples.iter().map(|sample| sample.raw_size).sum()
+    }
+
+    /// Size of the sample data stored in the file in bytes
+    pub fn stored_sample_size(&self) -> usize {
+        self.samples.iter().map(|sample| sample.stored_size).sum()
+    }
+}
+
+
+/// Serialize module into an Impulse Tracker module file (.it)
+///
+/// Writing is the inverse of [`parser::module_file`](crate::parser::module_file), parsing the
+/// output gives back the same module with the exception of values the parser derives from the
+/// file:
+///
+/// - [`ModuleFlags::MESSAGE_ATTACHED`] is set if and only if the message is not empty,
+/// - [`ModuleFlags::MIDI_CONIFG_EMBEDDED`] is cleared because [`Module`] doesn't hold the MIDI
+///   configuration, only [`module_file_preserving`] writes the one found in the original file,
+/// - patterns with 64 empty rows are stored as the offset 0, skipping the pattern data, so
+///   their [`Pattern::active_channels`] are parsed as empty,
+/// - message characters which are not in code page 437 and nulls are replaced with `?`, they are
+///   listed in [`WriteReport::message`] (or use [`Module::set_message`] to find out about those
+///   beforehand).
+///
+/// Returns an error if the module doesn't fit into the limits of the file format.
+pub fn module_file(module: &Module) -> Result<Vec<u8>, WriteError> {
+    let (out, _report) = write_module(module, &WriteOptions::default(), Extra::default())?;
+    Ok(out)
+}
+
+/// Serialize module into an Impulse Tracker module file (.it) with non-default options
+///
+/// See [`module_file`] for details.
+pub fn module_file_with_options(module: &Module, options: &WriteOptions) -> Result<(Vec<u8>, WriteReport), WriteError> {
+    write_module(module, options, Extra::default())
+}
+
+/// Serialize module into an Impulse Tracker module file (.it) written into `out`
+///
+/// The file is laid out before anything is written, so it's written front to back in a single
+/// pass and `out` doesn't need to be seekable. Only the header, patterns and compressed sample
+/// data are buffered, uncompressed sample data is written directly from the module. Wrap
+/// unbuffered writers like files in a [`BufWriter`](std::io::BufWriter).
+///
+/// If the module doesn't fit into the file format nothing is written. I/O errors can happen with
+/// a part of the file already written.
+///
+/// See [`module_file`] for details.
+pub fn module_file_to_writer(module: &Module, options: &WriteOptions, out: &mut impl Write) -> Result<WriteReport, StreamWriteError> {
+    let (module, mut adjustments) = compat::adjust(module, options.target);
+    let (extra, removed) = compat::adjust_extra(Extra::default().with_options(options), options.target);
+    adjustments.extend(removed);
+    let layout = layout(&module, options, &extra)?;
+    layout.write(out)?;
+    Ok(layout.report(adjustments))
+}
+
+/// Serialize module into an Impulse Tracker module file (.it) keeping the data in `preserved`
+///
+/// If the module is unchanged since it was parsed with
+/// [`parser::module_file_preserving`](crate::parser::module_file_preserving) the original file is
+/// returned. Otherwise the module is written like with [`module_file`] and the unknown data from
+/// `preserved` is stored in the same places of the file it was found in, see [`Preserved`].
+pub fn module_file_preserving(module: &Module, preserved: &Preserved) -> Result<Vec<u8>, WriteError> {
+    if preserved.is_unmodified(module) {
+        return Ok(preserved.original().to_vec());
+    }
+    let (out, _report) = write_module(module, &WriteOptions::default(), Extra::from(preserved))?;
+    Ok(out)
+}
+
+/// Serialize module into an Impulse Tracker module file (.it) keeping the data in `preserved`
+/// with non-default options
+///
+/// Unlike [`module_file_preserving`] the file is always laid out anew, even if the module is
+/// unchanged. The [edit history entry](WriteOptions::edit_history) is appended to the history
+/// found in the original file.
+pub fn module_file_preserving_with_options(
+    module: &Module,
+    preserved: &Preserved,
+    options: &WriteOptions,
+) -> Result<(Vec<u8>, WriteReport), WriteError> {
+    write_module(module, options, Extra::from(preserved))
+}
+
+/// Serialize instrument with its samples into an Impulse Tracker instrument file (.iti)
+///
+/// This is the inverse of [`parser::instrument_file`](crate::parser::instrument_file). The
+/// sample map of the instrument references the samples by their position in
+/// [`InstrumentFile::samples`], the number of samples stored in the instrument header is derived
+/// from the list.
+pub fn instrument_file(file: &InstrumentFile) -> Result<Vec<u8>, WriteError> {
+    check_count("samples", file.samples.len(), 99)?;
+    check_envelopes(&file.instrument, None)?;
+    for sample in &file.samples {
+        check_sample_data(sample, None)?;
+    }
+
+    let sample_data = file.samples
+        .iter()
+        .map(|sample| stored_sample_data(sample, Compression::None))
+        .collect::<Vec<_>>();
+    let mut size = INSTRUMENT_SIZE + file.samples.len() * SAMPLE_HEADER_SIZE;
+    let mut data_offsets = Vec::with_capacity(file.samples.len());
+    for data in &sample_data {
+        data_offsets.push(size);
+        size += data.size();
+    }
+    if u32::try_from(size).is_err() {
+        return Err(WriteError::FileTooLarge { size });
+    }
+
+    let mut out = Vec::with_capacity(size);
+    write_instrument(&mut out, &file.instrument, u8::try_from(file.samples.len()).unwrap());
+    for ((sample, data), &data_offset) in file.samples.iter().zip(&sample_data).zip(&data_offsets) {
+        write_sample_header(&mut out, sample, data, u32::try_from(data_offset).unwrap());
+    }
+    for (sample, data) in file.samples.iter().zip(&sample_data) {
+        // Writing into a `Vec` never fails.
+        data.write(&mut out, sample).unwrap();
+    }
+    debug_assert_eq!(out.len(), size);
+    Ok(out)
+}
+
+/// Serialize sample into an Impulse Tracker sample file (.its)
+///
+/// This is the inverse of [`parser::sample_file`](crate::parser::sample_file).
+pub fn sample_file(sample: &Sample) -> Result<Vec<u8>, WriteError> {
+    check_sample_data(sample, None)?;
+    let data = stored_sample_data(sample, Compression::None);
+    let size = SAMPLE_HEADER_SIZE + data.size();
+    if u32::try_from(size).is_err() {
+        return Err(WriteError::FileTooLarge { size });
+    }
+
+    let mut out = Vec::with_capacity(size);
+    write_sample_header(&mut out, sample, &data, u32::try_from(SAMPLE_HEADER_SIZE).unwrap());
+    // Writing into a `Vec` never fails.
+    data.write(&mut out, sample).unwrap();
+    Ok(out)
+}
+
+impl Module {
+    /// Size of the module file written by [`module_file_with_options`] with `options`
+    ///
+    /// The size is exact. Samples selected for compression are compressed to find their size, so
+    /// this costs about as much as writing the file.
+    pub fn serialized_size(&self, options: &WriteOptions) -> Result<usize, WriteError> {
+        let (module, _) = compat::adjust(self, options.target);
+        let (extra, _) = compat::adjust_extra(Extra::default().with_options(options), options.target);
+        Ok(layout(&module, options, &extra)?.size)
+    }
+}
+
+impl InstrumentFile {
+    /// Creates an instrument file from an instrument of `module` and the samples it uses
+    ///
+    /// The samples referenced from the sample map are copied in the order of their IDs, the
+    /// sample map is remapped to their positions in the file. References to samples which don't
+    /// exist in the module are dropped.
+    pub fn from_module(instrument: &Instrument, module: &Module) -> InstrumentFile {
+        let mut used = instrument.sample_map
+            .iter()
+            .filter_map(|(_, _, sample)| sample)
+            .filter(|&sample| module.get(sample).is_some())
+            .collect::<Vec<_>>();
+        used.sort_unstable();
+        used.dedup();
+
+        let mut instrument = instrument.clone();
+        instrument.sample_map.remap_samples(|sample| {
+            let position = used.binary_search(&sample).ok()?;
+            SampleId::from_index(u8::try_from(position).unwrap()).ok()
+        });
+        instrument.number_of_samples = u8::try_from(used.len()).unwrap();
+        let samples = used.iter().map(|&sample| module[sample].clone()).collect();
+
+        InstrumentFile { instrument, samples }
+    }
+
+    /// Serialize into an instrument file (.iti), see [`instrument_file`].
+    pub fn write_iti(&self) -> Result<Vec<u8>, WriteError> {
+        instrument_file(self)
+    }
+}
+
+impl Instrument {
+    /// Serialize into an instrument file (.iti) embedding the samples it uses from `module`
+    ///
+    /// See [`InstrumentFile::from_module`] for how the samples are picked.
+    pub fn write_iti(&self, module: &Module) -> Result<Vec<u8>, WriteError> {
+        instrument_file(&InstrumentFile::from_module(self, module))
+    }
+}
+
+impl Sample {
+    /// Serialize into a sample file (.its), see [`sample_file`].
+    pub fn write_its(&self) -> Result<Vec<u8>, WriteError> {
+        sample_file(self)
+    }
+}
+
+
+/// Data written around the structures of the module
+#[derive(Default)]
+struct Extra<'a> {
+    /// Edit history entries, written after the offset tables with their count
+    edit_history: Option<Cow<'a, [u8]>>,
+
+    /// Embedded MIDI configuration, written after the edit history
+    midi_config: Option<&'a [u8]>,
+
+    /// Rest of the data written after the offset tables
+    header: &'a [u8],
+
+    /// Written at the end of the file
+    trailing: Cow<'a, [u8]>,
+
+    /// Bits added to the `flags` and `special` header fields
+    flags: (u16, u16),
+
+    /// Instruments of the file `trailing` comes from, the OpenMPT instrument extensions have a
+    /// value for each
+    instruments: usize,
+}
+
+impl<'a> From<&'a Preserved> for Extra<'a> {
+    /// Splits the preserved header data into the edit history, the MIDI configuration and the
+    /// rest, data which doesn't match the header flags is kept in the rest as it is.
+    fn from(preserved: &'a Preserved) -> Extra<'a> {
+        let mut extra = Extra {
+            edit_history: None,
+            midi_config: None,
+            header: &preserved.header_extra,
+            trailing: Cow::Borrowed(&preserved.trailing),
+            flags: preserved.unknown_flags,
+            instruments: preserved.module.instruments.len(),
+        };
+        if extra.flags.1 & SPECIAL_EDIT_HISTORY != 0 {
+            let count = extra.header.get(..2).map(|count| u16::from_le_bytes(count.try_into().unwrap()));
+            let end = count.map(|count| 2 + usize::from(count) * EDIT_HISTORY_ENTRY_SIZE);
+            if let Some(entries) = end.and_then(|end| extra.header.get(2..end)) {
+                extra.edit_history = Some(Cow::Borrowed(entries));
+                extra.header = &extra.header[2 + entries.len()..];
+                extra.flags.1 &= !SPECIAL_EDIT_HISTORY;
+            }
+        }
+        if preserved.module.flags.contains(ModuleFlags::MIDI_CONIFG_EMBEDDED) && extra.header.len() >= MIDI_CONFIG_SIZE {
+            let (config, rest) = extra.header.split_at(MIDI_CONFIG_SIZE);
+            extra.midi_config = Some(config);
+            extra.header = rest;
+        }
+        extra
+    }
+}
+
+impl<'a> Extra<'a> {
+    /// Returns the data with the additions requested by `options`.
+    fn with_options(self, options: &WriteOptions) -> Extra<'a> {
+        let mut extra = match options.edit_history {
+            Some(entry) => self.with_edit_history(entry),
+            None => self,
+        };
+        extra.trailing = extensions::song_properties(extra.trailing, extra.instruments, &options.song_properties);
+        extra
+    }
+
+    /// Returns the data with `entry` appended to the edit history, starting a new one if there is
+    /// none.
+    ///
+    /// If the history is full the oldest entry is dropped.
+    fn with_edit_history(self, entry: EditHistoryEntry) -> Extra<'a> {
+        let mut entries = self.edit_history.as_deref().unwrap_or_default();
+        if entries.len() / EDIT_HISTORY_ENTRY_SIZE >= usize::from(u16::MAX) {
+            entries = &entries[EDIT_HISTORY_ENTRY_SIZE..];
+        }
+        let mut history = Vec::with_capacity(entries.len() + EDIT_HISTORY_ENTRY_SIZE);
+        history.extend_from_slice(entries);
+        history.extend_from_slice(&entry.to_bytes());
+        Extra { edit_history: Some(Cow::Owned(history)), ..self }
+    }
+
+    /// Size of the data written after the offset tables when the MIDI configuration is written
+    /// or not
+    fn header_size(&self, midi_config: bool) -> usize {
+        let edit_history = self.edit_history.as_ref().map_or(0, |entries| 2 + entries.len());
+        let midi_config = if midi_config { self.midi_config.map_or(0, <[u8]>::len) } else { 0 };
+        edit_history + midi_config + self.header.len()
+    }
+}
+
+/// Module laid out into a file
+///
+/// Everything up to the sample data is serialized into `head`, the sample data is written from
+/// the module (or from the compressed copy) when emitting the file.
+struct Layout<'m> {
+    module: &'m Module,
+
+    /// Header, offset tables, message, instrument headers, sample headers and patterns
+    head: Vec<u8>,
+
+    /// Data of each sample as it's stored
+    sample_data: Vec<StoredData>,
+
+    /// Characters lost when encoding the message
+    message_lossy: cp437::Lossy,
+
+    trailing: &'m [u8],
+
+    /// Size of the whole file
+    size: usize,
+}
+
+fn write_module(module: &Module, options: &WriteOptions, extra: Extra) -> Result<(Vec<u8>, WriteReport), WriteError> {
+    let (module, mut adjustments) = compat::adjust(module, options.target);
+    let (extra, removed) = compat::adjust_extra(extra.with_options(options), options.target);
+    adjustments.extend(removed);
+    let layout = layout(&module, options, &extra)?;
+    let mut out = Vec::with_capacity(layout.size);
+    // Writing into a `Vec` never fails.
+    layout.write(&mut out).unwrap();
+    debug_assert_eq!(out.len(), layout.size);
+    Ok((out, layout.report(adjustments)))
+}
+
+fn layout<'m>(module: &'m Module, options: &WriteOptions, extra: &'m Extra) -> Result<Layout<'m>, WriteError> {
+    check_count("orders", module.orders.len(), 256)?;
+    check_count("instruments", module.instruments.len(), 99)?;
+    check_count("samples", module.samples.len(), 99)?;
+    check_count("patterns", module.patterns.len(), 200)?;
+    for (idx, instrument) in (0..).zip(&module.instruments) {
+        let instrument_id = InstrumentId::from_index(idx).unwrap();
+        check_envelopes(instrument, Some(instrument_id))?;
+    }
+    if !options.strip_sample_data {
+        for (idx, sample) in (0..).zip(&module.samples) {
+            check_sample_data(sample, Some(SampleId::from_index(idx).unwrap()))?;
+        }
+    }
+
+    let (message, message_lossy) = message(&module.message)?;
+    let patterns = (0..)
+        .zip(&module.patterns)
+        .map(|(idx, pat)| {
+            let pattern_id = PatternId::from_index(idx).unwrap();
+            if is_empty_pattern(pat) {
+                Ok(None)
+            } else {
+                pattern::pattern(pat, pattern_id).map(Some)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let sample_data = (0..)
+        .zip(&module.samples)
+        .map(|(idx, sample)| {
+            if options.strip_sample_data {
+                StoredData::Stripped
+            } else {
+                stored_sample_data(sample, options.compression_for(SampleId::from_index(idx).unwrap()))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // The MIDI configuration is only stored in the preserved data, if the module stops using it
+    // it's left out.
+    let midi_config = module.flags.contains(ModuleFlags::MIDI_CONIFG_EMBEDDED) && extra.midi_config.is_some();
+
+    // Lay out the file, all the sizes are known at this point.
+    let mut size = MODULE_HEADER_SIZE
+        + module.orders.len()
+        + 4 * (module.instruments.len() + module.samples.len() + module.patterns.len())
+        + extra.header_size(midi_config);
+
+    let message_offset = if message.is_empty() { 0 } else { size };
+    size += message.len();
+
+    let instrument_offsets = (0..module.instruments.len())
+        .map(|idx| size + idx * INSTRUMENT_SIZE)
+        .collect::<Vec<_>>();
+    size += module.instruments.len() * INSTRUMENT_SIZE;
+
+    let sample_offsets = (0..module.samples.len())
+        .map(|idx| size + idx * SAMPLE_HEADER_SIZE)
+        .collect::<Vec<_>>();
+    size += module.samples.len() * SAMPLE_HEADER_SIZE;
+
+    let mut pattern_offsets = Vec::with_capacity(patterns.len());
+    for data in &patterns {
+        match data {
+            Some(data) => {
+                pattern_offsets.push(size);
+                size += data.len();
+            }
+            None => pattern_offsets.push(0),
+        }
+    }
+    let head_size = size;
+
+    let mut data_offsets = Vec::with_capacity(module.samples.len());
+    for data in &sample_data {
+        data_offsets.push(size);
+        size += data.size();
+    }
+    size += extra.trailing.len();
+
+    // Every offset is smaller than the file size, checking the size is enough.
+    if u32::try_from(size).is_err() {
+        return Err(WriteError::FileTooLarge { size });
+    }
+    let offset = |offset: usize| u32::try_from(offset).unwrap();
+
+    let mut flags = module.flags;
+    flags.set(ModuleFlags::MESSAGE_ATTACHED, !message.is_empty());
+    flags.set(ModuleFlags::MIDI_CONIFG_EMBEDDED, midi_config);
+    let history_flag = if extra.edit_history.is_some() { SPECIAL_EDIT_HISTORY } else { 0 };
+
+    let mut out = Vec::with_capacity(head_size);
+    out.extend_from_slice(b"IMPM");
+    out.extend_from_slice(&module.name.bytes);
+    out.push(module.highlight.1);
+    out.push(module.highlight.0);
+    for count in [module.orders.len(), module.instruments.len(), module.samples.len(), module.patterns.len()] {
+        out.extend_from_slice(&u16::try_from(count).unwrap().to_le_bytes());
+    }
+    out.extend_from_slice(&module.made_with_version.to_le_bytes());
+    out.extend_from_slice(&module.compatible_with_version.to_le_bytes());
+    let (flags, special) = flags.to_parts();
+    out.extend_from_slice(&(flags | extra.flags.0).to_le_bytes());
+    out.extend_from_slice(&(special | extra.flags.1 | history_flag).to_le_bytes());
+    out.push(module.global_volume.as_u8());
+    out.push(module.sample_volume.as_u8());
+    out.push(module.speed.as_u8());
+    out.push(module.tempo.as_u8());
+    out.push(module.pan_separation.as_u8());
+    out.push(module.pitch_wheel_depth);
+    out.extend_from_slice(&u16::try_from(message.len()).unwrap().to_le_bytes());
+    out.extend_from_slice(&offset(message_offset).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend(module.channels.iter().map(channel_panning));
+    out.extend(module.channels.iter().map(|channel| channel.volume.as_u8()));
+    debug_assert_eq!(out.len(), MODULE_HEADER_SIZE);
+
+    out.extend(module.orders.iter().map(order));
+    for list in [&instrument_offsets, &sample_offsets, &pattern_offsets] {
+        for &value in list {
+            out.extend_from_slice(&offset(value).to_le_bytes());
+        }
+    }
+    if let Some(entries) = &extra.edit_history {
+        let count = u16::try_from(entries.len() / EDIT_HISTORY_ENTRY_SIZE).unwrap();
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(entries);
+    }
+    if let Some(config) = extra.midi_config.filter(|_| midi_config) {
+        out.extend_from_slice(config);
+    }
+    out.extend_from_slice(extra.header);
+
+    out.extend_from_slice(&message);
+    for instrument in &module.instruments {
+        write_instrument(&mut out, instrument, instrument.number_of_samples);
+    }
+    for ((sample, data), &data_offset) in module.samples.iter().zip(&sample_data).zip(&data_offsets) {
+        write_sample_header(&mut out, sample, data, offset(data_offset));
+    }
+    for data in patterns.into_iter().flatten() {
+        out.extend_from_slice(&data);
+    }
+    debug_assert_eq!(out.len(), head_size);
+    check_offsets(&out, &sample_data, size)?;
+
+    Ok(Layout {
+        module,
+        head: out,
+        sample_data,
+        message_lossy,
+        trailing: &extra.trailing,
+        size,
+    })
+}
+
+impl Layout<'_> {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&self.head)?;
+        for (sample, data) in self.module.samples.iter().zip(&self.sample_data) {
+            data.write(out, sample)?;
+        }
+        out.write_all(self.trailing)
+    }
+
+    fn report(&self, adjustments: Vec<Adjustment>) -> WriteReport {
+        WriteReport {
+            adjustments,
+            message: self.message_lossy.clone(),
+            samples: (0..)
+                .zip(self.module.samples.iter().zip(&self.sample_data))
+                .map(|(idx, (sample, data))| SampleReport {
+                    sample: SampleId::from_index(idx).unwrap(),
+                    compression: data.compression(),
+                    raw_size: raw_sample_data_size(sample),
+                    stored_size: data.size(),
+                })
+                .collect(),
+        }
+    }
+}
+
+
+/// Checks that all the offsets in the serialized `head` point to structures inside the file.
+///
+/// The offsets are read back from the bytes, so this catches the layout and the serialized header
+/// disagreeing as well as offsets outside the file. All structures but the sample data are part of
+/// `head`.
+fn check_offsets(head: &[u8], sample_data: &[StoredData], size: usize) -> Result<(), WriteError> {
+    let u16_at = |at: usize| usize::from(u16::from_le_bytes([head[at], head[at + 1]]));
+    let u32_at = |at: usize| usize::try_from(u32::from_le_bytes([head[at], head[at + 1], head[at + 2], head[at + 3]])).unwrap();
+    let check = |structure: &'static str, offset: usize, length: usize, end: usize| {
+        if offset + length <= end {
+            Ok(())
+        } else {
+            Err(WriteError::InvalidOffset { structure, offset, length, size })
+        }
+    };
+
+    if u16_at(0x36) > 0 {
+        check("message", u32_at(0x38), u16_at(0x36), head.len())?;
+    }
+
+    let (orders, instruments, samples, patterns) = (u16_at(0x20), u16_at(0x22), u16_at(0x24), u16_at(0x26));
+    let table = MODULE_HEADER_SIZE + orders;
+    for idx in 0..instruments {
+        check("instrument", u32_at(table + 4 * idx), INSTRUMENT_SIZE, head.len())?;
+    }
+    let table = table + 4 * instruments;
+    for (idx, data) in (0..samples).zip(sample_data) {
+        let offset = u32_at(table + 4 * idx);
+        check("sample header", offset, SAMPLE_HEADER_SIZE, head.len())?;
+        let data_offset = u32_at(offset + 0x48);
+        if data_offset != 0 {
+            check("sample data", data_offset, data.size(), size)?;
+        }
+    }
+    let table = table + 4 * samples;
+    for idx in 0..patterns {
+        let offset = u32_at(table + 4 * idx);
+        if offset != 0 {
+            // The length is read from the pattern header.
+            check("pattern", offset, PATTERN_HEADER_SIZE, head.len())?;
+            check("pattern", offset, PATTERN_HEADER_SIZE + u16_at(offset), head.len())?;
+        }
+    }
+    Ok(())
+}
+
+fn check_count(kind: &'static str, count: usize, max: usize) -> Result<(), WriteError> {
+    if count > max {
+        Err(WriteError::TooManyItems { kind, count, max })
+    } else {
+        Ok(())
+    }
+}
+
+fn check_envelopes(instrument: &Instrument, instrument_id: Option<InstrumentId>) -> Result<(), WriteError> {
+    let envelopes = [
+        &instrument.volume_envelope,
+        &instrument.panning_envelope,
+        &instrument.pitch_filter_envelope,
+    ];
+    match envelopes.iter().find(|envelope| envelope.nodes.len() > ENVELOPE_NODES) {
+        Some(envelope) => Err(WriteError::TooManyNodes {
+            instrument: instrument_id,
+            nodes: envelope.nodes.len(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the [lazy data](Sample::lazy_data) of `sample` can be decoded.
+fn check_sample_data(sample: &Sample, sample_id: Option<SampleId>) -> Result<(), WriteError> {
+    if sample.lazy_data.is_some() && sample.decoded_data().is_none() {
+        Err(WriteError::InvalidSampleData { sample: sample_id })
+    } else {
+        Ok(())
+    }
+}
+
+/// Pattern which Impulse Tracker stores without any data
+fn is_empty_pattern(pattern: &Pattern) -> bool {
+    pattern.rows.len() == 64 && pattern.rows.iter().all(|row| row.iter().next().is_none())
+}
+
+/// Encodes the song message including the null terminator, empty message is not stored at all.
+///
+/// Nulls inside the message would end it early, they are replaced like the characters which
+/// can't be encoded.
+fn message(message: &str) -> Result<(Vec<u8>, cp437::Lossy), WriteError> {
+    let mut lossy = cp437::Lossy::default();
+    if message.is_empty() {
+        return Ok((Vec::new(), lossy));
+    }
+    let mut bytes = message
+        .chars()
+        .map(|ch| match cp437::encode_char(ch) {
+            Some(byte) if byte != b'\0' => byte,
+            _ => {
+                lossy.replaced.push(ch);
+                cp437::REPLACEMENT
+            }
+        })
+        .collect::<Vec<u8>>();
+    bytes.push(b'\0');
+    if u16::try_from(bytes.len()).is_err() {
+        return Err(WriteError::MessageTooLong { length: bytes.len() });
+    }
+    Ok((bytes, lossy))
+}
+
+fn channel_panning(channel: &ChannelSettings) -> u8 {
+    const MUTED: u8 = 128;
+    const SURROUND: u8 = 100;
+
+    let pan = match channel.panning {
+        ChannelPanning::Position(position) => position.as_u8(),
+        ChannelPanning::Surround => SURROUND,
+    };
+    if channel.muted { pan | MUTED } else { pan }
+}
+
+fn order(order: &Order) -> u8 {
+    match order {
+        Order::Index(pattern) => pattern.as_u8(),
+        Order::Separator => 254,
+        Order::EndOfSong => 255,
+    }
+}
+
+fn write_instrument(out: &mut Vec<u8>, instrument: &Instrument, number_of_samples: u8) {
+    let flags = instrument.flags;
+    let dfp = if flags.contains(InstrumentFlags::ENABLE_PANNING) { 0 } else { Instrument::dfp_ignorePanning };
+    let ifc = if flags.contains(InstrumentFlags::ENABLE_FILTER_CUTOFF) { Instrument::ifc_enableCutoff } else { 0 };
+    let ifr = if flags.contains(InstrumentFlags::ENABLE_FILTER_RESONANCE) { Instrument::ifr_enableResonance } else { 0 };
+
+    out.extend_from_slice(b"IMPI");
+    out.extend_from_slice(&instrument.filename.bytes);
+    out.push(u8::from(instrument.new_note_action));
+    out.push(u8::from(instrument.duplicate_check_type));
+    out.push(u8::from(instrument.duplicate_check_action));
+    out.extend_from_slice(&u16::from(instrument.instrument_fadeout).to_le_bytes());
+    out.extend_from_slice(&instrument.pitch_pan_separation.to_le_bytes());
+    out.push(instrument.pitch_pan_centre);
+    out.push(instrument.global_volume);
+    out.push(instrument.default_panning.as_u8() | dfp);
+    out.push(instrument.random_volume_variation.as_u8());
+    out.push(instrument.random_panning_variation.as_u8());
+    out.extend_from_slice(&instrument.trkver.to_le_bytes());
+    out.push(number_of_samples);
+    out.push(0);
+    out.extend_from_slice(&instrument.name.bytes);
+    out.push(instrument.initial_filter_cutoff.as_u8() | ifc);
+    out.push(instrument.initial_filter_resonance.as_u8() | ifr);
+    out.push(instrument.mch);
+    out.push(instrument.mpr);
+    out.extend_from_slice(&instrument.mbank);
+    for (_, translation, sample) in instrument.sample_map.iter() {
+        out.push(u8::from(translation));
+        out.push(sample.map_or(0, SampleId::number));
+    }
+    write_envelope(out, &instrument.volume_envelope);
+    write_envelope(out, &instrument.panning_envelope);
+    write_envelope(out, &instrument.pitch_filter_envelope);
+    out.extend_from_slice(&[0; 4]);
+}
+
+fn write_envelope(out: &mut Vec<u8>, envelope: &Envelope) {
+    let envelope_loop = envelope.envelope_loop.unwrap_or(EnvelopeLoop { start: 0, end: 0 });
+    let sustain_loop = envelope.sustain_loop.unwrap_or(EnvelopeLoop { start: 0, end: 0 });
+
+    out.push(envelope.flags.bits());
+    out.push(u8::try_from(envelope.nodes.len()).unwrap());
+    out.push(envelope_loop.start);
+    out.push(envelope_loop.end);
+    out.push(sustain_loop.start);
+    out.push(sustain_loop.end);
+    for idx in 0..ENVELOPE_NODES {
+        let node = envelope.nodes.as_slice().get(idx).copied().unwrap_or(Node { value: 0, tick: 0 });
+        out.extend_from_slice(&node.value.to_le_bytes());
+        out.extend_from_slice(&node.tick.to_le_bytes());
+    }
+    out.push(0);
+}
+
+fn sample_flags(sample: &Sample, stored: &StoredData) -> SampleFlags {
+    let mut flags = SampleFlags::DATA_SIGNED;
+    if let Some(data) = sample.decoded_data() {
+        flags.set(SampleFlags::DATA_PRESENT, !matches!(stored, StoredData::Stripped));
+        flags.set(SampleFlags::DATA_16BIT, data.is_16bit());
+    }
+    match stored.compression() {
+        Compression::None => {}
+        Compression::It214 => flags |= SampleFlags::COMPRESSED,
+        Compression::It215 => flags |= SampleFlags::COMPRESSED | SampleFlags::DELTA,
+    }
+    if let Some(loop_) = &sample.loop_ {
+        flags |= SampleFlags::LOOP;
+        flags.set(SampleFlags::BIDI_LOOP, loop_.bidi);
+    }
+    if let Some(sustain_loop) = &sample.sustain_loop {
+        flags |= SampleFlags::SUSTAIN;
+        flags.set(SampleFlags::BIDI_SUSTAIN, sustain_loop.bidi);
+    }
+    flags
+}
+
+fn write_sample_header(out: &mut Vec<u8>, sample: &Sample, stored: &StoredData, data_offset: u32) {
+    let flags = sample_flags(sample, stored);
+    let data_offset = if flags.contains(SampleFlags::DATA_PRESENT) { data_offset } else { 0 };
+    let (flags, cvt) = flags.to_parts();
+    let loop_ = sample.loop_.map_or((0, 0), |loop_| (loop_.start, loop_.end));
+    let sustain_loop = sample.sustain_loop.map_or((0, 0), |loop_| (loop_.start, loop_.end));
+
+    out.extend_from_slice(b"IMPS");
+    out.extend_from_slice(&sample.filename.bytes);
+    out.push(sample.global_volume);
+    out.push(flags);
+    out.push(sample.default_volume);
+    out.extend_from_slice(&sample.name.bytes);
+    out.push(cvt);
+    out.push(sample.default_panning);
+    for value in [
+        sample.length(),
+        loop_.0,
+        loop_.1,
+        sample.samplerate_c5,
+        sustain_loop.0,
+        sustain_loop.1,
+        data_offset,
+    ] {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+    out.push(sample.vibrato_speed);
+    out.push(sample.vibrato_depth);
+    out.push(sample.vibrato_rate);
+    out.push(sample.vibrato_type);
+}
+
+/// Sample data in the form it's stored in the file
+enum StoredData {
+    /// Raw PCM data, written directly from the sample
+    Raw(usize),
+
+    /// Compressed data
+    Compressed(Compression, Vec<u8>),
+
+    /// Data left out, see [`WriteOptions::strip_sample_data`]
+    Stripped,
+}
+
+fn stored_sample_data(sample: &Sample, compression: Compression) -> StoredData {
+    let (samples, bits) = match sample.decoded_data() {
+        Some(data) if !data.is_empty() && compression != Compression::None => match data {
+            SampleData::Pcm8(data) => (data.iter().map(|&x| i32::from(x)).collect::<Vec<_>>(), 8),
+            SampleData::Pcm16(data) => (data.iter().map(|&x| i32::from(x)).collect::<Vec<_>>(), 16),
+        },
+        _ => return StoredData::Raw(raw_sample_data_size(sample)),
+    };
+    let data = compression::compress(&samples, bits, compression == Compression::It215);
+    StoredData::Compressed(compression, data)
+}
+
+impl StoredData {
+    fn size(&self) -> usize {
+        match self {
+            StoredData::Raw(size) => *size,
+            StoredData::Compressed(_, data) => data.len(),
+            StoredData::Stripped => 0,
+        }
+    }
+
+    fn compression(&self) -> Compression {
+        match self {
+            StoredData::Raw(_) | StoredData::Stripped => Compression::None,
+            StoredData::Compressed(compression, _) => *compression,
+        }
+    }
+
+    fn write(&self, out: &mut impl Write, sample: &Sample) -> io::Result<()> {
+        match (self, sample.decoded_data()) {
+            (StoredData::Compressed(_, data), _) => out.write_all(data),
+            (StoredData::Raw(_), Some(SampleData::Pcm8(data))) => write_pcm(out, data, i8::to_le_bytes),
+            (StoredData::Raw(_), Some(SampleData::Pcm16(data))) => write_pcm(out, data, i16::to_le_bytes),
+            (StoredData::Raw(_), None) | (StoredData::Stripped, _) => Ok(()),
+        }
+    }
+}
+
+/// Writes PCM data converted to bytes through a small buffer.
+fn write_pcm<T: Copy, const N: usize>(out: &mut impl Write, data: &[T], to_bytes: fn(T) -> [u8; N]) -> io::Result<()> {
+    const BUFFER_SIZE: usize = 0x10000;
+
+    let mut buffer = Vec::with_capacity(BUFFER_SIZE.min(N * data.len()));
+    for chunk in data.chunks(BUFFER_SIZE / N) {
+        buffer.clear();
+        buffer.extend(chunk.iter().flat_map(|&x| to_bytes(x)));
+        out.write_all(&buffer)?;
+    }
+    Ok(())
+}
+
+fn raw_sample_data_size(sample: &Sample) -> usize {
+    match sample.decoded_data() {
+        Some(SampleData::Pcm8(data)) => data.len(),
+        Some(SampleData::Pcm16(data)) => 2 * data.len(),
+        None => 0,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diff::diff;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    fn roundtrip(module: &Module) -> Module {
+        let bytes = module_file(module).unwrap();
+        parser::module_file::<VerboseError<&[u8]>>(&bytes).unwrap()
+    }
+
+    #[test]
+    fn module_roundtrip() {
+        for data in [
+            &include_bytes!("../tests/effect_alphabet.it")[..],
+            &include_bytes!("../tests/song_message.it")[..],
+        ] {
+            let module = parser::module_file::<VerboseError<&[u8]>>(data).unwrap();
+            let written = roundtrip(&module);
+            assert_eq!(diff(&module, &written), Vec::new());
+            assert_eq!(written, module);
+        }
+    }
+
+    #[test]
+    fn module_samples() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap();
+        module.samples = instrument.samples;
+        module.samples[0].set_loop(Some(SampleLoop { start: 1, end: 100, bidi: true })).unwrap();
+        module.instruments = vec![instrument.instrument];
+
+        let written = roundtrip(&module);
+        assert_eq!(diff(&module, &written), Vec::new());
+    }
+
+    #[test]
+    fn offsets() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.samples.clear();
+        let mut bytes = module_file(&module).unwrap();
+        let size = bytes.len();
+        assert_eq!(check_offsets(&bytes, &[], size), Ok(()));
+
+        // Point the first pattern past the end of the file.
+        let count = |at: usize| usize::from(u16::from_le_bytes([bytes[at], bytes[at + 1]]));
+        let table = MODULE_HEADER_SIZE + count(0x20) + 4 * count(0x22);
+        bytes[table..table + 4].copy_from_slice(&u32::try_from(size).unwrap().to_le_bytes());
+        assert_eq!(
+            check_offsets(&bytes, &[], size),
+            Err(WriteError::InvalidOffset { structure: "pattern", offset: size, length: PATTERN_HEADER_SIZE, size }),
+        );
+    }
+
+    #[test]
+    fn preserving() {
+        for data in [
+            &include_bytes!("../tests/effect_alphabet.it")[..],
+            &include_bytes!("../tests/song_message.it")[..],
+        ] {
+            let (mut module, preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(data).unwrap();
+            assert_eq!(module_file_preserving(&module, &preserved).unwrap(), data);
+
+            module.set_speed(1).unwrap();
+            let written = module_file_preserving(&module, &preserved).unwrap();
+            assert!(written.ends_with(&preserved.trailing));
+            let (reparsed, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&written).unwrap();
+            assert_eq!(reparsed, module);
+            assert_eq!(represerved.header_extra, preserved.header_extra);
+            assert_eq!(represerved.trailing, preserved.trailing);
+            assert_eq!(represerved.unknown_flags, preserved.unknown_flags);
+        }
+    }
+
+    #[test]
+    fn offsets_after_edit() {
+        let data = include_bytes!("../tests/song_message.it");
+        let (mut module, preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(data).unwrap();
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap();
+
+        // Grow the first pattern and the samples, everything after them has to move.
+        let pattern = &mut module.patterns[0];
+        let row = pattern.rows[0].clone();
+        pattern.rows.extend(std::iter::repeat(row).take(100));
+        module.samples.splice(0..0, instrument.samples);
+
+        let written = module_file_preserving(&module, &preserved).unwrap();
+        let (reparsed, _) = parser::module_file_preserving::<VerboseError<&[u8]>>(&written).unwrap();
+        assert_eq!(reparsed, module);
+    }
+
+    #[test]
+    fn midi_config() {
+        let data = include_bytes!("../tests/midi_config.it");
+        let (module, preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(data).unwrap();
+        assert!(module.flags.contains(ModuleFlags::MIDI_CONIFG_EMBEDDED));
+        let config = preserved.midi_config().unwrap();
+        assert_eq!(config.fixed[0x10], MidiMacro::new("F0F00140"));
+
+        // Without the preserved data there is no configuration to write.
+        let written = parser::module_file::<VerboseError<&[u8]>>(&module_file(&module).unwrap()).unwrap();
+        assert!(!written.flags.contains(ModuleFlags::MIDI_CONIFG_EMBEDDED));
+        assert_eq!(written.message, module.message);
+
+        let mut edited = module.clone();
+        edited.set_speed(1).unwrap();
+        let bytes = module_file_preserving(&edited, &preserved).unwrap();
+        let (reparsed, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(reparsed, edited);
+        assert_eq!(represerved.midi_config(), Some(config));
+
+        // Clearing the flag leaves the configuration out.
+        edited.flags.remove(ModuleFlags::MIDI_CONIFG_EMBEDDED);
+        let bytes = module_file_preserving(&edited, &preserved).unwrap();
+        let (reparsed, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(reparsed, edited);
+        assert_eq!(represerved.header_extra.len(), preserved.header_extra.len() - MIDI_CONFIG_SIZE);
+    }
+
+    #[test]
+    fn compression() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap();
+        module.samples = instrument.samples;
+        // Long noisy sample spanning multiple blocks and using all the bit widths.
+        let noise = (0..100_000u32)
+            .map(|x| i16::try_from((x * 7919 % 65536) >> (x / 7000)).unwrap_or(-1))
+            .collect::<Vec<_>>();
+        let mut sample = module.samples[1].clone();
+        sample.data = Some(SampleData::from(noise));
+        module.samples.push(sample);
+
+        for compression in [Compression::It214, Compression::It215] {
+            let options = WriteOptions {
+                compression,
+                sample_compression: [(SampleId::from_index(0).unwrap(), Compression::None)].into_iter().collect(),
+                ..WriteOptions::default()
+            };
+            let (bytes, report) = module_file_with_options(&module, &options).unwrap();
+            assert_eq!(module.serialized_size(&options).unwrap(), bytes.len());
+            let written = parser::module_file::<VerboseError<&[u8]>>(&bytes).unwrap();
+            assert_eq!(written.samples, module.samples);
+
+            assert_eq!(report.samples[0].compression, Compression::None);
+            assert_eq!(report.samples[1].compression, compression);
+            assert!(report.samples[1].stored_size < report.samples[1].raw_size);
+        }
+    }
+
+    #[test]
+    fn instrument_and_sample_files() {
+        const INSTRUMENT_DATA: &[u8] = include_bytes!("../tests/compression/compressed.iti");
+
+        let file = parser::instrument_file::<VerboseError<&[u8]>>(INSTRUMENT_DATA).unwrap();
+        let written = parser::instrument_file::<VerboseError<&[u8]>>(&file.write_iti().unwrap()).unwrap();
+        assert_eq!(written, file);
+
+        for sample in &file.samples {
+            let written = parser::sample_file::<VerboseError<&[u8]>>(&sample.write_its().unwrap()).unwrap();
+            assert_eq!(&written, sample);
+        }
+
+        // Extracting from a module only embeds the samples used by the instrument.
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.samples = file.samples.clone();
+        let mut instrument = file.instrument.clone();
+        instrument.sample_map.fill_sample(SampleId::from_index(1).ok());
+        let extracted = InstrumentFile::from_module(&instrument, &module);
+        assert_eq!(extracted.samples, &file.samples[1..]);
+        let written = parser::instrument_file::<VerboseError<&[u8]>>(&instrument.write_iti(&module).unwrap()).unwrap();
+        assert_eq!(written, extracted);
+        assert_eq!(written.instrument.sample_map.sample_for(Note::C_0), SampleId::from_index(0).ok());
+    }
+
+    #[test]
+    fn reproducible() {
+        let module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/effect_alphabet.it")).unwrap();
+        let first = module_file(&module).unwrap();
+        assert_eq!(module_file(&module.clone()).unwrap(), first);
+        assert_eq!(module_file(&roundtrip(&module)).unwrap(), first);
+
+        // The order the per-sample options were inserted in doesn't matter.
+        let ids = (0..module.samples.len()).map(|idx| SampleId::from_index(u8::try_from(idx).unwrap()).unwrap());
+        let forward = WriteOptions {
+            sample_compression: ids.clone().map(|id| (id, Compression::It214)).collect(),
+            ..WriteOptions::default()
+        };
+        let backward = WriteOptions {
+            sample_compression: ids.rev().map(|id| (id, Compression::It214)).collect(),
+            ..WriteOptions::default()
+        };
+        assert_eq!(module_file_with_options(&module, &forward), module_file_with_options(&module, &backward));
+    }
+
+    #[test]
+    fn stripped_sample_data() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap();
+        module.samples = instrument.samples;
+        let sample = &mut module.samples[0];
+        sample.set_loop(Some(SampleLoop { start: 10, end: 100, bidi: false })).unwrap();
+
+        let options = WriteOptions { strip_sample_data: true, ..WriteOptions::default() };
+        let (bytes, report) = module_file_with_options(&module, &options).unwrap();
+        assert_eq!(report.stored_sample_size(), 0);
+        assert_eq!(bytes.len(), module_file(&module).unwrap().len() - report.raw_sample_size());
+
+        let written = parser::module_file::<VerboseError<&[u8]>>(&bytes).unwrap();
+        let map = parser::byte_map::<VerboseError<&[u8]>>(&bytes).unwrap();
+        for (idx, (written, sample)) in written.samples.iter().zip(&module.samples).enumerate() {
+            assert_eq!(written.data, None);
+            assert_eq!(written.length(), 0);
+            assert_eq!(written.name, sample.name);
+            assert_eq!(written.filename, sample.filename);
+            assert_eq!(written.samplerate_c5, sample.samplerate_c5);
+            assert_eq!(written.loop_, sample.loop_);
+            assert_eq!(written.sustain_loop, sample.sustain_loop);
+            assert_eq!(written.vibrato_depth, sample.vibrato_depth);
+
+            // The length is only left in the header.
+            let path = format!("sample[{idx}].header.length");
+            let region = map.iter().find(|region| region.path == path).unwrap();
+            let length = u32::from_le_bytes(bytes[region.range.clone()].try_into().unwrap());
+            assert_eq!(length, sample.length());
+        }
+    }
+
+    #[test]
+    fn message_report() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.message = "Naïve ♪\0end".to_owned();
+        let (bytes, report) = module_file_with_options(&module, &WriteOptions::default()).unwrap();
+        assert_eq!(report.message.replaced, vec!['♪', '\0']);
+        assert_eq!(parser::module_file::<VerboseError<&[u8]>>(&bytes).unwrap().message, "Naïve ??end");
+    }
+
+    #[test]
+    fn edit_history() {
+        let data = include_bytes!("../tests/effect_alphabet.it");
+        let (module, preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(data).unwrap();
+        let entry = EditHistoryEntry::new((2021, 3, 14), (15, 9, 26), Duration::from_secs(600));
+        assert_eq!(entry, EditHistoryEntry { dos_date: 0x526E, dos_time: 0x792D, run_time: 10923 });
+        let options = WriteOptions { edit_history: Some(entry), ..WriteOptions::default() };
+
+        let (bytes, _) = module_file_preserving_with_options(&module, &preserved, &options).unwrap();
+        let (reparsed, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(reparsed, module);
+        let history = &represerved.header_extra;
+        assert_eq!(history[..2], [10, 0]);
+        assert_eq!(history[2..74], preserved.header_extra[2..74]);
+        assert_eq!(history[74..82], entry.to_bytes());
+        assert_eq!(history[82..], preserved.header_extra[74..]);
+
+        let (bytes, _) = module_file_with_options(&module, &options).unwrap();
+        let (_, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(represerved.header_extra[..2], [1, 0]);
+        assert_eq!(represerved.header_extra[2..], entry.to_bytes());
+        assert_eq!(represerved.unknown_flags.1, SPECIAL_EDIT_HISTORY);
+    }
+
+    #[test]
+    fn targets() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.tempo = RangedU8::try_from(31).unwrap();
+        module.compatible_with_version = 0x0888;
+        let command = |effect| Command { effect: Some(effect), ..Command::EMPTY };
+        let row = Row::from_vec(vec![
+            (Channel::from_u8_index(0), command(EffectCmd::Special(Some(Special::SetDirection(PlayDirection::Backward))))),
+            (Channel::from_u8_index(1), command(EffectCmd::Special(Some(Special::SetSurround(false))))),
+            (Channel::from_u8_index(2), command(EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap()))),
+        ]);
+        let pattern = |rows| Pattern { active_channels: ActiveChannels::all(), rows: vec![row.clone(); rows] };
+        module.patterns = vec![pattern(10), pattern(300)];
+        module.orders = vec![Order::Index(PatternId::from_index(0).unwrap())];
+
+        let options = |target| WriteOptions { target: Some(target), ..WriteOptions::default() };
+        let (bytes, report) = module_file_with_options(&module, &options(Target::ImpulseTracker214)).unwrap();
+        let pattern_0 = PatternId::from_index(0).unwrap();
+        let pattern_1 = PatternId::from_index(1).unwrap();
+        assert_eq!(report.adjustments, [
+            Adjustment::Tempo { tempo: 31, adjusted: 32 },
+            Adjustment::CompatibleWithVersion { version: 0x0888, adjusted: 0x0214 },
+            Adjustment::EffectsRemoved { pattern: pattern_0, count: 20 },
+            Adjustment::PatternRows { pattern: pattern_0, rows: 10, adjusted: 32 },
+            Adjustment::EffectsRemoved { pattern: pattern_1, count: 600 },
+            Adjustment::PatternRows { pattern: pattern_1, rows: 300, adjusted: 200 },
+        ]);
+        let written = parser::module_file::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(written.tempo.as_u8(), 32);
+        assert_eq!(written.patterns[0].rows.len(), 32);
+        assert_eq!(written.patterns[1].rows.len(), 200);
+        let effects = written.patterns[0].rows[0].iter().map(|(_, command)| command.effect).collect::<Vec<_>>();
+        assert_eq!(effects, [None, None, Some(EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap()))]);
+
+        let (_, report) = module_file_with_options(&module, &options(Target::OpenMPT)).unwrap();
+        assert!(report.adjustments.is_empty());
+        assert_eq!(module_file_with_options(&module, &WriteOptions::default()).unwrap().1.adjustments, []);
+    }
+
+    #[test]
+    fn target_extensions() {
+        let data = include_bytes!("../tests/effect_alphabet.it");
+        let (module, mut preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(data).unwrap();
+        let history = preserved.header_extra.clone();
+        // Pattern names OpenMPT stores after the edit history.
+        preserved.header_extra.extend_from_slice(b"PNAM\x20\x00\x00\x00");
+        preserved.header_extra.extend_from_slice(&[b'a'; 32]);
+        assert!(!preserved.trailing.is_empty());
+
+        let options = |target| WriteOptions { target: Some(target), ..WriteOptions::default() };
+        let (bytes, report) = module_file_preserving_with_options(&module, &preserved, &options(Target::ImpulseTracker214)).unwrap();
+        assert!(report.adjustments.contains(&Adjustment::ExtensionsRemoved { header: 40, trailing: preserved.trailing.len() }));
+        let (_, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(represerved.header_extra, history);
+        assert!(represerved.trailing.is_empty());
+
+        let (bytes, report) = module_file_preserving_with_options(&module, &preserved, &options(Target::OpenMPT)).unwrap();
+        assert!(report.adjustments.is_empty());
+        let (_, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(represerved.header_extra, preserved.header_extra);
+        assert_eq!(represerved.trailing, preserved.trailing);
+    }
+
+    #[test]
+    fn song_properties() {
+        let module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        let options = WriteOptions {
+            song_properties: vec![SongProperty::Tempo(300), SongProperty::TempoMode(TempoMode::Modern { rows_per_beat: 6 })],
+            ..WriteOptions::default()
+        };
+        let (bytes, _) = module_file_with_options(&module, &options).unwrap();
+        assert_eq!(module.serialized_size(&options).unwrap(), bytes.len());
+        let (reparsed, preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(reparsed, module);
+        assert_eq!(preserved.trailing[..14], *b"STPM..TD\x04\x00\x2c\x01\x00\x00");
+        assert_eq!(TempoMode::detect(&reparsed, &preserved), TempoMode::Modern { rows_per_beat: 6 });
+
+        // The properties replace the ones in the chunks saved by OpenMPT, the other chunks stay.
+        let (module, preserved) = parser::module_file_preserving::<VerboseError<&[u8]>>(include_bytes!("../tests/effect_alphabet.it")).unwrap();
+        assert_eq!(TempoMode::detect(&module, &preserved), TempoMode::Classic);
+        let options = WriteOptions {
+            song_properties: vec![SongProperty::TempoMode(TempoMode::Alternative)],
+            ..WriteOptions::default()
+        };
+        let (bytes, _) = module_file_preserving_with_options(&module, &preserved, &options).unwrap();
+        let (reparsed, represerved) = parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap();
+        assert_eq!(TempoMode::detect(&reparsed, &represerved), TempoMode::Alternative);
+        // `TM..` grows from 1 to 4 bytes.
+        assert_eq!(represerved.trailing.len(), preserved.trailing.len() + 3);
+
+        let options = WriteOptions { target: Some(Target::Schism), ..options };
+        let (bytes, report) = module_file_preserving_with_options(&module, &preserved, &options).unwrap();
+        assert!(report.adjustments.contains(&Adjustment::ExtensionsRemoved { header: 0, trailing: preserved.trailing.len() + 3 }));
+        assert!(parser::module_file_preserving::<VerboseError<&[u8]>>(&bytes).unwrap().1.trailing.is_empty());
+    }
+
+    #[test]
+    fn streaming() {
+        struct Chunked(Vec<u8>);
+
+        impl Write for Chunked {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                // Accept at most a few bytes at a time to exercise `write_all`.
+                let len = buf.len().min(7);
+                self.0.extend_from_slice(&buf[..len]);
+                Ok(len)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../tests/compression/compressed.iti")).unwrap();
+        module.samples = instrument.samples;
+
+        let options = WriteOptions::default();
+        let mut out = Chunked(Vec::new());
+        let report = module_file_to_writer(&module, &options, &mut out).unwrap();
+        assert_eq!((out.0, report), module_file_with_options(&module, &options).unwrap());
+    }
+
+    #[test]
+    fn limits() {
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(include_bytes!("../tests/song_message.it")).unwrap();
+        module.orders = vec![Order::Separator; 257];
+        assert_eq!(
+            module_file(&module),
+            Err(WriteError::TooManyItems { kind: "orders", count: 257, max: 256 }),
+        );
+    }
+}
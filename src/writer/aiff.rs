@@ -0,0 +1,146 @@
+//! Export of samples to AIFF files
+
+use super::write_pcm;
+use crate::data::*;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+
+const COMM_SIZE: u32 = 18;
+const INST_SIZE: u32 = 20;
+
+/// MIDI note the sample plays unchanged at, IT `C-5`
+const BASE_NOTE: u8 = 60;
+
+/// Loop play modes
+const NO_LOOPING: u16 = 0;
+const FORWARD_LOOPING: u16 = 1;
+const FORWARD_BACKWARD_LOOPING: u16 = 2;
+
+
+impl Sample {
+    /// Writes the sample as an AIFF file
+    ///
+    /// The sample is written in its own bit depth with the C-5 frequency as the sample rate. The
+    /// loops are stored as markers referenced from the `INST` chunk: the sustain loop becomes the
+    /// AIFF sustain loop and the loop the release loop. Samples with only a loop store it as the
+    /// sustain loop, which samplers play from the start of the note.
+    pub fn write_aiff(&self, out: &mut impl Write) -> io::Result<()> {
+        let bits: u16 = match &self.data {
+            Some(SampleData::Pcm16(_)) => 16,
+            _ => 8,
+        };
+        let frames = self.length();
+        let data_size = frames
+            .checked_mul(u32::from(bits / 8))
+            .filter(|&size| size <= u32::MAX - 0x1000)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "sample data too large for an AIFF file"))?;
+
+        let (sustain_loop, release_loop) = match (self.sustain_loop, self.loop_) {
+            (None, loop_) => (loop_, None),
+            loops => loops,
+        };
+        let loops = [sustain_loop, release_loop];
+
+        let mut chunks = Vec::new();
+
+        chunks.extend_from_slice(b"COMM");
+        chunks.extend_from_slice(&COMM_SIZE.to_be_bytes());
+        chunks.extend_from_slice(&1u16.to_be_bytes());
+        chunks.extend_from_slice(&frames.to_be_bytes());
+        chunks.extend_from_slice(&bits.to_be_bytes());
+        chunks.extend_from_slice(&extended(self.samplerate_c5.max(1)));
+
+        // Each loop uses a begin and an end marker, numbered from 1.
+        let markers = loops
+            .iter()
+            .flatten()
+            .flat_map(|sample_loop| [sample_loop.start, sample_loop.end])
+            .collect::<Vec<_>>();
+        if !markers.is_empty() {
+            let mut mark = Vec::new();
+            mark.extend_from_slice(&u16::try_from(markers.len()).unwrap().to_be_bytes());
+            for (id, position) in (1u16..).zip(markers) {
+                mark.extend_from_slice(&id.to_be_bytes());
+                mark.extend_from_slice(&position.to_be_bytes());
+                // Empty name, padded to an even length.
+                mark.extend_from_slice(&[0, 0]);
+            }
+            chunks.extend_from_slice(b"MARK");
+            chunks.extend_from_slice(&u32::try_from(mark.len()).unwrap().to_be_bytes());
+            chunks.extend_from_slice(&mark);
+        }
+
+        chunks.extend_from_slice(b"INST");
+        chunks.extend_from_slice(&INST_SIZE.to_be_bytes());
+        // Base note, detune, note range and velocity range.
+        chunks.extend_from_slice(&[BASE_NOTE, 0, 0, 127, 1, 127]);
+        chunks.extend_from_slice(&0i16.to_be_bytes());
+        let mut marker = 1u16;
+        for sample_loop in loops {
+            let mode = match sample_loop {
+                None => NO_LOOPING,
+                Some(SampleLoop { bidi: false, .. }) => FORWARD_LOOPING,
+                Some(SampleLoop { bidi: true, .. }) => FORWARD_BACKWARD_LOOPING,
+            };
+            let (begin, end) = if sample_loop.is_some() {
+                marker += 2;
+                (marker - 2, marker - 1)
+            } else {
+                (0, 0)
+            };
+            for value in [mode, begin, end] {
+                chunks.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+
+        let padding = data_size % 2;
+        let ssnd_size = 8 + data_size;
+        let form_size = 4 + u32::try_from(chunks.len()).unwrap() + 8 + ssnd_size + padding;
+
+        let mut header = Vec::with_capacity(chunks.len() + 28);
+        header.extend_from_slice(b"FORM");
+        header.extend_from_slice(&form_size.to_be_bytes());
+        header.extend_from_slice(b"AIFF");
+        header.extend_from_slice(&chunks);
+        header.extend_from_slice(b"SSND");
+        header.extend_from_slice(&ssnd_size.to_be_bytes());
+        // Offset and block size.
+        header.extend_from_slice(&[0; 8]);
+        out.write_all(&header)?;
+
+        // AIFF data is signed big-endian.
+        match &self.data {
+            Some(SampleData::Pcm8(data)) => write_pcm(out, data, i8::to_be_bytes)?,
+            Some(SampleData::Pcm16(data)) => write_pcm(out, data, i16::to_be_bytes)?,
+            None => {}
+        }
+        if padding != 0 {
+            out.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a positive integer as an 80-bit IEEE 754 extended precision number
+fn extended(value: u32) -> [u8; 10] {
+    let value = u64::from(value);
+    let shift = value.leading_zeros();
+    let exponent = u16::try_from(16383 + 63 - shift).unwrap();
+    let mut bytes = [0; 10];
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&(value << shift).to_be_bytes());
+    bytes
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extended_samplerate() {
+        assert_eq!(extended(44100), [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(extended(8363), [0x40, 0x0C, 0x82, 0xAC, 0, 0, 0, 0, 0, 0]);
+    }
+}
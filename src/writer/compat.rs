@@ -0,0 +1,135 @@
+use super::*;
+use std::borrow::Cow;
+use std::ops::RangeInclusive;
+
+
+impl Target {
+    /// Range of pattern lengths the tracker can load
+    pub(crate) fn rows(self) -> RangeInclusive<usize> {
+        match self {
+            Target::ImpulseTracker214 => 32..=200,
+            Target::Schism => 1..=200,
+            Target::OpenMPT => 1..=1024,
+        }
+    }
+
+    /// Lowest initial tempo the tracker accepts
+    pub(crate) fn min_tempo(self) -> u8 {
+        match self {
+            Target::ImpulseTracker214 | Target::Schism => 32,
+            Target::OpenMPT => 31,
+        }
+    }
+
+    /// Highest "Compatible With" version the tracker is known to load
+    fn max_compatible_with(self) -> Option<u16> {
+        match self {
+            Target::ImpulseTracker214 => Some(0x0214),
+            Target::Schism | Target::OpenMPT => None,
+        }
+    }
+
+    /// Returns `true` if the tracker reads the OpenMPT extensions: the pattern and channel names
+    /// and plugins after the MIDI configuration and the extension chunks at the end of the file.
+    fn reads_extensions(self) -> bool {
+        match self {
+            Target::ImpulseTracker214 | Target::Schism => false,
+            Target::OpenMPT => true,
+        }
+    }
+
+    /// Returns `true` if the tracker knows the effect.
+    pub(crate) fn supports(self, effect: &EffectCmd) -> bool {
+        match (self, effect) {
+            (Target::OpenMPT, _) => true,
+            // Only `S91` is supported in Impulse Tracker, see `Special::SetSurround`.
+            (Target::ImpulseTracker214, EffectCmd::Special(Some(Special::SetSurround(false)))) => false,
+            (_, EffectCmd::Special(Some(
+                Special::SetReverb(_) |
+                Special::SetSurroundMode(_) |
+                Special::SetFilterMode(_) |
+                Special::SetDirection(_)
+            ))) => false,
+            _ => true,
+        }
+    }
+}
+
+
+/// Adjusts `module` to fit the `target`, the module is only copied if something has to change.
+pub(super) fn adjust(module: &Module, target: Option<Target>) -> (Cow<'_, Module>, Vec<Adjustment>) {
+    let mut module = Cow::Borrowed(module);
+    let mut adjustments = Vec::new();
+    let Some(target) = target else {
+        return (module, adjustments);
+    };
+
+    let tempo = module.tempo.as_u8();
+    if tempo < target.min_tempo() {
+        module.to_mut().tempo = RangedU8::try_from(target.min_tempo()).unwrap();
+        adjustments.push(Adjustment::Tempo { tempo, adjusted: target.min_tempo() });
+    }
+
+    if let Some(max) = target.max_compatible_with() {
+        let version = module.compatible_with_version;
+        if version > max {
+            module.to_mut().compatible_with_version = max;
+            adjustments.push(Adjustment::CompatibleWithVersion { version, adjusted: max });
+        }
+    }
+
+    for idx in 0..module.patterns.len() {
+        let pattern_id = PatternId::from_index(u8::try_from(idx).unwrap()).unwrap();
+
+        let removed = module.patterns[idx].rows
+            .iter()
+            .flat_map(Row::iter)
+            .filter(|(_, command)| command.effect.as_ref().is_some_and(|effect| !target.supports(effect)))
+            .count();
+        if removed > 0 {
+            let pattern = &mut module.to_mut().patterns[idx];
+            for row in &mut pattern.rows {
+                *row = Row::from_vec(
+                    row.iter()
+                        .map(|(channel, command)| {
+                            let mut command = *command;
+                            command.effect = command.effect.filter(|effect| target.supports(effect));
+                            (channel, command)
+                        })
+                        .collect(),
+                );
+            }
+            adjustments.push(Adjustment::EffectsRemoved { pattern: pattern_id, count: removed });
+        }
+
+        let rows = module.patterns[idx].rows.len();
+        let adjusted = rows.clamp(*target.rows().start(), *target.rows().end());
+        if adjusted != rows {
+            let pattern = &mut module.to_mut().patterns[idx];
+            pattern.rows.resize(adjusted, Row::empty());
+            pattern.active_channels = ActiveChannels::new(
+                pattern.rows.iter().flat_map(|row| row.iter().map(|(chan, _)| chan))
+            );
+            adjustments.push(Adjustment::PatternRows { pattern: pattern_id, rows, adjusted });
+        }
+    }
+
+    (module, adjustments)
+}
+
+/// Removes the data of `extra` the `target` doesn't read.
+///
+/// The edit history and the MIDI configuration are kept for every target, the rest of the data
+/// after the offset tables and the data at the end of the file are OpenMPT extensions.
+pub(super) fn adjust_extra(extra: Extra<'_>, target: Option<Target>) -> (Extra<'_>, Option<Adjustment>) {
+    match target {
+        Some(target) if !target.reads_extensions() && !(extra.header.is_empty() && extra.trailing.is_empty()) => {
+            let adjustment = Adjustment::ExtensionsRemoved {
+                header: extra.header.len(),
+                trailing: extra.trailing.len(),
+            };
+            (Extra { header: &[], trailing: Cow::Borrowed(&[]), ..extra }, Some(adjustment))
+        }
+        _ => (extra, None),
+    }
+}
@@ -0,0 +1,154 @@
+use super::*;
+
+
+/// Compress samples into IT214 (or IT215 when `delta` is set) blocks
+///
+/// This is the inverse of the decompression in the parser. `bits` is the sample bit depth (8 or
+/// 16), `samples` must be in the range of that bit depth.
+///
+/// The samples are split into blocks of 0x8000 bytes of uncompressed data, each block is stored
+/// as its compressed length followed by the bit stream.
+pub(super) fn compress(samples: &[i32], bits: u32, delta: bool) -> Vec<u8> {
+    let block_samples = 0x8000 / usize::try_from(bits / 8).unwrap();
+    let mut out = Vec::new();
+    for block in samples.chunks(block_samples) {
+        let data = compress_block(block, bits, delta);
+        // With at most `bits + 1` bits per sample plus the width changes the block always fits.
+        out.extend_from_slice(&u16::try_from(data.len()).unwrap().to_le_bytes());
+        out.extend(data);
+    }
+    out
+}
+
+/// Number of upcoming samples looked at when deciding the bit width
+const LOOKAHEAD: usize = 16;
+
+fn compress_block(block: &[i32], bits: u32, delta: bool) -> Vec<u8> {
+    // The decoder integrates the values once, or twice for IT215, starting from zero in every
+    // block.
+    let mut values = differentiate(block, bits);
+    if delta {
+        values = differentiate(&values, bits);
+    }
+
+    let widths = values.iter().map(|&value| required_width(value, bits)).collect::<Vec<_>>();
+
+    let mut writer = BitWriter::default();
+    let mut width = bits + 1;
+    for (idx, &value) in values.iter().enumerate() {
+        let window = &widths[idx..(idx + LOOKAHEAD).min(widths.len())];
+        let window_width = window.iter().copied().max().unwrap();
+        let window_len = u32::try_from(window.len()).unwrap();
+
+        // Grow as soon as a value doesn't fit, shrink only if it pays off for the upcoming values.
+        let grow = widths[idx] > width;
+        let shrink = window_width < width && (width - window_width) * window_len > change_cost(width, bits);
+        if grow || shrink {
+            change_width(&mut writer, width, window_width, bits);
+            width = window_width;
+        }
+
+        let raw = if width == bits + 1 {
+            // The top bit marks a width change, the value is stored in the lower bits.
+            value & ((1 << bits) - 1)
+        } else {
+            value & ((1 << width) - 1)
+        };
+        writer.push(u32::try_from(raw).unwrap(), width);
+    }
+    writer.finish()
+}
+
+/// Differences between consecutive values, wrapped into the range of the bit depth.
+fn differentiate(values: &[i32], bits: u32) -> Vec<i32> {
+    let mut last = 0;
+    values
+        .iter()
+        .map(|&value| {
+            let difference = wrap(value - last, bits);
+            last = value;
+            difference
+        })
+        .collect()
+}
+
+fn wrap(value: i32, bits: u32) -> i32 {
+    let half = 1 << (bits - 1);
+    (value + half).rem_euclid(1 << bits) - half
+}
+
+/// Returns `true` if `value` can be stored with `width` bits without colliding with the values
+/// reserved for width changes.
+fn fits(value: i32, width: u32, bits: u32) -> bool {
+    let half = 1 << (width - 1);
+    match width {
+        // The lowest value is reserved.
+        1..=6 => -half < value && value < half,
+        // `bits` values around the sign boundary are reserved.
+        _ if width <= bits => {
+            let reserved = i32::try_from(bits / 2).unwrap();
+            -half + reserved <= value && value < half - reserved
+        }
+        // Values with the top bit set are reserved, the value uses the lower bits.
+        _ => true,
+    }
+}
+
+fn required_width(value: i32, bits: u32) -> u32 {
+    (1..=bits + 1).find(|&width| fits(value, width, bits)).unwrap()
+}
+
+/// Number of bits needed to change the width from `width`.
+fn change_cost(width: u32, bits: u32) -> u32 {
+    if width <= 6 { width + width_bits(bits) } else { width }
+}
+
+/// Bits used to store the new width in the short (1 to 6 bits wide) mode.
+fn width_bits(bits: u32) -> u32 {
+    if bits == 8 { 3 } else { 4 }
+}
+
+fn change_width(writer: &mut BitWriter, width: u32, new_width: u32, bits: u32) {
+    debug_assert_ne!(width, new_width);
+    // The current width can't be encoded so the widths above it are shifted down by one.
+    let encoded = if new_width < width { new_width } else { new_width - 1 };
+    match width {
+        1..=6 => {
+            writer.push(1 << (width - 1), width);
+            writer.push(encoded - 1, width_bits(bits));
+        }
+        _ if width <= bits => {
+            let start = (1 << (width - 1)) - bits / 2;
+            writer.push(start + encoded - 1, width);
+        }
+        _ => writer.push((1 << bits) | (new_width - 1), width),
+    }
+}
+
+/// Writes values into a bit stream, least significant bits first
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u32,
+    length: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, width: u32) {
+        debug_assert!(width <= 17 && value < (1 << width));
+        self.buffer |= value << self.length;
+        self.length += width;
+        while self.length >= 8 {
+            self.out.push(self.buffer.to_le_bytes()[0]);
+            self.buffer >>= 8;
+            self.length -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.length > 0 {
+            self.out.push(self.buffer.to_le_bytes()[0]);
+        }
+        self.out
+    }
+}
@@ -0,0 +1,60 @@
+//! OpenMPT extension chunks at the end of the file
+
+use super::*;
+
+
+impl SongProperty {
+    /// Codes and values of the chunks storing the property
+    ///
+    /// The codes are the names of the properties, they're written reversed, see
+    /// [`ExtensionChunk`].
+    fn chunks(self) -> impl Iterator<Item = (&'static [u8; 4], u32)> {
+        let (chunk, extra) = match self {
+            SongProperty::Tempo(tempo) => ((b"DT..", tempo), None),
+            SongProperty::TempoMode(TempoMode::Classic) => ((b"TM..", 0), None),
+            SongProperty::TempoMode(TempoMode::Alternative) => ((b"TM..", 1), None),
+            SongProperty::TempoMode(TempoMode::Modern { rows_per_beat }) => ((b"TM..", 2), Some((b"RPB.", rows_per_beat))),
+            SongProperty::RowsPerMeasure(rows) => ((b"RPM.", rows), None),
+        };
+        Some(chunk).into_iter().chain(extra)
+    }
+}
+
+/// Returns `trailing` with the chunks of `properties` added to the song properties.
+///
+/// The new chunks are inserted right after the `STPM` marker and replace the chunks with the same
+/// code, a new marker is appended if `trailing` has none. `instruments` is the number of
+/// instruments of the file `trailing` comes from, see [`song_property_chunks`].
+pub(super) fn song_properties<'a>(trailing: Cow<'a, [u8]>, instruments: usize, properties: &[SongProperty]) -> Cow<'a, [u8]> {
+    if properties.is_empty() {
+        return trailing;
+    }
+    let mut chunks = Vec::new();
+    for (code, value) in properties.iter().flat_map(|property| property.chunks()) {
+        // A later property overrides an earlier one with the same code.
+        chunks.retain(|&(existing, _)| existing != code);
+        chunks.push((code, value));
+    }
+
+    let existing = song_property_chunks(&trailing, instruments);
+    let mut out = Vec::with_capacity(trailing.len() + 4 + 10 * chunks.len());
+    out.extend_from_slice(existing.as_ref().map_or(&trailing[..], |&(before, _)| before));
+    out.extend_from_slice(SONG_PROPERTIES);
+    for (code, value) in &chunks {
+        out.extend(code.iter().rev());
+        out.extend_from_slice(&4u16.to_le_bytes());
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Keep the existing chunks which aren't replaced, anything that doesn't parse as a chunk is
+    // kept as it is.
+    if let Some((_, mut existing)) = existing {
+        for chunk in &mut existing {
+            if !chunks.iter().any(|(code, _)| chunk.is(code)) {
+                out.extend_from_slice(chunk.bytes);
+            }
+        }
+        out.extend_from_slice(existing.rest());
+    }
+    Cow::Owned(out)
+}
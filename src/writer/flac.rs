@@ -0,0 +1,278 @@
+//! Export of samples to FLAC files
+
+use crate::data::*;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+
+/// Samples per frame, the last frame may be shorter
+const BLOCK_SIZE: u16 = 4096;
+
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const STREAMINFO_SIZE: usize = 34;
+
+/// Marks the last metadata block
+const LAST_BLOCK: u8 = 0x80;
+
+/// Highest sample rate STREAMINFO can store
+const MAX_SAMPLERATE: u32 = 655_350;
+
+const VENDOR: &str = "ittech";
+
+/// Order of the fixed predictor used for all frames
+const ORDER: usize = 2;
+
+/// Highest parameter of the 4-bit Rice coding method, 15 is the escape code
+const MAX_RICE_PARAMETER: u32 = 14;
+
+
+impl Sample {
+    /// Writes the sample as a FLAC file
+    ///
+    /// The sample is written in its own bit depth with the C-5 frequency as the sample rate
+    /// (clamped to the FLAC maximum of 655350 Hz). Every block of 4096 samples is encoded with the
+    /// second order fixed predictor and a single Rice partition, which is simple and gets close to
+    /// the reference encoder on typical samples.
+    ///
+    /// FLAC has no loop metadata, the loop is stored in the `LOOPSTART` and `LOOPLENGTH` comments
+    /// many samplers and game engines read. Bidirectional and sustain loops can't be represented.
+    pub fn write_flac(&self, out: &mut impl Write) -> io::Result<()> {
+        let (samples, bits) = match &self.data {
+            Some(SampleData::Pcm8(data)) => (data.iter().map(|&x| i32::from(x)).collect::<Vec<_>>(), 8),
+            Some(SampleData::Pcm16(data)) => (data.iter().map(|&x| i32::from(x)).collect::<Vec<_>>(), 16),
+            None => (Vec::new(), 8u32),
+        };
+        let comments = self.loop_.map_or_else(Vec::new, |sample_loop| {
+            vec![
+                format!("LOOPSTART={}", sample_loop.start),
+                format!("LOOPLENGTH={}", sample_loop.end - sample_loop.start),
+            ]
+        });
+
+        let mut header = Vec::new();
+        header.extend_from_slice(b"fLaC");
+
+        let mut info = BitWriter::default();
+        info.push(u64::from(BLOCK_SIZE), 16);
+        info.push(u64::from(BLOCK_SIZE), 16);
+        // Frame sizes are unknown.
+        info.push(0, 24);
+        info.push(0, 24);
+        info.push(u64::from(self.samplerate_c5.clamp(1, MAX_SAMPLERATE)), 20);
+        // One channel.
+        info.push(0, 3);
+        info.push(u64::from(bits - 1), 5);
+        info.push(u64::try_from(samples.len()).unwrap(), 36);
+        // The MD5 signature is optional.
+        info.push(0, 32);
+        info.push(0, 32);
+        info.push(0, 32);
+        info.push(0, 32);
+        let info = info.finish();
+        debug_assert_eq!(info.len(), STREAMINFO_SIZE);
+        let last = if comments.is_empty() { LAST_BLOCK } else { 0 };
+        metadata_block(&mut header, STREAMINFO | last, &info);
+
+        if !comments.is_empty() {
+            let mut block = Vec::new();
+            // Vorbis comment lengths are little-endian.
+            block.extend_from_slice(&u32::try_from(VENDOR.len()).unwrap().to_le_bytes());
+            block.extend_from_slice(VENDOR.as_bytes());
+            block.extend_from_slice(&u32::try_from(comments.len()).unwrap().to_le_bytes());
+            for comment in &comments {
+                block.extend_from_slice(&u32::try_from(comment.len()).unwrap().to_le_bytes());
+                block.extend_from_slice(comment.as_bytes());
+            }
+            metadata_block(&mut header, VORBIS_COMMENT | LAST_BLOCK, &block);
+        }
+        out.write_all(&header)?;
+
+        for (number, block) in (0..).zip(samples.chunks(usize::from(BLOCK_SIZE))) {
+            out.write_all(&frame(number, block, bits))?;
+        }
+        Ok(())
+    }
+}
+
+fn metadata_block(out: &mut Vec<u8>, kind: u8, data: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&u32::try_from(data.len()).unwrap().to_be_bytes()[1..]);
+    out.extend_from_slice(data);
+}
+
+fn frame(number: u64, block: &[i32], bits: u32) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+
+    // Sync code, fixed block size strategy.
+    writer.push(0b11_1111_1111_1110, 14);
+    writer.push(0, 2);
+    // Block size stored after the header, sample rate and sample size from STREAMINFO, mono.
+    writer.push(0b0111, 4);
+    writer.push(0b0000, 4);
+    writer.push(0b0000, 4);
+    writer.push(0b000, 3);
+    writer.push(0, 1);
+    for byte in coded_number(number) {
+        writer.push(u64::from(byte), 8);
+    }
+    writer.push(u64::try_from(block.len() - 1).unwrap(), 16);
+    let crc = crc8(writer.bytes());
+    writer.push(u64::from(crc), 8);
+
+    // Fixed predictor subframe without wasted bits.
+    let order = ORDER.min(block.len());
+    writer.push(0, 1);
+    writer.push(0b00_1000 | u64::try_from(order).unwrap(), 6);
+    writer.push(0, 1);
+    for &sample in &block[..order] {
+        writer.push_signed(sample, bits);
+    }
+
+    let residuals = (order..block.len())
+        .map(|idx| match order {
+            0 => i64::from(block[idx]),
+            1 => i64::from(block[idx]) - i64::from(block[idx - 1]),
+            _ => i64::from(block[idx]) - 2 * i64::from(block[idx - 1]) + i64::from(block[idx - 2]),
+        })
+        .map(fold)
+        .collect::<Vec<_>>();
+    let parameter = rice_parameter(&residuals);
+
+    // Rice coding with 4-bit parameters and a single partition.
+    writer.push(0b00, 2);
+    writer.push(0, 4);
+    writer.push(u64::from(parameter), 4);
+    for residual in residuals {
+        writer.push_unary(residual >> parameter);
+        writer.push(residual & ((1 << parameter) - 1), parameter);
+    }
+
+    writer.align();
+    let crc = crc16(writer.bytes());
+    writer.push(u64::from(crc), 16);
+    writer.finish()
+}
+
+/// Maps signed residuals to unsigned ones, `0, -1, 1, -2, 2...` to `0, 1, 2, 3, 4...`
+fn fold(residual: i64) -> u64 {
+    if residual >= 0 {
+        u64::try_from(residual).unwrap() * 2
+    } else {
+        u64::try_from(-residual).unwrap() * 2 - 1
+    }
+}
+
+/// Picks the Rice parameter producing the fewest bits.
+fn rice_parameter(residuals: &[u64]) -> u32 {
+    (0..=MAX_RICE_PARAMETER)
+        .min_by_key(|&parameter| residuals.iter().map(|&residual| (residual >> parameter) + 1 + u64::from(parameter)).sum::<u64>())
+        .unwrap()
+}
+
+/// Frame number in the UTF-8 like variable length coding
+fn coded_number(number: u64) -> Vec<u8> {
+    if number < 0x80 {
+        return vec![u8::try_from(number).unwrap()];
+    }
+    // The first byte stores `6 - n` bits, each of the `n` continuation bytes 6 bits.
+    let continuation = (1..=6u32).find(|&n| number < 1 << (5 * n + 6)).unwrap();
+    let mut bytes = vec![!(0xFFu8 >> (continuation + 1)) | u8::try_from(number >> (6 * continuation)).unwrap()];
+    for idx in (0..continuation).rev() {
+        bytes.push(0x80 | u8::try_from((number >> (6 * idx)) & 0x3F).unwrap());
+    }
+    bytes
+}
+
+/// CRC-8 with polynomial `x^8 + x^2 + x + 1` protecting the frame header
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+/// CRC-16 with polynomial `x^16 + x^15 + x^2 + 1` protecting the whole frame
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 })
+    })
+}
+
+
+/// Writes bits starting from the most significant one
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    buffer: u64,
+    length: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u64, width: u32) {
+        debug_assert!(width <= 40 && value >> width == 0);
+        self.buffer = (self.buffer << width) | value;
+        self.length += width;
+        while self.length >= 8 {
+            self.length -= 8;
+            self.out.push((self.buffer >> self.length).to_le_bytes()[0]);
+        }
+    }
+
+    /// Pushes `value` as a `width` bits wide two's complement number.
+    fn push_signed(&mut self, value: i32, width: u32) {
+        let bits = i64::from(value) & ((1 << width) - 1);
+        self.push(u64::try_from(bits).unwrap(), width);
+    }
+
+    /// Pushes `value` zeros followed by a one.
+    fn push_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.push(0, 32);
+            value -= 32;
+        }
+        self.push(1, u32::try_from(value).unwrap() + 1);
+    }
+
+    /// Pads the stream with zeros to a whole byte.
+    fn align(&mut self) {
+        if self.length > 0 {
+            self.push(0, 8 - self.length);
+        }
+    }
+
+    /// Bytes written so far, only complete when aligned
+    fn bytes(&self) -> &[u8] {
+        &self.out
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.out
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn frame_numbers() {
+        assert_eq!(coded_number(0x7F), [0x7F]);
+        assert_eq!(coded_number(0x80), [0xC2, 0x80]);
+        assert_eq!(coded_number(0x800), [0xE0, 0xA0, 0x80]);
+    }
+
+    #[test]
+    fn residual_coding() {
+        assert_eq!([0, -1, 1, -2, 2].map(fold), [0, 1, 2, 3, 4]);
+        assert_eq!(rice_parameter(&[0, 0, 1]), 0);
+        assert_eq!(rice_parameter(&[1000, 1200, 900]), 9);
+    }
+}
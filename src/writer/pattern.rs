@@ -0,0 +1,373 @@
+use super::*;
+
+
+/// Channel byte flag which says a mask byte follows
+const READ_MASK: u8 = 1 << 7;
+
+/// Sizes of the note, instrument, volume and effect values
+const FIELD_SIZES: [usize; 4] = [1, 1, 1, 2];
+
+/// Command in its raw form, before choosing how to pack it
+struct Packed {
+    channel: Channel,
+
+    /// Raw note, instrument, volume and effect, values shorter than two bytes only use the first
+    fields: [Option<(u8, u8)>; 4],
+
+    /// Bits of the fields which are present, in the order of the mask bits
+    present: u8,
+
+    /// Bits of the fields which are equal to the last value of the channel
+    repeated: u8,
+
+    /// Chosen mask
+    mask: u8,
+}
+
+/// Pack a pattern into its file representation, including the pattern header
+///
+/// The pattern is packed the same way Impulse Tracker does it, fields equal to the last value in
+/// the channel are replaced by the `LAST_*` mask bits and repeated masks are left out. Reusing a
+/// value is never larger than writing it again, but it changes the mask, so the masks of every
+/// channel are chosen together to give the smallest possible output.
+pub(super) fn pattern(pattern: &Pattern, pattern_id: PatternId) -> Result<Vec<u8>, WriteError> {
+    let rows = match u16::try_from(pattern.rows.len()) {
+        Ok(rows) => rows,
+        Err(_) => return Err(WriteError::TooManyRows { pattern: pattern_id, rows: pattern.rows.len() }),
+    };
+
+    let mut last_fields = [[None; 4]; 64];
+    let mut packed = pattern.rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|(channel, command)| {
+                    let fields = fields(command);
+                    let last = &mut last_fields[channel.as_usize()];
+                    let mut present = 0;
+                    let mut repeated = 0;
+                    for (bit, (field, last)) in fields.iter().zip(last.iter_mut()).enumerate() {
+                        if field.is_some() {
+                            present |= 1 << bit;
+                            if field == last {
+                                repeated |= 1 << bit;
+                            }
+                            *last = *field;
+                        }
+                    }
+                    Packed { channel, fields, present, repeated, mask: 0 }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut channels = vec![Vec::new(); 64];
+    for (row_idx, row) in packed.iter().enumerate() {
+        for (idx, command) in row.iter().enumerate() {
+            channels[command.channel.as_usize()].push((row_idx, idx));
+        }
+    }
+    for commands in &channels {
+        choose_masks(&mut packed, commands);
+    }
+
+    let mut data = Vec::new();
+    let mut last_mask = [0; 64];
+    for row in &packed {
+        for command in row {
+            let last_mask = &mut last_mask[command.channel.as_usize()];
+            if command.mask == *last_mask {
+                data.push(command.channel.number());
+            } else {
+                data.extend([command.channel.number() | READ_MASK, command.mask]);
+                *last_mask = command.mask;
+            }
+            for (bit, (field, size)) in command.fields.iter().zip(FIELD_SIZES).enumerate() {
+                if let (true, Some((first, second))) = (command.mask & (1 << bit) != 0, field) {
+                    data.push(*first);
+                    if size == 2 {
+                        data.push(*second);
+                    }
+                }
+            }
+        }
+        data.push(0);
+    }
+
+    let length = match u16::try_from(data.len()) {
+        Ok(length) => length,
+        Err(_) => return Err(WriteError::PatternTooLarge { pattern: pattern_id, size: data.len() }),
+    };
+
+    let mut out = Vec::with_capacity(PATTERN_HEADER_SIZE + data.len());
+    out.extend_from_slice(&length.to_le_bytes());
+    out.extend_from_slice(&rows.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend(data);
+    Ok(out)
+}
+
+fn fields(command: &Command) -> [Option<(u8, u8)>; 4] {
+    let note = command.note.map(|note| match note {
+        NoteCmd::Play(note) => u8::from(note),
+        NoteCmd::Off => 255,
+        NoteCmd::Cut => 254,
+        NoteCmd::Fade => 253,
+    });
+    let instrument = command.instrument.map(|instrument| instrument.number());
+    let volume = command.volume.as_ref().map(serialize_volume);
+    let effect = command.effect.as_ref().map(serialize_effect);
+    [note.map(|x| (x, 0)), instrument.map(|x| (x, 0)), volume.map(|x| (x, 0)), effect]
+}
+
+/// Chooses the masks of the `commands` of a single channel
+///
+/// The last values don't depend on the choice (a reused value is the same as the read one), so
+/// only the last mask carries over from one command to the next. This finds the cheapest
+/// sequence of masks by going through all the masks each command can use.
+fn choose_masks(packed: &mut [Vec<Packed>], commands: &[(usize, usize)]) {
+    // Cheapest total size ending with each mask of the previous command, the parser starts with
+    // an empty mask.
+    let mut states = vec![(0, 0)];
+    let mut steps = Vec::with_capacity(commands.len());
+    for &(row, idx) in commands {
+        let command = &packed[row][idx];
+        let choices = mask_choices(command.present, command.repeated);
+        let (next, from): (Vec<_>, Vec<_>) = choices
+            .iter()
+            .map(|&(mask, size)| {
+                let (prev, total) = states
+                    .iter()
+                    .enumerate()
+                    .map(|(prev, &(prev_mask, total))| (prev, total + usize::from(prev_mask != mask)))
+                    .min_by_key(|&(_, total)| total)
+                    .unwrap();
+                ((mask, total + 1 + size), prev)
+            })
+            .unzip();
+        states = next;
+        steps.push((choices, from));
+    }
+
+    let Some(mut state) = states.iter().enumerate().min_by_key(|(_, (_, total))| total).map(|(idx, _)| idx) else {
+        return;
+    };
+    for (&(row, idx), (choices, from)) in commands.iter().zip(&steps).rev() {
+        packed[row][idx].mask = choices[state].0;
+        state = from[state];
+    }
+}
+
+/// Masks a command can use with the size of the values they read
+fn mask_choices(present: u8, repeated: u8) -> Vec<(u8, usize)> {
+    let mut choices = Vec::new();
+    // Go through all the subsets of the repeated fields.
+    let mut reused = repeated;
+    loop {
+        let read = present & !reused;
+        let size = (0..4).filter(|bit| read & (1 << bit) != 0).map(|bit| FIELD_SIZES[bit]).sum();
+        choices.push((read | (reused << 4), size));
+        if reused == 0 {
+            break choices;
+        }
+        reused = (reused - 1) & repeated;
+    }
+}
+
+/// Serialize structured effect into raw effect number and parameter
+///
+/// This is the inverse of [`parser::effect`](crate::parser::effect), for all values the parser can
+/// produce `parser::effect(writer::effect(cmd))` returns `cmd` again.
+pub fn serialize_effect(effect: &EffectCmd) -> (u8, u8) {
+    // Combine two nibbles into a parameter byte.
+    fn nibbles(x: u8, y: u8) -> u8 {
+        (x << 4) | y
+    }
+
+    fn volume_slide(volume_slide: &Option<VolumeSlide>) -> u8 {
+        match volume_slide {
+            None => 0x00,
+            Some(VolumeSlide::Down(y)) => nibbles(0x0, y.as_u8()),
+            Some(VolumeSlide::Up(x)) => nibbles(x.as_u8(), 0x0),
+            Some(VolumeSlide::FineDown(y)) => nibbles(0xF, y.as_u8()),
+            Some(VolumeSlide::FineUp(x)) => nibbles(x.as_u8(), 0xF),
+        }
+    }
+
+    fn portamento(portamento: &Option<Portamento>) -> u8 {
+        match portamento {
+            None => 0x00,
+            Some(Portamento::Coarse(xx)) => xx.as_u8(),
+            Some(Portamento::Fine(x)) => 0xF0 | x.as_u8(),
+            Some(Portamento::ExtraFine(x)) => 0xE0 | x.as_u8(),
+        }
+    }
+
+    fn pair(x: &Option<RangedU8<1, 0x0F>>, y: &Option<RangedU8<1, 0x0F>>) -> u8 {
+        nibbles(x.map_or(0, RangedU8::as_u8), y.map_or(0, RangedU8::as_u8))
+    }
+
+    fn special(special: &Special) -> u8 {
+        fn waveform(waveform: &Waveform) -> u8 {
+            match waveform {
+                Waveform::Sine => 0x0,
+                Waveform::Sawtooth => 0x1,
+                Waveform::Square => 0x2,
+                Waveform::Random => 0x3,
+            }
+        }
+
+        match special {
+            Special::SetGlissando(on) => nibbles(0x1, u8::from(*on)),
+            Special::SetFinetune(y) => nibbles(0x2, y.as_u8()),
+            Special::SetVibratoWaveform(w) => nibbles(0x3, waveform(w)),
+            Special::SetTremoloWaveform(w) => nibbles(0x4, waveform(w)),
+            Special::SetPanbrelloWaveform(w) => nibbles(0x5, waveform(w)),
+            Special::PatternTickDelay(y) => nibbles(0x6, y.as_u8()),
+            Special::PastNote(SetPastNote::Cut) => 0x70,
+            Special::PastNote(SetPastNote::Off) => 0x71,
+            Special::PastNote(SetPastNote::Fade) => 0x72,
+            Special::SetNewNoteAction(SetNewNoteAction::Cut) => 0x73,
+            Special::SetNewNoteAction(SetNewNoteAction::Continue) => 0x74,
+            Special::SetNewNoteAction(SetNewNoteAction::Off) => 0x75,
+            Special::SetNewNoteAction(SetNewNoteAction::Fade) => 0x76,
+            Special::SetVolumeEnvelope(on) => if *on { 0x78 } else { 0x77 },
+            Special::SetPanningEnvelope(on) => if *on { 0x7A } else { 0x79 },
+            Special::SetPitchEnvelope(on) => if *on { 0x7C } else { 0x7B },
+            Special::SetPanning(y) => nibbles(0x8, y.as_u8()),
+            Special::SetSurround(on) => if *on { 0x91 } else { 0x90 },
+            Special::SetReverb(on) => if *on { 0x99 } else { 0x98 },
+            Special::SetSurroundMode(SurroundMode::Center) => 0x9A,
+            Special::SetSurroundMode(SurroundMode::Quad) => 0x9B,
+            Special::SetFilterMode(FilterMode::Global) => 0x9C,
+            Special::SetFilterMode(FilterMode::Local) => 0x9D,
+            Special::SetDirection(PlayDirection::Forward) => 0x9E,
+            Special::SetDirection(PlayDirection::Backward) => 0x9F,
+            Special::SetLoopbackPoint => 0xB0,
+            Special::LoopbackTimes(y) => nibbles(0xB, y.as_u8()),
+            Special::NoteCut(y) => nibbles(0xC, y.as_u8()),
+            Special::NoteDelay(y) => nibbles(0xD, y.as_u8()),
+            Special::PatternRowDelay(y) => nibbles(0xE, y.as_u8()),
+            Special::SetMidiParam(y) => nibbles(0xF, y.as_u8()),
+        }
+    }
+
+    let (effect_code, param) = match effect {
+        EffectCmd::SetSpeed(xx) => ('A', xx.as_u8()),
+        EffectCmd::JumpOrder(xx) => ('B', xx.as_u8()),
+        EffectCmd::BreakRow(xx) => ('C', *xx),
+        EffectCmd::VolumeSlide(vs) => ('D', volume_slide(vs)),
+        EffectCmd::PortamentoDown(porta) => ('E', portamento(porta)),
+        EffectCmd::PortamentoUp(porta) => ('F', portamento(porta)),
+        EffectCmd::TonePortamento(xx) => ('G', xx.map_or(0, RangedU8::as_u8)),
+        EffectCmd::Vibrato(x, y) => ('H', pair(x, y)),
+        EffectCmd::Tremor(xy) => ('I', xy.map_or(0, |(x, y)| nibbles(x.as_u8(), y.as_u8()))),
+        EffectCmd::Arpeggio(xy) => ('J', xy.map_or(0, |(x, y)| nibbles(x.as_u8(), y.as_u8()))),
+        EffectCmd::VolumeSlideAndVibrato(vs) => ('K', volume_slide(vs)),
+        EffectCmd::VolumeSlideAndPortamento(vs) => ('L', volume_slide(vs)),
+        EffectCmd::SetChannelVolume(xx) => ('M', xx.as_u8()),
+        EffectCmd::ChannelVolumeSlide(vs) => ('N', volume_slide(vs)),
+        EffectCmd::SetSampleOffset(SetSampleOffset::Low(xx)) => ('O', *xx),
+        EffectCmd::SetSampleOffset(SetSampleOffset::High(y)) => ('S', nibbles(0xA, y.as_u8())),
+        EffectCmd::PanningSlide(ps) => ('P', match ps {
+            None => 0x00,
+            Some(PanningSlide::Right(y)) => nibbles(0x0, y.as_u8()),
+            Some(PanningSlide::Left(x)) => nibbles(x.as_u8(), 0x0),
+            Some(PanningSlide::FineRight(y)) => nibbles(0xF, y.as_u8()),
+            Some(PanningSlide::FineLeft(x)) => nibbles(x.as_u8(), 0xF),
+        }),
+        EffectCmd::Retrigger(xy) => ('Q', xy.map_or(0, |(x, y)| nibbles(x.as_u8(), y.as_u8()))),
+        EffectCmd::Tremolo(x, y) => ('R', pair(x, y)),
+        EffectCmd::Special(sp) => ('S', sp.as_ref().map_or(0x00, special)),
+        EffectCmd::Tempo(tempo) => ('T', match tempo {
+            None => 0x00,
+            Some(Tempo::SlideDown(y)) => nibbles(0x0, y.as_u8()),
+            Some(Tempo::SlideUp(y)) => nibbles(0x1, y.as_u8()),
+            Some(Tempo::Set(xx)) => xx.as_u8(),
+        }),
+        EffectCmd::FineVibrato(x, y) => ('U', pair(x, y)),
+        EffectCmd::SetGlobalVolume(xx) => ('V', xx.as_u8()),
+        EffectCmd::GlobalVolumeSlide(vs) => ('W', volume_slide(vs)),
+        EffectCmd::SetPanningPosition(xx) => ('X', *xx),
+        EffectCmd::Panbrello(x, y) => ('Y', pair(x, y)),
+        EffectCmd::Midi(xx) => ('Z', *xx),
+    };
+
+    // Convert the effect letter back to the effect number, see `parser::effect`.
+    let effect = u8::try_from(effect_code).unwrap() - b'A' + 1;
+
+    (effect, param)
+}
+
+/// Serialize structured volume column command into the raw volume byte
+///
+/// This is the inverse of [`parser::volume`](crate::parser::volume), see [`VolumeCmd`] for the
+/// value ranges.
+pub fn serialize_volume(volume: &VolumeCmd) -> u8 {
+    fn param(x: &Option<RangedU8<1, 9>>) -> u8 {
+        x.map_or(0, RangedU8::as_u8)
+    }
+
+    match volume {
+        VolumeCmd::SetVolume(x) => x.as_u8(),
+        VolumeCmd::Panning(x) => 128 + x.as_u8(),
+        VolumeCmd::FineVolumeUp(x) => 65 + param(x),
+        VolumeCmd::FineVolumeDown(x) => 75 + param(x),
+        VolumeCmd::VolumeSlideUp(x) => 85 + param(x),
+        VolumeCmd::VolumeSlideDown(x) => 95 + param(x),
+        VolumeCmd::PortamentoDown(x) => 105 + param(x),
+        VolumeCmd::PortamentoUp(x) => 115 + param(x),
+        VolumeCmd::TonePortamento(x) => 193 + param(x),
+        VolumeCmd::Vibrato(x) => 203 + param(x),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    /// Compares the packed size with patterns saved by OpenMPT.
+    ///
+    /// Only the patterns of the two bundled OpenMPT files are covered, packing which is smaller
+    /// there isn't known to be smaller on every file OpenMPT saves.
+    #[test]
+    fn packing() {
+        use crate::error::VerboseError;
+
+        for file in [&include_bytes!("../../tests/effect_alphabet.it")[..], include_bytes!("../../tests/song_message.it")] {
+            let module = parser::module_file::<VerboseError<&[u8]>>(file).unwrap();
+            let count = |at: usize| usize::from(u16::from_le_bytes([file[at], file[at + 1]]));
+            let table = 0xC0 + count(0x20) + 4 * (count(0x22) + count(0x24));
+            for (idx, pattern) in (0..).zip(&module.patterns) {
+                let position = table + 4 * usize::from(idx);
+                let offset = usize::try_from(u32::from_le_bytes(file[position..position + 4].try_into().unwrap())).unwrap();
+                if offset == 0 {
+                    continue;
+                }
+                let original = count(offset);
+
+                let packed = super::pattern(pattern, PatternId::from_index(idx).unwrap()).unwrap();
+                assert!(packed.len() - PATTERN_HEADER_SIZE <= original, "pattern {idx}: {} > {original}", packed.len() - PATTERN_HEADER_SIZE);
+            }
+
+            let written = crate::writer::module_file(&module).unwrap();
+            let reparsed = parser::module_file::<VerboseError<&[u8]>>(&written).unwrap();
+            assert_eq!(reparsed.patterns, module.patterns);
+        }
+    }
+
+    #[test]
+    fn effect_roundtrip() {
+        for effect in 1..=26 {
+            for param in 0..=255 {
+                if let Some(cmd) = parser::effect(effect, param) {
+                    let (effect, param) = serialize_effect(&cmd);
+                    let reparsed = parser::effect(effect, param);
+                    assert_eq!(format!("{:?}", Some(cmd)), format!("{reparsed:?}"));
+                }
+            }
+        }
+    }
+}
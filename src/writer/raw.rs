@@ -0,0 +1,93 @@
+//! Export of samples to raw PCM files with a JSON descriptor
+
+use super::write_pcm;
+use crate::data::*;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+
+impl Sample {
+    /// Writes the sample data as raw PCM
+    ///
+    /// The samples are written exactly as stored: signed, little endian, mono, in the bit depth
+    /// of the sample. [`Sample::raw_descriptor`] describes the format for the tools reading the
+    /// data, e.g. for a 16-bit sample
+    /// `sox -t raw -e signed -b 16 -L -c 1 -r 8363 sample.raw sample.wav` or
+    /// `ffmpeg -f s16le -ac 1 -ar 8363 -i sample.raw sample.wav`. Samples without data write
+    /// nothing.
+    pub fn write_raw(&self, out: &mut impl Write) -> io::Result<()> {
+        match &self.data {
+            Some(SampleData::Pcm8(data)) => write_pcm(out, data, i8::to_le_bytes),
+            Some(SampleData::Pcm16(data)) => write_pcm(out, data, i16::to_le_bytes),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a JSON document describing the data written by [`Sample::write_raw`]
+    ///
+    /// The document has the fields `samplerate` (the C-5 frequency), `bits`, `channels`
+    /// (always 1), `encoding` (always `"signed"`), `endian` (always `"little"`), `length` in
+    /// samples, and `loop` and `sustain_loop`, which are `null` or objects with `start`, `end`
+    /// (exclusive) and `bidi`.
+    pub fn raw_descriptor(&self) -> String {
+        let bits = match &self.data {
+            Some(SampleData::Pcm16(_)) => 16,
+            _ => 8,
+        };
+        let json_loop = |sample_loop: Option<SampleLoop>| match sample_loop {
+            Some(SampleLoop { start, end, bidi }) => {
+                format!(r#"{{ "start": {}, "end": {}, "bidi": {} }}"#, start, end, bidi)
+            }
+            None => "null".to_string(),
+        };
+
+        // Writing into a `String` never fails.
+        let mut json = String::new();
+        writeln!(json, "{{").unwrap();
+        writeln!(json, r#"  "samplerate": {},"#, self.samplerate_c5).unwrap();
+        writeln!(json, r#"  "bits": {},"#, bits).unwrap();
+        writeln!(json, r#"  "channels": 1,"#).unwrap();
+        writeln!(json, r#"  "encoding": "signed","#).unwrap();
+        writeln!(json, r#"  "endian": "little","#).unwrap();
+        writeln!(json, r#"  "length": {},"#, self.length()).unwrap();
+        writeln!(json, r#"  "loop": {},"#, json_loop(self.loop_)).unwrap();
+        writeln!(json, r#"  "sustain_loop": {}"#, json_loop(self.sustain_loop)).unwrap();
+        writeln!(json, "}}").unwrap();
+        json
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn write_raw_with_descriptor() {
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/compression/compressed.iti")).unwrap();
+        let mut sample = instrument.samples[0].clone();
+        sample.sustain_loop = None;
+        sample.data = Some(SampleData::from(vec![0i16, 1000, -1000, 32767, -32768]));
+        sample.samplerate_c5 = 22050;
+        sample.set_loop(Some(SampleLoop { start: 1, end: 5, bidi: true })).unwrap();
+
+        let mut raw = Vec::new();
+        sample.write_raw(&mut raw).unwrap();
+        assert_eq!(raw, [0x00, 0x00, 0xE8, 0x03, 0x18, 0xFC, 0xFF, 0x7F, 0x00, 0x80]);
+
+        assert_eq!(sample.raw_descriptor(), concat!(
+            "{\n",
+            "  \"samplerate\": 22050,\n",
+            "  \"bits\": 16,\n",
+            "  \"channels\": 1,\n",
+            "  \"encoding\": \"signed\",\n",
+            "  \"endian\": \"little\",\n",
+            "  \"length\": 5,\n",
+            "  \"loop\": { \"start\": 1, \"end\": 5, \"bidi\": true },\n",
+            "  \"sustain_loop\": null\n",
+            "}\n",
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+//! Export of samples to WAV files
+
+use super::write_pcm;
+use crate::data::*;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+
+const FMT_SIZE: u32 = 16;
+const SMPL_HEADER_SIZE: u32 = 36;
+const SMPL_LOOP_SIZE: u32 = 24;
+
+/// `WAVE_FORMAT_PCM`
+const FORMAT_PCM: u16 = 1;
+
+/// MIDI note the sample plays unchanged at, IT `C-5`
+const UNITY_NOTE: u32 = 60;
+
+
+impl Sample {
+    /// Writes the sample as a PCM WAV file
+    ///
+    /// The sample is written in its own bit depth with the C-5 frequency as the sample rate. IT
+    /// samples are always mono. The loop and the sustain loop are stored in a `smpl` chunk (in
+    /// this order) with the end point made inclusive, as the chunk defines it. Samples without
+    /// data produce a file with an empty `data` chunk.
+    pub fn write_wav(&self, out: &mut impl Write) -> io::Result<()> {
+        let bits: u16 = match &self.data {
+            Some(SampleData::Pcm16(_)) => 16,
+            _ => 8,
+        };
+        let block_align = bits / 8;
+        let samplerate = self.samplerate_c5.max(1);
+        let data_size = self.length()
+            .checked_mul(u32::from(block_align))
+            .filter(|&size| size <= u32::MAX - 0x1000)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "sample data too large for a WAV file"))?;
+        let padding = data_size % 2;
+
+        let loops = [self.loop_, self.sustain_loop].into_iter().flatten().collect::<Vec<_>>();
+        let smpl_size = if loops.is_empty() {
+            0
+        } else {
+            8 + SMPL_HEADER_SIZE + SMPL_LOOP_SIZE * u32::try_from(loops.len()).unwrap()
+        };
+        let riff_size = 4 + (8 + FMT_SIZE) + smpl_size + (8 + data_size + padding);
+
+        let mut header = Vec::with_capacity(usize::try_from(riff_size - data_size).unwrap());
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&riff_size.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&FMT_SIZE.to_le_bytes());
+        header.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&samplerate.to_le_bytes());
+        header.extend_from_slice(&samplerate.saturating_mul(u32::from(block_align)).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+
+        if !loops.is_empty() {
+            header.extend_from_slice(b"smpl");
+            header.extend_from_slice(&(smpl_size - 8).to_le_bytes());
+            // Manufacturer and product.
+            header.extend_from_slice(&[0; 8]);
+            // Sample period in nanoseconds.
+            header.extend_from_slice(&(1_000_000_000 / samplerate).to_le_bytes());
+            header.extend_from_slice(&UNITY_NOTE.to_le_bytes());
+            // Pitch fraction, SMPTE format and offset.
+            header.extend_from_slice(&[0; 12]);
+            header.extend_from_slice(&u32::try_from(loops.len()).unwrap().to_le_bytes());
+            // Sampler data.
+            header.extend_from_slice(&[0; 4]);
+            for (cue, sample_loop) in (0u32..).zip(&loops) {
+                let kind = u32::from(sample_loop.bidi);
+                for value in [cue, kind, sample_loop.start, sample_loop.end - 1] {
+                    header.extend_from_slice(&value.to_le_bytes());
+                }
+                // Fraction and play count (infinite).
+                header.extend_from_slice(&[0; 8]);
+            }
+        }
+
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+        out.write_all(&header)?;
+
+        // 8-bit WAV data is unsigned.
+        match &self.data {
+            Some(SampleData::Pcm8(data)) => write_pcm(out, data, |x| [x.to_le_bytes()[0] ^ 0x80])?,
+            Some(SampleData::Pcm16(data)) => write_pcm(out, data, i16::to_le_bytes)?,
+            None => {}
+        }
+        if padding != 0 {
+            out.write_all(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    fn test_sample() -> Sample {
+        let instrument = parser::instrument_file::<VerboseError<&[u8]>>(include_bytes!("../../tests/compression/compressed.iti")).unwrap();
+        let mut sample = instrument.samples[0].clone();
+        sample.loop_ = None;
+        sample.sustain_loop = None;
+        sample
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn write_16bit_with_loop() {
+        let mut sample = test_sample();
+        sample.data = Some(SampleData::from(vec![0i16, 1000, -1000, 32767, -32768]));
+        sample.samplerate_c5 = 22050;
+        sample.set_loop(Some(SampleLoop { start: 1, end: 5, bidi: true })).unwrap();
+
+        let mut wav = Vec::new();
+        sample.write_wav(&mut wav).unwrap();
+
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(usize::try_from(u32_at(&wav, 4)).unwrap(), wav.len() - 8);
+        assert_eq!(&wav[12..16], b"fmt ");
+        assert_eq!(u32_at(&wav, 24), 22050);
+        assert_eq!(&wav[36..40], b"smpl");
+        // Number of loops, then the loop type, start and inclusive end.
+        assert_eq!(u32_at(&wav, 36 + 8 + 28), 1);
+        assert_eq!((u32_at(&wav, 36 + 8 + 40), u32_at(&wav, 36 + 8 + 44), u32_at(&wav, 36 + 8 + 48)), (1, 1, 4));
+        let data = 36 + 8 + 36 + 24;
+        assert_eq!(&wav[data..data + 4], b"data");
+        assert_eq!(u32_at(&wav, data + 4), 10);
+        assert_eq!(&wav[data + 10..data + 12], &32767i16.to_le_bytes());
+    }
+
+    #[test]
+    fn write_8bit_padded() {
+        let mut sample = test_sample();
+        sample.data = Some(SampleData::from(vec![-128i8, 0, 127]));
+
+        let mut wav = Vec::new();
+        sample.write_wav(&mut wav).unwrap();
+
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 3);
+        assert_eq!(&wav[44..], &[0x00, 0x80, 0xFF, 0x00]);
+        assert_eq!(usize::try_from(u32_at(&wav, 4)).unwrap(), wav.len() - 8);
+    }
+}